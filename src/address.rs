@@ -0,0 +1,88 @@
+//! Allocation of device addresses
+
+use crate::types::DeviceAddress;
+use core::num::NonZeroU8;
+
+/// Highest address that can be assigned to a device
+const MAX_ADDRESS: u8 = 127;
+
+/// Keeps track of the device addresses which are in use
+///
+/// Addresses are handed out round-robin, starting after the one that was assigned last. This way an address is not
+/// re-used right after the device that had it was removed, which makes stale `DeviceAddress` values held by drivers or
+/// the application less likely to alias a new device.
+#[derive(Copy, Clone)]
+pub(crate) struct AddressTable {
+    /// Bit `n` is set if address `n` is in use
+    used: u128,
+    last: u8,
+}
+
+impl AddressTable {
+    pub(crate) const fn new() -> Self {
+        AddressTable { used: 0, last: 0 }
+    }
+
+    /// Returns the address that will be allocated next, or `None` if all addresses are in use
+    pub(crate) fn next(&self) -> Option<DeviceAddress> {
+        (0..MAX_ADDRESS)
+            .map(|offset| (self.last + offset) % MAX_ADDRESS + 1)
+            .find(|addr| self.used & (1 << addr) == 0)
+            .map(|addr| DeviceAddress(NonZeroU8::new(addr).unwrap()))
+    }
+
+    /// Mark the given address as in use
+    pub(crate) fn allocate(&mut self, addr: DeviceAddress) {
+        let addr = u8::from(addr);
+        self.used |= 1 << addr;
+        self.last = addr;
+    }
+
+    /// Mark the given address as free again
+    pub(crate) fn free(&mut self, addr: DeviceAddress) {
+        self.used &= !(1 << u8::from(addr));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(n: u8) -> DeviceAddress {
+        DeviceAddress(NonZeroU8::new(n).unwrap())
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let mut table = AddressTable::new();
+        assert!(table.next() == Some(addr(1)));
+        table.allocate(addr(1));
+        assert!(table.next() == Some(addr(2)));
+        table.allocate(addr(2));
+        // a freed address is not re-used right away
+        table.free(addr(1));
+        assert!(table.next() == Some(addr(3)));
+    }
+
+    #[test]
+    fn test_wraparound() {
+        let mut table = AddressTable::new();
+        table.allocate(addr(1));
+        table.allocate(addr(127));
+        // 1 is still in use, so it is skipped
+        assert!(table.next() == Some(addr(2)));
+    }
+
+    #[test]
+    fn test_exhausted() {
+        let mut table = AddressTable::new();
+        for n in 1..=127 {
+            let next = table.next().unwrap();
+            assert!(next == addr(n));
+            table.allocate(next);
+        }
+        assert!(table.next().is_none());
+        table.free(addr(42));
+        assert!(table.next() == Some(addr(42)));
+    }
+}
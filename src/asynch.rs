@@ -0,0 +1,432 @@
+//! Async API for transfers
+//!
+//! Drivers built on the [`Driver`] callbacks have to track the state of each request themselves. For code that
+//! runs in an async executor (e.g. embassy), [`UsbHostAsync`] offers an alternative: it wraps a [`UsbHost`], and provides
+//! transfers that can be `.await`ed.
+//!
+//! Awaiting a transfer keeps polling the host, so the drivers passed in still receive their callbacks in the meantime.
+//! Between two polls, the future waits for the host bus to signal new events (see [`HostBus::register_waker`]). The RP2040
+//! bus does so with the `critical-section` feature, once its interrupt handler calls `Rp2040HostBus::on_interrupt`. With
+//! buses that do not support wakers, the futures wake themselves immediately, so the executor keeps polling them.
+//!
+//! ```ignore
+//! let mut host = UsbHostAsync::new(&mut usb_host);
+//! loop {
+//!     match host.poll(&mut [&mut kbd_driver]).await {
+//!         PollResult::DiscoveryError(_) => { /* ... */ }
+//!         _ => {}
+//!     }
+//!     if let Some(KbdEvent::DeviceAdded(dev_addr)) = kbd_driver.take_event() {
+//!         let mut status = [0; 2];
+//!         let pipe_id = host.host().create_control_pipe(dev_addr).unwrap();
+//!         host.control_in(&mut [&mut kbd_driver], dev_addr, pipe_id, get_status, &mut status).await?;
+//!     }
+//! }
+//! ```
+//!
+//! Poll results other than those of the final poll are discarded while a transfer is awaited.
+
+use crate::bus::HostBus;
+use crate::descriptor::DescriptorContext;
+use crate::driver::{DiscoveryInterest, Driver};
+use crate::types::{ConnectionSpeed, DeviceAddress, InterfaceSet, SetupPacket};
+use crate::{
+    ControlError, DetachReason, PipeId, PollResult, TransferError, UsbHost,
+    DEFAULT_CONTROL_BUFFER_SIZE, DEFAULT_CONTROL_QUEUE_DEPTH, DEFAULT_MAX_PIPES,
+};
+use core::future::poll_fn;
+use core::task::{Context, Poll};
+
+/// Error returned from the transfers of [`UsbHostAsync`]
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum AsyncError {
+    /// The transfer could not be started (other than because the bus was busy)
+    Control(ControlError),
+    /// The transfer was started, but failed
+    Transfer(TransferError),
+    /// The device was detached before the transfer completed
+    Detached,
+}
+
+impl From<ControlError> for AsyncError {
+    fn from(e: ControlError) -> Self {
+        AsyncError::Control(e)
+    }
+}
+
+/// Wraps a [`UsbHost`], to provide transfers that can be awaited
+///
+/// See the [module documentation](self) for details.
+#[derive(Debug)]
+pub struct UsbHostAsync<
+    'h,
+    B: HostBus,
+    const MAX_PIPES: usize = DEFAULT_MAX_PIPES,
+    const CONTROL_QUEUE_DEPTH: usize = DEFAULT_CONTROL_QUEUE_DEPTH,
+    const CONTROL_BUFFER_SIZE: usize = DEFAULT_CONTROL_BUFFER_SIZE,
+> {
+    host: &'h mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+}
+
+impl<
+        'h,
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    > UsbHostAsync<'h, B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>
+{
+    pub fn new(
+        host: &'h mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) -> Self {
+        Self { host }
+    }
+
+    /// Access the wrapped host, e.g. to create pipes
+    pub fn host(&mut self) -> &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE> {
+        self.host
+    }
+
+    /// Wait for new events from the host bus, then poll the host
+    pub async fn poll(
+        &mut self,
+        drivers: &mut [&mut dyn Driver<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>],
+    ) -> PollResult {
+        let mut waited = false;
+        poll_fn(|cx| {
+            if waited {
+                Poll::Ready(self.host.poll(drivers))
+            } else {
+                waited = true;
+                self.wait(cx);
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    /// Perform a control IN transfer on the given pipe, and wait for its completion
+    ///
+    /// The received data is copied to `buf`, and its length is returned. The length of the data stage is the length of
+    /// `buf` (the `length` of the `setup` packet is ignored).
+    pub async fn control_in(
+        &mut self,
+        drivers: &mut [&mut dyn Driver<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>],
+        dev_addr: DeviceAddress,
+        pipe_id: PipeId,
+        mut setup: SetupPacket,
+        buf: &mut [u8],
+    ) -> Result<usize, AsyncError> {
+        setup.length = buf.len() as u16;
+        let mut waiter = Waiter::new(dev_addr, pipe_id, Expect::Control, buf);
+        self.run(drivers, &mut waiter, |host| host.control_in(Some(dev_addr), Some(pipe_id), setup)).await
+    }
+
+    /// Perform a control OUT transfer on the given pipe, and wait for its completion
+    pub async fn control_out(
+        &mut self,
+        drivers: &mut [&mut dyn Driver<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>],
+        dev_addr: DeviceAddress,
+        pipe_id: PipeId,
+        setup: SetupPacket,
+        data: &[u8],
+    ) -> Result<(), AsyncError> {
+        let mut waiter = Waiter::new(dev_addr, pipe_id, Expect::Control, &mut []);
+        self.run(drivers, &mut waiter, |host| host.control_out(Some(dev_addr), Some(pipe_id), setup, data))
+            .await
+            .map(|_| ())
+    }
+
+    /// Wait for the next data received on the given interrupt IN pipe
+    ///
+    /// The data is copied to `buf`, and its length is returned.
+    pub async fn interrupt_in(
+        &mut self,
+        drivers: &mut [&mut dyn Driver<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>],
+        dev_addr: DeviceAddress,
+        pipe_id: PipeId,
+        buf: &mut [u8],
+    ) -> Result<usize, AsyncError> {
+        let mut waiter = Waiter::new(dev_addr, pipe_id, Expect::In, buf);
+        // interrupt transfers are initiated by the host controller
+        self.run(drivers, &mut waiter, |_| Ok(())).await
+    }
+
+    /// Start a transfer (retrying while the bus is busy), and poll the host until the `waiter` saw its outcome
+    async fn run(
+        &mut self,
+        drivers: &mut [&mut dyn Driver<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>],
+        waiter: &mut Waiter<'_>,
+        mut start: impl FnMut(
+            &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+        ) -> Result<(), ControlError>,
+    ) -> Result<usize, AsyncError> {
+        let mut started = false;
+        poll_fn(|cx| {
+            if !started {
+                match start(self.host) {
+                    Ok(()) => started = true,
+                    Err(ControlError::WouldBlock) => {}
+                    Err(error) => return Poll::Ready(Err(error.into())),
+                }
+            }
+            self.host.poll(&mut [&mut Observed { waiter: &mut *waiter, drivers: &mut *drivers }]);
+            if let Some(outcome) = waiter.outcome {
+                return Poll::Ready(outcome);
+            }
+            self.wait(cx);
+            Poll::Pending
+        })
+        .await
+    }
+
+    fn wait(&mut self, cx: &mut Context<'_>) {
+        if !self.host.bus.register_waker(cx.waker()) {
+            cx.waker().wake_by_ref();
+        }
+    }
+}
+
+/// Kind of completion a [`Waiter`] waits for
+#[derive(Copy, Clone, PartialEq)]
+enum Expect {
+    Control,
+    In,
+}
+
+/// Observes the driver callbacks for a single pipe, to find out when a transfer completed
+struct Waiter<'b> {
+    dev_addr: DeviceAddress,
+    pipe_id: PipeId,
+    expect: Expect,
+    buf: &'b mut [u8],
+    outcome: Option<Result<usize, AsyncError>>,
+}
+
+impl<'b> Waiter<'b> {
+    fn new(dev_addr: DeviceAddress, pipe_id: PipeId, expect: Expect, buf: &'b mut [u8]) -> Self {
+        Self { dev_addr, pipe_id, expect, buf, outcome: None }
+    }
+
+    fn matches(&self, dev_addr: DeviceAddress, pipe_id: PipeId) -> bool {
+        self.outcome.is_none() && self.dev_addr == dev_addr && self.pipe_id == pipe_id
+    }
+
+    fn complete(&mut self, data: &[u8]) {
+        let len = data.len().min(self.buf.len());
+        self.buf[..len].copy_from_slice(&data[..len]);
+        self.outcome = Some(Ok(len));
+    }
+}
+
+/// Combines a [`Waiter`] with the application's drivers, so that both can be passed to [`UsbHost::poll`]
+///
+/// Interfaces are distributed among the drivers the same way the host does it.
+struct Observed<
+    'a,
+    'b,
+    'd,
+    B: HostBus,
+    const MAX_PIPES: usize,
+    const CONTROL_QUEUE_DEPTH: usize,
+    const CONTROL_BUFFER_SIZE: usize,
+> {
+    waiter: &'a mut Waiter<'b>,
+    drivers: &'a mut [&'d mut dyn Driver<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>],
+}
+
+impl<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    > Driver<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>
+    for Observed<'_, '_, '_, B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>
+{
+    fn attached(&mut self, dev_addr: DeviceAddress, connection_speed: ConnectionSpeed) {
+        for driver in self.drivers.iter_mut() {
+            driver.attached(dev_addr, connection_speed);
+        }
+    }
+
+    fn detached(&mut self, dev_addr: DeviceAddress) {
+        self.detached_with_reason(dev_addr, DetachReason::Unplugged);
+    }
+
+    fn detached_with_reason(&mut self, dev_addr: DeviceAddress, reason: DetachReason) {
+        if self.waiter.outcome.is_none() && self.waiter.dev_addr == dev_addr {
+            self.waiter.outcome = Some(Err(AsyncError::Detached));
+        }
+        for driver in self.drivers.iter_mut() {
+            driver.detached_with_reason(dev_addr, reason);
+        }
+    }
+
+    fn descriptor(&mut self, dev_addr: DeviceAddress, descriptor_type: u8, data: &[u8]) {
+        for driver in self.drivers.iter_mut() {
+            driver.descriptor(dev_addr, descriptor_type, data);
+        }
+    }
+
+    fn descriptor_with_context(&mut self, dev_addr: DeviceAddress, descriptor_type: u8, data: &[u8], context: DescriptorContext) {
+        for driver in self.drivers.iter_mut() {
+            driver.descriptor_with_context(dev_addr, descriptor_type, data, context);
+        }
+    }
+
+    fn discovery_interest(&mut self, dev_addr: DeviceAddress) -> DiscoveryInterest {
+        let mut interest = DiscoveryInterest::NotInterested;
+        for driver in self.drivers.iter_mut() {
+            match driver.discovery_interest(dev_addr) {
+                DiscoveryInterest::ReportDescriptor { interface, length } => return DiscoveryInterest::ReportDescriptor { interface, length },
+                DiscoveryInterest::Undecided => interest = DiscoveryInterest::Undecided,
+                DiscoveryInterest::Ready if interest != DiscoveryInterest::Undecided => interest = DiscoveryInterest::Ready,
+                DiscoveryInterest::Ready | DiscoveryInterest::NotInterested => {}
+            }
+        }
+        interest
+    }
+
+    fn configure(&mut self, dev_addr: DeviceAddress) -> Option<u8> {
+        self.drivers.iter_mut().find_map(|driver| driver.configure(dev_addr))
+    }
+
+    fn configured(
+        &mut self,
+        dev_addr: DeviceAddress,
+        value: u8,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) {
+        let interfaces = self.claim_interfaces(dev_addr, value);
+        self.configured_interfaces(dev_addr, value, interfaces, host);
+    }
+
+    fn claim_interfaces(&mut self, dev_addr: DeviceAddress, value: u8) -> InterfaceSet {
+        self.drivers
+            .iter_mut()
+            .fold(InterfaceSet::EMPTY, |claimed, driver| claimed.union(driver.claim_interfaces(dev_addr, value)))
+    }
+
+    fn configured_interfaces(
+        &mut self,
+        dev_addr: DeviceAddress,
+        value: u8,
+        interfaces: InterfaceSet,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) {
+        // each interface goes to the first driver claiming it
+        let mut remaining = interfaces;
+        for driver in self.drivers.iter_mut() {
+            let granted = driver.claim_interfaces(dev_addr, value).intersection(remaining);
+            remaining = remaining.difference(granted);
+            driver.configured_interfaces(dev_addr, value, granted, host);
+        }
+    }
+
+    fn completed_control(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, data: Option<&[u8]>) {
+        if self.waiter.expect == Expect::Control && self.waiter.matches(dev_addr, pipe_id) {
+            self.waiter.complete(data.unwrap_or(&[]));
+        }
+        for driver in self.drivers.iter_mut() {
+            driver.completed_control(dev_addr, pipe_id, data);
+        }
+    }
+
+    fn completed_in(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, data: &[u8]) {
+        if self.waiter.expect == Expect::In && self.waiter.matches(dev_addr, pipe_id) {
+            self.waiter.complete(data);
+        }
+        for driver in self.drivers.iter_mut() {
+            driver.completed_in(dev_addr, pipe_id, data);
+        }
+    }
+
+    fn completed_out(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, data: &mut [u8]) {
+        for driver in self.drivers.iter_mut() {
+            driver.completed_out(dev_addr, pipe_id, data);
+        }
+    }
+
+    fn completed_bulk_out(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId) {
+        for driver in self.drivers.iter_mut() {
+            driver.completed_bulk_out(dev_addr, pipe_id);
+        }
+    }
+
+    fn stall(&mut self, dev_addr: DeviceAddress) {
+        for driver in self.drivers.iter_mut() {
+            driver.stall(dev_addr);
+        }
+    }
+
+    fn transfer_failed(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, error: TransferError) {
+        if self.waiter.matches(dev_addr, pipe_id) {
+            self.waiter.outcome = Some(Err(AsyncError::Transfer(error)));
+        }
+        for driver in self.drivers.iter_mut() {
+            driver.transfer_failed(dev_addr, pipe_id, error);
+        }
+    }
+
+    fn endpoint_recovered(&mut self, dev_addr: DeviceAddress, endpoint: u8, result: Result<(), TransferError>) {
+        for driver in self.drivers.iter_mut() {
+            driver.endpoint_recovered(dev_addr, endpoint, result);
+        }
+    }
+
+    fn suspended(&mut self) {
+        for driver in self.drivers.iter_mut() {
+            driver.suspended();
+        }
+    }
+
+    fn resumed(&mut self) {
+        for driver in self.drivers.iter_mut() {
+            driver.resumed();
+        }
+    }
+
+    fn sof(&mut self, frame_number: u16) {
+        for driver in self.drivers.iter_mut() {
+            driver.sof(frame_number);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "driver-kbd"))]
+mod tests {
+    use super::*;
+    use crate::bus::mock::fixtures::{enumerated_keyboard, flag_waker, DEVICE_DESCRIPTOR};
+    use core::future::Future;
+    use core::pin::pin;
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use usb_device::control::{Recipient, RequestType};
+    use usb_device::UsbDirection;
+
+    /// Poll the future until it completes, insisting on a wake-up before each new poll
+    fn block_on<F: Future>(future: F, woken: &'static AtomicBool) -> F::Output {
+        let waker = flag_waker(woken);
+        let mut cx = Context::from_waker(&waker);
+        let mut future = pin!(future);
+        for _ in 0..1000 {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            assert!(woken.swap(false, Ordering::Relaxed), "future is pending, but was not woken");
+        }
+        panic!("future did not complete");
+    }
+
+    #[test]
+    fn test_control_in() {
+        static WOKEN: AtomicBool = AtomicBool::new(false);
+        let (mut host, mut kbd, dev_addr) = enumerated_keyboard();
+        let pipe_id = host.create_control_pipe(dev_addr).unwrap();
+        let mut host = UsbHostAsync::new(&mut host);
+        let setup = SetupPacket::new(UsbDirection::In, RequestType::Standard, Recipient::Device, 0x06, 0x0100, 0, 0);
+        let mut buf = [0; 18];
+        let result = block_on(host.control_in(&mut [&mut kbd], dev_addr, pipe_id, setup, &mut buf), &WOKEN);
+        assert!(result == Ok(18));
+        assert!(buf == DEVICE_DESCRIPTOR);
+    }
+}
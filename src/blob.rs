@@ -0,0 +1,215 @@
+//! Retention of raw configuration descriptors, in storage provided by the application
+//!
+//! See [`UsbHost::set_configuration_storage`](crate::UsbHost::set_configuration_storage).
+
+use crate::descriptor;
+use crate::types::DeviceAddress;
+use crate::MAX_DEVICES;
+
+#[derive(Copy, Clone)]
+struct Entry {
+    dev_addr: DeviceAddress,
+    len: usize,
+    /// Set once the active configuration was selected. Until then the entry holds all configurations of the device.
+    selected: bool,
+    /// Set if the descriptors did not fit into the storage
+    overflow: bool,
+}
+
+/// Stores the configuration descriptors of multiple devices back to back
+///
+/// Descriptors are only ever appended for the device that is currently being discovered, which is always the last entry.
+/// Removing an entry moves the ones after it down, so that the free space stays at the end.
+pub struct BlobStorage<'a> {
+    buffer: &'a mut [u8],
+    /// Entries in the order in which they are stored in the buffer
+    entries: [Option<Entry>; MAX_DEVICES],
+}
+
+impl<'a> BlobStorage<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        Self {
+            buffer,
+            entries: [None; MAX_DEVICES],
+        }
+    }
+
+    fn used(&self) -> usize {
+        self.entries.iter().flatten().map(|entry| entry.len).sum()
+    }
+
+    /// Offset and entry for the given device
+    fn find(&self, dev_addr: DeviceAddress) -> Option<(usize, usize, Entry)> {
+        let mut offset = 0;
+        for (index, entry) in self.entries.iter().enumerate() {
+            match entry {
+                Some(entry) if entry.dev_addr == dev_addr => return Some((index, offset, *entry)),
+                Some(entry) => offset += entry.len,
+                None => return None,
+            }
+        }
+        None
+    }
+
+    /// Append descriptors read during discovery of the given device
+    pub fn append(&mut self, dev_addr: DeviceAddress, data: &[u8]) {
+        let used = self.used();
+        let index = match self.find(dev_addr) {
+            Some((index, _, _)) => index,
+            None => {
+                let Some(index) = self.entries.iter().position(|entry| entry.is_none()) else {
+                    return;
+                };
+                self.entries[index] = Some(Entry { dev_addr, len: 0, selected: false, overflow: false });
+                index
+            }
+        };
+        // Unwrap safety: entry was found or created above
+        let entry = self.entries[index].as_mut().unwrap();
+        if entry.overflow {
+            return;
+        }
+        if let Some(target) = self.buffer.get_mut(used..used + data.len()) {
+            target.copy_from_slice(data);
+            entry.len += data.len();
+        } else {
+            entry.overflow = true;
+        }
+    }
+
+    /// Keep only the configuration with the given value, dropping all others
+    ///
+    /// If there is no such configuration (or none is given), the entry is removed.
+    pub fn select(&mut self, dev_addr: DeviceAddress, value: Option<u8>) {
+        let Some((index, offset, entry)) = self.find(dev_addr) else {
+            return;
+        };
+        let range = value.filter(|_| !entry.overflow).and_then(|value| {
+            Self::find_configuration(&self.buffer[offset..offset + entry.len], value)
+        });
+        let Some((start, end)) = range else {
+            self.remove(dev_addr);
+            return;
+        };
+        self.buffer.copy_within(offset + start..offset + end, offset);
+        self.shrink(index, offset, end - start);
+        // Unwrap safety: entry was found above
+        self.entries[index].as_mut().unwrap().selected = true;
+    }
+
+    /// Locate the configuration descriptor with the given value, followed by its interface, endpoint (and other) descriptors
+    fn find_configuration(mut data: &[u8], value: u8) -> Option<(usize, usize)> {
+        let total = data.len();
+        let mut range: Option<(usize, usize)> = None;
+        while let Ok((rest, descriptor)) = descriptor::parse::any_descriptor(data) {
+            let position = total - data.len();
+            if descriptor.descriptor_type == descriptor::TYPE_CONFIGURATION {
+                if let Some((_, end)) = &mut range {
+                    // the next configuration starts here
+                    *end = position;
+                    break;
+                }
+                if let Ok((_, config)) = descriptor::parse::configuration_descriptor(descriptor.data) {
+                    if config.value == value {
+                        range = Some((position, total));
+                    }
+                }
+            }
+            if rest.is_empty() {
+                break;
+            }
+            data = rest;
+        }
+        range
+    }
+
+    /// Shrink the entry at `index` (stored at `offset`) to `len` bytes, moving the entries after it down
+    fn shrink(&mut self, index: usize, offset: usize, len: usize) {
+        let used = self.used();
+        // Unwrap safety: only called for existing entries
+        let entry = self.entries[index].as_mut().unwrap();
+        let old_end = offset + entry.len;
+        entry.len = len;
+        self.buffer.copy_within(old_end..used, offset + len);
+    }
+
+    /// Remove the descriptors of the given device
+    pub fn remove(&mut self, dev_addr: DeviceAddress) {
+        if let Some((index, offset, _)) = self.find(dev_addr) {
+            self.shrink(index, offset, 0);
+            self.entries[index..].rotate_left(1);
+            self.entries[MAX_DEVICES - 1] = None;
+        }
+    }
+
+    /// Forget all devices
+    pub fn clear(&mut self) {
+        self.entries = [None; MAX_DEVICES];
+    }
+
+    /// Returns the selected configuration of the given device
+    pub fn get(&self, dev_addr: DeviceAddress) -> Option<&[u8]> {
+        self.find(dev_addr)
+            .filter(|(_, _, entry)| entry.selected)
+            .map(|(_, offset, entry)| &self.buffer[offset..offset + entry.len])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::num::NonZeroU8;
+
+    fn addr(n: u8) -> DeviceAddress {
+        DeviceAddress(NonZeroU8::new(n).unwrap())
+    }
+
+    /// Configuration descriptor with given value, followed by an interface descriptor
+    fn configuration(value: u8) -> [u8; 18] {
+        [9, 2, 18, 0, 1, value, 0, 0x80, 50, 9, 4, 0, 0, 0, 0xff, 0, 0, 0]
+    }
+
+    #[test]
+    fn test_select_configuration() {
+        let mut buffer = [0; 64];
+        let mut storage = BlobStorage::new(&mut buffer);
+        storage.append(addr(1), &configuration(1));
+        storage.append(addr(1), &configuration(2));
+        assert!(storage.get(addr(1)).is_none());
+        storage.select(addr(1), Some(2));
+        assert!(storage.get(addr(1)) == Some(&configuration(2)[..]));
+    }
+
+    #[test]
+    fn test_remove_compacts() {
+        let mut buffer = [0; 64];
+        let mut storage = BlobStorage::new(&mut buffer);
+        storage.append(addr(1), &configuration(1));
+        storage.select(addr(1), Some(1));
+        storage.append(addr(2), &configuration(3));
+        storage.select(addr(2), Some(3));
+        storage.remove(addr(1));
+        assert!(storage.get(addr(1)).is_none());
+        assert!(storage.get(addr(2)) == Some(&configuration(3)[..]));
+        // the free space is at the end again
+        storage.append(addr(3), &configuration(4));
+        storage.append(addr(3), &configuration(5));
+        storage.select(addr(3), Some(4));
+        assert!(storage.get(addr(3)) == Some(&configuration(4)[..]));
+        assert!(storage.get(addr(2)) == Some(&configuration(3)[..]));
+    }
+
+    #[test]
+    fn test_overflow() {
+        let mut buffer = [0; 20];
+        let mut storage = BlobStorage::new(&mut buffer);
+        storage.append(addr(1), &configuration(1));
+        storage.append(addr(1), &configuration(2));
+        storage.select(addr(1), Some(1));
+        assert!(storage.get(addr(1)).is_none());
+        // a device that did not fit does not take up space
+        storage.append(addr(2), &configuration(1));
+        storage.select(addr(2), Some(1));
+        assert!(storage.get(addr(2)).is_some());
+    }
+}
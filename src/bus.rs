@@ -2,11 +2,38 @@
 //!
 //! In order to use `usbh` on a given device, there must be a [`HostBus`] implementation specific to that device.
 //!
-//! This interface is still evolving, as there is only one (partially complete) implementation so far.
+//! This interface is still evolving. An implementation for the RP2040 is included in the `rp2040` module, when the
+//! `rp2040` feature is enabled. The `stm32-otg` feature enables the `stm32_otg` module, which supports the OTG_FS
+//! controller of STM32F4 / STM32F7 devices, and the `max3421e` feature enables the `max3421e` module, which drives a
+//! MAX3421E host controller via SPI.
+//!
+//! New implementations can be checked against the expectations of the host with the [`conformance`] module.
+//!
+//! Only the [`HostBus`] trait itself is mandatory. It covers the basics: resetting the bus, detecting devices and running
+//! one transaction at a time. Features that not all controllers have are optional capabilities, each with a software
+//! fallback in the host:
+//! - [`InterruptPipeHw`]: interrupt pipes that are polled (or at least buffered) by the controller
+//! - [`SofTimer`]: an interrupt on every start-of-frame
+//! - [`TransferChannels`]: additional channels, running bulk transfers next to the regular one
+//!
+//! A host bus exposes the capabilities it has via [`HostBus::interrupt_pipe_hw`], [`HostBus::sof_timer`] and
+//! [`HostBus::transfer_channels`].
+//!
+//! How persistently NAKed transactions are retried can be tuned with [`HostBus::set_nak_policy`], see [`NakPolicy`].
 //!
 
+pub mod conformance;
+#[cfg(feature = "max3421e")]
+pub mod max3421e;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+#[cfg(feature = "rp2040")]
+pub mod rp2040;
+#[cfg(feature = "stm32-otg")]
+pub mod stm32_otg;
+
 use crate::types::{ConnectionSpeed, DeviceAddress, SetupPacket, TransferType};
-use defmt::Format;
+use crate::HubPort;
 use usb_device::UsbDirection;
 
 /// Interface for host bus hardware
@@ -23,7 +50,7 @@ pub trait HostBus {
     /// If applicable, this is also the point where all interrupts should be enabled that are necessary to generate the
     /// appropriate [`Event`]s when `poll` is called.
     ///
-    /// This method must *not* enable interrupts on start-of-frame. SOF-interrupts are separately controlled by [`HostBus::interrupt_on_sof`].
+    /// This method must *not* enable interrupts on start-of-frame. SOF-interrupts are separately controlled by [`SofTimer::interrupt_on_sof`].
     fn reset_controller(&mut self);
 
     /// Reset the bus, but keep the controller initialized.
@@ -42,10 +69,38 @@ pub trait HostBus {
     /// Check if SOF packets are currently enabled
     fn sof_enabled(&self) -> bool;
 
+    /// Suspend the bus, by no longer sending SOF (for full-speed) or keep-alive (for low-speed) packets
+    ///
+    /// This is called from [`UsbHost::suspend`](crate::UsbHost::suspend). Devices enter suspend mode once they have not seen
+    /// any bus activity for 3 ms. The controller may reduce its own power consumption as well, but must still detect
+    /// remote wakeup signalling (and generate [`Event::Resume`]) as well as device removal.
+    ///
+    /// The default implementation does nothing, so the bus keeps running.
+    fn suspend(&mut self) {}
+
+    /// Start driving resume signalling (K state) on the bus
+    ///
+    /// This is called to resume the bus after it was suspended, or after the device signalled remote wakeup (see [`Event::Resume`]). The host keeps the resume signalling
+    /// going for at least 20 ms, by counting [`Event::Sof`] events. Since no SOF packets are sent in the meantime, the controller
+    /// must keep generating `Sof` events (from a timer, if necessary) while SOF interrupts are enabled.
+    ///
+    /// The default implementation does nothing, which is appropriate for controllers that never generate [`Event::Resume`].
+    fn start_resume(&mut self) {}
+
+    /// Stop resume signalling, by sending an end-of-packet
+    ///
+    /// Afterwards the host calls [`HostBus::enable_sof`] to restart SOF generation, within the 3 ms allowed by the specification.
+    ///
+    /// The default implementation does nothing.
+    fn end_resume(&mut self) {}
+
     /// Set device address, endpoint and transfer type for an upcoming transfer
     ///
     /// A `dev_addr` of `0` is represented as `None`.
     ///
+    /// For control transfers, the `endpoint` is always `0`. For bulk transfers, it is the number of the bulk endpoint,
+    /// and the transfer is initiated with [`HostBus::write_data_in`] or [`HostBus::write_data_out`] directly (without a SETUP packet).
+    ///
     /// This method is always called before a transfer is initiated. It must have effect for all future transactions (`SETUP`, `DATA`, ...),
     /// until `set_recipient` is called again.
     fn set_recipient(
@@ -55,12 +110,37 @@ pub trait HostBus {
         transfer_type: TransferType,
     );
 
+    /// Enable/disable sending a PRE packet before each transaction, so that it reaches a low speed device behind a full speed hub
     fn ls_preamble(&mut self, enabled: bool);
 
+    /// Set the hub port through which the recipient of upcoming transfers is attached, if it is a low speed device behind a hub
+    ///
+    /// This method is called right after every [`set_recipient`](HostBus::set_recipient) call. While `hub` is `Some`,
+    /// transactions must be sent at low speed, preceded by a PRE packet. Controllers which address such devices
+    /// differently (e.g. with split transactions) can use the hub address and port.
+    ///
+    /// The default implementation enables [`ls_preamble`](HostBus::ls_preamble) while `hub` is `Some`.
+    fn set_low_speed_hub(&mut self, hub: Option<HubPort>) {
+        self.ls_preamble(hub.is_some());
+    }
+
+    /// Set the maximum packet size of endpoint zero of the recipient of upcoming control transfers
+    ///
+    /// This method is called after every [`set_recipient`](HostBus::set_recipient) call for a control transfer, with the
+    /// `bMaxPacketSize0` of the device (8, 16, 32 or 64). Until the host has learned it (with a GET_DESCRIPTOR request
+    /// for the first 8 bytes of the device descriptor), it passes 8.
+    ///
+    /// Controllers which split data stages into packets must use this size, and treat any shorter packet as the end of
+    /// an IN data stage.
+    ///
+    /// The default implementation does nothing, for controllers which do not need to know the packet size.
+    fn set_max_packet_size_0(&mut self, _max_packet_size: u8) {}
+
     /// Stop current transaction, if there is one in progress
     ///
     /// This will be called if a `RxTimeout` is encountered, to prevent the transaction from being
-    /// retried indefinitely.
+    /// retried indefinitely, and when a transfer is cancelled (see [`UsbHost::cancel_transfer`](crate::UsbHost::cancel_transfer)).
+    /// A completion reported for the stopped transaction afterwards results in [`HostError::UnexpectedCompletion`](crate::HostError::UnexpectedCompletion).
     fn stop_transaction(&mut self);
 
     /// Write a SETUP packet to the bus
@@ -74,6 +154,9 @@ pub trait HostBus {
 
     /// Write a DATA IN packet to the bus, then receive `length` bytes
     ///
+    /// The `pid` determines the data PID expected for the first packet: `true` for DATA1, `false` for DATA0.
+    /// If the data is received in multiple packets, the PID toggles with each packet.
+    ///
     /// Once all data has been received, a [`Event::TransComplete`] must be generated.
     ///
     /// Afterwards the received data must be accessible via [`received_data`](HostBus::received_data).
@@ -81,12 +164,17 @@ pub trait HostBus {
 
     /// Write a DATA OUT packet to the bus, after loading the given `data` into the output buffer
     ///
+    /// The `pid` determines the data PID of the first packet: `true` for DATA1, `false` for DATA0.
+    ///
     /// Once all data has been sent, a [`Event::TransComplete`] must be generated.
     ///
+    /// If `data` is empty, a single zero-length packet (ZLP) is sent. This is how the host issues ZLPs: both for the
+    /// status stage of control IN transfers, and to terminate bulk OUT transfers (see [`ZlpPolicy`](crate::types::ZlpPolicy)).
+    ///
     /// The default implementation is a wrapper around [`HostBus::prepare_data_out`] followed by [`HostBus::write_data_out_prepared`].
-    fn write_data_out(&mut self, data: &[u8]) {
+    fn write_data_out(&mut self, data: &[u8], pid: bool) {
         self.prepare_data_out(data);
-        self.write_data_out_prepared();
+        self.write_data_out_prepared(pid);
     }
 
     /// Load the given `data` into the output buffer
@@ -102,14 +190,48 @@ pub trait HostBus {
     ///
     /// The data sent will have been passed to [`HostBus::prepare_data_out`] before this call.
     ///
+    /// The `pid` determines the data PID of the first packet: `true` for DATA1, `false` for DATA0.
+    /// If the data is sent in multiple packets, the PID toggles with each packet.
+    ///
+    /// The host bus must not append a zero-length packet on its own, even if the data is an exact multiple of the maximum
+    /// packet size. When one is needed, the host issues it with a separate [`write_data_out`](HostBus::write_data_out) call.
+    ///
     /// Once all data has been sent, a [`Event::TransComplete`] must be generated.
-    fn write_data_out_prepared(&mut self);
+    fn write_data_out_prepared(&mut self, pid: bool);
+
+    /// Maximum number of bytes that can be received in the data stage of a control IN transfer, with a single call to [`write_data_in`](HostBus::write_data_in)
+    ///
+    /// For control IN transfers with a larger data stage, the host requests the data one packet at a time (i.e. `write_data_in` is called
+    /// with at most the maximum packet size of the device's endpoint zero), and reassembles it in its own buffer.
+    ///
+    /// The default is 64 bytes, which is the largest packet size allowed for control endpoints of full speed devices. Controllers which can
+    /// handle multiple packets in hardware can return a larger value.
+    fn max_control_data_size(&self) -> u16 {
+        64
+    }
 
     /// Check if there is an event pending on the bus, if there is return it.
     ///
     /// This will be called whenever application code calls [`crate::UsbHost::poll`].
     fn poll(&mut self) -> Option<Event>;
 
+    /// Hand over all pending events, in the order in which they occurred
+    ///
+    /// This will be called whenever application code calls [`crate::UsbHost::poll`], which then processes all of the events in order.
+    ///
+    /// The `handler` returns `false` if it cannot accept any more events during this call. In that case, the event that was
+    /// passed to it (and any further events) must be kept pending, and handed over on the next call.
+    ///
+    /// Controllers which coalesce multiple hardware events into a single interrupt should implement this method,
+    /// instead of keeping an internal queue that is drained one event per [`poll`](HostBus::poll).
+    ///
+    /// The default implementation hands over a single event, as returned by [`poll`](HostBus::poll).
+    fn poll_many(&mut self, handler: &mut dyn FnMut(Event) -> bool) {
+        if let Some(event) = self.poll() {
+            handler(event);
+        }
+    }
+
     /// Access the input buffer for a recent transfer
     ///
     /// This method will be called after the host bus completed a DATA IN transfer, as signaled by `Event::TransComplete`.
@@ -117,9 +239,78 @@ pub trait HostBus {
     /// The given `length` will be equal to the `length` passed to the most recent `write_data_in` call.
     ///
     /// The returned buffer *should* be exactly `length` bytes long. It *may* also be smaller though, if `length` exceeds
-    /// the maximum buffer size that the host bus supports.
+    /// the maximum buffer size that the host bus supports, or if the device sent less data (a short packet).
+    ///
+    /// The data only needs to stay valid until the next transaction is started: the host copies the data of control
+    /// transfers into its own buffer right away.
     fn received_data(&self, length: usize) -> &[u8];
 
+    /// Access the hardware management of interrupt pipes, if the controller has it
+    ///
+    /// The default implementation returns `None`. The host then runs interrupt transfers itself, see [`InterruptPipeHw`].
+    fn interrupt_pipe_hw(&mut self) -> Option<&mut dyn InterruptPipeHw> {
+        None
+    }
+
+    /// Access the start-of-frame interrupt, if the controller has it
+    ///
+    /// The default implementation returns `None`. The host then derives `Sof` events from the frame number, see [`SofTimer`].
+    fn sof_timer(&mut self) -> Option<&mut dyn SofTimer> {
+        None
+    }
+
+    /// Access additional channels for bulk transfers, if the controller has them
+    ///
+    /// The default implementation returns `None`. Bulk transfers then share the single transfer path with control
+    /// transfers, see [`TransferChannels`].
+    fn transfer_channels(&mut self) -> Option<&mut dyn TransferChannels> {
+        None
+    }
+
+    /// Set how NAKed transactions are retried, for the transactions started by the transfer methods of this trait (`None`),
+    /// or for the interrupt pipe with the given `pipe_ref` (see [`InterruptPipeHw`])
+    ///
+    /// The policy stays in effect until it is changed again, or the controller is reset. Once a transaction was NAKed more
+    /// often than the retry limit allows, the bus stops it and generates [`Event::Error`] with [`Error::NakLimit`].
+    /// Buses apply the retry interval as far as their hardware can time it (e.g. rounded to whole frames).
+    ///
+    /// Returns `false` if the bus cannot apply the policy (or parts of it). The default implementation does nothing, and
+    /// returns `false`: NAKed transactions are then retried indefinitely, as described for each of the transfer methods.
+    fn set_nak_policy(&mut self, _pipe_ref: Option<u8>, _policy: NakPolicy) -> bool {
+        false
+    }
+
+    /// Current frame number, as sent in the most recent start-of-frame packet (11 bits)
+    ///
+    /// This is used by the host to keep track of time, see [`UsbHost::frame_count`](crate::UsbHost::frame_count).
+    ///
+    /// The default implementation returns `None`, indicating that the controller does not report frame numbers.
+    fn frame_number(&self) -> Option<u16> {
+        None
+    }
+
+    /// Register a waker, to be woken when the controller has new events (i.e. on the next interrupt)
+    ///
+    /// Used by the [`asynch`](crate::asynch) API. Only the most recently registered waker needs to be kept.
+    ///
+    /// The default implementation returns `false`, indicating that wakers are not supported. The futures then wake
+    /// themselves immediately, so the executor keeps polling them.
+    fn register_waker(&mut self, _waker: &core::task::Waker) -> bool {
+        false
+    }
+}
+
+/// Optional capability: interrupt pipes managed by the host bus
+///
+/// Controllers which can poll interrupt endpoints on their own (or at least keep a buffer per endpoint) implement this
+/// trait, and return `Some(self)` from [`HostBus::interrupt_pipe_hw`].
+///
+/// Without it, the host runs interrupt transfers itself: once the interval of a pipe has elapsed, and no other transfer is in
+/// progress, it starts a single transaction with [`HostBus::set_recipient`] (using [`TransferType::Interrupt`]) followed by
+/// [`HostBus::write_data_in`] or [`HostBus::write_data_out`]. A transaction that was not completed by the next frame (because
+/// the device NAKed it) is stopped with [`HostBus::stop_transaction`], and tried again after the next interval. This requires
+/// the host to count frames, so SOF interrupts stay enabled while such pipes exist.
+pub trait InterruptPipeHw {
     /// Create an interrupt pipe
     ///
     /// Interrupt pipes are managed by the host bus.
@@ -131,26 +322,21 @@ pub trait HostBus {
     ///      (the device may reply with `NAK` any number of times while there is no data - this must not generate any events)
     ///    - if the `direction` is `Out`: the event is generated when the latest data has been sent out, and new data
     ///      can be placed in the buffer
-    /// 3. in response to the `InterruptPipe` event the host will call driver callbacks as necessary.
-    ///    As soon as the host is done with the buffer, it will call `pipe_continue`:
+    /// 3. in response to the `InterruptPipe` event the host will access the pipe's buffer via `interrupt_buffer`, and call
+    ///    driver callbacks as necessary. As soon as the host is done with the buffer, it will call `pipe_continue`:
     ///    - if the `direction` is `In`: the host bus can now re-use the buffer, and wait for the next transfer from the device
     ///    - if the `direction` is `Out`: the driver has placed data into the buffer, and the host bus can transmit it
     /// 4. Finally, if the device disconnects (or a pipe is no longer needed), `release_interrupt_pipe` is called.
     ///
-    /// The returned `InterruptPipe` contains two things:
-    /// - `bus_ref`: this is a reference, used to associate `InterruptPipe` events as well as `pipe_continue` and `release_interrupt_pipe` calls to a particular pipe
-    /// - `ptr`: pointer to the buffer used by this pipe. This is described below.
-    ///
-    /// ## Buffer pointer
+    /// The returned `InterruptPipe` contains the `bus_ref`: this is a reference, used to associate `InterruptPipe` events as well as
+    /// `interrupt_buffer`, `pipe_continue` and `release_interrupt_pipe` calls to a particular pipe.
     ///
-    /// The buffer pointer returned for this InterruptPipe must:
-    /// - point to a region with at least `size` bytes available
-    /// - be valid at least until `release_interrupt_pipe` is called with the corresponding pipe ref
+    /// The `interval` is given in frames (i.e. milliseconds), already resolved against the speed of the device
+    /// (see [`PollingInterval`](crate::types::PollingInterval)). It is at least 1. Host buses which cannot poll pipes at
+    /// that interval on their own can leave the scheduling to the host (see [`schedules_interrupt_pipes`](InterruptPipeHw::schedules_interrupt_pipes)).
     ///
-    /// Between any `Event::InterruptPipe` generated by the host bus, and the next corresponding call to `pipe_continue`,
-    /// the host bus must not access or modify the buffer.
-    ///
-    /// For `In` pipes, the host will only read from this buffer, for `Out` pipes it will only write to it.
+    /// Each pipe needs a buffer of (at least) `size` bytes, owned by the host bus. See [`interrupt_buffer`](InterruptPipeHw::interrupt_buffer)
+    /// for how it is shared with the host.
     ///
     fn create_interrupt_pipe(
         &mut self,
@@ -161,17 +347,84 @@ pub trait HostBus {
         interval: u8,
     ) -> Option<InterruptPipe>;
 
+    /// Create an interrupt pipe for a low speed device, attached to the given port of a (full speed) hub
+    ///
+    /// Same as [`create_interrupt_pipe`](InterruptPipeHw::create_interrupt_pipe), except that every transaction on the pipe
+    /// must be sent at low speed, preceded by a PRE packet (see [`set_low_speed_hub`](HostBus::set_low_speed_hub)).
+    ///
+    /// The default implementation returns `None`, indicating that the controller does not support this.
+    fn create_low_speed_interrupt_pipe(
+        &mut self,
+        _device_address: DeviceAddress,
+        _endpoint_number: u8,
+        _direction: UsbDirection,
+        _size: u16,
+        _interval: u8,
+        _hub: HubPort,
+    ) -> Option<InterruptPipe> {
+        None
+    }
+
     /// Release a pipe created with `create_interrupt_pipe`
     ///
     /// After a pipe is released, the `pipe_ref` as well as the buffer used by the pipe can be re-used.
     fn release_interrupt_pipe(&mut self, pipe_ref: u8);
 
+    /// Access the buffer of an interrupt pipe
+    ///
+    /// The host only calls this between an `Event::InterruptPipe` for the pipe and the next call to `pipe_continue`. In that
+    /// time the host bus must not access or modify the buffer itself. For `In` pipes, the host reads the data received from
+    /// the device, for `Out` pipes it places the data to be sent next.
+    ///
+    /// The returned slice must be exactly `size` bytes long (as given to `create_interrupt_pipe`). Returns `None` if there
+    /// is no pipe with the given `pipe_ref`.
+    fn interrupt_buffer(&mut self, pipe_ref: u8) -> Option<&mut [u8]>;
+
     /// Signal that a pipe can continue transfers
     ///
     /// For an `In` pipe this is called after the driver(s) have consumed the data.
     /// For an `Out` pipe this is called after new data has been placed in the buffer .
     fn pipe_continue(&mut self, pipe_ref: u8);
 
+    /// Set the data PID for the next packet on an interrupt pipe (`true` for DATA1)
+    ///
+    /// The host tracks the data toggle of each pipe itself: it calls this method before every call to `pipe_continue`,
+    /// and whenever the toggle is reset (after `SET_CONFIGURATION`, or clearing the halt condition of the endpoint).
+    /// A packet that was NAKed or not acknowledged must be retried with the same PID.
+    ///
+    /// The default implementation does nothing, leaving the data toggle to the hardware.
+    fn set_pipe_data_toggle(&mut self, _pipe_ref: u8, _data_toggle: bool) {}
+
+    /// Whether the host bus polls interrupt pipes on its own, at the interval given to `create_interrupt_pipe`
+    ///
+    /// Host buses returning `false` must implement [`poll_interrupt_pipe`](InterruptPipeHw::poll_interrupt_pipe). The host then
+    /// keeps SOF interrupts enabled while any interrupt pipes exist, and counts frames to start a transaction on each pipe
+    /// once its interval has elapsed.
+    fn schedules_interrupt_pipes(&self) -> bool {
+        true
+    }
+
+    /// Start a single transaction on the given interrupt pipe
+    ///
+    /// Only called if [`schedules_interrupt_pipes`](InterruptPipeHw::schedules_interrupt_pipes) returns `false`, once per interval
+    /// of the pipe. If the data was accepted (or received), the bus generates an `InterruptPipe` event like usual. NAKs
+    /// are not retried, the pipe is polled again after the next interval.
+    ///
+    /// Calls for pipes that are still waiting for `pipe_continue` must be ignored. If another transaction is in progress,
+    /// the bus should start this one as soon as possible.
+    fn poll_interrupt_pipe(&mut self, _pipe_ref: u8) {}
+}
+
+/// Optional capability: interrupt on start-of-frame
+///
+/// Controllers which can generate an [`Event::Sof`] for every frame implement this trait, and return `Some(self)` from
+/// [`HostBus::sof_timer`].
+///
+/// Without it, the host derives `Sof` events from the [frame number](HostBus::frame_number): while it would have enabled SOF
+/// interrupts, every frame that passed between two calls to [`UsbHost::poll`](crate::UsbHost::poll) is processed as a
+/// start-of-frame. Controllers which have neither rely on the application to keep time, by calling
+/// [`UsbHost::tick_1ms`](crate::UsbHost::tick_1ms) every millisecond.
+pub trait SofTimer {
     /// Enable/disable interrupt on SOF
     ///
     /// While enabled, the host bus should generate (call `poll` on the hsot) whenever
@@ -183,22 +436,85 @@ pub trait HostBus {
     fn interrupt_on_sof(&mut self, enable: bool);
 }
 
+/// Optional capability: additional channels for bulk transfers
+///
+/// Controllers with several host channels can have transactions on different endpoints outstanding at the same time.
+/// Such controllers implement this trait, and return `Some(self)` from [`HostBus::transfer_channels`].
+///
+/// The host then runs bulk transfers on these channels, while control transfers (and everything else) keep using the
+/// transfer methods of [`HostBus`]. This way a bulk endpoint that keeps NAKing (e.g. a serial adapter waiting for data)
+/// does not block control transfers to other devices. Each channel runs one transfer at a time. The controller is
+/// expected to retry NAKed transactions on its own, and to split the transfer into packets.
+///
+/// Without it, only a single transfer can be in progress at a time.
+pub trait TransferChannels {
+    /// Number of channels available for bulk transfers (numbered from 0)
+    fn channel_count(&self) -> u8;
+
+    /// Start a bulk IN transfer on the given channel, receiving up to `length` bytes
+    ///
+    /// The `pid` is the data toggle of the first packet (`true` for DATA1). Once the device sent a short packet, or `length`
+    /// bytes were received, the controller generates [`Event::ChannelComplete`]. The data is then accessed via
+    /// [`channel_data`](TransferChannels::channel_data).
+    fn start_bulk_in(&mut self, channel: u8, dev_addr: DeviceAddress, endpoint: u8, max_packet_size: u16, length: u16, pid: bool);
+
+    /// Start a bulk OUT transfer on the given channel, sending the given `data`
+    ///
+    /// The `pid` is the data toggle of the first packet. Once all of the data was sent, the controller generates
+    /// [`Event::ChannelComplete`].
+    fn start_bulk_out(&mut self, channel: u8, dev_addr: DeviceAddress, endpoint: u8, max_packet_size: u16, data: &[u8], pid: bool);
+
+    /// Abort the transfer in progress on the given channel, without generating an event for it
+    fn stop_channel(&mut self, channel: u8);
+
+    /// Data received by the most recent bulk IN transfer on the given channel, limited to `length` bytes
+    fn channel_data(&self, channel: u8, length: usize) -> &[u8];
+}
+
+/// How NAKed transactions are retried, see [`HostBus::set_nak_policy`]
+///
+/// Devices answer with NAK while they are not ready to send or receive data. Some of them do so for a long time (e.g. a
+/// serial adapter waiting for input), and retrying them back-to-back can keep the bus from doing other work.
+/// The default policy leaves retries to the bus, without a limit.
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct NakPolicy {
+    /// Number of NAKs after which a transaction is given up, or `None` to retry indefinitely
+    pub retry_limit: Option<u16>,
+    /// Time to wait before retrying a NAKed transaction, in microseconds, or `None` for the bus' default
+    pub retry_interval: Option<u16>,
+}
+
+impl NakPolicy {
+    /// Retry indefinitely, at the bus' default pace
+    pub const UNLIMITED: NakPolicy = NakPolicy { retry_limit: None, retry_interval: None };
+
+    /// Give up after the given number of NAKs
+    pub const fn with_retry_limit(self, retry_limit: u16) -> Self {
+        Self { retry_limit: Some(retry_limit), ..self }
+    }
+
+    /// Wait the given number of microseconds between retries
+    pub const fn with_retry_interval(self, retry_interval: u16) -> Self {
+        Self { retry_interval: Some(retry_interval), ..self }
+    }
+}
+
 /// Result from `create_interrupt_pipe`
+#[derive(Debug)]
 pub struct InterruptPipe {
-    /// Pointer to the buffer for this pipe
-    ///
-    /// See documentation for [`create_interrupt_pipe`](HostBus::create_interrupt_pipe) for details on how this is used.
-    pub ptr: *mut u8,
     /// Reference for this pipe generated by the host bus
     ///
-    /// This reference is used in three places:
+    /// This reference is used in these places:
     /// - in the [`Event::InterruptPipe`] event (generated by the host bus)
-    /// - passed to [`pipe_continue`](HostBus::pipe_continue)
-    /// - passed to [`release_interrupt_pipe`](HostBus::release_interrupt_pipe)
+    /// - passed to [`interrupt_buffer`](InterruptPipeHw::interrupt_buffer)
+    /// - passed to [`pipe_continue`](InterruptPipeHw::pipe_continue)
+    /// - passed to [`release_interrupt_pipe`](InterruptPipeHw::release_interrupt_pipe)
     pub bus_ref: u8,
 }
 
-#[derive(Copy, Clone, Format, PartialEq)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Event {
     /// A new device was attached, with given speed
     Attached(ConnectionSpeed),
@@ -208,7 +524,9 @@ pub enum Event {
     TransComplete,
     /// Device sent a STALL. This usually means that the device does not understand our communication
     Stall,
-    /// Device has resumed from sleep?
+    /// The device signalled remote wakeup (K state on the bus), while the bus was suspended
+    ///
+    /// The host responds by driving resume signalling itself, see [`HostBus::start_resume`].
     Resume,
     /// An error has occured (details in the Error)
     Error(Error),
@@ -218,11 +536,19 @@ pub enum Event {
     ///
     /// This event must only be generated while start-of-frame interrupts are enabled.
     ///
-    /// See [`HostBus::interrupt_on_sof`] for details.
+    /// See [`SofTimer::interrupt_on_sof`] for details.
     Sof,
+    /// The bulk transfer on the given channel has completed (see [`TransferChannels`])
+    ChannelComplete(u8),
+    /// The device sent a STALL during the bulk transfer on the given channel, which was aborted
+    ChannelStall(u8),
+    /// The bulk transfer on the given channel was aborted because of an error
+    ChannelError(u8, Error),
 }
 
-#[derive(Copy, Clone, Format, PartialEq)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Error {
     /// CRC mismatch
     Crc,
@@ -234,6 +560,22 @@ pub enum Error {
     RxTimeout,
     /// Data sequence error. Saw DATA0 when expecting DATA1 or vice versa.
     DataSequence,
+    /// The device kept answering with NAK, until the retry limit of the [`NakPolicy`] was reached
+    NakLimit,
     /// None of the above. Hardware specific error condition.
     Other,
 }
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Error::Crc => "CRC error",
+            Error::BitStuffing => "bit stuffing error",
+            Error::RxOverflow => "receive overflow",
+            Error::RxTimeout => "receive timeout",
+            Error::DataSequence => "data sequence error",
+            Error::NakLimit => "NAK retry limit reached",
+            Error::Other => "bus error",
+        })
+    }
+}
@@ -0,0 +1,378 @@
+//! Conformance checks for [`HostBus`] implementations
+//!
+//! When porting `usbh` to a new host controller, [`Conformance`] can be used to check that the implementation behaves the
+//! way the [`UsbHost`](crate::UsbHost) expects it to. It drives the bus directly (without a `UsbHost`), and checks:
+//!
+//! - reset: SOF packets are not generated after [`HostBus::reset_controller`], an attached device is reported
+//! - start-of-frame: [`HostBus::enable_sof`], SOF events only while
+//!   [`SofTimer::interrupt_on_sof`](super::SofTimer::interrupt_on_sof) is enabled, advancing frame numbers (the events
+//!   are only checked if the bus has a [`SofTimer`](super::SofTimer))
+//! - control transfers: the SETUP, DATA IN and status stages of a `GET_DESCRIPTOR` request each complete, and the received
+//!   data is a device descriptor
+//! - interrupt pipes: pipes are created with distinct references and buffers, and released again (if the bus has
+//!   [`InterruptPipeHw`](super::InterruptPipeHw))
+//!
+//! The checks need a device to talk to, so any USB device must be attached to the port. Since the checks are based on
+//! polling the bus, they are best run in the main loop, with the USB interrupt disabled:
+//!
+//! ```ignore
+//! let mut conformance = Conformance::new(&mut host_bus);
+//! conformance.set_poll_limit(10_000_000);
+//! let report = conformance.run();
+//! for violation in report.violations() {
+//!     defmt::error!("{}", violation);
+//! }
+//! assert!(report.is_ok());
+//! ```
+
+use super::{Event, HostBus};
+use crate::descriptor;
+use crate::frame::FRAME_NUMBER_MASK;
+use crate::types::{DeviceAddress, SetupPacket, TransferType};
+use core::num::NonZeroU8;
+use usb_device::{
+    control::{Recipient, Request, RequestType},
+    UsbDirection,
+};
+
+/// Maximum number of violations that are recorded in a [`Report`]
+pub const MAX_VIOLATIONS: usize = 16;
+
+/// Number of start-of-frame events to wait for, while checking SOF behavior
+///
+/// This also gives the device the time it needs to recover from the bus reset (10ms).
+const SOF_FRAMES: u16 = 20;
+/// Number of interrupt pipes that are created at the same time
+const PIPES: usize = 2;
+
+/// Stage of the control transfer, in which a violation occurred
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Stage {
+    Setup,
+    DataIn,
+    Status,
+}
+
+/// Deviation of the host bus from the expected behavior
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Violation {
+    /// `sof_enabled` returned `true` after `reset_controller`
+    SofEnabledAfterReset,
+    /// No `Attached` event was generated within the poll limit
+    ///
+    /// The remaining checks are skipped in this case.
+    NoDevice,
+    /// `sof_enabled` returned `false` after `enable_sof`
+    SofNotEnabled,
+    /// No `Sof` events were generated, while SOF interrupts were enabled
+    NoSof,
+    /// A `Sof` event was generated, while SOF interrupts were disabled
+    SofWhileDisabled,
+    /// `frame_number` returned the same frame number for two consecutive `Sof` events
+    FrameNumberStuck,
+    /// A stage of the control transfer did not result in `TransComplete`
+    ///
+    /// Contains the event that was generated instead, or `None` if no event was generated within the poll limit.
+    Transfer(Stage, Option<Event>),
+    /// `received_data` returned more data than requested
+    ReceivedTooMuch(usize),
+    /// The data received during the DATA IN stage is not (the beginning of) a device descriptor
+    InvalidDescriptor,
+    /// `create_interrupt_pipe` returned `None`
+    PipeRefused,
+    /// Two pipes were created with the same `bus_ref`
+    DuplicatePipeRef(u8),
+    /// Two pipes were created with the same buffer
+    SharedPipeBuffer,
+    /// The buffer of a pipe could not be accessed with `interrupt_buffer`
+    MissingPipeBuffer(u8),
+    /// The buffer of a pipe does not have the size the pipe was created with
+    PipeBufferSize(usize),
+    /// A pipe could not be created again, after all pipes were released
+    PipeNotReleased,
+    /// An `InterruptPipe` event was generated for a pipe that was released
+    EventForReleasedPipe(u8),
+}
+
+/// Outcome of [`Conformance::run`]
+#[derive(Debug)]
+pub struct Report {
+    violations: [Option<Violation>; MAX_VIOLATIONS],
+    violation_count: usize,
+    checks: u16,
+}
+
+impl Report {
+    /// Returns `true` if no violations were found
+    pub fn is_ok(&self) -> bool {
+        self.violation_count == 0
+    }
+
+    /// Violations that were found, in the order in which they occurred
+    ///
+    /// At most [`MAX_VIOLATIONS`] are recorded.
+    pub fn violations(&self) -> impl Iterator<Item = Violation> + '_ {
+        self.violations.iter().flatten().copied()
+    }
+
+    /// Number of checks that were performed
+    pub fn checks(&self) -> u16 {
+        self.checks
+    }
+
+    fn check(&mut self, condition: bool, violation: Violation) -> bool {
+        self.checks += 1;
+        if !condition {
+            if let Some(slot) = self.violations.get_mut(self.violation_count) {
+                *slot = Some(violation);
+                self.violation_count += 1;
+            }
+        }
+        condition
+    }
+}
+
+/// Runs conformance checks against a [`HostBus`] implementation
+///
+/// See the [module documentation](self) for details.
+#[derive(Debug)]
+pub struct Conformance<'b, B: HostBus> {
+    bus: &'b mut B,
+    poll_limit: u32,
+}
+
+impl<'b, B: HostBus> Conformance<'b, B> {
+    pub fn new(bus: &'b mut B) -> Self {
+        Self { bus, poll_limit: 100_000 }
+    }
+
+    /// Set the number of times the bus is polled while waiting for an event, before giving up
+    ///
+    /// Since `poll` usually returns immediately, this needs to be large enough to cover a few milliseconds on the target.
+    /// Defaults to `100_000`.
+    pub fn set_poll_limit(&mut self, poll_limit: u32) {
+        self.poll_limit = poll_limit;
+    }
+
+    /// Run all checks
+    ///
+    /// The bus is left with the controller reset, and may be passed to [`UsbHost::new`](crate::UsbHost::new) afterwards.
+    pub fn run(&mut self) -> Report {
+        let mut report = Report { violations: [None; MAX_VIOLATIONS], violation_count: 0, checks: 0 };
+        if self.check_reset(&mut report) {
+            self.check_sof(&mut report);
+            self.check_control_transfer(&mut report);
+            self.check_interrupt_pipes(&mut report);
+        }
+        self.bus.reset_controller();
+        report
+    }
+
+    fn check_reset(&mut self, report: &mut Report) -> bool {
+        self.bus.reset_controller();
+        if let Some(timer) = self.bus.sof_timer() {
+            timer.interrupt_on_sof(false);
+        }
+        report.check(!self.bus.sof_enabled(), Violation::SofEnabledAfterReset);
+        let mut sof = false;
+        let attached = self.wait_for(|event| {
+            sof |= event == Event::Sof;
+            matches!(event, Event::Attached(_))
+        });
+        report.check(!sof, Violation::SofWhileDisabled);
+        report.check(attached.is_some(), Violation::NoDevice)
+    }
+
+    fn check_sof(&mut self, report: &mut Report) {
+        self.bus.reset_bus();
+        self.bus.enable_sof();
+        report.check(self.bus.sof_enabled(), Violation::SofNotEnabled);
+
+        let Some(timer) = self.bus.sof_timer() else {
+            // without SOF events, the time the device needs to recover from the reset is taken from the frame number
+            let start = self.bus.frame_number();
+            for _ in 0..self.poll_limit {
+                self.bus.poll();
+                if let (Some(start), Some(now)) = (start, self.bus.frame_number()) {
+                    if now.wrapping_sub(start) & FRAME_NUMBER_MASK >= SOF_FRAMES {
+                        break;
+                    }
+                }
+            }
+            return;
+        };
+        timer.interrupt_on_sof(true);
+        let mut frames = 0;
+        let mut stuck = false;
+        let mut last_frame = None;
+        for _ in 0..self.poll_limit {
+            if self.bus.poll() == Some(Event::Sof) {
+                let frame = self.bus.frame_number();
+                stuck |= frame.is_some() && frame == last_frame;
+                last_frame = frame;
+                frames += 1;
+                if frames == SOF_FRAMES {
+                    break;
+                }
+            }
+        }
+        report.check(frames > 0, Violation::NoSof);
+        report.check(!stuck, Violation::FrameNumberStuck);
+
+        if let Some(timer) = self.bus.sof_timer() {
+            timer.interrupt_on_sof(false);
+        }
+        // the event for a frame that started just before disabling may still be pending
+        self.bus.poll();
+        let sof = self.wait_for(|event| event == Event::Sof);
+        report.check(sof.is_none(), Violation::SofWhileDisabled);
+    }
+
+    fn check_control_transfer(&mut self, report: &mut Report) {
+        const LENGTH: u16 = 8;
+        self.bus.set_recipient(None, 0, TransferType::Control);
+        self.bus.write_setup(SetupPacket::new(
+            UsbDirection::In,
+            RequestType::Standard,
+            Recipient::Device,
+            Request::GET_DESCRIPTOR,
+            (descriptor::TYPE_DEVICE as u16) << 8,
+            0,
+            LENGTH,
+        ));
+        if !self.check_complete(report, Stage::Setup) {
+            return;
+        }
+
+        self.bus.write_data_in(LENGTH, true);
+        if !self.check_complete(report, Stage::DataIn) {
+            return;
+        }
+        let received = self.bus.received_data(LENGTH as usize).len();
+        report.check(received <= LENGTH as usize, Violation::ReceivedTooMuch(received));
+        let data = self.bus.received_data(LENGTH as usize);
+        report.check(data.len() >= 2 && data[0] == 18 && data[1] == descriptor::TYPE_DEVICE, Violation::InvalidDescriptor);
+
+        self.bus.write_data_out(&[], true);
+        self.check_complete(report, Stage::Status);
+    }
+
+    fn check_interrupt_pipes(&mut self, report: &mut Report) {
+        let dev_addr = DeviceAddress(NonZeroU8::MIN);
+        let Some(hw) = self.bus.interrupt_pipe_hw() else {
+            return;
+        };
+        let mut pipes = [None; PIPES];
+        for (i, pipe) in pipes.iter_mut().enumerate() {
+            let created = hw.create_interrupt_pipe(dev_addr, i as u8 + 1, UsbDirection::In, 8, 10);
+            if !report.check(created.is_some(), Violation::PipeRefused) {
+                break;
+            }
+            *pipe = created.map(|pipe| pipe.bus_ref);
+        }
+        let created = || pipes.iter().flatten();
+        for (i, bus_ref) in created().enumerate() {
+            report.check(!created().skip(i + 1).any(|other| other == bus_ref), Violation::DuplicatePipeRef(*bus_ref));
+            // no device is attached, so the buffers are not in use by the bus
+            if let Some(buf) = hw.interrupt_buffer(*bus_ref) {
+                report.check(buf.len() == 8, Violation::PipeBufferSize(buf.len()));
+                buf.fill(*bus_ref);
+            } else {
+                report.check(false, Violation::MissingPipeBuffer(*bus_ref));
+            }
+        }
+        for bus_ref in created() {
+            let intact = hw.interrupt_buffer(*bus_ref).is_none_or(|buf| buf.iter().all(|byte| byte == bus_ref));
+            report.check(intact, Violation::SharedPipeBuffer);
+        }
+        for bus_ref in created() {
+            hw.release_interrupt_pipe(*bus_ref);
+        }
+
+        let released = |bus_ref| created().any(|pipe| *pipe == bus_ref);
+        let mut unexpected = None;
+        self.wait_for(|event| {
+            if let Event::InterruptPipe(bus_ref) = event {
+                if released(bus_ref) {
+                    unexpected = Some(bus_ref);
+                }
+            }
+            false
+        });
+        if let Some(bus_ref) = unexpected {
+            report.check(false, Violation::EventForReleasedPipe(bus_ref));
+        }
+
+        if let (true, Some(hw)) = (pipes.iter().all(Option::is_some), self.bus.interrupt_pipe_hw()) {
+            // all pipes that were created before must be available again
+            let mut recreated = [None; PIPES];
+            for pipe in recreated.iter_mut() {
+                *pipe = hw.create_interrupt_pipe(dev_addr, 1, UsbDirection::In, 8, 10).map(|pipe| pipe.bus_ref);
+            }
+            report.check(recreated.iter().all(Option::is_some), Violation::PipeNotReleased);
+            for bus_ref in recreated.iter().flatten() {
+                hw.release_interrupt_pipe(*bus_ref);
+            }
+        }
+    }
+
+    fn check_complete(&mut self, report: &mut Report, stage: Stage) -> bool {
+        let event = self.wait_for(|event| event != Event::Sof);
+        report.check(event == Some(Event::TransComplete), Violation::Transfer(stage, event))
+    }
+
+    /// Poll the bus until `matches` returns true for an event, or the poll limit is reached
+    fn wait_for(&mut self, mut matches: impl FnMut(Event) -> bool) -> Option<Event> {
+        for _ in 0..self.poll_limit {
+            match self.bus.poll() {
+                Some(event) if matches(event) => return Some(event),
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::mock::{MockDevice, MockHostBus};
+
+    const DEVICE_DESCRIPTOR: &[u8] = &[
+        0x12, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x40, 0x34, 0x12, 0x78, 0x56, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01,
+    ];
+
+    #[test]
+    fn test_mock_conforms() {
+        let mut bus = MockHostBus::new(MockDevice::new(DEVICE_DESCRIPTOR, &[]));
+        bus.attach();
+        let mut conformance = Conformance::new(&mut bus);
+        conformance.set_poll_limit(1000);
+        let report = conformance.run();
+        assert!(report.is_ok());
+        assert!(report.checks() > 10);
+    }
+
+    #[test]
+    fn test_optional_capabilities_skipped() {
+        let mut bus = MockHostBus::new(MockDevice::new(DEVICE_DESCRIPTOR, &[]));
+        bus.set_interrupt_pipe_hw(false);
+        bus.set_sof_timer(false);
+        bus.attach();
+        let mut conformance = Conformance::new(&mut bus);
+        conformance.set_poll_limit(1000);
+        let report = conformance.run();
+        assert!(report.is_ok());
+    }
+
+    #[test]
+    fn test_no_device() {
+        let mut bus = MockHostBus::new(MockDevice::new(DEVICE_DESCRIPTOR, &[]));
+        let mut conformance = Conformance::new(&mut bus);
+        conformance.set_poll_limit(1000);
+        let report = conformance.run();
+        assert!(report.violations().eq([Violation::NoDevice]));
+    }
+}
@@ -0,0 +1,829 @@
+//! Host bus implementation for the MAX3421E USB host controller
+//!
+//! The MAX3421E is connected via SPI, which makes it possible to add USB host support to microcontrollers without
+//! a native host controller. It is driven through the [`SpiDevice`] trait of `embedded-hal` 1.0, in full duplex mode.
+//!
+//! ```ignore
+//! let spi = ExclusiveDevice::new(spi_bus, cs_pin, delay);
+//! let host_bus = Max3421eHostBus::new(spi);
+//! let mut usb_host = UsbHost::new(host_bus);
+//! // ... enable the GPIO interrupt for the INT pin of the MAX3421E (active low, level triggered) ...
+//! ```
+//!
+//! The MAX3421E asserts its `INT` pin for all events that the host needs to handle, so calling
+//! [`UsbHost::poll`](crate::UsbHost::poll) from the GPIO interrupt handler for that pin is sufficient.
+//!
+//! ## Transfers
+//!
+//! The chip has a single transfer engine, which sends one packet at a time (the `HXFR` register).
+//!
+//! - Control and bulk transfers are split into packets in software, and are limited to [`MAX_TRANSFER_SIZE`] bytes.
+//!   NAKed packets are retried right away, or according to the policy set with [`HostBus::set_nak_policy`]. Retry
+//!   intervals are rounded down to whole frames, and interrupt pipes wait while a packet is delayed. Policies for
+//!   interrupt pipes are not supported.
+//! - Interrupt pipes are polled in software, whenever a frame starts and the transfer engine is not busy otherwise.
+//!   For this, frame interrupts stay enabled while interrupt pipes exist, but [`Event::Sof`] is only generated when
+//!   requested via [`SofTimer::interrupt_on_sof`]. Up to [`MAX_INTERRUPT_PIPES`] pipes are supported, with packets of up
+//!   to 64 bytes.
+//!
+//! Since interrupt pipes share the transfer engine, a control or bulk packet may have to wait for a pipe's packet to
+//! complete, before it is sent.
+//!
+//! Low speed devices behind a full speed hub are supported, by sending a PRE packet before each of their packets
+//! (both for transfers initiated by the host and for interrupt pipes).
+//!
+//! ## Errors
+//!
+//! SPI errors cannot be reported by the methods of [`HostBus`] directly. They are reported as
+//! [`Error::Other`] by the next call to [`poll`](HostBus::poll).
+
+use super::{Error, Event, HostBus, InterruptPipe, InterruptPipeHw, NakPolicy, SofTimer};
+use crate::types::{ConnectionSpeed, DeviceAddress, SetupPacket, TransferType};
+use crate::HubPort;
+use embedded_hal::spi::{Operation, SpiDevice};
+use usb_device::UsbDirection;
+
+/// Maximum length of a control or bulk transfer
+pub const MAX_TRANSFER_SIZE: usize = 1024;
+
+/// Maximum number of interrupt pipes
+pub const MAX_INTERRUPT_PIPES: usize = 8;
+
+/// Size of the send and receive FIFOs
+const PACKET_SIZE: usize = 64;
+/// Maximum packet size of low speed control and interrupt endpoints
+const LOW_SPEED_PACKET_SIZE: usize = 8;
+/// Number of status reads while waiting for the oscillator to start, before giving up
+const OSCILLATOR_TIMEOUT: u32 = 100_000;
+
+// Registers
+const RCVFIFO: u8 = 1;
+const SNDFIFO: u8 = 2;
+const SUDFIFO: u8 = 4;
+const RCVBC: u8 = 6;
+const SNDBC: u8 = 7;
+const USBIRQ: u8 = 13;
+const USBCTL: u8 = 15;
+const CPUCTL: u8 = 16;
+const PINCTL: u8 = 17;
+const HIRQ: u8 = 25;
+const HIEN: u8 = 26;
+const MODE: u8 = 27;
+const PERADDR: u8 = 28;
+const HCTL: u8 = 29;
+const HXFR: u8 = 30;
+const HRSL: u8 = 31;
+
+// USBIRQ
+const USBIRQ_OSCOK: u8 = 1 << 0;
+// USBCTL
+const USBCTL_CHIPRES: u8 = 1 << 5;
+// CPUCTL
+const CPUCTL_IE: u8 = 1 << 0;
+// PINCTL: full duplex SPI, level triggered (active low) interrupt
+const PINCTL_FDUPSPI: u8 = 1 << 4;
+const PINCTL_INTLEVEL: u8 = 1 << 3;
+// HIRQ / HIEN
+const HIRQ_BUSEVENT: u8 = 1 << 0;
+const HIRQ_RWU: u8 = 1 << 1;
+const HIRQ_RCVDAV: u8 = 1 << 2;
+const HIRQ_CONDET: u8 = 1 << 5;
+const HIRQ_FRAME: u8 = 1 << 6;
+const HIRQ_HXFRDN: u8 = 1 << 7;
+// MODE
+const MODE_HOST: u8 = 1 << 0;
+const MODE_LOWSPEED: u8 = 1 << 1;
+const MODE_HUBPRE: u8 = 1 << 2;
+const MODE_SOFKAENAB: u8 = 1 << 3;
+const MODE_DMPULLDN: u8 = 1 << 6;
+const MODE_DPPULLDN: u8 = 1 << 7;
+// HCTL
+const HCTL_BUSRST: u8 = 1 << 0;
+const HCTL_SAMPLEBUS: u8 = 1 << 2;
+const HCTL_SIGRSM: u8 = 1 << 3;
+const HCTL_RCVTOG0: u8 = 1 << 4;
+const HCTL_RCVTOG1: u8 = 1 << 5;
+const HCTL_SNDTOG0: u8 = 1 << 6;
+const HCTL_SNDTOG1: u8 = 1 << 7;
+// HXFR
+const HXFR_SETUP: u8 = 1 << 4;
+const HXFR_OUTNIN: u8 = 1 << 5;
+// HRSL
+const HRSL_KSTATUS: u8 = 1 << 6;
+const HRSL_JSTATUS: u8 = 1 << 7;
+// HRSL result codes
+const HRSLT_SUCCESS: u8 = 0x0;
+const HRSLT_NAK: u8 = 0x4;
+const HRSLT_STALL: u8 = 0x5;
+const HRSLT_TOGERR: u8 = 0x6;
+const HRSLT_CRCERR: u8 = 0xB;
+const HRSLT_TIMEOUT: u8 = 0xE;
+const HRSLT_BABBLE: u8 = 0xF;
+
+/// Transfer initiated by the host, which spans multiple packets
+#[derive(Copy, Clone, Debug)]
+struct Transfer {
+    direction: UsbDirection,
+    length: usize,
+    /// Number of bytes transferred so far
+    offset: usize,
+    /// Size of the packet currently in flight
+    packet: usize,
+    /// PID of the current packet
+    pid: bool,
+}
+
+/// State of the current packet of the host's transfer (or its SETUP packet)
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Step {
+    /// Waiting for the transfer engine to become available
+    Waiting,
+    /// Waiting for the given frame, before a NAKed packet is retried
+    Delayed(u16),
+    /// Handed to the transfer engine
+    InFlight,
+}
+
+/// Scheduling state of an interrupt pipe
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum PipeState {
+    /// Waiting for the host to call `pipe_continue`
+    Idle,
+    /// Waiting for the given frame, before the next packet is sent
+    Scheduled(u16),
+    /// Packet is handed to the transfer engine
+    InFlight,
+}
+
+/// Interrupt pipe, polled in software
+#[derive(Debug)]
+struct MaxPipe {
+    dev_addr: u8,
+    endpoint: u8,
+    direction: UsbDirection,
+    size: u16,
+    interval: u8,
+    /// PID of the next packet
+    pid: bool,
+    /// Send a PRE packet before each packet (for low speed devices behind a hub)
+    hub_pre: bool,
+    state: PipeState,
+    buf: [u8; PACKET_SIZE],
+}
+
+/// [`HostBus`] implementation for the MAX3421E
+///
+/// See the [module documentation](self) for details.
+#[derive(Debug)]
+pub struct Max3421eHostBus<SPI> {
+    spi: SPI,
+    spi_error: bool,
+    speed: Option<ConnectionSpeed>,
+    /// Set while a bus reset is in progress, to report the device once the reset is done
+    resetting: bool,
+    /// Device address and endpoint for the host's transfers
+    recipient: (u8, u8),
+    /// Maximum packet size of endpoint zero of the recipient, if it is the target of a control transfer
+    max_packet_size_0: Option<u8>,
+    /// Send a PRE packet before each packet of the host's transfers
+    hub_pre: bool,
+    /// Current state of the `HUBPRE` bit, which is shared by the host's transfers and interrupt pipes
+    mode_hub_pre: bool,
+    setup: Option<[u8; 8]>,
+    transfer: Option<Transfer>,
+    step: Option<Step>,
+    /// Set if the packet in flight belongs to a stopped transfer or a released pipe, so its result must be ignored
+    discard_result: bool,
+    buffer: [u8; MAX_TRANSFER_SIZE],
+    /// How NAKed packets of the host's transfers are retried
+    nak_policy: NakPolicy,
+    /// Number of NAKs received for the current packet of the host's transfer
+    naks: u16,
+    pipes: [Option<MaxPipe>; MAX_INTERRUPT_PIPES],
+    /// Frames counted since the controller was reset, used to schedule interrupt pipes
+    frame: u16,
+    sof_enabled: bool,
+    sof_interrupt: bool,
+}
+
+impl<SPI: SpiDevice> Max3421eHostBus<SPI> {
+    /// Create a host bus, using the given SPI device
+    ///
+    /// The chip is set up when the [`UsbHost`](crate::UsbHost) calls [`HostBus::reset_controller`].
+    pub fn new(spi: SPI) -> Self {
+        const NO_PIPE: Option<MaxPipe> = None;
+        Self {
+            spi,
+            spi_error: false,
+            speed: None,
+            resetting: false,
+            recipient: (0, 0),
+            max_packet_size_0: None,
+            hub_pre: false,
+            mode_hub_pre: false,
+            setup: None,
+            transfer: None,
+            step: None,
+            discard_result: false,
+            buffer: [0; MAX_TRANSFER_SIZE],
+            nak_policy: NakPolicy::UNLIMITED,
+            naks: 0,
+            pipes: [NO_PIPE; MAX_INTERRUPT_PIPES],
+            frame: 0,
+            sof_enabled: false,
+            sof_interrupt: false,
+        }
+    }
+
+    /// Release the SPI device
+    pub fn free(self) -> SPI {
+        self.spi
+    }
+
+    fn write_reg(&mut self, reg: u8, value: u8) {
+        self.write_bytes(reg, &[value]);
+    }
+
+    fn write_bytes(&mut self, reg: u8, data: &[u8]) {
+        let command = [reg << 3 | 0b10];
+        if self.spi.transaction(&mut [Operation::Write(&command), Operation::Write(data)]).is_err() {
+            self.spi_error = true;
+        }
+    }
+
+    fn read_reg(&mut self, reg: u8) -> u8 {
+        let mut value = [0];
+        self.read_bytes(reg, &mut value);
+        value[0]
+    }
+
+    fn read_bytes(&mut self, reg: u8, data: &mut [u8]) {
+        let command = [reg << 3];
+        if self.spi.transaction(&mut [Operation::Write(&command), Operation::Read(data)]).is_err() {
+            self.spi_error = true;
+        }
+    }
+
+    fn modify_reg(&mut self, reg: u8, f: impl FnOnce(u8) -> u8) {
+        let value = f(self.read_reg(reg));
+        self.write_reg(reg, value);
+    }
+
+    fn apply_hub_pre(&mut self, enabled: bool) {
+        if self.mode_hub_pre != enabled {
+            self.mode_hub_pre = enabled;
+            self.modify_reg(MODE, |r| if enabled { r | MODE_HUBPRE } else { r & !MODE_HUBPRE });
+        }
+    }
+
+    fn max_packet_size(&self) -> usize {
+        match (self.max_packet_size_0, self.speed) {
+            (Some(size), _) => (size as usize).min(PACKET_SIZE),
+            (None, Some(ConnectionSpeed::Low)) => LOW_SPEED_PACKET_SIZE,
+            (None, _) => PACKET_SIZE,
+        }
+    }
+
+    /// Determine the speed of the attached device (if any) from the state of the bus
+    ///
+    /// J and K states are swapped when the chip is in low speed mode, so the mode is updated accordingly.
+    fn sample_bus(&mut self) -> Option<ConnectionSpeed> {
+        let hrsl = self.read_reg(HRSL);
+        let low_speed_mode = self.read_reg(MODE) & MODE_LOWSPEED != 0;
+        let speed = match (hrsl & HRSL_JSTATUS != 0, hrsl & HRSL_KSTATUS != 0) {
+            (true, false) if low_speed_mode => ConnectionSpeed::Low,
+            (true, false) => ConnectionSpeed::Full,
+            (false, true) if low_speed_mode => ConnectionSpeed::Full,
+            (false, true) => ConnectionSpeed::Low,
+            // SE0: nothing is attached
+            _ => {
+                self.sof_enabled = false;
+                self.modify_reg(MODE, |r| r & !MODE_SOFKAENAB);
+                return None;
+            }
+        };
+        let lowspeed = if speed == ConnectionSpeed::Low { MODE_LOWSPEED } else { 0 };
+        self.modify_reg(MODE, |r| (r & !MODE_LOWSPEED) | lowspeed);
+        Some(speed)
+    }
+
+    fn busy(&self) -> bool {
+        self.discard_result || self.step == Some(Step::InFlight) || self.pipes.iter().flatten().any(|pipe| pipe.state == PipeState::InFlight)
+    }
+
+    /// Hand the host's current packet to the transfer engine, unless it is busy with an interrupt pipe
+    fn launch(&mut self) {
+        if self.step != Some(Step::Waiting) || self.busy() {
+            return;
+        }
+        self.step = Some(Step::InFlight);
+        let (dev_addr, endpoint) = self.recipient;
+        self.write_reg(PERADDR, dev_addr);
+        self.apply_hub_pre(self.hub_pre);
+        if let Some(setup) = self.setup {
+            self.write_bytes(SUDFIFO, &setup);
+            self.write_reg(HXFR, HXFR_SETUP | endpoint);
+            return;
+        }
+        let max_packet_size = self.max_packet_size();
+        let Some(transfer) = self.transfer.as_mut() else {
+            return;
+        };
+        let len = (transfer.length - transfer.offset).min(max_packet_size);
+        let (direction, pid, offset) = (transfer.direction, transfer.pid, transfer.offset);
+        // for IN packets, the size is known once the data was received
+        transfer.packet = if direction == UsbDirection::Out { len } else { 0 };
+        self.start_packet(endpoint, direction, pid, offset, len);
+    }
+
+    fn start_packet(&mut self, endpoint: u8, direction: UsbDirection, pid: bool, offset: usize, len: usize) {
+        if direction == UsbDirection::Out {
+            self.write_reg(HCTL, if pid { HCTL_SNDTOG1 } else { HCTL_SNDTOG0 });
+            let mut packet = [0; PACKET_SIZE];
+            packet[..len].copy_from_slice(&self.buffer[offset..offset + len]);
+            self.write_bytes(SNDFIFO, &packet[..len]);
+            self.write_reg(SNDBC, len as u8);
+            self.write_reg(HXFR, HXFR_OUTNIN | endpoint);
+        } else {
+            self.write_reg(HCTL, if pid { HCTL_RCVTOG1 } else { HCTL_RCVTOG0 });
+            self.write_reg(HXFR, endpoint);
+        }
+    }
+
+    /// Read a received packet (up to `max_len` bytes), and release the receive FIFO
+    ///
+    /// Returns the length of the packet as well. For zero-length packets, no data is available.
+    fn read_packet(&mut self, max_len: usize) -> (usize, [u8; PACKET_SIZE]) {
+        let mut packet = [0; PACKET_SIZE];
+        if self.read_reg(HIRQ) & HIRQ_RCVDAV == 0 {
+            return (0, packet);
+        }
+        let len = (self.read_reg(RCVBC) as usize).min(max_len).min(PACKET_SIZE);
+        self.read_bytes(RCVFIFO, &mut packet[..len]);
+        self.write_reg(HIRQ, HIRQ_RCVDAV);
+        (len, packet)
+    }
+
+    fn result_error(result: u8) -> Error {
+        match result {
+            HRSLT_TIMEOUT => Error::RxTimeout,
+            HRSLT_CRCERR => Error::Crc,
+            HRSLT_TOGERR => Error::DataSequence,
+            HRSLT_BABBLE => Error::RxOverflow,
+            _ => Error::Other,
+        }
+    }
+
+    fn transfer_done(&mut self, result: u8) -> Option<Event> {
+        match result {
+            HRSLT_SUCCESS => {
+                self.naks = 0;
+                if self.setup.take().is_some() {
+                    self.step = None;
+                    return Some(Event::TransComplete);
+                }
+                let max_packet_size = self.max_packet_size();
+                let Some(mut transfer) = self.transfer else {
+                    self.step = None;
+                    return Some(Event::TransComplete);
+                };
+                if transfer.direction == UsbDirection::In {
+                    let (len, packet) = self.read_packet(transfer.length - transfer.offset);
+                    transfer.packet = len;
+                    self.buffer[transfer.offset..transfer.offset + transfer.packet].copy_from_slice(&packet[..transfer.packet]);
+                }
+                transfer.offset += transfer.packet;
+                transfer.pid = !transfer.pid;
+                self.transfer = Some(transfer);
+                // a short packet ends the transfer early
+                if transfer.offset >= transfer.length || transfer.packet < max_packet_size {
+                    self.step = None;
+                    Some(Event::TransComplete)
+                } else {
+                    self.step = Some(Step::Waiting);
+                    self.launch();
+                    None
+                }
+            }
+            HRSLT_NAK => {
+                self.naks = self.naks.saturating_add(1);
+                if self.nak_policy.retry_limit.is_some_and(|limit| self.naks >= limit) {
+                    self.stop_transaction();
+                    return Some(Event::Error(Error::NakLimit));
+                }
+                let frames = self.nak_policy.retry_interval.map_or(0, |interval| interval / 1000);
+                if frames > 0 {
+                    self.step = Some(Step::Delayed(self.frame.wrapping_add(frames)));
+                } else {
+                    self.retry_packet();
+                }
+                None
+            }
+            HRSLT_STALL => {
+                self.stop_transaction();
+                Some(Event::Stall)
+            }
+            _ => {
+                self.stop_transaction();
+                Some(Event::Error(Self::result_error(result)))
+            }
+        }
+    }
+
+    /// Send the NAKed packet of the host's transfer again
+    fn retry_packet(&mut self) {
+        let (_, endpoint) = self.recipient;
+        match self.transfer {
+            Some(transfer) if transfer.direction == UsbDirection::Out && self.setup.is_none() => {
+                // rewriting the byte count sends the packet again
+                self.step = Some(Step::InFlight);
+                self.write_reg(SNDBC, transfer.packet as u8);
+                self.write_reg(HXFR, HXFR_OUTNIN | endpoint);
+            }
+            _ => {
+                self.step = Some(Step::Waiting);
+                self.launch();
+            }
+        }
+    }
+
+    fn pipe_done(&mut self, index: usize, result: u8) -> Option<Event> {
+        let size = self.pipes[index].as_ref()?.size as usize;
+        let packet = (result == HRSLT_SUCCESS && self.pipes[index].as_ref()?.direction == UsbDirection::In)
+            .then(|| self.read_packet(size));
+        let frame = self.frame;
+        let pipe = self.pipes[index].as_mut()?;
+        let event = match result {
+            HRSLT_SUCCESS => {
+                if let Some((len, packet)) = packet {
+                    pipe.buf[..len].copy_from_slice(&packet[..len]);
+                }
+                pipe.pid = !pipe.pid;
+                pipe.state = PipeState::Idle;
+                Some(Event::InterruptPipe(index as u8 + 1))
+            }
+            _ => {
+                if result != HRSLT_NAK {
+                    warn!("Interrupt pipe {} failed with result {}", index + 1, result);
+                }
+                pipe.state = PipeState::Scheduled(frame.wrapping_add(pipe.interval as u16));
+                None
+            }
+        };
+        // the host's transfer may have been waiting for the transfer engine
+        self.launch();
+        event
+    }
+
+    /// Start the next interrupt pipe packet that is due, if the transfer engine is available
+    fn poll_pipes(&mut self) {
+        if self.busy() || self.step.is_some() {
+            return;
+        }
+        let frame = self.frame;
+        let Some(index) = self.pipes.iter().position(|pipe| {
+            matches!(pipe, Some(MaxPipe { state: PipeState::Scheduled(due), .. }) if frame.wrapping_sub(*due) as i16 >= 0)
+        }) else {
+            return;
+        };
+        let Some(pipe) = self.pipes[index].as_mut() else {
+            return;
+        };
+        pipe.state = PipeState::InFlight;
+        let (dev_addr, endpoint, direction, size, pid, hub_pre, buf) =
+            (pipe.dev_addr, pipe.endpoint, pipe.direction, pipe.size as usize, pipe.pid, pipe.hub_pre, pipe.buf);
+        self.write_reg(PERADDR, dev_addr);
+        self.apply_hub_pre(hub_pre);
+        if direction == UsbDirection::Out {
+            self.write_reg(HCTL, if pid { HCTL_SNDTOG1 } else { HCTL_SNDTOG0 });
+            self.write_bytes(SNDFIFO, &buf[..size]);
+            self.write_reg(SNDBC, size as u8);
+            self.write_reg(HXFR, HXFR_OUTNIN | endpoint);
+        } else {
+            self.write_reg(HCTL, if pid { HCTL_RCVTOG1 } else { HCTL_RCVTOG0 });
+            self.write_reg(HXFR, endpoint);
+        }
+    }
+
+    fn schedule_pipe(&mut self, index: usize) {
+        let frame = self.frame;
+        if let Some(pipe) = self.pipes[index].as_mut() {
+            pipe.state = PipeState::Scheduled(frame.wrapping_add(pipe.interval as u16));
+        }
+    }
+
+    /// Set up an interrupt pipe, polled in software
+    ///
+    /// If `hub_pre` is set, each packet is preceded by a PRE packet (for low speed devices behind a hub).
+    fn create_pipe(
+        &mut self,
+        device_address: DeviceAddress,
+        endpoint_number: u8,
+        direction: UsbDirection,
+        size: u16,
+        interval: u8,
+        hub_pre: bool,
+    ) -> Option<InterruptPipe> {
+        if size as usize > PACKET_SIZE {
+            return None;
+        }
+        let index = self.pipes.iter().position(|pipe| pipe.is_none())?;
+        self.pipes[index] = Some(MaxPipe {
+            dev_addr: u8::from(device_address),
+            endpoint: endpoint_number,
+            direction,
+            size,
+            interval: interval.max(1),
+            pid: false,
+            hub_pre,
+            state: PipeState::Idle,
+            buf: [0; PACKET_SIZE],
+        });
+        if direction == UsbDirection::In {
+            // OUT pipes are scheduled once the driver filled the buffer
+            self.schedule_pipe(index);
+        }
+        self.update_frame_interrupt();
+
+        Some(InterruptPipe { bus_ref: index as u8 + 1 })
+    }
+
+    /// Frame interrupts are needed for polling interrupt pipes, delaying NAKed packets, or if the host asked for them
+    fn update_frame_interrupt(&mut self) {
+        let delays = self.nak_policy.retry_interval.is_some_and(|interval| interval >= 1000);
+        let needed = self.sof_interrupt || delays || self.pipes.iter().any(Option::is_some);
+        self.modify_reg(HIEN, |r| if needed { r | HIRQ_FRAME } else { r & !HIRQ_FRAME });
+    }
+}
+
+impl<SPI: SpiDevice> HostBus for Max3421eHostBus<SPI> {
+    fn reset_controller(&mut self) {
+        self.speed = None;
+        self.resetting = false;
+        self.setup = None;
+        self.transfer = None;
+        self.step = None;
+        self.discard_result = false;
+        self.nak_policy = NakPolicy::UNLIMITED;
+        self.naks = 0;
+        self.pipes.iter_mut().for_each(|pipe| *pipe = None);
+        self.frame = 0;
+        self.hub_pre = false;
+        self.mode_hub_pre = false;
+        self.sof_enabled = false;
+        self.sof_interrupt = false;
+
+        // the SPI mode must be configured before anything else can be read
+        self.write_reg(PINCTL, PINCTL_FDUPSPI | PINCTL_INTLEVEL);
+        self.write_reg(USBCTL, USBCTL_CHIPRES);
+        self.write_reg(USBCTL, 0);
+        for _ in 0..OSCILLATOR_TIMEOUT {
+            if self.read_reg(USBIRQ) & USBIRQ_OSCOK != 0 {
+                break;
+            }
+        }
+
+        self.write_reg(MODE, MODE_HOST | MODE_DMPULLDN | MODE_DPPULLDN);
+        self.write_reg(HIEN, HIRQ_CONDET | HIRQ_BUSEVENT | HIRQ_HXFRDN | HIRQ_RWU);
+        self.write_reg(HIRQ, 0xFF);
+        // a device may already be attached, in which case CONDET is raised after sampling the bus
+        self.write_reg(HCTL, HCTL_SAMPLEBUS);
+        self.write_reg(CPUCTL, CPUCTL_IE);
+    }
+
+    fn reset_bus(&mut self) {
+        self.resetting = true;
+        // reset is timed by the chip, BUSEVENT is raised when it is done
+        self.write_reg(HCTL, HCTL_BUSRST);
+    }
+
+    fn enable_sof(&mut self) {
+        self.sof_enabled = true;
+        self.modify_reg(MODE, |r| r | MODE_SOFKAENAB);
+    }
+
+    fn sof_enabled(&self) -> bool {
+        self.sof_enabled
+    }
+
+    fn suspend(&mut self) {
+        self.sof_enabled = false;
+        self.modify_reg(MODE, |r| r & !MODE_SOFKAENAB);
+    }
+
+    fn start_resume(&mut self) {
+        // the chip drives resume signalling for 20 ms on its own
+        self.write_reg(HCTL, HCTL_SIGRSM);
+    }
+
+    fn set_recipient(&mut self, dev_addr: Option<DeviceAddress>, endpoint: u8, _transfer_type: TransferType) {
+        self.recipient = (dev_addr.map_or(0, u8::from), endpoint);
+        self.max_packet_size_0 = None;
+    }
+
+    fn set_max_packet_size_0(&mut self, max_packet_size: u8) {
+        self.max_packet_size_0 = Some(max_packet_size);
+    }
+
+    fn ls_preamble(&mut self, enabled: bool) {
+        // applied when the next packet is started, since an interrupt pipe may be using the transfer engine
+        self.hub_pre = enabled;
+    }
+
+    fn stop_transaction(&mut self) {
+        // packets cannot be aborted, the result of the one in flight is ignored
+        if self.step == Some(Step::InFlight) {
+            self.discard_result = true;
+        }
+        self.setup = None;
+        self.transfer = None;
+        self.step = None;
+    }
+
+    fn write_setup(&mut self, setup: SetupPacket) {
+        self.setup = Some(setup.to_bytes());
+        self.transfer = None;
+        self.naks = 0;
+        self.step = Some(Step::Waiting);
+        self.launch();
+    }
+
+    fn write_data_in(&mut self, length: u16, pid: bool) {
+        self.setup = None;
+        self.transfer = Some(Transfer {
+            direction: UsbDirection::In,
+            length: (length as usize).min(MAX_TRANSFER_SIZE),
+            offset: 0,
+            packet: 0,
+            pid,
+        });
+        self.naks = 0;
+        self.step = Some(Step::Waiting);
+        self.launch();
+    }
+
+    fn prepare_data_out(&mut self, data: &[u8]) {
+        let len = data.len().min(MAX_TRANSFER_SIZE);
+        self.buffer[..len].copy_from_slice(&data[..len]);
+        self.transfer = Some(Transfer { direction: UsbDirection::Out, length: len, offset: 0, packet: 0, pid: false });
+    }
+
+    fn write_data_out_prepared(&mut self, pid: bool) {
+        self.setup = None;
+        if let Some(transfer) = self.transfer.as_mut() {
+            transfer.pid = pid;
+        }
+        self.naks = 0;
+        self.step = Some(Step::Waiting);
+        self.launch();
+    }
+
+    fn poll(&mut self) -> Option<Event> {
+        loop {
+            if core::mem::take(&mut self.spi_error) {
+                return Some(Event::Error(Error::Other));
+            }
+            let hirq = self.read_reg(HIRQ) & self.read_reg(HIEN);
+            let event = if hirq & HIRQ_CONDET != 0 {
+                self.write_reg(HIRQ, HIRQ_CONDET);
+                match self.sample_bus() {
+                    Some(speed) if self.speed.is_none() => {
+                        self.speed = Some(speed);
+                        Some(Event::Attached(speed))
+                    }
+                    Some(_) => None,
+                    None => {
+                        self.speed = None;
+                        self.stop_transaction();
+                        // the device is gone, so no result arrives for a packet in flight
+                        self.discard_result = false;
+                        Some(Event::Detached)
+                    }
+                }
+            } else if hirq & HIRQ_BUSEVENT != 0 {
+                // raised when a bus reset or resume signalling is done
+                self.write_reg(HIRQ, HIRQ_BUSEVENT);
+                if core::mem::take(&mut self.resetting) {
+                    self.speed = self.sample_bus();
+                    self.speed.map(Event::Attached)
+                } else {
+                    None
+                }
+            } else if hirq & HIRQ_HXFRDN != 0 {
+                self.write_reg(HIRQ, HIRQ_HXFRDN);
+                let result = self.read_reg(HRSL) & 0x0F;
+                if core::mem::take(&mut self.discard_result) {
+                    self.read_packet(0);
+                    self.launch();
+                    continue;
+                }
+                match self.pipes.iter().position(|pipe| matches!(pipe, Some(MaxPipe { state: PipeState::InFlight, .. }))) {
+                    Some(index) => self.pipe_done(index, result),
+                    None if self.step == Some(Step::InFlight) => self.transfer_done(result),
+                    // result of an aborted packet
+                    None => None,
+                }
+            } else if hirq & HIRQ_RWU != 0 {
+                self.write_reg(HIRQ, HIRQ_RWU);
+                Some(Event::Resume)
+            } else if hirq & HIRQ_FRAME != 0 {
+                self.write_reg(HIRQ, HIRQ_FRAME);
+                self.frame = self.frame.wrapping_add(1);
+                if let Some(Step::Delayed(due)) = self.step {
+                    if self.frame.wrapping_sub(due) as i16 >= 0 {
+                        self.retry_packet();
+                    }
+                }
+                self.poll_pipes();
+                self.sof_interrupt.then_some(Event::Sof)
+            } else {
+                return None;
+            };
+            if event.is_some() {
+                return event;
+            }
+        }
+    }
+
+    fn received_data(&self, length: usize) -> &[u8] {
+        let len = self.transfer.map_or(0, |transfer| transfer.offset).min(length);
+        &self.buffer[..len]
+    }
+
+    fn set_nak_policy(&mut self, pipe_ref: Option<u8>, policy: NakPolicy) -> bool {
+        // interrupt pipes are retried at their polling interval
+        if pipe_ref.is_some() {
+            return false;
+        }
+        self.nak_policy = policy;
+        self.update_frame_interrupt();
+        true
+    }
+
+    fn interrupt_pipe_hw(&mut self) -> Option<&mut dyn InterruptPipeHw> {
+        Some(self)
+    }
+
+    fn sof_timer(&mut self) -> Option<&mut dyn SofTimer> {
+        Some(self)
+    }
+}
+
+impl<SPI: SpiDevice> InterruptPipeHw for Max3421eHostBus<SPI> {
+    fn create_interrupt_pipe(
+        &mut self,
+        device_address: DeviceAddress,
+        endpoint_number: u8,
+        direction: UsbDirection,
+        size: u16,
+        interval: u8,
+    ) -> Option<InterruptPipe> {
+        self.create_pipe(device_address, endpoint_number, direction, size, interval, false)
+    }
+
+    fn create_low_speed_interrupt_pipe(
+        &mut self,
+        device_address: DeviceAddress,
+        endpoint_number: u8,
+        direction: UsbDirection,
+        size: u16,
+        interval: u8,
+        _hub: HubPort,
+    ) -> Option<InterruptPipe> {
+        self.create_pipe(device_address, endpoint_number, direction, size, interval, true)
+    }
+
+    fn release_interrupt_pipe(&mut self, pipe_ref: u8) {
+        let Some(index) = (pipe_ref as usize).checked_sub(1).filter(|i| *i < MAX_INTERRUPT_PIPES) else {
+            return;
+        };
+        if let Some(MaxPipe { state: PipeState::InFlight, .. }) = self.pipes[index].take() {
+            self.discard_result = true;
+        }
+        self.update_frame_interrupt();
+    }
+
+    fn interrupt_buffer(&mut self, pipe_ref: u8) -> Option<&mut [u8]> {
+        let pipe = self.pipes.get_mut((pipe_ref as usize).checked_sub(1)?)?.as_mut()?;
+        Some(&mut pipe.buf[..pipe.size as usize])
+    }
+
+    fn pipe_continue(&mut self, pipe_ref: u8) {
+        if let Some(index) = (pipe_ref as usize).checked_sub(1).filter(|i| *i < MAX_INTERRUPT_PIPES) {
+            self.schedule_pipe(index);
+        }
+    }
+
+    fn set_pipe_data_toggle(&mut self, pipe_ref: u8, data_toggle: bool) {
+        if let Some(Some(pipe)) = (pipe_ref as usize).checked_sub(1).and_then(|index| self.pipes.get_mut(index)) {
+            pipe.pid = data_toggle;
+        }
+    }
+}
+
+impl<SPI: SpiDevice> SofTimer for Max3421eHostBus<SPI> {
+    fn interrupt_on_sof(&mut self, enable: bool) {
+        self.sof_interrupt = enable;
+        self.update_frame_interrupt();
+    }
+}
@@ -0,0 +1,986 @@
+//! Simulated host bus, for testing drivers and the host itself without hardware
+//!
+//! The [`MockHostBus`] plays the role of both the host controller and a single attached device. The device is described by
+//! a [`MockDevice`] fixture: its descriptors, and scripted responses to other control requests. Transfers complete
+//! immediately: every transaction queues the event the real hardware would generate, to be picked up by the next
+//! [`UsbHost::poll`](crate::UsbHost::poll).
+//!
+//! ```ignore
+//! let mut host = UsbHost::new(MockHostBus::new(MockDevice::new(DEVICE_DESCRIPTOR, &[CONFIGURATION_DESCRIPTOR])));
+//! host.bus().attach();
+//! for _ in 0..1000 {
+//!     host.poll(&mut [&mut driver]);
+//! }
+//! assert!(host.bus().configuration() == Some(1));
+//! ```
+//!
+//! Only available for tests within the crate, and with the `mock` feature.
+
+use super::{Error, Event, HostBus, InterruptPipe, InterruptPipeHw, NakPolicy, SofTimer, TransferChannels};
+use crate::types::{ConnectionSpeed, DeviceAddress, SetupPacket, TransferType};
+use core::num::NonZeroU8;
+use core::task::Waker;
+use usb_device::UsbDirection;
+
+/// Number of events that can be queued
+const MAX_EVENTS: usize = 16;
+/// Number of interrupt pipes that can be created
+const MAX_PIPES: usize = 8;
+/// Size of each interrupt pipe's buffer
+const PIPE_BUFFER_SIZE: usize = 64;
+/// Size of the buffer for received data
+const BUFFER_SIZE: usize = 1024;
+/// Maximum number of channels for bulk transfers that can be exposed
+const MAX_CHANNELS: usize = 4;
+
+const CLEAR_FEATURE: u8 = 0x01;
+const GET_CONFIGURATION: u8 = 0x08;
+const GET_DESCRIPTOR: u8 = 0x06;
+const SET_ADDRESS: u8 = 0x05;
+const SET_CONFIGURATION: u8 = 0x09;
+const TYPE_DEVICE: u8 = 0x01;
+const TYPE_CONFIGURATION: u8 = 0x02;
+const TYPE_STRING: u8 = 0x03;
+
+/// Response of the simulated device to a control request
+#[derive(Copy, Clone, Debug)]
+pub enum Response<'a> {
+    /// Send the given data (IN requests), or accept the request (OUT requests)
+    Data(&'a [u8]),
+    /// Respond with a STALL handshake
+    Stall,
+}
+
+/// Scripted response to a control request, matched by the first four fields of the setup packet
+#[derive(Copy, Clone, Debug)]
+pub struct ControlResponse<'a> {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub response: Response<'a>,
+}
+
+impl ControlResponse<'_> {
+    fn matches(&self, setup: &SetupPacket) -> bool {
+        self.request_type == setup.request_type && self.request == setup.request && self.value == setup.value && self.index == setup.index
+    }
+}
+
+/// Fixture describing the simulated device
+#[derive(Copy, Clone, Debug)]
+pub struct MockDevice<'a> {
+    pub speed: ConnectionSpeed,
+    pub device_descriptor: &'a [u8],
+    /// Full configuration descriptors (including interface, endpoint and class specific descriptors), in order of their index
+    pub configuration_descriptors: &'a [&'a [u8]],
+    /// String descriptors, in order of their index. Index 0 is the list of language IDs.
+    pub string_descriptors: &'a [&'a [u8]],
+    /// Responses to control requests other than standard GET_DESCRIPTOR, SET_ADDRESS and SET_CONFIGURATION
+    ///
+    /// Requests without a matching response are answered with a STALL, except for GET_CONFIGURATION, which is answered
+    /// with the configuration selected by the host (or 0).
+    pub control_responses: &'a [ControlResponse<'a>],
+}
+
+impl<'a> MockDevice<'a> {
+    /// Full-speed device with the given descriptors, which has no strings, and stalls any other requests
+    pub fn new(device_descriptor: &'a [u8], configuration_descriptors: &'a [&'a [u8]]) -> Self {
+        Self {
+            speed: ConnectionSpeed::Full,
+            device_descriptor,
+            configuration_descriptors,
+            string_descriptors: &[],
+            control_responses: &[],
+        }
+    }
+
+    /// Response for the given setup packet
+    fn respond(&self, setup: &SetupPacket) -> Response<'a> {
+        let descriptor = |list: &[&'a [u8]], index: u16| list.get(index as usize).map_or(Response::Stall, |d| Response::Data(d));
+        match (setup.request_type, setup.request) {
+            (0x80, GET_DESCRIPTOR) => match (setup.value >> 8) as u8 {
+                TYPE_DEVICE => Response::Data(self.device_descriptor),
+                TYPE_CONFIGURATION => descriptor(self.configuration_descriptors, setup.value & 0xff),
+                TYPE_STRING => descriptor(self.string_descriptors, setup.value & 0xff),
+                _ => self.scripted(setup),
+            },
+            (0x00, SET_ADDRESS) | (0x00, SET_CONFIGURATION) => Response::Data(&[]),
+            _ => self.scripted(setup),
+        }
+    }
+
+    fn is_scripted(&self, setup: &SetupPacket) -> bool {
+        self.control_responses.iter().any(|response| response.matches(setup))
+    }
+
+    fn scripted(&self, setup: &SetupPacket) -> Response<'a> {
+        self.control_responses
+            .iter()
+            .find(|response| response.matches(setup))
+            .map_or(Response::Stall, |response| response.response)
+    }
+}
+
+#[derive(Debug)]
+struct MockPipe {
+    dev_addr: DeviceAddress,
+    endpoint: u8,
+    direction: UsbDirection,
+    size: u16,
+    buf: [u8; PIPE_BUFFER_SIZE],
+    /// Set between the `InterruptPipe` event and the call to `pipe_continue`
+    busy: bool,
+    /// Data toggle last set by the host
+    data_toggle: bool,
+    /// Number of calls to `poll_interrupt_pipe` for this pipe
+    polls: usize,
+    /// NAK policy last set by the host
+    nak_policy: NakPolicy,
+}
+
+/// Simulated host controller, with a single device attached to it
+///
+/// See the [module documentation](self) for details.
+#[derive(Debug)]
+pub struct MockHostBus<'a> {
+    device: MockDevice<'a>,
+    events: [Option<Event>; MAX_EVENTS],
+    event_count: usize,
+    pipes: [Option<MockPipe>; MAX_PIPES],
+    attached: bool,
+    sof: bool,
+    sof_interrupt: bool,
+    frame: u16,
+    /// Recipient set for the current transfer
+    recipient: (Option<DeviceAddress>, u8, TransferType),
+    /// Maximum packet size of endpoint zero, set for the current control transfer
+    max_packet_size_0: Option<u8>,
+    setup: Option<SetupPacket>,
+    /// Number of bytes of the current control IN response, that were already sent
+    position: usize,
+    data: [u8; BUFFER_SIZE],
+    received: usize,
+    address: Option<DeviceAddress>,
+    configuration: Option<u8>,
+    setup_count: usize,
+    bulk_in: &'a [u8],
+    /// NAK bulk IN transactions, as if the device had no data
+    bulk_nak: bool,
+    /// STALL bulk IN transactions, as if the endpoint was halted
+    bulk_stall: bool,
+    /// NAK the data and status stages of control transfers, as if the device stopped responding
+    control_nak: bool,
+    /// Set between `start_resume` and `end_resume`
+    resume_signalling: bool,
+    /// NAK policy last set by the host, for the transactions started by the transfer methods
+    nak_policy: NakPolicy,
+    /// Leave scheduling of interrupt pipes to the host
+    host_scheduling: bool,
+    /// Expose the `InterruptPipeHw` capability
+    interrupt_pipe_hw: bool,
+    /// Expose the `SofTimer` capability
+    sof_timer: bool,
+    /// Report frame numbers
+    frame_numbers: bool,
+    /// Number of channels exposed via the `TransferChannels` capability (0 to not expose it)
+    channels: u8,
+    /// Length and data received by the most recent bulk IN transfer on each channel
+    channel_buffers: [(usize, [u8; PIPE_BUFFER_SIZE]); MAX_CHANNELS],
+    /// Endpoint and length of the data waiting to be sent on an interrupt IN endpoint, without `InterruptPipeHw`
+    interrupt_in: Option<(u8, usize)>,
+    interrupt_in_data: [u8; PIPE_BUFFER_SIZE],
+    /// Endpoint and length of the data most recently received on an interrupt OUT endpoint, without `InterruptPipeHw`
+    interrupt_out: Option<(u8, usize)>,
+    interrupt_out_data: [u8; PIPE_BUFFER_SIZE],
+    /// Number of isochronous OUT packets sent by the host, and their combined length
+    isochronous_out: (usize, usize),
+    /// Waker registered by the async API, woken by the next event
+    waker: Option<Waker>,
+}
+
+impl<'a> MockHostBus<'a> {
+    pub fn new(device: MockDevice<'a>) -> Self {
+        Self {
+            device,
+            events: [None; MAX_EVENTS],
+            event_count: 0,
+            pipes: [const { None }; MAX_PIPES],
+            attached: false,
+            sof: false,
+            sof_interrupt: false,
+            frame: 0,
+            recipient: (None, 0, TransferType::Control),
+            max_packet_size_0: None,
+            setup: None,
+            position: 0,
+            data: [0; BUFFER_SIZE],
+            received: 0,
+            address: None,
+            configuration: None,
+            setup_count: 0,
+            bulk_in: &[],
+            bulk_nak: false,
+            bulk_stall: false,
+            control_nak: false,
+            resume_signalling: false,
+            nak_policy: NakPolicy::UNLIMITED,
+            host_scheduling: false,
+            interrupt_pipe_hw: true,
+            sof_timer: true,
+            frame_numbers: true,
+            channels: 0,
+            channel_buffers: [(0, [0; PIPE_BUFFER_SIZE]); MAX_CHANNELS],
+            interrupt_in: None,
+            interrupt_in_data: [0; PIPE_BUFFER_SIZE],
+            interrupt_out: None,
+            interrupt_out_data: [0; PIPE_BUFFER_SIZE],
+            isochronous_out: (0, 0),
+            waker: None,
+        }
+    }
+
+    /// Queue an event, as if it was generated by the hardware
+    ///
+    /// Events exceeding the capacity of the queue are dropped. Wakes the waker registered by the async API, if any.
+    pub fn push_event(&mut self, event: Event) {
+        if self.event_count < MAX_EVENTS {
+            self.events[self.event_count] = Some(event);
+            self.event_count += 1;
+        }
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Simulate plugging in the device
+    pub fn attach(&mut self) {
+        self.attached = true;
+        self.push_event(Event::Attached(self.device.speed));
+    }
+
+    /// Simulate unplugging the device
+    pub fn detach(&mut self) {
+        self.attached = false;
+        self.address = None;
+        self.configuration = None;
+        self.push_event(Event::Detached);
+    }
+
+    /// Address assigned to the device with SET_ADDRESS, if any
+    pub fn address(&self) -> Option<DeviceAddress> {
+        self.address
+    }
+
+    /// Configuration selected with SET_CONFIGURATION, if any
+    pub fn configuration(&self) -> Option<u8> {
+        self.configuration
+    }
+
+    /// Most recent setup packet sent by the host
+    pub fn last_setup(&self) -> Option<SetupPacket> {
+        self.setup
+    }
+
+    /// Maximum packet size of endpoint zero that the host set for the current control transfer
+    pub fn max_packet_size_0(&self) -> Option<u8> {
+        self.max_packet_size_0
+    }
+
+    /// Whether the host asked for an event on every start of frame, see [`SofTimer::interrupt_on_sof`]
+    pub fn sof_interrupt_enabled(&self) -> bool {
+        self.sof_interrupt
+    }
+
+    /// Number of setup packets sent by the host so far
+    pub fn setup_count(&self) -> usize {
+        self.setup_count
+    }
+
+    /// Data that the device sends in response to bulk (and isochronous) IN transfers
+    pub fn set_bulk_in(&mut self, data: &'a [u8]) {
+        self.bulk_in = data;
+    }
+
+    /// Simulate the device sending `data` on the given interrupt IN endpoint
+    ///
+    /// Returns `false` if there is no such pipe, or if the host has not consumed the previous data yet.
+    ///
+    /// Without `InterruptPipeHw`, the data is sent in response to the next transaction on the endpoint, which the host
+    /// starts once the interval of its pipe elapsed. Until then, `false` is returned for any further data.
+    pub fn send_interrupt(&mut self, endpoint: u8, data: &[u8]) -> bool {
+        if !self.interrupt_pipe_hw {
+            if self.interrupt_in.is_some() {
+                return false;
+            }
+            let len = data.len().min(PIPE_BUFFER_SIZE);
+            self.interrupt_in_data[..len].copy_from_slice(&data[..len]);
+            self.interrupt_in = Some((endpoint, len));
+            return true;
+        }
+        let Some((index, pipe)) = self.pipes.iter_mut().enumerate().find_map(|(index, pipe)| match pipe {
+            Some(pipe) if pipe.endpoint == endpoint && pipe.direction == UsbDirection::In && !pipe.busy => Some((index, pipe)),
+            _ => None,
+        }) else {
+            return false;
+        };
+        let len = data.len().min(pipe.size as usize);
+        pipe.buf[..len].copy_from_slice(&data[..len]);
+        pipe.busy = true;
+        self.push_event(Event::InterruptPipe(index as u8));
+        true
+    }
+
+    /// Simulate the host controller polling the given interrupt OUT endpoint
+    ///
+    /// Returns the data currently in the pipe's buffer, and signals the host to refill it. Returns `None` if there is
+    /// no such pipe, or the host has not refilled the buffer yet.
+    pub fn poll_interrupt_out(&mut self, endpoint: u8) -> Option<&[u8]> {
+        let index = self.pipes.iter().position(|pipe| {
+            matches!(pipe, Some(pipe) if pipe.endpoint == endpoint && pipe.direction == UsbDirection::Out && !pipe.busy)
+        })?;
+        self.push_event(Event::InterruptPipe(index as u8));
+        // Unwrap safety: the pipe was found above
+        let pipe = self.pipes[index].as_mut().unwrap();
+        pipe.busy = true;
+        Some(&pipe.buf[..pipe.size as usize])
+    }
+
+    /// Leave scheduling of interrupt pipes to the host (see [`InterruptPipeHw::schedules_interrupt_pipes`])
+    pub fn set_host_scheduling(&mut self, enable: bool) {
+        self.host_scheduling = enable;
+    }
+
+    /// Expose the [`InterruptPipeHw`] capability (enabled by default)
+    ///
+    /// When disabled, the host runs interrupt transfers itself. The device NAKs them while it has no data, see
+    /// [`send_interrupt`](MockHostBus::send_interrupt).
+    pub fn set_interrupt_pipe_hw(&mut self, enable: bool) {
+        self.interrupt_pipe_hw = enable;
+    }
+
+    /// Expose the [`SofTimer`] capability (enabled by default)
+    ///
+    /// When disabled, no `Sof` events are generated. Instead a frame passes whenever the bus is polled without any
+    /// pending events.
+    pub fn set_sof_timer(&mut self, enable: bool) {
+        self.sof_timer = enable;
+    }
+
+    /// NAK bulk IN transactions, as if the device had no data to send
+    ///
+    /// With a retry limit in the host's NAK policy, the transfer fails with [`Error::NakLimit`]. Otherwise it stays pending.
+    pub fn set_bulk_nak(&mut self, nak: bool) {
+        self.bulk_nak = nak;
+    }
+
+    /// STALL bulk IN transactions, as if the endpoint was halted
+    ///
+    /// The halt condition is cleared by a CLEAR_FEATURE(ENDPOINT_HALT) request for an IN endpoint, if the device accepts
+    /// it (see [`MockDevice::control_responses`]).
+    pub fn set_bulk_stall(&mut self, stall: bool) {
+        self.bulk_stall = stall;
+    }
+
+    /// NAK the data and status stages of control transfers, as if the device had stopped responding
+    ///
+    /// The SETUP stage is still acknowledged. As with [`set_bulk_nak`](MockHostBus::set_bulk_nak), the transfer fails with
+    /// [`Error::NakLimit`] if the host's NAK policy has a retry limit, and stays pending otherwise.
+    pub fn set_control_nak(&mut self, nak: bool) {
+        self.control_nak = nak;
+    }
+
+    /// Whether the host is currently driving resume signalling
+    pub fn resume_signalling(&self) -> bool {
+        self.resume_signalling
+    }
+
+    /// Number of isochronous OUT packets sent by the host, and their combined length in bytes
+    pub fn isochronous_out(&self) -> (usize, usize) {
+        self.isochronous_out
+    }
+
+    /// NAK policy last set by the host for its transfers, see [`HostBus::set_nak_policy`]
+    pub fn nak_policy(&self) -> NakPolicy {
+        self.nak_policy
+    }
+
+    /// NAK policy last set by the host for the interrupt pipe on the given endpoint, if there is one
+    pub fn interrupt_pipe_nak_policy(&self, endpoint: u8) -> Option<NakPolicy> {
+        self.pipes.iter().flatten().find(|pipe| pipe.endpoint == endpoint).map(|pipe| pipe.nak_policy)
+    }
+
+    /// Expose the [`TransferChannels`] capability with the given number of channels (disabled by default, with 0)
+    ///
+    /// Bulk transfers on the channels complete right away, like all other transfers. IN transfers receive the data set
+    /// with [`set_bulk_in`](MockHostBus::set_bulk_in), up to 64 bytes.
+    pub fn set_transfer_channels(&mut self, count: u8) {
+        self.channels = count.min(MAX_CHANNELS as u8);
+    }
+
+    /// Report frame numbers (enabled by default)
+    pub fn set_frame_numbers(&mut self, enable: bool) {
+        self.frame_numbers = enable;
+    }
+
+    /// Data most recently sent by the host on the given interrupt OUT endpoint, without `InterruptPipeHw`
+    pub fn interrupt_out_data(&self, endpoint: u8) -> Option<&[u8]> {
+        match self.interrupt_out {
+            Some((out_endpoint, len)) if out_endpoint == endpoint => Some(&self.interrupt_out_data[..len]),
+            _ => None,
+        }
+    }
+
+    /// Number of times the host polled the interrupt pipe for the given endpoint, if host scheduling is enabled
+    pub fn interrupt_polls(&self, endpoint: u8) -> Option<usize> {
+        self.pipes.iter().flatten().find(|pipe| pipe.endpoint == endpoint).map(|pipe| pipe.polls)
+    }
+
+    /// Data toggle the host expects for the next packet on the interrupt pipe for the given endpoint (`true` for DATA1)
+    pub fn interrupt_data_toggle(&self, endpoint: u8) -> Option<bool> {
+        self.pipes.iter().flatten().find(|pipe| pipe.endpoint == endpoint).map(|pipe| pipe.data_toggle)
+    }
+
+    /// Address of the interrupt pipe for the given endpoint, if one was created
+    pub fn interrupt_pipe_device(&self, endpoint: u8) -> Option<DeviceAddress> {
+        self.pipes.iter().flatten().find(|pipe| pipe.endpoint == endpoint).map(|pipe| pipe.dev_addr)
+    }
+
+    fn complete_or_stall(&mut self, response: Response) {
+        self.push_event(match response {
+            Response::Data(_) => Event::TransComplete,
+            Response::Stall => Event::Stall,
+        });
+    }
+
+    /// NAK the current transaction: it is retried until the limit is reached, or indefinitely (without an event)
+    fn nak(&mut self) {
+        if self.nak_policy.retry_limit.is_some() {
+            self.push_event(Event::Error(Error::NakLimit));
+        }
+    }
+
+    fn pop_event(&mut self) -> Option<Event> {
+        if self.event_count > 0 {
+            let event = self.events[0].take();
+            self.events.rotate_left(1);
+            self.event_count -= 1;
+            event
+        } else if !self.sof_timer {
+            self.frame = (self.frame + 1) & 0x7ff;
+            None
+        } else if self.sof_interrupt {
+            // time only passes while nothing else happens
+            self.frame = (self.frame + 1) & 0x7ff;
+            Some(Event::Sof)
+        } else {
+            None
+        }
+    }
+}
+
+impl HostBus for MockHostBus<'_> {
+    fn reset_controller(&mut self) {
+        self.events = [None; MAX_EVENTS];
+        self.event_count = 0;
+        self.pipes = [const { None }; MAX_PIPES];
+        self.sof = false;
+        self.sof_interrupt = false;
+        self.setup = None;
+        self.nak_policy = NakPolicy::UNLIMITED;
+        // the device is detected again once the controller is up
+        if self.attached {
+            self.push_event(Event::Attached(self.device.speed));
+        }
+    }
+
+    fn reset_bus(&mut self) {
+        self.sof = false;
+        self.address = None;
+        self.configuration = None;
+        // like real hardware, the device is detected again after the reset
+        if self.attached {
+            self.push_event(Event::Attached(self.device.speed));
+        }
+    }
+
+    fn enable_sof(&mut self) {
+        self.sof = true;
+    }
+
+    fn sof_enabled(&self) -> bool {
+        self.sof
+    }
+
+    fn suspend(&mut self) {
+        self.sof = false;
+    }
+
+    fn start_resume(&mut self) {
+        self.resume_signalling = true;
+    }
+
+    fn end_resume(&mut self) {
+        self.resume_signalling = false;
+    }
+
+    fn set_recipient(&mut self, dev_addr: Option<DeviceAddress>, endpoint: u8, transfer_type: TransferType) {
+        self.recipient = (dev_addr, endpoint, transfer_type);
+        self.max_packet_size_0 = None;
+    }
+
+    fn set_max_packet_size_0(&mut self, max_packet_size: u8) {
+        self.max_packet_size_0 = Some(max_packet_size);
+    }
+
+    fn ls_preamble(&mut self, _enabled: bool) {}
+
+    fn stop_transaction(&mut self) {
+        // completions of the stopped transaction are never reported
+        let mut kept = 0;
+        for i in 0..self.event_count {
+            if let Some(event) = self.events[i].take().filter(|event| !matches!(event, Event::TransComplete)) {
+                self.events[kept] = Some(event);
+                kept += 1;
+            }
+        }
+        self.event_count = kept;
+    }
+
+    fn write_setup(&mut self, setup: SetupPacket) {
+        self.setup = Some(setup);
+        self.setup_count += 1;
+        self.position = 0;
+        self.push_event(Event::TransComplete);
+    }
+
+    fn write_data_in(&mut self, length: u16, _pid: bool) {
+        if self.recipient.2 == TransferType::Interrupt {
+            // without data, the device NAKs, which does not generate an event
+            if let Some((_, len)) = self.interrupt_in.filter(|(endpoint, _)| *endpoint == self.recipient.1) {
+                self.received = len.min(length as usize);
+                self.data[..self.received].copy_from_slice(&self.interrupt_in_data[..self.received]);
+                self.interrupt_in = None;
+                self.push_event(Event::TransComplete);
+            }
+            return;
+        }
+        if self.recipient.2 == TransferType::Bulk && self.bulk_stall {
+            self.push_event(Event::Stall);
+            return;
+        }
+        if self.recipient.2 == TransferType::Bulk && self.bulk_nak {
+            self.nak();
+            return;
+        }
+        if matches!(self.recipient.2, TransferType::Bulk | TransferType::Isochronous) {
+            self.received = self.bulk_in.len().min(length as usize).min(BUFFER_SIZE);
+            self.data[..self.received].copy_from_slice(&self.bulk_in[..self.received]);
+            self.push_event(Event::TransComplete);
+            return;
+        }
+        if self.control_nak {
+            self.nak();
+            return;
+        }
+        let Some(setup) = self.setup else {
+            self.push_event(Event::Stall);
+            return;
+        };
+        let configuration = [self.configuration.unwrap_or(0)];
+        let response = match self.device.respond(&setup) {
+            Response::Stall if (setup.request_type, setup.request) == (0x80, GET_CONFIGURATION) && !self.device.is_scripted(&setup) => {
+                Response::Data(&configuration)
+            }
+            response => response,
+        };
+        if setup.request_type & 0x80 != 0 {
+            // data stage
+            if let Response::Data(data) = response {
+                let data = &data[..data.len().min(setup.length as usize)];
+                let start = self.position.min(data.len());
+                let end = (start + length as usize).min(data.len()).min(start + BUFFER_SIZE);
+                self.received = end - start;
+                self.data[..self.received].copy_from_slice(&data[start..end]);
+                self.position = end;
+            }
+        } else {
+            // status stage of an OUT request: the request takes effect
+            self.received = 0;
+            if let Response::Data(_) = response {
+                match setup.request {
+                    SET_ADDRESS if setup.request_type == 0 => self.address = NonZeroU8::new(setup.value as u8).map(DeviceAddress),
+                    SET_CONFIGURATION if setup.request_type == 0 => self.configuration = Some(setup.value as u8),
+                    CLEAR_FEATURE if setup.request_type == 0x02 && setup.index & 0x80 != 0 => self.bulk_stall = false,
+                    _ => {}
+                }
+            }
+        }
+        self.complete_or_stall(response);
+    }
+
+    fn prepare_data_out(&mut self, data: &[u8]) {
+        if self.recipient.2 == TransferType::Interrupt {
+            let len = data.len().min(PIPE_BUFFER_SIZE);
+            self.interrupt_out_data[..len].copy_from_slice(&data[..len]);
+            self.interrupt_out = Some((self.recipient.1, len));
+        } else if self.recipient.2 == TransferType::Isochronous {
+            self.isochronous_out.0 += 1;
+            self.isochronous_out.1 += data.len();
+        }
+    }
+
+    fn write_data_out_prepared(&mut self, _pid: bool) {
+        if self.recipient.2 == TransferType::Control && self.control_nak {
+            self.nak();
+            return;
+        }
+        match (self.recipient.2, self.setup) {
+            (TransferType::Control, Some(setup)) if setup.request_type & 0x80 == 0 => {
+                // data stage of an OUT request
+                let response = self.device.respond(&setup);
+                self.complete_or_stall(response);
+            }
+            // status stage of an IN request, or bulk OUT
+            _ => self.push_event(Event::TransComplete),
+        }
+    }
+
+    fn poll(&mut self) -> Option<Event> {
+        self.pop_event()
+    }
+
+    fn register_waker(&mut self, waker: &Waker) -> bool {
+        // time passes on every poll, as long as frames are counted
+        if self.event_count > 0 || self.sof_interrupt || !self.sof_timer {
+            waker.wake_by_ref();
+        } else {
+            self.waker = Some(waker.clone());
+        }
+        true
+    }
+
+    fn received_data(&self, length: usize) -> &[u8] {
+        &self.data[..self.received.min(length)]
+    }
+
+    fn interrupt_pipe_hw(&mut self) -> Option<&mut dyn InterruptPipeHw> {
+        if self.interrupt_pipe_hw {
+            Some(self)
+        } else {
+            None
+        }
+    }
+
+    fn sof_timer(&mut self) -> Option<&mut dyn SofTimer> {
+        if self.sof_timer {
+            Some(self)
+        } else {
+            None
+        }
+    }
+
+    fn transfer_channels(&mut self) -> Option<&mut dyn TransferChannels> {
+        if self.channels > 0 {
+            Some(self)
+        } else {
+            None
+        }
+    }
+
+    fn set_nak_policy(&mut self, pipe_ref: Option<u8>, policy: NakPolicy) -> bool {
+        match pipe_ref {
+            None => self.nak_policy = policy,
+            Some(pipe_ref) => match self.pipes.get_mut(pipe_ref as usize) {
+                Some(Some(pipe)) => pipe.nak_policy = policy,
+                _ => return false,
+            },
+        }
+        true
+    }
+
+    fn frame_number(&self) -> Option<u16> {
+        self.frame_numbers.then_some(self.frame)
+    }
+}
+
+impl InterruptPipeHw for MockHostBus<'_> {
+    fn create_interrupt_pipe(
+        &mut self,
+        device_address: DeviceAddress,
+        endpoint_number: u8,
+        direction: UsbDirection,
+        size: u16,
+        _interval: u8,
+    ) -> Option<InterruptPipe> {
+        if size as usize > PIPE_BUFFER_SIZE {
+            return None;
+        }
+        let index = self.pipes.iter().position(|pipe| pipe.is_none())?;
+        self.pipes[index] = Some(MockPipe {
+            dev_addr: device_address,
+            endpoint: endpoint_number,
+            direction,
+            size,
+            buf: [0; PIPE_BUFFER_SIZE],
+            busy: false,
+            data_toggle: false,
+            polls: 0,
+            nak_policy: NakPolicy::UNLIMITED,
+        });
+        Some(InterruptPipe { bus_ref: index as u8 })
+    }
+
+    fn release_interrupt_pipe(&mut self, pipe_ref: u8) {
+        if let Some(pipe) = self.pipes.get_mut(pipe_ref as usize) {
+            pipe.take();
+        }
+    }
+
+    fn interrupt_buffer(&mut self, pipe_ref: u8) -> Option<&mut [u8]> {
+        let pipe = self.pipes.get_mut(pipe_ref as usize)?.as_mut()?;
+        Some(&mut pipe.buf[..pipe.size as usize])
+    }
+
+    fn pipe_continue(&mut self, pipe_ref: u8) {
+        if let Some(Some(pipe)) = self.pipes.get_mut(pipe_ref as usize) {
+            pipe.busy = false;
+        }
+    }
+
+    fn schedules_interrupt_pipes(&self) -> bool {
+        !self.host_scheduling
+    }
+
+    fn poll_interrupt_pipe(&mut self, pipe_ref: u8) {
+        if let Some(Some(pipe)) = self.pipes.get_mut(pipe_ref as usize) {
+            pipe.polls += 1;
+        }
+    }
+
+    fn set_pipe_data_toggle(&mut self, pipe_ref: u8, data_toggle: bool) {
+        if let Some(Some(pipe)) = self.pipes.get_mut(pipe_ref as usize) {
+            pipe.data_toggle = data_toggle;
+        }
+    }
+}
+
+impl TransferChannels for MockHostBus<'_> {
+    fn channel_count(&self) -> u8 {
+        self.channels
+    }
+
+    fn start_bulk_in(&mut self, channel: u8, _dev_addr: DeviceAddress, _endpoint: u8, _max_packet_size: u16, length: u16, _pid: bool) {
+        let (received, buf) = &mut self.channel_buffers[channel as usize];
+        *received = self.bulk_in.len().min(length as usize).min(PIPE_BUFFER_SIZE);
+        buf[..*received].copy_from_slice(&self.bulk_in[..*received]);
+        self.push_event(Event::ChannelComplete(channel));
+    }
+
+    fn start_bulk_out(&mut self, channel: u8, _dev_addr: DeviceAddress, _endpoint: u8, _max_packet_size: u16, _data: &[u8], _pid: bool) {
+        self.push_event(Event::ChannelComplete(channel));
+    }
+
+    fn stop_channel(&mut self, channel: u8) {
+        // the completion of the stopped transfer is never reported
+        let mut kept = 0;
+        for i in 0..self.event_count {
+            if let Some(event) = self.events[i].take().filter(|event| *event != Event::ChannelComplete(channel)) {
+                self.events[kept] = Some(event);
+                kept += 1;
+            }
+        }
+        self.event_count = kept;
+    }
+
+    fn channel_data(&self, channel: u8, length: usize) -> &[u8] {
+        let (received, buf) = &self.channel_buffers[channel as usize];
+        &buf[..(*received).min(length)]
+    }
+}
+
+impl SofTimer for MockHostBus<'_> {
+    fn interrupt_on_sof(&mut self, enable: bool) {
+        self.sof_interrupt = enable;
+    }
+}
+
+/// Fixtures shared by the tests of the host and the bundled drivers
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use core::task::{RawWaker, RawWakerVTable, Waker};
+    #[cfg(feature = "driver-kbd")]
+    use {
+        super::*,
+        crate::descriptor,
+        crate::driver::{kbd::KbdDriver, Driver},
+        crate::{PipeId, TransferError, UsbHost},
+    };
+
+    pub(crate) const DEVICE_DESCRIPTOR: &[u8] = &[
+        0x12, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x40, 0x34, 0x12, 0x78, 0x56, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01,
+    ];
+
+    /// Configuration of a boot keyboard, with an interrupt IN endpoint
+    pub(crate) const CONFIGURATION_DESCRIPTOR: &[u8] = &[
+        0x09, 0x02, 0x22, 0x00, 0x01, 0x01, 0x00, 0xa0, 0x32, // configuration 1
+        0x09, 0x04, 0x00, 0x00, 0x01, 0x03, 0x01, 0x01, 0x00, // interface 0: boot keyboard
+        0x09, 0x21, 0x11, 0x01, 0x00, 0x01, 0x22, 0x3f, 0x00, // HID
+        0x07, 0x05, 0x81, 0x03, 0x08, 0x00, 0x0a, // endpoint 1 IN, interrupt
+    ];
+
+    /// Response to the SET_PROTOCOL(boot) request of the keyboard driver
+    #[cfg(feature = "driver-kbd")]
+    pub(crate) const SET_PROTOCOL: ControlResponse = ControlResponse {
+        request_type: 0x21,
+        request: 0x0b,
+        value: 0,
+        index: 0,
+        response: Response::Data(&[]),
+    };
+
+    /// Boot keyboard, which accepts the SET_PROTOCOL request
+    #[cfg(feature = "driver-kbd")]
+    pub(crate) fn keyboard() -> MockDevice<'static> {
+        MockDevice { control_responses: &[SET_PROTOCOL], ..MockDevice::new(DEVICE_DESCRIPTOR, &[CONFIGURATION_DESCRIPTOR]) }
+    }
+
+    /// Attach the device of the host's bus, and poll until the keyboard driver configured it
+    ///
+    /// The `observer` (if any) is polled after the keyboard driver. Returns the address of the device.
+    #[cfg(feature = "driver-kbd")]
+    pub(crate) fn enumerate<
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    >(
+        host: &mut UsbHost<MockHostBus<'_>, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+        kbd: &mut KbdDriver,
+        mut observer: Option<&mut Observer>,
+    ) -> DeviceAddress {
+        host.bus().attach();
+        for _ in 0..1000 {
+            match observer.as_deref_mut() {
+                Some(observer) => host.poll(&mut [&mut *kbd, observer]),
+                None => host.poll(&mut [&mut *kbd]),
+            };
+            kbd.poll(host);
+        }
+        host.bus().address().expect("device was enumerated")
+    }
+
+    /// Host with a [`keyboard`] attached, which was enumerated and configured by the returned driver
+    #[cfg(feature = "driver-kbd")]
+    pub(crate) fn enumerated_keyboard() -> (UsbHost<MockHostBus<'static>>, KbdDriver, DeviceAddress) {
+        let mut host = UsbHost::new(MockHostBus::new(keyboard()));
+        let mut kbd = KbdDriver::new();
+        let dev_addr = enumerate(&mut host, &mut kbd, None);
+        (host, kbd, dev_addr)
+    }
+
+    /// Waker which sets the given flag when woken
+    pub(crate) fn flag_waker(flag: &'static AtomicBool) -> Waker {
+        const VTABLE: RawWakerVTable = RawWakerVTable::new(
+            |data| RawWaker::new(data, &VTABLE),
+            |data| unsafe { (*(data as *const AtomicBool)).store(true, Ordering::Relaxed) },
+            |data| unsafe { (*(data as *const AtomicBool)).store(true, Ordering::Relaxed) },
+            |_| {},
+        );
+        // Safety: the flag lives forever, and the functions of the vtable only access it atomically
+        unsafe { Waker::from_raw(RawWaker::new(flag as *const AtomicBool as *const (), &VTABLE)) }
+    }
+
+    /// Records the callbacks of the host that it is informed about, without claiming any device
+    #[cfg(feature = "driver-kbd")]
+    #[derive(Default)]
+    pub(crate) struct Observer {
+        /// Number of completed control transfers
+        pub completed: usize,
+        /// Number of bytes received by IN transfers
+        pub received: usize,
+        pub failed: Option<TransferError>,
+        /// Endpoint and result of the most recent endpoint recovery
+        pub recovered: Option<(u8, Result<(), TransferError>)>,
+        /// Context of the most recent endpoint descriptor
+        pub endpoint_context: Option<descriptor::DescriptorContext>,
+        /// Set between the `suspended` and `resumed` callbacks
+        pub suspended: bool,
+    }
+
+    #[cfg(feature = "driver-kbd")]
+    impl<
+            B: HostBus,
+            const MAX_PIPES: usize,
+            const CONTROL_QUEUE_DEPTH: usize,
+            const CONTROL_BUFFER_SIZE: usize,
+        > Driver<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE> for Observer
+    {
+        fn attached(&mut self, _dev_addr: DeviceAddress, _connection_speed: ConnectionSpeed) {}
+        fn detached(&mut self, _dev_addr: DeviceAddress) {}
+        fn descriptor(&mut self, _dev_addr: DeviceAddress, _descriptor_type: u8, _data: &[u8]) {}
+        fn descriptor_with_context(&mut self, _dev_addr: DeviceAddress, descriptor_type: u8, _data: &[u8], context: descriptor::DescriptorContext) {
+            if descriptor_type == descriptor::TYPE_ENDPOINT {
+                self.endpoint_context = Some(context);
+            }
+        }
+        fn configure(&mut self, _dev_addr: DeviceAddress) -> Option<u8> {
+            None
+        }
+        fn configured(
+            &mut self,
+            _dev_addr: DeviceAddress,
+            _value: u8,
+            _host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+        ) {
+        }
+        fn completed_control(&mut self, _dev_addr: DeviceAddress, _pipe_id: PipeId, _data: Option<&[u8]>) {
+            self.completed += 1;
+        }
+        fn completed_in(&mut self, _dev_addr: DeviceAddress, _pipe_id: PipeId, data: &[u8]) {
+            self.received += data.len();
+        }
+        fn completed_out(&mut self, _dev_addr: DeviceAddress, _pipe_id: PipeId, _data: &mut [u8]) {}
+        fn transfer_failed(&mut self, _dev_addr: DeviceAddress, _pipe_id: PipeId, error: TransferError) {
+            self.failed = Some(error);
+        }
+        fn endpoint_recovered(&mut self, _dev_addr: DeviceAddress, endpoint: u8, result: Result<(), TransferError>) {
+            self.recovered = Some((endpoint, result));
+        }
+        fn suspended(&mut self) {
+            self.suspended = true;
+        }
+        fn resumed(&mut self) {
+            self.suspended = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixtures::{flag_waker, CONFIGURATION_DESCRIPTOR, DEVICE_DESCRIPTOR};
+    use super::*;
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    fn test_stall_unknown_request() {
+        let device = MockDevice::new(DEVICE_DESCRIPTOR, &[CONFIGURATION_DESCRIPTOR]);
+        let setup = SetupPacket { request_type: 0xc0, request: 0x01, value: 0, index: 0, length: 4 };
+        assert!(matches!(device.respond(&setup), Response::Stall));
+        let setup = SetupPacket { request_type: 0x80, request: GET_DESCRIPTOR, value: 0x0200, index: 0, length: 9 };
+        assert!(matches!(device.respond(&setup), Response::Data(data) if data == CONFIGURATION_DESCRIPTOR));
+    }
+
+    #[test]
+    fn test_register_waker() {
+        static WOKEN: AtomicBool = AtomicBool::new(false);
+        let mut bus = MockHostBus::new(MockDevice::new(DEVICE_DESCRIPTOR, &[CONFIGURATION_DESCRIPTOR]));
+        assert!(bus.register_waker(&flag_waker(&WOKEN)));
+        assert!(!WOKEN.load(Ordering::Relaxed));
+        bus.attach();
+        assert!(WOKEN.swap(false, Ordering::Relaxed));
+        // an event is pending already
+        assert!(bus.register_waker(&flag_waker(&WOKEN)));
+        assert!(WOKEN.load(Ordering::Relaxed));
+    }
+}
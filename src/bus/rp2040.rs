@@ -0,0 +1,561 @@
+//! Host bus implementation for the RP2040
+//!
+//! Uses the USB controller of the RP2040 in host mode, via the registers exposed by the `rp2040-pac` crate.
+//!
+//! ```ignore
+//! let mut pac = rp2040_pac::Peripherals::take().unwrap();
+//! // ... set up clocks: the USB PLL must provide 48 MHz to clk_usb ...
+//! let host_bus = Rp2040HostBus::new(pac.USBCTRL_REGS, pac.USBCTRL_DPRAM, &mut pac.RESETS);
+//! let mut usb_host = UsbHost::new(host_bus);
+//! unsafe { cortex_m::peripheral::NVIC::unmask(rp2040_pac::Interrupt::USBCTRL_IRQ) };
+//! ```
+//!
+//! The controller raises `USBCTRL_IRQ` for all events that the host needs to handle, so calling
+//! [`UsbHost::poll`](crate::UsbHost::poll) from that interrupt handler is sufficient.
+//!
+//! With the `critical-section` feature, the bus can wake the tasks of the [`asynch`](crate::asynch) API instead. The
+//! interrupt handler then only calls [`Rp2040HostBus::on_interrupt`]:
+//!
+//! ```ignore
+//! #[interrupt]
+//! fn USBCTRL_IRQ() {
+//!     Rp2040HostBus::on_interrupt();
+//! }
+//! ```
+//!
+//! ## Limitations
+//!
+//! - Transfers through the single EPX endpoint are split into packets of 64 bytes (or the maximum packet size of endpoint
+//!   zero, for control transfers) in software. Control and bulk transfers are limited to [`MAX_TRANSFER_SIZE`] bytes.
+//! - Interrupt pipes use the 15 interrupt endpoints of the controller, with packets of up to 64 bytes.
+//! - The controller cannot drive resume signalling in host mode, so [`HostBus::start_resume`] is not implemented.
+//!   Devices are woken up by restarting SOF packets instead, which most devices accept.
+//! - NAKed transactions are retried by the controller, without a limit. Only the interval between retries can be set
+//!   with [`HostBus::set_nak_policy`], for the transfers through the EPX endpoint. Interrupt pipes keep their polling interval.
+
+use super::{Error, Event, HostBus, InterruptPipe, InterruptPipeHw, NakPolicy, SofTimer};
+use crate::types::{ConnectionSpeed, DeviceAddress, SetupPacket, TransferType};
+use crate::HubPort;
+use rp2040_pac::{usbctrl_dpram, RESETS, USBCTRL_DPRAM, USBCTRL_REGS};
+use usb_device::UsbDirection;
+#[cfg(feature = "critical-section")]
+use {core::cell::RefCell, core::task::Waker, critical_section::Mutex};
+
+/// Maximum length of a control or bulk transfer
+pub const MAX_TRANSFER_SIZE: usize = 1024;
+
+/// Size of the DPRAM
+const DPRAM_SIZE: usize = 4096;
+/// Packet size used for the EPX endpoint
+const PACKET_SIZE: usize = 64;
+/// Offset of the EPX buffer in DPRAM. Packets of a transfer are placed one after the other.
+const EPX_BUFFER: usize = 0x180;
+/// Offset of the interrupt endpoint buffers in DPRAM, following the EPX buffer
+const INTERRUPT_BUFFERS: usize = EPX_BUFFER + MAX_TRANSFER_SIZE;
+/// Number of interrupt endpoints supported by the controller
+const INTERRUPT_ENDPOINTS: usize = 15;
+/// Interval between retries of NAKed transactions after reset, in microseconds
+const NAK_POLL_DEFAULT: u16 = 16;
+/// Largest interval between retries that the `NAK_POLL` register can hold
+const NAK_POLL_MAX: u16 = 0x3ff;
+
+/// Transfer on the EPX endpoint, which spans multiple packets
+#[derive(Copy, Clone, Debug)]
+struct EpxTransfer {
+    direction: UsbDirection,
+    length: usize,
+    /// Number of bytes transferred so far
+    offset: usize,
+    /// PID of the current packet
+    pid: bool,
+}
+
+/// Waker registered by the async API, woken from the interrupt handler
+#[cfg(feature = "critical-section")]
+static WAKER: Mutex<RefCell<Option<Waker>>> = Mutex::new(RefCell::new(None));
+
+/// Interrupt pipe, using one of the controller's interrupt endpoints
+#[derive(Copy, Clone, Debug)]
+struct Rp2040Pipe {
+    direction: UsbDirection,
+    size: u16,
+    /// PID of the next packet
+    pid: bool,
+}
+
+/// [`HostBus`] implementation for the USB controller of the RP2040
+///
+/// See the [module documentation](self) for details.
+#[derive(Debug)]
+pub struct Rp2040HostBus {
+    regs: USBCTRL_REGS,
+    dpram: USBCTRL_DPRAM,
+    transfer: Option<EpxTransfer>,
+    /// Packet size of the EPX endpoint, for the current recipient
+    packet_size: usize,
+    /// Pipe for each of the interrupt endpoints (index 0 is endpoint 1)
+    pipes: [Option<Rp2040Pipe>; INTERRUPT_ENDPOINTS],
+    /// Whether the SOF interrupt is enabled
+    sof_interrupt: bool,
+}
+
+impl Rp2040HostBus {
+    /// Take control of the USB controller, and bring it out of reset
+    ///
+    /// The USB clock (48 MHz) must be set up before.
+    pub fn new(regs: USBCTRL_REGS, dpram: USBCTRL_DPRAM, resets: &mut RESETS) -> Self {
+        resets.reset().modify(|_, w| w.usbctrl().set_bit());
+        resets.reset().modify(|_, w| w.usbctrl().clear_bit());
+        while resets.reset_done().read().usbctrl().bit_is_clear() {}
+        Self {
+            regs,
+            dpram,
+            transfer: None,
+            packet_size: PACKET_SIZE,
+            pipes: [None; INTERRUPT_ENDPOINTS],
+            sof_interrupt: false,
+        }
+    }
+
+    /// Wake the task waiting for new events, if any
+    ///
+    /// Call this from the `USBCTRL_IRQ` handler when using the [`asynch`](crate::asynch) API. The controller's interrupts
+    /// are disabled until the task waits again, so that the handler does not keep firing until the events are processed.
+    #[cfg(feature = "critical-section")]
+    pub fn on_interrupt() {
+        critical_section::with(|cs| {
+            // Safety: only the interrupt enable register is written, which the bus restores before waiting again
+            unsafe { (*USBCTRL_REGS::ptr()).inte().write(|w| w.bits(0)) };
+            if let Some(waker) = WAKER.borrow(cs).take() {
+                waker.wake();
+            }
+        });
+    }
+
+    /// Release the peripherals
+    pub fn free(self) -> (USBCTRL_REGS, USBCTRL_DPRAM) {
+        (self.regs, self.dpram)
+    }
+
+    fn dpram_ptr(&self, offset: usize) -> *mut u8 {
+        // Safety: callers stay within the DPRAM
+        unsafe { (USBCTRL_DPRAM::ptr() as *mut u8).add(offset) }
+    }
+
+    /// Enable the interrupts for all events the host handles
+    fn enable_interrupts(&self) {
+        self.regs.inte().write(|w| {
+            w.host_conn_dis().set_bit();
+            w.host_resume().set_bit();
+            w.host_sof().bit(self.sof_interrupt);
+            w.stall().set_bit();
+            w.trans_complete().set_bit();
+            w.buff_status().set_bit();
+            w.error_rx_timeout().set_bit();
+            w.error_data_seq().set_bit();
+            w.error_crc().set_bit();
+            w.error_bit_stuff().set_bit();
+            w.error_rx_overflow().set_bit()
+        });
+    }
+
+    /// Pause for the controller to pick up changes to the buffer control registers
+    ///
+    /// The datasheet requires a few cycles of clk_usb between setting up a buffer and marking it available.
+    fn settle() {
+        for _ in 0..12 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Set up the EPX buffer for the next packet of the current transfer, and start the transaction
+    fn start_packet(&mut self) {
+        let Some(transfer) = self.transfer else {
+            return;
+        };
+        let len = (transfer.length - transfer.offset).min(self.packet_size);
+        let out = transfer.direction == UsbDirection::Out;
+        self.dpram.epx_control().modify(|_, w| unsafe { w.buffer_address().bits((EPX_BUFFER + transfer.offset) as u16) });
+        self.dpram.ep_buffer_control(0).write(|w| unsafe {
+            w.length_0().bits(len as u16);
+            w.pid_0().bit(transfer.pid);
+            w.full_0().bit(out);
+            w.last_0().set_bit()
+        });
+        Self::settle();
+        self.dpram.ep_buffer_control(0).modify(|_, w| w.available_0().set_bit());
+        self.regs.sie_ctrl().modify(|_, w| {
+            w.send_setup().clear_bit();
+            w.send_data().bit(out);
+            w.receive_data().bit(!out)
+        });
+        Self::settle();
+        self.regs.sie_ctrl().modify(|_, w| w.start_trans().set_bit());
+    }
+
+    /// Handle completion of a packet on EPX
+    ///
+    /// Returns `true` if the transfer is complete, otherwise starts the next packet.
+    fn packet_complete(&mut self) -> bool {
+        let Some(transfer) = self.transfer.as_mut() else {
+            // SETUP packet
+            return true;
+        };
+        let sent = self.dpram.ep_buffer_control(0).read().length_0().bits() as usize;
+        transfer.offset += sent;
+        transfer.pid = !transfer.pid;
+        // a short packet ends the transfer early
+        if transfer.offset >= transfer.length || sent < self.packet_size {
+            return true;
+        }
+        self.start_packet();
+        false
+    }
+
+    fn arm_interrupt_buffer(&mut self, index: usize) {
+        let Some(pipe) = self.pipes[index].as_mut() else {
+            return;
+        };
+        let (size, pid, out) = (pipe.size, pipe.pid, pipe.direction == UsbDirection::Out);
+        pipe.pid = !pipe.pid;
+        let buffer_control = self.dpram.ep_buffer_control(2 * (index + 1));
+        buffer_control.write(|w| unsafe {
+            w.length_0().bits(size);
+            w.pid_0().bit(pid);
+            w.full_0().bit(out);
+            w.last_0().set_bit()
+        });
+        Self::settle();
+        buffer_control.modify(|_, w| w.available_0().set_bit());
+    }
+
+    /// Set up an interrupt pipe on one of the interrupt endpoints
+    ///
+    /// If `preamble` is set, each transaction is preceded by a PRE packet (for low speed devices behind a hub).
+    fn create_pipe(
+        &mut self,
+        device_address: DeviceAddress,
+        endpoint_number: u8,
+        direction: UsbDirection,
+        size: u16,
+        interval: u8,
+        preamble: bool,
+    ) -> Option<InterruptPipe> {
+        if size as usize > PACKET_SIZE {
+            return None;
+        }
+        let index = self.pipes.iter().position(|pipe| pipe.is_none())?;
+        let buffer = INTERRUPT_BUFFERS + index * PACKET_SIZE;
+        self.pipes[index] = Some(Rp2040Pipe { direction, size, pid: false });
+
+        self.regs.host_addr_endp(index).write(|w| unsafe {
+            w.address().bits(u8::from(device_address));
+            w.endpoint().bits(endpoint_number);
+            w.intep_dir().bit(direction == UsbDirection::Out);
+            w.intep_preamble().bit(preamble)
+        });
+        self.dpram.ep_control(2 * index).write(|w| unsafe {
+            w.enable().set_bit();
+            w.interrupt_per_buff().set_bit();
+            w.endpoint_type().variant(usbctrl_dpram::ep_control::ENDPOINT_TYPE_A::INTERRUPT);
+            w.host_poll_interval().bits(interval.max(1) as u16 - 1);
+            w.buffer_address().bits(buffer as u16)
+        });
+        if direction == UsbDirection::In {
+            // OUT pipes are armed once the driver filled the buffer
+            self.arm_interrupt_buffer(index);
+        }
+        self.regs.int_ep_ctrl().modify(|r, w| unsafe { w.int_ep_active().bits(r.int_ep_active().bits() | (1 << index)) });
+
+        Some(InterruptPipe { bus_ref: index as u8 + 1 })
+    }
+
+    fn clear_errors(&mut self) {
+        self.regs.sie_status().write(|w| {
+            w.crc_error().clear_bit_by_one();
+            w.bit_stuff_error().clear_bit_by_one();
+            w.rx_overflow().clear_bit_by_one();
+            w.rx_timeout().clear_bit_by_one();
+            w.data_seq_error().clear_bit_by_one()
+        });
+    }
+}
+
+impl HostBus for Rp2040HostBus {
+    fn reset_controller(&mut self) {
+        // Safety: the DPRAM is owned by this bus
+        unsafe { core::ptr::write_bytes(self.dpram_ptr(0), 0, DPRAM_SIZE) };
+        self.transfer = None;
+        self.pipes = [None; INTERRUPT_ENDPOINTS];
+
+        self.regs.usb_muxing().write(|w| w.to_phy().set_bit().softcon().set_bit());
+        self.regs.usb_pwr().write(|w| w.vbus_detect().set_bit().vbus_detect_override_en().set_bit());
+        self.regs.main_ctrl().write(|w| w.controller_en().set_bit().host_ndevice().set_bit());
+        self.regs.sie_ctrl().write(|w| w.pulldown_en().set_bit().ep0_int_1buf().set_bit());
+        self.regs.nak_poll().reset();
+        self.sof_interrupt = false;
+        self.enable_interrupts();
+    }
+
+    fn reset_bus(&mut self) {
+        self.regs.sie_ctrl().modify(|_, w| w.reset_bus().set_bit());
+    }
+
+    fn enable_sof(&mut self) {
+        self.regs.sie_ctrl().modify(|_, w| w.sof_en().set_bit().keep_alive_en().set_bit());
+    }
+
+    fn sof_enabled(&self) -> bool {
+        self.regs.sie_ctrl().read().sof_en().bit_is_set()
+    }
+
+    fn suspend(&mut self) {
+        self.regs.sie_ctrl().modify(|_, w| w.sof_en().clear_bit().keep_alive_en().clear_bit());
+    }
+
+    fn set_recipient(&mut self, dev_addr: Option<DeviceAddress>, endpoint: u8, transfer_type: TransferType) {
+        let address = dev_addr.map_or(0, u8::from);
+        self.regs.addr_endp().write(|w| unsafe { w.address().bits(address).endpoint().bits(endpoint) });
+        let endpoint_type = match transfer_type {
+            TransferType::Control => usbctrl_dpram::epx_control::ENDPOINT_TYPE_A::CONTROL,
+            TransferType::Isochronous => usbctrl_dpram::epx_control::ENDPOINT_TYPE_A::ISOCHRONOUS,
+            TransferType::Bulk => usbctrl_dpram::epx_control::ENDPOINT_TYPE_A::BULK,
+            TransferType::Interrupt => usbctrl_dpram::epx_control::ENDPOINT_TYPE_A::INTERRUPT,
+        };
+        self.dpram.epx_control().write(|w| unsafe {
+            w.enable().set_bit();
+            w.interrupt_per_buff().set_bit();
+            w.endpoint_type().variant(endpoint_type);
+            w.buffer_address().bits(EPX_BUFFER as u16)
+        });
+        self.packet_size = PACKET_SIZE;
+    }
+
+    fn set_max_packet_size_0(&mut self, max_packet_size: u8) {
+        self.packet_size = (max_packet_size as usize).min(PACKET_SIZE);
+    }
+
+    fn ls_preamble(&mut self, enabled: bool) {
+        self.regs.sie_ctrl().modify(|_, w| w.preamble_en().bit(enabled));
+    }
+
+    fn stop_transaction(&mut self) {
+        self.transfer = None;
+        self.regs.sie_ctrl().modify(|_, w| w.stop_trans().set_bit());
+    }
+
+    fn write_setup(&mut self, setup: SetupPacket) {
+        self.transfer = None;
+        self.dpram.setup_packet_low().write(|w| unsafe {
+            w.bmrequesttype().bits(setup.request_type);
+            w.brequest().bits(setup.request);
+            w.wvalue().bits(setup.value)
+        });
+        self.dpram.setup_packet_high().write(|w| unsafe { w.windex().bits(setup.index).wlength().bits(setup.length) });
+        self.regs.sie_ctrl().modify(|_, w| {
+            w.send_setup().set_bit();
+            w.send_data().clear_bit();
+            w.receive_data().clear_bit()
+        });
+        Self::settle();
+        self.regs.sie_ctrl().modify(|_, w| w.start_trans().set_bit());
+    }
+
+    fn write_data_in(&mut self, length: u16, pid: bool) {
+        self.transfer = Some(EpxTransfer {
+            direction: UsbDirection::In,
+            length: (length as usize).min(MAX_TRANSFER_SIZE),
+            offset: 0,
+            pid,
+        });
+        self.start_packet();
+    }
+
+    fn prepare_data_out(&mut self, data: &[u8]) {
+        let len = data.len().min(MAX_TRANSFER_SIZE);
+        // Safety: the EPX buffer region is MAX_TRANSFER_SIZE bytes long, and not in use by the controller between transfers
+        unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), self.dpram_ptr(EPX_BUFFER), len) };
+        self.transfer = Some(EpxTransfer { direction: UsbDirection::Out, length: len, offset: 0, pid: false });
+    }
+
+    fn write_data_out_prepared(&mut self, pid: bool) {
+        if let Some(transfer) = self.transfer.as_mut() {
+            transfer.pid = pid;
+        }
+        self.start_packet();
+    }
+
+    fn poll(&mut self) -> Option<Event> {
+        // the raw status is read, since `on_interrupt` disables the interrupts until the next waker is registered
+        let ints = self.regs.intr().read();
+        if ints.host_conn_dis().bit_is_set() {
+            let speed = self.regs.sie_status().read().speed().bits();
+            // Safety: writing ones clears the speed change
+            self.regs.sie_status().write(|w| unsafe { w.speed().bits(0b11) });
+            return Some(match speed {
+                0b01 => Event::Attached(ConnectionSpeed::Low),
+                0b10 => Event::Attached(ConnectionSpeed::Full),
+                _ => {
+                    self.transfer = None;
+                    Event::Detached
+                }
+            });
+        }
+        if ints.stall().bit_is_set() {
+            self.regs.sie_status().write(|w| w.stall_rec().clear_bit_by_one());
+            self.transfer = None;
+            return Some(Event::Stall);
+        }
+        if ints.error_rx_timeout().bit_is_set()
+            || ints.error_data_seq().bit_is_set()
+            || ints.error_crc().bit_is_set()
+            || ints.error_bit_stuff().bit_is_set()
+            || ints.error_rx_overflow().bit_is_set()
+        {
+            let status = self.regs.sie_status().read();
+            let error = if status.rx_timeout().bit_is_set() {
+                Error::RxTimeout
+            } else if status.data_seq_error().bit_is_set() {
+                Error::DataSequence
+            } else if status.crc_error().bit_is_set() {
+                Error::Crc
+            } else if status.bit_stuff_error().bit_is_set() {
+                Error::BitStuffing
+            } else if status.rx_overflow().bit_is_set() {
+                Error::RxOverflow
+            } else {
+                Error::Other
+            };
+            self.clear_errors();
+            self.transfer = None;
+            return Some(Event::Error(error));
+        }
+        if ints.trans_complete().bit_is_set() {
+            self.regs.sie_status().write(|w| w.trans_complete().clear_bit_by_one());
+            // the EPX buffer status is not needed, since completion is tracked via TRANS_COMPLETE
+            self.regs.buff_status().write(|w| w.ep0_in().clear_bit_by_one().ep0_out().clear_bit_by_one());
+            if self.packet_complete() {
+                return Some(Event::TransComplete);
+            }
+            return None;
+        }
+        if ints.buff_status().bit_is_set() {
+            let status = self.regs.buff_status().read().bits();
+            // interrupt endpoint `n` reports on bit `2 * n`
+            if let Some(index) = (0..INTERRUPT_ENDPOINTS).find(|i| status & (1 << (2 * (i + 1))) != 0) {
+                // Safety: writing a one clears the bit
+                self.regs.buff_status().write(|w| unsafe { w.bits(1 << (2 * (index + 1))) });
+                return Some(Event::InterruptPipe(index as u8 + 1));
+            }
+            // EPX, handled via TRANS_COMPLETE
+            self.regs.buff_status().write(|w| w.ep0_in().clear_bit_by_one().ep0_out().clear_bit_by_one());
+        }
+        if ints.host_resume().bit_is_set() {
+            self.regs.sie_status().write(|w| w.resume().clear_bit_by_one());
+            return Some(Event::Resume);
+        }
+        if self.sof_interrupt && ints.host_sof().bit_is_set() {
+            // reading SOF_RD clears the interrupt
+            self.regs.sof_rd().read();
+            return Some(Event::Sof);
+        }
+        None
+    }
+
+    fn received_data(&self, length: usize) -> &[u8] {
+        let len = self.transfer.map_or(0, |transfer| transfer.offset).min(length);
+        // Safety: the EPX buffer region is MAX_TRANSFER_SIZE bytes long, and the transfer is complete
+        unsafe { core::slice::from_raw_parts(self.dpram_ptr(EPX_BUFFER), len) }
+    }
+
+    #[cfg(feature = "critical-section")]
+    fn register_waker(&mut self, waker: &Waker) -> bool {
+        critical_section::with(|cs| WAKER.borrow(cs).replace(Some(waker.clone())));
+        self.enable_interrupts();
+        true
+    }
+
+    fn interrupt_pipe_hw(&mut self) -> Option<&mut dyn InterruptPipeHw> {
+        Some(self)
+    }
+
+    fn sof_timer(&mut self) -> Option<&mut dyn SofTimer> {
+        Some(self)
+    }
+
+    fn set_nak_policy(&mut self, pipe_ref: Option<u8>, policy: NakPolicy) -> bool {
+        // interrupt endpoints are polled at their own interval
+        if pipe_ref.is_some() {
+            return false;
+        }
+        let interval = policy.retry_interval.unwrap_or(NAK_POLL_DEFAULT).min(NAK_POLL_MAX);
+        self.regs.nak_poll().write(|w| unsafe { w.delay_fs().bits(interval).delay_ls().bits(interval) });
+        policy.retry_limit.is_none() && policy.retry_interval.is_none_or(|interval| interval <= NAK_POLL_MAX)
+    }
+
+    fn frame_number(&self) -> Option<u16> {
+        Some(self.regs.sof_rd().read().count().bits())
+    }
+}
+
+impl InterruptPipeHw for Rp2040HostBus {
+    fn create_interrupt_pipe(
+        &mut self,
+        device_address: DeviceAddress,
+        endpoint_number: u8,
+        direction: UsbDirection,
+        size: u16,
+        interval: u8,
+    ) -> Option<InterruptPipe> {
+        self.create_pipe(device_address, endpoint_number, direction, size, interval, false)
+    }
+
+    fn create_low_speed_interrupt_pipe(
+        &mut self,
+        device_address: DeviceAddress,
+        endpoint_number: u8,
+        direction: UsbDirection,
+        size: u16,
+        interval: u8,
+        _hub: HubPort,
+    ) -> Option<InterruptPipe> {
+        self.create_pipe(device_address, endpoint_number, direction, size, interval, true)
+    }
+
+    fn release_interrupt_pipe(&mut self, pipe_ref: u8) {
+        let Some(index) = (pipe_ref as usize).checked_sub(1).filter(|i| *i < INTERRUPT_ENDPOINTS) else {
+            return;
+        };
+        self.regs.int_ep_ctrl().modify(|r, w| unsafe { w.int_ep_active().bits(r.int_ep_active().bits() & !(1 << index)) });
+        self.dpram.ep_control(2 * index).write(|w| w.enable().clear_bit());
+        self.regs.host_addr_endp(index).write(|w| unsafe { w.bits(0) });
+        self.pipes[index] = None;
+    }
+
+    fn interrupt_buffer(&mut self, pipe_ref: u8) -> Option<&mut [u8]> {
+        let index = (pipe_ref as usize).checked_sub(1)?;
+        let size = self.pipes.get(index)?.as_ref()?.size as usize;
+        // Safety: each pipe has its own region of PACKET_SIZE bytes in the DPRAM, and the controller does not access
+        //   it until the buffer is armed again by `pipe_continue`
+        Some(unsafe { core::slice::from_raw_parts_mut(self.dpram_ptr(INTERRUPT_BUFFERS + index * PACKET_SIZE), size) })
+    }
+
+    fn pipe_continue(&mut self, pipe_ref: u8) {
+        if let Some(index) = (pipe_ref as usize).checked_sub(1).filter(|i| *i < INTERRUPT_ENDPOINTS) {
+            self.arm_interrupt_buffer(index);
+        }
+    }
+
+    fn set_pipe_data_toggle(&mut self, pipe_ref: u8, data_toggle: bool) {
+        if let Some(Some(pipe)) = (pipe_ref as usize).checked_sub(1).and_then(|index| self.pipes.get_mut(index)) {
+            pipe.pid = data_toggle;
+        }
+    }
+}
+
+impl SofTimer for Rp2040HostBus {
+    fn interrupt_on_sof(&mut self, enable: bool) {
+        self.sof_interrupt = enable;
+        self.regs.inte().modify(|_, w| w.host_sof().bit(enable));
+    }
+}
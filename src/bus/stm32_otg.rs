@@ -0,0 +1,806 @@
+//! Host bus implementation for the OTG_FS controller of STM32F4 / STM32F7 devices
+//!
+//! The controller is a Synopsys DWC2 core, which uses a set of *host channels* instead of fixed endpoints. Data is
+//! exchanged through FIFOs: every channel has its own transmit FIFO address, while all received data is taken from a
+//! shared receive FIFO (reported via `RXFLVL`).
+//!
+//! Registers are accessed directly, so no peripheral access crate is needed. The layout of the register block is the
+//! same for all of the supported families.
+//!
+//! ```ignore
+//! let dp = stm32f4xx_hal::pac::Peripherals::take().unwrap();
+//! // ... set up clocks: the OTG_FS peripheral needs 48 MHz, and its RCC clock must be enabled ...
+//! // ... configure PA11 / PA12 for the OTG_FS alternate function, and switch on VBUS for the port ...
+//! let host_bus = unsafe { Stm32OtgHostBus::new(OTG_FS_BASE, 168_000_000) };
+//! let mut usb_host = UsbHost::new(host_bus);
+//! unsafe { cortex_m::peripheral::NVIC::unmask(stm32f4xx_hal::pac::Interrupt::OTG_FS) };
+//! ```
+//!
+//! The controller raises `OTG_FS` for all events that the host needs to handle, so calling
+//! [`UsbHost::poll`](crate::UsbHost::poll) from that interrupt handler is sufficient.
+//!
+//! ## Channels
+//!
+//! - Channel 0 is used for control and bulk transfers. Like on the RP2040, transfers are split into packets in
+//!   software, with a single packet in flight at a time. Transfers are limited to [`MAX_TRANSFER_SIZE`] bytes.
+//! - Channels 1 to 7 are used for interrupt pipes, with packets of up to 64 bytes. The `bus_ref` of a pipe is the
+//!   number of its channel.
+//!
+//! ## NAK handling
+//!
+//! The core does not retry transactions that were NAKed on its own. This implementation retries them:
+//!
+//! - on channel 0, IN transactions are re-enabled right away, while OUT transactions are halted and sent again once
+//!   the channel was halted (since the FIFO has to be refilled).
+//! - on interrupt channels, the transaction is retried after the polling interval of the pipe has elapsed. For this,
+//!   SOF interrupts stay enabled while interrupt pipes exist, but [`Event::Sof`] is only generated when requested
+//!   via [`SofTimer::interrupt_on_sof`].
+//!
+//! A retry limit can be set for channel 0 with [`HostBus::set_nak_policy`]. Retry intervals are not supported.
+//!
+//! ## Limitations
+//!
+//! - Port resets are timed with a busy loop, calibrated from the system clock passed to [`Stm32OtgHostBus::new`].
+//!   This blocks for about 15 ms, twice during the enumeration of each device.
+//! - The core cannot send PRE packets, so low speed devices behind a full speed hub are not supported
+//!   ([`HostBus::ls_preamble`] is ignored).
+//! - Over-current conditions on the port are only logged.
+
+use super::{Error, Event, HostBus, InterruptPipe, InterruptPipeHw, NakPolicy, SofTimer};
+use crate::types::{ConnectionSpeed, DeviceAddress, SetupPacket, TransferType};
+use usb_device::UsbDirection;
+
+/// Base address of the OTG_FS register block on STM32F4 and STM32F7 devices
+pub const OTG_FS_BASE: usize = 0x5000_0000;
+
+/// Maximum length of a control or bulk transfer
+pub const MAX_TRANSFER_SIZE: usize = 1024;
+
+/// Number of host channels of the OTG_FS core (the STM32F7 has 12, but only the first 8 are used)
+const CHANNELS: usize = 8;
+/// Maximum packet size of full speed control, bulk and interrupt endpoints
+const PACKET_SIZE: usize = 64;
+/// Maximum packet size of low speed control and interrupt endpoints
+const LOW_SPEED_PACKET_SIZE: usize = 8;
+/// Duration of a port reset
+const RESET_MS: u32 = 15;
+/// Time the core needs to switch into host mode
+const MODE_SWITCH_MS: u32 = 25;
+
+// Core global registers
+const GAHBCFG: usize = 0x008;
+const GUSBCFG: usize = 0x00C;
+const GRSTCTL: usize = 0x010;
+const GINTSTS: usize = 0x014;
+const GINTMSK: usize = 0x018;
+const GRXSTSP: usize = 0x020;
+const GRXFSIZ: usize = 0x024;
+const HNPTXFSIZ: usize = 0x028;
+const GCCFG: usize = 0x038;
+const HPTXFSIZ: usize = 0x100;
+// Host mode registers
+const HCFG: usize = 0x400;
+const HFIR: usize = 0x404;
+const HFNUM: usize = 0x408;
+const HAINT: usize = 0x414;
+const HAINTMSK: usize = 0x418;
+const HPRT: usize = 0x440;
+const PCGCCTL: usize = 0xE00;
+// Channel registers, relative to the channel's register block
+const HCCHAR: usize = 0x00;
+const HCINT: usize = 0x08;
+const HCINTMSK: usize = 0x0C;
+const HCTSIZ: usize = 0x10;
+
+// GINTSTS / GINTMSK
+const GINT_SOF: u32 = 1 << 3;
+const GINT_RXFLVL: u32 = 1 << 4;
+const GINT_HPRTINT: u32 = 1 << 24;
+const GINT_HCINT: u32 = 1 << 25;
+const GINT_DISCINT: u32 = 1 << 29;
+const GINT_WKUINT: u32 = 1 << 31;
+// HPRT
+const HPRT_PCSTS: u32 = 1 << 0;
+const HPRT_PCDET: u32 = 1 << 1;
+const HPRT_PENA: u32 = 1 << 2;
+const HPRT_PENCHNG: u32 = 1 << 3;
+const HPRT_POCCHNG: u32 = 1 << 5;
+const HPRT_PRES: u32 = 1 << 6;
+const HPRT_PSUSP: u32 = 1 << 7;
+const HPRT_PRST: u32 = 1 << 8;
+const HPRT_PLSTS_DM: u32 = 1 << 11;
+const HPRT_PPWR: u32 = 1 << 12;
+/// Bits of HPRT that are cleared by writing a one. These must be masked out when modifying other bits.
+const HPRT_W1C: u32 = HPRT_PCDET | HPRT_PENA | HPRT_PENCHNG | HPRT_POCCHNG;
+// HCCHAR
+const HCCHAR_EPDIR_IN: u32 = 1 << 15;
+const HCCHAR_LSDEV: u32 = 1 << 17;
+const HCCHAR_ODDFRM: u32 = 1 << 29;
+const HCCHAR_CHDIS: u32 = 1 << 30;
+const HCCHAR_CHENA: u32 = 1 << 31;
+// HCINT / HCINTMSK
+const HCINT_XFRC: u32 = 1 << 0;
+const HCINT_CHH: u32 = 1 << 1;
+const HCINT_STALL: u32 = 1 << 3;
+const HCINT_NAK: u32 = 1 << 4;
+const HCINT_TXERR: u32 = 1 << 7;
+const HCINT_BBERR: u32 = 1 << 8;
+const HCINT_DTERR: u32 = 1 << 10;
+// HCTSIZ data PIDs
+const DPID_DATA0: u32 = 0b00;
+const DPID_DATA1: u32 = 0b10;
+const DPID_SETUP: u32 = 0b11;
+// GRXSTSP packet status
+const PKTSTS_IN_DATA: u32 = 0b0010;
+
+/// Transfer on channel 0, which spans multiple packets
+#[derive(Copy, Clone, Debug)]
+struct ChannelTransfer {
+    direction: UsbDirection,
+    length: usize,
+    /// Number of bytes transferred so far
+    offset: usize,
+    /// Size of the packet currently in flight
+    packet: usize,
+    /// PID of the current packet
+    pid: bool,
+}
+
+/// Scheduling state of an interrupt pipe
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum PipeState {
+    /// Waiting for the host to call `pipe_continue`
+    Idle,
+    /// Waiting for the given frame, before the channel is enabled
+    Scheduled(u16),
+    /// Channel is enabled
+    Armed,
+    /// Channel is being halted after a NAK, it is scheduled again once halted
+    Halting,
+}
+
+/// Interrupt pipe, using one of the controller's host channels
+#[derive(Debug)]
+struct OtgPipe {
+    dev_addr: u8,
+    endpoint: u8,
+    direction: UsbDirection,
+    size: u16,
+    interval: u8,
+    /// PID of the next packet
+    pid: bool,
+    state: PipeState,
+    buf: [u8; PACKET_SIZE],
+}
+
+/// [`HostBus`] implementation for the OTG_FS controller of STM32F4 / STM32F7 devices
+///
+/// See the [module documentation](self) for details.
+#[derive(Debug)]
+pub struct Stm32OtgHostBus {
+    base: usize,
+    /// Number of busy loop iterations per millisecond
+    loops_per_ms: u32,
+    port_speed: ConnectionSpeed,
+    /// Device address, endpoint and transfer type for channel 0
+    recipient: (u8, u8, TransferType),
+    /// Maximum packet size of endpoint zero of the recipient, if it is the target of a control transfer
+    max_packet_size_0: Option<u8>,
+    transfer: Option<ChannelTransfer>,
+    /// Set if channel 0 was halted after a NAK on an OUT transaction, and the packet needs to be sent again
+    retry_out: bool,
+    /// Retry limit for NAKed transactions on channel 0
+    nak_limit: Option<u16>,
+    /// Number of NAKs received for the current packet on channel 0
+    naks: u16,
+    buffer: [u8; MAX_TRANSFER_SIZE],
+    /// Pipe for each of the interrupt channels (index 0 is channel 1)
+    pipes: [Option<OtgPipe>; CHANNELS - 1],
+    sof_enabled: bool,
+    sof_interrupt: bool,
+}
+
+impl Stm32OtgHostBus {
+    /// Create a host bus for the OTG_FS core at the given `base` address (usually [`OTG_FS_BASE`])
+    ///
+    /// `sysclk_hz` is the frequency of the system clock, which is used to time port resets.
+    ///
+    /// The 48 MHz clock of the peripheral, its RCC clock, and the GPIOs must be set up before.
+    ///
+    /// # Safety
+    ///
+    /// `base` must point to the register block of an OTG_FS core, and nothing else may access it while this bus exists.
+    pub unsafe fn new(base: usize, sysclk_hz: u32) -> Self {
+        const NO_PIPE: Option<OtgPipe> = None;
+        Self {
+            base,
+            // a busy loop iteration takes roughly 4 cycles
+            loops_per_ms: (sysclk_hz / 4000).max(1),
+            port_speed: ConnectionSpeed::Full,
+            recipient: (0, 0, TransferType::Control),
+            max_packet_size_0: None,
+            transfer: None,
+            retry_out: false,
+            nak_limit: None,
+            naks: 0,
+            buffer: [0; MAX_TRANSFER_SIZE],
+            pipes: [NO_PIPE; CHANNELS - 1],
+            sof_enabled: false,
+            sof_interrupt: false,
+        }
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        // Safety: `new` requires `base` to point to the register block
+        unsafe { core::ptr::read_volatile((self.base + offset) as *const u32) }
+    }
+
+    fn write(&mut self, offset: usize, value: u32) {
+        // Safety: `new` requires `base` to point to the register block
+        unsafe { core::ptr::write_volatile((self.base + offset) as *mut u32, value) }
+    }
+
+    fn modify(&mut self, offset: usize, f: impl FnOnce(u32) -> u32) {
+        let value = f(self.read(offset));
+        self.write(offset, value);
+    }
+
+    /// Modify the port register, without clearing any of its status change bits by accident
+    fn modify_port(&mut self, f: impl FnOnce(u32) -> u32) {
+        self.modify(HPRT, |r| f(r & !HPRT_W1C));
+    }
+
+    fn channel(ch: usize, offset: usize) -> usize {
+        0x500 + 0x20 * ch + offset
+    }
+
+    fn fifo(ch: usize) -> usize {
+        0x1000 * (ch + 1)
+    }
+
+    fn delay_ms(&self, ms: u32) {
+        for _ in 0..(ms * self.loops_per_ms) {
+            core::hint::spin_loop();
+        }
+    }
+
+    fn wait_for(&self, offset: usize, mask: u32, set: bool) {
+        while (self.read(offset) & mask != 0) != set {
+            core::hint::spin_loop();
+        }
+    }
+
+    fn frame(&self) -> u16 {
+        self.read(HFNUM) as u16
+    }
+
+    fn push_fifo(base: usize, ch: usize, data: &[u8]) {
+        let fifo = (base + Self::fifo(ch)) as *mut u32;
+        for chunk in data.chunks(4) {
+            let mut word = [0; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            // Safety: `new` requires `base` to point to the register block
+            unsafe { core::ptr::write_volatile(fifo, u32::from_le_bytes(word)) };
+        }
+    }
+
+    /// Read `len` bytes from the receive FIFO into `target`. Bytes that do not fit are discarded.
+    fn pop_fifo(base: usize, len: usize, target: &mut [u8]) {
+        let fifo = (base + Self::fifo(0)) as *const u32;
+        for i in (0..len).step_by(4) {
+            // Safety: `new` requires `base` to point to the register block
+            let word = unsafe { core::ptr::read_volatile(fifo) }.to_le_bytes();
+            for (j, byte) in word.iter().enumerate().take(len - i) {
+                if let Some(slot) = target.get_mut(i + j) {
+                    *slot = *byte;
+                }
+            }
+        }
+    }
+
+    fn max_packet_size(&self) -> usize {
+        match (self.max_packet_size_0, self.port_speed) {
+            (Some(size), _) => (size as usize).min(PACKET_SIZE),
+            (None, ConnectionSpeed::Low) => LOW_SPEED_PACKET_SIZE,
+            (None, _) => PACKET_SIZE,
+        }
+    }
+
+    fn lsdev(&self) -> u32 {
+        if self.port_speed == ConnectionSpeed::Low {
+            HCCHAR_LSDEV
+        } else {
+            0
+        }
+    }
+
+    fn hcchar(&self, dev_addr: u8, endpoint: u8, direction: UsbDirection, transfer_type: TransferType, size: usize) -> u32 {
+        let direction = if direction == UsbDirection::In { HCCHAR_EPDIR_IN } else { 0 };
+        size as u32
+            | (endpoint as u32 & 0xF) << 11
+            | direction
+            | self.lsdev()
+            | (transfer_type as u32) << 18
+            | 1 << 20 // one transaction per frame
+            | (dev_addr as u32 & 0x7F) << 22
+    }
+
+    fn hctsiz(size: usize, pid: u32) -> u32 {
+        size as u32 | 1 << 19 | pid << 29
+    }
+
+    /// Start the next packet of the current transfer on channel 0
+    fn start_packet(&mut self) {
+        let max_packet_size = self.max_packet_size();
+        let (dev_addr, endpoint, transfer_type) = self.recipient;
+        let Some(transfer) = self.transfer.as_mut() else {
+            return;
+        };
+        let len = (transfer.length - transfer.offset).min(max_packet_size);
+        let (direction, pid, offset) = (transfer.direction, transfer.pid, transfer.offset);
+        // for IN packets, the size is known once the data was received
+        transfer.packet = if direction == UsbDirection::Out { len } else { 0 };
+        let size = if direction == UsbDirection::In { max_packet_size } else { len };
+        let hcchar = self.hcchar(dev_addr, endpoint, direction, transfer_type, max_packet_size);
+        self.write(Self::channel(0, HCTSIZ), Self::hctsiz(size, if pid { DPID_DATA1 } else { DPID_DATA0 }));
+        self.write(Self::channel(0, HCCHAR), hcchar | HCCHAR_CHENA);
+        if direction == UsbDirection::Out {
+            Self::push_fifo(self.base, 0, &self.buffer[offset..offset + len]);
+        }
+    }
+
+    /// Handle completion of a packet on channel 0
+    ///
+    /// Returns `true` if the transfer is complete, otherwise starts the next packet.
+    fn packet_complete(&mut self) -> bool {
+        self.naks = 0;
+        let max_packet_size = self.max_packet_size();
+        let Some(transfer) = self.transfer.as_mut() else {
+            // SETUP packet
+            return true;
+        };
+        transfer.offset += transfer.packet;
+        transfer.pid = !transfer.pid;
+        // a short packet ends the transfer early
+        if transfer.offset >= transfer.length || transfer.packet < max_packet_size {
+            return true;
+        }
+        self.start_packet();
+        false
+    }
+
+    fn halt_channel(&mut self, ch: usize) {
+        self.modify(Self::channel(ch, HCCHAR), |r| r | HCCHAR_CHDIS | HCCHAR_CHENA);
+    }
+
+    fn arm_pipe(&mut self, index: usize) {
+        let ch = index + 1;
+        // the transaction must be started in the next frame
+        let odd = if self.frame() & 1 == 0 { HCCHAR_ODDFRM } else { 0 };
+        let Some(pipe) = self.pipes[index].as_mut() else {
+            return;
+        };
+        pipe.state = PipeState::Armed;
+        let (dev_addr, endpoint, direction, size, pid) = (pipe.dev_addr, pipe.endpoint, pipe.direction, pipe.size as usize, pipe.pid);
+        let hcchar = self.hcchar(dev_addr, endpoint, direction, TransferType::Interrupt, size);
+        self.write(Self::channel(ch, HCTSIZ), Self::hctsiz(size, if pid { DPID_DATA1 } else { DPID_DATA0 }));
+        self.write(Self::channel(ch, HCCHAR), hcchar | odd | HCCHAR_CHENA);
+        if let Some(pipe) = self.pipes[index].as_ref().filter(|pipe| pipe.direction == UsbDirection::Out) {
+            Self::push_fifo(self.base, ch, &pipe.buf[..size]);
+        }
+    }
+
+    fn schedule_pipe(&mut self, index: usize) {
+        let frame = self.frame();
+        if let Some(pipe) = self.pipes[index].as_mut() {
+            pipe.state = PipeState::Scheduled(frame.wrapping_add(pipe.interval as u16));
+        }
+    }
+
+    /// SOF interrupts are needed for scheduling interrupt pipes, or if the host asked for them
+    fn update_sof_interrupt(&mut self) {
+        let needed = self.sof_interrupt || self.pipes.iter().any(Option::is_some);
+        self.modify(GINTMSK, |r| if needed { r | GINT_SOF } else { r & !GINT_SOF });
+    }
+
+    fn flush_fifos(&mut self) {
+        // flush all transmit FIFOs
+        self.write(GRSTCTL, 1 << 5 | 0x10 << 6);
+        self.wait_for(GRSTCTL, 1 << 5, false);
+        self.write(GRSTCTL, 1 << 4);
+        self.wait_for(GRSTCTL, 1 << 4, false);
+    }
+
+    fn handle_port(&mut self) -> Option<Event> {
+        let hprt = self.read(HPRT);
+        // acknowledge all status changes at once
+        self.write(HPRT, hprt & !HPRT_PENA);
+        if hprt & HPRT_POCCHNG != 0 {
+            warn!("Over-current on USB port");
+        }
+        if hprt & HPRT_PENCHNG != 0 && hprt & HPRT_PENA != 0 {
+            let speed = if (hprt >> 17) & 0b11 == 0b10 { ConnectionSpeed::Low } else { ConnectionSpeed::Full };
+            let fslspcs = if speed == ConnectionSpeed::Low { 0b10 } else { 0b01 };
+            if self.read(HCFG) & 0b11 != fslspcs {
+                // the PHY clock must match the speed of the device. Changing it requires another reset.
+                self.modify(HCFG, |r| (r & !0b11) | fslspcs);
+                self.write(HFIR, if speed == ConnectionSpeed::Low { 6000 } else { 48000 });
+                self.reset_bus();
+                return None;
+            }
+            self.port_speed = speed;
+            return Some(Event::Attached(speed));
+        }
+        if hprt & HPRT_PCDET != 0 && hprt & HPRT_PCSTS != 0 {
+            // a pulled up D- line indicates a low speed device
+            let speed = if hprt & HPRT_PLSTS_DM != 0 { ConnectionSpeed::Low } else { ConnectionSpeed::Full };
+            self.port_speed = speed;
+            return Some(Event::Attached(speed));
+        }
+        None
+    }
+
+    fn handle_rx(&mut self) {
+        let status = self.read(GRXSTSP);
+        let ch = (status & 0xF) as usize;
+        let len = ((status >> 4) & 0x7FF) as usize;
+        if (status >> 17) & 0xF != PKTSTS_IN_DATA || len == 0 {
+            return;
+        }
+        if ch == 0 {
+            let offset = self.transfer.map_or(MAX_TRANSFER_SIZE, |transfer| transfer.offset);
+            Self::pop_fifo(self.base, len, &mut self.buffer[offset..]);
+            if let Some(transfer) = self.transfer.as_mut() {
+                transfer.packet = len;
+            }
+        } else if let Some(pipe) = self.pipes.get_mut(ch - 1).and_then(Option::as_mut) {
+            Self::pop_fifo(self.base, len, &mut pipe.buf);
+        } else {
+            Self::pop_fifo(self.base, len, &mut []);
+        }
+    }
+
+    fn handle_control_channel(&mut self, hcint: u32) -> Option<Event> {
+        if hcint & HCINT_XFRC != 0 {
+            if self.packet_complete() {
+                return Some(Event::TransComplete);
+            }
+        } else if hcint & HCINT_STALL != 0 {
+            self.transfer = None;
+            self.halt_channel(0);
+            return Some(Event::Stall);
+        } else if hcint & (HCINT_TXERR | HCINT_BBERR | HCINT_DTERR) != 0 {
+            self.transfer = None;
+            self.halt_channel(0);
+            return Some(Event::Error(if hcint & HCINT_DTERR != 0 {
+                Error::DataSequence
+            } else if hcint & HCINT_BBERR != 0 {
+                Error::RxOverflow
+            } else {
+                Error::Other
+            }));
+        } else if hcint & HCINT_NAK != 0 {
+            self.naks = self.naks.saturating_add(1);
+            if self.nak_limit.is_some_and(|limit| self.naks >= limit) {
+                self.transfer = None;
+                self.halt_channel(0);
+                return Some(Event::Error(Error::NakLimit));
+            }
+            match self.transfer {
+                Some(ChannelTransfer { direction: UsbDirection::In, .. }) => {
+                    self.modify(Self::channel(0, HCCHAR), |r| (r & !HCCHAR_CHDIS) | HCCHAR_CHENA);
+                }
+                _ => {
+                    self.retry_out = true;
+                    self.halt_channel(0);
+                }
+            }
+        } else if hcint & HCINT_CHH != 0 && core::mem::take(&mut self.retry_out) {
+            self.start_packet();
+        }
+        None
+    }
+
+    fn handle_pipe_channel(&mut self, index: usize, hcint: u32) -> Option<Event> {
+        let pipe = self.pipes[index].as_mut()?;
+        if hcint & HCINT_XFRC != 0 {
+            pipe.pid = !pipe.pid;
+            pipe.state = PipeState::Idle;
+            return Some(Event::InterruptPipe(index as u8 + 1));
+        }
+        if hcint & (HCINT_NAK | HCINT_TXERR | HCINT_BBERR | HCINT_DTERR | HCINT_STALL) != 0 {
+            if hcint & HCINT_DTERR != 0 {
+                pipe.pid = !pipe.pid;
+            }
+            pipe.state = PipeState::Halting;
+            self.halt_channel(index + 1);
+        } else if hcint & HCINT_CHH != 0 && pipe.state == PipeState::Halting {
+            self.schedule_pipe(index);
+        }
+        None
+    }
+
+    fn handle_channels(&mut self) -> Option<Event> {
+        let haint = self.read(HAINT);
+        let ch = (0..CHANNELS).find(|ch| haint & (1 << ch) != 0)?;
+        let hcint = self.read(Self::channel(ch, HCINT));
+        self.write(Self::channel(ch, HCINT), hcint);
+        if ch == 0 {
+            self.handle_control_channel(hcint)
+        } else {
+            self.handle_pipe_channel(ch - 1, hcint)
+        }
+    }
+
+    fn handle_sof(&mut self) -> Option<Event> {
+        let frame = self.frame();
+        for index in 0..self.pipes.len() {
+            if let Some(PipeState::Scheduled(due)) = self.pipes[index].as_ref().map(|pipe| pipe.state) {
+                if frame.wrapping_sub(due) as i16 >= 0 {
+                    self.arm_pipe(index);
+                }
+            }
+        }
+        self.sof_interrupt.then_some(Event::Sof)
+    }
+}
+
+impl HostBus for Stm32OtgHostBus {
+    fn reset_controller(&mut self) {
+        self.transfer = None;
+        self.retry_out = false;
+        self.nak_limit = None;
+        self.naks = 0;
+        self.pipes.iter_mut().for_each(|pipe| *pipe = None);
+        self.sof_enabled = false;
+        self.sof_interrupt = false;
+
+        // core soft reset
+        self.wait_for(GRSTCTL, 1 << 31, true);
+        self.write(GRSTCTL, 1 << 0);
+        self.wait_for(GRSTCTL, 1 << 0, false);
+
+        // power up the transceiver, select the internal full speed PHY and force host mode
+        self.write(GCCFG, 1 << 16);
+        self.write(GUSBCFG, 1 << 6 | 1 << 29);
+        self.delay_ms(MODE_SWITCH_MS);
+        self.write(PCGCCTL, 0);
+        self.write(HCFG, 0b01 | 1 << 2);
+        self.write(HFIR, 48000);
+
+        // FIFOs (320 words in total): receive FIFO, then non-periodic and periodic transmit FIFOs
+        self.write(GRXFSIZ, 128);
+        self.write(HNPTXFSIZ, 96 << 16 | 128);
+        self.write(HPTXFSIZ, 96 << 16 | 224);
+        self.flush_fifos();
+
+        for ch in 0..CHANNELS {
+            self.write(Self::channel(ch, HCINT), 0xFFFF_FFFF);
+            self.write(
+                Self::channel(ch, HCINTMSK),
+                HCINT_XFRC | HCINT_CHH | HCINT_STALL | HCINT_NAK | HCINT_TXERR | HCINT_BBERR | HCINT_DTERR,
+            );
+        }
+        self.write(HAINTMSK, (1 << CHANNELS) - 1);
+
+        self.modify_port(|r| r | HPRT_PPWR);
+        self.write(GINTSTS, 0xFFFF_FFFF);
+        self.write(GINTMSK, GINT_RXFLVL | GINT_HPRTINT | GINT_HCINT | GINT_DISCINT | GINT_WKUINT);
+        // global interrupt enable
+        self.write(GAHBCFG, 1 << 0);
+    }
+
+    fn reset_bus(&mut self) {
+        self.modify_port(|r| r | HPRT_PRST);
+        self.delay_ms(RESET_MS);
+        self.modify_port(|r| r & !HPRT_PRST);
+    }
+
+    fn enable_sof(&mut self) {
+        // the core generates SOF / keep-alive packets on its own while the port is enabled
+        self.sof_enabled = true;
+    }
+
+    fn sof_enabled(&self) -> bool {
+        self.sof_enabled
+    }
+
+    fn suspend(&mut self) {
+        self.sof_enabled = false;
+        self.modify_port(|r| r | HPRT_PSUSP);
+    }
+
+    fn start_resume(&mut self) {
+        self.modify_port(|r| r | HPRT_PRES);
+    }
+
+    fn end_resume(&mut self) {
+        // clearing PRES also clears PSUSP
+        self.modify_port(|r| r & !HPRT_PRES);
+    }
+
+    fn set_recipient(&mut self, dev_addr: Option<DeviceAddress>, endpoint: u8, transfer_type: TransferType) {
+        self.recipient = (dev_addr.map_or(0, u8::from), endpoint, transfer_type);
+        self.max_packet_size_0 = None;
+    }
+
+    fn set_max_packet_size_0(&mut self, max_packet_size: u8) {
+        self.max_packet_size_0 = Some(max_packet_size);
+    }
+
+    fn ls_preamble(&mut self, _enabled: bool) {
+        // not supported by the core
+    }
+
+    fn stop_transaction(&mut self) {
+        self.transfer = None;
+        self.retry_out = false;
+        self.halt_channel(0);
+    }
+
+    fn write_setup(&mut self, setup: SetupPacket) {
+        self.transfer = None;
+        self.retry_out = false;
+        self.naks = 0;
+        let (dev_addr, endpoint, _) = self.recipient;
+        let hcchar = self.hcchar(dev_addr, endpoint, UsbDirection::Out, TransferType::Control, self.max_packet_size());
+        self.write(Self::channel(0, HCTSIZ), Self::hctsiz(8, DPID_SETUP));
+        self.write(Self::channel(0, HCCHAR), hcchar | HCCHAR_CHENA);
+        Self::push_fifo(self.base, 0, &setup.to_bytes());
+    }
+
+    fn write_data_in(&mut self, length: u16, pid: bool) {
+        self.transfer = Some(ChannelTransfer {
+            direction: UsbDirection::In,
+            length: (length as usize).min(MAX_TRANSFER_SIZE),
+            offset: 0,
+            packet: 0,
+            pid,
+        });
+        self.naks = 0;
+        self.start_packet();
+    }
+
+    fn prepare_data_out(&mut self, data: &[u8]) {
+        let len = data.len().min(MAX_TRANSFER_SIZE);
+        self.buffer[..len].copy_from_slice(&data[..len]);
+        self.transfer = Some(ChannelTransfer { direction: UsbDirection::Out, length: len, offset: 0, packet: 0, pid: false });
+    }
+
+    fn write_data_out_prepared(&mut self, pid: bool) {
+        self.retry_out = false;
+        self.naks = 0;
+        if let Some(transfer) = self.transfer.as_mut() {
+            transfer.pid = pid;
+        }
+        self.start_packet();
+    }
+
+    fn poll(&mut self) -> Option<Event> {
+        loop {
+            let status = self.read(GINTSTS) & self.read(GINTMSK);
+            if status == 0 {
+                return None;
+            }
+            let event = if status & GINT_DISCINT != 0 {
+                self.write(GINTSTS, GINT_DISCINT);
+                self.transfer = None;
+                Some(Event::Detached)
+            } else if status & GINT_HPRTINT != 0 {
+                self.handle_port()
+            } else if status & GINT_RXFLVL != 0 {
+                // the received data must be read before the channel reports completion
+                self.handle_rx();
+                None
+            } else if status & GINT_HCINT != 0 {
+                self.handle_channels()
+            } else if status & GINT_WKUINT != 0 {
+                self.write(GINTSTS, GINT_WKUINT);
+                Some(Event::Resume)
+            } else if status & GINT_SOF != 0 {
+                self.write(GINTSTS, GINT_SOF);
+                self.handle_sof()
+            } else {
+                // not an interrupt this implementation enables. Clear it, to avoid getting stuck.
+                self.write(GINTSTS, status);
+                None
+            };
+            if event.is_some() {
+                return event;
+            }
+        }
+    }
+
+    fn received_data(&self, length: usize) -> &[u8] {
+        let len = self.transfer.map_or(0, |transfer| transfer.offset).min(length);
+        &self.buffer[..len]
+    }
+
+    fn interrupt_pipe_hw(&mut self) -> Option<&mut dyn InterruptPipeHw> {
+        Some(self)
+    }
+
+    fn set_nak_policy(&mut self, pipe_ref: Option<u8>, policy: NakPolicy) -> bool {
+        // interrupt channels are retried at their polling interval
+        if pipe_ref.is_some() {
+            return false;
+        }
+        self.nak_limit = policy.retry_limit;
+        policy.retry_interval.is_none()
+    }
+
+    fn sof_timer(&mut self) -> Option<&mut dyn SofTimer> {
+        Some(self)
+    }
+
+    fn frame_number(&self) -> Option<u16> {
+        Some(self.frame() & 0x7FF)
+    }
+}
+
+impl InterruptPipeHw for Stm32OtgHostBus {
+    fn create_interrupt_pipe(
+        &mut self,
+        device_address: DeviceAddress,
+        endpoint_number: u8,
+        direction: UsbDirection,
+        size: u16,
+        interval: u8,
+    ) -> Option<InterruptPipe> {
+        if size as usize > PACKET_SIZE {
+            return None;
+        }
+        let index = self.pipes.iter().position(|pipe| pipe.is_none())?;
+        self.pipes[index] = Some(OtgPipe {
+            dev_addr: u8::from(device_address),
+            endpoint: endpoint_number,
+            direction,
+            size,
+            interval: interval.max(1),
+            pid: false,
+            state: PipeState::Idle,
+            buf: [0; PACKET_SIZE],
+        });
+        if direction == UsbDirection::In {
+            // OUT pipes are armed once the driver filled the buffer
+            self.schedule_pipe(index);
+        }
+        self.update_sof_interrupt();
+
+        Some(InterruptPipe { bus_ref: index as u8 + 1 })
+    }
+
+    fn release_interrupt_pipe(&mut self, pipe_ref: u8) {
+        let Some(index) = (pipe_ref as usize).checked_sub(1).filter(|i| *i < CHANNELS - 1) else {
+            return;
+        };
+        if self.pipes[index].take().is_some() {
+            self.halt_channel(index + 1);
+        }
+        self.update_sof_interrupt();
+    }
+
+    fn interrupt_buffer(&mut self, pipe_ref: u8) -> Option<&mut [u8]> {
+        let pipe = self.pipes.get_mut((pipe_ref as usize).checked_sub(1)?)?.as_mut()?;
+        Some(&mut pipe.buf[..pipe.size as usize])
+    }
+
+    fn pipe_continue(&mut self, pipe_ref: u8) {
+        if let Some(index) = (pipe_ref as usize).checked_sub(1).filter(|i| *i < CHANNELS - 1) {
+            self.schedule_pipe(index);
+        }
+    }
+
+    fn set_pipe_data_toggle(&mut self, pipe_ref: u8, data_toggle: bool) {
+        if let Some(Some(pipe)) = (pipe_ref as usize).checked_sub(1).and_then(|index| self.pipes.get_mut(index)) {
+            pipe.pid = data_toggle;
+        }
+    }
+}
+
+impl SofTimer for Stm32OtgHostBus {
+    fn interrupt_on_sof(&mut self, enable: bool) {
+        self.sof_interrupt = enable;
+        self.update_sof_interrupt();
+    }
+}
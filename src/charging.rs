@@ -0,0 +1,377 @@
+//! Charging port detection (BC1.2) and billboard devices
+//!
+//! Products that charge their own battery from a USB port (e.g. a power bank, which also acts as a host on another port)
+//! need to know how much current they may draw. The USB Battery Charging specification (BC1.2) distinguishes three
+//! kinds of [`ChargingPort`]s, told apart by the state of the data lines:
+//! - a standard downstream port (SDP) of a host or hub, which supplies up to 500 mA once the device is configured
+//! - a charging downstream port (CDP), which supports data transfers, and supplies up to 1.5 A
+//! - a dedicated charging port (DCP), e.g. a wall adapter, which has its data lines shorted, and supplies up to 1.5 A
+//!
+//! The detection itself is done by hardware: either a dedicated charger detection chip, or a peripheral of the
+//! microcontroller (such as the battery charging detector of some STM32 OTG controllers). The application makes it
+//! available by implementing [`ChargerDetection`].
+//!
+//! USB Type-C devices whose alternate mode (e.g. DisplayPort) could not be entered present a *billboard device*,
+//! which describes the modes, and why they failed, in a [`BillboardCapability`]. When such a device is attached to the host,
+//! it is announced with a [`ChargingEvent::BillboardAttached`].
+//!
+//! Both are handled by the [`ChargingMonitor`], which is a driver:
+//!
+//! ```ignore
+//! let mut charging = ChargingMonitor::new(detector);
+//!
+//! loop {
+//!     usb_host.poll(&mut [&mut kbd, &mut charging]);
+//!     charging.poll();
+//!
+//!     match charging.take_event() {
+//!         Some(ChargingEvent::PortDetected(port)) => set_charge_current(port.max_current(false)),
+//!         Some(ChargingEvent::BillboardAttached(_, Some(billboard))) if !billboard.all_configured() => show_warning(),
+//!         _ => {}
+//!     }
+//! }
+//! ```
+//!
+//! Billboard devices are claimed (configured) by the monitor. If the billboard is part of a composite device, the drivers
+//! for the other functions should come first in the list passed to [`UsbHost::poll`], since only one driver gets to
+//! configure a device.
+
+use crate::bus::HostBus;
+use crate::descriptor::{self, BillboardCapability};
+use crate::driver::{detector::SingleDevice, Driver, EventQueue, HasEvents, EVENT_QUEUE_DEPTH};
+use crate::types::{ConnectionSpeed, DeviceAddress};
+use crate::{PipeId, TransferError, UsbHost};
+
+/// Device and interface class code of billboard devices
+const CLASS_BILLBOARD: u8 = 0x11;
+
+/// Type of port, as detected according to BC1.2
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ChargingPort {
+    /// Nothing is connected (no VBUS)
+    NotConnected,
+    /// Standard downstream port (SDP)
+    StandardDownstream,
+    /// Charging downstream port (CDP)
+    ChargingDownstream,
+    /// Dedicated charging port (DCP)
+    DedicatedCharging,
+}
+
+impl ChargingPort {
+    /// Maximum current that may be drawn from the port, in mA
+    ///
+    /// On a standard downstream port, only 100 mA are available until the device is `configured` by the host.
+    pub fn max_current(&self, configured: bool) -> u16 {
+        match self {
+            ChargingPort::NotConnected => 0,
+            ChargingPort::StandardDownstream if configured => 500,
+            ChargingPort::StandardDownstream => 100,
+            ChargingPort::ChargingDownstream | ChargingPort::DedicatedCharging => 1500,
+        }
+    }
+
+    /// Whether data can be transferred via the port
+    pub fn has_data(&self) -> bool {
+        matches!(self, ChargingPort::StandardDownstream | ChargingPort::ChargingDownstream)
+    }
+}
+
+/// Hardware that detects the type of charging port, see the [module documentation](self)
+pub trait ChargerDetection {
+    /// Type of port currently connected
+    ///
+    /// Called on every [`ChargingMonitor::poll`]. Implementations that need time to run the detection should return the
+    /// most recent result, and start over when the port is disconnected.
+    fn detect(&mut self) -> ChargingPort;
+}
+
+/// [`ChargerDetection`] for products without charger detection hardware
+///
+/// Always reports [`ChargingPort::NotConnected`], so the [`ChargingMonitor`] only announces billboard devices.
+#[derive(Debug)]
+pub struct NoDetection;
+
+impl ChargerDetection for NoDetection {
+    fn detect(&mut self) -> ChargingPort {
+        ChargingPort::NotConnected
+    }
+}
+
+/// Events generated by the [`ChargingMonitor`]
+#[derive(Copy, Clone, Debug)]
+pub enum ChargingEvent {
+    /// The type of charging port changed
+    PortDetected(ChargingPort),
+
+    /// A billboard device was attached
+    ///
+    /// The capability is `None` if it could not be read from the device.
+    BillboardAttached(DeviceAddress, Option<BillboardCapability>),
+
+    /// The billboard device was removed
+    BillboardRemoved(DeviceAddress),
+}
+
+/// Descriptors of a newly attached device, as far as they were inspected
+#[derive(Copy, Clone, Default, Debug)]
+struct Pending {
+    /// Set if the device or one of its interfaces has the billboard class
+    billboard: bool,
+    /// Value of the first configuration
+    config: Option<u8>,
+}
+
+/// State of the configured billboard device
+#[derive(Copy, Clone, Debug)]
+enum Billboard {
+    /// The device was configured, and its BOS descriptor is being read
+    Reading { control_pipe: PipeId },
+    /// The device was announced
+    Attached,
+}
+
+/// Monitors the charging port, and announces billboard devices
+///
+/// See [module-level documentation](self) for details.
+#[derive(Debug)]
+pub struct ChargingMonitor<D: ChargerDetection> {
+    detector: D,
+    port: ChargingPort,
+    state: SingleDevice<Pending, Billboard>,
+    events: EventQueue<ChargingEvent, EVENT_QUEUE_DEPTH>,
+}
+
+impl<D: ChargerDetection> ChargingMonitor<D> {
+    pub fn new(detector: D) -> Self {
+        Self { detector, port: ChargingPort::NotConnected, state: SingleDevice::Idle, events: EventQueue::new() }
+    }
+
+    /// Returns the oldest pending event (if any), and removes it from the queue.
+    ///
+    /// This method should be called repeatedly after calling `usb_host.poll(...)` and `charging.poll()`, until it returns `None` (see
+    /// [`HasEvents`] for what happens otherwise).
+    pub fn take_event(&mut self) -> Option<ChargingEvent> {
+        self.events.pop()
+    }
+
+    /// Query the charger detection, and generate a [`ChargingEvent::PortDetected`] if the type of port changed
+    pub fn poll(&mut self) {
+        let port = self.detector.detect();
+        if port != self.port {
+            self.port = port;
+            self.events.push(ChargingEvent::PortDetected(port));
+        }
+    }
+
+    /// Type of port, as of the most recent call to [`poll`](ChargingMonitor::poll)
+    pub fn port(&self) -> ChargingPort {
+        self.port
+    }
+
+    /// Access the charger detection
+    pub fn detector(&mut self) -> &mut D {
+        &mut self.detector
+    }
+
+    /// Address of the billboard device, if one is attached
+    pub fn billboard_address(&self) -> Option<DeviceAddress> {
+        self.state.address()
+    }
+
+    /// Announce the device, once its BOS descriptor was read (or could not be read)
+    fn announce(&mut self, dev_addr: DeviceAddress, capability: Option<BillboardCapability>) {
+        if let Some(billboard @ Billboard::Reading { .. }) = self.state.device_mut(dev_addr) {
+            *billboard = Billboard::Attached;
+            self.events.push(ChargingEvent::BillboardAttached(dev_addr, capability));
+        }
+    }
+}
+
+impl<D: ChargerDetection> HasEvents for ChargingMonitor<D> {
+    type Event = ChargingEvent;
+
+    fn take_event(&mut self) -> Option<ChargingEvent> {
+        self.events.pop()
+    }
+
+    fn events_pending(&self) -> bool {
+        !self.events.is_empty()
+    }
+}
+
+impl<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+        D: ChargerDetection,
+    > Driver<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE> for ChargingMonitor<D>
+{
+    fn attached(&mut self, dev_addr: DeviceAddress, _connection_speed: ConnectionSpeed) {
+        self.state.attached(dev_addr, Pending::default());
+    }
+
+    fn detached(&mut self, dev_addr: DeviceAddress) {
+        if self.state.detached(dev_addr) {
+            self.events.push(ChargingEvent::BillboardRemoved(dev_addr));
+        }
+    }
+
+    fn descriptor(&mut self, dev_addr: DeviceAddress, descriptor_type: u8, data: &[u8]) {
+        let Some(Pending { billboard, config }) = self.state.pending(dev_addr) else {
+            return;
+        };
+        match descriptor_type {
+            descriptor::TYPE_DEVICE => {
+                if let Ok((_, device)) = descriptor::parse::device_descriptor(data) {
+                    *billboard |= device.device_class == CLASS_BILLBOARD;
+                }
+            }
+            descriptor::TYPE_CONFIGURATION if config.is_none() => {
+                if let Ok((_, configuration)) = descriptor::parse::configuration_descriptor(data) {
+                    *config = Some(configuration.value);
+                }
+            }
+            descriptor::TYPE_INTERFACE => {
+                if let Ok((_, interface)) = descriptor::parse::interface_descriptor(data) {
+                    *billboard |= interface.interface_class == CLASS_BILLBOARD;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn configure(&mut self, dev_addr: DeviceAddress) -> Option<u8> {
+        self.state.configure(dev_addr, |pending| pending.config.filter(|_| pending.billboard))
+    }
+
+    fn configured(
+        &mut self,
+        dev_addr: DeviceAddress,
+        _value: u8,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) {
+        let Some(Pending { billboard: true, .. }) = self.state.take_pending(dev_addr) else {
+            return;
+        };
+        let Some(control_pipe) = host.create_control_pipe(dev_addr) else {
+            // the host ran out of pipes
+            return;
+        };
+        self.state = SingleDevice::Configured(dev_addr, Billboard::Reading { control_pipe });
+        // the device only returns as much as its BOS descriptor is long
+        if host.get_bos_descriptor(dev_addr, Some(control_pipe), CONTROL_BUFFER_SIZE as u16).is_err() {
+            self.announce(dev_addr, None);
+        }
+    }
+
+    fn completed_control(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, data: Option<&[u8]>) {
+        let Some(&Billboard::Reading { control_pipe }) = self.state.device(dev_addr) else {
+            return;
+        };
+        if pipe_id != control_pipe {
+            return;
+        }
+        let capability = descriptor::device_capabilities(data.unwrap_or(&[])).find_map(|capability| BillboardCapability::parse(&capability));
+        self.announce(dev_addr, capability);
+    }
+
+    fn completed_in(&mut self, _dev_addr: DeviceAddress, _pipe_id: PipeId, _data: &[u8]) {}
+
+    fn completed_out(&mut self, _dev_addr: DeviceAddress, _pipe_id: PipeId, _data: &mut [u8]) {}
+
+    fn stall(&mut self, dev_addr: DeviceAddress) {
+        // the device has no BOS descriptor
+        self.announce(dev_addr, None);
+    }
+
+    fn transfer_failed(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, _error: TransferError) {
+        if let Some(&Billboard::Reading { control_pipe }) = self.state.device(dev_addr) {
+            if pipe_id == control_pipe {
+                self.announce(dev_addr, None);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::mock::{ControlResponse, MockDevice, MockHostBus, Response};
+
+    struct Detector(ChargingPort);
+
+    impl ChargerDetection for Detector {
+        fn detect(&mut self) -> ChargingPort {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_port_detected() {
+        let mut monitor = ChargingMonitor::new(Detector(ChargingPort::NotConnected));
+        monitor.poll();
+        assert!(monitor.take_event().is_none());
+
+        monitor.detector().0 = ChargingPort::DedicatedCharging;
+        monitor.poll();
+        monitor.poll();
+        assert!(matches!(monitor.take_event(), Some(ChargingEvent::PortDetected(ChargingPort::DedicatedCharging))));
+        assert!(monitor.take_event().is_none());
+        assert_eq!(monitor.port().max_current(false), 1500);
+        assert!(!monitor.port().has_data());
+        assert_eq!(ChargingPort::StandardDownstream.max_current(false), 100);
+        assert_eq!(ChargingPort::StandardDownstream.max_current(true), 500);
+    }
+
+    #[test]
+    fn test_billboard() {
+        use crate::descriptor::AlternateModeState;
+        const BILLBOARD_DEVICE: &[u8] = &[
+            0x12, 0x01, 0x01, 0x02, 0x11, 0x00, 0x00, 0x40, 0x34, 0x12, 0x78, 0x56, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01,
+        ];
+        const BILLBOARD_CONFIGURATION: &[u8] = &[
+            0x09, 0x02, 0x12, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32, // configuration 1
+            0x09, 0x04, 0x00, 0x00, 0x00, 0x11, 0x00, 0x00, 0x00, // interface 0: billboard
+        ];
+        const BOS: &[u8] = &[
+            0x05, 0x0f, 0x35, 0x00, 0x01, // BOS, 1 capability
+            0x30, 0x10, 0x0d, 0x00, 0x01, 0x00, 0x00, 0x80, // billboard: 1 mode
+            0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // mode 0: unsuccessful
+            0x21, 0x01, 0x00, 0x00, // version 1.21
+            0x01, 0xff, 0x01, 0x00, // mode 0: DisplayPort
+        ];
+        const GET_BOS: ControlResponse = ControlResponse {
+            request_type: 0x80,
+            request: 0x06,
+            value: 0x0f00,
+            index: 0,
+            response: Response::Data(BOS),
+        };
+
+        let device = MockDevice { control_responses: &[GET_BOS], ..MockDevice::new(BILLBOARD_DEVICE, &[BILLBOARD_CONFIGURATION]) };
+        let mut host = UsbHost::new(MockHostBus::new(device));
+        let mut charging = ChargingMonitor::new(NoDetection);
+        host.bus().attach();
+
+        let mut attached = None;
+        for _ in 0..1000 {
+            host.poll(&mut [&mut charging]);
+            charging.poll();
+            match charging.take_event() {
+                Some(ChargingEvent::BillboardAttached(dev_addr, capability)) => attached = Some((dev_addr, capability)),
+                Some(ChargingEvent::PortDetected(_)) => panic!("no charger detection"),
+                _ => {}
+            }
+        }
+        let (dev_addr, Some(capability)) = attached.expect("billboard attached") else {
+            panic!("capability was not read");
+        };
+        assert_eq!(charging.billboard_address(), Some(dev_addr));
+        assert_eq!(capability.num_alternate_modes, 1);
+        assert_eq!(capability.alternate_mode_state(0), AlternateModeState::Unsuccessful);
+        assert!(!capability.all_configured());
+    }
+}
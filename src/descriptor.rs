@@ -14,23 +14,46 @@
 //!   then it's `data` can further be parsed by the respective methods in the [`parse`] module.
 //! - Otherwise it's up to the driver to interpret the descriptor.
 //!
+//! To walk all the descriptors of a configuration at once (e.g. the [`configuration_blob`](crate::UsbHost::configuration_blob)),
+//! use a [`ConfigurationBundle`]. It parses the known descriptors, and tracks which interface (and interface association)
+//! each descriptor belongs to.
+//!
 
-use crate::types::{Bcd16, TransferType};
-use defmt::Format;
+use crate::types::{Bcd16, ConnectionSpeed, PollingInterval, TransferType};
 use usb_device::UsbDirection;
 
 /// [`descriptor_type`](Descriptor::descriptor_type) identifying a [`DeviceDescriptor`]
 pub const TYPE_DEVICE: u8 = 1;
 /// [`descriptor_type`](Descriptor::descriptor_type) identifying a [`ConfigurationDescriptor`]
 pub const TYPE_CONFIGURATION: u8 = 2;
-/// [`descriptor_type`](Descriptor::descriptor_type) identifying a `StringDescriptor` (not yet implemented)
+/// [`descriptor_type`](Descriptor::descriptor_type) identifying a string descriptor (see [`decode_string`])
 pub const TYPE_STRING: u8 = 3;
 /// [`descriptor_type`](Descriptor::descriptor_type) identifying an [`InterfaceDescriptor`]
 pub const TYPE_INTERFACE: u8 = 4;
 /// [`descriptor_type`](Descriptor::descriptor_type) identifying an [`EndpointDescriptor`]
 pub const TYPE_ENDPOINT: u8 = 5;
+/// [`descriptor_type`](Descriptor::descriptor_type) identifying a [`DeviceQualifierDescriptor`]
+pub const TYPE_DEVICE_QUALIFIER: u8 = 6;
+/// [`descriptor_type`](Descriptor::descriptor_type) identifying an [`InterfaceAssociationDescriptor`]
+pub const TYPE_INTERFACE_ASSOCIATION: u8 = 11;
+/// [`descriptor_type`](Descriptor::descriptor_type) identifying a [`BosDescriptor`]
+pub const TYPE_BOS: u8 = 15;
+/// [`descriptor_type`](Descriptor::descriptor_type) identifying a [`DeviceCapability`], as part of the BOS descriptor
+pub const TYPE_DEVICE_CAPABILITY: u8 = 16;
+
+/// [`capability_type`](DeviceCapability::capability_type) identifying a [`Usb20ExtensionCapability`]
+pub const CAPABILITY_USB_20_EXTENSION: u8 = 0x02;
+/// [`capability_type`](DeviceCapability::capability_type) of the SuperSpeed USB capability
+pub const CAPABILITY_SUPERSPEED_USB: u8 = 0x03;
+/// [`capability_type`](DeviceCapability::capability_type) identifying a [`ContainerIdCapability`]
+pub const CAPABILITY_CONTAINER_ID: u8 = 0x04;
+/// [`capability_type`](DeviceCapability::capability_type) of platform capabilities (e.g. WebUSB, Microsoft OS 2.0 descriptors)
+pub const CAPABILITY_PLATFORM: u8 = 0x05;
+/// [`capability_type`](DeviceCapability::capability_type) identifying a [`BillboardCapability`]
+pub const CAPABILITY_BILLBOARD: u8 = 0x0D;
 
 /// Outer framing of a descriptor
+#[derive(Debug)]
 pub struct Descriptor<'a> {
     /// Total length of the descriptor, including this length byte itself and the `descriptor_type` byte
     pub length: u8,
@@ -44,7 +67,9 @@ pub struct Descriptor<'a> {
 
 /// A device descriptor describes general information about a USB device. It includes information that applies
 /// globally to the device and all of the device’s configurations. A USB device has only one device descriptor.
-#[derive(Format)]
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DeviceDescriptor {
     /// USB Specification Release Number in Binary-Coded Decimal (i.e., 2.10 is 210H).
     ///
@@ -111,7 +136,9 @@ pub struct DeviceDescriptor {
 ///
 /// The descriptor contains a bConfigurationValue field with a value that, when used as a parameter
 /// to the SetConfiguration() request, causes the device to assume the described configuration.
-#[derive(Format)]
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ConfigurationDescriptor {
     /// Total length of data returned for this configuration.
     ///
@@ -137,7 +164,9 @@ pub struct ConfigurationDescriptor {
     pub max_power: u8,
 }
 
-#[derive(Clone, Copy, Format)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ConfigurationAttributes(u8);
 
 /// Part of the [`ConfigurationDescriptor`]
@@ -163,7 +192,9 @@ impl ConfigurationAttributes {
 /// particular interface follow the interface descriptor in the data returned by the GetConfiguration() request.
 /// An interface descriptor is always returned as part of a configuration descriptor. Interface descriptors cannot
 /// be directly accessed with a GetDescriptor() or SetDescriptor() request.
-#[derive(Format)]
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct InterfaceDescriptor {
     /// Number of this interface.
     ///
@@ -208,10 +239,23 @@ pub struct InterfaceDescriptor {
     pub interface_index: u8,
 }
 
+impl InterfaceDescriptor {
+    /// Returns `true` if this descriptor describes an alternate setting, rather than the default setting of the interface
+    ///
+    /// Alternate settings share the `interface_number` of the default setting (which has an `alternate_setting` of 0).
+    /// The endpoint descriptors following an alternate setting only apply once it was selected with
+    /// [`UsbHost::set_interface`](crate::UsbHost::set_interface).
+    pub fn is_alternate_setting(&self) -> bool {
+        self.alternate_setting != 0
+    }
+}
+
 /// Each endpoint used for an interface has its own descriptor.
 ///
 /// This descriptor contains the information required by the host to determine the bandwidth requirements of each endpoint.
-#[derive(Format)]
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct EndpointDescriptor {
     /// The address of the endpoint on the USB device described by this descriptor.
     pub address: EndpointAddress,
@@ -224,11 +268,21 @@ pub struct EndpointDescriptor {
 
     /// Interval for polling endpoint for data transfers.
     ///
-    /// Expressed in frames (1 millisecond).
+    /// This is the raw `bInterval` value. Its meaning depends on the speed of the device and the transfer type,
+    /// use [`EndpointDescriptor::polling_interval`] to interpret it.
     pub interval: u8,
 }
 
-#[derive(Clone, Copy, Format)]
+impl EndpointDescriptor {
+    /// Interval at which the endpoint is polled, for a device connected with the given speed
+    pub fn polling_interval(&self, speed: ConnectionSpeed) -> PollingInterval {
+        PollingInterval::from_descriptor(self.interval, speed, self.attributes.transfer_type())
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 /// Address of an endpoint
 ///
 /// Part of an [`EndpointDescriptor`].
@@ -248,7 +302,9 @@ impl EndpointAddress {
     }
 }
 
-#[derive(Clone, Copy, Format)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 /// Attributes of an endpoint
 ///
 /// Part of an [`EndpointDescriptor`].
@@ -273,6 +329,8 @@ impl EndpointAttributes {
 #[derive(Clone, Copy)]
 #[repr(u8)]
 /// Synchronization type for an Isochronous endpoint
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum SynchronizationType {
     NoSynchronization = 0b00,
     Asynchronouse = 0b01,
@@ -283,6 +341,8 @@ pub enum SynchronizationType {
 #[derive(Clone, Copy)]
 #[repr(u8)]
 /// Usage type for an Isochronous endpoint
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum UsageType {
     Data = 0b00,
     Feedback = 0b01,
@@ -290,6 +350,546 @@ pub enum UsageType {
     Reserved = 0b11,
 }
 
+/// The interface association descriptor groups consecutive interfaces, which together form a single function
+///
+/// For example, a CDC ACM function consists of a communication and a data interface. Devices with multiple such functions
+/// (composite devices) describe each of them with an interface association descriptor, preceding the interfaces.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct InterfaceAssociationDescriptor {
+    /// Number of the first interface that is associated with this function
+    pub first_interface: u8,
+
+    /// Number of contiguous interfaces that are associated with this function
+    pub interface_count: u8,
+
+    /// Class code (assigned by the USB-IF) of the function
+    pub function_class: u8,
+
+    /// Subclass code (assigned by the USB-IF) of the function
+    pub function_sub_class: u8,
+
+    /// Protocol code (assigned by the USB-IF) of the function
+    pub function_protocol: u8,
+
+    /// Index of string descriptor describing this function
+    pub function_index: u8,
+}
+
+impl InterfaceAssociationDescriptor {
+    /// Returns `true` if the interface with the given number is part of this function
+    pub fn contains(&self, interface_number: u8) -> bool {
+        interface_number.wrapping_sub(self.first_interface) < self.interface_count
+    }
+}
+
+/// The device qualifier descriptor describes how a high-speed capable device would operate at the other speed
+///
+/// Only high-speed capable devices (with a `usb_release` of 2.0 or higher) have one. Full-speed only devices respond to
+/// the request with a STALL.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeviceQualifierDescriptor {
+    /// USB Specification Release Number in Binary-Coded Decimal
+    pub usb_release: Bcd16,
+
+    /// Class code, when operating at the other speed
+    pub device_class: u8,
+
+    /// Subclass code, when operating at the other speed
+    pub device_sub_class: u8,
+
+    /// Protocol code, when operating at the other speed
+    pub device_protocol: u8,
+
+    /// Maximum packet size of endpoint zero, when operating at the other speed
+    pub max_packet_size: u8,
+
+    /// Number of configurations, when operating at the other speed
+    pub num_configurations: u8,
+}
+
+/// The Binary device Object Store (BOS) descriptor is the header of a set of device capability descriptors
+///
+/// Devices with a `usb_release` of 2.1 or higher provide one. To read the capabilities, request the BOS descriptor
+/// with its `total_length` (see [`UsbHost::get_bos_descriptor`](crate::UsbHost::get_bos_descriptor)), and iterate over
+/// them with [`device_capabilities`].
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BosDescriptor {
+    /// Length of the BOS descriptor, including all of its device capability descriptors
+    pub total_length: u16,
+
+    /// Number of device capability descriptors in the BOS
+    pub num_device_caps: u8,
+}
+
+/// Device capability descriptor, as found in the BOS descriptor
+///
+/// The `data` can be interpreted further, depending on the `capability_type` (see the `CAPABILITY_*` constants).
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DeviceCapability<'a> {
+    /// Type of the capability (`bDevCapabilityType`)
+    pub capability_type: u8,
+
+    /// Capability-specific data, following the `capability_type`
+    pub data: &'a [u8],
+}
+
+/// Iterate over the device capabilities in the given BOS descriptor
+///
+/// The `data` is the complete BOS descriptor, including its header and framing (i.e. as received from the device).
+/// Descriptors other than device capabilities are skipped. Iteration stops at the first descriptor that is truncated.
+pub fn device_capabilities(data: &[u8]) -> impl Iterator<Item = DeviceCapability<'_>> {
+    let mut rest = data;
+    core::iter::from_fn(move || loop {
+        let length = *rest.first()? as usize;
+        if length < 2 || length > rest.len() {
+            return None;
+        }
+        let (descriptor, next) = rest.split_at(length);
+        rest = next;
+        if descriptor[1] == TYPE_DEVICE_CAPABILITY && length >= 3 {
+            return Some(DeviceCapability { capability_type: descriptor[2], data: &descriptor[3..] });
+        }
+    })
+}
+
+/// USB 2.0 Extension capability, announcing support for Link Power Management (LPM)
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Usb20ExtensionCapability {
+    /// Raw `bmAttributes`
+    pub attributes: u32,
+}
+
+impl Usb20ExtensionCapability {
+    /// Parse the `data` of a capability with type [`CAPABILITY_USB_20_EXTENSION`]
+    pub fn parse(capability: &DeviceCapability) -> Option<Self> {
+        let bytes = capability.data.get(..4)?;
+        (capability.capability_type == CAPABILITY_USB_20_EXTENSION)
+            .then(|| Usb20ExtensionCapability { attributes: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) })
+    }
+
+    /// Whether the device supports Link Power Management
+    pub fn lpm(&self) -> bool {
+        self.attributes & (1 << 1) != 0
+    }
+
+    /// Whether the BESL (best effort service latency) values below are valid
+    pub fn besl_and_alternate_hird(&self) -> bool {
+        self.attributes & (1 << 2) != 0
+    }
+
+    /// Recommended baseline BESL value, if given
+    pub fn baseline_besl(&self) -> Option<u8> {
+        (self.attributes & (1 << 3) != 0).then_some(((self.attributes >> 8) & 0xF) as u8)
+    }
+
+    /// Recommended deep BESL value, if given
+    pub fn deep_besl(&self) -> Option<u8> {
+        (self.attributes & (1 << 4) != 0).then_some(((self.attributes >> 12) & 0xF) as u8)
+    }
+}
+
+/// Container ID capability: a UUID which is unique to the device instance
+///
+/// It is the same for all the functions of a device (even across multiple ports or speeds), so it can be used to identify
+/// a device in the absence of a serial number.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ContainerIdCapability {
+    pub container_id: [u8; 16],
+}
+
+impl ContainerIdCapability {
+    /// Parse the `data` of a capability with type [`CAPABILITY_CONTAINER_ID`]
+    pub fn parse(capability: &DeviceCapability) -> Option<Self> {
+        // the data starts with a reserved byte
+        let id = capability.data.get(1..17)?;
+        let mut container_id = [0; 16];
+        container_id.copy_from_slice(id);
+        (capability.capability_type == CAPABILITY_CONTAINER_ID).then_some(ContainerIdCapability { container_id })
+    }
+}
+
+/// Billboard capability, announcing the alternate modes of a USB Type-C device, and whether they could be entered
+///
+/// Billboard devices are presented by Type-C devices whose alternate mode (e.g. DisplayPort) could not be configured,
+/// to let the user know why. Use [`alternate_modes`](BillboardCapability::alternate_modes) to list the modes themselves.
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BillboardCapability {
+    /// Index of a string descriptor with a URL, where the user can find more information (0 if none)
+    pub additional_info_url: u8,
+
+    /// Number of alternate modes the device supports
+    pub num_alternate_modes: u8,
+
+    /// Index of the alternate mode the device prefers
+    pub preferred_alternate_mode: u8,
+
+    /// Raw `VCONNPower`
+    pub vconn_power: u16,
+
+    /// Two bits of state per alternate mode (`bmConfigured`)
+    configured: [u8; 32],
+
+    /// Billboard Capability version number
+    pub billboard_release: Bcd16,
+
+    /// Raw `bAdditionalFailureInfo`
+    pub additional_failure_info: u8,
+}
+
+/// State of an alternate mode, as reported by a [`BillboardCapability`]
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AlternateModeState {
+    /// Unspecified error
+    Error,
+    /// Entering the mode was not attempted, or the mode was exited
+    NotAttempted,
+    /// Entering the mode was attempted, but not successful
+    Unsuccessful,
+    /// The mode was entered
+    Configured,
+}
+
+/// Alternate mode listed in a [`BillboardCapability`]
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AlternateMode {
+    /// Standard or vendor ID of the mode (e.g. `0xFF01` for DisplayPort)
+    pub svid: u16,
+
+    /// Index of the mode within the SVID
+    pub mode: u8,
+
+    /// Index of a string descriptor describing the mode (0 if none)
+    pub string_index: u8,
+}
+
+impl BillboardCapability {
+    /// Parse the `data` of a capability with type [`CAPABILITY_BILLBOARD`]
+    pub fn parse(capability: &DeviceCapability) -> Option<Self> {
+        let data = capability.data.get(..40)?;
+        let mut configured = [0; 32];
+        configured.copy_from_slice(&data[5..37]);
+        (capability.capability_type == CAPABILITY_BILLBOARD).then_some(BillboardCapability {
+            additional_info_url: data[0],
+            num_alternate_modes: data[1],
+            preferred_alternate_mode: data[2],
+            vconn_power: u16::from_le_bytes([data[3], data[4]]),
+            configured,
+            billboard_release: Bcd16(u16::from_le_bytes([data[37], data[38]])),
+            additional_failure_info: data[39],
+        })
+    }
+
+    /// State of the alternate mode with the given index
+    pub fn alternate_mode_state(&self, index: u8) -> AlternateModeState {
+        let bits = self.configured.get(index as usize / 4).map_or(0, |byte| byte >> ((index % 4) * 2)) & 0b11;
+        match bits {
+            0 => AlternateModeState::Error,
+            1 => AlternateModeState::NotAttempted,
+            2 => AlternateModeState::Unsuccessful,
+            _ => AlternateModeState::Configured,
+        }
+    }
+
+    /// Whether all of the device's alternate modes were entered successfully
+    pub fn all_configured(&self) -> bool {
+        (0..self.num_alternate_modes).all(|index| self.alternate_mode_state(index) == AlternateModeState::Configured)
+    }
+
+    /// Iterate over the alternate modes listed in the given capability
+    ///
+    /// Modes that are truncated are skipped. Yields nothing, if the capability is not a billboard capability.
+    pub fn alternate_modes<'a>(capability: &DeviceCapability<'a>) -> impl Iterator<Item = AlternateMode> + 'a {
+        let modes = match capability.capability_type {
+            CAPABILITY_BILLBOARD => capability.data.get(41..).unwrap_or(&[]),
+            _ => &[],
+        };
+        let count = capability.data.get(1).copied().unwrap_or(0) as usize;
+        modes.chunks_exact(4).take(count).map(|mode| AlternateMode {
+            svid: u16::from_le_bytes([mode[0], mode[1]]),
+            mode: mode[2],
+            string_index: mode[3],
+        })
+    }
+}
+
+/// Maximum number of class descriptors retained from a [`HidDescriptor`]
+pub const MAX_HID_CLASS_DESCRIPTORS: usize = 4;
+
+/// The HID descriptor follows the interface descriptor of a HID interface (type [`crate::hid::TYPE_HID`])
+///
+/// It announces the class-specific descriptors of the interface, most importantly the report descriptor. Since that is
+/// not part of the configuration, HID drivers use its length to request it after configuration (see [`crate::hid`]).
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HidDescriptor {
+    /// HID Class Specification release number
+    pub hid_release: Bcd16,
+
+    /// Country code of localized hardware (0 if the hardware is not localized)
+    pub country_code: u8,
+
+    /// Number of class descriptors announced by the device
+    pub num_descriptors: u8,
+
+    /// Type and length of the announced class descriptors
+    ///
+    /// Only the first [`MAX_HID_CLASS_DESCRIPTORS`] are kept, see [`HidDescriptor::class_descriptors`].
+    descriptors: [(u8, u16); MAX_HID_CLASS_DESCRIPTORS],
+}
+
+impl HidDescriptor {
+    /// Type and length of each class descriptor announced by the device
+    ///
+    /// Yields at most [`MAX_HID_CLASS_DESCRIPTORS`] items, fewer if the descriptor was truncated.
+    pub fn class_descriptors(&self) -> impl Iterator<Item = (u8, u16)> + '_ {
+        self.descriptors.iter().copied().take_while(|(descriptor_type, _)| *descriptor_type != 0)
+    }
+
+    /// Length of the report descriptor, or `None` if the device does not announce one
+    pub fn report_descriptor_length(&self) -> Option<u16> {
+        self.class_descriptors()
+            .find(|(descriptor_type, _)| *descriptor_type == crate::hid::TYPE_REPORT)
+            .map(|(_, length)| length)
+    }
+}
+
+/// A single descriptor from a configuration, as yielded by [`ConfigurationBundle`]
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ConfigurationItem<'a> {
+    Configuration(ConfigurationDescriptor),
+    InterfaceAssociation(InterfaceAssociationDescriptor),
+    Interface(InterfaceDescriptor),
+    Endpoint(EndpointDescriptor),
+    /// HID descriptor, following the interface descriptor of a HID interface
+    Hid(HidDescriptor),
+    /// Any other descriptor, including known types which could not be parsed
+    Unknown {
+        descriptor_type: u8,
+        /// Remaining data of the descriptor, excluding the length & type bytes
+        data: &'a [u8],
+    },
+}
+
+/// Descriptor yielded by [`ConfigurationBundle`], together with the interface it belongs to
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BundleItem<'a> {
+    pub item: ConfigurationItem<'a>,
+
+    /// Number and alternate setting of the interface which the descriptor belongs to
+    ///
+    /// This is set for interface descriptors, as well as all descriptors following one (until the next interface descriptor).
+    pub interface: Option<(u8, u8)>,
+
+    /// Interface association which the `interface` is part of, if any
+    pub function: Option<InterfaceAssociationDescriptor>,
+}
+
+/// Iterator over all the descriptors of a configuration
+///
+/// The data starts with the configuration descriptor, followed by all the interface, endpoint and class- or vendor-specific
+/// descriptors belonging to it, i.e. it is the response to a `Get_Descriptor` request for the full `total_length` of the
+/// configuration (for example the [`configuration_blob`](crate::UsbHost::configuration_blob) of a device).
+///
+/// Each descriptor is yielded together with the interface it belongs to, and the interface association (if any) grouping
+/// that interface with others. This makes it easy to match drivers against a whole function of a composite device.
+///
+/// Iteration stops at the first descriptor with an invalid length, or which is truncated.
+#[derive(Clone, Debug)]
+pub struct ConfigurationBundle<'a> {
+    rest: &'a [u8],
+    interface: Option<(u8, u8)>,
+    association: Option<InterfaceAssociationDescriptor>,
+}
+
+impl<'a> ConfigurationBundle<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        ConfigurationBundle {
+            rest: data,
+            interface: None,
+            association: None,
+        }
+    }
+
+    fn parse_item(descriptor_type: u8, data: &'a [u8]) -> ConfigurationItem<'a> {
+        let item = match descriptor_type {
+            TYPE_CONFIGURATION => parse::configuration_descriptor(data).map(|(_, d)| ConfigurationItem::Configuration(d)),
+            TYPE_INTERFACE_ASSOCIATION => {
+                parse::interface_association_descriptor(data).map(|(_, d)| ConfigurationItem::InterfaceAssociation(d))
+            }
+            TYPE_INTERFACE => parse::interface_descriptor(data).map(|(_, d)| ConfigurationItem::Interface(d)),
+            TYPE_ENDPOINT => parse::endpoint_descriptor(data).map(|(_, d)| ConfigurationItem::Endpoint(d)),
+            crate::hid::TYPE_HID => parse::hid_descriptor(data).map(|(_, d)| ConfigurationItem::Hid(d)),
+            _ => return ConfigurationItem::Unknown { descriptor_type, data },
+        };
+        item.unwrap_or(ConfigurationItem::Unknown { descriptor_type, data })
+    }
+}
+
+impl<'a> Iterator for ConfigurationBundle<'a> {
+    type Item = BundleItem<'a>;
+
+    fn next(&mut self) -> Option<BundleItem<'a>> {
+        let length = *self.rest.first()? as usize;
+        if length < 2 || length > self.rest.len() {
+            self.rest = &[];
+            return None;
+        }
+        let (descriptor, rest) = self.rest.split_at(length);
+        self.rest = rest;
+
+        let item = Self::parse_item(descriptor[1], &descriptor[2..]);
+        match &item {
+            ConfigurationItem::InterfaceAssociation(association) => {
+                self.association = Some(*association);
+                self.interface = None;
+            }
+            ConfigurationItem::Interface(interface) => {
+                self.interface = Some((interface.interface_number, interface.alternate_setting));
+            }
+            _ => {}
+        }
+        let function = self
+            .interface
+            .and_then(|(number, _)| self.association.filter(|association| association.contains(number)));
+        Some(BundleItem {
+            item,
+            interface: self.interface,
+            function,
+        })
+    }
+}
+
+/// Configuration and interface which a descriptor read during discovery belongs to
+///
+/// Passed to [`Driver::descriptor_with_context`](crate::driver::Driver::descriptor_with_context), so that drivers can tell
+/// which interface (and alternate setting) an endpoint or class specific descriptor belongs to, without tracking the order
+/// of descriptors themselves.
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DescriptorContext {
+    /// Value of the configuration which the descriptor belongs to
+    ///
+    /// This is `None` for descriptors outside of a configuration, e.g. the device descriptor.
+    pub configuration: Option<u8>,
+
+    /// Number and alternate setting of the interface which the descriptor belongs to
+    ///
+    /// As for [`BundleItem::interface`], this is set for interface descriptors, as well as all descriptors following one
+    /// (until the next interface or interface association descriptor).
+    pub interface: Option<(u8, u8)>,
+}
+
+impl DescriptorContext {
+    /// Advance the context to the given descriptor
+    ///
+    /// The `data` excludes the length & type bytes (i.e. it is [`Descriptor::data`]).
+    pub fn update(&mut self, descriptor_type: u8, data: &[u8]) {
+        match descriptor_type {
+            TYPE_CONFIGURATION => {
+                *self = DescriptorContext {
+                    configuration: parse::configuration_descriptor(data).ok().map(|(_, configuration)| configuration.value),
+                    interface: None,
+                };
+            }
+            TYPE_INTERFACE_ASSOCIATION => self.interface = None,
+            TYPE_INTERFACE => {
+                self.interface = parse::interface_descriptor(data)
+                    .ok()
+                    .map(|(_, interface)| (interface.interface_number, interface.alternate_setting));
+            }
+            TYPE_DEVICE | TYPE_DEVICE_QUALIFIER | TYPE_BOS => *self = DescriptorContext::default(),
+            _ => {}
+        }
+    }
+}
+
+/// Language ID for US English, which is the language supported by most devices
+pub const LANG_ID_EN_US: u16 = 0x0409;
+
+/// Decode the UTF-16LE payload of a string descriptor into the given buffer
+///
+/// The `data` is the descriptor data, excluding the length & type bytes (i.e. [`Descriptor::data`]).
+///
+/// If the string does not fit into `buf`, it is truncated (at a character boundary). Invalid UTF-16 sequences are
+/// replaced with `U+FFFD`.
+pub fn decode_string<'a>(data: &[u8], buf: &'a mut [u8]) -> &'a str {
+    let units = data.chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]]));
+    let mut len = 0;
+    for c in core::char::decode_utf16(units) {
+        let c = c.unwrap_or(core::char::REPLACEMENT_CHARACTER);
+        if len + c.len_utf8() > buf.len() {
+            break;
+        }
+        len += c.encode_utf8(&mut buf[len..]).len();
+    }
+    // Unwrap safety: the buffer only contains complete UTF-8 encoded characters
+    core::str::from_utf8(&buf[..len]).unwrap()
+}
+
+/// List the language IDs supported by the device
+///
+/// The `data` is the data of string descriptor zero, excluding the length & type bytes.
+pub fn language_ids(data: &[u8]) -> impl Iterator<Item = u16> + '_ {
+    data.chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+}
+
+/// Computes a stable hash over a set of descriptors
+///
+/// Uses the 32-bit FNV-1a algorithm. The hash only depends on the descriptor bytes (and the order in which they are fed in),
+/// so it can be used to recognize a device (or a change in its firmware) across reconnects and reboots.
+///
+/// The host computes this hash over all descriptors read during discovery, see [`DeviceSummary::descriptor_hash`](crate::DeviceSummary::descriptor_hash).
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DescriptorHasher(u32);
+
+impl DescriptorHasher {
+    const OFFSET_BASIS: u32 = 0x811c9dc5;
+    const PRIME: u32 = 0x01000193;
+
+    pub const fn new() -> Self {
+        Self(Self::OFFSET_BASIS)
+    }
+
+    /// Feed raw descriptor data into the hash
+    pub fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.0 = (self.0 ^ *byte as u32).wrapping_mul(Self::PRIME);
+        }
+    }
+
+    /// The hash over all data fed in so far
+    pub fn finish(&self) -> u32 {
+        self.0
+    }
+}
+
+impl Default for DescriptorHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub mod parse {
     use nom::bytes::streaming::take;
     use nom::combinator::{map, verify};
@@ -304,7 +904,7 @@ pub mod parse {
     /// The resulting `data` within the descriptor can then be parsed with one of the other functions below,
     /// depending on the `type`.
     pub fn any_descriptor(input: &[u8]) -> IResult<&[u8], Descriptor<'_>> {
-        let (input, (length, descriptor_type)) = tuple((u8, u8))(input)?;
+        let (input, (length, descriptor_type)) = tuple((verify(u8, |length| *length >= 2), u8))(input)?;
         let (input, data) = take((length - 2) as usize)(input)?;
         Ok((
             input,
@@ -354,6 +954,31 @@ pub mod parse {
         )(input)
     }
 
+    /// Parse descriptor data for a device qualifier
+    pub fn device_qualifier_descriptor(input: &[u8]) -> IResult<&[u8], DeviceQualifierDescriptor> {
+        map(
+            tuple((bcd_16, u8, u8, u8, u8, u8)),
+            |(usb_release, device_class, device_sub_class, device_protocol, max_packet_size, num_configurations)| {
+                DeviceQualifierDescriptor {
+                    usb_release,
+                    device_class,
+                    device_sub_class,
+                    device_protocol,
+                    max_packet_size,
+                    num_configurations,
+                }
+            },
+        )(input)
+    }
+
+    /// Parse descriptor data for a BOS descriptor (only the header, see [`device_capabilities`] for the rest)
+    pub fn bos_descriptor(input: &[u8]) -> IResult<&[u8], BosDescriptor> {
+        map(tuple((le_u16, u8)), |(total_length, num_device_caps)| BosDescriptor {
+            total_length,
+            num_device_caps,
+        })(input)
+    }
+
     /// Parse descriptor data for a configuration
     pub fn configuration_descriptor(input: &[u8]) -> IResult<&[u8], ConfigurationDescriptor> {
         map(
@@ -415,6 +1040,47 @@ pub mod parse {
         )(input)
     }
 
+    /// Parse descriptor data for an interface association
+    pub fn interface_association_descriptor(input: &[u8]) -> IResult<&[u8], InterfaceAssociationDescriptor> {
+        map(
+            tuple((u8, u8, u8, u8, u8, u8)),
+            |(first_interface, interface_count, function_class, function_sub_class, function_protocol, function_index)| {
+                InterfaceAssociationDescriptor {
+                    first_interface,
+                    interface_count,
+                    function_class,
+                    function_sub_class,
+                    function_protocol,
+                    function_index,
+                }
+            },
+        )(input)
+    }
+
+    /// Parse descriptor data for a HID descriptor
+    ///
+    /// Class descriptors beyond [`MAX_HID_CLASS_DESCRIPTORS`], or which are cut off, are skipped.
+    pub fn hid_descriptor(input: &[u8]) -> IResult<&[u8], HidDescriptor> {
+        let (mut input, (hid_release, country_code, num_descriptors)) = tuple((bcd_16, u8, u8))(input)?;
+        let mut descriptors = [(0, 0); MAX_HID_CLASS_DESCRIPTORS];
+        for slot in descriptors.iter_mut().take(num_descriptors as usize) {
+            let Ok((rest, entry)) = tuple::<_, _, nom::error::Error<_>, _>((u8, le_u16))(input) else {
+                break;
+            };
+            *slot = entry;
+            input = rest;
+        }
+        Ok((
+            input,
+            HidDescriptor {
+                hid_release,
+                country_code,
+                num_descriptors,
+                descriptors,
+            },
+        ))
+    }
+
     /// Parses a 16-bit binary coded decimal value
     ///
     /// Succeeds only if the data is indeed a valid value. This requires all four nibbles (i.e. half-bytes) to be in the 0-9 range.
@@ -434,6 +1100,24 @@ pub mod parse {
             assert_eq!(desc.descriptor_type, 7);
             assert_eq!(desc.data, &[6, 5, 4, 3, 2, 1]);
             assert_eq!(rest, &[0]);
+
+            // the length includes the length & type bytes
+            assert!(any_descriptor(&[1, 7, 6]).is_err());
+        }
+
+        #[test]
+        fn test_hid_descriptor() {
+            let (_, hid) = hid_descriptor(&[0x11, 0x01, 0x21, 0x02, 0x22, 0x3f, 0x00, 0x23, 0x10, 0x00]).unwrap();
+            assert_eq!(hid.hid_release, Bcd16(0x0111));
+            assert_eq!(hid.country_code, 0x21);
+            assert_eq!(hid.num_descriptors, 2);
+            assert!(hid.class_descriptors().eq([(0x22, 0x3f), (0x23, 0x10)]));
+            assert_eq!(hid.report_descriptor_length(), Some(0x3f));
+
+            // truncated after the header
+            let (_, hid) = hid_descriptor(&[0x11, 0x01, 0x00, 0x01]).unwrap();
+            assert_eq!(hid.class_descriptors().count(), 0);
+            assert_eq!(hid.report_descriptor_length(), None);
         }
 
         #[test]
@@ -459,3 +1143,162 @@ pub mod parse {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_string() {
+        let data = [b'U', 0, b'S', 0, b'B', 0, 0xAC, 0x20];
+        let mut buf = [0; 16];
+        assert_eq!(decode_string(&data, &mut buf), "USB\u{20AC}");
+
+        // truncated at a character boundary
+        let mut small = [0; 5];
+        assert_eq!(decode_string(&data, &mut small), "USB");
+
+        // unpaired surrogate
+        assert_eq!(decode_string(&[0x00, 0xD8, b'a', 0], &mut buf), "\u{FFFD}a");
+    }
+
+    #[test]
+    fn test_language_ids() {
+        let mut ids = language_ids(&[0x09, 0x04, 0x07, 0x04]);
+        assert_eq!(ids.next(), Some(LANG_ID_EN_US));
+        assert_eq!(ids.next(), Some(0x0407));
+        assert_eq!(ids.next(), None);
+    }
+
+    #[test]
+    fn test_configuration_bundle() {
+        const CONFIGURATION: &[u8] = &[
+            0x09, 0x02, 0x4b, 0x00, 0x03, 0x01, 0x00, 0x80, 0x32, // configuration 1
+            0x09, 0x04, 0x00, 0x00, 0x01, 0x03, 0x01, 0x01, 0x00, // interface 0: boot keyboard
+            0x09, 0x21, 0x11, 0x01, 0x00, 0x01, 0x22, 0x3f, 0x00, // HID
+            0x07, 0x05, 0x81, 0x03, 0x08, 0x00, 0x0a, // endpoint 1 IN, interrupt
+            0x08, 0x0b, 0x01, 0x02, 0x02, 0x02, 0x01, 0x00, // association: interfaces 1-2, CDC ACM
+            0x09, 0x04, 0x01, 0x00, 0x01, 0x02, 0x02, 0x01, 0x00, // interface 1: CDC communication
+            0x05, 0x24, 0x00, 0x10, 0x01, // CDC header
+            0x09, 0x04, 0x02, 0x00, 0x02, 0x0a, 0x00, 0x00, 0x00, // interface 2: CDC data
+            0x07, 0x05, 0x02, 0x02, 0x40, 0x00, 0x00, // endpoint 2 OUT, bulk
+            0x02, // truncated
+        ];
+        let mut bundle = ConfigurationBundle::new(CONFIGURATION);
+
+        let item = bundle.next().unwrap();
+        assert!(matches!(item.item, ConfigurationItem::Configuration(ConfigurationDescriptor { num_interfaces: 3, .. })));
+        assert!(item.interface.is_none() && item.function.is_none());
+
+        let item = bundle.next().unwrap();
+        assert!(matches!(item.item, ConfigurationItem::Interface(InterfaceDescriptor { interface_class: 3, .. })));
+        assert_eq!(item.interface, Some((0, 0)));
+        let item = bundle.next().unwrap();
+        assert!(matches!(&item.item, ConfigurationItem::Hid(hid) if hid.report_descriptor_length() == Some(0x3f)));
+        assert_eq!(item.interface, Some((0, 0)));
+        let item = bundle.next().unwrap();
+        assert!(matches!(item.item, ConfigurationItem::Endpoint(EndpointDescriptor { max_packet_size: 8, .. })));
+        assert!(item.function.is_none());
+
+        let item = bundle.next().unwrap();
+        assert!(matches!(item.item, ConfigurationItem::InterfaceAssociation(InterfaceAssociationDescriptor { first_interface: 1, .. })));
+        assert!(item.interface.is_none());
+
+        let item = bundle.next().unwrap();
+        assert_eq!(item.interface, Some((1, 0)));
+        assert!(item.function.is_some_and(|function| function.function_class == 2));
+        let item = bundle.next().unwrap();
+        assert!(matches!(item.item, ConfigurationItem::Unknown { descriptor_type: 0x24, data: [0x00, 0x10, 0x01] }));
+        assert_eq!(item.interface, Some((1, 0)));
+        let item = bundle.next().unwrap();
+        assert_eq!(item.interface, Some((2, 0)));
+        assert!(item.function.is_some());
+        let item = bundle.next().unwrap();
+        assert!(matches!(item.item, ConfigurationItem::Endpoint(_)));
+        assert!(item.function.is_some_and(|function| function.contains(2)));
+
+        assert!(bundle.next().is_none());
+    }
+
+    #[test]
+    fn test_descriptor_context() {
+        let mut context = DescriptorContext::default();
+        context.update(TYPE_CONFIGURATION, &[0x22, 0x00, 0x02, 0x03, 0x00, 0xa0, 0x32]);
+        assert_eq!(context, DescriptorContext { configuration: Some(3), interface: None });
+        context.update(TYPE_INTERFACE, &[0x01, 0x02, 0x01, 0x03, 0x00, 0x00, 0x00]);
+        context.update(TYPE_ENDPOINT, &[0x81, 0x03, 0x08, 0x00, 0x0a]);
+        assert_eq!(context, DescriptorContext { configuration: Some(3), interface: Some((1, 2)) });
+        context.update(TYPE_INTERFACE_ASSOCIATION, &[0x02, 0x02, 0x02, 0x02, 0x01, 0x00]);
+        assert_eq!(context.interface, None);
+        context.update(TYPE_DEVICE, &[]);
+        assert_eq!(context, DescriptorContext::default());
+    }
+
+    #[test]
+    fn test_bos_descriptor() {
+        const BOS: &[u8] = &[
+            0x05, 0x0f, 0x20, 0x00, 0x02, // BOS, 2 capabilities
+            0x07, 0x10, 0x02, 0x1e, 0x64, 0x00, 0x00, // USB 2.0 extension: LPM, baseline BESL 4, deep BESL 6
+            0x14, 0x10, 0x04, 0x00, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, // container ID
+        ];
+        let (_, bos) = parse::bos_descriptor(&BOS[2..]).unwrap();
+        assert_eq!(bos.total_length, BOS.len() as u16);
+        assert_eq!(bos.num_device_caps, 2);
+
+        let mut capabilities = device_capabilities(BOS);
+        let usb20 = Usb20ExtensionCapability::parse(&capabilities.next().unwrap()).unwrap();
+        assert!(usb20.lpm());
+        assert_eq!(usb20.baseline_besl(), Some(4));
+        assert_eq!(usb20.deep_besl(), Some(6));
+        let container = capabilities.next().unwrap();
+        assert!(Usb20ExtensionCapability::parse(&container).is_none());
+        let container = ContainerIdCapability::parse(&container).unwrap();
+        assert_eq!(container.container_id[15], 15);
+        assert!(capabilities.next().is_none());
+    }
+
+    #[test]
+    fn test_billboard_capability() {
+        const BILLBOARD: &[u8] = &[
+            0x34, 0x10, 0x0d, 0x01, 0x02, 0x00, 0x00, 0x80, // billboard: URL string 1, 2 modes, VCONN power not required
+            0x0e, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // mode 0: unsuccessful, mode 1: configured
+            0x21, 0x01, 0x00, 0x00, // version 1.21
+            0x01, 0xff, 0x01, 0x02, // mode 0: DisplayPort
+            0x87, 0x80, 0x01, 0x00, // mode 1: Thunderbolt
+        ];
+        let capability = device_capabilities(BILLBOARD).next().unwrap();
+        let billboard = BillboardCapability::parse(&capability).unwrap();
+        assert_eq!(billboard.additional_info_url, 1);
+        assert_eq!(billboard.num_alternate_modes, 2);
+        assert_eq!(billboard.vconn_power, 0x8000);
+        assert_eq!(billboard.billboard_release, Bcd16(0x0121));
+        assert_eq!(billboard.alternate_mode_state(0), AlternateModeState::Unsuccessful);
+        assert_eq!(billboard.alternate_mode_state(1), AlternateModeState::Configured);
+        assert!(!billboard.all_configured());
+
+        let mut modes = BillboardCapability::alternate_modes(&capability);
+        assert_eq!(modes.next(), Some(AlternateMode { svid: 0xff01, mode: 1, string_index: 2 }));
+        assert_eq!(modes.next(), Some(AlternateMode { svid: 0x8087, mode: 1, string_index: 0 }));
+        assert!(modes.next().is_none());
+        // truncated
+        assert!(BillboardCapability::parse(&DeviceCapability { capability_type: CAPABILITY_BILLBOARD, data: &BILLBOARD[3..20] }).is_none());
+    }
+
+    #[test]
+    fn test_descriptor_hasher() {
+        assert_eq!(DescriptorHasher::new().finish(), 0x811c9dc5);
+
+        let mut hasher = DescriptorHasher::new();
+        hasher.update(b"a");
+        assert_eq!(hasher.finish(), 0xe40c292c);
+
+        // feeding data in pieces yields the same hash
+        let mut split = DescriptorHasher::new();
+        split.update(b"foo");
+        split.update(b"bar");
+        let mut whole = DescriptorHasher::new();
+        whole.update(b"foobar");
+        assert_eq!(split.finish(), whole.finish());
+        assert_eq!(whole.finish(), 0xbf9cf968);
+    }
+}
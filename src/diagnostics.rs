@@ -0,0 +1,168 @@
+//! Information about what the host is doing, for watchdog diagnostics
+//!
+//! When an external watchdog resets the system, it is useful to know which transfer the USB stack was waiting for.
+//! [`UsbHost::active_transfer_info`](crate::UsbHost::active_transfer_info) describes the transfer in progress, and
+//! [`UsbHost::crash_dump`](crate::UsbHost::crash_dump) captures it (along with the host state) in a [`CrashDump`], which
+//! can be stored in memory that survives a reset.
+//!
+//! Example:
+//! ```ignore
+//! #[link_section = ".uninit.USB_DUMP"]
+//! static mut USB_DUMP: MaybeUninit<CrashDump> = MaybeUninit::uninit();
+//!
+//! loop {
+//!     usb_host.poll(&mut drivers);
+//!     unsafe { USB_DUMP.write(usb_host.crash_dump()) };
+//! }
+//!
+//! // after a reset:
+//! let dump = unsafe { USB_DUMP.assume_init_read() };
+//! if dump.is_valid() {
+//!     defmt::warn!("USB stack was in state {}, transfer stage {}", dump.host_state, dump.stage);
+//! }
+//! ```
+
+use crate::types::{DeviceAddress, TransferType};
+use crate::PipeId;
+use usb_device::UsbDirection;
+
+/// Stage of a transfer in progress
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum TransferStage {
+    /// Waiting for the SETUP packet of a control transfer to be acknowledged
+    Setup = 1,
+    /// Waiting for the data stage (of a control transfer), or for the data of a bulk transfer
+    Data = 2,
+    /// Waiting for the status stage of a control transfer
+    Status = 3,
+}
+
+/// Phase the host is in, see [`UsbHost`](crate::UsbHost) for a description of the phases
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum HostPhase {
+    Enumeration = 1,
+    HubEnumeration = 2,
+    Discovery = 3,
+    Verification = 4,
+    Configuration = 5,
+    Idle = 6,
+}
+
+/// Describes the transfer that is currently in progress
+///
+/// Returned by [`UsbHost::active_transfer_info`](crate::UsbHost::active_transfer_info).
+#[derive(Copy, Clone, Debug)]
+pub struct TransferInfo {
+    /// Device the transfer is addressed to, if known
+    ///
+    /// This is `None` for transfers that the host initiates during enumeration, before the device has an address.
+    pub dev_addr: Option<DeviceAddress>,
+    /// Pipe of the transfer, or `None` for transfers initiated by the host itself (e.g. during discovery)
+    pub pipe_id: Option<PipeId>,
+    pub transfer_type: TransferType,
+    pub direction: UsbDirection,
+    pub stage: TransferStage,
+    /// Length of the data stage, in bytes
+    pub length: u16,
+    /// Number of frames (i.e. milliseconds) since the transfer was started
+    pub frames_in_flight: u32,
+}
+
+/// Marker written to [`CrashDump::magic`], to recognize a dump in uninitialized memory
+pub const CRASH_DUMP_MAGIC: u32 = 0x5553_4248;
+
+/// Compact snapshot of the host state, meant to be stored in memory that is not initialized on reset
+///
+/// Consists of plain integers only, with a fixed layout, so that it can be read back after a reset (by the same firmware),
+/// or extracted by a debugger. Created with [`UsbHost::crash_dump`](crate::UsbHost::crash_dump).
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(C)]
+pub struct CrashDump {
+    /// Always [`CRASH_DUMP_MAGIC`]
+    pub magic: u32,
+    /// Frame count at the time the dump was taken
+    pub frame_count: u32,
+    /// Frames since the active transfer was started (0 if there is none)
+    pub frames_in_flight: u32,
+    /// Length of the data stage of the active transfer
+    pub length: u16,
+    /// Phase of the host: 1 = enumeration, 2 = hub enumeration, 3 = discovery, 4 = verification, 5 = configuration, 6 = idle
+    /// (see [`HostPhase`])
+    pub host_state: u8,
+    /// Address of the device of the active transfer (0 if there is no transfer, or the address is not known)
+    pub dev_addr: u8,
+    /// Index of the pipe of the active transfer (`0xff` if there is no transfer, or it does not use a pipe)
+    pub pipe: u8,
+    /// Transfer type of the active transfer (see [`TransferType`], `0xff` if there is no transfer)
+    pub transfer_type: u8,
+    /// Direction of the active transfer (`0x80` for IN, `0` for OUT)
+    pub direction: u8,
+    /// Stage of the active transfer (see [`TransferStage`], 0 if there is no transfer)
+    pub stage: u8,
+}
+
+impl CrashDump {
+    /// Returns `true` if this dump was written by [`UsbHost::crash_dump`](crate::UsbHost::crash_dump)
+    ///
+    /// Memory that was never written contains random data, which is unlikely to contain the magic value.
+    pub fn is_valid(&self) -> bool {
+        self.magic == CRASH_DUMP_MAGIC
+    }
+
+    pub(crate) fn new(frame_count: u32, host_state: u8, transfer: Option<TransferInfo>) -> Self {
+        let mut dump = CrashDump {
+            magic: CRASH_DUMP_MAGIC,
+            frame_count,
+            frames_in_flight: 0,
+            length: 0,
+            host_state,
+            dev_addr: 0,
+            pipe: 0xff,
+            transfer_type: 0xff,
+            direction: 0,
+            stage: 0,
+        };
+        if let Some(info) = transfer {
+            dump.frames_in_flight = info.frames_in_flight;
+            dump.length = info.length;
+            dump.dev_addr = info.dev_addr.map_or(0, u8::from);
+            dump.pipe = info.pipe_id.map_or(0xff, |pipe_id| pipe_id.0);
+            dump.transfer_type = info.transfer_type as u8;
+            dump.direction = info.direction as u8;
+            dump.stage = info.stage as u8;
+        }
+        dump
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::num::NonZeroU8;
+
+    #[test]
+    fn test_crash_dump() {
+        let dump = CrashDump::new(100, 6, None);
+        assert!(dump.is_valid());
+        assert!(dump.pipe == 0xff && dump.stage == 0);
+
+        let info = TransferInfo {
+            dev_addr: Some(DeviceAddress(NonZeroU8::new(3).unwrap())),
+            pipe_id: None,
+            transfer_type: TransferType::Control,
+            direction: UsbDirection::In,
+            stage: TransferStage::Status,
+            length: 18,
+            frames_in_flight: 7,
+        };
+        let dump = CrashDump::new(100, 3, Some(info));
+        assert!(dump.dev_addr == 3 && dump.pipe == 0xff);
+        assert!(dump.direction == 0x80 && dump.stage == 3);
+        assert!(dump.frames_in_flight == 7 && dump.length == 18);
+    }
+}
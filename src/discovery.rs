@@ -1,159 +1,530 @@
-use crate::bus::HostBus;
+//! Discovery phase: reading the device and configuration descriptors of a newly addressed device
+//!
+//! The state machine does not perform any I/O itself. Each step returns a [`DiscoveryStep`], describing what the
+//! [`UsbHost`](crate::UsbHost) needs to do next.
+
 use crate::descriptor;
-use crate::driver::Driver;
-use crate::types::DeviceAddress;
-use crate::{Event, UsbHost};
+use crate::hid;
+use crate::DeviceInfo;
 use usb_device::control::Recipient;
-use defmt::trace;
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum DiscoveryState {
-    // get device descriptor
-    DeviceDesc,
+    // get the first 8 bytes of the device descriptor, to learn the maximum packet size of endpoint zero
+    DeviceDescHeader(bool),
+    // get device descriptor (and the serial number afterwards, if set)
+    DeviceDesc(bool),
+    // get serial number string, before continuing with m configurations
+    SerialNumber(u8),
     // get configuration descriptor length n of m
     ConfigDescLen(u8, u8),
-    // get full configuration descriptor n of m
-    ConfigDesc(u8, u8),
+    // get full configuration descriptor n of m, with given total length, starting at given offset
+    ConfigDesc(u8, u8, u16, u16),
+    // get a HID report descriptor for a driver, before continuing with configuration n of m (or finishing, if n == m)
+    ReportDesc(u8, u8),
     // finished discovery.
     Done,
     // failed to parse one of the descriptors
     ParseError,
 }
 
+/// Descriptor request to send to the device next
+#[derive(Copy, Clone, PartialEq)]
+pub enum DiscoveryRequest {
+    /// GET_DESCRIPTOR for the first 8 bytes of the device descriptor, which can be read with any packet size
+    DeviceDescriptorHeader,
+    /// GET_DESCRIPTOR for the (full) device descriptor
+    DeviceDescriptor,
+    /// GET_DESCRIPTOR for the serial number string with given index (in US English)
+    SerialNumber { index: u8 },
+    /// GET_DESCRIPTOR for the configuration descriptor with given index, reading `length` bytes
+    ///
+    /// The device always returns the descriptor from the start, so the first `offset` bytes are discarded by the host.
+    ConfigurationDescriptor { index: u8, length: u16, offset: u16 },
+    /// GET_DESCRIPTOR for (the first `length` bytes of) the HID report descriptor of the given interface
+    ReportDescriptor { interface: u8, length: u16 },
+}
+
+impl DiscoveryRequest {
+    pub fn descriptor_type(&self) -> u8 {
+        match self {
+            DiscoveryRequest::DeviceDescriptorHeader | DiscoveryRequest::DeviceDescriptor => descriptor::TYPE_DEVICE,
+            DiscoveryRequest::SerialNumber { .. } => descriptor::TYPE_STRING,
+            DiscoveryRequest::ConfigurationDescriptor { .. } => descriptor::TYPE_CONFIGURATION,
+            DiscoveryRequest::ReportDescriptor { .. } => hid::TYPE_REPORT,
+        }
+    }
+
+    pub fn index(&self) -> u8 {
+        match self {
+            DiscoveryRequest::DeviceDescriptorHeader | DiscoveryRequest::DeviceDescriptor => 0,
+            DiscoveryRequest::SerialNumber { index } => *index,
+            DiscoveryRequest::ConfigurationDescriptor { index, .. } => *index,
+            DiscoveryRequest::ReportDescriptor { .. } => 0,
+        }
+    }
+
+    /// Recipient of the request: the interface for report descriptors, the device otherwise
+    pub fn recipient(&self) -> Recipient {
+        match self {
+            DiscoveryRequest::ReportDescriptor { .. } => Recipient::Interface,
+            _ => Recipient::Device,
+        }
+    }
+
+    /// `wIndex` of the request: the language ID of string descriptors, or the interface number of report descriptors
+    pub fn w_index(&self) -> u16 {
+        match self {
+            DiscoveryRequest::SerialNumber { .. } => descriptor::LANG_ID_EN_US,
+            DiscoveryRequest::ReportDescriptor { interface, .. } => *interface as u16,
+            _ => 0,
+        }
+    }
+
+    pub fn length(&self) -> u16 {
+        match self {
+            DiscoveryRequest::DeviceDescriptorHeader => 8,
+            DiscoveryRequest::DeviceDescriptor => 18,
+            // string descriptors are at most 255 bytes long
+            DiscoveryRequest::SerialNumber { .. } => 255,
+            DiscoveryRequest::ConfigurationDescriptor { length, .. } => *length,
+            DiscoveryRequest::ReportDescriptor { length, .. } => *length,
+        }
+    }
+
+    pub fn offset(&self) -> u16 {
+        match self {
+            DiscoveryRequest::ConfigurationDescriptor { offset, .. } => *offset,
+            _ => 0,
+        }
+    }
+}
+
+/// Side effects of a single discovery step
+#[derive(Copy, Clone, PartialEq, Default)]
+pub struct DiscoveryStep<'a> {
+    /// Descriptors to hash, and to forward to the drivers (one by one)
+    pub descriptors: Option<&'a [u8]>,
+    /// The maximum packet size of endpoint zero was learned from the first 8 bytes of the device descriptor
+    pub max_packet_size_0: Option<u8>,
+    /// The device descriptor was read
+    pub device: Option<DeviceInfo>,
+    /// The serial number string descriptor was read (raw descriptor, including the header)
+    pub serial_number: Option<&'a [u8]>,
+    /// A HID report descriptor was read (empty, if the device refused the request)
+    pub report_descriptor: Option<&'a [u8]>,
+    /// Transfer to start next
+    pub request: Option<DiscoveryRequest>,
+}
+
+/// Request for the first 9 bytes of a configuration descriptor, which contain its total length
+fn config_length_request(index: u8) -> DiscoveryRequest {
+    DiscoveryRequest::ConfigurationDescriptor { index, length: 9, offset: 0 }
+}
+
+/// Request for the window of the configuration descriptor starting at `offset`
+///
+/// The host can only keep `window` bytes of a transfer, so the request does not ask for more than that past the offset.
+fn config_request(index: u8, total_length: u16, offset: u16, window: u16) -> DiscoveryRequest {
+    let length = total_length.min(offset.saturating_add(window));
+    DiscoveryRequest::ConfigurationDescriptor { index, length, offset }
+}
+
+/// Maximum packet size of endpoint zero, from (the first 8 bytes of) a device descriptor
+///
+/// Returns `None` if the data is not a device descriptor, or the size is not one allowed for full and low speed devices.
+pub fn max_packet_size_0(data: &[u8]) -> Option<u8> {
+    match data {
+        [_, descriptor::TYPE_DEVICE, _, _, _, _, _, size @ (8 | 16 | 32 | 64), ..] => Some(*size),
+        _ => None,
+    }
+}
+
+/// Request a HID report descriptor that a driver needs, before discovery goes on
+///
+/// `state` is the state that discovery is in otherwise: [`DiscoveryState::ConfigDescLen`] (whose request is sent once the
+/// report descriptor was read), or [`DiscoveryState::Done`].
+pub fn request_report_descriptor(state: DiscoveryState, interface: u8, length: u16) -> (DiscoveryState, DiscoveryRequest) {
+    let (n, m) = match state {
+        DiscoveryState::ConfigDescLen(n, m) => (n, m),
+        // no configurations left
+        _ => (0, 0),
+    };
+    trace!("-> ReportDesc({}, {})", n, m);
+    (DiscoveryState::ReportDesc(n, m), DiscoveryRequest::ReportDescriptor { interface, length })
+}
+
+/// Continue with configuration n of m, once the report descriptor was read
+fn report_descriptor_read(n: u8, m: u8, report_descriptor: &[u8]) -> (DiscoveryState, DiscoveryStep<'_>) {
+    if n < m {
+        trace!("-> ConfigDescLen({}, {})", n, m);
+        (
+            DiscoveryState::ConfigDescLen(n, m),
+            DiscoveryStep {
+                report_descriptor: Some(report_descriptor),
+                request: Some(config_length_request(n)),
+                ..Default::default()
+            },
+        )
+    } else {
+        trace!("-> Done");
+        (
+            DiscoveryState::Done,
+            DiscoveryStep {
+                report_descriptor: Some(report_descriptor),
+                ..Default::default()
+            },
+        )
+    }
+}
+
 /// Begin discovery, by requesting the device descriptor
-pub fn start_discovery<B: HostBus>(
-    dev_addr: DeviceAddress,
-    host: &mut UsbHost<B>,
-) -> DiscoveryState {
-    // Unwrap safety: it is up to the UsbHost to start discovery only when no other transfer is in progress.
-    host.get_descriptor(
-        Some(dev_addr),
-        None,
-        Recipient::Device,
-        descriptor::TYPE_DEVICE,
-        0,
-        18,
-    )
-    .ok()
-    .unwrap();
-    DiscoveryState::DeviceDesc
+///
+/// If `read_serial_number` is set, the serial number string is requested as well (if the device has one).
+///
+/// If `read_header` is set, the first 8 bytes of the device descriptor are requested before, because the maximum packet
+/// size of endpoint zero is not known yet. This is the case for devices on hub ports, which are assigned an address right
+/// away.
+pub fn start_discovery(read_serial_number: bool, read_header: bool) -> (DiscoveryState, DiscoveryRequest) {
+    if read_header {
+        (DiscoveryState::DeviceDescHeader(read_serial_number), DiscoveryRequest::DeviceDescriptorHeader)
+    } else {
+        (DiscoveryState::DeviceDesc(read_serial_number), DiscoveryRequest::DeviceDescriptor)
+    }
 }
 
-pub fn process_discovery<B: HostBus>(
-    event: Event,
-    dev_addr: DeviceAddress,
-    state: DiscoveryState,
-    drivers: &mut [&mut dyn Driver<B>],
-    host: &mut UsbHost<B>,
-) -> DiscoveryState {
+/// Advance discovery after the device responded to the last request with a STALL
+///
+/// Devices are not required to support string descriptors, so a stalled request for the serial number is skipped. A
+/// stalled request for a report descriptor is passed on as an empty descriptor. Other requests are mandatory, so discovery
+/// fails.
+pub fn discovery_stalled(state: DiscoveryState) -> (DiscoveryState, DiscoveryStep<'static>) {
     match state {
-        DiscoveryState::DeviceDesc => {
-            match event {
-                Event::ControlInData(_, length) => {
-                    let data = host.bus.received_data(length as usize);
-                    let Ok((_, descriptor)) = descriptor::parse::any_descriptor(data) else {
-                        trace!("Failed to parse descriptor frame: {}", data);
-                        return DiscoveryState::ParseError
-                    };
-                    for driver in drivers {
-                        driver.descriptor(dev_addr, descriptor.descriptor_type, descriptor.data);
-                    }
-                    let Ok((_, device_descriptor)) = descriptor::parse::device_descriptor(descriptor.data) else {
-                        trace!("Failed to parse device descriptor: {}", descriptor.data);
-                        return DiscoveryState::ParseError
-                    };
-
-                    // Unwrap safety: when a `Control*` event is emitted, the host is idle and a transfer can be started
-                    host.get_descriptor(
-                        Some(dev_addr),
-                        None,
-                        Recipient::Device,
-                        descriptor::TYPE_CONFIGURATION,
-                        0,
-                        9,
-                    )
-                    .ok()
-                        .unwrap();
-                    trace!("-> ConfigDescLen(0, {})", device_descriptor.num_configurations);
-                    DiscoveryState::ConfigDescLen(0, device_descriptor.num_configurations)
-                }
-                _ => state,
+        DiscoveryState::SerialNumber(m) => {
+            trace!("-> ConfigDescLen(0, {})", m);
+            (
+                DiscoveryState::ConfigDescLen(0, m),
+                DiscoveryStep {
+                    request: Some(config_length_request(0)),
+                    ..Default::default()
+                },
+            )
+        }
+        DiscoveryState::ReportDesc(n, m) => report_descriptor_read(n, m, &[]),
+        _ => (DiscoveryState::ParseError, DiscoveryStep::default()),
+    }
+}
+
+/// Advance discovery, given the data received from a completed control IN transfer
+///
+/// `data` is `None` for events other than the completion of the host's own control IN transfer, which
+/// do not affect discovery. `window` is the size of the host's control buffer, which limits how much of a configuration
+/// descriptor is requested at once.
+pub fn process_discovery(data: Option<&[u8]>, state: DiscoveryState, window: u16) -> (DiscoveryState, DiscoveryStep<'_>) {
+    let Some(data) = data else {
+        return (state, DiscoveryStep::default());
+    };
+    match state {
+        DiscoveryState::DeviceDescHeader(read_serial_number) => {
+            let Some(max_packet_size_0) = max_packet_size_0(data) else {
+                trace!("Invalid device descriptor header: {:?}", data);
+                return (DiscoveryState::ParseError, DiscoveryStep::default());
+            };
+            trace!("-> DeviceDesc");
+            (
+                DiscoveryState::DeviceDesc(read_serial_number),
+                DiscoveryStep {
+                    max_packet_size_0: Some(max_packet_size_0),
+                    request: Some(DiscoveryRequest::DeviceDescriptor),
+                    ..Default::default()
+                },
+            )
+        }
+        DiscoveryState::DeviceDesc(read_serial_number) => {
+            let Ok((_, descriptor)) = descriptor::parse::any_descriptor(data) else {
+                trace!("Failed to parse descriptor frame: {:?}", data);
+                return (DiscoveryState::ParseError, DiscoveryStep::default());
+            };
+            let Ok((_, device_descriptor)) = descriptor::parse::device_descriptor(descriptor.data) else {
+                trace!("Failed to parse device descriptor: {:?}", descriptor.data);
+                return (DiscoveryState::ParseError, DiscoveryStep::default());
+            };
+            let device = Some(DeviceInfo::from(&device_descriptor));
+            let m = device_descriptor.num_configurations;
+            if read_serial_number && device_descriptor.serial_number_index != 0 {
+                trace!("-> SerialNumber({})", m);
+                (
+                    DiscoveryState::SerialNumber(m),
+                    DiscoveryStep {
+                        descriptors: Some(data),
+                        device,
+                        request: Some(DiscoveryRequest::SerialNumber {
+                            index: device_descriptor.serial_number_index,
+                        }),
+                        ..Default::default()
+                    },
+                )
+            } else {
+                trace!("-> ConfigDescLen(0, {})", m);
+                (
+                    DiscoveryState::ConfigDescLen(0, m),
+                    DiscoveryStep {
+                        descriptors: Some(data),
+                        device,
+                        request: Some(config_length_request(0)),
+                        ..Default::default()
+                    },
+                )
             }
         }
+        DiscoveryState::SerialNumber(m) => {
+            trace!("-> ConfigDescLen(0, {})", m);
+            (
+                DiscoveryState::ConfigDescLen(0, m),
+                DiscoveryStep {
+                    serial_number: Some(data),
+                    request: Some(config_length_request(0)),
+                    ..Default::default()
+                },
+            )
+        }
         DiscoveryState::ConfigDescLen(n, m) => {
-            match event {
-                Event::ControlInData(_, length) => {
-                    let data = host.bus.received_data(length as usize);
-                    let Ok((_, descriptor)) = descriptor::parse::any_descriptor(data) else {
-                        trace!("Failed to parse descriptor frame: {}", data);
-                        return DiscoveryState::ParseError
-                    };
-                    let Ok((_, total_length)) = descriptor::parse::configuration_descriptor_length(descriptor.data) else {
-                        trace!("Failed to extract length from configuration descriptor: {}", descriptor.data);
-                        return DiscoveryState::ParseError
-                    };
-                    // Unwrap safety: when a `Control*` event is emitted, the host is idle and a transfer can be started
-                    host.get_descriptor(
-                        Some(dev_addr),
-                        None,
-                        Recipient::Device,
-                        descriptor::TYPE_CONFIGURATION,
-                        n,
-                        total_length,
-                    )
-                    .ok()
-                        .unwrap();
-                    trace!("-> ConfigDesc({}, {})", n, m);
-                    DiscoveryState::ConfigDesc(n, m)
-                }
-                _ => state,
-            }
+            let Ok((_, descriptor)) = descriptor::parse::any_descriptor(data) else {
+                trace!("Failed to parse descriptor frame: {:?}", data);
+                return (DiscoveryState::ParseError, DiscoveryStep::default());
+            };
+            let Ok((_, total_length)) = descriptor::parse::configuration_descriptor_length(descriptor.data) else {
+                trace!("Failed to extract length from configuration descriptor: {:?}", descriptor.data);
+                return (DiscoveryState::ParseError, DiscoveryStep::default());
+            };
+            trace!("-> ConfigDesc({}, {}, {}, 0)", n, m, total_length);
+            (
+                DiscoveryState::ConfigDesc(n, m, total_length, 0),
+                DiscoveryStep {
+                    request: Some(config_request(n, total_length, 0, window)),
+                    ..Default::default()
+                },
+            )
         }
-        DiscoveryState::ConfigDesc(n, m) => {
-            match event {
-                Event::ControlInData(_, length) => {
-                    let mut data = host.bus.received_data(length as usize);
-                    loop {
-                        let Ok((rest, descriptor)) = descriptor::parse::any_descriptor(data) else {
-                            trace!("Failed to parse descriptor frame: {}", data);
-                            return DiscoveryState::ParseError
-                        };
-                        for driver in &mut *drivers {
-                            driver.descriptor(
-                                dev_addr,
-                                descriptor.descriptor_type,
-                                descriptor.data,
-                            );
-                        }
-                        if rest.len() > 0 {
-                            data = rest;
-                        } else {
-                            break;
-                        }
-                    }
-                    if (n + 1) < m {
-                        // Unwrap safety: when a `Control*` event is emitted, the host is idle and a transfer can be started
-                        host.get_descriptor(
-                            Some(dev_addr),
-                            None,
-                            Recipient::Device,
-                            descriptor::TYPE_CONFIGURATION,
-                            n + 1,
-                            9,
-                        )
-                        .ok()
-                        .unwrap();
-                        trace!("-> ConfigDescLen({}, {})", n + 1, m);
-                        DiscoveryState::ConfigDescLen(n + 1, m)
-                    } else {
-                        // NOTE: do not start a transfer here, the UsbHost code expects the bus to stay idle.
-                        trace!("-> Done");
-                        DiscoveryState::Done
-                    }
+        DiscoveryState::ConfigDesc(n, m, total_length, offset) => {
+            // Configurations larger than the control buffer are read in multiple windows. Only complete descriptors
+            // are consumed from each window, the next one starts at the first incomplete descriptor.
+            let mut rest = data;
+            while let Ok((next, _)) = descriptor::parse::any_descriptor(rest) {
+                rest = next;
+                if rest.is_empty() {
+                    break;
                 }
-                _ => state,
+            }
+            let consumed = data.len() - rest.len();
+            let expected = (total_length - offset).min(window) as usize;
+            // a short response also ends the descriptor, even if it is shorter than `wTotalLength` claims
+            let complete = offset as usize + data.len() >= total_length as usize || data.len() < expected;
+            if (complete && consumed < data.len()) || consumed == 0 {
+                trace!("Failed to parse descriptor frame: {:?}", rest);
+                return (DiscoveryState::ParseError, DiscoveryStep::default());
+            }
+            let descriptors = Some(&data[..consumed]);
+            if !complete {
+                let offset = offset + consumed as u16;
+                trace!("-> ConfigDesc({}, {}, {}, {})", n, m, total_length, offset);
+                (
+                    DiscoveryState::ConfigDesc(n, m, total_length, offset),
+                    DiscoveryStep {
+                        descriptors,
+                        request: Some(config_request(n, total_length, offset, window)),
+                        ..Default::default()
+                    },
+                )
+            } else if (n + 1) < m {
+                trace!("-> ConfigDescLen({}, {})", n + 1, m);
+                (
+                    DiscoveryState::ConfigDescLen(n + 1, m),
+                    DiscoveryStep {
+                        descriptors,
+                        request: Some(config_length_request(n + 1)),
+                        ..Default::default()
+                    },
+                )
+            } else {
+                // NOTE: no request here, the UsbHost code expects the bus to stay idle.
+                trace!("-> Done");
+                (
+                    DiscoveryState::Done,
+                    DiscoveryStep {
+                        descriptors,
+                        ..Default::default()
+                    },
+                )
             }
         }
-        DiscoveryState::Done | DiscoveryState::ParseError => unreachable!(),
+        DiscoveryState::ReportDesc(n, m) => report_descriptor_read(n, m, data),
+        // final states, which the host does not pass in (see `UsbHost::process_discovery`)
+        DiscoveryState::Done | DiscoveryState::ParseError => (state, DiscoveryStep::default()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Bcd16;
+
+    const WINDOW: u16 = crate::DEFAULT_CONTROL_BUFFER_SIZE as u16;
+
+    const DEVICE_DESCRIPTOR: [u8; 18] = [
+        18, 1, 0x00, 0x02, 0, 0, 0, 64, 0x34, 0x12, 0x78, 0x56, 0x00, 0x01, 1, 2, 3, 2,
+    ];
+
+    const CONFIGURATION_DESCRIPTOR: [u8; 25] = [
+        9, 2, 25, 0, 1, 1, 0, 0x80, 50, // configuration
+        9, 4, 0, 0, 1, 3, 1, 1, 0, // interface
+        7, 5, 0x81, 3, 8, 0, 10, // endpoint
+    ];
+
+    #[test]
+    fn test_discovery_sequence() {
+        let (state, request) = start_discovery(true, false);
+        assert!(request == DiscoveryRequest::DeviceDescriptor);
+
+        // events other than control IN completion are ignored
+        let (state, step) = process_discovery(None, state, WINDOW);
+        assert!(state == DiscoveryState::DeviceDesc(true));
+        assert!(step == DiscoveryStep::default());
+
+        let (state, step) = process_discovery(Some(&DEVICE_DESCRIPTOR), state, WINDOW);
+        assert!(state == DiscoveryState::SerialNumber(2));
+        assert!(step.descriptors == Some(&DEVICE_DESCRIPTOR[..]));
+        assert!(step.device == Some(DeviceInfo {
+                vendor_id: 0x1234,
+                product_id: 0x5678,
+                usb_release: Bcd16(0x0200),
+                device_class: 0,
+                device_sub_class: 0,
+                device_protocol: 0,
+                max_packet_size_0: 64,
+                device_release: Bcd16(0x0100),
+                num_configurations: 2,
+            }));
+        assert!(step.request == Some(DiscoveryRequest::SerialNumber { index: 3 }));
+
+        let serial = [6, 3, b'1', 0, b'2', 0];
+        let (state, step) = process_discovery(Some(&serial), state, WINDOW);
+        assert!(state == DiscoveryState::ConfigDescLen(0, 2));
+        assert!(step.serial_number == Some(&serial[..]));
+        assert!(step.request == Some(config_length_request(0)));
+
+        let mut state = state;
+        for index in 0..2 {
+            let (next, step) = process_discovery(Some(&CONFIGURATION_DESCRIPTOR[..9]), state, WINDOW);
+            assert!(next == DiscoveryState::ConfigDesc(index, 2, 25, 0));
+            assert!(step.descriptors.is_none());
+            assert!(step.request == Some(DiscoveryRequest::ConfigurationDescriptor { index, length: 25, offset: 0 }));
+
+            let (next, step) = process_discovery(Some(&CONFIGURATION_DESCRIPTOR), next, WINDOW);
+            assert!(step.descriptors == Some(&CONFIGURATION_DESCRIPTOR[..]));
+            state = next;
+        }
+        assert!(state == DiscoveryState::Done);
+    }
+
+    #[test]
+    fn test_discovery_parse_error() {
+        let (state, step) = process_discovery(Some(&DEVICE_DESCRIPTOR[..10]), DiscoveryState::DeviceDesc(false), WINDOW);
+        assert!(state == DiscoveryState::ParseError);
+        assert!(step.request.is_none());
+
+        // truncated endpoint descriptor: nothing is forwarded to drivers
+        let (state, step) = process_discovery(Some(&CONFIGURATION_DESCRIPTOR[..22]), DiscoveryState::ConfigDesc(0, 1, 25, 0), WINDOW);
+        assert!(state == DiscoveryState::ParseError);
+        assert!(step.descriptors.is_none());
+
+        // the final state is kept, whatever data arrives next
+        let (state, step) = process_discovery(Some(&DEVICE_DESCRIPTOR), state, WINDOW);
+        assert!(state == DiscoveryState::ParseError);
+        assert!(step.request.is_none() && step.descriptors.is_none());
+    }
+
+    #[test]
+    fn test_device_descriptor_header() {
+        let (state, request) = start_discovery(false, true);
+        assert!(request == DiscoveryRequest::DeviceDescriptorHeader && request.length() == 8);
+
+        let (state, step) = process_discovery(Some(&DEVICE_DESCRIPTOR[..8]), state, WINDOW);
+        assert!(state == DiscoveryState::DeviceDesc(false));
+        assert!(step.max_packet_size_0 == Some(64));
+        assert!(step.descriptors.is_none());
+        assert!(step.request == Some(DiscoveryRequest::DeviceDescriptor));
+
+        // not a valid packet size for endpoint zero
+        let mut header = [0; 8];
+        header.copy_from_slice(&DEVICE_DESCRIPTOR[..8]);
+        header[7] = 12;
+        let (state, _) = process_discovery(Some(&header), DiscoveryState::DeviceDescHeader(false), WINDOW);
+        assert!(state == DiscoveryState::ParseError);
+        assert!(max_packet_size_0(&DEVICE_DESCRIPTOR[..7]).is_none());
+    }
+
+    #[test]
+    fn test_large_configuration() {
+        // configuration with 100 interfaces (900 bytes), which does not fit into the control buffer
+        let mut data = [0u8; 909];
+        data[..9].copy_from_slice(&[9, 2, 0x8D, 0x03, 100, 1, 0, 0x80, 50]);
+        for (i, interface) in data[9..].chunks_mut(9).enumerate() {
+            interface.copy_from_slice(&[9, 4, i as u8, 0, 0, 0xFF, 0, 0, 0]);
+        }
+
+        let (state, step) = process_discovery(Some(&data[..9]), DiscoveryState::ConfigDescLen(0, 1), WINDOW);
+        assert!(state == DiscoveryState::ConfigDesc(0, 1, 909, 0));
+        assert!(step.request == Some(DiscoveryRequest::ConfigurationDescriptor { index: 0, length: 512, offset: 0 }));
+
+        // the window ends in the middle of an interface descriptor, which is requested again
+        let (state, step) = process_discovery(Some(&data[..512]), state, WINDOW);
+        assert!(state == DiscoveryState::ConfigDesc(0, 1, 909, 504));
+        assert!(step.descriptors == Some(&data[..504]));
+        assert!(step.request == Some(DiscoveryRequest::ConfigurationDescriptor { index: 0, length: 909, offset: 504 }));
+
+        let (state, step) = process_discovery(Some(&data[504..]), state, WINDOW);
+        assert!(state == DiscoveryState::Done);
+        assert!(step.descriptors == Some(&data[504..]));
+        assert!(step.request.is_none());
+    }
+
+    #[test]
+    fn test_serial_number_stalled() {
+        let (state, step) = process_discovery(Some(&DEVICE_DESCRIPTOR), DiscoveryState::DeviceDesc(true), WINDOW);
+        assert!(state == DiscoveryState::SerialNumber(2));
+        assert!(step.device.is_some());
+        let (state, step) = discovery_stalled(state);
+        assert!(state == DiscoveryState::ConfigDescLen(0, 2));
+        assert!(step.request == Some(config_length_request(0)));
+
+        let (state, _) = discovery_stalled(DiscoveryState::ConfigDescLen(0, 2));
+        assert!(state == DiscoveryState::ParseError);
+
+        // without reading the serial number
+        let (state, _) = process_discovery(Some(&DEVICE_DESCRIPTOR), DiscoveryState::DeviceDesc(false), WINDOW);
+        assert!(state == DiscoveryState::ConfigDescLen(0, 2));
+    }
+
+    #[test]
+    fn test_report_descriptor() {
+        // requested before the next configuration
+        let (state, request) = request_report_descriptor(DiscoveryState::ConfigDescLen(1, 2), 3, 64);
+        assert!(state == DiscoveryState::ReportDesc(1, 2));
+        assert!(request == DiscoveryRequest::ReportDescriptor { interface: 3, length: 64 });
+        assert!((request.descriptor_type(), request.recipient(), request.w_index()) == (hid::TYPE_REPORT, Recipient::Interface, 3));
+        let report = [0x05, 0x8D, 0x09, 0x20];
+        let (state, step) = process_discovery(Some(&report), state, WINDOW);
+        assert!(state == DiscoveryState::ConfigDescLen(1, 2));
+        assert!(step.report_descriptor == Some(&report[..]));
+        assert!(step.request == Some(config_length_request(1)));
+
+        // requested after the last configuration, and refused
+        let (state, _) = request_report_descriptor(DiscoveryState::Done, 0, 64);
+        let (state, step) = discovery_stalled(state);
+        assert!(state == DiscoveryState::Done);
+        assert!(step.report_descriptor == Some(&[][..]));
+        assert!(step.request.is_none());
     }
 }
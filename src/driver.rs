@@ -4,13 +4,15 @@
 //!
 //! The methods defined in this trait are then called by by the UsbHost at the appropriate times.
 //!
-//! All of these methods are called on *all* of the drivers (with the exception fo the [`configure`](Driver::configure) method).
+//! All of these methods are called on *all* of the drivers (with the exception fo the [`configure`](Driver::configure) method, and the
+//! callbacks for transfers on a pipe, which only go to the driver that created the pipe).
 //!
 //! Drivers must interpret the `dev_addr` parameter (and `pipe_id` where applicable) to determine if the event
 //! targets one of the devices that the driver is controlling.
 //!
 //! In general multiple drivers can communicate with the same device, except that only one driver can decide which device
-//! configuration to set.
+//! configuration to set. For composite devices (e.g. a keyboard with a built-in mouse), drivers can claim individual
+//! interfaces, see [`claim_interfaces`](Driver::claim_interfaces).
 //!
 //! ## Walkthrough for a newly connected device
 //!
@@ -20,14 +22,23 @@
 //!    the configurations that the device supports. All of these descriptors are parsed into `descriptor_type` and `data` and passed to the [`descriptor`](Driver::descriptor) method one-by-one.
 //!    When requesting a configuration descriptor, the device sends *all* of the nested descriptors (interface, endpoint, class specifics, ...) as well.
 //!    The discovery logic separates these descriptors and passes each of them to the [`descriptor`](Driver::descriptor) method separately.
+//!    Interfaces with alternate settings are described by one interface descriptor per setting, each followed by the endpoints of that
+//!    setting (see [`InterfaceDescriptor::is_alternate_setting`](crate::descriptor::InterfaceDescriptor::is_alternate_setting)). Only the
+//!    default setting is active after configuration, others can be selected with [`set_interface`](crate::UsbHost::set_interface).
+//!    Drivers which need to know the configuration and interface a descriptor belongs to can implement
+//!    [`descriptor_with_context`](Driver::descriptor_with_context) instead.
+//!    Drivers can shorten discovery by telling the host early that they do not need further configurations, see
+//!    [`discovery_interest`](Driver::discovery_interest).
 //! 4. When all descriptors have been fetched, the host enters the **configuration** phase.
 //! 5. During configuration, the host calls [`configure`](Driver::configure) on each of the drivers *until one of them returns a value*.
 //!    The value must be a valid configuration value (i.e. come from a [`ConfigurationDescriptor::value`](crate::descriptor::ConfigurationDescriptor::value)).
 //! 6. If all of the drivers' `configure` calls returned `None` (no driver is interested in it), the host enteres **dormant** state.
-//!    Otherwise the host calls [`configured`](Driver::configured) on *all* of the drivers and enteres **configured** state.
+//!    Otherwise the host sets the configuration, asks *all* of the drivers which of its interfaces they want to handle
+//!    ([`claim_interfaces`](Driver::claim_interfaces)), then calls [`configured`](Driver::configured) on *all* of the drivers and
+//!    enteres **configured** state. Each interface is granted to the first driver that claims it.
 //! 7. The [`configured`](Driver::configured) callback informs the driver about the chosen configuration, and gives access to the host interface,
 //!    to allow the driver to set up pipes for the device's endpoints.
-//!    Currently only **control pipes** and **interrupt pipes** are supported.
+//!    Currently **control pipes**, **interrupt pipes** and **bulk pipes** are supported.
 //!
 //! This concludes the configuration phase. If the device ends up in **configured** state (one of the drivers selected a configuration),
 //! drivers can communicate with the device from now on.
@@ -39,6 +50,7 @@
 //! For this purpose the driver should define function specific methods on the driver object, which take a `&mut UsbHost<B>`.
 //!
 //! Communication may only happen through the pipes which were created by the [`Driver::configured`] callback. Pipes are identified by a [`PipeId`].
+//! The callbacks for transfers on these pipes are only called on the driver that created them (see [`UsbHost::set_pipe_owner`](crate::UsbHost::set_pipe_owner)).
 //!
 //! ### Control transfers
 //!
@@ -54,8 +66,21 @@
 //!     control_pipe: Option<PipeId>,
 //! }
 //!
-//! impl<B: HostBus> Driver<B> for MyDriver {
-//!     fn configured(&mut self, dev_addr: DeviceAddress, _value: u8, host: &mut UsbHost) {
+//! // implementing the trait for any parameters makes the driver usable with any size of pipe table, control queue and
+//! // control buffer
+//! impl<
+//!         B: HostBus,
+//!         const MAX_PIPES: usize,
+//!         const CONTROL_QUEUE_DEPTH: usize,
+//!         const CONTROL_BUFFER_SIZE: usize,
+//!     > Driver<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE> for MyDriver
+//! {
+//!     fn configured(
+//!         &mut self,
+//!         dev_addr: DeviceAddress,
+//!         _value: u8,
+//!         host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+//!     ) {
 //!         self.dev_addr = Some(dev_addr);
 //!         // NOTE: the host can only handle a fixed number of pipes. If it runs out of pipes, None is returned.
 //!         self.control_pipe = host.create_control_pipe(dev_addr);
@@ -66,7 +91,15 @@
 //!
 //! impl MyDriver {
 //!     // driver specific method, which will be called by application code
-//!     fn turn_on_led<B: HostBus>(&mut self, host: &mut UsbHost<B>) -> Result<(), ControlError> {
+//!     fn turn_on_led<
+//!         B: HostBus,
+//!         const MAX_PIPES: usize,
+//!         const CONTROL_QUEUE_DEPTH: usize,
+//!         const CONTROL_BUFFER_SIZE: usize,
+//!     >(
+//!         &mut self,
+//!         host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+//!     ) -> Result<(), ControlError> {
 //!         if let (Some(dev_addr), Some(control_pipe)) = (self.dev_addr, self.control_pipe) {
 //!             host.control_out(
 //!                 // device being addressed
@@ -84,23 +117,83 @@
 //! }
 //! ```
 //!
+//! All of these parameters have defaults ([`DEFAULT_MAX_PIPES`], [`DEFAULT_CONTROL_QUEUE_DEPTH`] and
+//! [`DEFAULT_CONTROL_BUFFER_SIZE`]). A driver which only needs to work with a default host can implement `Driver<B>`
+//! instead, and take a [`DefaultUsbHost<B>`](crate::DefaultUsbHost) (which is the same as `UsbHost<B>`):
+//!
+//! ```ignore
+//! impl<B: HostBus> Driver<B> for MyDriver {
+//!     fn configured(&mut self, dev_addr: DeviceAddress, _value: u8, host: &mut DefaultUsbHost<B>) {
+//!         // ...
+//!     }
+//! }
+//! ```
+//!
 //!
 //!
 use crate::bus::HostBus;
-use crate::types::{ConnectionSpeed, DeviceAddress};
-use crate::{PipeId, UsbHost};
+use crate::descriptor::DescriptorContext;
+use crate::types::{ConnectionSpeed, DeviceAddress, InterfaceSet};
+use crate::{
+    DetachReason, PipeId, TransferError, UsbHost, DEFAULT_CONTROL_BUFFER_SIZE,
+    DEFAULT_CONTROL_QUEUE_DEPTH, DEFAULT_MAX_PIPES,
+};
 
 pub mod detector;
+pub mod registry;
 
+pub mod audio;
+pub mod gamepad;
+#[cfg(feature = "driver-kbd")]
 pub mod kbd;
+#[cfg(feature = "driver-log")]
 pub mod log;
+pub mod mouse;
+pub mod msc;
+pub mod printer;
+#[cfg(feature = "driver-hub")]
 pub mod hub;
+pub mod raw;
+pub mod scale;
+pub mod serial;
+pub mod uvc;
+
+/// Answer of a driver during discovery, whether it still needs to see descriptors of a device
+///
+/// See [`Driver::discovery_interest`].
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DiscoveryInterest {
+    /// The driver needs to see further configurations, before it can decide
+    Undecided,
+    /// The driver will not configure the device, e.g. because of the class or the IDs in its device descriptor
+    NotInterested,
+    /// The driver has seen the configuration it is going to choose
+    Ready,
+    /// The driver needs (the first `length` bytes of) the HID report descriptor of the given interface, before it can decide
+    ///
+    /// The host requests the descriptor before it goes on with discovery, and passes it to
+    /// [`descriptor`](Driver::descriptor) with type [`hid::TYPE_REPORT`](crate::hid::TYPE_REPORT). If the device refuses
+    /// the request, the descriptor is empty. The device is not configured yet at this point.
+    ReportDescriptor { interface: u8, length: u16 },
+}
 
 /// The Driver trait
 ///
 /// See [module-level documentation](`crate::driver`) for details.
 ///
-pub trait Driver<B: HostBus> {
+/// `MAX_PIPES` is the size of the pipe table of the [`UsbHost`] that the driver is used with (see
+/// [Number of pipes](UsbHost#number-of-pipes)). `CONTROL_QUEUE_DEPTH` and `CONTROL_BUFFER_SIZE` are the depth of its
+/// control queue and the size of its control buffer (see [Control queue](UsbHost#control-queue) and
+/// [Control buffer](UsbHost#control-buffer)). Drivers that should work with hosts of any size implement the trait for
+/// any value of these parameters, like the drivers in this crate do.
+pub trait Driver<
+    B: HostBus,
+    const MAX_PIPES: usize = DEFAULT_MAX_PIPES,
+    const CONTROL_QUEUE_DEPTH: usize = DEFAULT_CONTROL_QUEUE_DEPTH,
+    const CONTROL_BUFFER_SIZE: usize = DEFAULT_CONTROL_BUFFER_SIZE,
+>
+{
     /// New device was attached, and got assigned the given address.
     ///
     /// This is where the driver can set up internal structures to continue processing the device.
@@ -111,6 +204,15 @@ pub trait Driver<B: HostBus> {
     /// Clean up any internal data related to the device here.
     fn detached(&mut self, dev_addr: DeviceAddress);
 
+    /// The device with the given address was detached, for the given reason.
+    ///
+    /// This is what the host calls when a device is removed. The default implementation ignores the `reason`, and calls
+    /// [`detached`](Driver::detached). Drivers that want to react differently to an unplugged device and one that is
+    /// removed due to an error (e.g. by reconnecting automatically) can override it.
+    fn detached_with_reason(&mut self, dev_addr: DeviceAddress, _reason: DetachReason) {
+        self.detached(dev_addr);
+    }
+
     /// A descriptor was received for the device
     ///
     /// When a new device is attached, the device descriptor and all the configuration descriptors will
@@ -119,6 +221,32 @@ pub trait Driver<B: HostBus> {
     /// The driver should parse these descriptors to figure out if it can handle a given device or not.
     fn descriptor(&mut self, dev_addr: DeviceAddress, descriptor_type: u8, data: &[u8]);
 
+    /// A descriptor was received for the device, which belongs to the configuration and interface given by the `context`
+    ///
+    /// This is what the host calls during discovery. The default implementation ignores the `context`, and calls
+    /// [`descriptor`](Driver::descriptor). Drivers that need to associate endpoint or class specific descriptors with
+    /// their interface (and alternate setting) can override it, instead of relying on the order of the descriptors.
+    fn descriptor_with_context(&mut self, dev_addr: DeviceAddress, descriptor_type: u8, data: &[u8], _context: DescriptorContext) {
+        self.descriptor(dev_addr, descriptor_type, data);
+    }
+
+    /// Whether the driver needs to see further configuration descriptors of the device
+    ///
+    /// Called during discovery, before each configuration descriptor is requested (i.e. after the device descriptor, and
+    /// after each configuration). Once none of the drivers is [`Undecided`](DiscoveryInterest::Undecided), the remaining
+    /// configuration descriptors are not read, which shortens discovery of devices with many (or large) configurations,
+    /// especially on low speed links. The descriptor hash (see [`DeviceSummary`](crate::DeviceSummary)) and the
+    /// [configuration storage](UsbHost::set_configuration_storage) then only cover the configurations that were read.
+    ///
+    /// It is asked once more after the last configuration, when a driver can still ask for a
+    /// [report descriptor](DiscoveryInterest::ReportDescriptor). Once the driver has received the report descriptor, it must
+    /// not ask for it again.
+    ///
+    /// The default implementation returns `Undecided`, so that all descriptors are read.
+    fn discovery_interest(&mut self, _dev_addr: DeviceAddress) -> DiscoveryInterest {
+        DiscoveryInterest::Undecided
+    }
+
     /// The host is asking the driver to configure the device.
     ///
     /// If the driver can handle one of the configurations of the device (based on the descriptor),
@@ -132,7 +260,41 @@ pub trait Driver<B: HostBus> {
     /// Informs the driver that a given configuration was selected for this device.
     ///
     /// Here the driver can set up pipes for the device's endpoints.
-    fn configured(&mut self, dev_addr: DeviceAddress, value: u8, host: &mut UsbHost<B>);
+    fn configured(
+        &mut self,
+        dev_addr: DeviceAddress,
+        value: u8,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    );
+
+    /// Declares which interfaces of the selected configuration the driver wants to handle
+    ///
+    /// This is called on each of the drivers (in order), after the configuration was set, and before
+    /// [`configured_interfaces`](Driver::configured_interfaces). It is called regardless of which driver chose the
+    /// configuration, so that several drivers can handle different interfaces of a composite device.
+    ///
+    /// An interface that was already claimed by a driver earlier in the list is not granted again.
+    ///
+    /// The default implementation claims nothing.
+    fn claim_interfaces(&mut self, _dev_addr: DeviceAddress, _value: u8) -> InterfaceSet {
+        InterfaceSet::EMPTY
+    }
+
+    /// Informs the driver that a given configuration was selected, and which of the claimed interfaces it was granted
+    ///
+    /// `interfaces` is the part of the set returned from [`claim_interfaces`](Driver::claim_interfaces), that was not
+    /// claimed by another driver before. Drivers that claim interfaces should only set up pipes for those.
+    ///
+    /// The default implementation calls [`configured`](Driver::configured).
+    fn configured_interfaces(
+        &mut self,
+        dev_addr: DeviceAddress,
+        value: u8,
+        _interfaces: InterfaceSet,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) {
+        self.configured(dev_addr, value, host);
+    }
 
     /// Called when a control transfer was completed on the given pipe
     ///
@@ -140,11 +302,201 @@ pub trait Driver<B: HostBus> {
     fn completed_control(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, data: Option<&[u8]>);
 
     /// Called when data was received on the given IN pipe
+    ///
+    /// For interrupt pipes this is called whenever the device sent new data, for bulk pipes when a
+    /// transfer initiated with [`UsbHost::bulk_in`] has completed.
     fn completed_in(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, data: &[u8]);
 
     /// Called when new data is needed for the given OUT pipe
     fn completed_out(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, data: &mut [u8]);
 
+    /// Called when a bulk OUT transfer, initiated with [`UsbHost::bulk_out`], has completed on the given pipe
+    fn completed_bulk_out(&mut self, _dev_addr: DeviceAddress, _pipe_id: PipeId) {}
+
     /// Called when a device sends a STALL
     fn stall(&mut self, _dev_addr: DeviceAddress) {}
+
+    /// Called when a transfer initiated on the given pipe failed
+    ///
+    /// See [`TransferError`] for possible reasons. For stalled transfers this is called in addition to [`Driver::stall`].
+    fn transfer_failed(&mut self, _dev_addr: DeviceAddress, _pipe_id: PipeId, _error: TransferError) {}
+
+    /// Called when the recovery of an endpoint, started with [`UsbHost::recover_endpoint`], has finished
+    ///
+    /// `endpoint` is the endpoint address, including the direction bit. On success, the halt condition was cleared and the
+    /// data toggle of the pipes on that endpoint was reset to DATA0: the driver should now resynchronize with the device
+    /// (e.g. reset the state of its class protocol), before starting new transfers. Otherwise `result` holds the reason
+    /// why the halt condition could not be cleared.
+    fn endpoint_recovered(&mut self, _dev_addr: DeviceAddress, _endpoint: u8, _result: Result<(), TransferError>) {}
+
+    /// The bus was suspended by the application (see [`UsbHost::suspend`])
+    ///
+    /// No transfers can be started until the bus is resumed. Devices enter suspend mode shortly afterwards.
+    fn suspended(&mut self) {}
+
+    /// The bus was resumed, either by the application (see [`UsbHost::resume`]) or by a device signalling remote wakeup
+    ///
+    /// Transfers can be started again from now on.
+    fn resumed(&mut self) {}
+
+    /// Called for every start-of-frame event, with the current frame number (11 bits)
+    ///
+    /// Start-of-frame events are only generated while the host needs them itself (e.g. during enumeration), unless
+    /// enabled with [`UsbHost::set_sof_events`]. If the host bus does not report frame numbers
+    /// (see [`HostBus::frame_number`]), the number is derived from [`UsbHost::frame_count`] instead.
+    fn sof(&mut self, _frame_number: u16) {}
+}
+
+/// Drivers that report events to the application
+///
+/// Implemented by all bundled drivers that generate events, so that generic application code can drain the events of
+/// several drivers after each call to [`UsbHost::poll`]:
+///
+/// ```ignore
+/// fn handle_events<D: HasEvents>(driver: &mut D) where D::Event: defmt::Format {
+///     driver.drain_events(|event| defmt::info!("{}", event));
+/// }
+/// ```
+///
+/// The bundled drivers keep their events in an [`EventQueue`] of [`EVENT_QUEUE_DEPTH`] entries. Once it is full, every
+/// new event drops the oldest one, so events that are not taken after each `poll` may be lost.
+pub trait HasEvents {
+    type Event;
+
+    /// Returns the oldest pending event (if any), and removes it
+    fn take_event(&mut self) -> Option<Self::Event>;
+
+    /// Returns `true` if there are events that were not taken yet
+    fn events_pending(&self) -> bool;
+
+    /// Take all pending events, passing each of them to `f` in order
+    fn drain_events(&mut self, mut f: impl FnMut(Self::Event))
+    where
+        Self: Sized,
+    {
+        while let Some(event) = self.take_event() {
+            f(event);
+        }
+    }
+}
+
+/// Number of events that the bundled drivers keep, until they are taken by the application
+pub const EVENT_QUEUE_DEPTH: usize = 4;
+
+/// Fixed-size FIFO of driver events
+///
+/// Drivers push events from within their callbacks, and the application takes them in order via the driver's `take_event`
+/// method, after calling [`UsbHost::poll`]. A single `poll` can produce several events (e.g. a device being added, followed
+/// by its first report), which a single slot could not hold.
+///
+/// When the queue is full, the oldest event is dropped, so that the most recent state is never lost.
+#[derive(Debug)]
+pub struct EventQueue<T, const N: usize> {
+    events: [Option<T>; N],
+    /// Index of the oldest event
+    head: usize,
+    len: usize,
+}
+
+impl<T: Copy, const N: usize> EventQueue<T, N> {
+    pub const fn new() -> Self {
+        EventQueue { events: [None; N], head: 0, len: 0 }
+    }
+
+    /// Append an event. Returns `false` if the queue was full, and the oldest event was dropped to make room.
+    pub fn push(&mut self, event: T) -> bool {
+        if N == 0 {
+            return false;
+        }
+        let dropped = self.len == N;
+        if dropped {
+            self.head = (self.head + 1) % N;
+            self.len -= 1;
+        }
+        self.events[(self.head + self.len) % N] = Some(event);
+        self.len += 1;
+        !dropped
+    }
+
+    /// Remove and return the oldest event
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        let event = self.events[self.head].take();
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        event
+    }
+
+    /// Number of events in the queue
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Drop all events
+    pub fn clear(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+impl<T: Copy, const N: usize> Default for EventQueue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_queue() {
+        let mut queue: EventQueue<u8, 3> = EventQueue::new();
+        assert!(queue.push(1));
+        assert!(queue.push(2));
+        assert_eq!(queue.pop(), Some(1));
+        assert!(queue.push(3));
+        assert!(queue.push(4));
+        // full: the oldest event is dropped
+        assert!(!queue.push(5));
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue.pop(), Some(3));
+        assert_eq!(queue.pop(), Some(4));
+        assert_eq!(queue.pop(), Some(5));
+        assert!(queue.pop().is_none() && queue.is_empty());
+    }
+
+    #[test]
+    fn test_drain_events() {
+        struct Events(EventQueue<u8, 4>);
+        impl HasEvents for Events {
+            type Event = u8;
+
+            fn take_event(&mut self) -> Option<u8> {
+                self.0.pop()
+            }
+
+            fn events_pending(&self) -> bool {
+                !self.0.is_empty()
+            }
+        }
+
+        let mut events = Events(EventQueue::new());
+        events.0.push(1);
+        events.0.push(2);
+        assert!(events.events_pending());
+        let mut drained = [0; 2];
+        let mut count = 0;
+        events.drain_events(|event| {
+            drained[count] = event;
+            count += 1;
+        });
+        assert_eq!(drained, [1, 2]);
+        assert!(!events.events_pending());
+    }
 }
@@ -0,0 +1,1213 @@
+//! USB audio class (UAC1) devices
+//!
+//! The [`AudioDriver`] streams PCM audio from a microphone, or to a speaker. When a device is attached, the driver
+//! looks for an AudioStreaming interface with an alternate setting that supports the requested [`AudioFormat`]
+//! (e.g. [`AudioFormat::MONO_48KHZ_16BIT`]), selects that setting and the sample rate, and then transfers one
+//! isochronous packet per frame between the device and a [`RingBuffer`] provided by the application.
+//!
+//! The class-specific descriptors of the AudioControl and AudioStreaming interfaces can also be parsed
+//! on their own, via [`parse_control_descriptor`] and [`parse_streaming_descriptor`].
+//!
+//! Since transfers cannot be initiated from within driver callbacks, the application must call [`AudioDriver::poll`]
+//! after every call to [`UsbHost::poll`], at least once per frame.
+//!
+//! Example:
+//! ```ignore
+//! static mut SAMPLES: [u8; 1024] = [0; 1024];
+//!
+//! let mut mic = AudioDriver::microphone(AudioFormat::MONO_48KHZ_16BIT, unsafe { &mut SAMPLES });
+//!
+//! loop {
+//!     usb_host.poll(&mut [&mut mic]);
+//!     mic.poll(&mut usb_host).ok();
+//!
+//!     let mut samples = [0; 96];
+//!     let length = mic.ring().read(&mut samples);
+//!     process(&samples[..length]);
+//! }
+//! ```
+//!
+//! Limitations:
+//! - Only a single device, and a single direction per driver instance is supported
+//! - Only format type I PCM data is supported, at full speed
+//! - Implicit feedback is not used: without a feedback endpoint, OUT packets are sized according to the nominal sample rate
+//! - Volume, mute and other controls of the AudioControl interface are not used
+//!
+//! ## Feedback endpoints
+//!
+//! Speakers with an asynchronous clock consume samples at a rate that is determined by their own clock,
+//! not by the host's. To prevent buffer under- or overruns on the device, they provide an isochronous IN
+//! *feedback endpoint*, through which they report the number of samples they actually consume per frame.
+//!
+//! For full-speed devices the feedback value is a 3-byte, little endian, unsigned 10.14 fixed point number
+//! (10 bits of integer part, 14 bits of fraction), expressed in samples per frame (1 ms).
+//!
+//! The host must use this value to determine how many samples to put into each isochronous OUT packet.
+//! Since the value is usually not an integer, the fractional part must be accumulated across frames,
+//! so that over time the number of samples sent matches the reported rate exactly.
+//!
+//! [`FeedbackPacer`] implements this logic: feed it the data received on the feedback endpoint via
+//! [`FeedbackPacer::update`], and ask it for the size of each outgoing packet via [`FeedbackPacer::next_packet_size`].
+//!
+//! The [`AudioDriver`] does this on its own, if the chosen alternate setting of a speaker has a feedback endpoint.
+//! It is read every 2^`bRefresh` frames, in a frame in which the OUT packet was sent already.
+
+use super::{detector::SingleDevice, Driver, EventQueue, HasEvents, EVENT_QUEUE_DEPTH};
+use crate::bus::HostBus;
+use crate::descriptor::{self, UsageType};
+use crate::types::{ConnectionSpeed, DeviceAddress, SetupPacket, TransferType};
+use crate::{ControlError, PipeId, TransferError, UsbHost};
+use usb_device::control::{Recipient, RequestType};
+use usb_device::UsbDirection;
+
+/// Interface class code for audio devices
+const CLASS_AUDIO: u8 = 0x01;
+const SUBCLASS_AUDIO_STREAMING: u8 = 0x02;
+
+/// Descriptor type of class-specific interface descriptors
+pub const TYPE_CS_INTERFACE: u8 = 0x24;
+/// Descriptor type of class-specific endpoint descriptors
+pub const TYPE_CS_ENDPOINT: u8 = 0x25;
+
+const AC_HEADER: u8 = 0x01;
+const AC_INPUT_TERMINAL: u8 = 0x02;
+const AC_OUTPUT_TERMINAL: u8 = 0x03;
+
+const AS_GENERAL: u8 = 0x01;
+const AS_FORMAT_TYPE: u8 = 0x02;
+const EP_GENERAL: u8 = 0x01;
+
+const FORMAT_TYPE_I: u8 = 0x01;
+/// `wFormatTag` of PCM data
+pub const FORMAT_TAG_PCM: u16 = 0x0001;
+
+/// Terminal type of the USB streaming endpoint
+pub const TERMINAL_USB_STREAMING: u16 = 0x0101;
+/// Terminal type of a microphone
+pub const TERMINAL_MICROPHONE: u16 = 0x0201;
+/// Terminal type of a speaker
+pub const TERMINAL_SPEAKER: u16 = 0x0301;
+/// Terminal type of headphones
+pub const TERMINAL_HEADPHONES: u16 = 0x0302;
+
+const REQUEST_SET_CUR: u8 = 0x01;
+const SAMPLING_FREQ_CONTROL: u8 = 0x01;
+
+/// Maximum number of AudioStreaming interfaces retained from an AudioControl header
+pub const MAX_STREAMING_INTERFACES: usize = 4;
+
+/// Maximum number of discrete sample rates retained from a format type descriptor
+pub const MAX_SAMPLE_RATES: usize = 8;
+
+/// Maximum packet size of the isochronous endpoints used by the [`AudioDriver`]
+///
+/// Alternate settings with larger packets are ignored. 512 bytes fit 48 kHz stereo with 24-bit samples.
+pub const MAX_PACKET_SIZE: usize = 512;
+
+/// Number of fractional bits in a full-speed feedback value
+const FRACTION_BITS: u32 = 14;
+
+/// Largest refresh period of a feedback endpoint (as a power of two, in frames), allowed by the UAC1 specification
+const MAX_REFRESH: u8 = 9;
+
+/// Maximum deviation of a feedback value from the nominal rate, in parts of the nominal rate (1/8 = 12.5%)
+///
+/// Values further away are considered invalid and ignored.
+const MAX_DEVIATION_SHIFT: u32 = 3;
+
+/// Samples per frame, in 10.14 fixed point format
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FeedbackValue(u32);
+
+impl FeedbackValue {
+    /// Compute the nominal feedback value for the given sample rate (in Hz), rounded to the nearest value
+    pub fn from_sample_rate(sample_rate: u32) -> Self {
+        FeedbackValue(((((sample_rate as u64) << FRACTION_BITS) + 500) / 1000) as u32)
+    }
+
+    /// Decode a feedback value, as received from a full-speed feedback endpoint
+    ///
+    /// Returns `None` if fewer than 3 bytes were received.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        match data {
+            [b0, b1, b2, ..] => Some(FeedbackValue(u32::from_le_bytes([*b0, *b1, *b2, 0]))),
+            _ => None,
+        }
+    }
+
+    /// Raw 10.14 fixed point value
+    pub fn raw(&self) -> u32 {
+        self.0
+    }
+
+    /// Whole number of samples per frame
+    pub fn samples(&self) -> u32 {
+        self.0 >> FRACTION_BITS
+    }
+
+    /// The sample rate (in Hz) this value corresponds to, rounded down
+    pub fn sample_rate(&self) -> u32 {
+        ((self.0 as u64 * 1000) >> FRACTION_BITS) as u32
+    }
+}
+
+/// Computes isochronous OUT packet sizes, steered by a feedback endpoint
+///
+/// See [module-level documentation](crate::driver::audio) for details.
+#[derive(Debug)]
+pub struct FeedbackPacer {
+    nominal: FeedbackValue,
+    current: FeedbackValue,
+    accumulator: u32,
+    bytes_per_sample: u16,
+    max_samples: u32,
+}
+
+impl FeedbackPacer {
+    /// Create a pacer for the given nominal `sample_rate` (in Hz)
+    ///
+    /// The `bytes_per_sample` is the size of one audio frame, i.e. `channels * subframe size`.
+    ///
+    /// `max_packet_size` is the `wMaxPacketSize` of the isochronous OUT endpoint. Packet sizes returned by
+    /// [`next_packet_size`](FeedbackPacer::next_packet_size) never exceed it.
+    pub fn new(sample_rate: u32, bytes_per_sample: u16, max_packet_size: u16) -> Self {
+        let nominal = FeedbackValue::from_sample_rate(sample_rate);
+        Self {
+            nominal,
+            current: nominal,
+            accumulator: 0,
+            bytes_per_sample,
+            max_samples: (max_packet_size / bytes_per_sample.max(1)) as u32,
+        }
+    }
+
+    /// Currently active feedback value
+    pub fn current(&self) -> FeedbackValue {
+        self.current
+    }
+
+    /// Process data received on the feedback endpoint
+    ///
+    /// Returns `true` if the value was accepted. Values that cannot be parsed, or deviate from the nominal
+    /// rate by more than 12.5% are ignored (some devices report garbage before their clock has settled).
+    pub fn update(&mut self, data: &[u8]) -> bool {
+        match FeedbackValue::parse(data) {
+            Some(value) if value.0.abs_diff(self.nominal.0) <= self.nominal.0 >> MAX_DEVIATION_SHIFT => {
+                self.current = value;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Return to the nominal rate, and drop any accumulated fraction
+    ///
+    /// This should be called whenever streaming is (re-)started.
+    pub fn reset(&mut self) {
+        self.current = self.nominal;
+        self.accumulator = 0;
+    }
+
+    /// Number of samples to send in the next frame
+    pub fn next_packet_samples(&mut self) -> u32 {
+        self.accumulator += self.current.0;
+        let samples = (self.accumulator >> FRACTION_BITS).min(self.max_samples);
+        self.accumulator -= samples << FRACTION_BITS;
+        samples
+    }
+
+    /// Size in bytes of the packet to send in the next frame
+    pub fn next_packet_size(&mut self) -> usize {
+        self.next_packet_samples() as usize * self.bytes_per_sample as usize
+    }
+}
+
+/// Sample format requested from the device
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AudioFormat {
+    /// Number of channels
+    pub channels: u8,
+    /// Number of significant bits per sample
+    pub bit_resolution: u8,
+    /// Sample rate in Hz
+    pub sample_rate: u32,
+}
+
+impl AudioFormat {
+    /// Mono, 16-bit samples at 48 kHz
+    pub const MONO_48KHZ_16BIT: AudioFormat = AudioFormat::new(1, 16, 48000);
+
+    pub const fn new(channels: u8, bit_resolution: u8, sample_rate: u32) -> Self {
+        Self { channels, bit_resolution, sample_rate }
+    }
+}
+
+/// Header of the class-specific AudioControl interface descriptors
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AudioControlHeader {
+    /// Audio class release number, in BCD (`0x0100` for UAC1)
+    pub audio_class: u16,
+    /// Combined length of the class-specific AudioControl descriptors, including this one
+    pub total_length: u16,
+    interfaces: [u8; MAX_STREAMING_INTERFACES],
+    interface_count: u8,
+}
+
+impl AudioControlHeader {
+    /// Numbers of the AudioStreaming (and MIDIStreaming) interfaces belonging to this function
+    ///
+    /// At most [`MAX_STREAMING_INTERFACES`] are retained.
+    pub fn streaming_interfaces(&self) -> &[u8] {
+        &self.interfaces[..self.interface_count as usize]
+    }
+}
+
+/// Input terminal, i.e. where audio enters the function (e.g. a microphone, or the USB streaming endpoint of a speaker)
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct InputTerminal {
+    pub terminal_id: u8,
+    /// Terminal type, e.g. [`TERMINAL_MICROPHONE`]
+    pub terminal_type: u16,
+    pub channels: u8,
+    /// Spatial locations of the channels (`wChannelConfig`)
+    pub channel_config: u16,
+}
+
+/// Output terminal, i.e. where audio leaves the function (e.g. a speaker, or the USB streaming endpoint of a microphone)
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct OutputTerminal {
+    pub terminal_id: u8,
+    /// Terminal type, e.g. [`TERMINAL_SPEAKER`]
+    pub terminal_type: u16,
+    /// ID of the unit or terminal this terminal is connected to
+    pub source_id: u8,
+}
+
+/// Class-specific AudioControl interface descriptor
+///
+/// Units (mixer, selector, feature units, ...) are not decoded.
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ControlDescriptor {
+    Header(AudioControlHeader),
+    InputTerminal(InputTerminal),
+    OutputTerminal(OutputTerminal),
+}
+
+/// General information about an AudioStreaming interface (AS_GENERAL)
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StreamingGeneral {
+    /// ID of the terminal the interface's endpoint is connected to
+    pub terminal_link: u8,
+    /// Delay introduced by the data path, in frames
+    pub delay: u8,
+    /// Format of the audio data, e.g. [`FORMAT_TAG_PCM`]
+    pub format_tag: u16,
+}
+
+/// Sample rates supported by an alternate setting
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SampleRates {
+    /// Any rate within the given range (in Hz, inclusive)
+    Continuous { min: u32, max: u32 },
+    /// A list of rates (in Hz). At most [`MAX_SAMPLE_RATES`] are retained.
+    Discrete { rates: [u32; MAX_SAMPLE_RATES], count: u8 },
+}
+
+impl SampleRates {
+    /// Returns `true` if the given rate (in Hz) is supported
+    pub fn contains(&self, rate: u32) -> bool {
+        match self {
+            SampleRates::Continuous { min, max } => (*min..=*max).contains(&rate),
+            SampleRates::Discrete { rates, count } => rates[..*count as usize].contains(&rate),
+        }
+    }
+}
+
+/// Format type I descriptor, describing PCM-like formats with a fixed number of bytes per sample
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FormatTypeI {
+    pub channels: u8,
+    /// Number of bytes occupied by one sample of one channel
+    pub subframe_size: u8,
+    /// Number of significant bits per sample
+    pub bit_resolution: u8,
+    pub sample_rates: SampleRates,
+}
+
+impl FormatTypeI {
+    /// Returns `true` if data in the given format can be streamed with this format
+    pub fn supports(&self, format: &AudioFormat) -> bool {
+        self.channels == format.channels
+            && self.bit_resolution == format.bit_resolution
+            && self.sample_rates.contains(format.sample_rate)
+    }
+
+    /// Size of one audio frame (one sample of each channel) in bytes
+    pub fn bytes_per_frame(&self) -> u16 {
+        self.channels as u16 * self.subframe_size as u16
+    }
+}
+
+/// Class-specific AudioStreaming interface descriptor
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum StreamingDescriptor {
+    General(StreamingGeneral),
+    FormatTypeI(FormatTypeI),
+}
+
+/// Parse a class-specific descriptor ([`TYPE_CS_INTERFACE`]) of an AudioControl interface
+///
+/// The `data` starts with the descriptor subtype, i.e. it excludes the length & type. Returns `None` for
+/// descriptors that are truncated or not supported.
+pub fn parse_control_descriptor(data: &[u8]) -> Option<ControlDescriptor> {
+    match data {
+        [AC_HEADER, adc0, adc1, len0, len1, count, interfaces @ ..] => {
+            let interfaces = &interfaces[..(*count as usize).min(interfaces.len()).min(MAX_STREAMING_INTERFACES)];
+            let mut header = AudioControlHeader {
+                audio_class: u16::from_le_bytes([*adc0, *adc1]),
+                total_length: u16::from_le_bytes([*len0, *len1]),
+                interfaces: [0; MAX_STREAMING_INTERFACES],
+                interface_count: interfaces.len() as u8,
+            };
+            header.interfaces[..interfaces.len()].copy_from_slice(interfaces);
+            Some(ControlDescriptor::Header(header))
+        }
+        [AC_INPUT_TERMINAL, id, type0, type1, _assoc, channels, config0, config1, ..] => {
+            Some(ControlDescriptor::InputTerminal(InputTerminal {
+                terminal_id: *id,
+                terminal_type: u16::from_le_bytes([*type0, *type1]),
+                channels: *channels,
+                channel_config: u16::from_le_bytes([*config0, *config1]),
+            }))
+        }
+        [AC_OUTPUT_TERMINAL, id, type0, type1, _assoc, source, ..] => Some(ControlDescriptor::OutputTerminal(OutputTerminal {
+            terminal_id: *id,
+            terminal_type: u16::from_le_bytes([*type0, *type1]),
+            source_id: *source,
+        })),
+        _ => None,
+    }
+}
+
+/// Parse a class-specific descriptor ([`TYPE_CS_INTERFACE`]) of an AudioStreaming interface
+///
+/// The `data` starts with the descriptor subtype, i.e. it excludes the length & type. Returns `None` for
+/// descriptors that are truncated or not supported (only format type I is).
+pub fn parse_streaming_descriptor(data: &[u8]) -> Option<StreamingDescriptor> {
+    match data {
+        [AS_GENERAL, link, delay, tag0, tag1, ..] => Some(StreamingDescriptor::General(StreamingGeneral {
+            terminal_link: *link,
+            delay: *delay,
+            format_tag: u16::from_le_bytes([*tag0, *tag1]),
+        })),
+        [AS_FORMAT_TYPE, FORMAT_TYPE_I, channels, subframe_size, bit_resolution, frequency_type, frequencies @ ..] => {
+            // sample rates are 3 bytes each
+            let mut frequencies = frequencies.chunks_exact(3).map(|f| u32::from_le_bytes([f[0], f[1], f[2], 0]));
+            let sample_rates = match frequency_type {
+                0 => SampleRates::Continuous { min: frequencies.next()?, max: frequencies.next()? },
+                count => {
+                    let mut rates = [0; MAX_SAMPLE_RATES];
+                    let mut stored = 0;
+                    for (rate, frequency) in rates.iter_mut().zip(frequencies.take(*count as usize)) {
+                        *rate = frequency;
+                        stored += 1;
+                    }
+                    SampleRates::Discrete { rates, count: stored }
+                }
+            };
+            Some(StreamingDescriptor::FormatTypeI(FormatTypeI {
+                channels: *channels,
+                subframe_size: *subframe_size,
+                bit_resolution: *bit_resolution,
+                sample_rates,
+            }))
+        }
+        _ => None,
+    }
+}
+
+/// Ring buffer for audio data, backed by memory provided by the application
+///
+/// For microphones the driver writes received data to it, for speakers it reads the data to send from it.
+#[derive(Debug)]
+pub struct RingBuffer<'a> {
+    buffer: &'a mut [u8],
+    start: usize,
+    len: usize,
+}
+
+impl<'a> RingBuffer<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        Self { buffer, start: 0, len: 0 }
+    }
+
+    /// Number of bytes in the buffer
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of bytes that can be written before the buffer is full
+    pub fn free(&self) -> usize {
+        self.buffer.len() - self.len
+    }
+
+    /// Discard all data
+    pub fn clear(&mut self) {
+        self.start = 0;
+        self.len = 0;
+    }
+
+    /// Append as much of `data` as fits, returning the number of bytes written
+    pub fn write(&mut self, data: &[u8]) -> usize {
+        let count = data.len().min(self.free());
+        if count == 0 {
+            return 0;
+        }
+        let end = (self.start + self.len) % self.buffer.len();
+        let first = count.min(self.buffer.len() - end);
+        self.buffer[end..end + first].copy_from_slice(&data[..first]);
+        self.buffer[..count - first].copy_from_slice(&data[first..count]);
+        self.len += count;
+        count
+    }
+
+    /// Remove up to `out.len()` bytes from the buffer, returning the number of bytes read
+    pub fn read(&mut self, out: &mut [u8]) -> usize {
+        let count = out.len().min(self.len);
+        if count == 0 {
+            return 0;
+        }
+        let first = count.min(self.buffer.len() - self.start);
+        out[..first].copy_from_slice(&self.buffer[self.start..self.start + first]);
+        out[first..count].copy_from_slice(&self.buffer[..count - first]);
+        self.start = (self.start + count) % self.buffer.len();
+        self.len -= count;
+        count
+    }
+}
+
+/// Events generated by the [`AudioDriver`]
+#[derive(Copy, Clone, Debug)]
+pub enum AudioEvent {
+    /// A device with a matching alternate setting was detected & configured
+    DeviceAdded(DeviceAddress),
+
+    /// The device was removed
+    DeviceRemoved(DeviceAddress),
+
+    /// The alternate setting and sample rate were selected, streaming has started
+    StreamStarted(DeviceAddress),
+
+    /// Selecting the alternate setting or the sample rate failed. The device is not used.
+    SetupFailed(DeviceAddress),
+}
+
+/// Alternate setting of an AudioStreaming interface, as found in the descriptors
+#[derive(Copy, Clone, Debug)]
+struct StreamingSetting {
+    interface: u8,
+    alternate: u8,
+    /// Set if the AS_GENERAL descriptor declares PCM data
+    pcm: bool,
+    /// Size of one audio frame, set if the format type descriptor supports the requested format
+    bytes_per_frame: Option<u16>,
+    /// Endpoint number & max packet size of the isochronous data endpoint
+    endpoint: Option<(u8, u16)>,
+    /// Endpoint number, max packet size & refresh period (as a power of two, in frames) of the feedback endpoint
+    feedback: Option<(u8, u16, u8)>,
+    /// Set if the endpoint supports setting the sample rate
+    sample_rate_control: bool,
+}
+
+impl StreamingSetting {
+    fn usable(&self) -> bool {
+        self.pcm && self.bytes_per_frame.is_some() && self.endpoint.is_some()
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Stream {
+    /// Waiting for the given number of control requests to complete
+    Setup(u8),
+    Streaming,
+    Failed,
+}
+
+/// Descriptors of a newly attached device, as far as they were inspected
+#[derive(Copy, Clone, Default, Debug)]
+struct Pending {
+    /// Value of the configuration currently being inspected
+    config: Option<u8>,
+    /// AudioStreaming alternate setting currently being inspected
+    setting: Option<StreamingSetting>,
+    /// Configuration value and the first usable alternate setting
+    chosen: Option<(u8, StreamingSetting)>,
+}
+
+/// The device was configured, pipes are open
+#[derive(Copy, Clone, Debug)]
+struct Configured {
+    control_pipe: PipeId,
+    pipe: PipeId,
+    max_packet_size: u16,
+    /// Pipe, max packet size & refresh period of the feedback endpoint, if any
+    feedback: Option<(PipeId, u16, u8)>,
+    stream: Stream,
+}
+
+/// Driver for USB audio class microphones and speakers
+///
+/// See [module-level documentation](crate::driver::audio) for details.
+#[derive(Debug)]
+pub struct AudioDriver {
+    direction: UsbDirection,
+    format: AudioFormat,
+    state: SingleDevice<Pending, Configured>,
+    ring: RingBuffer<'static>,
+    pacer: Option<FeedbackPacer>,
+    /// Packet prepared for the next isochronous OUT transfer, and its length
+    packet: [u8; MAX_PACKET_SIZE],
+    packet_len: Option<usize>,
+    in_flight: bool,
+    /// Frame count at which the last packet was transferred
+    last_frame: Option<u32>,
+    /// Frame count at which the feedback endpoint was last read
+    feedback_frame: Option<u32>,
+    events: EventQueue<AudioEvent, EVENT_QUEUE_DEPTH>,
+}
+
+impl AudioDriver {
+    /// Create a driver that receives audio in the given format from a microphone (or other source), into the given buffer
+    pub fn microphone(format: AudioFormat, buffer: &'static mut [u8]) -> Self {
+        Self::new(UsbDirection::In, format, buffer)
+    }
+
+    /// Create a driver that sends audio in the given format from the given buffer to a speaker (or other sink)
+    pub fn speaker(format: AudioFormat, buffer: &'static mut [u8]) -> Self {
+        Self::new(UsbDirection::Out, format, buffer)
+    }
+
+    fn new(direction: UsbDirection, format: AudioFormat, buffer: &'static mut [u8]) -> Self {
+        Self {
+            direction,
+            format,
+            state: SingleDevice::Idle,
+            ring: RingBuffer::new(buffer),
+            pacer: None,
+            packet: [0; MAX_PACKET_SIZE],
+            packet_len: None,
+            in_flight: false,
+            last_frame: None,
+            feedback_frame: None,
+            events: EventQueue::new(),
+        }
+    }
+
+    /// Returns the oldest pending event (if any), and removes it from the queue.
+    ///
+    /// This method should be called repeatedly after calling `usb_host.poll(...)` and `audio.poll(...)`, until it returns `None` (see
+    /// [`HasEvents`] for what happens otherwise).
+    pub fn take_event(&mut self) -> Option<AudioEvent> {
+        self.events.pop()
+    }
+
+    /// Address of the device, if it is currently configured
+    pub fn device_address(&self) -> Option<DeviceAddress> {
+        self.state.address()
+    }
+
+    /// Returns `true` while audio is being streamed
+    pub fn streaming(&self) -> bool {
+        matches!(self.state, SingleDevice::Configured(_, Configured { stream: Stream::Streaming, .. }))
+    }
+
+    /// The ring buffer holding the audio data
+    ///
+    /// For microphones, read the received data from it. Data that does not fit into the buffer is dropped.
+    /// For speakers, write the data to send to it. When it runs empty, silence is sent.
+    pub fn ring(&mut self) -> &mut RingBuffer<'static> {
+        &mut self.ring
+    }
+
+    /// Transfer the next isochronous packet, if one is due
+    ///
+    /// Must be called after every call to `usb_host.poll(...)`. At most one packet is transferred per frame, followed
+    /// by a read of the feedback endpoint (if any) when it is due.
+    /// If the bus is currently busy, nothing happens, and the transfer is initiated on a later call.
+    pub fn poll<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    >(
+        &mut self,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) -> Result<(), ControlError> {
+        let SingleDevice::Configured(_, Configured { pipe, max_packet_size, feedback, stream: Stream::Streaming, .. }) = self.state else {
+            return Ok(());
+        };
+        let now = host.frame_count();
+        if self.in_flight {
+            return Ok(());
+        }
+        if self.last_frame == Some(now) {
+            let Some((feedback_pipe, feedback_size, refresh)) = feedback else {
+                return Ok(());
+            };
+            if self.feedback_frame.is_some_and(|frame| now.wrapping_sub(frame) < 1 << refresh) {
+                return Ok(());
+            }
+            return match host.isochronous_in(feedback_pipe, feedback_size) {
+                Ok(()) => {
+                    self.in_flight = true;
+                    self.feedback_frame = Some(now);
+                    Ok(())
+                }
+                Err(ControlError::WouldBlock) => Ok(()),
+                Err(e) => Err(e),
+            };
+        }
+        let result = match self.direction {
+            UsbDirection::In => host.isochronous_in(pipe, max_packet_size),
+            UsbDirection::Out => {
+                // the packet is kept until it was sent, in case the bus is busy
+                let length = match self.packet_len {
+                    Some(length) => length,
+                    None => {
+                        let length = self.pacer.as_mut().map_or(0, FeedbackPacer::next_packet_size).min(max_packet_size as usize);
+                        let read = self.ring.read(&mut self.packet[..length]);
+                        // fill up with silence
+                        self.packet[read..length].fill(0);
+                        self.packet_len = Some(length);
+                        length
+                    }
+                };
+                host.isochronous_out(pipe, &self.packet[..length])
+            }
+        };
+        match result {
+            Ok(()) => {
+                self.in_flight = true;
+                self.last_frame = Some(now);
+                Ok(())
+            }
+            Err(ControlError::WouldBlock) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Mark the isochronous transfer in progress as done, whether it succeeded or not
+    fn packet_done(&mut self) {
+        self.in_flight = false;
+        self.packet_len = None;
+    }
+
+    fn setup_failed(&mut self, dev_addr: DeviceAddress) {
+        if let SingleDevice::Configured(_, Configured { stream: stream @ Stream::Setup(_), .. }) = &mut self.state {
+            *stream = Stream::Failed;
+            self.events.push(AudioEvent::SetupFailed(dev_addr));
+        }
+    }
+
+    fn reset(&mut self) {
+        self.state = SingleDevice::Idle;
+        self.pacer = None;
+        self.packet_len = None;
+        self.in_flight = false;
+        self.last_frame = None;
+        self.feedback_frame = None;
+    }
+}
+
+/// Remember the given setting as the chosen one, if it is usable and none was chosen yet
+fn choose(chosen: &mut Option<(u8, StreamingSetting)>, config: Option<u8>, setting: Option<StreamingSetting>) {
+    if let (None, Some(config), Some(setting)) = (*chosen, config, setting) {
+        if setting.usable() {
+            *chosen = Some((config, setting));
+        }
+    }
+}
+
+impl HasEvents for AudioDriver {
+    type Event = AudioEvent;
+
+    fn take_event(&mut self) -> Option<AudioEvent> {
+        self.events.pop()
+    }
+
+    fn events_pending(&self) -> bool {
+        !self.events.is_empty()
+    }
+}
+
+impl<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    > Driver<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE> for AudioDriver
+{
+    fn attached(&mut self, dev_addr: DeviceAddress, _connection_speed: ConnectionSpeed) {
+        self.state.attached(dev_addr, Pending::default());
+    }
+
+    fn detached(&mut self, dev_addr: DeviceAddress) {
+        if self.state.detached(dev_addr) {
+            self.reset();
+            self.events.push(AudioEvent::DeviceRemoved(dev_addr));
+        }
+    }
+
+    fn descriptor(&mut self, dev_addr: DeviceAddress, descriptor_type: u8, data: &[u8]) {
+        let (direction, format) = (self.direction, self.format);
+        let Some(Pending { config, setting, chosen }) = self.state.pending(dev_addr) else {
+            return;
+        };
+        if chosen.is_some() {
+            return;
+        }
+        // each setting is complete once the next interface (or configuration) starts
+        match descriptor_type {
+            descriptor::TYPE_CONFIGURATION => {
+                choose(chosen, *config, setting.take());
+                if let Ok((_, configuration)) = descriptor::parse::configuration_descriptor(data) {
+                    *config = Some(configuration.value);
+                }
+            }
+            descriptor::TYPE_INTERFACE => {
+                choose(chosen, *config, setting.take());
+                if let Ok((_, descriptor)) = descriptor::parse::interface_descriptor(data) {
+                    // alternate setting 0 of an AudioStreaming interface has no endpoints
+                    if descriptor.interface_class == CLASS_AUDIO
+                        && descriptor.interface_sub_class == SUBCLASS_AUDIO_STREAMING
+                        && descriptor.is_alternate_setting()
+                    {
+                        *setting = Some(StreamingSetting {
+                            interface: descriptor.interface_number,
+                            alternate: descriptor.alternate_setting,
+                            pcm: false,
+                            bytes_per_frame: None,
+                            endpoint: None,
+                            feedback: None,
+                            sample_rate_control: false,
+                        });
+                    }
+                }
+            }
+            TYPE_CS_INTERFACE => {
+                let Some(setting) = setting else {
+                    return;
+                };
+                match parse_streaming_descriptor(data) {
+                    Some(StreamingDescriptor::General(general)) => setting.pcm = general.format_tag == FORMAT_TAG_PCM,
+                    Some(StreamingDescriptor::FormatTypeI(format_type)) => {
+                        setting.bytes_per_frame = format_type.supports(&format).then(|| format_type.bytes_per_frame());
+                    }
+                    None => {}
+                }
+            }
+            descriptor::TYPE_ENDPOINT => {
+                let Some(setting) = setting else {
+                    return;
+                };
+                if let Ok((_, endpoint)) = descriptor::parse::endpoint_descriptor(data) {
+                    if endpoint.attributes.transfer_type() != TransferType::Isochronous
+                        || !(1..=MAX_PACKET_SIZE).contains(&(endpoint.max_packet_size as usize))
+                    {
+                        return;
+                    }
+                    if matches!(endpoint.attributes.usage_type(), UsageType::Data | UsageType::ImplicitFeedbackData)
+                        && endpoint.address.direction() == direction
+                    {
+                        setting.endpoint = Some((endpoint.address.number(), endpoint.max_packet_size));
+                    } else if matches!(endpoint.attributes.usage_type(), UsageType::Data | UsageType::Feedback)
+                        && endpoint.address.direction() == UsbDirection::In
+                        && direction == UsbDirection::Out
+                    {
+                        // UAC1 predates usage types, so feedback endpoints of speakers are often marked as data endpoints.
+                        // Their bRefresh follows the standard fields, otherwise bInterval is used.
+                        let refresh = match data.get(5) {
+                            Some(refresh) if *refresh > 0 => *refresh,
+                            _ => endpoint.interval.saturating_sub(1),
+                        };
+                        setting.feedback = Some((endpoint.address.number(), endpoint.max_packet_size, refresh.min(MAX_REFRESH)));
+                    }
+                }
+            }
+            TYPE_CS_ENDPOINT => {
+                if let (Some(setting), [EP_GENERAL, attributes, ..]) = (setting, data) {
+                    if setting.endpoint.is_some() {
+                        setting.sample_rate_control = attributes & 0x01 != 0;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn configure(&mut self, dev_addr: DeviceAddress) -> Option<u8> {
+        self.state.configure(dev_addr, |pending| {
+            choose(&mut pending.chosen, pending.config, pending.setting.take());
+            pending.chosen.map(|(value, _)| value)
+        })
+    }
+
+    fn configured(
+        &mut self,
+        dev_addr: DeviceAddress,
+        value: u8,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) {
+        let Some(Pending { chosen, .. }) = self.state.take_pending(dev_addr) else {
+            return;
+        };
+        let Some((_, setting)) = chosen.filter(|(chosen_value, _)| *chosen_value == value) else {
+            return;
+        };
+        let (Some((ep_number, max_packet_size)), Some(bytes_per_frame)) = (setting.endpoint, setting.bytes_per_frame) else {
+            return;
+        };
+        let control_pipe = host.create_control_pipe(dev_addr);
+        let pipe = host.create_isochronous_pipe(dev_addr, ep_number, self.direction, max_packet_size);
+        let feedback_pipe = setting.feedback.map(|(number, size, _)| host.create_isochronous_pipe(dev_addr, number, UsbDirection::In, size));
+        let (Some(control_pipe), Some(pipe), None | Some(Some(_))) = (control_pipe, pipe, feedback_pipe) else {
+            // the host ran out of pipes: release the ones that were created, if any
+            for pipe in control_pipe.into_iter().chain(pipe).chain(feedback_pipe.flatten()) {
+                host.release_pipe(pipe);
+            }
+            return;
+        };
+        let feedback = setting.feedback.zip(feedback_pipe.flatten()).map(|((_, size, refresh), pipe)| (pipe, size, refresh));
+        self.pacer = Some(FeedbackPacer::new(self.format.sample_rate, bytes_per_frame, max_packet_size));
+        self.events.push(AudioEvent::DeviceAdded(dev_addr));
+
+        // the requests are queued, and complete in order
+        let mut requests = 1;
+        let mut result = host.set_interface(dev_addr, Some(control_pipe), setting.interface, setting.alternate);
+        if setting.sample_rate_control && result.is_ok() {
+            let rate = self.format.sample_rate.to_le_bytes();
+            result = host.control_out(
+                Some(dev_addr),
+                Some(control_pipe),
+                SetupPacket::new(
+                    UsbDirection::Out,
+                    RequestType::Class,
+                    Recipient::Endpoint,
+                    REQUEST_SET_CUR,
+                    (SAMPLING_FREQ_CONTROL as u16) << 8,
+                    (ep_number | self.direction as u8) as u16,
+                    3,
+                ),
+                &rate[..3],
+            );
+            requests += 1;
+        }
+        self.state = SingleDevice::Configured(dev_addr, Configured { control_pipe, pipe, max_packet_size, feedback, stream: Stream::Setup(requests) });
+        if result.is_err() {
+            self.setup_failed(dev_addr);
+        }
+    }
+
+    fn completed_control(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, _data: Option<&[u8]>) {
+        let Some(Configured { control_pipe, stream, .. }) = self.state.device_mut(dev_addr) else {
+            return;
+        };
+        if pipe_id != *control_pipe {
+            return;
+        }
+        if let Stream::Setup(pending) = stream {
+            *pending -= 1;
+            if *pending == 0 {
+                *stream = Stream::Streaming;
+                if let Some(pacer) = &mut self.pacer {
+                    pacer.reset();
+                }
+                self.feedback_frame = None;
+                self.events.push(AudioEvent::StreamStarted(dev_addr));
+            }
+        }
+    }
+
+    fn completed_in(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, data: &[u8]) {
+        let Some(&Configured { pipe, feedback, .. }) = self.state.device(dev_addr) else {
+            return;
+        };
+        if pipe_id == pipe {
+            self.ring.write(data);
+            self.packet_done();
+        } else if feedback.is_some_and(|(feedback_pipe, _, _)| pipe_id == feedback_pipe) {
+            if let Some(pacer) = &mut self.pacer {
+                pacer.update(data);
+            }
+            self.in_flight = false;
+        }
+    }
+
+    fn completed_bulk_out(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId) {
+        let Some(&Configured { pipe, .. }) = self.state.device(dev_addr) else {
+            return;
+        };
+        if pipe_id == pipe {
+            self.packet_done();
+        }
+    }
+
+    fn completed_out(&mut self, _dev_addr: DeviceAddress, _pipe_id: PipeId, _data: &mut [u8]) {
+        // ignored, since there are no interrupt OUT pipes in use.
+    }
+
+    fn stall(&mut self, dev_addr: DeviceAddress) {
+        if self.device_address() == Some(dev_addr) {
+            self.setup_failed(dev_addr);
+        }
+    }
+
+    fn transfer_failed(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, _error: TransferError) {
+        let Some(&Configured { control_pipe, pipe, feedback, .. }) = self.state.device(dev_addr) else {
+            return;
+        };
+        if pipe_id == control_pipe {
+            self.setup_failed(dev_addr);
+        } else if pipe_id == pipe {
+            // isochronous data is not retried, the packet is lost
+            self.packet_done();
+        } else if feedback.is_some_and(|(feedback_pipe, _, _)| pipe_id == feedback_pipe) {
+            // the previous feedback value remains in use
+            self.in_flight = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::mock::fixtures::DEVICE_DESCRIPTOR;
+    use crate::bus::mock::{ControlResponse, MockDevice, MockHostBus, Response};
+
+    #[test]
+    fn test_feedback_value() {
+        // 44.1 samples per frame are 722534.4 in 10.14 format
+        assert_eq!(FeedbackValue::from_sample_rate(44100).raw(), 722534);
+        let value = FeedbackValue::from_sample_rate(48000);
+        assert_eq!(value.raw(), 48 << 14);
+        assert_eq!(value.samples(), 48);
+        assert_eq!(value.sample_rate(), 48000);
+
+        let parsed = FeedbackValue::parse(&[0x00, 0x00, 0x0C]).unwrap();
+        assert!(parsed == value);
+        assert!(FeedbackValue::parse(&[0x00, 0x00]).is_none());
+    }
+
+    #[test]
+    fn test_pacer_fractional_rate() {
+        // 44.1 kHz, 16-bit stereo
+        let mut pacer = FeedbackPacer::new(44100, 4, 192);
+        let total: u32 = (0..1000).map(|_| pacer.next_packet_samples()).sum();
+        // the nominal value is off by less than half of its last bit, which adds up to a fraction of a sample
+        assert!((44099..=44100).contains(&total));
+    }
+
+    #[test]
+    fn test_pacer_update() {
+        let mut pacer = FeedbackPacer::new(48000, 4, 200);
+        // 48.5 samples per frame
+        assert!(pacer.update(&[0x00, 0x20, 0x0C]));
+        assert_eq!(pacer.next_packet_size(), 192);
+        assert_eq!(pacer.next_packet_size(), 196);
+        // way off, ignored
+        assert!(!pacer.update(&[0x00, 0x00, 0x10]));
+        assert_eq!(pacer.current().raw(), 0x0C2000);
+    }
+
+    #[test]
+    fn test_parse_control_descriptor() {
+        let Some(ControlDescriptor::Header(header)) = parse_control_descriptor(&[0x01, 0x00, 0x01, 0x1e, 0x00, 0x02, 0x01, 0x02]) else {
+            panic!("expected header");
+        };
+        assert_eq!(header.audio_class, 0x0100);
+        assert_eq!(header.total_length, 30);
+        assert_eq!(header.streaming_interfaces(), &[1, 2]);
+
+        let input = [0x02, 0x01, 0x01, 0x02, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00];
+        assert!(matches!(
+            parse_control_descriptor(&input),
+            Some(ControlDescriptor::InputTerminal(InputTerminal { terminal_id: 1, terminal_type: TERMINAL_MICROPHONE, channels: 1, .. }))
+        ));
+        let output = [0x03, 0x02, 0x01, 0x01, 0x00, 0x01, 0x00];
+        assert!(matches!(
+            parse_control_descriptor(&output),
+            Some(ControlDescriptor::OutputTerminal(OutputTerminal { terminal_id: 2, terminal_type: TERMINAL_USB_STREAMING, source_id: 1 }))
+        ));
+        // truncated
+        assert!(parse_control_descriptor(&output[..4]).is_none());
+    }
+
+    #[test]
+    fn test_parse_streaming_descriptor() {
+        assert!(matches!(
+            parse_streaming_descriptor(&[0x01, 0x02, 0x01, 0x01, 0x00]),
+            Some(StreamingDescriptor::General(StreamingGeneral { terminal_link: 2, delay: 1, format_tag: FORMAT_TAG_PCM }))
+        ));
+
+        // stereo 16-bit, 44.1 and 48 kHz
+        let discrete = [0x02, 0x01, 0x02, 0x02, 0x10, 0x02, 0x44, 0xac, 0x00, 0x80, 0xbb, 0x00];
+        let Some(StreamingDescriptor::FormatTypeI(format)) = parse_streaming_descriptor(&discrete) else {
+            panic!("expected format type I");
+        };
+        assert_eq!(format.bytes_per_frame(), 4);
+        assert!(format.supports(&AudioFormat::new(2, 16, 44100)));
+        assert!(format.supports(&AudioFormat::new(2, 16, 48000)));
+        assert!(!format.supports(&AudioFormat::new(2, 16, 32000)));
+        assert!(!format.supports(&AudioFormat::MONO_48KHZ_16BIT));
+
+        // mono 16-bit, 8 to 48 kHz
+        let continuous = [0x02, 0x01, 0x01, 0x02, 0x10, 0x00, 0x40, 0x1f, 0x00, 0x80, 0xbb, 0x00];
+        let Some(StreamingDescriptor::FormatTypeI(format)) = parse_streaming_descriptor(&continuous) else {
+            panic!("expected format type I");
+        };
+        assert!(format.supports(&AudioFormat::MONO_48KHZ_16BIT));
+        assert!(format.supports(&AudioFormat::new(1, 16, 16000)));
+        assert!(parse_streaming_descriptor(&continuous[..9]).is_none());
+    }
+
+    #[test]
+    fn test_ring_buffer() {
+        let mut buffer = [0; 8];
+        let mut ring = RingBuffer::new(&mut buffer);
+        assert_eq!(ring.write(&[1, 2, 3, 4, 5, 6]), 6);
+        let mut out = [0; 4];
+        assert_eq!(ring.read(&mut out), 4);
+        assert_eq!(out, [1, 2, 3, 4]);
+        // wraps around, and is truncated when full
+        assert_eq!(ring.write(&[7, 8, 9, 10, 11, 12, 13]), 6);
+        assert_eq!(ring.free(), 0);
+        let mut out = [0; 10];
+        assert_eq!(ring.read(&mut out), 8);
+        assert_eq!(out[..8], [5, 6, 7, 8, 9, 10, 11, 12]);
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn test_audio_microphone() {
+        const MICROPHONE_DESCRIPTOR: &[u8] = &[
+            0x09, 0x02, 0x64, 0x00, 0x02, 0x01, 0x00, 0x80, 0x32, // configuration 1
+            0x09, 0x04, 0x00, 0x00, 0x00, 0x01, 0x01, 0x00, 0x00, // interface 0: audio control
+            0x09, 0x24, 0x01, 0x00, 0x01, 0x1e, 0x00, 0x01, 0x01, // header
+            0x0c, 0x24, 0x02, 0x01, 0x01, 0x02, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, // input terminal: microphone
+            0x09, 0x24, 0x03, 0x02, 0x01, 0x01, 0x00, 0x01, 0x00, // output terminal: USB streaming
+            0x09, 0x04, 0x01, 0x00, 0x00, 0x01, 0x02, 0x00, 0x00, // interface 1: audio streaming, no endpoints
+            0x09, 0x04, 0x01, 0x01, 0x01, 0x01, 0x02, 0x00, 0x00, // interface 1, alternate setting 1
+            0x07, 0x24, 0x01, 0x02, 0x01, 0x01, 0x00, // AS general: PCM
+            0x0b, 0x24, 0x02, 0x01, 0x01, 0x02, 0x10, 0x01, 0x80, 0xbb, 0x00, // format type I: mono, 16-bit, 48 kHz
+            0x09, 0x05, 0x81, 0x01, 0x60, 0x00, 0x01, 0x00, 0x00, // endpoint 1 IN, isochronous
+            0x07, 0x25, 0x01, 0x01, 0x00, 0x00, 0x00, // CS endpoint: sampling frequency control
+        ];
+        const SET_INTERFACE: ControlResponse = ControlResponse {
+            request_type: 0x01,
+            request: 0x0b,
+            value: 1,
+            index: 1,
+            response: Response::Data(&[]),
+        };
+        const SET_SAMPLE_RATE: ControlResponse = ControlResponse {
+            request_type: 0x22,
+            request: 0x01,
+            value: 0x0100,
+            index: 0x81,
+            response: Response::Data(&[]),
+        };
+        static mut SAMPLES: [u8; 64] = [0; 64];
+
+        let device = MockDevice {
+            control_responses: &[SET_INTERFACE, SET_SAMPLE_RATE],
+            ..MockDevice::new(DEVICE_DESCRIPTOR, &[MICROPHONE_DESCRIPTOR])
+        };
+        let mut host = UsbHost::new(MockHostBus::new(device));
+        let samples = unsafe { &mut *core::ptr::addr_of_mut!(SAMPLES) };
+        let mut mic = AudioDriver::microphone(AudioFormat::MONO_48KHZ_16BIT, samples);
+        host.bus().attach();
+        host.bus().set_bulk_in(&[0x01, 0x02, 0x03, 0x04]);
+
+        let mut events = [false; 2];
+        for _ in 0..1000 {
+            host.poll(&mut [&mut mic]);
+            assert!(mic.poll(&mut host).is_ok());
+            match mic.take_event() {
+                Some(AudioEvent::DeviceAdded(_)) => events[0] = true,
+                Some(AudioEvent::StreamStarted(_)) => {
+                    events[1] = true;
+                    assert_eq!(host.bus().last_setup().map(|setup| (setup.request_type, setup.value)), Some((0x22, 0x0100)));
+                }
+                Some(AudioEvent::SetupFailed(_)) => panic!("setup failed"),
+                _ => {}
+            }
+        }
+        assert_eq!(events, [true; 2]);
+        assert!(mic.streaming());
+        // one packet per frame, until the buffer is full
+        assert_eq!(mic.ring().free(), 0);
+        let mut out = [0; 6];
+        assert_eq!(mic.ring().read(&mut out), 6);
+        assert_eq!(out, [0x01, 0x02, 0x03, 0x04, 0x01, 0x02]);
+    }
+
+    #[test]
+    fn test_audio_speaker_feedback() {
+        const SPEAKER_DESCRIPTOR: &[u8] = &[
+            0x09, 0x02, 0x76, 0x00, 0x02, 0x01, 0x00, 0x80, 0x32, // configuration 1
+            0x09, 0x04, 0x00, 0x00, 0x00, 0x01, 0x01, 0x00, 0x00, // interface 0: audio control
+            0x09, 0x24, 0x01, 0x00, 0x01, 0x1e, 0x00, 0x01, 0x01, // header
+            0x0c, 0x24, 0x02, 0x01, 0x01, 0x01, 0x00, 0x02, 0x03, 0x00, 0x00, 0x00, // input terminal: USB streaming
+            0x09, 0x24, 0x03, 0x02, 0x01, 0x03, 0x00, 0x01, 0x00, // output terminal: speaker
+            0x09, 0x04, 0x01, 0x00, 0x00, 0x01, 0x02, 0x00, 0x00, // interface 1: audio streaming, no endpoints
+            0x09, 0x04, 0x01, 0x01, 0x02, 0x01, 0x02, 0x00, 0x00, // interface 1, alternate setting 1
+            0x07, 0x24, 0x01, 0x01, 0x01, 0x01, 0x00, // AS general: PCM
+            0x0b, 0x24, 0x02, 0x01, 0x02, 0x02, 0x10, 0x01, 0x80, 0xbb, 0x00, // format type I: stereo, 16-bit, 48 kHz
+            0x09, 0x05, 0x01, 0x05, 0xc8, 0x00, 0x01, 0x00, 0x82, // endpoint 1 OUT, isochronous, asynchronous
+            0x07, 0x25, 0x01, 0x00, 0x00, 0x00, 0x00, // CS endpoint
+            0x09, 0x05, 0x82, 0x11, 0x03, 0x00, 0x01, 0x01, 0x00, // endpoint 2 IN, isochronous feedback, every 2 frames
+        ];
+        const SET_INTERFACE: ControlResponse = ControlResponse {
+            request_type: 0x01,
+            request: 0x0b,
+            value: 1,
+            index: 1,
+            response: Response::Data(&[]),
+        };
+        static mut SAMPLES: [u8; 64] = [0; 64];
+
+        let device = MockDevice { control_responses: &[SET_INTERFACE], ..MockDevice::new(DEVICE_DESCRIPTOR, &[SPEAKER_DESCRIPTOR]) };
+        let mut host = UsbHost::new(MockHostBus::new(device));
+        let samples = unsafe { &mut *core::ptr::addr_of_mut!(SAMPLES) };
+        let mut speaker = AudioDriver::speaker(AudioFormat::new(2, 16, 48000), samples);
+        host.bus().attach();
+        // 48.5 samples per frame
+        host.bus().set_bulk_in(&[0x00, 0x20, 0x0C]);
+
+        for _ in 0..1000 {
+            host.poll(&mut [&mut speaker]);
+            assert!(speaker.poll(&mut host).is_ok());
+            assert!(!matches!(speaker.take_event(), Some(AudioEvent::SetupFailed(_))));
+        }
+        assert!(speaker.streaming());
+        assert_eq!(speaker.pacer.as_ref().map(|pacer| pacer.current().raw()), Some(0x0C2000));
+        // packets alternate between 48 and 49 samples of 4 bytes, after the first one at the nominal rate
+        let (packets, bytes) = host.bus().isochronous_out();
+        assert!(packets > 100);
+        assert!((packets * 194).abs_diff(bytes) <= 4);
+    }
+}
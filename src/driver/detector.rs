@@ -1,21 +1,30 @@
 //! Helpers for detecting USB devices from drivers
 //!
 
+use super::DiscoveryInterest;
 use crate::descriptor;
-use crate::types::DeviceAddress;
-use defmt::debug;
+use crate::types::{DeviceAddress, InterfaceSet, TransferType};
+use usb_device::UsbDirection;
 
-#[derive(Default)]
+/// Detects devices with an interface of a given class & subclass, which has an endpoint of the given direction & type
+///
+/// Optionally the interface protocol can be matched as well, see [`with_protocol`](SimpleDetector::with_protocol).
+#[derive(Default, Debug)]
 pub struct SimpleDetector<
     const CLASS_CODE: u8,
     const SUB_CLASS_CODE: u8,
     const EP_DIRECTION: u8,
     const EP_TYPE: u8,
     > {
+    protocol: Option<u8>,
     dev_addr: Option<DeviceAddress>,
     config: Option<u8>,
     interface: Option<u8>,
     endpoint: Option<(u8, u16, u8)>,
+    /// Set while the descriptors following a matching interface descriptor are processed
+    matching: bool,
+    /// Set if the device descriptor names a class other than the one detected
+    rejected: bool,
 }
 
 impl<
@@ -25,27 +34,55 @@ impl<
     const EP_TYPE: u8,
     > SimpleDetector<CLASS_CODE, SUB_CLASS_CODE, EP_DIRECTION, EP_TYPE> {
 
+    /// Create a detector which only matches interfaces with the given protocol code
+    pub fn with_protocol(protocol: u8) -> Self {
+        Self {
+            protocol: Some(protocol),
+            dev_addr: None,
+            config: None,
+            interface: None,
+            endpoint: None,
+            matching: false,
+            rejected: false,
+        }
+    }
+
     fn reset(&mut self, dev_addr: Option<DeviceAddress>) {
         self.dev_addr = dev_addr;
         self.config = None;
         self.interface = None;
         self.endpoint = None;
+        self.matching = false;
+        self.rejected = false;
     }
 
+    /// Start detection for a newly attached device
+    ///
+    /// If a previous device was not claimed (i.e. it remained dormant), it is forgotten.
     pub fn attached(&mut self, dev_addr: DeviceAddress) {
-        assert!(self.dev_addr == None);
         self.reset(Some(dev_addr));
     }
 
-    pub fn detached(&mut self, _dev_addr: DeviceAddress) {
-        self.reset(None);
+    pub fn detached(&mut self, dev_addr: DeviceAddress) {
+        if self.dev_addr == Some(dev_addr) {
+            self.reset(None);
+        }
     }
 
     pub fn descriptor(&mut self, dev_addr: DeviceAddress, descriptor_type: u8, data: &[u8]) {
-        assert!(self.dev_addr == Some(dev_addr));
+        if self.dev_addr != Some(dev_addr) {
+            return;
+        }
         match descriptor_type {
+            descriptor::TYPE_DEVICE => {
+                if let Ok((_, device)) = descriptor::parse::device_descriptor(data) {
+                    // classes 0x00 (per interface), 0xEF (miscellaneous) and 0xFF (vendor specific) leave it to the interfaces
+                    self.rejected = !matches!(device.device_class, 0x00 | 0xEF | 0xFF) && device.device_class != CLASS_CODE;
+                }
+            }
             descriptor::TYPE_CONFIGURATION => {
                 debug!("check config");
+                self.matching = false;
                 if self.endpoint.is_none() {
                     if let Ok((_, config)) = descriptor::parse::configuration_descriptor(data) {
                         self.config = Some(config.value);
@@ -55,16 +92,24 @@ impl<
             descriptor::TYPE_INTERFACE => {
                 debug!("check iface");
                 if let Ok((_, interface)) = descriptor::parse::interface_descriptor(data) {
-                    if interface.interface_class == CLASS_CODE && interface.interface_sub_class == SUB_CLASS_CODE {
+                    // endpoints of other interfaces (e.g. of a composite device) must not be picked up
+                    self.matching = self.endpoint.is_none()
+                        && interface.interface_class == CLASS_CODE
+                        && interface.interface_sub_class == SUB_CLASS_CODE
+                        && self.protocol.is_none_or(|protocol| interface.interface_protocol == protocol);
+                    if self.matching {
                         self.interface = Some(interface.interface_number);
                     }
                 }
             }
             descriptor::TYPE_ENDPOINT => {
                 debug!("check ep");
-                if self.interface.is_some() {
+                if self.matching && self.endpoint.is_none() {
                     if let Ok((_, endpoint)) = descriptor::parse::endpoint_descriptor(data) {
-                        if endpoint.address.direction() as u8 == EP_DIRECTION && endpoint.attributes.transfer_type() as u8 == EP_TYPE {
+                        if endpoint.address.direction() as u8 == EP_DIRECTION
+                            && endpoint.attributes.transfer_type() as u8 == EP_TYPE
+                            && endpoint.max_packet_size > 0
+                        {
                             self.endpoint = Some((endpoint.address.number(), endpoint.max_packet_size, endpoint.interval));
                         }
                     }
@@ -74,18 +119,53 @@ impl<
                 // TODO
             }
         }
-        debug!("{}, {}, {}, {}", self.dev_addr, self.config, self.interface, self.endpoint);
+        debug!("{:?}, {:?}, {:?}, {:?}", self.dev_addr, self.config, self.interface, self.endpoint);
     }
 
     pub fn configure(&mut self, dev_addr: DeviceAddress) -> Option<u8> {
-        assert!(self.dev_addr == Some(dev_addr));
+        if self.dev_addr != Some(dev_addr) {
+            return None;
+        }
         self.endpoint
-            .and_then(|_| self.interface)
-            .and_then(|_| self.config)
+            .and(self.interface)
+            .and(self.config)
+    }
+
+    /// Whether further configurations need to be seen, see [`Driver::discovery_interest`](super::Driver::discovery_interest)
+    pub fn interest(&self, dev_addr: DeviceAddress) -> DiscoveryInterest {
+        if self.dev_addr != Some(dev_addr) || self.rejected {
+            DiscoveryInterest::NotInterested
+        } else if self.endpoint.is_some() {
+            DiscoveryInterest::Ready
+        } else {
+            DiscoveryInterest::Undecided
+        }
+    }
+
+    /// Returns the detected interface of the given device, once a matching endpoint was found
+    pub fn interface(&self, dev_addr: DeviceAddress) -> Option<u8> {
+        match self {
+            Self { dev_addr: Some(addr), interface, endpoint: Some(_), .. } if *addr == dev_addr => *interface,
+            _ => None,
+        }
+    }
+
+    /// Returns the detected interface, if the given configuration is the one it belongs to
+    pub fn claim(&self, dev_addr: DeviceAddress, value: u8) -> InterfaceSet {
+        match self {
+            Self { dev_addr: Some(addr), config: Some(config), interface: Some(interface), endpoint: Some(_), .. }
+                if *addr == dev_addr && *config == value =>
+            {
+                InterfaceSet::single(*interface)
+            }
+            _ => InterfaceSet::EMPTY,
+        }
     }
 
     pub fn configured(&mut self, dev_addr: DeviceAddress, value: u8) -> Option<(u8, (u8, u16, u8))> {
-        assert!(self.dev_addr == Some(dev_addr));
+        if self.dev_addr != Some(dev_addr) {
+            return None;
+        }
         let result = match self {
             Self { config: Some(config), interface: Some(interface), endpoint: Some(endpoint), .. } if *config == value => Some((*interface, *endpoint)),
             _ => None,
@@ -94,3 +174,426 @@ impl<
         result
     }
 }
+
+/// State of a driver that handles a single device at a time
+///
+/// While the descriptors of a newly attached device are inspected, the driver keeps what it found in `P`. Once it
+/// configured the device, it keeps its pipes etc. in `C`. Another device attached in the meantime does not affect the
+/// configured one.
+///
+/// A pending device that was not claimed by any driver remains dormant, so it is simply forgotten when the next device
+/// is attached.
+#[derive(Copy, Clone, Debug)]
+pub enum SingleDevice<P, C> {
+    /// No device is attached
+    Idle,
+    /// A device was attached, and its descriptors are being inspected
+    Pending(DeviceAddress, P),
+    /// The device was configured
+    Configured(DeviceAddress, C),
+}
+
+impl<P, C> SingleDevice<P, C> {
+    /// Start inspecting a newly attached device, unless a device is configured already
+    pub fn attached(&mut self, dev_addr: DeviceAddress, pending: P) {
+        if !matches!(self, SingleDevice::Configured(..)) {
+            *self = SingleDevice::Pending(dev_addr, pending);
+        }
+    }
+
+    /// Forget the given device, if it is the one being handled
+    ///
+    /// Returns `true` if it was configured, i.e. if its removal should be reported.
+    pub fn detached(&mut self, dev_addr: DeviceAddress) -> bool {
+        match self {
+            SingleDevice::Pending(addr, _) if *addr == dev_addr => {
+                *self = SingleDevice::Idle;
+                false
+            }
+            SingleDevice::Configured(addr, _) if *addr == dev_addr => {
+                *self = SingleDevice::Idle;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// State of the given device, while its descriptors are being inspected
+    pub fn pending(&mut self, dev_addr: DeviceAddress) -> Option<&mut P> {
+        match self {
+            SingleDevice::Pending(addr, pending) if *addr == dev_addr => Some(pending),
+            _ => None,
+        }
+    }
+
+    /// Answer the host's request to configure the given device, with the configuration chosen from the pending state
+    ///
+    /// If no configuration was chosen, the device is forgotten.
+    pub fn configure(&mut self, dev_addr: DeviceAddress, choose: impl FnOnce(&mut P) -> Option<u8>) -> Option<u8> {
+        let value = choose(self.pending(dev_addr)?);
+        if value.is_none() {
+            *self = SingleDevice::Idle;
+        }
+        value
+    }
+
+    /// Take the pending state of the given device, once it was configured
+    ///
+    /// The driver becomes idle, until it stores the configured state of the device.
+    pub fn take_pending(&mut self, dev_addr: DeviceAddress) -> Option<P> {
+        match core::mem::replace(self, SingleDevice::Idle) {
+            SingleDevice::Pending(addr, pending) if addr == dev_addr => Some(pending),
+            other => {
+                *self = other;
+                None
+            }
+        }
+    }
+
+    /// Address of the configured device, if any
+    pub fn address(&self) -> Option<DeviceAddress> {
+        match self {
+            SingleDevice::Configured(dev_addr, _) => Some(*dev_addr),
+            _ => None,
+        }
+    }
+
+    /// State of the given device, if it is the configured one
+    pub fn device(&self, dev_addr: DeviceAddress) -> Option<&C> {
+        match self {
+            SingleDevice::Configured(addr, configured) if *addr == dev_addr => Some(configured),
+            _ => None,
+        }
+    }
+
+    /// Mutable state of the given device, if it is the configured one
+    pub fn device_mut(&mut self, dev_addr: DeviceAddress) -> Option<&mut C> {
+        match self {
+            SingleDevice::Configured(addr, configured) if *addr == dev_addr => Some(configured),
+            _ => None,
+        }
+    }
+}
+
+/// Vendor and product ID to match, see [`VidPidDetector`]
+///
+/// Only the bits set in the respective mask are compared, which allows matching a range of product IDs.
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct VidPid {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub vendor_mask: u16,
+    pub product_mask: u16,
+}
+
+impl VidPid {
+    /// Match exactly the given vendor and product ID
+    pub const fn new(vendor_id: u16, product_id: u16) -> Self {
+        Self {
+            vendor_id,
+            product_id,
+            vendor_mask: 0xFFFF,
+            product_mask: 0xFFFF,
+        }
+    }
+
+    /// Match any product of the given vendor
+    pub const fn vendor(vendor_id: u16) -> Self {
+        Self::new(vendor_id, 0).with_product_mask(0)
+    }
+
+    /// Only compare the bits of the product ID which are set in the given `mask`
+    pub const fn with_product_mask(self, mask: u16) -> Self {
+        Self {
+            product_mask: mask,
+            ..self
+        }
+    }
+
+    pub fn matches(&self, vendor_id: u16, product_id: u16) -> bool {
+        (vendor_id ^ self.vendor_id) & self.vendor_mask == 0 && (product_id ^ self.product_id) & self.product_mask == 0
+    }
+}
+
+/// Endpoint found by the [`VidPidDetector`]
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct DetectedEndpoint {
+    pub number: u8,
+    pub direction: UsbDirection,
+    pub transfer_type: TransferType,
+    pub max_packet_size: u16,
+    /// Raw `bInterval` value, see [`descriptor::EndpointDescriptor::interval`]
+    pub interval: u8,
+}
+
+/// Device matched by the [`VidPidDetector`]
+///
+/// Returned from [`VidPidDetector::configured`].
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct VidPidMatch<const MAX_ENDPOINTS: usize> {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub interface: u8,
+    /// Endpoints of the interface, in the order of their descriptors
+    ///
+    /// Endpoints beyond `MAX_ENDPOINTS` are ignored, and so are endpoints with a maximum packet size of zero (for which
+    /// no pipe can be created).
+    pub endpoints: [Option<DetectedEndpoint>; MAX_ENDPOINTS],
+}
+
+/// Detects devices by their vendor and product ID, for vendor specific drivers
+///
+/// The detector chooses the first configuration of a matching device, and collects the endpoints of the first interface
+/// in it (or of the interface given to [`with_interface`](VidPidDetector::with_interface)). Alternate settings other than 0 are ignored.
+///
+/// It is meant to be used the same way as the [`SimpleDetector`]: forward the `attached`, `detached`, `descriptor`, `configure`
+/// and `configured` callbacks of the driver to it.
+#[derive(Debug)]
+pub struct VidPidDetector<const MAX_ENDPOINTS: usize = 4> {
+    ids: &'static [VidPid],
+    interface_number: Option<u8>,
+    dev_addr: Option<DeviceAddress>,
+    device: Option<(u16, u16)>,
+    /// Set once the device descriptor was seen, whether it matched or not
+    device_seen: bool,
+    config: Option<u8>,
+    /// Number of configuration descriptors seen so far
+    configurations: u8,
+    interface: Option<u8>,
+    /// Set while the endpoint descriptors that follow belong to the chosen interface
+    collecting: bool,
+    endpoints: [Option<DetectedEndpoint>; MAX_ENDPOINTS],
+}
+
+impl<const MAX_ENDPOINTS: usize> VidPidDetector<MAX_ENDPOINTS> {
+    /// Create a detector which matches devices with any of the given IDs
+    pub const fn new(ids: &'static [VidPid]) -> Self {
+        Self {
+            ids,
+            interface_number: None,
+            dev_addr: None,
+            device: None,
+            device_seen: false,
+            config: None,
+            configurations: 0,
+            interface: None,
+            collecting: false,
+            endpoints: [None; MAX_ENDPOINTS],
+        }
+    }
+
+    /// Collect the endpoints of the interface with the given number, instead of the first one
+    pub const fn with_interface(self, interface_number: u8) -> Self {
+        Self {
+            interface_number: Some(interface_number),
+            ..self
+        }
+    }
+
+    fn reset(&mut self, dev_addr: Option<DeviceAddress>) {
+        self.dev_addr = dev_addr;
+        self.device = None;
+        self.device_seen = false;
+        self.config = None;
+        self.configurations = 0;
+        self.interface = None;
+        self.collecting = false;
+        self.endpoints = [None; MAX_ENDPOINTS];
+    }
+
+    /// Start detection for a newly attached device
+    ///
+    /// If a previous device was not claimed (i.e. it remained dormant), it is forgotten.
+    pub fn attached(&mut self, dev_addr: DeviceAddress) {
+        self.reset(Some(dev_addr));
+    }
+
+    pub fn detached(&mut self, dev_addr: DeviceAddress) {
+        if self.dev_addr == Some(dev_addr) {
+            self.reset(None);
+        }
+    }
+
+    pub fn descriptor(&mut self, dev_addr: DeviceAddress, descriptor_type: u8, data: &[u8]) {
+        if self.dev_addr != Some(dev_addr) {
+            return;
+        }
+        match descriptor_type {
+            descriptor::TYPE_DEVICE => {
+                self.device_seen = true;
+                if let Ok((_, device)) = descriptor::parse::device_descriptor(data) {
+                    if self.ids.iter().any(|ids| ids.matches(device.id_vendor, device.id_product)) {
+                        self.device = Some((device.id_vendor, device.id_product));
+                    }
+                }
+            }
+            _ if self.device.is_none() => {}
+            descriptor::TYPE_CONFIGURATION => {
+                self.collecting = false;
+                self.configurations = self.configurations.saturating_add(1);
+                if self.config.is_none() {
+                    if let Ok((_, config)) = descriptor::parse::configuration_descriptor(data) {
+                        self.config = Some(config.value);
+                    }
+                }
+            }
+            descriptor::TYPE_INTERFACE => {
+                self.collecting = false;
+                if let Ok((_, interface)) = descriptor::parse::interface_descriptor(data) {
+                    // only the first configuration is considered
+                    if self.configurations == 1
+                        && self.interface.is_none()
+                        && interface.alternate_setting == 0
+                        && self.interface_number.is_none_or(|number| interface.interface_number == number)
+                    {
+                        self.interface = Some(interface.interface_number);
+                        self.collecting = true;
+                    }
+                }
+            }
+            descriptor::TYPE_ENDPOINT if self.collecting => {
+                if let Ok((_, endpoint)) = descriptor::parse::endpoint_descriptor(data) {
+                    // no pipe can be created for an endpoint without packets
+                    if endpoint.max_packet_size == 0 {
+                        return;
+                    }
+                    if let Some(slot) = self.endpoints.iter_mut().find(|slot| slot.is_none()) {
+                        slot.replace(DetectedEndpoint {
+                            number: endpoint.address.number(),
+                            direction: endpoint.address.direction(),
+                            transfer_type: endpoint.attributes.transfer_type(),
+                            max_packet_size: endpoint.max_packet_size,
+                            interval: endpoint.interval,
+                        });
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn configure(&mut self, dev_addr: DeviceAddress) -> Option<u8> {
+        if self.dev_addr != Some(dev_addr) {
+            return None;
+        }
+        self.device.and(self.interface).and(self.config)
+    }
+
+    /// Whether further configurations need to be seen, see [`Driver::discovery_interest`](super::Driver::discovery_interest)
+    ///
+    /// Only the first configuration is considered, so a matching device is decided once it was seen.
+    pub fn interest(&self, dev_addr: DeviceAddress) -> DiscoveryInterest {
+        if self.dev_addr != Some(dev_addr) || (self.device_seen && self.device.is_none()) {
+            DiscoveryInterest::NotInterested
+        } else if self.configurations >= 1 {
+            DiscoveryInterest::Ready
+        } else {
+            DiscoveryInterest::Undecided
+        }
+    }
+
+    pub fn configured(&mut self, dev_addr: DeviceAddress, value: u8) -> Option<VidPidMatch<MAX_ENDPOINTS>> {
+        if self.dev_addr != Some(dev_addr) {
+            return None;
+        }
+        let result = match self {
+            Self { device: Some((vendor_id, product_id)), config: Some(config), interface: Some(interface), .. } if *config == value => {
+                Some(VidPidMatch {
+                    vendor_id: *vendor_id,
+                    product_id: *product_id,
+                    interface: *interface,
+                    endpoints: self.endpoints,
+                })
+            }
+            _ => None,
+        };
+        self.reset(None);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::num::NonZeroU8;
+
+    const IDS: [VidPid; 2] = [VidPid::new(0x1234, 0x5678), VidPid::vendor(0xabcd)];
+
+    const DEVICE: [u8; 16] = [0x00, 0x02, 0xff, 0, 0, 64, 0xcd, 0xab, 0x01, 0x00, 0x00, 0x01, 1, 2, 3, 2];
+    const CONFIGURATION_1: [u8; 7] = [32, 0, 1, 1, 0, 0x80, 50];
+    const CONFIGURATION_2: [u8; 7] = [32, 0, 1, 2, 0, 0x80, 50];
+    const INTERFACE: [u8; 7] = [0, 0, 2, 0xff, 0, 0, 0];
+    const ENDPOINT_IN: [u8; 5] = [0x81, 0x02, 64, 0, 0];
+    const ENDPOINT_OUT: [u8; 5] = [0x02, 0x02, 64, 0, 0];
+
+    #[test]
+    fn test_vid_pid_matches() {
+        assert!(IDS[0].matches(0x1234, 0x5678));
+        assert!(!IDS[0].matches(0x1234, 0x5679));
+        assert!(IDS[1].matches(0xabcd, 0x0001));
+        assert!(VidPid::new(0x1234, 0x5600).with_product_mask(0xff00).matches(0x1234, 0x56ab));
+    }
+
+    #[test]
+    fn test_vid_pid_detector() {
+        static DETECTOR_IDS: [VidPid; 2] = IDS;
+        let dev_addr = DeviceAddress(NonZeroU8::new(1).unwrap());
+        let mut detector: VidPidDetector<2> = VidPidDetector::new(&DETECTOR_IDS);
+        detector.attached(dev_addr);
+        detector.descriptor(dev_addr, descriptor::TYPE_DEVICE, &DEVICE);
+        assert!(detector.interest(dev_addr) == DiscoveryInterest::Undecided);
+        for config in [CONFIGURATION_1, CONFIGURATION_2] {
+            detector.descriptor(dev_addr, descriptor::TYPE_CONFIGURATION, &config);
+            detector.descriptor(dev_addr, descriptor::TYPE_INTERFACE, &INTERFACE);
+            detector.descriptor(dev_addr, descriptor::TYPE_ENDPOINT, &ENDPOINT_IN);
+            detector.descriptor(dev_addr, descriptor::TYPE_ENDPOINT, &ENDPOINT_OUT);
+        }
+        assert!(detector.interest(dev_addr) == DiscoveryInterest::Ready);
+        assert!(detector.configure(dev_addr) == Some(1));
+        let found = detector.configured(dev_addr, 1).unwrap();
+        assert!((found.vendor_id, found.product_id, found.interface) == (0xabcd, 0x0001, 0));
+        let [Some(ep_in), Some(ep_out)] = found.endpoints else {
+            panic!("expected two endpoints");
+        };
+        assert!((ep_in.number, ep_in.direction, ep_in.transfer_type) == (1, UsbDirection::In, TransferType::Bulk));
+        assert!((ep_out.number, ep_out.direction, ep_out.max_packet_size) == (2, UsbDirection::Out, 64));
+    }
+
+    #[test]
+    fn test_vid_pid_detector_mismatch() {
+        static OTHER_IDS: [VidPid; 1] = [VidPid::new(0x1234, 0x5678)];
+        let dev_addr = DeviceAddress(NonZeroU8::new(1).unwrap());
+        let mut detector: VidPidDetector<2> = VidPidDetector::new(&OTHER_IDS);
+        detector.attached(dev_addr);
+        assert!(detector.interest(dev_addr) == DiscoveryInterest::Undecided);
+        detector.descriptor(dev_addr, descriptor::TYPE_DEVICE, &DEVICE);
+        assert!(detector.interest(dev_addr) == DiscoveryInterest::NotInterested);
+        assert!(detector.configure(dev_addr).is_none());
+    }
+
+    #[test]
+    fn test_single_device() {
+        let first = DeviceAddress(NonZeroU8::new(1).unwrap());
+        let second = DeviceAddress(NonZeroU8::new(2).unwrap());
+        let mut state: SingleDevice<Option<u8>, u8> = SingleDevice::Idle;
+        // an unclaimed device is replaced by the next one
+        state.attached(first, None);
+        state.attached(second, Some(1));
+        assert!(state.pending(first).is_none());
+        assert!(state.configure(second, |chosen| *chosen) == Some(1));
+        assert!(state.take_pending(first).is_none());
+        assert!(state.take_pending(second) == Some(Some(1)));
+        state = SingleDevice::Configured(second, 7);
+        // the configured device is kept while another one is attached
+        state.attached(first, None);
+        assert!(state.address() == Some(second) && state.device(second) == Some(&7));
+        assert!(!state.detached(first));
+        assert!(state.detached(second));
+        assert!(state.address().is_none());
+        // a device without a matching configuration is forgotten
+        state.attached(first, None);
+        assert!(state.configure(first, |chosen| *chosen).is_none());
+        assert!(state.pending(first).is_none());
+    }
+}
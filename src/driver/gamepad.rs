@@ -0,0 +1,364 @@
+use super::{detector::SimpleDetector, DiscoveryInterest, Driver, EventQueue, HasEvents, EVENT_QUEUE_DEPTH};
+use crate::bus::HostBus;
+use crate::types::{ConnectionSpeed, DeviceAddress, InterfaceSet, TransferType};
+use crate::{PipeId, UsbHost};
+use usb_device::UsbDirection;
+
+/// Interface class, subclass and protocol of the XInput controller interface
+const CLASS_VENDOR: u8 = 0xff;
+const SUB_CLASS_XINPUT: u8 = 0x5d;
+const PROTOCOL_XINPUT: u8 = 0x01;
+
+/// Message type of input reports
+const MESSAGE_INPUT: u8 = 0x00;
+/// Size of an input report
+const INPUT_REPORT_SIZE: usize = 20;
+
+/// Driver for game controllers implementing the XInput protocol (Xbox 360 style controllers)
+///
+/// Handles devices with a vendor specific interface (class `0xff`), using the XInput subclass (`0x5d`) and protocol (`0x01`).
+/// Many third party controllers implement this protocol as well. Other interfaces of the controller (headset, security)
+/// are left alone.
+///
+/// Input reports have the following format:
+///
+/// | Byte  | Content                                     |
+/// |-------|---------------------------------------------|
+/// | 0     | message type (`0x00` for input reports)     |
+/// | 1     | message length (`0x14`)                     |
+/// | 2..4  | button state (see [`GamepadButtons`])        |
+/// | 4     | left trigger                                |
+/// | 5     | right trigger                               |
+/// | 6..14 | left X, left Y, right X, right Y (`i16` LE) |
+///
+/// Controllers send a report whenever their state changes. Other messages (e.g. LED status) are ignored.
+///
+/// By default, up to 2 connected controllers can be handled. Events are reported for each device separately.
+#[derive(Debug)]
+pub struct GamepadDriver<const MAX_DEVICES: usize = 2> {
+    devices: [Option<GamepadDevice>; MAX_DEVICES],
+    detector: SimpleDetector<CLASS_VENDOR, SUB_CLASS_XINPUT, { UsbDirection::In as u8 }, { TransferType::Interrupt as u8 }>,
+    events: EventQueue<GamepadEvent, EVENT_QUEUE_DEPTH>,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct GamepadDevice {
+    dev_addr: DeviceAddress,
+    interrupt_pipe: PipeId,
+    state: GamepadState,
+}
+
+/// State of the buttons of a controller
+#[derive(Copy, Clone, PartialEq, Default, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct GamepadButtons(u16);
+
+impl GamepadButtons {
+    pub fn dpad_up(&self) -> bool {
+        self.pressed(0)
+    }
+
+    pub fn dpad_down(&self) -> bool {
+        self.pressed(1)
+    }
+
+    pub fn dpad_left(&self) -> bool {
+        self.pressed(2)
+    }
+
+    pub fn dpad_right(&self) -> bool {
+        self.pressed(3)
+    }
+
+    pub fn start(&self) -> bool {
+        self.pressed(4)
+    }
+
+    pub fn back(&self) -> bool {
+        self.pressed(5)
+    }
+
+    /// Is the left stick pressed down?
+    pub fn left_thumb(&self) -> bool {
+        self.pressed(6)
+    }
+
+    /// Is the right stick pressed down?
+    pub fn right_thumb(&self) -> bool {
+        self.pressed(7)
+    }
+
+    /// Is the left shoulder button (bumper) pressed?
+    pub fn left_shoulder(&self) -> bool {
+        self.pressed(8)
+    }
+
+    /// Is the right shoulder button (bumper) pressed?
+    pub fn right_shoulder(&self) -> bool {
+        self.pressed(9)
+    }
+
+    /// Is the guide button (the logo in the center) pressed?
+    pub fn guide(&self) -> bool {
+        self.pressed(10)
+    }
+
+    pub fn a(&self) -> bool {
+        self.pressed(12)
+    }
+
+    pub fn b(&self) -> bool {
+        self.pressed(13)
+    }
+
+    pub fn x(&self) -> bool {
+        self.pressed(14)
+    }
+
+    pub fn y(&self) -> bool {
+        self.pressed(15)
+    }
+
+    /// Is the button with the given bit number pressed?
+    pub fn pressed(&self, bit: u8) -> bool {
+        bit < 16 && (self.0 >> bit) & 1 == 1
+    }
+
+    /// Raw button bitmap
+    pub fn bits(&self) -> u16 {
+        self.0
+    }
+}
+
+/// Position of an analog stick
+///
+/// Both axes range from `-32768` to `32767`. Positive values point right (`x`) and up (`y`).
+#[derive(Copy, Clone, PartialEq, Default, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Stick {
+    pub x: i16,
+    pub y: i16,
+}
+
+/// State of a controller, decoded from an input report
+#[derive(Copy, Clone, PartialEq, Default, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct GamepadState {
+    pub buttons: GamepadButtons,
+    /// Left trigger, from `0` (released) to `255` (fully pressed)
+    pub left_trigger: u8,
+    /// Right trigger, from `0` (released) to `255` (fully pressed)
+    pub right_trigger: u8,
+    pub left_stick: Stick,
+    pub right_stick: Stick,
+}
+
+impl GamepadState {
+    /// Decode an XInput input report
+    ///
+    /// Returns `None` if the report is too short, or is not an input report.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let data = data.get(..INPUT_REPORT_SIZE)?;
+        if data[0] != MESSAGE_INPUT || (data[1] as usize) < 14 {
+            return None;
+        }
+        let word = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        Some(GamepadState {
+            buttons: GamepadButtons(word(2)),
+            left_trigger: data[4],
+            right_trigger: data[5],
+            left_stick: Stick { x: word(6) as i16, y: word(8) as i16 },
+            right_stick: Stick { x: word(10) as i16, y: word(12) as i16 },
+        })
+    }
+}
+
+/// Events related to attached game controllers
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum GamepadEvent {
+    /// A new controller was detected & configured, with given device address
+    DeviceAdded(DeviceAddress),
+
+    /// A controller was removed
+    DeviceRemoved(DeviceAddress),
+
+    /// Buttons, triggers or sticks of the controller changed
+    ///
+    /// Compare to the previous state (see [`GamepadDriver::state`]) before handling the event, to find out what changed.
+    StateChanged(DeviceAddress, GamepadState),
+}
+
+impl<const MAX_DEVICES: usize> Default for GamepadDriver<MAX_DEVICES> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const MAX_DEVICES: usize> GamepadDriver<MAX_DEVICES> {
+    pub fn new() -> Self {
+        Self {
+            devices: [None; MAX_DEVICES],
+            detector: SimpleDetector::with_protocol(PROTOCOL_XINPUT),
+            events: EventQueue::new(),
+        }
+    }
+
+    /// Returns the oldest pending gamepad event (if any), and removes it from the queue.
+    ///
+    /// This method should be called repeatedly after calling `usb_host.poll(...)`, until it returns `None` (see
+    /// [`HasEvents`] for what happens otherwise).
+    pub fn take_event(&mut self) -> Option<GamepadEvent> {
+        self.events.pop()
+    }
+
+    /// Returns the current state of the given device
+    pub fn state(&self, dev_addr: DeviceAddress) -> Option<GamepadState> {
+        self.devices
+            .iter()
+            .flatten()
+            .find(|d| d.dev_addr == dev_addr)
+            .map(|d| d.state)
+    }
+}
+
+impl<const MAX_DEVICES: usize> HasEvents for GamepadDriver<MAX_DEVICES> {
+    type Event = GamepadEvent;
+
+    fn take_event(&mut self) -> Option<GamepadEvent> {
+        self.events.pop()
+    }
+
+    fn events_pending(&self) -> bool {
+        !self.events.is_empty()
+    }
+}
+
+impl<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+        const MAX_DEVICES: usize,
+    > Driver<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>
+    for GamepadDriver<MAX_DEVICES>
+{
+    fn attached(&mut self, dev_addr: DeviceAddress, _connection_speed: ConnectionSpeed) {
+        self.detector.attached(dev_addr);
+    }
+
+    fn detached(&mut self, dev_addr: DeviceAddress) {
+        if let Some(slot) = self.devices.iter_mut().find(|d| matches!(d, Some(d) if d.dev_addr == dev_addr)) {
+            slot.take();
+            self.events.push(GamepadEvent::DeviceRemoved(dev_addr));
+        } else {
+            self.detector.detached(dev_addr);
+        }
+    }
+
+    fn descriptor(&mut self, dev_addr: DeviceAddress, descriptor_type: u8, data: &[u8]) {
+        self.detector.descriptor(dev_addr, descriptor_type, data);
+    }
+
+    fn discovery_interest(&mut self, dev_addr: DeviceAddress) -> DiscoveryInterest {
+        self.detector.interest(dev_addr)
+    }
+
+    fn configure(&mut self, dev_addr: DeviceAddress) -> Option<u8> {
+        self.detector.configure(dev_addr)
+    }
+
+    fn claim_interfaces(&mut self, dev_addr: DeviceAddress, value: u8) -> InterfaceSet {
+        self.detector.claim(dev_addr, value)
+    }
+
+    fn configured(
+        &mut self,
+        dev_addr: DeviceAddress,
+        value: u8,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) {
+        let interfaces = self.detector.claim(dev_addr, value);
+        self.configured_interfaces(dev_addr, value, interfaces, host);
+    }
+
+    fn configured_interfaces(
+        &mut self,
+        dev_addr: DeviceAddress,
+        value: u8,
+        interfaces: InterfaceSet,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) {
+        let Some((interface, (endpoint, size, interval))) = self.detector.configured(dev_addr, value) else {
+            return;
+        };
+        if !interfaces.contains(interface) {
+            // claimed by another driver
+            return;
+        }
+        if let Some((index, slot)) = self.devices.iter_mut().enumerate().find(|(_, d)| d.is_none()) {
+            // the slot index is used as pipe context, to find the device in `completed_in`
+            if let Some(interrupt_pipe) =
+                host.create_interrupt_pipe_with_context(dev_addr, endpoint, UsbDirection::In, size, interval, index as u16)
+            {
+                slot.replace(GamepadDevice {
+                    dev_addr,
+                    interrupt_pipe,
+                    state: GamepadState::default(),
+                });
+                self.events.push(GamepadEvent::DeviceAdded(dev_addr));
+            }
+        }
+    }
+
+    fn completed_control(&mut self, _dev_addr: DeviceAddress, _pipe_id: PipeId, _data: Option<&[u8]>) {
+        // ignored, since the driver does not initiate any control transfers
+    }
+
+    fn completed_in(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, data: &[u8]) {
+        let Some(Some(device)) = self.devices.get_mut(pipe_id.context() as usize) else {
+            return;
+        };
+        if device.dev_addr != dev_addr || pipe_id != device.interrupt_pipe {
+            return;
+        }
+        if let Some(state) = GamepadState::parse(data) {
+            if state != device.state {
+                self.events.push(GamepadEvent::StateChanged(dev_addr, state));
+                device.state = state;
+            }
+        }
+    }
+
+    fn completed_out(&mut self, _dev_addr: DeviceAddress, _pipe_id: PipeId, _data: &mut [u8]) {
+        // ignored, since there are no OUT pipes in use.
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_report() {
+        let mut data = [0u8; 20];
+        data[1] = 0x14;
+        // A and dpad up pressed
+        data[2..4].copy_from_slice(&0x1001u16.to_le_bytes());
+        data[5] = 0xff;
+        data[6..8].copy_from_slice(&(-32768i16).to_le_bytes());
+        data[12..14].copy_from_slice(&1234i16.to_le_bytes());
+        let state = GamepadState::parse(&data).unwrap();
+        assert!(state.buttons.a() && state.buttons.dpad_up());
+        assert!(!state.buttons.b() && !state.buttons.guide());
+        assert_eq!(state.left_trigger, 0);
+        assert_eq!(state.right_trigger, 0xff);
+        assert_eq!(state.left_stick.x, -32768);
+        assert_eq!(state.right_stick.y, 1234);
+
+        // LED status message
+        data[0] = 0x01;
+        assert!(GamepadState::parse(&data).is_none());
+        assert!(GamepadState::parse(&[0, 0x14, 0, 0]).is_none());
+    }
+}
@@ -1,24 +1,28 @@
 use super::{
     Driver,
+    DiscoveryInterest,
+    EventQueue,
+    HasEvents,
+    EVENT_QUEUE_DEPTH,
     detector::SimpleDetector,
 };
-use crate::{UsbHost, PipeId, ControlError};
+use crate::{UsbHost, PipeId, ControlError, TransferError};
 use crate::bus::HostBus;
 use crate::types::{ConnectionSpeed, DeviceAddress, TransferType, SetupPacket};
 use usb_device::control::Request;
 use usb_device::{UsbDirection, control::{Recipient, RequestType}};
-use defmt::{error, debug, info, Format, bitflags};
+use bitflags::bitflags;
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 struct HubDevice {
     dev_addr: DeviceAddress,
-    interface: u8,
     control_pipe: PipeId,
     interrupt_pipe: PipeId,
     control_state: ControlState,
 }
 
-#[derive(Copy, Clone, Format, PartialEq)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 enum ControlState {
     Idle,
     GetDescriptor,
@@ -28,7 +32,9 @@ enum ControlState {
     ClearPortFeature(u8, PortFeature),
 }
 
-#[derive(Copy, Clone, Format)]
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct HubDescriptor {
     pub port_count: u8,
     pub characteristics: Characteristics,
@@ -37,11 +43,17 @@ pub struct HubDescriptor {
     pub device_removable: DeviceRemovable,
 }
 
-#[derive(Copy, Clone, Format)]
-pub struct Characteristics(u16);
+/// Raw `wHubCharacteristics` field of the hub descriptor
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Characteristics(pub u16);
 
-#[derive(Copy, Clone, Format)]
-pub struct DeviceRemovable(u8);
+/// Raw `DeviceRemovable` field of the hub descriptor: bit N is set if the device on port N is not removable
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeviceRemovable(pub u8);
 
 fn parse_hub_descriptor(data: &[u8]) -> Option<HubDescriptor> {
     if data.len() < 8 {
@@ -84,7 +96,9 @@ fn parse_hub_status(data: &[u8]) -> Option<HubStatus> {
     }
 }
 
-#[derive(Copy, Clone, Format, PartialEq)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum PortFeature {
     Connection = 0,
@@ -101,11 +115,15 @@ pub enum PortFeature {
     CReset = 20,
 }
 
-#[derive(Copy, Clone, Format)]
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum HubEvent {
     HubAdded(DeviceAddress),
     HubRemoved(DeviceAddress),
     Stall(DeviceAddress),
+    /// A control request to the hub failed. The hub is ready for the next request.
+    ControlFailed(DeviceAddress, TransferError),
     HubDescriptor(DeviceAddress, HubDescriptor),
     HubStatus(DeviceAddress, HubStatus),
     PortStatus(DeviceAddress, u8, PortStatus),
@@ -116,6 +134,7 @@ pub enum HubEvent {
 }
 
 bitflags! {
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub struct PortStatus: u32 {
         const CONNECTION = 1 << 0;
         const ENABLE = 1 << 1;
@@ -132,11 +151,21 @@ bitflags! {
     }
 }
 
-#[derive(Copy, Clone, Format)]
-pub struct HubStatus(u16, u16);
+#[cfg(feature = "defmt")]
+impl defmt::Format for PortStatus {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "PortStatus({=u32:#x})", self.bits())
+    }
+}
+
+/// Raw `wHubStatus` and `wHubChange` fields, as returned by the `GET_STATUS` request for the hub
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HubStatus(pub u16, pub u16);
 
 /// Error type for interactions with the driver
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub enum HubError {
     /// Error initiating control transfer
     ControlError(ControlError),
@@ -153,11 +182,33 @@ impl From<ControlError> for HubError {
     }
 }
 
-/// A [`Driver`] which logs various events
+/// A [`Driver`] for USB hubs
+///
+/// The driver reports changes on the hub's ports through [`HubEvent`]s, and provides methods to query and
+/// change the state of the hub and its ports.
+///
+/// Bringing up a device connected to one of the ports is driven by application code, roughly like this:
+/// 1. [`HubEvent::PortStatusChange`] is reported: call [`get_port_status`](HubDriver::get_port_status)
+/// 2. [`HubEvent::PortStatus`] shows `C_CONNECTION` and `CONNECTION`: clear `CConnection`, then set the `Reset` feature on the port
+/// 3. the port status shows `C_RESET` and `ENABLE`: clear `CReset`, then hand the device over to the host
+///    by calling [`UsbHost::enumerate_hub_port`], passing the speed indicated by `LOW_SPEED`
+/// 4. if the port status shows `C_CONNECTION` without `CONNECTION`, the device was removed: call [`UsbHost::hub_port_detached`]
+/// 5. if the port status shows `C_OVER_CURRENT`, the hub disabled the port: call [`UsbHost::hub_port_removed`] with
+///    [`DetachReason::Overcurrent`](crate::DetachReason::Overcurrent)
+///
+/// When a device on a port is reset with [`UsbHost::reset_device`], the host resets the port itself, and bringing the device
+/// up again continues at step 3.
+#[derive(Debug)]
 pub struct HubDriver<const MAX_HUBS: usize = 4> {
     devices: [Option<HubDevice>; MAX_HUBS],
     detector: SimpleDetector<0x09, 0x00, { UsbDirection::In as u8 }, { TransferType::Interrupt as u8 }>,
-    event: Option<HubEvent>,
+    events: EventQueue<HubEvent, EVENT_QUEUE_DEPTH>,
+}
+
+impl<const MAX_HUBS: usize> Default for HubDriver<MAX_HUBS> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<const MAX_HUBS: usize> HubDriver<MAX_HUBS> {
@@ -165,15 +216,24 @@ impl<const MAX_HUBS: usize> HubDriver<MAX_HUBS> {
         Self {
             devices: [None; MAX_HUBS],
             detector: SimpleDetector::default(),
-            event: None,
+            events: EventQueue::new(),
         }
     }
 
     pub fn take_event(&mut self) -> Option<HubEvent> {
-        self.event.take()
+        self.events.pop()
     }
 
-    pub fn get_hub_descriptor<B: HostBus>(&mut self, dev_addr: DeviceAddress, host: &mut UsbHost<B>) -> Result<(), HubError> {
+    pub fn get_hub_descriptor<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    >(
+        &mut self,
+        dev_addr: DeviceAddress,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) -> Result<(), HubError> {
         if let Some(device) = self.find_device(dev_addr) {
             host.control_in(
                 Some(dev_addr),
@@ -195,7 +255,16 @@ impl<const MAX_HUBS: usize> HubDriver<MAX_HUBS> {
         }
     }
 
-    pub fn get_hub_status<B: HostBus>(&mut self, dev_addr: DeviceAddress, host: &mut UsbHost<B>) -> Result<(), HubError> {
+    pub fn get_hub_status<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    >(
+        &mut self,
+        dev_addr: DeviceAddress,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) -> Result<(), HubError> {
         if let Some(device) = self.find_device(dev_addr) {
             host.control_in(
                 Some(dev_addr),
@@ -217,7 +286,17 @@ impl<const MAX_HUBS: usize> HubDriver<MAX_HUBS> {
         }
     }
 
-    pub fn get_port_status<B: HostBus>(&mut self, dev_addr: DeviceAddress, port: u8, host: &mut UsbHost<B>) -> Result<(), HubError> {
+    pub fn get_port_status<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    >(
+        &mut self,
+        dev_addr: DeviceAddress,
+        port: u8,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) -> Result<(), HubError> {
         if let Some(device) = self.find_device(dev_addr) {
             host.control_in(
                 Some(dev_addr),
@@ -239,7 +318,18 @@ impl<const MAX_HUBS: usize> HubDriver<MAX_HUBS> {
         }
     }
 
-    pub fn set_port_feature<B: HostBus>(&mut self, dev_addr: DeviceAddress, port: u8, feature: PortFeature, host: &mut UsbHost<B>) -> Result<(), HubError> {
+    pub fn set_port_feature<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    >(
+        &mut self,
+        dev_addr: DeviceAddress,
+        port: u8,
+        feature: PortFeature,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) -> Result<(), HubError> {
         if let Some(device) = self.find_device(dev_addr) {
             host.control_out(
                 Some(dev_addr), Some(device.control_pipe),
@@ -253,7 +343,18 @@ impl<const MAX_HUBS: usize> HubDriver<MAX_HUBS> {
         }
     }
 
-    pub fn clear_port_feature<B: HostBus>(&mut self, dev_addr: DeviceAddress, port: u8, feature: PortFeature, host: &mut UsbHost<B>) -> Result<(), HubError> {
+    pub fn clear_port_feature<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    >(
+        &mut self,
+        dev_addr: DeviceAddress,
+        port: u8,
+        feature: PortFeature,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) -> Result<(), HubError> {
         if let Some(device) = self.find_device(dev_addr) {
             host.control_out(
                 Some(dev_addr), Some(device.control_pipe),
@@ -267,12 +368,55 @@ impl<const MAX_HUBS: usize> HubDriver<MAX_HUBS> {
         }
     }
 
+    /// Switch the power of a port on or off
+    ///
+    /// Only has an effect on hubs with per-port power switching. Can be used to power down a port whose device exceeds the
+    /// power budget (see [`PollResult::PowerBudgetExceeded`](crate::PollResult::PowerBudgetExceeded)). The current available
+    /// at each port is reported by [`UsbHost::available_current`].
+    pub fn set_port_power<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    >(
+        &mut self,
+        dev_addr: DeviceAddress,
+        port: u8,
+        enable: bool,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) -> Result<(), HubError> {
+        if enable {
+            self.set_port_feature(dev_addr, port, PortFeature::Power, host)
+        } else {
+            self.clear_port_feature(dev_addr, port, PortFeature::Power, host)
+        }
+    }
+
     fn find_device(&mut self, dev_addr: DeviceAddress) -> Option<&mut HubDevice> {
         self.devices.iter_mut().filter_map(|d| d.as_mut()).find(|d| d.dev_addr == dev_addr)
     }
 }
 
-impl<B: HostBus, const MAX_HUBS: usize> Driver<B> for HubDriver<MAX_HUBS> {
+impl<const MAX_HUBS: usize> HasEvents for HubDriver<MAX_HUBS> {
+    type Event = HubEvent;
+
+    fn take_event(&mut self) -> Option<HubEvent> {
+        self.events.pop()
+    }
+
+    fn events_pending(&self) -> bool {
+        !self.events.is_empty()
+    }
+}
+
+impl<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+        const MAX_HUBS: usize,
+    > Driver<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE> for HubDriver<MAX_HUBS>
+{
     fn attached(
         &mut self,
         dev_addr: DeviceAddress,
@@ -284,7 +428,7 @@ impl<B: HostBus, const MAX_HUBS: usize> Driver<B> for HubDriver<MAX_HUBS> {
     fn detached(&mut self, dev_addr: DeviceAddress) {
         if let Some(slot) = self.devices.iter_mut().find(|d| d.is_some() && d.unwrap().dev_addr == dev_addr) {
             slot.take();
-            self.event = Some(HubEvent::HubRemoved(dev_addr));            
+            self.events.push(HubEvent::HubRemoved(dev_addr));
         } else {
             self.detector.detached(dev_addr);
         }
@@ -294,6 +438,10 @@ impl<B: HostBus, const MAX_HUBS: usize> Driver<B> for HubDriver<MAX_HUBS> {
         self.detector.descriptor(dev_addr, descriptor_type, data);
     }
 
+    fn discovery_interest(&mut self, dev_addr: DeviceAddress) -> DiscoveryInterest {
+        self.detector.interest(dev_addr)
+    }
+
     fn configure(&mut self, dev_addr: DeviceAddress) -> Option<u8> {
         self.detector.configure(dev_addr)
     }
@@ -302,9 +450,9 @@ impl<B: HostBus, const MAX_HUBS: usize> Driver<B> for HubDriver<MAX_HUBS> {
         &mut self,
         dev_addr: DeviceAddress,
         value: u8,
-        host: &mut UsbHost<B>,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
     ) {
-        if let Some((interface, (endpoint, size, interval))) = self.detector.configured(dev_addr, value) {
+        if let Some((_, (endpoint, size, interval))) = self.detector.configured(dev_addr, value) {
             if let Some(slot) = self.devices.iter_mut().find(|d| d.is_none()) {
                 match (
                     host.create_control_pipe(dev_addr),
@@ -315,12 +463,11 @@ impl<B: HostBus, const MAX_HUBS: usize> Driver<B> for HubDriver<MAX_HUBS> {
                     (Some(control_pipe), Some(interrupt_pipe)) => {
                         slot.replace(HubDevice {
                             dev_addr,
-                            interface,
                             control_pipe,
                             interrupt_pipe,
                             control_state: ControlState::Idle,
                         });
-                        self.event = Some(HubEvent::HubAdded(dev_addr));
+                        self.events.push(HubEvent::HubAdded(dev_addr));
                     },
                     (None, None) => {},
                 }
@@ -341,28 +488,28 @@ impl<B: HostBus, const MAX_HUBS: usize> Driver<B> for HubDriver<MAX_HUBS> {
                     ControlState::GetDescriptor => {
                         if let Some(desc) = data.and_then(parse_hub_descriptor) {
                             device.control_state = ControlState::Idle;
-                            self.event = Some(HubEvent::HubDescriptor(dev_addr, desc));
+                            self.events.push(HubEvent::HubDescriptor(dev_addr, desc));
                         }
                     }
                     ControlState::HubStatus => {
                         if let Some(status) = data.and_then(parse_hub_status) {
                             device.control_state = ControlState::Idle;
-                            self.event = Some(HubEvent::HubStatus(dev_addr, status));
+                            self.events.push(HubEvent::HubStatus(dev_addr, status));
                         }
                     }
                     ControlState::PortStatus(port) => {
                         if let Some(port_status) = data.and_then(parse_port_status) {
                             device.control_state = ControlState::Idle;
-                            self.event = Some(HubEvent::PortStatus(dev_addr, port, port_status));
+                            self.events.push(HubEvent::PortStatus(dev_addr, port, port_status));
                         }
                     }
                     ControlState::SetPortFeature(port, feature) => {
                         device.control_state = ControlState::Idle;
-                        self.event = Some(HubEvent::PortFeatureSet(dev_addr, port, feature));
+                        self.events.push(HubEvent::PortFeatureSet(dev_addr, port, feature));
                     }
                     ControlState::ClearPortFeature(port, feature) => {
                         device.control_state = ControlState::Idle;
-                        self.event = Some(HubEvent::PortFeatureClear(dev_addr, port, feature));
+                        self.events.push(HubEvent::PortFeatureClear(dev_addr, port, feature));
                     }
                 }
             }
@@ -388,9 +535,9 @@ impl<B: HostBus, const MAX_HUBS: usize> Driver<B> for HubDriver<MAX_HUBS> {
 
                 if let Some(bit) = bit {
                     if bit == 0 {
-                        self.event = Some(HubEvent::HubStatusChange(dev_addr));
+                        self.events.push(HubEvent::HubStatusChange(dev_addr));
                     } else {
-                        self.event = Some(HubEvent::PortStatusChange(dev_addr, bit));
+                        self.events.push(HubEvent::PortStatusChange(dev_addr, bit));
                     }
                 }
             };
@@ -399,8 +546,8 @@ impl<B: HostBus, const MAX_HUBS: usize> Driver<B> for HubDriver<MAX_HUBS> {
 
     fn completed_out(
         &mut self,
-        dev_addr: DeviceAddress,
-        pipe_id: crate::PipeId,
+        _dev_addr: DeviceAddress,
+        _pipe_id: crate::PipeId,
         _data: &mut [u8],
     ) {
         todo!()
@@ -413,9 +560,19 @@ impl<B: HostBus, const MAX_HUBS: usize> Driver<B> for HubDriver<MAX_HUBS> {
     ) {
         if let Some(device) = self.find_device(dev_addr) {
             if device.control_state != ControlState::Idle {
-                error!("Stall received, aborting control state {}", device.control_state);
+                error!("Stall received, aborting control state {:?}", device.control_state);
+            }
+            self.events.push(HubEvent::Stall(dev_addr));
+        }
+    }
+
+    fn transfer_failed(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, error: TransferError) {
+        if let Some(device) = self.find_device(dev_addr) {
+            if pipe_id == device.control_pipe && device.control_state != ControlState::Idle {
+                warn!("Hub control request failed in state {:?}", device.control_state);
+                device.control_state = ControlState::Idle;
+                self.events.push(HubEvent::ControlFailed(dev_addr, error));
             }
-            self.event = Some(HubEvent::Stall(dev_addr));
         }
     }
 }
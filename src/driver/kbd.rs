@@ -1,14 +1,18 @@
-use super::Driver;
+use super::{DiscoveryInterest, Driver, EventQueue, HasEvents, EVENT_QUEUE_DEPTH};
 use crate::bus::HostBus;
 use crate::descriptor;
-use crate::types::{ConnectionSpeed, DeviceAddress, SetupPacket, TransferType};
-use crate::{ControlError, PipeId, UsbHost};
+use crate::hid::{self, Protocol};
+use crate::types::{ConnectionSpeed, DeviceAddress, InterfaceSet, SetupPacket, TransferType};
+use crate::{ControlError, PipeId, TransferError, UsbHost};
 use core::num::NonZeroU8;
 use usb_device::{
     control::{Recipient, RequestType},
     UsbDirection,
 };
 
+pub mod keys;
+pub mod report;
+
 /// Driver for boot keyboards
 ///
 /// By default, up to 8 connected keyboards can be handled. Events are reported for
@@ -18,18 +22,69 @@ use usb_device::{
 ///
 /// Note: the number of devices that can be handled also depends on [`UsbHost`] which limits the number of pipes that can be created.
 ///   Each connected keyboard requires two pipes: a control pipe and an interrupt pipe.
-pub struct KbdDriver<const MAX_DEVICES: usize = 8> {
+///
+/// Keyboards whose reports don't follow the boot protocol layout (e.g. N-key rollover keyboards) are handled by
+/// [`report::KbdReportDriver`] instead.
+///
+/// # Injection protection
+///
+/// Devices posing as keyboards can inject keystrokes far faster than a human can type. To detect (and throttle) this,
+/// a limit for the number of input reports per second can be set with [`KbdDriver::set_rate_limit`]. Reports exceeding
+/// the limit are dropped, and [`KbdEvent::RateLimitExceeded`] is emitted once per second while the limit is exceeded.
+/// Input reports which cannot come from a real keyboard (the same key reported as pressed twice) are dropped as well.
+///
+/// The number of dropped reports is available via [`KbdDriver::dropped_reports`].
+///
+/// Rate limiting requires [`KbdDriver::poll`] to be called after every call to `usb_host.poll(...)`, to keep track of time.
+///
+/// # Setup
+///
+/// Keyboards start out in report protocol, in which the layout of input reports is not necessarily the one of the boot
+/// protocol. After configuration, the driver therefore switches each keyboard to the boot protocol (with a SET_PROTOCOL
+/// request). Input reports received before that request completed are ignored. The protocol can be changed later on with
+/// [`KbdDriver::set_protocol`], and queried with [`KbdDriver::get_protocol`].
+///
+/// A keyboard only sends an input report when a key is pressed or released, so keys (and modifiers) that are already
+/// held down while the keyboard is configured would go unnoticed. With [`KbdDriver::set_initial_sync`], the driver reads
+/// the current input report with a GET_REPORT request after switching the protocol, and emits it as [`KbdEvent::InputChanged`].
+///
+/// Since transfers cannot be initiated from within driver callbacks, these requests are sent from [`KbdDriver::poll`],
+/// which must be called after every call to `usb_host.poll(...)`.
+///
+/// # LED output
+///
+/// The LEDs of a keyboard are set with [`KbdDriver::set_led`]. If the keyboard interface has an interrupt OUT endpoint,
+/// the output report is sent through it, and the control pipe stays free for other requests. Otherwise the report
+/// is sent with a SET_REPORT control request.
+///
+/// Note: each keyboard with an interrupt OUT endpoint requires a third pipe.
+///
+/// # Report size
+///
+/// Some keyboards send input reports which are longer than the 8 bytes of the boot protocol (e.g. with extra bits
+/// for media keys). The first 8 bytes are still interpreted as an [`InputReport`], and up to `MAX_REPORT_SIZE` bytes
+/// are received in total. The remaining bytes are available via [`KbdDriver::report_suffix`].
+#[derive(Debug)]
+pub struct KbdDriver<const MAX_DEVICES: usize = 8, const MAX_REPORT_SIZE: usize = 16> {
     devices: [Option<KbdDevice>; MAX_DEVICES],
-    event: Option<KbdEvent>,
+    /// Most recent input report of each device (indexed like `devices`), and its length
+    reports: [([u8; MAX_REPORT_SIZE], usize); MAX_DEVICES],
+    events: EventQueue<KbdEvent, EVENT_QUEUE_DEPTH>,
+    /// Maximum number of input reports per second, for each device
+    rate_limit: Option<u16>,
+    /// Frame count, as of the last call to `poll`
+    now: u32,
+    /// Read the input report of each keyboard after configuration
+    initial_sync: bool,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 struct KbdDevice {
     device_address: DeviceAddress,
     inner: KbdDeviceInner,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 enum KbdDeviceInner {
     Pending(PendingKbdDevice),
     Configured(ConfiguredKbdDevice),
@@ -41,25 +96,100 @@ impl KbdDeviceInner {
             config: None,
             interface: None,
             endpoint: None,
+            max_packet_size: None,
             interval: None,
+            out_endpoint: None,
+            in_interface: false,
         })
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 struct PendingKbdDevice {
     config: Option<u8>,
     interface: Option<u8>,
     endpoint: Option<u8>,
+    max_packet_size: Option<u16>,
     interval: Option<u8>,
+    /// Interrupt OUT endpoint of the interface (if any): number, maximum packet size and interval
+    out_endpoint: Option<(u8, u16, u8)>,
+    /// Set while the endpoint descriptors following the keyboard interface descriptor are being processed
+    in_interface: bool,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 struct ConfiguredKbdDevice {
     interface: u8,
     control_pipe: PipeId,
     interrupt_pipe: PipeId,
+    /// Interrupt OUT pipe for the output report, if the keyboard has an OUT endpoint
+    output_pipe: Option<PipeId>,
     output_report: u8,
+    rate: ReportRate,
+    dropped_reports: u32,
+    /// Next step of the setup after configuration
+    setup: SetupStep,
+    /// Request initiated by the driver, whose response is handled by the driver itself
+    request: Option<KbdRequest>,
+    /// Protocol the keyboard is using, if known
+    protocol: Option<Protocol>,
+}
+
+/// Steps performed by the driver after a keyboard was configured
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum SetupStep {
+    /// Switch to the boot protocol
+    SetProtocol,
+    /// Read the current input report
+    Sync,
+    Done,
+}
+
+/// Control requests which are in progress on the control pipe of a keyboard
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum KbdRequest {
+    SetProtocol(Protocol),
+    GetProtocol,
+    GetReport,
+}
+
+/// Number of frames over which input reports are counted for rate limiting
+const RATE_WINDOW_FRAMES: u32 = 1000;
+
+/// Counts input reports within a window of one second
+#[derive(Copy, Clone, Default, Debug)]
+struct ReportRate {
+    window_start: u32,
+    reports: u16,
+    exceeded: bool,
+}
+
+enum RateCheck {
+    Allowed,
+    /// The limit was exceeded for the first time within the current window
+    Exceeded,
+    /// The limit was already exceeded within the current window
+    StillExceeded,
+}
+
+impl ReportRate {
+    fn check(&mut self, now: u32, limit: u16) -> RateCheck {
+        if now.wrapping_sub(self.window_start) >= RATE_WINDOW_FRAMES {
+            *self = ReportRate {
+                window_start: now,
+                ..Default::default()
+            };
+        }
+        self.reports = self.reports.saturating_add(1);
+        if self.reports <= limit {
+            RateCheck::Allowed
+        } else if !self.exceeded {
+            self.exceeded = true;
+            RateCheck::Exceeded
+        } else {
+            RateCheck::StillExceeded
+        }
+    }
 }
 
 impl PendingKbdDevice {
@@ -70,17 +200,21 @@ impl PendingKbdDevice {
     /// - an IN interrupt endpoint
     fn supported_config(&self) -> Option<u8> {
         self.interface
-            .and_then(|_| self.endpoint)
-            .and_then(|_| self.interval)
-            .and_then(|_| self.config)
+            .and(self.endpoint)
+            .and(self.interval)
+            .and(self.config)
     }
 }
 
 /// Represents an input report, received from a keyboard
 ///
 /// The input report describes which keys are currently pressed.
-#[derive(Copy, Clone, defmt::Format)]
-#[repr(packed)]
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+// `C`, because reports are cast from the received bytes (see `TryFrom<&[u8]>`), so the fields have to be in the order of
+// the boot protocol report. With `packed` alone, the compiler is free to reorder them.
+#[repr(C, packed)]
 pub struct InputReport {
     /// Status of modifier keys
     pub modifier_status: ModifierStatus,
@@ -96,14 +230,35 @@ impl InputReport {
             .filter_map(|opt| *opt)
             .map(|code| code.into())
     }
+
+    /// Checks whether the report could have been sent by an actual keyboard
+    ///
+    /// A key can only be reported as pressed once.
+    pub fn is_plausible(&self) -> bool {
+        let keys = self.keypress;
+        keys.iter()
+            .enumerate()
+            .all(|(i, key)| key.is_none() || !keys[i + 1..].contains(key))
+    }
 }
 
+/// Size of an input report in the boot protocol
+const BOOT_REPORT_SIZE: usize = 8;
+
+/// Size of the output report (LED state) in the boot protocol
+const OUTPUT_REPORT_SIZE: u16 = 1;
+
+/// Interprets the first 8 bytes of an input report
+///
+/// Longer reports are accepted, the extra bytes are ignored.
 impl<'a> TryFrom<&'a [u8]> for &'a InputReport {
     type Error = ();
 
     fn try_from(value: &'a [u8]) -> Result<Self, Self::Error> {
-        if value.len() == 8 && core::mem::size_of::<InputReport>() == 8 {
-            // Safety: we have verified that the InputReport struct and the provided value have the expected size
+        let value = value.get(..BOOT_REPORT_SIZE).ok_or(())?;
+        if core::mem::size_of::<InputReport>() == BOOT_REPORT_SIZE {
+            // Safety: we have verified that the InputReport struct and the provided value have the expected size. Its layout
+            //   matches the report, since it is `repr(C, packed)`, and any byte is a valid value for each of its fields.
             Ok(unsafe { &*(value as *const _ as *const InputReport) })
         } else {
             Err(())
@@ -111,7 +266,10 @@ impl<'a> TryFrom<&'a [u8]> for &'a InputReport {
     }
 }
 
-#[derive(Debug, Copy, Clone, defmt::Format)]
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[repr(transparent)]
 pub struct ModifierStatus(u8);
 
 impl ModifierStatus {
@@ -161,7 +319,9 @@ impl ModifierStatus {
 }
 
 /// Events related to attached keyboard(s)
-#[derive(Copy, Clone, defmt::Format)]
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum KbdEvent {
     /// A new keyboard was detected & configured, with given device address
     DeviceAdded(DeviceAddress),
@@ -176,12 +336,20 @@ pub enum KbdEvent {
 
     /// A control transfer has completed.
     ///
-    /// Control transfers are initiated by the [`KbdDriver::set_idle`] and [`KbdDriver::set_led`] methods.
+    /// Control transfers are initiated by the [`KbdDriver::set_idle`], [`KbdDriver::set_led`] and [`KbdDriver::set_protocol`] methods.
     ControlComplete(DeviceAddress),
+
+    /// The device sent more input reports than allowed by the rate limit
+    ///
+    /// Reported at most once per second. See [`KbdDriver::set_rate_limit`].
+    RateLimitExceeded(DeviceAddress),
+
+    /// The device reported the protocol it is using, in response to [`KbdDriver::get_protocol`]
+    Protocol(DeviceAddress, Protocol),
 }
 
 /// Identifies the five LEDs that a boot keyboard can support
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 #[repr(u8)]
 pub enum KbdLed {
     NumLock = 0,
@@ -192,7 +360,7 @@ pub enum KbdLed {
 }
 
 /// Error type for interactions with the driver
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub enum KbdError {
     /// Error initiating control transfer
     ControlError(ControlError),
@@ -209,23 +377,178 @@ impl From<ControlError> for KbdError {
     }
 }
 
-impl<const MAX_DEVICES: usize> KbdDriver<MAX_DEVICES> {
+impl<const MAX_DEVICES: usize, const MAX_REPORT_SIZE: usize> Default for KbdDriver<MAX_DEVICES, MAX_REPORT_SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const MAX_DEVICES: usize, const MAX_REPORT_SIZE: usize> KbdDriver<MAX_DEVICES, MAX_REPORT_SIZE> {
     pub fn new() -> Self {
+        const { assert!(MAX_REPORT_SIZE >= BOOT_REPORT_SIZE, "MAX_REPORT_SIZE must be at least 8") };
         Self {
             devices: [None; MAX_DEVICES],
-            event: None,
+            reports: [([0; MAX_REPORT_SIZE], 0); MAX_DEVICES],
+            events: EventQueue::new(),
+            rate_limit: None,
+            now: 0,
+            initial_sync: false,
         }
     }
 
-    /// Returns the last keyboard event that occurred (if any) and clears it.
+    /// Read the current input report from each keyboard, right after it was configured
+    ///
+    /// Disabled by default. When enabled, the driver sends a GET_REPORT request for the input report to each newly
+    /// configured keyboard (once it was switched to the boot protocol), and emits the result as [`KbdEvent::InputChanged`].
+    /// This way keys and modifiers which are held down while the keyboard is connected are known right away.
+    ///
+    /// If the keyboard does not support the request, the step is skipped.
+    /// The LED state is not affected: it starts out with all LEDs off, until changed with [`KbdDriver::set_led`].
+    pub fn set_initial_sync(&mut self, enable: bool) {
+        self.initial_sync = enable;
+    }
+
+    /// Switch the given device to the given protocol
     ///
-    /// This method should be called directly after calling `usb_host.poll(...)`.
+    /// Once done, [`KbdEvent::ControlComplete`] is emitted. The driver only understands the boot protocol, which is selected
+    /// automatically after configuration. In report protocol, input reports are still interpreted as boot reports, which
+    /// only works if the keyboard uses a compatible layout.
+    pub fn set_protocol<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    >(
+        &mut self,
+        dev_addr: DeviceAddress,
+        protocol: Protocol,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) -> Result<(), KbdError> {
+        let device = self.find_configured_device(dev_addr).ok_or(KbdError::UnknownDevice)?;
+        hid::set_protocol(host, dev_addr, device.control_pipe, device.interface, protocol)?;
+        device.request = Some(KbdRequest::SetProtocol(protocol));
+        Ok(())
+    }
+
+    /// Request the protocol that the given device is currently using
     ///
-    /// Otherwise events may be lost.
+    /// The response is reported with [`KbdEvent::Protocol`]. The last known protocol is available via [`KbdDriver::protocol`].
+    pub fn get_protocol<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    >(
+        &mut self,
+        dev_addr: DeviceAddress,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) -> Result<(), KbdError> {
+        let device = self.find_configured_device(dev_addr).ok_or(KbdError::UnknownDevice)?;
+        hid::get_protocol(host, dev_addr, device.control_pipe, device.interface)?;
+        device.request = Some(KbdRequest::GetProtocol);
+        Ok(())
+    }
+
+    /// Protocol that the given device is using, if known
+    ///
+    /// Returns `None` if the device is unknown, or if it did not confirm a protocol yet.
+    pub fn protocol(&mut self, dev_addr: DeviceAddress) -> Option<Protocol> {
+        self.find_configured_device(dev_addr).and_then(|device| device.protocol)
+    }
+
+    /// Send the next setup request for the given device, if the bus is free
+    fn advance_setup<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    >(
+        device: &mut ConfiguredKbdDevice,
+        dev_addr: DeviceAddress,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) {
+        if device.request.is_some() {
+            return;
+        }
+        let (request, result) = match device.setup {
+            SetupStep::SetProtocol => (
+                KbdRequest::SetProtocol(Protocol::Boot),
+                hid::set_protocol(host, dev_addr, device.control_pipe, device.interface, Protocol::Boot),
+            ),
+            SetupStep::Sync => (
+                KbdRequest::GetReport,
+                host.control_in(
+                    Some(dev_addr),
+                    Some(device.control_pipe),
+                    SetupPacket::new(
+                        UsbDirection::In,
+                        RequestType::Class,
+                        Recipient::Interface,
+                        0x01,   // GetReport
+                        1 << 8, // 1 means "input" report
+                        device.interface as u16,
+                        BOOT_REPORT_SIZE as u16,
+                    ),
+                ),
+            ),
+            SetupStep::Done => return,
+        };
+        // if the bus is busy, try again on the next call to `poll`
+        if result.is_ok() {
+            device.request = Some(request);
+        }
+    }
+
+    /// The current setup step was completed (or failed)
+    fn setup_step_done(device: &mut ConfiguredKbdDevice, initial_sync: bool) {
+        device.setup = match device.setup {
+            SetupStep::SetProtocol if initial_sync => SetupStep::Sync,
+            _ => SetupStep::Done,
+        };
+    }
+
+    /// Limit the number of input reports accepted from each device per second
+    ///
+    /// A boot keyboard only needs to send a report when a key is pressed or released (plus idle reports, see [`KbdDriver::set_idle`]),
+    /// so a limit of e.g. 50 reports per second is not noticeable when typing. Pass `None` to disable the limit (default).
+    pub fn set_rate_limit(&mut self, max_reports_per_second: Option<u16>) {
+        self.rate_limit = max_reports_per_second;
+    }
+
+    /// Keep track of time (for rate limiting), and send the setup requests for newly configured keyboards
+    ///
+    /// Must be called after every call to `usb_host.poll(...)`.
+    pub fn poll<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    >(
+        &mut self,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) {
+        self.now = host.frame_count();
+        for device in self.devices.iter_mut().flatten() {
+            if let KbdDeviceInner::Configured(configured) = &mut device.inner {
+                Self::advance_setup(configured, device.device_address, host);
+            }
+        }
+    }
+
+    /// Number of input reports dropped for the given device, either because they exceeded the rate limit,
+    /// or because they were not plausible (see [`InputReport::is_plausible`])
+    pub fn dropped_reports(&mut self, dev_addr: DeviceAddress) -> Option<u32> {
+        self.find_configured_device(dev_addr).map(|device| device.dropped_reports)
+    }
+
+    /// Returns the oldest pending keyboard event (if any), and removes it from the queue.
+    ///
+    /// This method should be called repeatedly after calling `usb_host.poll(...)`, until it returns `None` (see
+    /// [`HasEvents`] for what happens otherwise).
     ///
     /// For the meaning of events, please refer to the [`KbdEvent`] documentation.
     pub fn take_event(&mut self) -> Option<KbdEvent> {
-        self.event.take()
+        self.events.pop()
     }
 
     /// Set interval for idle reports
@@ -240,11 +563,16 @@ impl<const MAX_DEVICES: usize> KbdDriver<MAX_DEVICES> {
     ///
     /// The USB HID specification recommends a default interval of 500ms for keyboards (duration value: 125).
     ///
-    pub fn set_idle<B: HostBus>(
+    pub fn set_idle<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    >(
         &mut self,
         dev_addr: DeviceAddress,
         latency: u8,
-        host: &mut UsbHost<B>,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
     ) -> Result<(), KbdError> {
         if let Some(device) = self.find_configured_device(dev_addr) {
             host.control_out(
@@ -274,12 +602,20 @@ impl<const MAX_DEVICES: usize> KbdDriver<MAX_DEVICES> {
     ///
     /// This method updates one of the bits in the output report (identified by [`KbdLed`]) and sents the
     /// updated report to the device.
-    pub fn set_led<B: HostBus>(
+    ///
+    /// If the keyboard has an interrupt OUT endpoint, the report is sent with the next transfer on that endpoint.
+    /// Otherwise it is sent with a SET_REPORT request, and [`KbdEvent::ControlComplete`] is emitted once done.
+    pub fn set_led<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    >(
         &mut self,
         dev_addr: DeviceAddress,
         led: KbdLed,
         on: bool,
-        host: &mut UsbHost<B>,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
     ) -> Result<(), KbdError> {
         if let Some(device) = self.find_configured_device(dev_addr) {
             if on {
@@ -287,6 +623,10 @@ impl<const MAX_DEVICES: usize> KbdDriver<MAX_DEVICES> {
             } else {
                 device.output_report &= !(1 << (led as u8));
             }
+            if device.output_pipe.is_some() {
+                // picked up by `completed_out`
+                return Ok(());
+            }
             host.control_out(
                 Some(dev_addr),
                 Some(device.control_pipe),
@@ -307,6 +647,15 @@ impl<const MAX_DEVICES: usize> KbdDriver<MAX_DEVICES> {
         }
     }
 
+    /// Bytes of the most recent input report of the given device, beyond the 8 bytes of the boot protocol
+    ///
+    /// Empty if the keyboard sends plain boot reports. Returns `None` if the device is unknown.
+    pub fn report_suffix(&self, dev_addr: DeviceAddress) -> Option<&[u8]> {
+        let index = self.devices.iter().position(|dev| matches!(dev, Some(dev) if dev.device_address == dev_addr))?;
+        let (report, len) = &self.reports[index];
+        Some(&report[BOOT_REPORT_SIZE.min(*len)..*len])
+    }
+
     fn find_device_slot(
         &mut self,
         device_address: DeviceAddress,
@@ -361,13 +710,35 @@ impl<const MAX_DEVICES: usize> KbdDriver<MAX_DEVICES> {
     }
 }
 
-impl<B: HostBus> Driver<B> for KbdDriver {
+impl<const MAX_DEVICES: usize, const MAX_REPORT_SIZE: usize> HasEvents for KbdDriver<MAX_DEVICES, MAX_REPORT_SIZE> {
+    type Event = KbdEvent;
+
+    fn take_event(&mut self) -> Option<KbdEvent> {
+        self.events.pop()
+    }
+
+    fn events_pending(&self) -> bool {
+        !self.events.is_empty()
+    }
+}
+
+impl<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+        const MAX_DEVICES: usize,
+        const MAX_REPORT_SIZE: usize,
+    > Driver<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>
+    for KbdDriver<MAX_DEVICES, MAX_REPORT_SIZE>
+{
     fn attached(&mut self, device_address: DeviceAddress, _connection_speed: ConnectionSpeed) {
-        if let Some(slot) = self.devices.iter_mut().find(|dev| dev.is_none()) {
-            slot.replace(KbdDevice {
+        if let Some(index) = self.devices.iter().position(|dev| dev.is_none()) {
+            self.devices[index] = Some(KbdDevice {
                 device_address,
                 inner: KbdDeviceInner::pending(),
             });
+            self.reports[index].1 = 0;
         } else {
             // maximum number of devices reached.
         }
@@ -380,14 +751,14 @@ impl<B: HostBus> Driver<B> for KbdDriver {
                 ..
             }) = slot.take()
             {
-                self.event = Some(KbdEvent::DeviceRemoved(device_address));
+                self.events.push(KbdEvent::DeviceRemoved(device_address));
             }
         }
     }
 
     fn descriptor(&mut self, device_address: DeviceAddress, descriptor_type: u8, data: &[u8]) {
         if let Some(device) = self.find_pending_device(device_address) {
-            if descriptor_type == descriptor::TYPE_CONFIGURATION as u8 {
+            if descriptor_type == descriptor::TYPE_CONFIGURATION {
                 if device.interface.is_none() {
                     // we only care about new configurations if we haven't already found an interface that we can handle
                     if let Ok((_, config)) = descriptor::parse::configuration_descriptor(data) {
@@ -398,23 +769,32 @@ impl<B: HostBus> Driver<B> for KbdDriver {
                     }
                 }
             } else if descriptor_type == descriptor::TYPE_INTERFACE {
+                device.in_interface = false;
                 if let Ok((_, interface)) = descriptor::parse::interface_descriptor(data) {
                     if interface.interface_class == 0x03 && // HID
                         interface.interface_sub_class == 0x01 && // boot interface
-                        interface.interface_protocol  == 0x01
+                        interface.interface_protocol  == 0x01 &&
+                        device.interface.is_none()
                     {
                         // keyboard
                         device.interface = Some(interface.interface_number);
+                        device.in_interface = true;
                     }
                 }
-            } else if descriptor_type == descriptor::TYPE_ENDPOINT {
-                if device.interface.is_some() && device.endpoint.is_none() {
-                    if let Ok((_, endpoint)) = descriptor::parse::endpoint_descriptor(data) {
-                        if endpoint.address.direction() == UsbDirection::In
-                            && endpoint.attributes.transfer_type() == TransferType::Interrupt
-                        {
-                            device.endpoint = Some(endpoint.address.number());
-                            device.interval = Some(endpoint.interval);
+            } else if descriptor_type == descriptor::TYPE_ENDPOINT && device.in_interface {
+                if let Ok((_, endpoint)) = descriptor::parse::endpoint_descriptor(data) {
+                    if endpoint.attributes.transfer_type() == TransferType::Interrupt {
+                        match endpoint.address.direction() {
+                            UsbDirection::In if device.endpoint.is_none() => {
+                                device.endpoint = Some(endpoint.address.number());
+                                device.max_packet_size = Some(endpoint.max_packet_size);
+                                device.interval = Some(endpoint.interval);
+                            }
+                            UsbDirection::Out if device.out_endpoint.is_none() => {
+                                device.out_endpoint =
+                                    Some((endpoint.address.number(), endpoint.max_packet_size, endpoint.interval));
+                            }
+                            _ => {}
                         }
                     }
                 }
@@ -422,6 +802,15 @@ impl<B: HostBus> Driver<B> for KbdDriver {
         }
     }
 
+    fn discovery_interest(&mut self, device_address: DeviceAddress) -> DiscoveryInterest {
+        match self.find_pending_device(device_address) {
+            None => DiscoveryInterest::NotInterested,
+            // the first keyboard interface wins, later configurations would not change the choice
+            Some(device) if device.supported_config().is_some() => DiscoveryInterest::Ready,
+            Some(_) => DiscoveryInterest::Undecided,
+        }
+    }
+
     fn configure(&mut self, device_address: DeviceAddress) -> Option<u8> {
         // We choose a configuration only if we found an interface that we can handle
         let config = self
@@ -436,32 +825,77 @@ impl<B: HostBus> Driver<B> for KbdDriver {
         config
     }
 
-    fn configured(&mut self, device_address: DeviceAddress, value: u8, host: &mut UsbHost<B>) {
+    fn claim_interfaces(&mut self, device_address: DeviceAddress, value: u8) -> InterfaceSet {
+        match self.find_pending_device(device_address) {
+            Some(device) if device.supported_config() == Some(value) => {
+                // Unwrap safety: supported_config() verifies there is a value
+                InterfaceSet::single(device.interface.unwrap())
+            }
+            _ => InterfaceSet::EMPTY,
+        }
+    }
+
+    fn configured(
+        &mut self,
+        device_address: DeviceAddress,
+        value: u8,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) {
+        let interfaces = <Self as Driver<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>>::claim_interfaces(
+            self, device_address, value,
+        );
+        self.configured_interfaces(device_address, value, interfaces, host);
+    }
+
+    fn configured_interfaces(
+        &mut self,
+        device_address: DeviceAddress,
+        value: u8,
+        interfaces: InterfaceSet,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) {
         let configured_device = if let Some(device) = self.find_pending_device(device_address) {
             if let Some(config) = device.supported_config() {
+                // Unwrap safety: supported_config() verifies there is a value
+                let interface = device.interface.unwrap();
                 if value != config {
                     // a different configuration was selected for this device. We can't handle it (probably).
                     None
+                } else if !interfaces.contains(interface) {
+                    // the interface was claimed by another driver
+                    None
                 } else {
-                    // Unwrap safety: supported_config() verifies there is a value
-                    let interface = device.interface.unwrap();
                     let control_pipe = host.create_control_pipe(device_address);
                     let interrupt_pipe = host.create_interrupt_pipe(
                         device_address,
                         // Unwrap safety: supported_config() verifies there is a value
                         device.endpoint.unwrap(),
                         UsbDirection::In,
-                        8,
+                        device
+                            .max_packet_size
+                            .map_or(BOOT_REPORT_SIZE, |size| size as usize)
+                            .clamp(BOOT_REPORT_SIZE, MAX_REPORT_SIZE) as u16,
                         // Unwrap safety: supported_config() verifies there is a value
                         device.interval.unwrap(),
                     );
-                    self.event = Some(KbdEvent::DeviceAdded(device_address));
+                    // The boot protocol output report is a single byte. If no pipe can be created for it,
+                    // the report is sent via the control pipe instead.
+                    let output_pipe = device.out_endpoint.and_then(|(endpoint, _, interval)| {
+                        host.create_interrupt_pipe(device_address, endpoint, UsbDirection::Out, OUTPUT_REPORT_SIZE, interval)
+                    });
+                    self.events.push(KbdEvent::DeviceAdded(device_address));
                     match (control_pipe, interrupt_pipe) {
                         (Some(control_pipe), Some(interrupt_pipe)) => Some(ConfiguredKbdDevice {
                             interface,
                             control_pipe,
                             interrupt_pipe,
+                            output_pipe,
                             output_report: 0,
+                            rate: ReportRate::default(),
+                            dropped_reports: 0,
+                            setup: SetupStep::SetProtocol,
+                            request: None,
+                            protocol: None,
                         }),
                         _ => None,
                     }
@@ -483,6 +917,8 @@ impl<B: HostBus> Driver<B> for KbdDriver {
                     device_address,
                     inner: KbdDeviceInner::Configured(configured_device),
                 });
+            // Unwrap safety: the device was configured above
+            Self::advance_setup(self.find_configured_device(device_address).unwrap(), device_address, host);
         } else {
             self.remove_device(device_address);
         }
@@ -491,18 +927,94 @@ impl<B: HostBus> Driver<B> for KbdDriver {
     fn completed_control(
         &mut self,
         dev_addr: DeviceAddress,
-        _pipe_id: PipeId,
-        _data: Option<&[u8]>,
+        pipe_id: PipeId,
+        data: Option<&[u8]>,
     ) {
-        self.event = Some(KbdEvent::ControlComplete(dev_addr));
+        let initial_sync = self.initial_sync;
+        let index = self.devices.iter().position(|dev| matches!(dev, Some(dev) if dev.device_address == dev_addr));
+        let Some(Some(KbdDevice { inner: KbdDeviceInner::Configured(device), .. })) = index.map(|i| &mut self.devices[i]) else {
+            self.events.push(KbdEvent::ControlComplete(dev_addr));
+            return;
+        };
+        if pipe_id != device.control_pipe {
+            self.events.push(KbdEvent::ControlComplete(dev_addr));
+            return;
+        }
+        let data = data.unwrap_or(&[]);
+        let event = match device.request.take() {
+            Some(KbdRequest::SetProtocol(protocol)) => {
+                device.protocol = Some(protocol);
+                if device.setup == SetupStep::SetProtocol {
+                    Self::setup_step_done(device, initial_sync);
+                    None
+                } else {
+                    Some(KbdEvent::ControlComplete(dev_addr))
+                }
+            }
+            Some(KbdRequest::GetProtocol) => match data.first().copied().and_then(Protocol::from_value) {
+                Some(protocol) => {
+                    device.protocol = Some(protocol);
+                    Some(KbdEvent::Protocol(dev_addr, protocol))
+                }
+                None => None,
+            },
+            Some(KbdRequest::GetReport) => {
+                Self::setup_step_done(device, initial_sync);
+                let converted: Result<&InputReport, _> = data.try_into();
+                match converted {
+                    Ok(input_report) if input_report.is_plausible() => {
+                        // Unwrap safety: `index` is set, since the device was found
+                        let (report, len) = &mut self.reports[index.unwrap()];
+                        *len = data.len().min(MAX_REPORT_SIZE);
+                        report[..*len].copy_from_slice(&data[..*len]);
+                        Some(KbdEvent::InputChanged(dev_addr, *input_report))
+                    }
+                    // the keyboard sent something unexpected. Leave it to regular input reports.
+                    _ => None,
+                }
+            }
+            None => Some(KbdEvent::ControlComplete(dev_addr)),
+        };
+        if let Some(event) = event {
+            self.events.push(event);
+        }
+    }
+
+    fn transfer_failed(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, _error: TransferError) {
+        let initial_sync = self.initial_sync;
+        if let Some(device) = self.find_configured_device(dev_addr) {
+            if pipe_id == device.control_pipe && device.request.take().is_some() && device.setup != SetupStep::Done {
+                // SET_PROTOCOL and GET_REPORT are not supported by all keyboards. Continue without them.
+                Self::setup_step_done(device, initial_sync);
+            }
+        }
     }
 
     fn completed_in(&mut self, device_address: DeviceAddress, pipe: PipeId, data: &[u8]) {
-        if let Some(device) = self.find_configured_device(device_address) {
-            if pipe == device.interrupt_pipe {
+        let (now, rate_limit) = (self.now, self.rate_limit);
+        let index = self.devices.iter().position(|dev| matches!(dev, Some(dev) if dev.device_address == device_address));
+        if let Some(Some(KbdDevice { inner: KbdDeviceInner::Configured(device), .. })) = index.map(|i| &mut self.devices[i]) {
+            if pipe == device.interrupt_pipe && device.setup != SetupStep::SetProtocol {
                 let converted: Result<&InputReport, _> = data.try_into();
                 if let Ok(input_report) = converted {
-                    self.event = Some(KbdEvent::InputChanged(device_address, *input_report));
+                    let check = rate_limit.map_or(RateCheck::Allowed, |limit| device.rate.check(now, limit));
+                    if !matches!(check, RateCheck::Allowed) || !input_report.is_plausible() {
+                        device.dropped_reports = device.dropped_reports.saturating_add(1);
+                    }
+                    let event = match check {
+                        RateCheck::Allowed if input_report.is_plausible() => {
+                            // Unwrap safety: `index` is set, since the device was found
+                            let (report, len) = &mut self.reports[index.unwrap()];
+                            *len = data.len().min(MAX_REPORT_SIZE);
+                            report[..*len].copy_from_slice(&data[..*len]);
+                            Some(KbdEvent::InputChanged(device_address, *input_report))
+                        }
+                        RateCheck::Exceeded => Some(KbdEvent::RateLimitExceeded(device_address)),
+                        _ => None,
+                    };
+                    if let Some(event) = event {
+                        self.events.push(event);
+                    }
                 }
             }
         }
@@ -510,10 +1022,95 @@ impl<B: HostBus> Driver<B> for KbdDriver {
 
     fn completed_out(
         &mut self,
-        _device_address: DeviceAddress,
-        _pipe_id: PipeId,
-        _data: &mut [u8],
+        device_address: DeviceAddress,
+        pipe_id: PipeId,
+        data: &mut [u8],
     ) {
-        // ignored, since there are no OUT pipes in use.
+        if let Some(device) = self.find_configured_device(device_address) {
+            if device.output_pipe == Some(pipe_id) {
+                if let Some(first) = data.first_mut() {
+                    *first = device.output_report;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::mock::fixtures::enumerated_keyboard;
+
+    #[test]
+    fn test_report_rate() {
+        let mut rate = ReportRate::default();
+        assert!(matches!(rate.check(0, 2), RateCheck::Allowed));
+        assert!(matches!(rate.check(10, 2), RateCheck::Allowed));
+        assert!(matches!(rate.check(20, 2), RateCheck::Exceeded));
+        assert!(matches!(rate.check(30, 2), RateCheck::StillExceeded));
+        // new window
+        assert!(matches!(rate.check(1000, 2), RateCheck::Allowed));
+    }
+
+    #[test]
+    fn test_long_report() {
+        let data = [0x02, 0, 4, 0, 0, 0, 0, 0, 0xAA, 0xBB];
+        let report: &InputReport = data[..].try_into().unwrap();
+        assert!(report.modifier_status.left_shift());
+        assert_eq!(report.pressed_keys().next(), Some(4));
+
+        let short: Result<&InputReport, _> = data[..7].try_into();
+        assert!(short.is_err());
+    }
+
+    #[test]
+    fn test_plausible_report() {
+        let report: &InputReport = [0, 0, 4, 5, 0, 0, 0, 0][..].try_into().unwrap();
+        assert!(report.is_plausible());
+        let report: &InputReport = [0, 0, 4, 5, 4, 0, 0, 0][..].try_into().unwrap();
+        assert!(!report.is_plausible());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_event() {
+        let mut buf = [0; 16];
+        let report: &InputReport = [2, 0, 4, 5, 0, 0, 0, 0][..].try_into().unwrap();
+        let event = KbdEvent::InputChanged(DeviceAddress(NonZeroU8::new(3).unwrap()), *report);
+        let encoded = postcard::to_slice(&event, &mut buf).unwrap();
+        match postcard::from_bytes(encoded).unwrap() {
+            KbdEvent::InputChanged(address, report) => {
+                assert_eq!(u8::from(address), 3);
+                assert!(report.pressed_keys().eq([4, 5]));
+            }
+            other => panic!("unexpected event: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_enumerate_keyboard() {
+        let (mut host, mut kbd, dev_addr) = enumerated_keyboard();
+        assert!(matches!(kbd.take_event(), Some(KbdEvent::DeviceAdded(added)) if added == dev_addr));
+        assert_eq!(host.bus().configuration(), Some(1));
+        assert_eq!(host.device_list().count(), 1);
+        let entry = host.device_list().next().unwrap();
+        assert!(entry.address == dev_addr && entry.hub_port.is_none());
+        assert!(entry.phase == crate::DevicePhase::Configured);
+        assert_eq!(entry.configuration, Some(1));
+        assert_eq!(host.active_configuration(dev_addr), Some(1));
+        assert!(host.bus().interrupt_pipe_device(1) == Some(dev_addr));
+        // the keyboard was switched to the boot protocol
+        assert_eq!(host.bus().last_setup().map(|setup| setup.request), Some(0x0b));
+
+        assert!(host.bus().send_interrupt(1, &[0x02, 0, 0x04, 0, 0, 0, 0, 0]));
+        host.poll(&mut [&mut kbd]);
+        match kbd.take_event() {
+            Some(KbdEvent::InputChanged(addr, report)) => {
+                assert!(addr == dev_addr);
+                assert!(report.modifier_status.left_shift());
+                assert!(report.pressed_keys().eq([0x04]));
+            }
+            _ => panic!("expected input report"),
+        }
     }
 }
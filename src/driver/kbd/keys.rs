@@ -0,0 +1,245 @@
+//! Individual key events, and translation of key codes to characters
+//!
+//! [`KbdEvent::InputChanged`](super::KbdEvent::InputChanged) reports the full set of pressed keys. A [`KeyTracker`]
+//! remembers the previous report of a keyboard, and turns each new report into [`KeyEvent`]s for the keys that were
+//! pressed or released. A [`Keymap`] translates key codes into characters, according to a keyboard layout.
+//!
+//! Example:
+//! ```ignore
+//! let mut tracker = KeyTracker::new();
+//!
+//! if let Some(KbdEvent::InputChanged(_, report)) = kbd_driver.take_event() {
+//!     for event in tracker.update(&report) {
+//!         if let KeyEvent::Pressed(code) = event {
+//!             if let Some(c) = UsQwerty.translate(code, report.modifier_status, Locks::default()) {
+//!                 // ...
+//!             }
+//!         }
+//!     }
+//! }
+//! ```
+
+use super::{InputReport, ModifierStatus};
+use core::num::NonZeroU8;
+
+/// Key code of the first modifier key (left `Ctrl`). The modifiers are numbered in the order of the bits in [`ModifierStatus`].
+pub const KEY_LEFT_CTRL: u8 = 0xe0;
+
+/// Key codes below this value report errors (e.g. "too many keys pressed"), rather than keys
+const FIRST_KEY: u8 = 0x04;
+/// Key code reported in all slots if too many keys are pressed at once
+const ERROR_ROLL_OVER: u8 = 0x01;
+
+/// Maximum number of events caused by a single report: all keys & modifiers released, and as many pressed
+const MAX_CHANGES: usize = 2 * (6 + 8);
+
+/// A key was pressed or released
+///
+/// Modifier keys are reported like other keys, with codes `0xe0` (left `Ctrl`) to `0xe7` (right `Gui`).
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum KeyEvent {
+    Pressed(u8),
+    Released(u8),
+}
+
+/// Remembers the keys pressed on a keyboard, to detect which keys changed
+///
+/// One tracker is needed per keyboard.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct KeyTracker {
+    keys: [Option<NonZeroU8>; 6],
+    modifiers: u8,
+}
+
+impl KeyTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forget the pressed keys, e.g. after the keyboard was removed
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Returns `true` if the given key is currently pressed
+    pub fn is_pressed(&self, code: u8) -> bool {
+        match code.checked_sub(KEY_LEFT_CTRL) {
+            Some(bit) if bit < 8 => self.modifiers & (1 << bit) != 0,
+            _ => self.keys.iter().flatten().any(|key| key.get() == code),
+        }
+    }
+
+    /// Compare the given report to the previous one, and return the keys that were released and pressed (in this order)
+    ///
+    /// Reports indicating an error (too many keys pressed at once) are ignored, since they do not contain the actual keys.
+    pub fn update(&mut self, report: &InputReport) -> KeyChanges {
+        let mut changes = KeyChanges::default();
+        if report.keypress.iter().flatten().any(|key| key.get() == ERROR_ROLL_OVER) {
+            return changes;
+        }
+        let keys = report.keypress.map(|key| key.filter(|key| key.get() >= FIRST_KEY));
+        let modifiers = report.modifier_status.0;
+
+        for bit in 0..8 {
+            if self.modifiers & !modifiers & (1 << bit) != 0 {
+                changes.push(KeyEvent::Released(KEY_LEFT_CTRL + bit));
+            }
+        }
+        for key in self.keys.iter().flatten() {
+            if !keys.contains(&Some(*key)) {
+                changes.push(KeyEvent::Released(key.get()));
+            }
+        }
+        for bit in 0..8 {
+            if !self.modifiers & modifiers & (1 << bit) != 0 {
+                changes.push(KeyEvent::Pressed(KEY_LEFT_CTRL + bit));
+            }
+        }
+        for key in keys.iter().flatten() {
+            if !self.keys.contains(&Some(*key)) {
+                changes.push(KeyEvent::Pressed(key.get()));
+            }
+        }
+
+        self.keys = keys;
+        self.modifiers = modifiers;
+        changes
+    }
+}
+
+/// Key events resulting from a single report, see [`KeyTracker::update`]
+#[derive(Copy, Clone, Default, Debug)]
+pub struct KeyChanges {
+    events: [Option<KeyEvent>; MAX_CHANGES],
+    len: usize,
+    next: usize,
+}
+
+impl KeyChanges {
+    fn push(&mut self, event: KeyEvent) {
+        if let Some(slot) = self.events.get_mut(self.len) {
+            *slot = Some(event);
+            self.len += 1;
+        }
+    }
+}
+
+impl Iterator for KeyChanges {
+    type Item = KeyEvent;
+
+    fn next(&mut self) -> Option<KeyEvent> {
+        let event = self.events[..self.len].get(self.next).copied().flatten();
+        self.next += 1;
+        event
+    }
+}
+
+/// State of the lock keys, which affects the translation of some keys
+///
+/// Keyboards do not keep track of this themselves: the host toggles the state when the lock key is pressed, and
+/// reports it to the keyboard via the LEDs (see [`KbdDriver::set_led`](super::KbdDriver::set_led)).
+#[derive(Copy, Clone, PartialEq, Default, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Locks {
+    pub caps_lock: bool,
+    pub num_lock: bool,
+}
+
+/// Translates key codes into characters, according to a keyboard layout
+pub trait Keymap {
+    /// Returns the character produced by the given key, or `None` if the key does not produce a character
+    /// (e.g. function keys, cursor keys and modifiers).
+    ///
+    /// Modifiers other than `Shift` (and `AltGr` for some layouts) are not taken into account, i.e. `Ctrl+C` is translated
+    /// to `'c'`. Handling shortcuts is up to the application.
+    fn translate(&self, code: u8, modifiers: ModifierStatus, locks: Locks) -> Option<char>;
+}
+
+/// US English (QWERTY) keyboard layout
+///
+/// Besides printable characters, `Enter` is translated to `'\n'`, `Tab` to `'\t'`, `Backspace` to `'\x08'` and
+/// `Escape` to `'\x1b'`. Keypad digits only produce characters while `Num Lock` is active.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct UsQwerty;
+
+/// Characters for codes `0x1e` (`1`) to `0x38` (`/`), without and with `Shift`
+const US_SYMBOLS: &[(char, char)] = &[
+    ('1', '!'), ('2', '@'), ('3', '#'), ('4', '$'), ('5', '%'), ('6', '^'), ('7', '&'), ('8', '*'), ('9', '('), ('0', ')'),
+    ('\n', '\n'), ('\x1b', '\x1b'), ('\x08', '\x08'), ('\t', '\t'), (' ', ' '),
+    ('-', '_'), ('=', '+'), ('[', '{'), (']', '}'), ('\\', '|'), ('#', '~'), (';', ':'), ('\'', '"'), ('`', '~'),
+    (',', '<'), ('.', '>'), ('/', '?'),
+];
+
+impl Keymap for UsQwerty {
+    fn translate(&self, code: u8, modifiers: ModifierStatus, locks: Locks) -> Option<char> {
+        let shift = modifiers.left_shift() || modifiers.right_shift();
+        match code {
+            // a - z
+            0x04..=0x1d => {
+                let c = (b'a' + code - 0x04) as char;
+                Some(if shift != locks.caps_lock { c.to_ascii_uppercase() } else { c })
+            }
+            0x1e..=0x38 => US_SYMBOLS.get((code - 0x1e) as usize).map(|(plain, shifted)| if shift { *shifted } else { *plain }),
+            // keypad
+            0x54 => Some('/'),
+            0x55 => Some('*'),
+            0x56 => Some('-'),
+            0x57 => Some('+'),
+            0x58 => Some('\n'),
+            0x59..=0x61 if locks.num_lock => Some((b'1' + code - 0x59) as char),
+            0x62 if locks.num_lock => Some('0'),
+            0x63 if locks.num_lock => Some('.'),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(modifiers: u8, keys: &[u8]) -> InputReport {
+        let mut data = [0u8; 8];
+        data[0] = modifiers;
+        data[2..2 + keys.len()].copy_from_slice(keys);
+        let report: &InputReport = data[..].try_into().unwrap();
+        *report
+    }
+
+    #[test]
+    fn test_key_changes() {
+        let mut tracker = KeyTracker::new();
+        let mut changes = tracker.update(&report(0b10, &[0x04]));
+        assert!(changes.next() == Some(KeyEvent::Pressed(0xe1)));
+        assert!(changes.next() == Some(KeyEvent::Pressed(0x04)));
+        assert!(changes.next().is_none());
+        assert!(tracker.is_pressed(0xe1) && tracker.is_pressed(0x04));
+
+        // 'a' released, 'b' pressed
+        let mut changes = tracker.update(&report(0b10, &[0x05]));
+        assert!(changes.next() == Some(KeyEvent::Released(0x04)));
+        assert!(changes.next() == Some(KeyEvent::Pressed(0x05)));
+        assert!(changes.next().is_none());
+
+        // roll over error is ignored
+        assert!(tracker.update(&report(0, &[1, 1, 1, 1, 1, 1])).next().is_none());
+        assert!(tracker.is_pressed(0x05));
+    }
+
+    #[test]
+    fn test_us_qwerty() {
+        let none = ModifierStatus(0);
+        let shift = ModifierStatus(0b10);
+        let caps = Locks { caps_lock: true, num_lock: false };
+        assert!(UsQwerty.translate(0x04, none, Locks::default()) == Some('a'));
+        assert!(UsQwerty.translate(0x04, shift, Locks::default()) == Some('A'));
+        assert!(UsQwerty.translate(0x04, none, caps) == Some('A'));
+        assert!(UsQwerty.translate(0x04, shift, caps) == Some('a'));
+        assert!(UsQwerty.translate(0x1f, shift, caps) == Some('@'));
+        assert!(UsQwerty.translate(0x38, shift, Locks::default()) == Some('?'));
+        assert!(UsQwerty.translate(0x59, none, Locks::default()).is_none());
+        assert!(UsQwerty.translate(0x59, none, Locks { caps_lock: false, num_lock: true }) == Some('1'));
+        assert!(UsQwerty.translate(0x3a, none, Locks::default()).is_none());
+    }
+}
@@ -0,0 +1,505 @@
+//! Keyboards using the report protocol
+//!
+//! The [`KbdDriver`](super::KbdDriver) only understands the 8 byte reports of the boot protocol, which can report at most
+//! 6 keys at once (6-key rollover). Many keyboards (especially those supporting N-key rollover) use a different report
+//! layout, and some don't offer a boot interface at all. The [`KbdReportDriver`] requests the HID report descriptor of
+//! the keyboard, and uses it to interpret the reports. This covers:
+//! - bitmap style reports, with one bit per key (N-key rollover)
+//! - reports of any length, including longer array style reports
+//! - devices using report IDs, e.g. keyboards which send media keys in a separate report
+//!
+//! Keyboards with a boot interface can be handled by either driver. Since each interface is claimed by only one driver
+//! (see [`Driver::claim_interfaces`]), the one coming first in the list of drivers passed to `usb_host.poll(...)` wins.
+//!
+//! Like the [`MscDriver`](crate::driver::msc::MscDriver), the driver needs to initiate transfers on its own (to request the
+//! report descriptor), so [`KbdReportDriver::poll`] must be called after every call to `usb_host.poll(...)`.
+
+use super::keys::{KeyEvent, KEY_LEFT_CTRL};
+use super::ModifierStatus;
+use crate::bus::HostBus;
+use crate::descriptor;
+use crate::driver::{Driver, EventQueue, HasEvents, EVENT_QUEUE_DEPTH};
+use crate::hid::{self, ReportParser, ReportType, USAGE_PAGE_KEYBOARD};
+use crate::types::{ConnectionSpeed, DeviceAddress, InterfaceSet, TransferType};
+use crate::{PipeId, TransferError, UsbHost};
+use usb_device::UsbDirection;
+
+/// Interface class of HID devices
+const CLASS_HID: u8 = 0x03;
+/// Interface subclass of HID devices supporting the boot protocol
+const SUB_CLASS_BOOT: u8 = 0x01;
+/// Interface protocol of boot keyboards
+const PROTOCOL_KEYBOARD: u8 = 0x01;
+
+/// Key codes below this value report errors, rather than keys
+const FIRST_KEY: u16 = 0x04;
+
+/// Set of pressed keys, with one bit for each of the 256 key codes
+///
+/// Modifier keys are included, with codes `0xe0` (left `Ctrl`) to `0xe7` (right `Gui`).
+#[derive(Copy, Clone, PartialEq, Default, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct KeyState([u8; 32]);
+
+impl KeyState {
+    pub fn is_pressed(&self, code: u8) -> bool {
+        self.0[code as usize / 8] & (1 << (code % 8)) != 0
+    }
+
+    fn press(&mut self, code: u8) {
+        self.0[code as usize / 8] |= 1 << (code % 8);
+    }
+
+    /// Iterate over the codes of all pressed keys, in ascending order
+    pub fn pressed(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..=255).filter(|code| self.is_pressed(*code))
+    }
+
+    /// State of the modifier keys, in the format of the boot protocol
+    pub fn modifiers(&self) -> ModifierStatus {
+        ModifierStatus(self.0[KEY_LEFT_CTRL as usize / 8])
+    }
+
+    /// Keys that were released and pressed (in this order), compared to the `previous` state
+    pub fn changes<'a>(&'a self, previous: &'a KeyState) -> impl Iterator<Item = KeyEvent> + 'a {
+        let released = previous.pressed().filter(|code| !self.is_pressed(*code)).map(KeyEvent::Released);
+        let pressed = self.pressed().filter(|code| !previous.is_pressed(*code)).map(KeyEvent::Pressed);
+        released.chain(pressed)
+    }
+}
+
+/// Events related to attached keyboards
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum KbdReportEvent {
+    /// A new keyboard was detected & its report descriptor was read successfully
+    DeviceAdded(DeviceAddress),
+
+    /// A keyboard was removed
+    DeviceRemoved(DeviceAddress),
+
+    /// The set of pressed keys changed
+    ///
+    /// The previous state is available via [`KbdReportDriver::keys`] until the event is handled. Use
+    /// [`KeyState::changes`] to find out which keys changed.
+    KeysChanged(DeviceAddress, KeyState),
+
+    /// The HID interface did not turn out to be a keyboard, or its report descriptor could not be parsed
+    ///
+    /// The device is ignored from now on.
+    Unsupported(DeviceAddress),
+}
+
+/// Keyboard that was found during discovery, but is not configured yet
+#[derive(Copy, Clone, Debug)]
+struct Candidate {
+    dev_addr: DeviceAddress,
+    config: Option<u8>,
+    /// Interface that is currently being looked at, and the length of its report descriptor
+    interface: Option<(u8, Option<u16>)>,
+    /// Interrupt IN endpoint of the interface: number, maximum packet size and interval
+    endpoint: Option<(u8, u16, u8)>,
+    /// Set once a usable interface was found
+    found: bool,
+}
+
+#[derive(Debug)]
+struct ReportKbdDevice<const MAX_FIELDS: usize> {
+    dev_addr: DeviceAddress,
+    interface: u8,
+    control_pipe: PipeId,
+    interrupt_pipe: PipeId,
+    report_descriptor_length: u16,
+    /// Set once the request for the report descriptor was sent
+    requested: bool,
+    /// Parsed report descriptor, once received
+    parser: Option<ReportParser<MAX_FIELDS>>,
+    keys: KeyState,
+}
+
+/// Driver for keyboards which are not limited to the boot protocol
+///
+/// See the [module documentation](self) for details.
+///
+/// By default, up to 2 keyboards can be handled, each with a report descriptor declaring up to 32 fields.
+#[derive(Debug)]
+pub struct KbdReportDriver<const MAX_DEVICES: usize = 2, const MAX_FIELDS: usize = 32> {
+    devices: [Option<ReportKbdDevice<MAX_FIELDS>>; MAX_DEVICES],
+    candidate: Option<Candidate>,
+    events: EventQueue<KbdReportEvent, EVENT_QUEUE_DEPTH>,
+}
+
+impl<const MAX_DEVICES: usize, const MAX_FIELDS: usize> Default for KbdReportDriver<MAX_DEVICES, MAX_FIELDS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const MAX_DEVICES: usize, const MAX_FIELDS: usize> KbdReportDriver<MAX_DEVICES, MAX_FIELDS> {
+    pub fn new() -> Self {
+        Self {
+            devices: [const { None }; MAX_DEVICES],
+            candidate: None,
+            events: EventQueue::new(),
+        }
+    }
+
+    /// Returns the oldest pending keyboard event (if any), and removes it from the queue.
+    ///
+    /// This method should be called repeatedly after calling `usb_host.poll(...)`, until it returns `None` (see
+    /// [`HasEvents`] for what happens otherwise).
+    pub fn take_event(&mut self) -> Option<KbdReportEvent> {
+        self.events.pop()
+    }
+
+    /// Keys currently pressed on the given keyboard
+    pub fn keys(&self, dev_addr: DeviceAddress) -> Option<KeyState> {
+        self.devices
+            .iter()
+            .flatten()
+            .find(|d| d.dev_addr == dev_addr && d.parser.is_some())
+            .map(|d| d.keys)
+    }
+
+    /// Request the report descriptors of newly configured keyboards
+    ///
+    /// Must be called after every call to `usb_host.poll(...)`. If the bus is busy, the request is sent on a later call.
+    pub fn poll<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    >(
+        &mut self,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) {
+        for device in self.devices.iter_mut().flatten() {
+            if !device.requested {
+                device.requested = hid::get_report_descriptor(
+                    host,
+                    device.dev_addr,
+                    device.control_pipe,
+                    device.interface,
+                    device.report_descriptor_length,
+                )
+                .is_ok();
+            }
+        }
+    }
+
+    fn find_device(&mut self, dev_addr: DeviceAddress) -> Option<&mut Option<ReportKbdDevice<MAX_FIELDS>>> {
+        self.devices.iter_mut().find(|d| matches!(d, Some(d) if d.dev_addr == dev_addr))
+    }
+
+    /// Interface found during discovery, if the given configuration is the one it belongs to
+    fn claim(&self, dev_addr: DeviceAddress, value: u8) -> Option<(u8, u16, (u8, u16, u8))> {
+        match self.candidate {
+            Some(Candidate {
+                dev_addr: addr,
+                config: Some(config),
+                interface: Some((interface, Some(length))),
+                endpoint: Some(endpoint),
+                found: true,
+            }) if addr == dev_addr && config == value => Some((interface, length, endpoint)),
+            _ => None,
+        }
+    }
+}
+
+/// Parse the given report, and return the pressed keys
+///
+/// Returns `None` if the report does not contain any keyboard keys (e.g. a report with a different report ID).
+fn parse_keys<const MAX_FIELDS: usize>(parser: &ReportParser<MAX_FIELDS>, report: &[u8]) -> Option<KeyState> {
+    let report_id = if parser.uses_report_ids() { *report.first()? } else { 0 };
+    let has_keys = parser
+        .fields()
+        .iter()
+        .any(|field| field.report_type == ReportType::Input && field.report_id == report_id && field.usage_page == USAGE_PAGE_KEYBOARD);
+    if !has_keys {
+        return None;
+    }
+    let mut keys = KeyState::default();
+    for value in parser.input_values(report) {
+        if value.usage_page == USAGE_PAGE_KEYBOARD && value.value != 0 && (FIRST_KEY..=0xff).contains(&value.usage) {
+            keys.press(value.usage as u8);
+        }
+    }
+    Some(keys)
+}
+
+impl<const MAX_DEVICES: usize, const MAX_FIELDS: usize> HasEvents for KbdReportDriver<MAX_DEVICES, MAX_FIELDS> {
+    type Event = KbdReportEvent;
+
+    fn take_event(&mut self) -> Option<KbdReportEvent> {
+        self.events.pop()
+    }
+
+    fn events_pending(&self) -> bool {
+        !self.events.is_empty()
+    }
+}
+
+impl<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+        const MAX_DEVICES: usize,
+        const MAX_FIELDS: usize,
+    > Driver<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>
+    for KbdReportDriver<MAX_DEVICES, MAX_FIELDS>
+{
+    fn attached(&mut self, dev_addr: DeviceAddress, _connection_speed: ConnectionSpeed) {
+        self.candidate = Some(Candidate { dev_addr, config: None, interface: None, endpoint: None, found: false });
+    }
+
+    fn detached(&mut self, dev_addr: DeviceAddress) {
+        if let Some(slot) = self.find_device(dev_addr) {
+            let known = slot.take().is_some_and(|device| device.parser.is_some());
+            if known {
+                self.events.push(KbdReportEvent::DeviceRemoved(dev_addr));
+            }
+        }
+        if self.candidate.is_some_and(|candidate| candidate.dev_addr == dev_addr) {
+            self.candidate = None;
+        }
+    }
+
+    fn descriptor(&mut self, dev_addr: DeviceAddress, descriptor_type: u8, data: &[u8]) {
+        let Some(candidate) = self.candidate.as_mut().filter(|c| c.dev_addr == dev_addr && !c.found) else {
+            return;
+        };
+        match descriptor_type {
+            descriptor::TYPE_CONFIGURATION => {
+                if let Ok((_, config)) = descriptor::parse::configuration_descriptor(data) {
+                    candidate.config = Some(config.value);
+                    candidate.interface = None;
+                }
+            }
+            descriptor::TYPE_INTERFACE => {
+                candidate.interface = None;
+                candidate.endpoint = None;
+                if let Ok((_, interface)) = descriptor::parse::interface_descriptor(data) {
+                    // non-boot interfaces may be keyboards as well, which is only known once the report descriptor was read
+                    let maybe_keyboard = interface.interface_sub_class != SUB_CLASS_BOOT || interface.interface_protocol == PROTOCOL_KEYBOARD;
+                    if interface.interface_class == CLASS_HID && maybe_keyboard && !interface.is_alternate_setting() {
+                        candidate.interface = Some((interface.interface_number, None));
+                    }
+                }
+            }
+            hid::TYPE_HID => {
+                if let Some((_, length)) = &mut candidate.interface {
+                    *length = descriptor::parse::hid_descriptor(data).ok().and_then(|(_, hid)| hid.report_descriptor_length());
+                }
+            }
+            descriptor::TYPE_ENDPOINT => {
+                if let (Some((_, Some(_))), None) = (candidate.interface, candidate.endpoint) {
+                    if let Ok((_, endpoint)) = descriptor::parse::endpoint_descriptor(data) {
+                        if endpoint.address.direction() == UsbDirection::In
+                            && endpoint.attributes.transfer_type() == TransferType::Interrupt
+                            && endpoint.max_packet_size > 0
+                        {
+                            candidate.endpoint = Some((endpoint.address.number(), endpoint.max_packet_size, endpoint.interval));
+                            candidate.found = true;
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn configure(&mut self, dev_addr: DeviceAddress) -> Option<u8> {
+        match self.candidate {
+            Some(candidate) if candidate.dev_addr == dev_addr && candidate.found => candidate.config,
+            _ => None,
+        }
+    }
+
+    fn claim_interfaces(&mut self, dev_addr: DeviceAddress, value: u8) -> InterfaceSet {
+        self.claim(dev_addr, value)
+            .map_or(InterfaceSet::EMPTY, |(interface, _, _)| InterfaceSet::single(interface))
+    }
+
+    fn configured(
+        &mut self,
+        dev_addr: DeviceAddress,
+        value: u8,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) {
+        let interfaces = <Self as Driver<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>>::claim_interfaces(
+            self, dev_addr, value,
+        );
+        self.configured_interfaces(dev_addr, value, interfaces, host);
+    }
+
+    fn configured_interfaces(
+        &mut self,
+        dev_addr: DeviceAddress,
+        value: u8,
+        interfaces: InterfaceSet,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) {
+        let claimed = self.claim(dev_addr, value);
+        if self.candidate.is_some_and(|candidate| candidate.dev_addr == dev_addr) {
+            self.candidate = None;
+        }
+        let Some((interface, report_descriptor_length, (endpoint, size, interval))) = claimed else {
+            return;
+        };
+        if !interfaces.contains(interface) {
+            // claimed by another driver
+            return;
+        }
+        let Some(slot) = self.devices.iter_mut().find(|d| d.is_none()) else {
+            return;
+        };
+        let control_pipe = host.create_control_pipe(dev_addr);
+        let interrupt_pipe = host.create_interrupt_pipe(dev_addr, endpoint, UsbDirection::In, size, interval);
+        if let (Some(control_pipe), Some(interrupt_pipe)) = (control_pipe, interrupt_pipe) {
+            slot.replace(ReportKbdDevice {
+                dev_addr,
+                interface,
+                control_pipe,
+                interrupt_pipe,
+                report_descriptor_length,
+                requested: false,
+                parser: None,
+                keys: KeyState::default(),
+            });
+            self.poll(host);
+        } else {
+            // the host ran out of pipes: release the one that was created, if any
+            for pipe in control_pipe.into_iter().chain(interrupt_pipe) {
+                host.release_pipe(pipe);
+            }
+        }
+    }
+
+    fn completed_control(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, data: Option<&[u8]>) {
+        let Some(slot) = self.find_device(dev_addr) else {
+            return;
+        };
+        let Some(device) = slot.as_mut().filter(|d| d.control_pipe == pipe_id && d.parser.is_none()) else {
+            return;
+        };
+        let parser = data.and_then(|data| ReportParser::<MAX_FIELDS>::parse(data).ok());
+        let is_keyboard = parser.as_ref().is_some_and(|parser| {
+            parser
+                .fields()
+                .iter()
+                .any(|field| field.report_type == ReportType::Input && field.usage_page == USAGE_PAGE_KEYBOARD)
+        });
+        if is_keyboard {
+            device.parser = parser;
+            self.events.push(KbdReportEvent::DeviceAdded(dev_addr));
+        } else {
+            slot.take();
+            self.events.push(KbdReportEvent::Unsupported(dev_addr));
+        }
+    }
+
+    fn transfer_failed(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, _error: TransferError) {
+        if let Some(slot) = self.find_device(dev_addr) {
+            if slot.as_ref().is_some_and(|d| d.control_pipe == pipe_id && d.parser.is_none()) {
+                // without the report descriptor, the reports cannot be interpreted
+                slot.take();
+                self.events.push(KbdReportEvent::Unsupported(dev_addr));
+            }
+        }
+    }
+
+    fn completed_in(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, data: &[u8]) {
+        let Some(Some(device)) = self.find_device(dev_addr) else {
+            return;
+        };
+        if device.interrupt_pipe != pipe_id {
+            return;
+        }
+        let Some(parser) = &device.parser else {
+            // the report descriptor was not received yet
+            return;
+        };
+        if let Some(keys) = parse_keys(parser, data) {
+            if keys != device.keys {
+                device.keys = keys;
+                self.events.push(KbdReportEvent::KeysChanged(dev_addr, keys));
+            }
+        }
+    }
+
+    fn completed_out(&mut self, _dev_addr: DeviceAddress, _pipe_id: PipeId, _data: &mut [u8]) {
+        // ignored, since there are no OUT pipes in use.
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Keyboard with report IDs: report 1 is a modifier byte followed by a bitmap of keys 0x00..0x67,
+    /// report 2 is a consumer control
+    const NKRO_KEYBOARD: &[u8] = &[
+        0x05, 0x01, // Usage Page (Generic Desktop)
+        0x09, 0x06, // Usage (Keyboard)
+        0xA1, 0x01, // Collection (Application)
+        0x85, 0x01, //   Report ID (1)
+        0x05, 0x07, //   Usage Page (Keyboard)
+        0x19, 0xE0, //   Usage Minimum (0xE0)
+        0x29, 0xE7, //   Usage Maximum (0xE7)
+        0x15, 0x00, //   Logical Minimum (0)
+        0x25, 0x01, //   Logical Maximum (1)
+        0x75, 0x01, //   Report Size (1)
+        0x95, 0x08, //   Report Count (8)
+        0x81, 0x02, //   Input (Data, Variable, Absolute)
+        0x19, 0x00, //   Usage Minimum (0x00)
+        0x29, 0x67, //   Usage Maximum (0x67)
+        0x95, 0x68, //   Report Count (104)
+        0x81, 0x02, //   Input (Data, Variable, Absolute)
+        0xC0, // End Collection
+        0x05, 0x0C, // Usage Page (Consumer)
+        0x09, 0x01, // Usage (Consumer Control)
+        0xA1, 0x01, // Collection (Application)
+        0x85, 0x02, //   Report ID (2)
+        0x19, 0x00, //   Usage Minimum (0)
+        0x2A, 0x3C, 0x02, // Usage Maximum (0x23C)
+        0x15, 0x00, //   Logical Minimum (0)
+        0x26, 0x3C, 0x02, // Logical Maximum (0x23C)
+        0x75, 0x10, //   Report Size (16)
+        0x95, 0x01, //   Report Count (1)
+        0x81, 0x00, //   Input (Data, Array, Absolute)
+        0xC0, // End Collection
+    ];
+
+    #[test]
+    fn test_nkro_report() {
+        let parser: ReportParser = ReportParser::parse(NKRO_KEYBOARD).ok().unwrap();
+        let mut report = [0u8; 15];
+        report[0] = 1;
+        // left shift
+        report[1] = 0b10;
+        // 7 keys at once: a - g (0x04 - 0x0a)
+        report[2] = 0b1111_0000;
+        report[3] = 0b0000_0111;
+        let keys = parse_keys(&parser, &report).unwrap();
+        assert!(keys.modifiers().left_shift());
+        assert!(keys.pressed().eq([0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0xe1]));
+
+        // consumer control report does not affect the keys
+        assert!(parse_keys(&parser, &[2, 0xe9, 0x00]).is_none());
+    }
+
+    #[test]
+    fn test_key_changes() {
+        let mut previous = KeyState::default();
+        previous.press(0x04);
+        previous.press(0x05);
+        let mut current = KeyState::default();
+        current.press(0x05);
+        current.press(0x06);
+        let mut changes = current.changes(&previous);
+        assert!(changes.next() == Some(KeyEvent::Released(0x04)));
+        assert!(changes.next() == Some(KeyEvent::Pressed(0x06)));
+        assert!(changes.next().is_none());
+    }
+}
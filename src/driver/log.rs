@@ -1,10 +1,12 @@
 use super::Driver;
 use crate::bus::HostBus;
 use crate::descriptor;
+use crate::hid;
 use crate::types::DeviceAddress;
-use defmt::{bitflags, info};
+use bitflags::bitflags;
 
 /// A [`Driver`] which logs various events
+#[derive(Debug)]
 pub struct LogDriver(EventMask);
 
 bitflags! {
@@ -23,13 +25,26 @@ bitflags! {
     }
 }
 
+#[cfg(feature = "defmt")]
+impl defmt::Format for EventMask {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "EventMask({=u8:#x})", self.bits())
+    }
+}
+
 impl LogDriver {
     pub fn new(event_mask: EventMask) -> Self {
         Self(event_mask)
     }
 }
 
-impl<B: HostBus> Driver<B> for LogDriver {
+impl<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    > Driver<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE> for LogDriver
+{
     fn attached(
         &mut self,
         dev_addr: DeviceAddress,
@@ -66,7 +81,7 @@ impl<B: HostBus> Driver<B> for LogDriver {
                         .map(|(_, desc)| desc)
                         .map_err(|_| "(parse failed)");
                     info!(
-                        "[usbh LogDriver] Device {} sent device descriptor:\n  {:#X}",
+                        "[usbh LogDriver] Device {} sent device descriptor:\n  {:?}",
                         u8::from(dev_addr),
                         descriptor,
                     )
@@ -76,14 +91,14 @@ impl<B: HostBus> Driver<B> for LogDriver {
                         .map(|(_, desc)| desc)
                         .map_err(|_| "(parse failed)");
                     info!(
-                        "[usbh LogDriver] Device {} sent configuration descriptor:\n  {:#X}",
+                        "[usbh LogDriver] Device {} sent configuration descriptor:\n  {:?}",
                         u8::from(dev_addr),
                         descriptor,
                     )
                 }
                 descriptor::TYPE_STRING => {
                     info!(
-                        "[usbh LogDriver] Device {} sent string descriptor:\n  {:#X}",
+                        "[usbh LogDriver] Device {} sent string descriptor:\n  {:?}",
                         u8::from(dev_addr),
                         data,
                     )
@@ -93,7 +108,7 @@ impl<B: HostBus> Driver<B> for LogDriver {
                         .map(|(_, desc)| desc)
                         .map_err(|_| "(parse failed)");
                     info!(
-                        "[usbh LogDriver] Device {} sent interface descriptor:\n  {:#X}",
+                        "[usbh LogDriver] Device {} sent interface descriptor:\n  {:?}",
                         u8::from(dev_addr),
                         descriptor,
                     )
@@ -103,14 +118,24 @@ impl<B: HostBus> Driver<B> for LogDriver {
                         .map(|(_, desc)| desc)
                         .map_err(|_| "(parse failed)");
                     info!(
-                        "[usbh LogDriver] Device {} sent endpoint descriptor:\n  {:#X}",
+                        "[usbh LogDriver] Device {} sent endpoint descriptor:\n  {:?}",
+                        u8::from(dev_addr),
+                        descriptor,
+                    )
+                }
+                hid::TYPE_HID => {
+                    let descriptor = descriptor::parse::hid_descriptor(data)
+                        .map(|(_, desc)| desc)
+                        .map_err(|_| "(parse failed)");
+                    info!(
+                        "[usbh LogDriver] Device {} sent HID descriptor:\n  {:?}",
                         u8::from(dev_addr),
                         descriptor,
                     )
                 }
                 _ => {
                     info!(
-                        "[usbh LogDriver] Device {} sent descriptor of type {:#X}: {}",
+                        "[usbh LogDriver] Device {} sent descriptor of type {:#X}: {:?}",
                         u8::from(dev_addr),
                         descriptor_type,
                         data,
@@ -134,7 +159,7 @@ impl<B: HostBus> Driver<B> for LogDriver {
         &mut self,
         dev_addr: DeviceAddress,
         value: u8,
-        _host: &mut crate::UsbHost<B>,
+        _host: &mut crate::UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
     ) {
         if self.0.contains(EventMask::CONFIGURED) {
             info!(
@@ -0,0 +1,367 @@
+use super::{detector::SimpleDetector, DiscoveryInterest, Driver, EventQueue, HasEvents, EVENT_QUEUE_DEPTH};
+use crate::bus::HostBus;
+use crate::hid::{self, Protocol};
+use crate::types::{ConnectionSpeed, DeviceAddress, InterfaceSet, TransferType};
+use crate::{PipeId, TransferError, UsbHost};
+use usb_device::UsbDirection;
+
+/// Interface protocol code of boot mice
+const PROTOCOL_MOUSE: u8 = 0x02;
+
+/// Driver for boot mice
+///
+/// Handles HID devices with a boot interface (subclass `0x01`) using the mouse protocol (`0x02`).
+///
+/// The boot protocol input report has the following format:
+///
+/// | Byte | Content                                     |
+/// |------|---------------------------------------------|
+/// | 0    | button state (see [`MouseButtons`])          |
+/// | 1    | X displacement (signed)                     |
+/// | 2    | Y displacement (signed)                     |
+/// | 3    | wheel displacement (signed, optional)       |
+///
+/// Once a mouse is configured, the driver switches it to the boot protocol (with SET_PROTOCOL), since mice default to the
+/// report protocol, whose reports may have a different format. Input reports are ignored until the request completed.
+///
+/// By default, up to 2 connected mice can be handled. Events are reported for each device separately.
+#[derive(Debug)]
+pub struct MouseDriver<const MAX_DEVICES: usize = 2> {
+    devices: [Option<MouseDevice>; MAX_DEVICES],
+    detector: SimpleDetector<0x03, 0x01, { UsbDirection::In as u8 }, { TransferType::Interrupt as u8 }>,
+    events: EventQueue<MouseEvent, EVENT_QUEUE_DEPTH>,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct MouseDevice {
+    dev_addr: DeviceAddress,
+    control_pipe: PipeId,
+    interrupt_pipe: PipeId,
+    buttons: MouseButtons,
+    /// Set once the SET_PROTOCOL request completed (or failed, since not all mice support it)
+    ready: bool,
+}
+
+/// State of the mouse buttons
+#[derive(Copy, Clone, PartialEq, Default, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MouseButtons(u8);
+
+impl MouseButtons {
+    /// Is the left (primary) button pressed?
+    pub fn left(&self) -> bool {
+        self.0 & 1 == 1
+    }
+
+    /// Is the right (secondary) button pressed?
+    pub fn right(&self) -> bool {
+        (self.0 >> 1) & 1 == 1
+    }
+
+    /// Is the middle button pressed?
+    pub fn middle(&self) -> bool {
+        (self.0 >> 2) & 1 == 1
+    }
+
+    /// Is the given button pressed?
+    ///
+    /// Buttons are numbered starting at 0 (left), up to 7. Boot mice are only required to report the first three.
+    pub fn pressed(&self, button: u8) -> bool {
+        button < 8 && (self.0 >> button) & 1 == 1
+    }
+
+    /// Raw button bitmap
+    pub fn bits(&self) -> u8 {
+        self.0
+    }
+}
+
+/// An input report, decoded from the boot protocol format
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MouseReport {
+    pub buttons: MouseButtons,
+    pub dx: i8,
+    pub dy: i8,
+    /// Wheel displacement. Zero if the mouse does not report a wheel.
+    pub wheel: i8,
+}
+
+impl MouseReport {
+    /// Decode a boot protocol input report
+    ///
+    /// Returns `None` if the report is too short.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        match data {
+            [buttons, dx, dy, rest @ ..] => Some(MouseReport {
+                buttons: MouseButtons(*buttons),
+                dx: *dx as i8,
+                dy: *dy as i8,
+                wheel: rest.first().map_or(0, |wheel| *wheel as i8),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Events related to attached mice
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MouseEvent {
+    /// A new mouse was detected & configured, with given device address
+    DeviceAdded(DeviceAddress),
+
+    /// A mouse was removed
+    DeviceRemoved(DeviceAddress),
+
+    /// The mouse was moved (or the wheel was turned)
+    ///
+    /// Since only one event can be reported per report, a change of the button state which happens at the same time
+    /// is reported via this event as well. Compare `buttons` to the previous state to detect it.
+    Moved {
+        dev_addr: DeviceAddress,
+        dx: i8,
+        dy: i8,
+        wheel: i8,
+        buttons: MouseButtons,
+    },
+
+    /// Buttons were pressed or released, without the mouse moving
+    ButtonsChanged(DeviceAddress, MouseButtons),
+}
+
+impl<const MAX_DEVICES: usize> Default for MouseDriver<MAX_DEVICES> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const MAX_DEVICES: usize> MouseDriver<MAX_DEVICES> {
+    pub fn new() -> Self {
+        Self {
+            devices: [None; MAX_DEVICES],
+            detector: SimpleDetector::with_protocol(PROTOCOL_MOUSE),
+            events: EventQueue::new(),
+        }
+    }
+
+    /// Returns the oldest pending mouse event (if any), and removes it from the queue.
+    ///
+    /// This method should be called repeatedly after calling `usb_host.poll(...)`, until it returns `None` (see
+    /// [`HasEvents`] for what happens otherwise).
+    pub fn take_event(&mut self) -> Option<MouseEvent> {
+        self.events.pop()
+    }
+
+    /// Returns the current button state of the given device
+    pub fn buttons(&self, dev_addr: DeviceAddress) -> Option<MouseButtons> {
+        self.devices
+            .iter()
+            .flatten()
+            .find(|d| d.dev_addr == dev_addr)
+            .map(|d| d.buttons)
+    }
+
+    /// Start accepting input reports, once the SET_PROTOCOL request on the given pipe is done
+    fn set_protocol_done(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId) {
+        if let Some(Some(device)) = self.devices.get_mut(pipe_id.context() as usize) {
+            if device.dev_addr == dev_addr && pipe_id == device.control_pipe {
+                device.ready = true;
+            }
+        }
+    }
+}
+
+impl<const MAX_DEVICES: usize> HasEvents for MouseDriver<MAX_DEVICES> {
+    type Event = MouseEvent;
+
+    fn take_event(&mut self) -> Option<MouseEvent> {
+        self.events.pop()
+    }
+
+    fn events_pending(&self) -> bool {
+        !self.events.is_empty()
+    }
+}
+
+impl<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+        const MAX_DEVICES: usize,
+    > Driver<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE> for MouseDriver<MAX_DEVICES>
+{
+    fn attached(&mut self, dev_addr: DeviceAddress, _connection_speed: ConnectionSpeed) {
+        self.detector.attached(dev_addr);
+    }
+
+    fn detached(&mut self, dev_addr: DeviceAddress) {
+        if let Some(slot) = self.devices.iter_mut().find(|d| matches!(d, Some(d) if d.dev_addr == dev_addr)) {
+            slot.take();
+            self.events.push(MouseEvent::DeviceRemoved(dev_addr));
+        } else {
+            self.detector.detached(dev_addr);
+        }
+    }
+
+    fn descriptor(&mut self, dev_addr: DeviceAddress, descriptor_type: u8, data: &[u8]) {
+        self.detector.descriptor(dev_addr, descriptor_type, data);
+    }
+
+    fn discovery_interest(&mut self, dev_addr: DeviceAddress) -> DiscoveryInterest {
+        self.detector.interest(dev_addr)
+    }
+
+    fn configure(&mut self, dev_addr: DeviceAddress) -> Option<u8> {
+        self.detector.configure(dev_addr)
+    }
+
+    fn claim_interfaces(&mut self, dev_addr: DeviceAddress, value: u8) -> InterfaceSet {
+        self.detector.claim(dev_addr, value)
+    }
+
+    fn configured(
+        &mut self,
+        dev_addr: DeviceAddress,
+        value: u8,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) {
+        let interfaces = self.detector.claim(dev_addr, value);
+        self.configured_interfaces(dev_addr, value, interfaces, host);
+    }
+
+    fn configured_interfaces(
+        &mut self,
+        dev_addr: DeviceAddress,
+        value: u8,
+        interfaces: InterfaceSet,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) {
+        let Some((interface, (endpoint, size, interval))) = self.detector.configured(dev_addr, value) else {
+            return;
+        };
+        if !interfaces.contains(interface) {
+            // claimed by another driver
+            return;
+        }
+        let Some((index, slot)) = self.devices.iter_mut().enumerate().find(|(_, d)| d.is_none()) else {
+            return;
+        };
+        // the slot index is used as pipe context, to find the device in the callbacks
+        let Some(control_pipe) = host.create_control_pipe_with_context(dev_addr, index as u16) else {
+            return;
+        };
+        let Some(interrupt_pipe) =
+            host.create_interrupt_pipe_with_context(dev_addr, endpoint, UsbDirection::In, size, interval, index as u16)
+        else {
+            host.release_pipe(control_pipe);
+            return;
+        };
+        // without the request, the mouse keeps sending reports in the format of the report protocol
+        let ready = hid::set_protocol(host, dev_addr, control_pipe, interface, Protocol::Boot).is_err();
+        slot.replace(MouseDevice {
+            dev_addr,
+            control_pipe,
+            interrupt_pipe,
+            buttons: MouseButtons::default(),
+            ready,
+        });
+        self.events.push(MouseEvent::DeviceAdded(dev_addr));
+    }
+
+    fn completed_control(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, _data: Option<&[u8]>) {
+        self.set_protocol_done(dev_addr, pipe_id);
+    }
+
+    fn transfer_failed(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, _error: TransferError) {
+        // SET_PROTOCOL is optional for mice which do not support the report protocol. Continue without it.
+        self.set_protocol_done(dev_addr, pipe_id);
+    }
+
+    fn completed_in(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, data: &[u8]) {
+        let Some(Some(device)) = self.devices.get_mut(pipe_id.context() as usize) else {
+            return;
+        };
+        if device.dev_addr != dev_addr || pipe_id != device.interrupt_pipe || !device.ready {
+            return;
+        }
+        if let Some(report) = MouseReport::parse(data) {
+            let buttons_changed = report.buttons != device.buttons;
+            device.buttons = report.buttons;
+            if report.dx != 0 || report.dy != 0 || report.wheel != 0 {
+                self.events.push(MouseEvent::Moved {
+                    dev_addr,
+                    dx: report.dx,
+                    dy: report.dy,
+                    wheel: report.wheel,
+                    buttons: report.buttons,
+                });
+            } else if buttons_changed {
+                self.events.push(MouseEvent::ButtonsChanged(dev_addr, report.buttons));
+            }
+        }
+    }
+
+    fn completed_out(&mut self, _dev_addr: DeviceAddress, _pipe_id: PipeId, _data: &mut [u8]) {
+        // ignored, since there are no OUT pipes in use.
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::mock::fixtures::DEVICE_DESCRIPTOR;
+    use crate::bus::mock::{ControlResponse, MockDevice, MockHostBus, Response};
+
+    #[test]
+    fn test_parse_report() {
+        let report = MouseReport::parse(&[0b101, 0x05, 0xFE, 0xFF]).unwrap();
+        assert!(report.buttons.left());
+        assert!(!report.buttons.right());
+        assert!(report.buttons.middle());
+        assert_eq!(report.dx, 5);
+        assert_eq!(report.dy, -2);
+        assert_eq!(report.wheel, -1);
+
+        let without_wheel = MouseReport::parse(&[0, 1, 1]).unwrap();
+        assert_eq!(without_wheel.wheel, 0);
+
+        assert!(MouseReport::parse(&[0, 1]).is_none());
+    }
+
+    #[test]
+    fn test_boot_protocol() {
+        const MOUSE_DESCRIPTOR: &[u8] = &[
+            0x09, 0x02, 0x22, 0x00, 0x01, 0x01, 0x00, 0xa0, 0x32, // configuration 1
+            0x09, 0x04, 0x00, 0x00, 0x01, 0x03, 0x01, 0x02, 0x00, // interface 0: boot mouse
+            0x09, 0x21, 0x11, 0x01, 0x00, 0x01, 0x22, 0x34, 0x00, // HID
+            0x07, 0x05, 0x81, 0x03, 0x04, 0x00, 0x0a, // endpoint 1 IN, interrupt
+        ];
+        const SET_PROTOCOL: ControlResponse = ControlResponse {
+            request_type: 0x21,
+            request: 0x0b,
+            value: 0,
+            index: 0,
+            response: Response::Data(&[]),
+        };
+        // mice that stall the request are used all the same
+        for responses in [&[SET_PROTOCOL][..], &[]] {
+            let device = MockDevice { control_responses: responses, ..MockDevice::new(DEVICE_DESCRIPTOR, &[MOUSE_DESCRIPTOR]) };
+            let mut host = UsbHost::new(MockHostBus::new(device));
+            let mut mouse: MouseDriver = MouseDriver::new();
+            host.bus().attach();
+            for _ in 0..1000 {
+                host.poll(&mut [&mut mouse]);
+            }
+            assert!(matches!(mouse.take_event(), Some(MouseEvent::DeviceAdded(_))));
+            // the last request switched the mouse to the boot protocol
+            let setup = host.bus().last_setup().unwrap();
+            assert_eq!((setup.request_type, setup.request, setup.value), (0x21, 0x0b, 0));
+
+            assert!(host.bus().send_interrupt(1, &[0b001, 0x05, 0xfe, 0x00]));
+            host.poll(&mut [&mut mouse]);
+            assert!(matches!(mouse.take_event(), Some(MouseEvent::Moved { dx: 5, dy: -2, buttons, .. }) if buttons.left()));
+        }
+    }
+}
@@ -0,0 +1,1007 @@
+//! Mass storage devices (USB sticks, card readers, ...)
+//!
+//! The [`MscDriver`] implements the *Bulk-Only Transport* (BOT) protocol, with the SCSI transparent command set.
+//! This is what practically all USB mass storage devices speak.
+//!
+//! Every command is executed in three stages, each of them consisting of one or more bulk transfers:
+//! 1. A *Command Block Wrapper* (CBW) containing the SCSI command is sent to the bulk OUT endpoint
+//! 2. Data is transferred on the bulk IN or OUT endpoint (depending on the command, this stage may be empty)
+//! 3. A *Command Status Wrapper* (CSW) is received from the bulk IN endpoint, indicating success or failure
+//!
+//! Since transfers cannot be initiated from within driver callbacks, the application must call [`MscDriver::poll`]
+//! after every call to [`UsbHost::poll`], to advance the current command.
+//!
+//! Example:
+//! ```ignore
+//! let mut msc = MscDriver::new();
+//!
+//! loop {
+//!     usb_host.poll(&mut [&mut msc]);
+//!     msc.poll(&mut usb_host).ok();
+//!
+//!     match msc.take_event() {
+//!         Some(MscEvent::DeviceAdded(_)) => msc.read_capacity().unwrap(),
+//!         Some(MscEvent::Capacity(_, _)) => msc.read_block(0).unwrap(),
+//!         Some(MscEvent::ReadComplete(_, 0)) => {
+//!             let boot_sector = msc.block_data();
+//!             // ...
+//!         }
+//!         _ => {}
+//!     }
+//! }
+//! ```
+//!
+//! Limitations:
+//! - Only a single device, and only logical unit 0 is supported
+//! - Only one block is transferred per READ(10) / WRITE(10) command, and the block size must not exceed 512 bytes
+//!
+//! When the device stalls a bulk endpoint, or responds with an invalid CSW or a phase error, the driver performs a
+//! *reset recovery*: a Bulk-Only Mass Storage Reset, followed by clearing the halt condition of both bulk endpoints.
+//! The failed command is reported (via [`MscEvent::CommandFailed`]) once the recovery is done, and [`MscDriver::busy`]
+//! returns `true` until then.
+
+use super::{detector::SingleDevice, Driver, EventQueue, HasEvents, EVENT_QUEUE_DEPTH};
+use crate::bus::HostBus;
+use crate::descriptor;
+use crate::types::{ConnectionSpeed, DeviceAddress, SetupPacket, TransferType};
+use crate::{ControlError, PipeId, TransferError, UsbHost};
+use usb_device::control::{Recipient, RequestType};
+use usb_device::UsbDirection;
+
+/// Interface class code for mass storage
+const CLASS_MASS_STORAGE: u8 = 0x08;
+/// Interface subclass code for the SCSI transparent command set
+const SUBCLASS_SCSI: u8 = 0x06;
+/// Interface protocol code for Bulk-Only Transport
+const PROTOCOL_BULK_ONLY: u8 = 0x50;
+
+/// Class request resetting the mass storage interface (Bulk-Only Mass Storage Reset)
+const REQUEST_BULK_ONLY_RESET: u8 = 0xFF;
+
+const CBW_SIGNATURE: u32 = 0x43425355;
+const CBW_LENGTH: usize = 31;
+const CSW_SIGNATURE: u32 = 0x53425355;
+const CSW_LENGTH: usize = 13;
+
+/// Size of the data buffer, which limits the supported block size
+pub const BLOCK_BUFFER_SIZE: usize = 512;
+
+const OP_TEST_UNIT_READY: u8 = 0x00;
+const OP_REQUEST_SENSE: u8 = 0x03;
+const OP_INQUIRY: u8 = 0x12;
+const OP_READ_CAPACITY_10: u8 = 0x25;
+const OP_READ_10: u8 = 0x28;
+const OP_WRITE_10: u8 = 0x2A;
+
+const INQUIRY_LENGTH: u16 = 36;
+const REQUEST_SENSE_LENGTH: u16 = 18;
+const READ_CAPACITY_LENGTH: u16 = 8;
+
+/// Command Block Wrapper, sent at the start of each command
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct CommandBlockWrapper {
+    /// Tag identifying the command. The device echoes it in the corresponding CSW.
+    pub tag: u32,
+    /// Number of bytes expected to be transferred in the data stage
+    pub data_transfer_length: u32,
+    /// Direction of the data stage
+    pub direction: UsbDirection,
+    /// Logical unit the command is addressed to
+    pub lun: u8,
+    /// The command block (only the first `command_length` bytes are valid)
+    pub command: [u8; 16],
+    pub command_length: u8,
+}
+
+impl CommandBlockWrapper {
+    /// Encode the CBW in the format expected by the device
+    pub fn encode(&self) -> [u8; CBW_LENGTH] {
+        let mut buf = [0; CBW_LENGTH];
+        buf[0..4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.tag.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.data_transfer_length.to_le_bytes());
+        buf[12] = if self.direction == UsbDirection::In { 0x80 } else { 0x00 };
+        buf[13] = self.lun & 0x0F;
+        buf[14] = self.command_length & 0x1F;
+        buf[15..31].copy_from_slice(&self.command);
+        buf
+    }
+}
+
+/// Command Status Wrapper, received at the end of each command
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CommandStatusWrapper {
+    /// Tag of the command this status belongs to
+    pub tag: u32,
+    /// Difference between the expected and actual amount of data transferred
+    pub data_residue: u32,
+    pub status: CommandStatus,
+}
+
+/// Status reported in a [`CommandStatusWrapper`]
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CommandStatus {
+    Passed,
+    Failed,
+    PhaseError,
+    /// Status value not defined by the specification
+    Unknown(u8),
+}
+
+impl From<u8> for CommandStatus {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => CommandStatus::Passed,
+            1 => CommandStatus::Failed,
+            2 => CommandStatus::PhaseError,
+            other => CommandStatus::Unknown(other),
+        }
+    }
+}
+
+impl CommandStatusWrapper {
+    /// Decode a CSW
+    ///
+    /// Returns `None` if the data is too short, or does not carry the CSW signature.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < CSW_LENGTH || data[0..4] != CSW_SIGNATURE.to_le_bytes() {
+            return None;
+        }
+        Some(CommandStatusWrapper {
+            tag: u32::from_le_bytes([data[4], data[5], data[6], data[7]]),
+            data_residue: u32::from_le_bytes([data[8], data[9], data[10], data[11]]),
+            status: data[12].into(),
+        })
+    }
+}
+
+/// Information returned by the INQUIRY command
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct InquiryData {
+    /// SCSI peripheral device type (0 for direct access block devices)
+    pub device_type: u8,
+    /// Whether the medium is removable
+    pub removable: bool,
+    /// Vendor identification, ASCII, padded with spaces
+    pub vendor: [u8; 8],
+    /// Product identification, ASCII, padded with spaces
+    pub product: [u8; 16],
+    /// Product revision level, ASCII, padded with spaces
+    pub revision: [u8; 4],
+}
+
+impl InquiryData {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < INQUIRY_LENGTH as usize {
+            return None;
+        }
+        let mut result = InquiryData {
+            device_type: data[0] & 0x1F,
+            removable: data[1] & 0x80 != 0,
+            vendor: [0; 8],
+            product: [0; 16],
+            revision: [0; 4],
+        };
+        result.vendor.copy_from_slice(&data[8..16]);
+        result.product.copy_from_slice(&data[16..32]);
+        result.revision.copy_from_slice(&data[32..36]);
+        Some(result)
+    }
+}
+
+/// Capacity of the medium, as returned by the READ CAPACITY(10) command
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Capacity {
+    /// Address of the last block on the medium
+    pub last_lba: u32,
+    /// Size of each block, in bytes
+    pub block_size: u32,
+}
+
+impl Capacity {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        match data {
+            [a, b, c, d, e, f, g, h, ..] => Some(Capacity {
+                last_lba: u32::from_be_bytes([*a, *b, *c, *d]),
+                block_size: u32::from_be_bytes([*e, *f, *g, *h]),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Total number of blocks on the medium
+    pub fn block_count(&self) -> u64 {
+        self.last_lba as u64 + 1
+    }
+}
+
+/// Sense data, as returned by the REQUEST SENSE command
+///
+/// Describes why the previous command failed.
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SenseData {
+    pub sense_key: u8,
+    pub additional_sense_code: u8,
+    pub additional_sense_code_qualifier: u8,
+}
+
+impl SenseData {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 14 {
+            return None;
+        }
+        Some(SenseData {
+            sense_key: data[2] & 0x0F,
+            additional_sense_code: data[12],
+            additional_sense_code_qualifier: data[13],
+        })
+    }
+}
+
+/// Events generated by the [`MscDriver`]
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MscEvent {
+    /// A mass storage device was detected & configured
+    DeviceAdded(DeviceAddress),
+
+    /// The mass storage device was removed
+    DeviceRemoved(DeviceAddress),
+
+    /// TEST UNIT READY succeeded: the medium is ready for access
+    UnitReady(DeviceAddress),
+
+    /// INQUIRY completed
+    Inquiry(DeviceAddress, InquiryData),
+
+    /// READ CAPACITY completed
+    Capacity(DeviceAddress, Capacity),
+
+    /// REQUEST SENSE completed
+    Sense(DeviceAddress, SenseData),
+
+    /// The block with the given address was read. The data can be accessed with [`MscDriver::block_data`].
+    ReadComplete(DeviceAddress, u32),
+
+    /// The block with the given address was written.
+    WriteComplete(DeviceAddress, u32),
+
+    /// The device reported that the current command failed, or the command was aborted because an endpoint stalled.
+    ///
+    /// For [`CommandStatus::Failed`], details can be obtained with [`MscDriver::request_sense`]. If the failure required
+    /// a reset recovery, this is reported once the recovery has finished.
+    CommandFailed(DeviceAddress, CommandStatus),
+}
+
+/// Error type for interactions with the driver
+#[derive(Copy, Clone, Debug)]
+pub enum MscError {
+    /// Error initiating a bulk transfer
+    ControlError(ControlError),
+
+    /// No mass storage device is currently attached & configured
+    NotConfigured,
+
+    /// Another command is still in progress
+    Busy,
+
+    /// The block size of the medium is not known yet (see [`MscDriver::read_capacity`]),
+    /// or exceeds [`BLOCK_BUFFER_SIZE`].
+    UnsupportedBlockSize,
+
+    /// The data passed to [`MscDriver::write_block`] does not match the block size.
+    InvalidLength,
+}
+
+impl From<ControlError> for MscError {
+    fn from(e: ControlError) -> Self {
+        MscError::ControlError(e)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Command {
+    TestUnitReady,
+    RequestSense,
+    Inquiry,
+    ReadCapacity,
+    Read(u32),
+    Write(u32),
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Stage {
+    Command,
+    Data,
+    Status,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Transaction {
+    command: Command,
+    cbw: CommandBlockWrapper,
+    stage: Stage,
+    /// Bytes transferred in the data stage so far
+    transferred: u16,
+    /// Set while a transfer for the current stage is in progress
+    in_flight: bool,
+}
+
+impl Transaction {
+    fn data_length(&self) -> u16 {
+        self.cbw.data_transfer_length as u16
+    }
+}
+
+/// Steps of the reset recovery, in order
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum RecoveryStep {
+    Reset,
+    ClearInHalt,
+    ClearOutHalt,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Recovery {
+    step: RecoveryStep,
+    /// Set while the request for the current step is in progress
+    in_flight: bool,
+    /// Status reported for the failed command, once the recovery is done
+    status: CommandStatus,
+}
+
+/// Descriptors of a newly attached device, as far as they were inspected
+#[derive(Copy, Clone, Default, Debug)]
+struct Pending {
+    /// Configuration currently being inspected
+    config: Option<u8>,
+    /// Number of the interface whose descriptors are being inspected, if it matches
+    interface: Option<u8>,
+    /// Endpoint number & max packet size of the bulk IN endpoint
+    bulk_in: Option<(u8, u16)>,
+    /// Endpoint number & max packet size of the bulk OUT endpoint
+    bulk_out: Option<(u8, u16)>,
+    /// Configuration containing a matching interface
+    chosen_config: Option<u8>,
+}
+
+/// The device was configured, pipes are open
+#[derive(Copy, Clone, Debug)]
+struct Configured {
+    interface: u8,
+    /// Used for reset recovery
+    control_pipe: PipeId,
+    in_ep: u8,
+    in_pipe: PipeId,
+    in_max_packet_size: u16,
+    out_ep: u8,
+    out_pipe: PipeId,
+    out_max_packet_size: u16,
+}
+
+/// Driver for mass storage devices using the Bulk-Only Transport
+///
+/// See [module-level documentation](crate::driver::msc) for details.
+#[derive(Debug)]
+pub struct MscDriver {
+    state: SingleDevice<Pending, Configured>,
+    transaction: Option<Transaction>,
+    recovery: Option<Recovery>,
+    next_tag: u32,
+    block_size: Option<u32>,
+    buffer: [u8; BLOCK_BUFFER_SIZE],
+    events: EventQueue<MscEvent, EVENT_QUEUE_DEPTH>,
+}
+
+impl Default for MscDriver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MscDriver {
+    pub fn new() -> Self {
+        Self {
+            state: SingleDevice::Idle,
+            transaction: None,
+            recovery: None,
+            next_tag: 1,
+            block_size: None,
+            buffer: [0; BLOCK_BUFFER_SIZE],
+            events: EventQueue::new(),
+        }
+    }
+
+    /// Returns the oldest pending event (if any), and removes it from the queue.
+    ///
+    /// This method should be called repeatedly after calling `usb_host.poll(...)` and `msc.poll(...)`, until it returns `None` (see
+    /// [`HasEvents`] for what happens otherwise).
+    pub fn take_event(&mut self) -> Option<MscEvent> {
+        self.events.pop()
+    }
+
+    /// Address of the device, if it is currently configured
+    pub fn device_address(&self) -> Option<DeviceAddress> {
+        self.state.address()
+    }
+
+    /// Returns `true` if a command (or the reset recovery after a failed command) is currently in progress
+    pub fn busy(&self) -> bool {
+        self.transaction.is_some() || self.recovery.is_some()
+    }
+
+    /// Data of the block read by the most recent [`read_block`](MscDriver::read_block)
+    pub fn block_data(&self) -> &[u8] {
+        &self.buffer[..self.block_size.unwrap_or(0) as usize]
+    }
+
+    /// Issue a TEST UNIT READY command
+    ///
+    /// Results in [`MscEvent::UnitReady`] or [`MscEvent::CommandFailed`].
+    pub fn test_unit_ready(&mut self) -> Result<(), MscError> {
+        self.start_command(Command::TestUnitReady, UsbDirection::Out, 0, &[OP_TEST_UNIT_READY, 0, 0, 0, 0, 0])
+    }
+
+    /// Issue a REQUEST SENSE command
+    ///
+    /// Results in [`MscEvent::Sense`].
+    pub fn request_sense(&mut self) -> Result<(), MscError> {
+        self.start_command(
+            Command::RequestSense,
+            UsbDirection::In,
+            REQUEST_SENSE_LENGTH,
+            &[OP_REQUEST_SENSE, 0, 0, 0, REQUEST_SENSE_LENGTH as u8, 0],
+        )
+    }
+
+    /// Issue an INQUIRY command
+    ///
+    /// Results in [`MscEvent::Inquiry`].
+    pub fn inquiry(&mut self) -> Result<(), MscError> {
+        self.start_command(
+            Command::Inquiry,
+            UsbDirection::In,
+            INQUIRY_LENGTH,
+            &[OP_INQUIRY, 0, 0, 0, INQUIRY_LENGTH as u8, 0],
+        )
+    }
+
+    /// Issue a READ CAPACITY(10) command
+    ///
+    /// Results in [`MscEvent::Capacity`]. The block size is remembered, and used for subsequent reads and writes.
+    pub fn read_capacity(&mut self) -> Result<(), MscError> {
+        self.start_command(
+            Command::ReadCapacity,
+            UsbDirection::In,
+            READ_CAPACITY_LENGTH,
+            &[OP_READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        )
+    }
+
+    /// Read the block with the given logical block address
+    ///
+    /// Results in [`MscEvent::ReadComplete`], after which the data can be accessed with [`block_data`](MscDriver::block_data).
+    pub fn read_block(&mut self, lba: u32) -> Result<(), MscError> {
+        let block_size = self.supported_block_size()?;
+        self.start_command(Command::Read(lba), UsbDirection::In, block_size, &rw10_command(OP_READ_10, lba))
+    }
+
+    /// Write the block with the given logical block address
+    ///
+    /// The `data` must be exactly one block long. Results in [`MscEvent::WriteComplete`].
+    pub fn write_block(&mut self, lba: u32, data: &[u8]) -> Result<(), MscError> {
+        let block_size = self.supported_block_size()?;
+        if data.len() != block_size as usize {
+            return Err(MscError::InvalidLength);
+        }
+        self.start_command(Command::Write(lba), UsbDirection::Out, block_size, &rw10_command(OP_WRITE_10, lba))?;
+        self.buffer[..data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Advance the current command, by initiating the next transfer (if any)
+    ///
+    /// Must be called after every call to `usb_host.poll(...)`. If the bus is currently busy, nothing happens,
+    /// and the transfer is initiated on a later call.
+    pub fn poll<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    >(
+        &mut self,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) -> Result<(), MscError> {
+        let SingleDevice::Configured(dev_addr, Configured { in_pipe, in_max_packet_size, out_pipe, out_max_packet_size, .. }) = self.state else {
+            return Ok(());
+        };
+        if self.recovery.is_some() {
+            return self.poll_recovery(dev_addr, host);
+        }
+        let Some(transaction) = &mut self.transaction else {
+            return Ok(());
+        };
+        if transaction.in_flight {
+            return Ok(());
+        }
+        let result = match transaction.stage {
+            Stage::Command => host.bulk_out(out_pipe, &transaction.cbw.encode()),
+            Stage::Data if transaction.cbw.direction == UsbDirection::In => {
+                let chunk = in_max_packet_size.min(transaction.data_length() - transaction.transferred);
+                host.bulk_in(in_pipe, chunk)
+            }
+            Stage::Data => {
+                let start = transaction.transferred as usize;
+                let chunk = out_max_packet_size.min(transaction.data_length() - transaction.transferred);
+                host.bulk_out(out_pipe, &self.buffer[start..start + chunk as usize])
+            }
+            Stage::Status => host.bulk_in(in_pipe, CSW_LENGTH as u16),
+        };
+        match result {
+            Ok(()) => {
+                transaction.in_flight = true;
+                Ok(())
+            }
+            Err(ControlError::WouldBlock) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Start the request for the current step of the reset recovery
+    fn poll_recovery<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    >(
+        &mut self,
+        dev_addr: DeviceAddress,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) -> Result<(), MscError> {
+        let SingleDevice::Configured(_, Configured { interface, control_pipe, in_ep, out_ep, .. }) = self.state else {
+            return Ok(());
+        };
+        let Some(recovery) = &mut self.recovery else {
+            return Ok(());
+        };
+        if recovery.in_flight {
+            return Ok(());
+        }
+        let result = match recovery.step {
+            RecoveryStep::Reset => host.control_out(
+                Some(dev_addr),
+                Some(control_pipe),
+                SetupPacket::new(
+                    UsbDirection::Out,
+                    RequestType::Class,
+                    Recipient::Interface,
+                    REQUEST_BULK_ONLY_RESET,
+                    0,
+                    interface as u16,
+                    0,
+                ),
+                &[],
+            ),
+            RecoveryStep::ClearInHalt => host.clear_halt(dev_addr, Some(control_pipe), in_ep | UsbDirection::In as u8),
+            RecoveryStep::ClearOutHalt => host.clear_halt(dev_addr, Some(control_pipe), out_ep),
+        };
+        match result {
+            Ok(()) => {
+                recovery.in_flight = true;
+                Ok(())
+            }
+            Err(ControlError::WouldBlock) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Abort the current command, and start the reset recovery
+    fn start_recovery(&mut self, status: CommandStatus) {
+        self.transaction = None;
+        self.recovery = Some(Recovery { step: RecoveryStep::Reset, in_flight: false, status });
+    }
+
+    fn supported_block_size(&self) -> Result<u16, MscError> {
+        match self.block_size {
+            Some(size) if size as usize <= BLOCK_BUFFER_SIZE => Ok(size as u16),
+            _ => Err(MscError::UnsupportedBlockSize),
+        }
+    }
+
+    fn start_command(
+        &mut self,
+        command: Command,
+        direction: UsbDirection,
+        data_length: u16,
+        command_block: &[u8],
+    ) -> Result<(), MscError> {
+        if self.device_address().is_none() {
+            return Err(MscError::NotConfigured);
+        }
+        if self.busy() {
+            return Err(MscError::Busy);
+        }
+        let mut cbw = CommandBlockWrapper {
+            tag: self.next_tag,
+            data_transfer_length: data_length as u32,
+            direction,
+            lun: 0,
+            command: [0; 16],
+            command_length: command_block.len() as u8,
+        };
+        cbw.command[..command_block.len()].copy_from_slice(command_block);
+        self.next_tag = self.next_tag.wrapping_add(1);
+        self.transaction = Some(Transaction {
+            command,
+            cbw,
+            stage: Stage::Command,
+            transferred: 0,
+            in_flight: false,
+        });
+        Ok(())
+    }
+
+    /// Handle the CSW received for the current transaction
+    fn finish(&mut self, dev_addr: DeviceAddress, transaction: Transaction, data: &[u8]) {
+        let status = match CommandStatusWrapper::parse(data) {
+            Some(csw) if csw.tag == transaction.cbw.tag => csw.status,
+            // an invalid CSW requires reset recovery, just like a phase error
+            _ => CommandStatus::PhaseError,
+        };
+        if status == CommandStatus::PhaseError {
+            self.start_recovery(status);
+            return;
+        }
+        if status != CommandStatus::Passed {
+            self.events.push(MscEvent::CommandFailed(dev_addr, status));
+            return;
+        }
+        let received = &self.buffer[..transaction.transferred as usize];
+        let event = match transaction.command {
+            Command::TestUnitReady => Some(MscEvent::UnitReady(dev_addr)),
+            Command::RequestSense => SenseData::parse(received).map(|sense| MscEvent::Sense(dev_addr, sense)),
+            Command::Inquiry => InquiryData::parse(received).map(|inquiry| MscEvent::Inquiry(dev_addr, inquiry)),
+            Command::ReadCapacity => Capacity::parse(received).map(|capacity| {
+                self.block_size = Some(capacity.block_size);
+                MscEvent::Capacity(dev_addr, capacity)
+            }),
+            Command::Read(lba) => Some(MscEvent::ReadComplete(dev_addr, lba)),
+            Command::Write(lba) => Some(MscEvent::WriteComplete(dev_addr, lba)),
+        };
+        // without data, the device sent less than required
+        self.events.push(event.unwrap_or(MscEvent::CommandFailed(dev_addr, CommandStatus::PhaseError)));
+    }
+
+    fn reset(&mut self) {
+        self.state = SingleDevice::Idle;
+        self.transaction = None;
+        self.recovery = None;
+        self.block_size = None;
+    }
+}
+
+/// Build a READ(10) or WRITE(10) command block, for a single block
+fn rw10_command(opcode: u8, lba: u32) -> [u8; 10] {
+    let lba = lba.to_be_bytes();
+    [opcode, 0, lba[0], lba[1], lba[2], lba[3], 0, 0, 1, 0]
+}
+
+impl HasEvents for MscDriver {
+    type Event = MscEvent;
+
+    fn take_event(&mut self) -> Option<MscEvent> {
+        self.events.pop()
+    }
+
+    fn events_pending(&self) -> bool {
+        !self.events.is_empty()
+    }
+}
+
+impl<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    > Driver<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE> for MscDriver
+{
+    fn attached(&mut self, dev_addr: DeviceAddress, _connection_speed: ConnectionSpeed) {
+        self.state.attached(dev_addr, Pending::default());
+    }
+
+    fn detached(&mut self, dev_addr: DeviceAddress) {
+        if self.state.detached(dev_addr) {
+            self.reset();
+            self.events.push(MscEvent::DeviceRemoved(dev_addr));
+        }
+    }
+
+    fn descriptor(&mut self, dev_addr: DeviceAddress, descriptor_type: u8, data: &[u8]) {
+        let Some(Pending { config, interface: matching, bulk_in, bulk_out, chosen_config }) = self.state.pending(dev_addr) else {
+            return;
+        };
+        if chosen_config.is_some() {
+            return;
+        }
+        match descriptor_type {
+            descriptor::TYPE_CONFIGURATION => {
+                if let Ok((_, configuration)) = descriptor::parse::configuration_descriptor(data) {
+                    *config = Some(configuration.value);
+                    *matching = None;
+                }
+            }
+            descriptor::TYPE_INTERFACE => {
+                if let Ok((_, interface)) = descriptor::parse::interface_descriptor(data) {
+                    *matching = Some(interface.interface_number).filter(|_| {
+                        interface.interface_class == CLASS_MASS_STORAGE
+                            && interface.interface_sub_class == SUBCLASS_SCSI
+                            && interface.interface_protocol == PROTOCOL_BULK_ONLY
+                    });
+                    *bulk_in = None;
+                    *bulk_out = None;
+                }
+            }
+            descriptor::TYPE_ENDPOINT if matching.is_some() => {
+                if let Ok((_, endpoint)) = descriptor::parse::endpoint_descriptor(data) {
+                    if endpoint.attributes.transfer_type() != TransferType::Bulk || endpoint.max_packet_size == 0 {
+                        return;
+                    }
+                    let found = Some((endpoint.address.number(), endpoint.max_packet_size));
+                    match endpoint.address.direction() {
+                        UsbDirection::In => *bulk_in = found,
+                        UsbDirection::Out => *bulk_out = found,
+                    }
+                    if bulk_in.is_some() && bulk_out.is_some() {
+                        *chosen_config = *config;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn configure(&mut self, dev_addr: DeviceAddress) -> Option<u8> {
+        self.state.configure(dev_addr, |pending| pending.chosen_config)
+    }
+
+    fn configured(
+        &mut self,
+        dev_addr: DeviceAddress,
+        value: u8,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) {
+        let Some(Pending { interface, chosen_config, bulk_in, bulk_out, .. }) = self.state.take_pending(dev_addr) else {
+            return;
+        };
+        let (Some(interface), Some((in_ep, in_max_packet_size)), Some((out_ep, out_max_packet_size))) = (interface, bulk_in, bulk_out) else {
+            return;
+        };
+        if chosen_config != Some(value) {
+            return;
+        }
+        let control_pipe = host.create_control_pipe(dev_addr);
+        let in_pipe = host.create_bulk_pipe(dev_addr, in_ep, UsbDirection::In, in_max_packet_size);
+        let out_pipe = host.create_bulk_pipe(dev_addr, out_ep, UsbDirection::Out, out_max_packet_size);
+        let (Some(control_pipe), Some(in_pipe), Some(out_pipe)) = (control_pipe, in_pipe, out_pipe) else {
+            // the host ran out of pipes: release the ones that were created
+            for pipe in control_pipe.into_iter().chain(in_pipe).chain(out_pipe) {
+                host.release_pipe(pipe);
+            }
+            return;
+        };
+        // start at a random tag, so that a stale CSW from before a reconnect is not mistaken for a new one
+        self.next_tag = host.random_u32();
+        self.state = SingleDevice::Configured(
+            dev_addr,
+            Configured { interface, control_pipe, in_ep, in_pipe, in_max_packet_size, out_ep, out_pipe, out_max_packet_size },
+        );
+        self.events.push(MscEvent::DeviceAdded(dev_addr));
+    }
+
+    fn completed_control(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, _data: Option<&[u8]>) {
+        let Some(&Configured { control_pipe, .. }) = self.state.device(dev_addr) else {
+            return;
+        };
+        if pipe_id != control_pipe {
+            return;
+        }
+        // control transfers are only made for the reset recovery
+        let Some(recovery) = &mut self.recovery else {
+            return;
+        };
+        recovery.in_flight = false;
+        recovery.step = match recovery.step {
+            RecoveryStep::Reset => RecoveryStep::ClearInHalt,
+            RecoveryStep::ClearInHalt => RecoveryStep::ClearOutHalt,
+            RecoveryStep::ClearOutHalt => {
+                let status = recovery.status;
+                self.recovery = None;
+                self.events.push(MscEvent::CommandFailed(dev_addr, status));
+                return;
+            }
+        };
+    }
+
+    fn completed_in(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, data: &[u8]) {
+        let Some(&Configured { in_pipe, in_max_packet_size, .. }) = self.state.device(dev_addr) else {
+            return;
+        };
+        if pipe_id != in_pipe {
+            return;
+        }
+        let Some(transaction) = &mut self.transaction else {
+            return;
+        };
+        if !transaction.in_flight {
+            return;
+        }
+        transaction.in_flight = false;
+        match transaction.stage {
+            Stage::Data => {
+                let requested = in_max_packet_size.min(transaction.data_length() - transaction.transferred);
+                let start = transaction.transferred as usize;
+                let len = data.len().min(requested as usize);
+                self.buffer[start..start + len].copy_from_slice(&data[..len]);
+                transaction.transferred += len as u16;
+                // a short packet ends the data stage early
+                if len < requested as usize || transaction.transferred >= transaction.data_length() {
+                    transaction.stage = Stage::Status;
+                }
+            }
+            Stage::Status => {
+                let transaction = *transaction;
+                self.transaction = None;
+                self.finish(dev_addr, transaction, data);
+            }
+            Stage::Command => {}
+        }
+    }
+
+    fn completed_bulk_out(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId) {
+        let Some(&Configured { out_pipe, out_max_packet_size, .. }) = self.state.device(dev_addr) else {
+            return;
+        };
+        if pipe_id != out_pipe {
+            return;
+        }
+        let Some(transaction) = &mut self.transaction else {
+            return;
+        };
+        if !transaction.in_flight {
+            return;
+        }
+        transaction.in_flight = false;
+        match transaction.stage {
+            Stage::Command => {
+                transaction.stage = if transaction.data_length() == 0 { Stage::Status } else { Stage::Data };
+            }
+            Stage::Data => {
+                transaction.transferred += out_max_packet_size.min(transaction.data_length() - transaction.transferred);
+                if transaction.transferred >= transaction.data_length() {
+                    transaction.stage = Stage::Status;
+                }
+            }
+            Stage::Status => {}
+        }
+    }
+
+    fn completed_out(&mut self, _dev_addr: DeviceAddress, _pipe_id: PipeId, _data: &mut [u8]) {
+        // ignored, since there are no interrupt OUT pipes in use.
+    }
+
+    fn transfer_failed(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, error: TransferError) {
+        let Some(&Configured { control_pipe, .. }) = self.state.device(dev_addr) else {
+            return;
+        };
+        if pipe_id == control_pipe {
+            // the device did not accept the reset recovery: there is nothing else to try
+            if let Some(recovery) = self.recovery.take() {
+                self.events.push(MscEvent::CommandFailed(dev_addr, recovery.status));
+            }
+        } else if self.transaction.is_some() {
+            match error {
+                TransferError::Stall { .. } => self.start_recovery(CommandStatus::Failed),
+                _ => {
+                    self.transaction = None;
+                    self.events.push(MscEvent::CommandFailed(dev_addr, CommandStatus::Failed));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::mock::fixtures::DEVICE_DESCRIPTOR;
+    use crate::bus::mock::{ControlResponse, MockDevice, MockHostBus, Response};
+
+    #[test]
+    fn test_encode_cbw() {
+        let mut command = [0; 16];
+        command[..10].copy_from_slice(&rw10_command(OP_READ_10, 0x12345678));
+        let cbw = CommandBlockWrapper {
+            tag: 0xAABBCCDD,
+            data_transfer_length: 512,
+            direction: UsbDirection::In,
+            lun: 0,
+            command,
+            command_length: 10,
+        };
+        let encoded = cbw.encode();
+        assert_eq!(&encoded[0..4], b"USBC");
+        assert_eq!(&encoded[4..8], &[0xDD, 0xCC, 0xBB, 0xAA]);
+        assert_eq!(&encoded[8..12], &[0x00, 0x02, 0x00, 0x00]);
+        assert_eq!(encoded[12], 0x80);
+        assert_eq!(encoded[14], 10);
+        assert_eq!(&encoded[15..25], &[0x28, 0, 0x12, 0x34, 0x56, 0x78, 0, 0, 1, 0]);
+    }
+
+    #[test]
+    fn test_parse_csw() {
+        let csw = CommandStatusWrapper::parse(b"USBS\x01\x00\x00\x00\x10\x00\x00\x00\x01").unwrap();
+        assert_eq!(csw.tag, 1);
+        assert_eq!(csw.data_residue, 16);
+        assert!(csw.status == CommandStatus::Failed);
+        assert!(CommandStatusWrapper::parse(b"USBC\x01\x00\x00\x00\x10\x00\x00\x00\x01").is_none());
+        assert!(CommandStatusWrapper::parse(b"USBS\x01").is_none());
+    }
+
+    #[test]
+    fn test_parse_capacity() {
+        let capacity = Capacity::parse(&[0x00, 0x3F, 0xFF, 0xFF, 0x00, 0x00, 0x02, 0x00]).unwrap();
+        assert_eq!(capacity.last_lba, 0x3FFFFF);
+        assert_eq!(capacity.block_size, 512);
+        assert_eq!(capacity.block_count(), 0x400000);
+    }
+
+    const MSC_DESCRIPTOR: &[u8] = &[
+        0x09, 0x02, 0x20, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32, // configuration 1
+        0x09, 0x04, 0x00, 0x00, 0x02, 0x08, 0x06, 0x50, 0x00, // interface 0: SCSI bulk-only transport
+        0x07, 0x05, 0x81, 0x02, 0x40, 0x00, 0x00, // endpoint 1 IN, bulk
+        0x07, 0x05, 0x02, 0x02, 0x40, 0x00, 0x00, // endpoint 2 OUT, bulk
+    ];
+
+    const fn accept(request_type: u8, request: u8, index: u16) -> ControlResponse<'static> {
+        ControlResponse { request_type, request, value: 0, index, response: Response::Data(&[]) }
+    }
+
+    #[test]
+    fn test_msc_reset_recovery() {
+        // bulk-only mass storage reset, then CLEAR_FEATURE(ENDPOINT_HALT) for both endpoints
+        const RECOVERY: &[ControlResponse] = &[accept(0x21, 0xff, 0), accept(0x02, 0x01, 0x81), accept(0x02, 0x01, 0x02)];
+        let device = MockDevice { control_responses: RECOVERY, ..MockDevice::new(DEVICE_DESCRIPTOR, &[MSC_DESCRIPTOR]) };
+        let mut host = UsbHost::new(MockHostBus::new(device));
+        let mut msc = MscDriver::new();
+        host.bus().attach();
+
+        let mut failures = [None; 2];
+        for _ in 0..2000 {
+            host.poll(&mut [&mut msc]);
+            assert!(msc.poll(&mut host).is_ok());
+            match msc.take_event() {
+                Some(MscEvent::DeviceAdded(_)) => {
+                    // the status stage STALLs
+                    host.bus().set_bulk_stall(true);
+                    assert!(msc.test_unit_ready().is_ok());
+                }
+                Some(MscEvent::CommandFailed(_, status)) if failures[0].is_none() => {
+                    failures[0] = Some(status);
+                    assert!(!msc.busy());
+                    // the IN endpoint is no longer halted, but the device sends an invalid CSW
+                    host.bus().set_bulk_in(b"garbage");
+                    assert!(msc.test_unit_ready().is_ok());
+                }
+                Some(MscEvent::CommandFailed(_, status)) => failures[1] = Some(status),
+                _ => {}
+            }
+        }
+        assert!(failures == [Some(CommandStatus::Failed), Some(CommandStatus::PhaseError)]);
+        assert!(!msc.busy());
+    }
+}
@@ -0,0 +1,667 @@
+//! Printers (receipt printers, label printers, ...)
+//!
+//! The [`PrinterDriver`] supports devices implementing the USB printer class (interface class 7, subclass 1), with the
+//! unidirectional (1) or bidirectional (2) protocol. Print data is sent to the bulk OUT endpoint, as-is: the driver does
+//! not know anything about the page description language (ESC/POS, PCL, ZPL, ...) that the printer expects.
+//!
+//! In addition the driver supports the class specific requests:
+//! - GET_DEVICE_ID, which returns the IEEE 1284 device ID string (e.g. `MFG:ACME;MDL:Receipt 80;CMD:ESC/POS;`)
+//! - GET_PORT_STATUS, which reports whether the printer is selected, out of paper, or in an error state.
+//!   The status can be polled periodically, see [`PrinterDriver::set_status_interval`].
+//!
+//! Since bulk transfers cannot be initiated from within driver callbacks, the application must call [`PrinterDriver::poll`]
+//! after every call to [`UsbHost::poll`], to send the print data.
+//!
+//! Example:
+//! ```ignore
+//! let mut printer = PrinterDriver::new();
+//!
+//! loop {
+//!     usb_host.poll(&mut [&mut printer]);
+//!     printer.poll(&mut usb_host).ok();
+//!
+//!     match printer.take_event() {
+//!         Some(PrinterEvent::DeviceAdded(_)) => printer.get_device_id(&mut usb_host).unwrap(),
+//!         Some(PrinterEvent::DeviceId(_)) => printer.print(b"Hello, world!\n").unwrap(),
+//!         _ => {}
+//!     }
+//! }
+//! ```
+//!
+//! Limitations:
+//! - Only a single device is supported
+//! - The data passed to a single [`print`](PrinterDriver::print) call must not exceed [`PRINT_BUFFER_SIZE`]
+//! - The bulk IN endpoint of bidirectional printers is not used
+
+use super::{detector::SingleDevice, Driver, EventQueue, HasEvents, EVENT_QUEUE_DEPTH};
+use crate::bus::HostBus;
+use crate::descriptor;
+use crate::types::{ConnectionSpeed, DeviceAddress, SetupPacket, TransferType};
+use crate::{ControlError, PipeId, TransferError, UsbHost};
+use bitflags::bitflags;
+use usb_device::control::{Recipient, RequestType};
+use usb_device::UsbDirection;
+
+/// Interface class code for printers
+const CLASS_PRINTER: u8 = 0x07;
+/// Interface subclass code for printers
+const SUBCLASS_PRINTER: u8 = 0x01;
+const PROTOCOL_UNIDIRECTIONAL: u8 = 0x01;
+const PROTOCOL_BIDIRECTIONAL: u8 = 0x02;
+
+const REQUEST_GET_DEVICE_ID: u8 = 0x00;
+const REQUEST_GET_PORT_STATUS: u8 = 0x01;
+
+/// Size of the print buffer, which limits the amount of data passed to a single [`PrinterDriver::print`] call
+pub const PRINT_BUFFER_SIZE: usize = 512;
+
+/// Maximum length of the device ID that is retained, including its two byte length prefix
+pub const DEVICE_ID_BUFFER_SIZE: usize = 256;
+
+bitflags! {
+    /// Status byte returned by GET_PORT_STATUS
+    ///
+    /// All other bits are reserved.
+    pub struct PortStatus: u8 {
+        /// The printer is out of paper
+        const PAPER_EMPTY = 1 << 5;
+        /// The printer is selected (online)
+        const SELECT = 1 << 4;
+        /// The printer is *not* in an error state
+        const NOT_ERROR = 1 << 3;
+    }
+}
+
+/// Events generated by the [`PrinterDriver`]
+#[derive(Copy, Clone, Debug)]
+pub enum PrinterEvent {
+    /// A printer was detected & configured
+    DeviceAdded(DeviceAddress),
+
+    /// The printer was removed
+    DeviceRemoved(DeviceAddress),
+
+    /// GET_DEVICE_ID completed. The ID can be accessed with [`PrinterDriver::device_id`].
+    DeviceId(DeviceAddress),
+
+    /// GET_PORT_STATUS completed
+    ///
+    /// Explicit requests (see [`PrinterDriver::get_port_status`]) are always reported. When the status is polled
+    /// periodically, it is only reported when it changed.
+    PortStatus(DeviceAddress, PortStatus),
+
+    /// All data passed to [`PrinterDriver::print`] was sent to the printer
+    PrintComplete(DeviceAddress),
+
+    /// Sending print data failed, and the rest of the data was discarded
+    PrintFailed(DeviceAddress),
+}
+
+/// Error type for interactions with the driver
+#[derive(Copy, Clone, Debug)]
+pub enum PrinterError {
+    /// Error initiating a transfer
+    ControlError(ControlError),
+
+    /// No printer is currently attached & configured
+    NotConfigured,
+
+    /// Print data from a previous call to [`PrinterDriver::print`] is still being sent, or another
+    /// control request is in progress.
+    Busy,
+
+    /// The data passed to [`PrinterDriver::print`] exceeds [`PRINT_BUFFER_SIZE`].
+    InvalidLength,
+}
+
+impl From<ControlError> for PrinterError {
+    fn from(e: ControlError) -> Self {
+        PrinterError::ControlError(e)
+    }
+}
+
+/// Class specific control request in progress
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum ControlRequest {
+    DeviceId,
+    /// Port status, `true` if it was requested explicitly
+    PortStatus(bool),
+}
+
+/// Print data being sent
+#[derive(Copy, Clone, Debug)]
+struct PrintJob {
+    length: u16,
+    /// Bytes sent so far
+    sent: u16,
+    /// Set while a bulk transfer is in progress
+    in_flight: bool,
+}
+
+/// Descriptors of a newly attached device, as far as they were inspected
+#[derive(Copy, Clone, Default, Debug)]
+struct Pending {
+    /// Value & index of the configuration currently being inspected
+    config: Option<(u8, u8)>,
+    /// Number of the matching interface currently being inspected, and its alternate setting
+    interface: Option<(u8, u8)>,
+    /// Endpoint number & max packet size of the bulk OUT endpoint
+    bulk_out: Option<(u8, u16)>,
+    /// Configuration (value & index), interface and alternate setting containing a matching interface
+    chosen: Option<((u8, u8), (u8, u8))>,
+}
+
+/// The printer was configured, pipes are open
+#[derive(Copy, Clone, Debug)]
+struct Configured {
+    config_index: u8,
+    interface: (u8, u8),
+    control_pipe: PipeId,
+    out_pipe: PipeId,
+    out_max_packet_size: u16,
+}
+
+/// Driver for USB printers
+///
+/// See [module-level documentation](crate::driver::printer) for details.
+#[derive(Debug)]
+pub struct PrinterDriver {
+    state: SingleDevice<Pending, Configured>,
+    job: Option<PrintJob>,
+    control: Option<ControlRequest>,
+    buffer: [u8; PRINT_BUFFER_SIZE],
+    device_id: [u8; DEVICE_ID_BUFFER_SIZE],
+    device_id_len: usize,
+    last_status: Option<PortStatus>,
+    /// Interval of status polling in frames, and the frame count at which the status was last requested
+    status_polling: Option<(u32, u32)>,
+    events: EventQueue<PrinterEvent, EVENT_QUEUE_DEPTH>,
+}
+
+impl Default for PrinterDriver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PrinterDriver {
+    pub fn new() -> Self {
+        Self {
+            state: SingleDevice::Idle,
+            job: None,
+            control: None,
+            buffer: [0; PRINT_BUFFER_SIZE],
+            device_id: [0; DEVICE_ID_BUFFER_SIZE],
+            device_id_len: 0,
+            last_status: None,
+            status_polling: None,
+            events: EventQueue::new(),
+        }
+    }
+
+    /// Returns the oldest pending event (if any), and removes it from the queue.
+    ///
+    /// This method should be called repeatedly after calling `usb_host.poll(...)` and `printer.poll(...)`, until it returns `None` (see
+    /// [`HasEvents`] for what happens otherwise).
+    pub fn take_event(&mut self) -> Option<PrinterEvent> {
+        self.events.pop()
+    }
+
+    /// Address of the printer, if it is currently configured
+    pub fn device_address(&self) -> Option<DeviceAddress> {
+        self.state.address()
+    }
+
+    /// Returns `true` if print data is still being sent
+    pub fn busy(&self) -> bool {
+        self.job.is_some()
+    }
+
+    /// IEEE 1284 device ID, as returned by the most recent [`get_device_id`](PrinterDriver::get_device_id)
+    ///
+    /// The length prefix is not included. IDs longer than the buffer are truncated.
+    pub fn device_id(&self) -> &[u8] {
+        &self.device_id[..self.device_id_len]
+    }
+
+    /// Port status, as returned by the most recent GET_PORT_STATUS request
+    pub fn port_status(&self) -> Option<PortStatus> {
+        self.last_status
+    }
+
+    /// Poll the port status periodically, every `interval` frames (i.e. milliseconds)
+    ///
+    /// Disabled by default. Changes are reported via [`PrinterEvent::PortStatus`]. Requests are issued from
+    /// [`poll`](PrinterDriver::poll), while no other request is in progress.
+    pub fn set_status_interval(&mut self, interval: Option<u32>) {
+        self.status_polling = interval.map(|interval| (interval, 0));
+    }
+
+    /// Request the IEEE 1284 device ID from the printer
+    ///
+    /// Results in [`PrinterEvent::DeviceId`].
+    pub fn get_device_id<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    >(
+        &mut self,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) -> Result<(), PrinterError> {
+        let SingleDevice::Configured(dev_addr, Configured { config_index, interface: (interface, alternate), control_pipe, .. }) = self.state else {
+            return Err(PrinterError::NotConfigured);
+        };
+        if self.control.is_some() {
+            return Err(PrinterError::Busy);
+        }
+        host.control_in(
+            Some(dev_addr),
+            Some(control_pipe),
+            SetupPacket::new(
+                UsbDirection::In,
+                RequestType::Class,
+                Recipient::Interface,
+                REQUEST_GET_DEVICE_ID,
+                config_index as u16,
+                ((interface as u16) << 8) | alternate as u16,
+                DEVICE_ID_BUFFER_SIZE as u16,
+            ),
+        )?;
+        self.control = Some(ControlRequest::DeviceId);
+        Ok(())
+    }
+
+    /// Request the port status from the printer
+    ///
+    /// Results in [`PrinterEvent::PortStatus`].
+    pub fn get_port_status<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    >(
+        &mut self,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) -> Result<(), PrinterError> {
+        self.request_port_status(host, true)
+    }
+
+    /// Send the given data to the printer
+    ///
+    /// The data is copied, and sent from subsequent calls to [`poll`](PrinterDriver::poll). Results in
+    /// [`PrinterEvent::PrintComplete`] or [`PrinterEvent::PrintFailed`].
+    pub fn print(&mut self, data: &[u8]) -> Result<(), PrinterError> {
+        if self.device_address().is_none() {
+            return Err(PrinterError::NotConfigured);
+        }
+        if self.job.is_some() {
+            return Err(PrinterError::Busy);
+        }
+        if data.len() > PRINT_BUFFER_SIZE {
+            return Err(PrinterError::InvalidLength);
+        }
+        self.buffer[..data.len()].copy_from_slice(data);
+        self.job = Some(PrintJob { length: data.len() as u16, sent: 0, in_flight: false });
+        Ok(())
+    }
+
+    /// Send the next packet of print data (if any), and poll the port status when it is due
+    ///
+    /// Must be called after every call to `usb_host.poll(...)`. If the bus is currently busy, nothing happens,
+    /// and the transfer is initiated on a later call.
+    pub fn poll<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    >(
+        &mut self,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) -> Result<(), PrinterError> {
+        let SingleDevice::Configured(dev_addr, Configured { out_pipe, out_max_packet_size, .. }) = self.state else {
+            return Ok(());
+        };
+        if let Some((interval, last)) = self.status_polling {
+            let now = host.frame_count();
+            if self.control.is_none() && now.wrapping_sub(last) >= interval {
+                match self.request_port_status(host, false) {
+                    Ok(()) => self.status_polling = Some((interval, now)),
+                    Err(PrinterError::ControlError(ControlError::WouldBlock)) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        let Some(job) = &mut self.job else {
+            return Ok(());
+        };
+        if job.in_flight {
+            return Ok(());
+        }
+        if job.sent >= job.length {
+            self.job = None;
+            self.events.push(PrinterEvent::PrintComplete(dev_addr));
+            return Ok(());
+        }
+        let start = job.sent as usize;
+        let chunk = out_max_packet_size.min(job.length - job.sent);
+        match host.bulk_out(out_pipe, &self.buffer[start..start + chunk as usize]) {
+            Ok(()) => {
+                job.in_flight = true;
+                Ok(())
+            }
+            Err(ControlError::WouldBlock) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn request_port_status<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    >(
+        &mut self,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+        explicit: bool,
+    ) -> Result<(), PrinterError> {
+        let SingleDevice::Configured(dev_addr, Configured { interface: (interface, _), control_pipe, .. }) = self.state else {
+            return Err(PrinterError::NotConfigured);
+        };
+        if self.control.is_some() {
+            return Err(PrinterError::Busy);
+        }
+        host.control_in(
+            Some(dev_addr),
+            Some(control_pipe),
+            SetupPacket::new(
+                UsbDirection::In,
+                RequestType::Class,
+                Recipient::Interface,
+                REQUEST_GET_PORT_STATUS,
+                0,
+                interface as u16,
+                1,
+            ),
+        )?;
+        self.control = Some(ControlRequest::PortStatus(explicit));
+        Ok(())
+    }
+
+    /// Abort the print job after a failed transfer
+    fn print_failed(&mut self, dev_addr: DeviceAddress) {
+        if self.job.take().is_some() {
+            self.events.push(PrinterEvent::PrintFailed(dev_addr));
+        }
+    }
+
+    fn reset(&mut self) {
+        self.state = SingleDevice::Idle;
+        self.job = None;
+        self.control = None;
+        self.device_id_len = 0;
+        self.last_status = None;
+    }
+}
+
+/// Extract the device ID from a GET_DEVICE_ID response, which is prefixed with its length (big endian, including the prefix)
+fn parse_device_id(data: &[u8]) -> Option<&[u8]> {
+    match data {
+        [high, low, rest @ ..] => {
+            let length = (u16::from_be_bytes([*high, *low]) as usize).checked_sub(2)?;
+            Some(&rest[..length.min(rest.len())])
+        }
+        _ => None,
+    }
+}
+
+impl HasEvents for PrinterDriver {
+    type Event = PrinterEvent;
+
+    fn take_event(&mut self) -> Option<PrinterEvent> {
+        self.events.pop()
+    }
+
+    fn events_pending(&self) -> bool {
+        !self.events.is_empty()
+    }
+}
+
+impl<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    > Driver<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE> for PrinterDriver
+{
+    fn attached(&mut self, dev_addr: DeviceAddress, _connection_speed: ConnectionSpeed) {
+        self.state.attached(dev_addr, Pending::default());
+    }
+
+    fn detached(&mut self, dev_addr: DeviceAddress) {
+        if self.state.detached(dev_addr) {
+            self.reset();
+            self.events.push(PrinterEvent::DeviceRemoved(dev_addr));
+        }
+    }
+
+    fn descriptor(&mut self, dev_addr: DeviceAddress, descriptor_type: u8, data: &[u8]) {
+        let Some(Pending { config, interface, bulk_out, chosen }) = self.state.pending(dev_addr) else {
+            return;
+        };
+        if chosen.is_some() {
+            return;
+        }
+        match descriptor_type {
+            descriptor::TYPE_CONFIGURATION => {
+                if let Ok((_, configuration)) = descriptor::parse::configuration_descriptor(data) {
+                    // configurations are read in order of their index
+                    let index = config.map(|(_, index)| index + 1).unwrap_or(0);
+                    *config = Some((configuration.value, index));
+                    *interface = None;
+                }
+            }
+            descriptor::TYPE_INTERFACE => {
+                if let Ok((_, descriptor)) = descriptor::parse::interface_descriptor(data) {
+                    let matching = descriptor.interface_class == CLASS_PRINTER
+                        && descriptor.interface_sub_class == SUBCLASS_PRINTER
+                        && matches!(descriptor.interface_protocol, PROTOCOL_UNIDIRECTIONAL | PROTOCOL_BIDIRECTIONAL);
+                    *interface = matching.then_some((descriptor.interface_number, descriptor.alternate_setting));
+                    *bulk_out = None;
+                }
+            }
+            descriptor::TYPE_ENDPOINT => {
+                let (Some(config), Some(interface)) = (*config, *interface) else {
+                    return;
+                };
+                if let Ok((_, endpoint)) = descriptor::parse::endpoint_descriptor(data) {
+                    if endpoint.attributes.transfer_type() == TransferType::Bulk
+                        && endpoint.address.direction() == UsbDirection::Out
+                        && endpoint.max_packet_size > 0
+                    {
+                        *bulk_out = Some((endpoint.address.number(), endpoint.max_packet_size));
+                        *chosen = Some((config, interface));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn configure(&mut self, dev_addr: DeviceAddress) -> Option<u8> {
+        self.state.configure(dev_addr, |pending| pending.chosen.map(|((value, _), _)| value))
+    }
+
+    fn configured(
+        &mut self,
+        dev_addr: DeviceAddress,
+        value: u8,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) {
+        let Some(Pending { chosen, bulk_out, .. }) = self.state.take_pending(dev_addr) else {
+            return;
+        };
+        let (Some(((chosen_value, config_index), interface)), Some((out_ep, out_max_packet_size))) = (chosen, bulk_out) else {
+            return;
+        };
+        if chosen_value != value {
+            return;
+        }
+        let control_pipe = host.create_control_pipe(dev_addr);
+        let out_pipe = host.create_bulk_pipe(dev_addr, out_ep, UsbDirection::Out, out_max_packet_size);
+        let (Some(control_pipe), Some(out_pipe)) = (control_pipe, out_pipe) else {
+            // the host ran out of pipes: release the one that was created, if any
+            for pipe in control_pipe.into_iter().chain(out_pipe) {
+                host.release_pipe(pipe);
+            }
+            return;
+        };
+        self.state = SingleDevice::Configured(dev_addr, Configured { config_index, interface, control_pipe, out_pipe, out_max_packet_size });
+        self.events.push(PrinterEvent::DeviceAdded(dev_addr));
+    }
+
+    fn completed_control(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, data: Option<&[u8]>) {
+        let Some(&Configured { control_pipe, .. }) = self.state.device(dev_addr) else {
+            return;
+        };
+        if pipe_id != control_pipe {
+            return;
+        }
+        match self.control.take() {
+            Some(ControlRequest::DeviceId) => {
+                if let Some(id) = data.and_then(parse_device_id) {
+                    self.device_id[..id.len()].copy_from_slice(id);
+                    self.device_id_len = id.len();
+                    self.events.push(PrinterEvent::DeviceId(dev_addr));
+                }
+            }
+            Some(ControlRequest::PortStatus(explicit)) => {
+                if let Some(&[status]) = data {
+                    let status = PortStatus::from_bits_truncate(status);
+                    if explicit || self.last_status != Some(status) {
+                        self.events.push(PrinterEvent::PortStatus(dev_addr, status));
+                    }
+                    self.last_status = Some(status);
+                }
+            }
+            None => {}
+        }
+    }
+
+    fn completed_in(&mut self, _dev_addr: DeviceAddress, _pipe_id: PipeId, _data: &[u8]) {
+        // ignored, since there are no IN pipes in use.
+    }
+
+    fn completed_bulk_out(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId) {
+        let Some(&Configured { out_pipe, out_max_packet_size, .. }) = self.state.device(dev_addr) else {
+            return;
+        };
+        if pipe_id != out_pipe {
+            return;
+        }
+        if let Some(job) = &mut self.job {
+            if job.in_flight {
+                job.in_flight = false;
+                job.sent += out_max_packet_size.min(job.length - job.sent);
+            }
+        }
+    }
+
+    fn completed_out(&mut self, _dev_addr: DeviceAddress, _pipe_id: PipeId, _data: &mut [u8]) {
+        // ignored, since there are no interrupt OUT pipes in use.
+    }
+
+    fn stall(&mut self, dev_addr: DeviceAddress) {
+        if self.device_address() == Some(dev_addr) {
+            // a STALL on the control pipe means the request is not supported
+            self.control = None;
+            self.print_failed(dev_addr);
+        }
+    }
+
+    fn transfer_failed(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, _error: TransferError) {
+        let Some(&Configured { control_pipe, out_pipe, .. }) = self.state.device(dev_addr) else {
+            return;
+        };
+        if pipe_id == control_pipe {
+            self.control = None;
+        } else if pipe_id == out_pipe {
+            self.print_failed(dev_addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::mock::fixtures::DEVICE_DESCRIPTOR;
+    use crate::bus::mock::{ControlResponse, MockDevice, MockHostBus, Response};
+
+    #[test]
+    fn test_parse_device_id() {
+        assert_eq!(parse_device_id(b"\x00\x0eMFG:ACME;CMD"), Some(&b"MFG:ACME;CMD"[..]));
+        // truncated response
+        assert_eq!(parse_device_id(b"\x01\x00MFG:ACME;"), Some(&b"MFG:ACME;"[..]));
+        assert_eq!(parse_device_id(b"\x00\x01"), None);
+        assert_eq!(parse_device_id(b"\x00"), None);
+    }
+
+    const PRINTER_DESCRIPTOR: &[u8] = &[
+        0x09, 0x02, 0x20, 0x00, 0x01, 0x01, 0x00, 0xc0, 0x01, // configuration 1
+        0x09, 0x04, 0x00, 0x00, 0x02, 0x07, 0x01, 0x02, 0x00, // interface 0: bidirectional printer
+        0x07, 0x05, 0x01, 0x02, 0x40, 0x00, 0x00, // endpoint 1 OUT, bulk
+        0x07, 0x05, 0x82, 0x02, 0x40, 0x00, 0x00, // endpoint 2 IN, bulk
+    ];
+
+    #[test]
+    fn test_printer() {
+        const GET_DEVICE_ID: ControlResponse = ControlResponse {
+            request_type: 0xa1,
+            request: 0x00,
+            value: 0,
+            index: 0,
+            response: Response::Data(b"\x00\x0bMFG:ACME;"),
+        };
+        let device = MockDevice { control_responses: &[GET_DEVICE_ID], ..MockDevice::new(DEVICE_DESCRIPTOR, &[PRINTER_DESCRIPTOR]) };
+        let mut host = UsbHost::new(MockHostBus::new(device));
+        let mut printer = PrinterDriver::new();
+        host.bus().attach();
+
+        let mut events = [false; 3];
+        for _ in 0..1000 {
+            host.poll(&mut [&mut printer]);
+            assert!(printer.poll(&mut host).is_ok());
+            match printer.take_event() {
+                Some(PrinterEvent::DeviceAdded(_)) => {
+                    events[0] = true;
+                    assert!(printer.get_device_id(&mut host).is_ok());
+                }
+                Some(PrinterEvent::DeviceId(_)) => {
+                    events[1] = true;
+                    assert_eq!(printer.device_id(), b"MFG:ACME;");
+                    // sent in two packets
+                    assert!(printer.print(&[0x1b; 100]).is_ok());
+                }
+                Some(PrinterEvent::PrintComplete(_)) => events[2] = true,
+                _ => {}
+            }
+        }
+        assert_eq!(events, [true; 3]);
+        assert!(!printer.busy());
+    }
+
+    #[test]
+    fn test_printer_out_of_pipes() {
+        // room for the control pipe only
+        let mut host: UsbHost<_, 1> = UsbHost::new_sized(MockHostBus::new(MockDevice::new(DEVICE_DESCRIPTOR, &[PRINTER_DESCRIPTOR])), Default::default());
+        let mut printer = PrinterDriver::new();
+        host.bus().attach();
+        for _ in 0..1000 {
+            host.poll(&mut [&mut printer]);
+        }
+        assert!(printer.take_event().is_none());
+        // the control pipe was released again
+        let dev_addr = host.bus().address().unwrap();
+        assert!(host.create_control_pipe(dev_addr).is_some());
+    }
+}
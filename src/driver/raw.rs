@@ -0,0 +1,488 @@
+//! Raw endpoint access for known devices
+//!
+//! The [`RawDeviceDriver`] does not implement any class logic. It claims a single device, identified by vendor and product ID,
+//! opens pipes for a list of endpoints chosen by the application, and hands the data on those pipes to the application as-is.
+//!
+//! This is useful for proprietary devices (wireless dongles, IrDA adapters, ...) where writing a full driver is not worth it.
+//!
+//! Since the driver bypasses any class specific handling, it must be explicitly configured with the exact device to claim.
+//! The application is responsible for speaking the device's protocol correctly.
+//!
+//! Example:
+//! ```ignore
+//! // claim the device 1234:5678, opening endpoints 0x81 (interrupt IN) and 0x02 (interrupt OUT)
+//! let mut raw = RawDeviceDriver::new(0x1234, 0x5678, [0x81, 0x02]);
+//!
+//! // ... after polling:
+//! match raw.take_event() {
+//!     Some(RawEvent::DataReceived(dev_addr, 0x81)) => {
+//!         let mut buf = [0; 64];
+//!         if let Ok(Some(len)) = raw.read(0x81, &mut buf) {
+//!             let data = &buf[..len];
+//!             // ...
+//!         }
+//!     }
+//!     _ => {}
+//! }
+//! ```
+
+use super::{detector::SingleDevice, Driver, EventQueue, HasEvents, EVENT_QUEUE_DEPTH};
+use crate::bus::HostBus;
+use crate::descriptor;
+use crate::types::{ConnectionSpeed, DeviceAddress, SetupPacket, TransferType};
+use crate::{ControlError, PipeId, UsbHost};
+use usb_device::UsbDirection;
+
+/// Size of the buffer kept for each endpoint (and for control transfers)
+const BUFFER_SIZE: usize = 64;
+
+/// Driver giving the application direct access to the endpoints of a single, known device
+///
+/// See [module-level documentation](crate::driver::raw) for details.
+///
+/// `NUM_ENDPOINTS` is the number of endpoints that the driver opens. The endpoints are identified by their
+/// address (endpoint number, with bit 7 set for IN endpoints), as found in the endpoint descriptor.
+///
+/// Currently only interrupt endpoints can be opened. A control pipe is always created as well.
+#[derive(Debug)]
+pub struct RawDeviceDriver<const NUM_ENDPOINTS: usize> {
+    vendor_id: u16,
+    product_id: u16,
+    endpoints: [RawEndpoint; NUM_ENDPOINTS],
+    /// State of the device, with its control pipe once configured
+    state: SingleDevice<Pending, PipeId>,
+    control_buffer: Buffer,
+    events: EventQueue<RawEvent, EVENT_QUEUE_DEPTH>,
+}
+
+/// Descriptors of a newly attached device, as far as they were inspected
+#[derive(Copy, Clone, Default, Debug)]
+struct Pending {
+    /// Set if the device descriptor matched the vendor and product ID
+    matched: bool,
+    /// Configuration currently being inspected
+    config: Option<u8>,
+    /// Configuration containing all of the endpoints
+    chosen_config: Option<u8>,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct RawEndpoint {
+    address: u8,
+    max_packet_size: Option<u16>,
+    interval: u8,
+    pipe: Option<PipeId>,
+    buffer: Buffer,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Buffer {
+    data: [u8; BUFFER_SIZE],
+    len: Option<usize>,
+}
+
+impl Buffer {
+    const fn empty() -> Self {
+        Buffer {
+            data: [0; BUFFER_SIZE],
+            len: None,
+        }
+    }
+
+    fn store(&mut self, data: &[u8]) {
+        let len = data.len().min(BUFFER_SIZE);
+        self.data[..len].copy_from_slice(&data[..len]);
+        self.len = Some(len);
+    }
+
+    fn take(&mut self, buf: &mut [u8]) -> Option<usize> {
+        self.len.take().map(|len| {
+            let len = len.min(buf.len());
+            buf[..len].copy_from_slice(&self.data[..len]);
+            len
+        })
+    }
+}
+
+/// Events generated by the [`RawDeviceDriver`]
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RawEvent {
+    /// The device was detected & configured, and all pipes were opened
+    DeviceAdded(DeviceAddress),
+
+    /// The device was removed
+    DeviceRemoved(DeviceAddress),
+
+    /// Data was received on the IN endpoint with the given address
+    ///
+    /// Use [`RawDeviceDriver::read`] to retrieve it.
+    DataReceived(DeviceAddress, u8),
+
+    /// Data previously passed to [`RawDeviceDriver::write`] was handed to the OUT endpoint with the given address
+    DataSent(DeviceAddress, u8),
+
+    /// A control transfer has completed.
+    ///
+    /// For IN transfers, the data can be retrieved with [`RawDeviceDriver::read_control`].
+    ControlComplete(DeviceAddress),
+}
+
+/// Error type for interactions with the driver
+#[derive(Copy, Clone, Debug)]
+pub enum RawError {
+    /// Error initiating control transfer
+    ControlError(ControlError),
+
+    /// The device is not currently attached & configured
+    NotConfigured,
+
+    /// The given endpoint address is not one of the endpoints that the driver was configured with
+    UnknownEndpoint,
+}
+
+impl From<ControlError> for RawError {
+    fn from(e: ControlError) -> Self {
+        RawError::ControlError(e)
+    }
+}
+
+impl<const NUM_ENDPOINTS: usize> RawDeviceDriver<NUM_ENDPOINTS> {
+    /// Create a driver for the device with given `vendor_id` and `product_id`
+    ///
+    /// The driver only selects a configuration which contains *all* of the given `endpoints`.
+    pub fn new(vendor_id: u16, product_id: u16, endpoints: [u8; NUM_ENDPOINTS]) -> Self {
+        Self {
+            vendor_id,
+            product_id,
+            endpoints: endpoints.map(|address| RawEndpoint {
+                address,
+                max_packet_size: None,
+                interval: 0,
+                pipe: None,
+                buffer: Buffer::empty(),
+            }),
+            state: SingleDevice::Idle,
+            control_buffer: Buffer::empty(),
+            events: EventQueue::new(),
+        }
+    }
+
+    /// Returns the oldest pending event (if any), and removes it from the queue.
+    ///
+    /// This method should be called repeatedly after calling `usb_host.poll(...)`, until it returns `None` (see
+    /// [`HasEvents`] for what happens otherwise).
+    pub fn take_event(&mut self) -> Option<RawEvent> {
+        self.events.pop()
+    }
+
+    /// Address of the device, if it is currently configured
+    pub fn device_address(&self) -> Option<DeviceAddress> {
+        self.state.address()
+    }
+
+    /// Copy the data most recently received on the given IN endpoint into `buf`
+    ///
+    /// Returns the number of bytes copied, or `None` if no new data was received since the last call.
+    pub fn read(&mut self, endpoint: u8, buf: &mut [u8]) -> Result<Option<usize>, RawError> {
+        Ok(self.find_endpoint(endpoint)?.buffer.take(buf))
+    }
+
+    /// Place data to be sent on the given OUT endpoint
+    ///
+    /// The data is handed to the host bus the next time the pipe is ready to accept data.
+    /// Data exceeding the endpoint's maximum packet size is truncated.
+    pub fn write(&mut self, endpoint: u8, data: &[u8]) -> Result<(), RawError> {
+        self.find_endpoint(endpoint)?.buffer.store(data);
+        Ok(())
+    }
+
+    /// Initiate a control IN transfer on the device's control pipe
+    ///
+    /// Completion is reported by [`RawEvent::ControlComplete`], the received data can then be retrieved with [`RawDeviceDriver::read_control`].
+    pub fn control_in<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    >(
+        &mut self,
+        setup: SetupPacket,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) -> Result<(), RawError> {
+        let SingleDevice::Configured(dev_addr, control_pipe) = self.state else {
+            return Err(RawError::NotConfigured);
+        };
+        host.control_in(Some(dev_addr), Some(control_pipe), setup)?;
+        Ok(())
+    }
+
+    /// Initiate a control OUT transfer on the device's control pipe
+    ///
+    /// Completion is reported by [`RawEvent::ControlComplete`].
+    pub fn control_out<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    >(
+        &mut self,
+        setup: SetupPacket,
+        data: &[u8],
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) -> Result<(), RawError> {
+        let SingleDevice::Configured(dev_addr, control_pipe) = self.state else {
+            return Err(RawError::NotConfigured);
+        };
+        host.control_out(Some(dev_addr), Some(control_pipe), setup, data)?;
+        Ok(())
+    }
+
+    /// Copy the data received by the most recent control IN transfer into `buf`
+    ///
+    /// Returns the number of bytes copied, or `None` if no new data was received since the last call.
+    pub fn read_control(&mut self, buf: &mut [u8]) -> Option<usize> {
+        self.control_buffer.take(buf)
+    }
+
+    fn find_endpoint(&mut self, address: u8) -> Result<&mut RawEndpoint, RawError> {
+        if !matches!(self.state, SingleDevice::Configured(..)) {
+            return Err(RawError::NotConfigured);
+        }
+        self.endpoints
+            .iter_mut()
+            .find(|ep| ep.address == address)
+            .ok_or(RawError::UnknownEndpoint)
+    }
+
+    fn reset(&mut self) {
+        self.state = SingleDevice::Idle;
+        self.control_buffer = Buffer::empty();
+        for endpoint in self.endpoints.iter_mut() {
+            endpoint.max_packet_size = None;
+            endpoint.pipe = None;
+            endpoint.buffer = Buffer::empty();
+        }
+    }
+}
+
+impl<const NUM_ENDPOINTS: usize> HasEvents for RawDeviceDriver<NUM_ENDPOINTS> {
+    type Event = RawEvent;
+
+    fn take_event(&mut self) -> Option<RawEvent> {
+        self.events.pop()
+    }
+
+    fn events_pending(&self) -> bool {
+        !self.events.is_empty()
+    }
+}
+
+impl<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+        const NUM_ENDPOINTS: usize,
+    > Driver<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>
+    for RawDeviceDriver<NUM_ENDPOINTS>
+{
+    fn attached(&mut self, dev_addr: DeviceAddress, _connection_speed: ConnectionSpeed) {
+        self.state.attached(dev_addr, Pending::default());
+    }
+
+    fn detached(&mut self, dev_addr: DeviceAddress) {
+        if self.state.detached(dev_addr) {
+            self.reset();
+            self.events.push(RawEvent::DeviceRemoved(dev_addr));
+        }
+    }
+
+    fn descriptor(&mut self, dev_addr: DeviceAddress, descriptor_type: u8, data: &[u8]) {
+        let Some(Pending { matched, config, chosen_config }) = self.state.pending(dev_addr) else {
+            return;
+        };
+        match descriptor_type {
+            descriptor::TYPE_DEVICE => {
+                if let Ok((_, device)) = descriptor::parse::device_descriptor(data) {
+                    *matched = device.id_vendor == self.vendor_id && device.id_product == self.product_id;
+                }
+            }
+            descriptor::TYPE_CONFIGURATION if *matched && chosen_config.is_none() => {
+                if let Ok((_, configuration)) = descriptor::parse::configuration_descriptor(data) {
+                    // endpoints seen so far belong to a configuration which was not complete
+                    for endpoint in self.endpoints.iter_mut() {
+                        endpoint.max_packet_size = None;
+                    }
+                    *config = Some(configuration.value);
+                }
+            }
+            descriptor::TYPE_ENDPOINT if *matched && chosen_config.is_none() => {
+                if let Ok((_, endpoint)) = descriptor::parse::endpoint_descriptor(data) {
+                    if endpoint.attributes.transfer_type() != TransferType::Interrupt || endpoint.max_packet_size == 0 {
+                        return;
+                    }
+                    let address = endpoint.address.number()
+                        | if endpoint.address.direction() == UsbDirection::In { 0x80 } else { 0 };
+                    if let Some(raw) = self.endpoints.iter_mut().find(|ep| ep.address == address) {
+                        raw.max_packet_size = Some(endpoint.max_packet_size);
+                        raw.interval = endpoint.interval;
+                    }
+                    if self.endpoints.iter().all(|ep| ep.max_packet_size.is_some()) {
+                        *chosen_config = *config;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn configure(&mut self, dev_addr: DeviceAddress) -> Option<u8> {
+        self.state.configure(dev_addr, |pending| pending.chosen_config)
+    }
+
+    fn configured(
+        &mut self,
+        dev_addr: DeviceAddress,
+        value: u8,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) {
+        let Some(Pending { chosen_config, .. }) = self.state.take_pending(dev_addr) else {
+            return;
+        };
+        if chosen_config != Some(value) {
+            self.reset();
+            return;
+        }
+        let Some(control_pipe) = host.create_control_pipe(dev_addr) else {
+            self.reset();
+            return;
+        };
+        for (index, endpoint) in self.endpoints.iter_mut().enumerate() {
+            let direction = if endpoint.address & 0x80 == 0x80 { UsbDirection::In } else { UsbDirection::Out };
+            // the endpoint index is used as pipe context, to find the endpoint in `completed_in` / `completed_out`
+            endpoint.pipe = host.create_interrupt_pipe_with_context(
+                dev_addr,
+                endpoint.address & 0x0F,
+                direction,
+                // Unwrap safety: a configuration is only chosen once all endpoints were found
+                endpoint.max_packet_size.unwrap(),
+                endpoint.interval,
+                index as u16,
+            );
+            if endpoint.pipe.is_none() {
+                // the host or the bus ran out of pipes: release the ones created so far
+                host.release_pipe(control_pipe);
+                for pipe in self.endpoints.iter().filter_map(|ep| ep.pipe) {
+                    host.release_pipe(pipe);
+                }
+                self.reset();
+                return;
+            }
+        }
+        self.state = SingleDevice::Configured(dev_addr, control_pipe);
+        self.events.push(RawEvent::DeviceAdded(dev_addr));
+    }
+
+    fn completed_control(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, data: Option<&[u8]>) {
+        if let Some(&control_pipe) = self.state.device(dev_addr) {
+            if pipe_id == control_pipe {
+                if let Some(data) = data {
+                    self.control_buffer.store(data);
+                }
+                self.events.push(RawEvent::ControlComplete(dev_addr));
+            }
+        }
+    }
+
+    fn completed_in(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, data: &[u8]) {
+        if self.device_address() != Some(dev_addr) {
+            return;
+        }
+        if let Some(endpoint) = self.endpoints.get_mut(pipe_id.context() as usize).filter(|ep| ep.pipe == Some(pipe_id)) {
+            endpoint.buffer.store(data);
+            self.events.push(RawEvent::DataReceived(dev_addr, endpoint.address));
+        }
+    }
+
+    fn completed_out(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, data: &mut [u8]) {
+        if self.device_address() != Some(dev_addr) {
+            return;
+        }
+        if let Some(endpoint) = self.endpoints.get_mut(pipe_id.context() as usize).filter(|ep| ep.pipe == Some(pipe_id)) {
+            if endpoint.buffer.take(data).is_some() {
+                self.events.push(RawEvent::DataSent(dev_addr, endpoint.address));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::mock::fixtures::DEVICE_DESCRIPTOR;
+    use crate::bus::mock::{MockDevice, MockHostBus};
+
+    /// Vendor specific interface, with an interrupt IN and an interrupt OUT endpoint
+    const RAW_DESCRIPTOR: &[u8] = &[
+        0x09, 0x02, 0x20, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32, // configuration 1
+        0x09, 0x04, 0x00, 0x00, 0x02, 0xff, 0x00, 0x00, 0x00, // interface 0: vendor specific
+        0x07, 0x05, 0x81, 0x03, 0x08, 0x00, 0x01, // endpoint 1 IN, interrupt
+        0x07, 0x05, 0x02, 0x03, 0x08, 0x00, 0x01, // endpoint 2 OUT, interrupt
+    ];
+
+    #[test]
+    fn test_raw_device() {
+        let mut host = UsbHost::new(MockHostBus::new(MockDevice::new(DEVICE_DESCRIPTOR, &[RAW_DESCRIPTOR])));
+        // the device descriptor is 1234:5678
+        let mut raw = RawDeviceDriver::new(0x1234, 0x5678, [0x81, 0x02]);
+        host.bus().attach();
+        let mut dev_addr = None;
+        for _ in 0..1000 {
+            host.poll(&mut [&mut raw]);
+            if let Some(RawEvent::DeviceAdded(addr)) = raw.take_event() {
+                dev_addr = Some(addr);
+                break;
+            }
+        }
+        let dev_addr = dev_addr.unwrap();
+        assert_eq!(raw.device_address(), Some(dev_addr));
+
+        // IN: the data is kept until the application reads it
+        assert!(host.bus().send_interrupt(1, &[1, 2, 3, 4, 5, 6, 7, 8]));
+        host.poll(&mut [&mut raw]);
+        assert!(matches!(raw.take_event(), Some(RawEvent::DataReceived(addr, 0x81)) if addr == dev_addr));
+        let mut buf = [0; 64];
+        assert!(matches!(raw.read(0x81, &mut buf), Ok(Some(8))));
+        assert_eq!(&buf[..8], &[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert!(matches!(raw.read(0x81, &mut buf), Ok(None)));
+
+        // OUT: the data is handed to the pipe once the bus asks for it
+        assert!(raw.write(0x02, &[4, 5]).is_ok());
+        assert!(host.bus().poll_interrupt_out(2).is_some());
+        host.poll(&mut [&mut raw]);
+        assert!(matches!(raw.take_event(), Some(RawEvent::DataSent(addr, 0x02)) if addr == dev_addr));
+        assert_eq!(host.bus().poll_interrupt_out(2).map(|data| data[..2] == [4, 5]), Some(true));
+
+        assert!(matches!(raw.read(0x03, &mut buf), Err(RawError::UnknownEndpoint)));
+    }
+
+    #[test]
+    fn test_raw_device_out_of_pipes() {
+        // room for the control pipe and one of the endpoints only
+        let mut host: UsbHost<_, 2> = UsbHost::new_sized(MockHostBus::new(MockDevice::new(DEVICE_DESCRIPTOR, &[RAW_DESCRIPTOR])), Default::default());
+        let mut raw = RawDeviceDriver::new(0x1234, 0x5678, [0x81, 0x02]);
+        host.bus().attach();
+        for _ in 0..1000 {
+            host.poll(&mut [&mut raw]);
+        }
+        assert!(raw.take_event().is_none());
+        assert!(raw.device_address().is_none());
+
+        // the pipes created before running out were released again
+        assert!(host.bus().interrupt_pipe_device(1).is_none());
+        let dev_addr = host.bus().address().unwrap();
+        assert!(host.create_control_pipe(dev_addr).is_some());
+        assert!(host.create_control_pipe(dev_addr).is_some());
+    }
+}
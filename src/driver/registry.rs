@@ -0,0 +1,492 @@
+//! Registration of drivers, with a stable identity and runtime enable/disable
+//!
+//! [`UsbHost::poll`] expects the same list of drivers on every call, but cannot check that it gets one. A [`DriverRegistry`]
+//! holds that list instead: drivers are placed in its slots when registered, and [`UsbHost::poll_registry`] always polls
+//! them in the same order.
+//!
+//! Registering a driver hands out a [`DriverId`], which identifies its slot. Since the registry holds on to the driver,
+//! the application uses the id to reach it between polls (e.g. to take events).
+//!
+//! ```ignore
+//! let mut registry: DriverRegistry<_, 2> = DriverRegistry::new();
+//! let kbd_id = registry.register(&mut kbd).unwrap();
+//! registry.register(&mut hub).unwrap();
+//! loop {
+//!     usb_host.poll_registry(&mut registry);
+//!     while let Some(event) = registry.get_mut(kbd_id).unwrap().take_event() {
+//!         // ...
+//!     }
+//!     // stop handling new keyboards, without affecting the ones attached already
+//!     registry.set_enabled(kbd_id, false);
+//! }
+//! ```
+
+use super::{DiscoveryInterest, Driver};
+use crate::bus::HostBus;
+use crate::descriptor::DescriptorContext;
+use crate::types::{ConnectionSpeed, DeviceAddress, InterfaceSet};
+use crate::{
+    DetachReason, PipeId, TransferError, UsbHost, DEFAULT_CONTROL_BUFFER_SIZE, DEFAULT_CONTROL_QUEUE_DEPTH,
+    DEFAULT_MAX_PIPES,
+};
+use core::any::Any;
+use core::marker::PhantomData;
+
+/// Identifies a driver of type `T` within a [`DriverRegistry`]
+pub struct DriverId<T> {
+    index: u8,
+    driver: PhantomData<fn() -> T>,
+}
+
+impl<T> DriverId<T> {
+    /// Position of the driver in the list polled by [`UsbHost::poll_registry`]
+    ///
+    /// This is the index expected by [`UsbHost::set_pipe_owner`].
+    pub fn index(&self) -> usize {
+        self.index as usize
+    }
+}
+
+impl<T> Clone for DriverId<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for DriverId<T> {}
+
+impl<T> PartialEq for DriverId<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index
+    }
+}
+
+impl<T> core::fmt::Debug for DriverId<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_tuple("DriverId").field(&self.index).finish()
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<T> defmt::Format for DriverId<T> {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "DriverId({=u8})", self.index)
+    }
+}
+
+/// A registered driver, which can be used both as a driver and as its concrete type
+trait Entry<B, const MAX_PIPES: usize, const CONTROL_QUEUE_DEPTH: usize, const CONTROL_BUFFER_SIZE: usize> {
+    fn driver(&mut self) -> &mut dyn Driver<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>;
+    fn any(&mut self) -> &mut dyn Any;
+}
+
+impl<
+        T: Driver<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE> + Any,
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    > Entry<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE> for T
+{
+    fn driver(&mut self) -> &mut dyn Driver<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE> {
+        self
+    }
+
+    fn any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+struct Slot<'d, B, const MAX_PIPES: usize, const CONTROL_QUEUE_DEPTH: usize, const CONTROL_BUFFER_SIZE: usize> {
+    driver: &'d mut dyn Entry<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    enabled: bool,
+}
+
+impl<B, const MAX_PIPES: usize, const CONTROL_QUEUE_DEPTH: usize, const CONTROL_BUFFER_SIZE: usize> core::fmt::Debug
+    for Slot<'_, B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Slot").field("enabled", &self.enabled).finish_non_exhaustive()
+    }
+}
+
+/// Holds up to `N` drivers, for a [`UsbHost`] with the given parameters
+///
+/// See [module-level documentation](self) for details.
+pub struct DriverRegistry<
+    'd,
+    B,
+    const N: usize,
+    const MAX_PIPES: usize = DEFAULT_MAX_PIPES,
+    const CONTROL_QUEUE_DEPTH: usize = DEFAULT_CONTROL_QUEUE_DEPTH,
+    const CONTROL_BUFFER_SIZE: usize = DEFAULT_CONTROL_BUFFER_SIZE,
+> {
+    slots: [Option<Slot<'d, B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>>; N],
+}
+
+impl<
+        'd,
+        B: HostBus,
+        const N: usize,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    > DriverRegistry<'d, B, N, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>
+{
+    pub const fn new() -> Self {
+        Self { slots: [const { None }; N] }
+    }
+
+    /// Register the given driver, returning its identity
+    ///
+    /// Drivers are polled in the order they were registered in.
+    /// Returns `None` if the registry is full.
+    pub fn register<T: Driver<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE> + Any>(
+        &mut self,
+        driver: &'d mut T,
+    ) -> Option<DriverId<T>> {
+        let index = self.slots.iter().position(Option::is_none)?;
+        self.slots[index] = Some(Slot { driver, enabled: true });
+        Some(DriverId { index: index as u8, driver: PhantomData })
+    }
+
+    /// Access the driver with the given identity
+    ///
+    /// Returns `None` if the id was handed out by another registry, for a driver of the same type in another slot.
+    pub fn get_mut<T: Any>(&mut self, id: DriverId<T>) -> Option<&mut T> {
+        self.slots.get_mut(id.index())?.as_mut()?.driver.any().downcast_mut()
+    }
+
+    /// Enable or disable the given driver
+    ///
+    /// A disabled driver is not offered any new devices: it does not see their descriptors, and is not asked to configure them
+    /// or to claim their interfaces. Devices it handles already are not affected, so it keeps being informed about their
+    /// transfers, and about them being detached. Drivers are enabled when registered.
+    pub fn set_enabled<T>(&mut self, id: DriverId<T>, enabled: bool) {
+        if let Some(Some(slot)) = self.slots.get_mut(id.index()) {
+            slot.enabled = enabled;
+        }
+    }
+
+    /// Whether the given driver is enabled (see [`DriverRegistry::set_enabled`])
+    pub fn is_enabled<T>(&self, id: DriverId<T>) -> bool {
+        self.slots.get(id.index()).is_some_and(|slot| slot.as_ref().is_some_and(|slot| slot.enabled))
+    }
+
+    /// Number of registered drivers
+    pub fn len(&self) -> usize {
+        self.slots.iter().flatten().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Pass the registered drivers to `f`, with disabled drivers wrapped, so that they are not offered new devices
+    pub(crate) fn with_gated<R>(
+        &mut self,
+        f: impl FnOnce(&mut [&mut dyn Driver<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>]) -> R,
+    ) -> R {
+        let count = self.len();
+        let mut slots = self.slots.iter_mut().flatten();
+        let mut gates: [Gate<'_, B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>; N] =
+            core::array::from_fn(|_| match slots.next() {
+                Some(slot) => Gate { driver: Some(slot.driver.driver()), enabled: slot.enabled },
+                None => Gate { driver: None, enabled: false },
+            });
+        let mut gates_iter = gates.iter_mut();
+        // Unwrap safety: there are exactly N gates
+        let mut gated: [&mut dyn Driver<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>; N] =
+            core::array::from_fn(|_| {
+                gates_iter.next().unwrap() as &mut dyn Driver<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>
+            });
+        f(&mut gated[..count])
+    }
+}
+
+impl<
+        B: HostBus,
+        const N: usize,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    > Default for DriverRegistry<'_, B, N, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<
+        B,
+        const N: usize,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    > core::fmt::Debug for DriverRegistry<'_, B, N, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("DriverRegistry").field("slots", &self.slots).finish()
+    }
+}
+
+/// Forwards to a driver, except for the callbacks that offer it new devices, if it is disabled
+struct Gate<
+    'a,
+    B,
+    const MAX_PIPES: usize,
+    const CONTROL_QUEUE_DEPTH: usize,
+    const CONTROL_BUFFER_SIZE: usize,
+> {
+    driver: Option<&'a mut dyn Driver<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>>,
+    enabled: bool,
+}
+
+impl<
+        'a,
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    > Gate<'a, B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>
+{
+    fn enabled(
+        &mut self,
+    ) -> Option<&mut (dyn Driver<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE> + 'a)>
+    {
+        if self.enabled {
+            self.driver.as_deref_mut()
+        } else {
+            None
+        }
+    }
+}
+
+impl<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    > Driver<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>
+    for Gate<'_, B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>
+{
+    fn attached(&mut self, dev_addr: DeviceAddress, connection_speed: ConnectionSpeed) {
+        if let Some(driver) = self.enabled() {
+            driver.attached(dev_addr, connection_speed);
+        }
+    }
+
+    fn detached(&mut self, dev_addr: DeviceAddress) {
+        if let Some(driver) = &mut self.driver {
+            driver.detached(dev_addr);
+        }
+    }
+
+    fn detached_with_reason(&mut self, dev_addr: DeviceAddress, reason: DetachReason) {
+        if let Some(driver) = &mut self.driver {
+            driver.detached_with_reason(dev_addr, reason);
+        }
+    }
+
+    fn descriptor(&mut self, dev_addr: DeviceAddress, descriptor_type: u8, data: &[u8]) {
+        if let Some(driver) = self.enabled() {
+            driver.descriptor(dev_addr, descriptor_type, data);
+        }
+    }
+
+    fn descriptor_with_context(&mut self, dev_addr: DeviceAddress, descriptor_type: u8, data: &[u8], context: DescriptorContext) {
+        if let Some(driver) = self.enabled() {
+            driver.descriptor_with_context(dev_addr, descriptor_type, data, context);
+        }
+    }
+
+    fn discovery_interest(&mut self, dev_addr: DeviceAddress) -> DiscoveryInterest {
+        self.enabled()
+            .map(|driver| driver.discovery_interest(dev_addr))
+            .unwrap_or(DiscoveryInterest::NotInterested)
+    }
+
+    fn configure(&mut self, dev_addr: DeviceAddress) -> Option<u8> {
+        self.enabled().and_then(|driver| driver.configure(dev_addr))
+    }
+
+    fn configured(
+        &mut self,
+        dev_addr: DeviceAddress,
+        value: u8,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) {
+        if let Some(driver) = self.enabled() {
+            driver.configured(dev_addr, value, host);
+        }
+    }
+
+    fn claim_interfaces(&mut self, dev_addr: DeviceAddress, value: u8) -> InterfaceSet {
+        self.enabled().map_or(InterfaceSet::EMPTY, |driver| driver.claim_interfaces(dev_addr, value))
+    }
+
+    fn configured_interfaces(
+        &mut self,
+        dev_addr: DeviceAddress,
+        value: u8,
+        interfaces: InterfaceSet,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) {
+        if let Some(driver) = self.enabled() {
+            driver.configured_interfaces(dev_addr, value, interfaces, host);
+        }
+    }
+
+    fn completed_control(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, data: Option<&[u8]>) {
+        if let Some(driver) = &mut self.driver {
+            driver.completed_control(dev_addr, pipe_id, data);
+        }
+    }
+
+    fn completed_in(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, data: &[u8]) {
+        if let Some(driver) = &mut self.driver {
+            driver.completed_in(dev_addr, pipe_id, data);
+        }
+    }
+
+    fn completed_out(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, data: &mut [u8]) {
+        if let Some(driver) = &mut self.driver {
+            driver.completed_out(dev_addr, pipe_id, data);
+        }
+    }
+
+    fn completed_bulk_out(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId) {
+        if let Some(driver) = &mut self.driver {
+            driver.completed_bulk_out(dev_addr, pipe_id);
+        }
+    }
+
+    fn stall(&mut self, dev_addr: DeviceAddress) {
+        if let Some(driver) = &mut self.driver {
+            driver.stall(dev_addr);
+        }
+    }
+
+    fn transfer_failed(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, error: TransferError) {
+        if let Some(driver) = &mut self.driver {
+            driver.transfer_failed(dev_addr, pipe_id, error);
+        }
+    }
+
+    fn endpoint_recovered(&mut self, dev_addr: DeviceAddress, endpoint: u8, result: Result<(), TransferError>) {
+        if let Some(driver) = &mut self.driver {
+            driver.endpoint_recovered(dev_addr, endpoint, result);
+        }
+    }
+
+    fn suspended(&mut self) {
+        if let Some(driver) = &mut self.driver {
+            driver.suspended();
+        }
+    }
+
+    fn resumed(&mut self) {
+        if let Some(driver) = &mut self.driver {
+            driver.resumed();
+        }
+    }
+
+    fn sof(&mut self, frame_number: u16) {
+        if let Some(driver) = &mut self.driver {
+            driver.sof(frame_number);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::mock::MockHostBus;
+
+    /// Driver without any state, so that all of its instances share an address (`KIND` only distinguishes types)
+    struct Stateless<const KIND: u8>;
+
+    impl<B: HostBus, const KIND: u8> Driver<B> for Stateless<KIND> {
+        fn attached(&mut self, _dev_addr: DeviceAddress, _connection_speed: ConnectionSpeed) {}
+        fn detached(&mut self, _dev_addr: DeviceAddress) {}
+        fn descriptor(&mut self, _dev_addr: DeviceAddress, _descriptor_type: u8, _data: &[u8]) {}
+        fn configure(&mut self, _dev_addr: DeviceAddress) -> Option<u8> {
+            None
+        }
+        fn configured(&mut self, _dev_addr: DeviceAddress, _value: u8, _host: &mut UsbHost<B>) {}
+        fn completed_control(&mut self, _dev_addr: DeviceAddress, _pipe_id: PipeId, _data: Option<&[u8]>) {}
+        fn completed_in(&mut self, _dev_addr: DeviceAddress, _pipe_id: PipeId, _data: &[u8]) {}
+        fn completed_out(&mut self, _dev_addr: DeviceAddress, _pipe_id: PipeId, _data: &mut [u8]) {}
+    }
+
+    #[test]
+    fn test_zero_sized_drivers() {
+        let (mut first, mut second, mut third) = (Stateless::<0>, Stateless::<0>, Stateless::<0>);
+        let mut registry: DriverRegistry<MockHostBus, 2> = DriverRegistry::new();
+        let first_id = registry.register(&mut first).unwrap();
+        let second_id = registry.register(&mut second).unwrap();
+        assert!(registry.register(&mut third).is_none());
+        assert_ne!(first_id, second_id);
+        assert_eq!((first_id.index(), second_id.index()), (0, 1));
+        assert_eq!(registry.len(), 2);
+
+        // each driver keeps its own slot
+        registry.set_enabled(first_id, false);
+        assert!(!registry.is_enabled(first_id));
+        assert!(registry.is_enabled(second_id));
+        assert!(registry.get_mut(first_id).is_some());
+        registry.with_gated(|drivers| assert_eq!(drivers.len(), 2));
+    }
+
+    #[test]
+    fn test_foreign_id() {
+        let mut stateless = Stateless::<0>;
+        let mut registry: DriverRegistry<MockHostBus, 1> = DriverRegistry::new();
+        let id = registry.register(&mut stateless).unwrap();
+
+        let mut other = Stateless::<1>;
+        let mut other_registry: DriverRegistry<MockHostBus, 1> = DriverRegistry::new();
+        other_registry.register(&mut other).unwrap();
+        assert!(other_registry.get_mut(id).is_none());
+        assert!(registry.get_mut(id).is_some());
+    }
+
+    #[cfg(feature = "driver-kbd")]
+    #[test]
+    fn test_driver_registry() {
+        use crate::bus::mock::fixtures::{keyboard, Observer};
+        use crate::driver::kbd::{KbdDriver, KbdEvent};
+        use crate::PollResult;
+
+        let mut host = UsbHost::new(MockHostBus::new(keyboard()));
+        let mut kbd: KbdDriver = KbdDriver::new();
+        let mut observer = Observer::default();
+        let mut registry: DriverRegistry<MockHostBus, 2> = DriverRegistry::new();
+        let kbd_id = registry.register(&mut kbd).unwrap();
+        let observer_id = registry.register(&mut observer).unwrap();
+        assert_eq!(observer_id.index(), 1);
+
+        // a disabled driver is not offered new devices
+        registry.set_enabled(kbd_id, false);
+        assert!(!registry.is_enabled(kbd_id));
+        host.bus().attach();
+        let mut unsupported = false;
+        for _ in 0..1000 {
+            if let PollResult::UnsupportedDevice(_) = host.poll_registry(&mut registry) {
+                unsupported = true;
+            }
+        }
+        assert!(unsupported);
+        assert!(registry.get_mut(kbd_id).unwrap().take_event().is_none());
+
+        // once enabled again, it handles the device after it was reconnected
+        registry.set_enabled(kbd_id, true);
+        host.bus().detach();
+        host.bus().attach();
+        for _ in 0..1000 {
+            host.poll_registry(&mut registry);
+            registry.get_mut(kbd_id).unwrap().poll(&mut host);
+        }
+        assert!(matches!(registry.get_mut(kbd_id).unwrap().take_event(), Some(KbdEvent::DeviceAdded(_))));
+    }
+}
@@ -0,0 +1,395 @@
+use super::{detector::SimpleDetector, DiscoveryInterest, Driver, EventQueue, HasEvents, EVENT_QUEUE_DEPTH};
+use crate::bus::HostBus;
+use crate::hid::{self, USAGE_PAGE_SCALE};
+use crate::types::{ConnectionSpeed, DeviceAddress, TransferType};
+use crate::{PipeId, UsbHost};
+use usb_device::UsbDirection;
+
+/// Report ID of the "Scale Data Report", as defined by the HID Point of Sale usage tables (usage page 0x8D)
+const SCALE_DATA_REPORT_ID: u8 = 3;
+
+/// Number of bytes requested from the report descriptor
+///
+/// Only the beginning is needed, to find the usage page of the top-level collection.
+const REPORT_DESCRIPTOR_PREFIX: u16 = 64;
+
+/// Driver for HID point-of-sale scales
+///
+/// Scales following the HID Point of Sale usage tables (usage page `0x8D`) report their current reading
+/// through an interrupt IN endpoint, using the "Scale Data Report" (report ID 3):
+///
+/// | Byte | Content                                      |
+/// |------|----------------------------------------------|
+/// | 0    | report ID (3)                                |
+/// | 1    | status (see [`ScaleStatus`])                 |
+/// | 2    | weight unit (see [`WeightUnit`])             |
+/// | 3    | data scaling: signed power-of-ten exponent   |
+/// | 4..6 | weight, little endian                        |
+///
+/// Scales usually send this report continuously. The driver keeps track of the last reading for each device,
+/// and only reports a [`ScaleEvent::WeightChanged`] when it differs from the previous one.
+///
+/// Note: the usage page is only declared in the HID report descriptor. For any non-boot HID interface with an interrupt
+///   IN endpoint, the driver therefore has the host read the beginning of the report descriptor during discovery (see
+///   [`DiscoveryInterest::ReportDescriptor`]). Only devices whose top-level collection is on usage page `0x8D` are
+///   configured, any other device is left to the drivers that follow in the list passed to [`UsbHost::poll`].
+#[derive(Debug)]
+pub struct ScaleDriver<const MAX_DEVICES: usize = 2> {
+    devices: [Option<ScaleDevice>; MAX_DEVICES],
+    detector: SimpleDetector<0x03, 0x00, { UsbDirection::In as u8 }, { TransferType::Interrupt as u8 }>,
+    /// Whether the report descriptor identified the device being discovered as a scale, once it was read
+    identified: Option<(DeviceAddress, bool)>,
+    events: EventQueue<ScaleEvent, EVENT_QUEUE_DEPTH>,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct ScaleDevice {
+    dev_addr: DeviceAddress,
+    interrupt_pipe: PipeId,
+    last_reading: Option<ScaleReading>,
+}
+
+/// Status of the scale, as reported in the scale data report
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ScaleStatus {
+    Fault,
+    StableAtZero,
+    InMotion,
+    Stable,
+    UnderZero,
+    OverWeightLimit,
+    RequiresCalibration,
+    RequiresRezeroing,
+    /// Status value not defined by the specification
+    Unknown(u8),
+}
+
+impl From<u8> for ScaleStatus {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => ScaleStatus::Fault,
+            2 => ScaleStatus::StableAtZero,
+            3 => ScaleStatus::InMotion,
+            4 => ScaleStatus::Stable,
+            5 => ScaleStatus::UnderZero,
+            6 => ScaleStatus::OverWeightLimit,
+            7 => ScaleStatus::RequiresCalibration,
+            8 => ScaleStatus::RequiresRezeroing,
+            other => ScaleStatus::Unknown(other),
+        }
+    }
+}
+
+/// Unit in which the weight is reported
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum WeightUnit {
+    Milligram,
+    Gram,
+    Kilogram,
+    Carat,
+    Tael,
+    Grain,
+    Pennyweight,
+    MetricTon,
+    AvoirTon,
+    TroyOunce,
+    Ounce,
+    Pound,
+    /// Unit value not defined by the specification
+    Unknown(u8),
+}
+
+impl From<u8> for WeightUnit {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => WeightUnit::Milligram,
+            2 => WeightUnit::Gram,
+            3 => WeightUnit::Kilogram,
+            4 => WeightUnit::Carat,
+            5 => WeightUnit::Tael,
+            6 => WeightUnit::Grain,
+            7 => WeightUnit::Pennyweight,
+            8 => WeightUnit::MetricTon,
+            9 => WeightUnit::AvoirTon,
+            10 => WeightUnit::TroyOunce,
+            11 => WeightUnit::Ounce,
+            12 => WeightUnit::Pound,
+            other => WeightUnit::Unknown(other),
+        }
+    }
+}
+
+/// A single reading, decoded from a scale data report
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ScaleReading {
+    pub status: ScaleStatus,
+    pub unit: WeightUnit,
+    /// Power-of-ten exponent applied to `value`
+    pub exponent: i8,
+    /// Raw weight value
+    ///
+    /// The actual weight is `value * 10^exponent`, expressed in `unit`.
+    pub value: u16,
+}
+
+impl ScaleReading {
+    /// Decode a scale data report (including the leading report ID)
+    ///
+    /// Returns `None` if the data is not a scale data report.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        match data {
+            [SCALE_DATA_REPORT_ID, status, unit, exponent, lo, hi, ..] => Some(ScaleReading {
+                status: (*status).into(),
+                unit: (*unit).into(),
+                exponent: *exponent as i8,
+                value: u16::from_le_bytes([*lo, *hi]),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Events related to attached scale(s)
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ScaleEvent {
+    /// A new scale was detected & configured, with given device address
+    DeviceAdded(DeviceAddress),
+
+    /// A scale was removed
+    DeviceRemoved(DeviceAddress),
+
+    /// The reading of one of the scales changed
+    WeightChanged(DeviceAddress, ScaleReading),
+}
+
+impl<const MAX_DEVICES: usize> Default for ScaleDriver<MAX_DEVICES> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const MAX_DEVICES: usize> ScaleDriver<MAX_DEVICES> {
+    pub fn new() -> Self {
+        Self {
+            devices: [None; MAX_DEVICES],
+            detector: SimpleDetector::default(),
+            identified: None,
+            events: EventQueue::new(),
+        }
+    }
+
+    /// Returns the oldest pending scale event (if any), and removes it from the queue.
+    ///
+    /// This method should be called repeatedly after calling `usb_host.poll(...)`, until it returns `None` (see
+    /// [`HasEvents`] for what happens otherwise).
+    pub fn take_event(&mut self) -> Option<ScaleEvent> {
+        self.events.pop()
+    }
+
+    /// Returns the most recent reading received from the given device
+    pub fn reading(&self, dev_addr: DeviceAddress) -> Option<ScaleReading> {
+        self.devices
+            .iter()
+            .flatten()
+            .find(|d| d.dev_addr == dev_addr)
+            .and_then(|d| d.last_reading)
+    }
+
+    /// Whether the report descriptor identified the given device as a scale, once it was read
+    fn identified(&self, dev_addr: DeviceAddress) -> Option<bool> {
+        self.identified.filter(|(addr, _)| *addr == dev_addr).map(|(_, is_scale)| is_scale)
+    }
+}
+
+impl<const MAX_DEVICES: usize> HasEvents for ScaleDriver<MAX_DEVICES> {
+    type Event = ScaleEvent;
+
+    fn take_event(&mut self) -> Option<ScaleEvent> {
+        self.events.pop()
+    }
+
+    fn events_pending(&self) -> bool {
+        !self.events.is_empty()
+    }
+}
+
+impl<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+        const MAX_DEVICES: usize,
+    > Driver<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE> for ScaleDriver<MAX_DEVICES>
+{
+    fn attached(&mut self, dev_addr: DeviceAddress, _connection_speed: ConnectionSpeed) {
+        self.detector.attached(dev_addr);
+        self.identified = None;
+    }
+
+    fn detached(&mut self, dev_addr: DeviceAddress) {
+        if let Some(slot) = self.devices.iter_mut().find(|d| matches!(d, Some(d) if d.dev_addr == dev_addr)) {
+            slot.take();
+            self.events.push(ScaleEvent::DeviceRemoved(dev_addr));
+        } else {
+            self.detector.detached(dev_addr);
+            if self.identified(dev_addr).is_some() {
+                self.identified = None;
+            }
+        }
+    }
+
+    fn descriptor(&mut self, dev_addr: DeviceAddress, descriptor_type: u8, data: &[u8]) {
+        if descriptor_type == hid::TYPE_REPORT {
+            // an empty descriptor (refused by the device) does not identify a scale either
+            if self.detector.interface(dev_addr).is_some() {
+                self.identified = Some((dev_addr, hid::top_level_usage_page(data) == Some(USAGE_PAGE_SCALE)));
+            }
+        } else {
+            self.detector.descriptor(dev_addr, descriptor_type, data);
+        }
+    }
+
+    fn discovery_interest(&mut self, dev_addr: DeviceAddress) -> DiscoveryInterest {
+        match (self.detector.interface(dev_addr), self.identified(dev_addr)) {
+            (Some(interface), None) => DiscoveryInterest::ReportDescriptor { interface, length: REPORT_DESCRIPTOR_PREFIX },
+            (Some(_), Some(false)) => DiscoveryInterest::NotInterested,
+            _ => self.detector.interest(dev_addr),
+        }
+    }
+
+    fn configure(&mut self, dev_addr: DeviceAddress) -> Option<u8> {
+        match self.identified(dev_addr) {
+            Some(true) => self.detector.configure(dev_addr),
+            _ => None,
+        }
+    }
+
+    fn configured(
+        &mut self,
+        dev_addr: DeviceAddress,
+        value: u8,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) {
+        if self.identified(dev_addr).is_some() {
+            self.identified = None;
+        }
+        if let Some((_, (endpoint, size, interval))) = self.detector.configured(dev_addr, value) {
+            if let Some((index, slot)) = self.devices.iter_mut().enumerate().find(|(_, d)| d.is_none()) {
+                // the slot index is used as pipe context, to find the device in `completed_in`
+                if let Some(interrupt_pipe) =
+                    host.create_interrupt_pipe_with_context(dev_addr, endpoint, UsbDirection::In, size, interval, index as u16)
+                {
+                    slot.replace(ScaleDevice { dev_addr, interrupt_pipe, last_reading: None });
+                    self.events.push(ScaleEvent::DeviceAdded(dev_addr));
+                }
+            }
+        }
+    }
+
+    fn completed_control(&mut self, _dev_addr: DeviceAddress, _pipe_id: PipeId, _data: Option<&[u8]>) {
+        // ignored, since there are no control transfers after configuration.
+    }
+
+    fn completed_in(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, data: &[u8]) {
+        if let Some(Some(device)) = self.devices.get_mut(pipe_id.context() as usize) {
+            if device.dev_addr == dev_addr && pipe_id == device.interrupt_pipe {
+                if let Some(reading) = ScaleReading::parse(data) {
+                    if device.last_reading != Some(reading) {
+                        device.last_reading = Some(reading);
+                        self.events.push(ScaleEvent::WeightChanged(dev_addr, reading));
+                    }
+                }
+            }
+        }
+    }
+
+    fn completed_out(&mut self, _dev_addr: DeviceAddress, _pipe_id: PipeId, _data: &mut [u8]) {
+        // ignored, since there are no OUT pipes in use.
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::mock::fixtures::DEVICE_DESCRIPTOR;
+    use crate::bus::mock::{ControlResponse, MockDevice, MockHostBus, Response};
+
+    #[test]
+    fn test_parse_reading() {
+        let reading = ScaleReading::parse(&[3, 4, 2, 0xFF, 0x39, 0x30]).unwrap();
+        assert!(reading.status == ScaleStatus::Stable);
+        assert!(reading.unit == WeightUnit::Gram);
+        assert_eq!(reading.exponent, -1);
+        assert_eq!(reading.value, 12345);
+    }
+
+    #[test]
+    fn test_parse_other_report() {
+        assert!(ScaleReading::parse(&[4, 4, 2, 0, 0, 0]).is_none());
+        assert!(ScaleReading::parse(&[3, 4, 2]).is_none());
+    }
+
+    /// Configuration with a non-boot HID interface, with an interrupt IN endpoint
+    const HID_DESCRIPTOR: &[u8] = &[
+        0x09, 0x02, 0x22, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32, // configuration 1
+        0x09, 0x04, 0x00, 0x00, 0x01, 0x03, 0x00, 0x00, 0x00, // interface 0: HID, no boot protocol
+        0x09, 0x21, 0x11, 0x01, 0x00, 0x01, 0x22, 0x40, 0x00, // HID
+        0x07, 0x05, 0x81, 0x03, 0x08, 0x00, 0x0a, // endpoint 1 IN, interrupt
+    ];
+
+    const fn report_descriptor(data: &'static [u8]) -> ControlResponse<'static> {
+        ControlResponse { request_type: 0x81, request: 0x06, value: 0x2200, index: 0, response: Response::Data(data) }
+    }
+
+    fn run(report_descriptor: &'static [ControlResponse<'static>]) -> (UsbHost<MockHostBus<'static>>, ScaleDriver, [Option<ScaleEvent>; 2]) {
+        let device = MockDevice { control_responses: report_descriptor, ..MockDevice::new(DEVICE_DESCRIPTOR, &[HID_DESCRIPTOR]) };
+        let mut host = UsbHost::new(MockHostBus::new(device));
+        let mut scale = ScaleDriver::new();
+        host.bus().attach();
+        let mut events = [None; 2];
+        for _ in 0..1000 {
+            host.poll(&mut [&mut scale]);
+            if let Some(event) = scale.take_event() {
+                if let ScaleEvent::DeviceAdded(_) = event {
+                    assert!(host.bus().send_interrupt(1, &[3, 4, 2, 0xFF, 0x39, 0x30]));
+                }
+                events[events.iter().position(Option::is_none).unwrap()] = Some(event);
+            }
+        }
+        (host, scale, events)
+    }
+
+    #[test]
+    fn test_scale() {
+        // Usage Page (Scale), Usage (Scale Device), Collection (Application), ...
+        const SCALE: &[ControlResponse] = &[report_descriptor(&[0x05, 0x8D, 0x09, 0x20, 0xA1, 0x01, 0x85, 0x03])];
+        let (mut host, scale, events) = run(SCALE);
+        assert_eq!(host.bus().configuration(), Some(1));
+        let reading = ScaleReading::parse(&[3, 4, 2, 0xFF, 0x39, 0x30]);
+        assert!(matches!(events, [Some(ScaleEvent::DeviceAdded(_)), Some(ScaleEvent::WeightChanged(_, r))] if Some(r) == reading));
+        let Some(ScaleEvent::DeviceAdded(dev_addr)) = events[0] else { unreachable!() };
+        assert!(scale.reading(dev_addr) == reading);
+    }
+
+    #[test]
+    fn test_not_a_scale() {
+        // Usage Page (Generic Desktop), Usage (Game Pad), Collection (Application), ...
+        const GAMEPAD: &[ControlResponse] = &[report_descriptor(&[0x05, 0x01, 0x09, 0x05, 0xA1, 0x01, 0x15, 0x00])];
+        let (mut host, _, events) = run(GAMEPAD);
+        assert!(matches!(events, [None, None]));
+        // the device was left to other drivers, so it was not configured
+        assert_eq!(host.bus().configuration(), None);
+    }
+
+    #[test]
+    fn test_report_descriptor_refused() {
+        // the device stalls the request for the report descriptor
+        let (mut host, _, events) = run(&[]);
+        assert!(matches!(events, [None, None]));
+        assert_eq!(host.bus().configuration(), None);
+    }
+}
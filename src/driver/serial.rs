@@ -0,0 +1,755 @@
+//! USB to serial adapters with vendor specific protocols (FTDI, CP210x, CH340)
+//!
+//! These adapters do not implement the CDC-ACM class. Instead each chip family has its own set of vendor requests to
+//! configure the baud rate, the line settings and the modem control lines. The data itself is exchanged via a pair of
+//! bulk endpoints, just like with CDC-ACM.
+//!
+//! The [`SerialDriver`] implements everything that is common to the chips, and delegates the vendor requests to a
+//! [`SerialChip`] backend:
+//! - [`ftdi::Ftdi`] for FTDI chips (FT232R, FT2232, FT232H, ...)
+//! - [`cp210x::Cp210x`] for Silicon Labs CP210x chips
+//! - [`ch340::Ch340`] for WCH CH340 / CH341 chips
+//!
+//! Applications can be written against the [`SerialPort`] trait, to support all of them the same way. Events are taken
+//! via the [`HasEvents`] trait.
+//!
+//! Since transfers cannot be initiated from within driver callbacks, the application must call [`SerialPort::poll`]
+//! after every call to [`UsbHost::poll`], to send pending requests and data.
+//!
+//! Example:
+//! ```ignore
+//! let mut serial = FtdiDriver::new();
+//!
+//! loop {
+//!     usb_host.poll(&mut [&mut serial]);
+//!     serial.poll(&mut usb_host).ok();
+//!
+//!     match serial.take_event() {
+//!         Some(SerialEvent::DeviceAdded(_)) => {
+//!             serial.set_line_coding(LineCoding { baud_rate: 9600, ..LineCoding::default() }).unwrap();
+//!             serial.write(b"AT\r\n").unwrap();
+//!         }
+//!         Some(SerialEvent::WriteComplete(_)) => serial.read().unwrap(),
+//!         Some(SerialEvent::DataReceived(_)) => {
+//!             let data = serial.received();
+//!             // ...
+//!         }
+//!         _ => {}
+//!     }
+//! }
+//! ```
+//!
+//! Limitations:
+//! - Only a single device, and only the first port of multi-port chips is supported
+//! - Flow control is not supported
+//! - Data is only received when requested with [`SerialPort::read`], one packet at a time
+
+use super::{detector::SingleDevice, Driver, EventQueue, HasEvents, EVENT_QUEUE_DEPTH};
+use crate::bus::HostBus;
+use crate::descriptor;
+use crate::types::{ConnectionSpeed, DeviceAddress, SetupPacket, TransferType};
+use crate::{ControlError, PipeId, TransferError, UsbHost};
+use core::marker::PhantomData;
+use usb_device::control::{Recipient, RequestType};
+use usb_device::UsbDirection;
+
+pub mod ch340;
+pub mod cp210x;
+pub mod ftdi;
+
+pub use ch340::Ch340Driver;
+pub use cp210x::Cp210xDriver;
+pub use ftdi::FtdiDriver;
+
+/// Interface class code for vendor specific interfaces
+const CLASS_VENDOR_SPECIFIC: u8 = 0xFF;
+
+/// Size of the write buffer, which limits the amount of data passed to a single [`SerialPort::write`] call
+pub const WRITE_BUFFER_SIZE: usize = 256;
+
+/// Size of the read buffer, which limits the amount of data received per [`SerialPort::read`]
+pub const READ_BUFFER_SIZE: usize = 64;
+
+/// Maximum number of vendor requests a single operation of a [`SerialChip`] consists of
+pub const MAX_REQUESTS: usize = 4;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    Mark,
+    Space,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum StopBits {
+    One,
+    OnePointFive,
+    Two,
+}
+
+/// Baud rate and character format
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LineCoding {
+    pub baud_rate: u32,
+    /// Number of data bits (5 to 8). Not all chips support all of them.
+    pub data_bits: u8,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+
+impl Default for LineCoding {
+    /// 115200 baud, 8N1
+    fn default() -> Self {
+        LineCoding { baud_rate: 115200, data_bits: 8, parity: Parity::None, stop_bits: StopBits::One }
+    }
+}
+
+/// A vendor specific control OUT request
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct VendorRequest {
+    pub recipient: Recipient,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    data: [u8; 4],
+    length: u8,
+}
+
+impl VendorRequest {
+    /// A request without data stage
+    pub const fn new(recipient: Recipient, request: u8, value: u16, index: u16) -> Self {
+        VendorRequest { recipient, request, value, index, data: [0; 4], length: 0 }
+    }
+
+    /// A request with a data stage of up to 4 bytes
+    pub fn with_data(recipient: Recipient, request: u8, value: u16, index: u16, data: &[u8]) -> Self {
+        let mut request = Self::new(recipient, request, value, index);
+        let length = data.len().min(request.data.len());
+        request.data[..length].copy_from_slice(&data[..length]);
+        request.length = length as u8;
+        request
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.length as usize]
+    }
+
+    fn setup(&self) -> SetupPacket {
+        SetupPacket::new(
+            UsbDirection::Out,
+            RequestType::Vendor,
+            self.recipient,
+            self.request,
+            self.value,
+            self.index,
+            self.length as u16,
+        )
+    }
+}
+
+/// Vendor requests to be sent to the device, in order
+#[derive(Copy, Clone, Debug)]
+pub struct RequestList {
+    requests: [Option<VendorRequest>; MAX_REQUESTS],
+}
+
+impl RequestList {
+    pub const fn new() -> Self {
+        RequestList { requests: [None; MAX_REQUESTS] }
+    }
+
+    /// Append a request. Requests exceeding [`MAX_REQUESTS`] are dropped.
+    pub fn push(&mut self, request: VendorRequest) {
+        if let Some(slot) = self.requests.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(request);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &VendorRequest> {
+        self.requests.iter().flatten()
+    }
+}
+
+impl Default for RequestList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Chip specific part of a serial adapter driver
+///
+/// The requests produced by the backend are sent in order, once the previous operation has finished. The `interface`
+/// passed to each method is the number of the interface the driver uses.
+pub trait SerialChip {
+    /// Returns `true` if the device with the given vendor and product ID is handled by this backend
+    fn matches(vendor_id: u16, product_id: u16) -> bool;
+
+    /// Requests to initialize the chip, sent right after the device was configured
+    fn open(interface: u8, requests: &mut RequestList);
+
+    /// Requests to apply the given line coding
+    ///
+    /// Returns [`SerialError::Unsupported`] if the chip cannot use the given settings.
+    fn set_line_coding(interface: u8, coding: &LineCoding, requests: &mut RequestList) -> Result<(), SerialError>;
+
+    /// Requests to set the DTR and RTS modem control lines
+    fn set_control_lines(interface: u8, dtr: bool, rts: bool, requests: &mut RequestList);
+
+    /// Extract the payload from a packet received on the bulk IN endpoint
+    ///
+    /// Some chips prepend status information to every packet.
+    fn payload(data: &[u8]) -> &[u8] {
+        data
+    }
+}
+
+/// Events generated by the [`SerialDriver`]
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SerialEvent {
+    /// A serial adapter was detected & configured. It was initialized with the default [`LineCoding`].
+    DeviceAdded(DeviceAddress),
+
+    /// The serial adapter was removed
+    DeviceRemoved(DeviceAddress),
+
+    /// Data requested with [`SerialPort::read`] was received. It can be accessed with [`SerialPort::received`].
+    DataReceived(DeviceAddress),
+
+    /// All data passed to [`SerialPort::write`] was sent to the adapter
+    WriteComplete(DeviceAddress),
+
+    /// A vendor request was rejected by the adapter, or a transfer failed
+    Failed(DeviceAddress),
+}
+
+/// Error type for interactions with the driver
+#[derive(Copy, Clone, Debug)]
+pub enum SerialError {
+    /// Error initiating a transfer
+    ControlError(ControlError),
+
+    /// No serial adapter is currently attached & configured
+    NotConfigured,
+
+    /// A previous operation of the same kind is still in progress
+    Busy,
+
+    /// The data passed to [`SerialPort::write`] exceeds [`WRITE_BUFFER_SIZE`].
+    InvalidLength,
+
+    /// The chip does not support the requested settings
+    Unsupported,
+}
+
+impl From<ControlError> for SerialError {
+    fn from(e: ControlError) -> Self {
+        SerialError::ControlError(e)
+    }
+}
+
+/// Common interface of serial adapter drivers
+///
+/// Events are taken via [`HasEvents::take_event`].
+pub trait SerialPort: HasEvents<Event = SerialEvent> {
+    /// Address of the adapter, if it is currently configured
+    fn device_address(&self) -> Option<DeviceAddress>;
+
+    /// Change baud rate and character format
+    fn set_line_coding(&mut self, coding: LineCoding) -> Result<(), SerialError>;
+
+    /// Set the DTR and RTS modem control lines
+    fn set_control_lines(&mut self, dtr: bool, rts: bool) -> Result<(), SerialError>;
+
+    /// Send the given data. Results in [`SerialEvent::WriteComplete`].
+    fn write(&mut self, data: &[u8]) -> Result<(), SerialError>;
+
+    /// Receive the next packet of data. Results in [`SerialEvent::DataReceived`].
+    ///
+    /// Note that the bus is occupied until the adapter has data to send, which may block other transfers. Reading is
+    /// therefore only done on request.
+    fn read(&mut self) -> Result<(), SerialError>;
+
+    /// Data received by the most recent [`read`](SerialPort::read)
+    fn received(&self) -> &[u8];
+
+    /// Send pending requests and data
+    ///
+    /// Must be called after every call to `usb_host.poll(...)`. If the bus is currently busy, nothing happens,
+    /// and the transfer is initiated on a later call.
+    fn poll<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    >(
+        &mut self,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) -> Result<(), SerialError>;
+}
+
+/// Descriptors of a newly attached device, as far as they were inspected
+#[derive(Copy, Clone, Default, Debug)]
+struct Pending {
+    /// Set once the device descriptor matched the chip
+    supported: bool,
+    /// Configuration currently being inspected
+    config: Option<u8>,
+    /// Set while the descriptors of a matching interface are being inspected
+    interface: Option<u8>,
+    /// Endpoint number & max packet size of the bulk IN endpoint
+    bulk_in: Option<(u8, u16)>,
+    /// Endpoint number & max packet size of the bulk OUT endpoint
+    bulk_out: Option<(u8, u16)>,
+    /// Configuration & interface number containing a matching interface
+    chosen: Option<(u8, u8)>,
+}
+
+/// The adapter was configured, pipes are open
+#[derive(Copy, Clone, Debug)]
+struct Configured {
+    interface: u8,
+    control_pipe: PipeId,
+    in_pipe: PipeId,
+    in_max_packet_size: u16,
+    out_pipe: PipeId,
+    out_max_packet_size: u16,
+}
+
+/// Data being sent
+#[derive(Copy, Clone, Debug)]
+struct WriteJob {
+    length: u16,
+    /// Bytes sent so far
+    sent: u16,
+}
+
+/// Driver for serial adapters, using the vendor requests of the chip `C`
+///
+/// See [module-level documentation](crate::driver::serial) for details.
+#[derive(Debug)]
+pub struct SerialDriver<C: SerialChip> {
+    state: SingleDevice<Pending, Configured>,
+    /// Vendor requests waiting to be sent, and the number of them already sent
+    requests: RequestList,
+    next_request: usize,
+    write: Option<WriteJob>,
+    read_pending: bool,
+    /// Set while a transfer (of any kind) is in progress
+    in_flight: bool,
+    write_buffer: [u8; WRITE_BUFFER_SIZE],
+    read_buffer: [u8; READ_BUFFER_SIZE],
+    read_len: usize,
+    events: EventQueue<SerialEvent, EVENT_QUEUE_DEPTH>,
+    chip: PhantomData<C>,
+}
+
+impl<C: SerialChip> Default for SerialDriver<C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C: SerialChip> SerialDriver<C> {
+    pub fn new() -> Self {
+        Self {
+            state: SingleDevice::Idle,
+            requests: RequestList::new(),
+            next_request: 0,
+            write: None,
+            read_pending: false,
+            in_flight: false,
+            write_buffer: [0; WRITE_BUFFER_SIZE],
+            read_buffer: [0; READ_BUFFER_SIZE],
+            read_len: 0,
+            events: EventQueue::new(),
+            chip: PhantomData,
+        }
+    }
+
+    fn interface(&self) -> Result<u8, SerialError> {
+        match self.state {
+            SingleDevice::Configured(_, Configured { interface, .. }) => Ok(interface),
+            _ => Err(SerialError::NotConfigured),
+        }
+    }
+
+    /// Queue the requests produced by the backend, unless others are still pending
+    fn queue_requests(&mut self, build: impl FnOnce(u8, &mut RequestList) -> Result<(), SerialError>) -> Result<(), SerialError> {
+        let interface = self.interface()?;
+        if self.requests.iter().nth(self.next_request).is_some() {
+            return Err(SerialError::Busy);
+        }
+        let mut requests = RequestList::new();
+        build(interface, &mut requests)?;
+        self.requests = requests;
+        self.next_request = 0;
+        Ok(())
+    }
+
+    fn failed(&mut self, dev_addr: DeviceAddress) {
+        self.in_flight = false;
+        self.write = None;
+        self.read_pending = false;
+        self.requests = RequestList::new();
+        self.next_request = 0;
+        self.events.push(SerialEvent::Failed(dev_addr));
+    }
+
+    fn reset(&mut self) {
+        self.state = SingleDevice::Idle;
+        self.requests = RequestList::new();
+        self.next_request = 0;
+        self.write = None;
+        self.read_pending = false;
+        self.in_flight = false;
+        self.read_len = 0;
+    }
+}
+
+impl<C: SerialChip> SerialPort for SerialDriver<C> {
+    fn device_address(&self) -> Option<DeviceAddress> {
+        self.state.address()
+    }
+
+    fn set_line_coding(&mut self, coding: LineCoding) -> Result<(), SerialError> {
+        self.queue_requests(|interface, requests| C::set_line_coding(interface, &coding, requests))
+    }
+
+    fn set_control_lines(&mut self, dtr: bool, rts: bool) -> Result<(), SerialError> {
+        self.queue_requests(|interface, requests| {
+            C::set_control_lines(interface, dtr, rts, requests);
+            Ok(())
+        })
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), SerialError> {
+        self.interface()?;
+        if self.write.is_some() {
+            return Err(SerialError::Busy);
+        }
+        if data.len() > WRITE_BUFFER_SIZE {
+            return Err(SerialError::InvalidLength);
+        }
+        self.write_buffer[..data.len()].copy_from_slice(data);
+        self.write = Some(WriteJob { length: data.len() as u16, sent: 0 });
+        Ok(())
+    }
+
+    fn read(&mut self) -> Result<(), SerialError> {
+        self.interface()?;
+        if self.read_pending {
+            return Err(SerialError::Busy);
+        }
+        self.read_pending = true;
+        Ok(())
+    }
+
+    fn received(&self) -> &[u8] {
+        &self.read_buffer[..self.read_len]
+    }
+
+    fn poll<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    >(
+        &mut self,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) -> Result<(), SerialError> {
+        let SingleDevice::Configured(dev_addr, Configured { control_pipe, in_pipe, in_max_packet_size, out_pipe, out_max_packet_size, .. }) =
+            self.state
+        else {
+            return Ok(());
+        };
+        if self.in_flight {
+            return Ok(());
+        }
+        // vendor requests go first, so that data is sent with the new settings
+        let result = if let Some(request) = self.requests.iter().nth(self.next_request) {
+            host.control_out(Some(dev_addr), Some(control_pipe), request.setup(), request.data())
+        } else if let Some(job) = &mut self.write {
+            if job.sent >= job.length {
+                self.write = None;
+                self.events.push(SerialEvent::WriteComplete(dev_addr));
+                return Ok(());
+            }
+            let start = job.sent as usize;
+            let chunk = out_max_packet_size.min(job.length - job.sent);
+            host.bulk_out(out_pipe, &self.write_buffer[start..start + chunk as usize])
+        } else if self.read_pending {
+            host.bulk_in(in_pipe, in_max_packet_size.min(READ_BUFFER_SIZE as u16))
+        } else {
+            return Ok(());
+        };
+        match result {
+            Ok(()) => {
+                self.in_flight = true;
+                Ok(())
+            }
+            Err(ControlError::WouldBlock) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl<C: SerialChip> HasEvents for SerialDriver<C> {
+    type Event = SerialEvent;
+
+    fn take_event(&mut self) -> Option<SerialEvent> {
+        self.events.pop()
+    }
+
+    fn events_pending(&self) -> bool {
+        !self.events.is_empty()
+    }
+}
+
+impl<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+        C: SerialChip,
+    > Driver<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE> for SerialDriver<C>
+{
+    fn attached(&mut self, dev_addr: DeviceAddress, _connection_speed: ConnectionSpeed) {
+        self.state.attached(dev_addr, Pending::default());
+    }
+
+    fn detached(&mut self, dev_addr: DeviceAddress) {
+        if self.state.detached(dev_addr) {
+            self.reset();
+            self.events.push(SerialEvent::DeviceRemoved(dev_addr));
+        }
+    }
+
+    fn descriptor(&mut self, dev_addr: DeviceAddress, descriptor_type: u8, data: &[u8]) {
+        let Some(Pending { supported, config, interface, bulk_in, bulk_out, chosen }) = self.state.pending(dev_addr) else {
+            return;
+        };
+        if chosen.is_some() {
+            return;
+        }
+        match descriptor_type {
+            descriptor::TYPE_DEVICE => {
+                if let Ok((_, device)) = descriptor::parse::device_descriptor(data) {
+                    *supported = C::matches(device.id_vendor, device.id_product);
+                }
+            }
+            descriptor::TYPE_CONFIGURATION if *supported => {
+                if let Ok((_, configuration)) = descriptor::parse::configuration_descriptor(data) {
+                    *config = Some(configuration.value);
+                    *interface = None;
+                }
+            }
+            descriptor::TYPE_INTERFACE if *supported => {
+                if let Ok((_, descriptor)) = descriptor::parse::interface_descriptor(data) {
+                    let matching = descriptor.interface_class == CLASS_VENDOR_SPECIFIC && descriptor.alternate_setting == 0;
+                    *interface = matching.then_some(descriptor.interface_number);
+                    *bulk_in = None;
+                    *bulk_out = None;
+                }
+            }
+            descriptor::TYPE_ENDPOINT => {
+                let (Some(config), Some(interface)) = (*config, *interface) else {
+                    return;
+                };
+                if let Ok((_, endpoint)) = descriptor::parse::endpoint_descriptor(data) {
+                    if endpoint.attributes.transfer_type() != TransferType::Bulk || endpoint.max_packet_size == 0 {
+                        return;
+                    }
+                    let found = Some((endpoint.address.number(), endpoint.max_packet_size));
+                    match endpoint.address.direction() {
+                        UsbDirection::In => *bulk_in = found,
+                        UsbDirection::Out => *bulk_out = found,
+                    }
+                    if bulk_in.is_some() && bulk_out.is_some() {
+                        *chosen = Some((config, interface));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn configure(&mut self, dev_addr: DeviceAddress) -> Option<u8> {
+        self.state.configure(dev_addr, |pending| pending.chosen.map(|(config, _)| config))
+    }
+
+    fn configured(
+        &mut self,
+        dev_addr: DeviceAddress,
+        value: u8,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) {
+        let Some(Pending { chosen, bulk_in, bulk_out, .. }) = self.state.take_pending(dev_addr) else {
+            return;
+        };
+        let (Some((config, interface)), Some((in_ep, in_max_packet_size)), Some((out_ep, out_max_packet_size))) =
+            (chosen, bulk_in, bulk_out)
+        else {
+            return;
+        };
+        if config != value {
+            return;
+        }
+        let control_pipe = host.create_control_pipe(dev_addr);
+        let in_pipe = host.create_bulk_pipe(dev_addr, in_ep, UsbDirection::In, in_max_packet_size);
+        let out_pipe = host.create_bulk_pipe(dev_addr, out_ep, UsbDirection::Out, out_max_packet_size);
+        let (Some(control_pipe), Some(in_pipe), Some(out_pipe)) = (control_pipe, in_pipe, out_pipe) else {
+            // the host ran out of pipes: release the ones that were created
+            for pipe in control_pipe.into_iter().chain(in_pipe).chain(out_pipe) {
+                host.release_pipe(pipe);
+            }
+            return;
+        };
+        self.state = SingleDevice::Configured(
+            dev_addr,
+            Configured { interface, control_pipe, in_pipe, in_max_packet_size, out_pipe, out_max_packet_size },
+        );
+        let mut requests = RequestList::new();
+        C::open(interface, &mut requests);
+        // the default line coding is supported by all chips
+        let mut coding = RequestList::new();
+        C::set_line_coding(interface, &LineCoding::default(), &mut coding).ok();
+        for request in coding.iter() {
+            requests.push(*request);
+        }
+        self.requests = requests;
+        self.next_request = 0;
+        self.events.push(SerialEvent::DeviceAdded(dev_addr));
+    }
+
+    fn completed_control(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, _data: Option<&[u8]>) {
+        let Some(&Configured { control_pipe, .. }) = self.state.device(dev_addr) else {
+            return;
+        };
+        if pipe_id == control_pipe && self.in_flight {
+            self.in_flight = false;
+            self.next_request += 1;
+        }
+    }
+
+    fn completed_in(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, data: &[u8]) {
+        let Some(&Configured { in_pipe, .. }) = self.state.device(dev_addr) else {
+            return;
+        };
+        if pipe_id != in_pipe || !self.in_flight {
+            return;
+        }
+        self.in_flight = false;
+        let payload = C::payload(data);
+        if payload.is_empty() {
+            // nothing received yet (e.g. just a status packet), try again on the next `poll`
+            return;
+        }
+        self.read_pending = false;
+        self.read_len = payload.len().min(READ_BUFFER_SIZE);
+        self.read_buffer[..self.read_len].copy_from_slice(&payload[..self.read_len]);
+        self.events.push(SerialEvent::DataReceived(dev_addr));
+    }
+
+    fn completed_bulk_out(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId) {
+        let Some(&Configured { out_pipe, out_max_packet_size, .. }) = self.state.device(dev_addr) else {
+            return;
+        };
+        if pipe_id != out_pipe || !self.in_flight {
+            return;
+        }
+        self.in_flight = false;
+        if let Some(job) = &mut self.write {
+            job.sent += out_max_packet_size.min(job.length - job.sent);
+        }
+    }
+
+    fn completed_out(&mut self, _dev_addr: DeviceAddress, _pipe_id: PipeId, _data: &mut [u8]) {
+        // ignored, since there are no interrupt OUT pipes in use.
+    }
+
+    fn stall(&mut self, dev_addr: DeviceAddress) {
+        if self.device_address() == Some(dev_addr) && self.in_flight {
+            self.failed(dev_addr);
+        }
+    }
+
+    fn transfer_failed(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, _error: TransferError) {
+        let Some(&Configured { control_pipe, in_pipe, out_pipe, .. }) = self.state.device(dev_addr) else {
+            return;
+        };
+        if [control_pipe, in_pipe, out_pipe].contains(&pipe_id) && self.in_flight {
+            self.failed(dev_addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::mock::{ControlResponse, MockDevice, MockHostBus, Response};
+
+    #[test]
+    fn test_ftdi_serial() {
+        const FTDI_DEVICE_DESCRIPTOR: &[u8] = &[
+            0x12, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x08, 0x03, 0x04, 0x01, 0x60, 0x00, 0x06, 0x01, 0x02, 0x03, 0x01,
+        ];
+        const FTDI_CONFIGURATION_DESCRIPTOR: &[u8] = &[
+            0x09, 0x02, 0x20, 0x00, 0x01, 0x01, 0x00, 0xa0, 0x2d, // configuration 1
+            0x09, 0x04, 0x00, 0x00, 0x02, 0xff, 0xff, 0xff, 0x02, // interface 0: vendor specific
+            0x07, 0x05, 0x81, 0x02, 0x40, 0x00, 0x00, // endpoint 1 IN, bulk
+            0x07, 0x05, 0x02, 0x02, 0x40, 0x00, 0x00, // endpoint 2 OUT, bulk
+        ];
+        const VENDOR_OUT: ControlResponse = ControlResponse {
+            request_type: 0x40,
+            request: 0x04,
+            value: 0x0008,
+            index: 0,
+            response: Response::Data(&[]),
+        };
+        let device = MockDevice {
+            control_responses: &[
+                ControlResponse { request: 0x00, value: 0, ..VENDOR_OUT },
+                ControlResponse { request: 0x02, value: 0, ..VENDOR_OUT },
+                ControlResponse { request: 0x03, value: 0x001a, ..VENDOR_OUT },
+                VENDOR_OUT,
+            ],
+            ..MockDevice::new(FTDI_DEVICE_DESCRIPTOR, &[FTDI_CONFIGURATION_DESCRIPTOR])
+        };
+        let mut host = UsbHost::new(MockHostBus::new(device));
+        let mut serial = FtdiDriver::new();
+        host.bus().attach();
+        host.bus().set_bulk_in(&[0x01, 0x60, b'O', b'K']);
+
+        let mut events = [false; 3];
+        for _ in 0..1000 {
+            host.poll(&mut [&mut serial]);
+            assert!(serial.poll(&mut host).is_ok());
+            match serial.take_event() {
+                Some(SerialEvent::DeviceAdded(_)) => {
+                    events[0] = true;
+                    assert!(serial.write(b"AT\r\n").is_ok());
+                }
+                Some(SerialEvent::WriteComplete(_)) => {
+                    events[1] = true;
+                    assert!(serial.read().is_ok());
+                }
+                Some(SerialEvent::DataReceived(_)) => {
+                    events[2] = true;
+                    // the status bytes are stripped
+                    assert_eq!(serial.received(), b"OK");
+                }
+                Some(SerialEvent::Failed(_)) => panic!("vendor request failed"),
+                _ => {}
+            }
+        }
+        assert_eq!(events, [true; 3]);
+        // the default line coding (115200 8N1) was applied
+        assert_eq!(host.bus().last_setup().map(|setup| (setup.request, setup.value)), Some((0x04, 0x0008)));
+    }
+}
@@ -0,0 +1,145 @@
+//! WCH CH340 / CH341 USB to serial converters
+//!
+//! The chip is configured by writing to its registers. The baud rate is derived from a 48 MHz clock, with one of four
+//! prescalers and an 8 bit divisor.
+
+use super::{LineCoding, Parity, RequestList, SerialChip, SerialDriver, SerialError, StopBits, VendorRequest};
+use usb_device::control::Recipient;
+
+const VENDOR_ID: u16 = 0x1A86;
+/// CH340 and CH341 (in serial mode)
+const PRODUCT_IDS: &[u16] = &[0x7523, 0x5523];
+
+const REQUEST_WRITE_REG: u8 = 0x9A;
+const REQUEST_SERIAL_INIT: u8 = 0xA1;
+const REQUEST_MODEM_CTRL: u8 = 0xA4;
+
+/// Divisor (high byte) and prescaler (low byte) registers
+const REG_DIVISOR_PRESCALER: u16 = 0x1312;
+/// Line control registers
+const REG_LCR: u16 = 0x2518;
+
+const LCR_ENABLE_RX: u16 = 0x80;
+const LCR_ENABLE_TX: u16 = 0x40;
+const LCR_MARK_SPACE: u16 = 0x20;
+const LCR_PAR_EVEN: u16 = 0x10;
+const LCR_ENABLE_PAR: u16 = 0x08;
+const LCR_STOP_BITS_2: u16 = 0x04;
+
+/// Modem control bits (active low)
+const MODEM_DTR: u16 = 0x20;
+const MODEM_RTS: u16 = 0x40;
+
+/// Send data right away, instead of buffering it until a full packet was received
+const PRESCALER_NO_BUFFERING: u16 = 0x80;
+
+const CLOCK_RATE: u32 = 48_000_000;
+const MAX_BAUD_RATE: u32 = CLOCK_RATE / (clock_divider(3, 0) * 2);
+
+/// Clock divider of the given prescaler, with (`fact` = 1) or without (`fact` = 0) the additional divider by two
+const fn clock_divider(prescaler: u32, fact: u32) -> u32 {
+    1 << (12 - 3 * prescaler - fact)
+}
+
+/// Lowest baud rate which can be generated with the given prescaler
+const fn min_baud_rate(prescaler: u32) -> u32 {
+    CLOCK_RATE / (clock_divider(prescaler, 1) * 512)
+}
+
+/// Driver for CH340 serial converters
+pub type Ch340Driver = SerialDriver<Ch340>;
+
+/// [`SerialChip`] backend for CH340 / CH341 chips
+#[derive(Debug)]
+pub struct Ch340;
+
+/// Compute the value of the divisor and prescaler registers for the given baud rate
+///
+/// Returns `None` if the baud rate cannot be generated.
+pub fn baud_rate_divisor(baud_rate: u32) -> Option<u16> {
+    if baud_rate > MAX_BAUD_RATE {
+        return None;
+    }
+    let prescaler = (0..4).rev().find(|prescaler| baud_rate > min_baud_rate(*prescaler))?;
+    let mut fact = 1;
+    let mut clock_div = clock_divider(prescaler, fact);
+    let mut div = CLOCK_RATE / (clock_div * baud_rate);
+    if !(9..=255).contains(&div) {
+        div /= 2;
+        clock_div *= 2;
+        fact = 0;
+    }
+    if div < 2 {
+        return None;
+    }
+    // pick the next divisor if the resulting rate is closer to the requested one
+    if 16 * CLOCK_RATE / (clock_div * div) - 16 * baud_rate >= 16 * baud_rate - 16 * CLOCK_RATE / (clock_div * (div + 1)) {
+        div += 1;
+    }
+    // prefer the lower base clock if the divisor is even
+    if fact == 1 && div.is_multiple_of(2) {
+        div /= 2;
+        fact = 0;
+    }
+    Some((((0x100 - div) << 8) | (fact << 2) | prescaler) as u16)
+}
+
+impl SerialChip for Ch340 {
+    fn matches(vendor_id: u16, product_id: u16) -> bool {
+        vendor_id == VENDOR_ID && PRODUCT_IDS.contains(&product_id)
+    }
+
+    fn open(_interface: u8, requests: &mut RequestList) {
+        requests.push(VendorRequest::new(Recipient::Device, REQUEST_SERIAL_INIT, 0, 0));
+    }
+
+    fn set_line_coding(_interface: u8, coding: &LineCoding, requests: &mut RequestList) -> Result<(), SerialError> {
+        let divisor = baud_rate_divisor(coding.baud_rate).ok_or(SerialError::Unsupported)?;
+        if !(5..=8).contains(&coding.data_bits) || coding.stop_bits == StopBits::OnePointFive {
+            return Err(SerialError::Unsupported);
+        }
+        let mut lcr = LCR_ENABLE_RX | LCR_ENABLE_TX | (coding.data_bits as u16 - 5);
+        lcr |= match coding.parity {
+            Parity::None => 0,
+            Parity::Odd => LCR_ENABLE_PAR,
+            Parity::Even => LCR_ENABLE_PAR | LCR_PAR_EVEN,
+            Parity::Mark => LCR_ENABLE_PAR | LCR_MARK_SPACE,
+            Parity::Space => LCR_ENABLE_PAR | LCR_MARK_SPACE | LCR_PAR_EVEN,
+        };
+        if coding.stop_bits == StopBits::Two {
+            lcr |= LCR_STOP_BITS_2;
+        }
+        requests.push(VendorRequest::new(
+            Recipient::Device,
+            REQUEST_WRITE_REG,
+            REG_DIVISOR_PRESCALER,
+            divisor | PRESCALER_NO_BUFFERING,
+        ));
+        requests.push(VendorRequest::new(Recipient::Device, REQUEST_WRITE_REG, REG_LCR, lcr));
+        Ok(())
+    }
+
+    fn set_control_lines(_interface: u8, dtr: bool, rts: bool, requests: &mut RequestList) {
+        let mut lines = 0;
+        if dtr {
+            lines |= MODEM_DTR;
+        }
+        if rts {
+            lines |= MODEM_RTS;
+        }
+        requests.push(VendorRequest::new(Recipient::Device, REQUEST_MODEM_CTRL, !lines, 0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_baud_rate_divisor() {
+        assert_eq!(baud_rate_divisor(115200), Some(0xCC03));
+        assert_eq!(baud_rate_divisor(9600), Some(0xB202));
+        assert_eq!(baud_rate_divisor(10), None);
+        assert_eq!(baud_rate_divisor(4_000_000), None);
+    }
+}
@@ -0,0 +1,99 @@
+//! Silicon Labs CP210x USB to UART bridges (CP2102, CP2104, CP2105, CP2108)
+//!
+//! All requests are addressed to the interface. The baud rate is set directly (in baud), the chip picks the closest
+//! rate it can generate.
+
+use super::{LineCoding, Parity, RequestList, SerialChip, SerialDriver, SerialError, StopBits, VendorRequest};
+use usb_device::control::Recipient;
+
+const VENDOR_ID: u16 = 0x10C4;
+/// CP2102/CP2104, CP2105 and CP2108
+const PRODUCT_IDS: &[u16] = &[0xEA60, 0xEA70, 0xEA71];
+
+const REQUEST_IFC_ENABLE: u8 = 0x00;
+const REQUEST_SET_LINE_CTL: u8 = 0x03;
+const REQUEST_SET_MHS: u8 = 0x07;
+const REQUEST_SET_BAUDRATE: u8 = 0x1E;
+
+const UART_ENABLE: u16 = 0x0001;
+/// Bits of SET_MHS, which select the lines to change
+const MHS_MASK_DTR: u16 = 0x0100;
+const MHS_MASK_RTS: u16 = 0x0200;
+
+/// Maximum baud rate of the CP2102
+const MAX_BAUD_RATE: u32 = 921_600;
+
+/// Driver for CP210x serial converters
+pub type Cp210xDriver = SerialDriver<Cp210x>;
+
+/// [`SerialChip`] backend for CP210x chips
+#[derive(Debug)]
+pub struct Cp210x;
+
+/// Compute the `value` of the SET_LINE_CTL request
+pub fn line_control(coding: &LineCoding) -> u16 {
+    let stop_bits = match coding.stop_bits {
+        StopBits::One => 0,
+        StopBits::OnePointFive => 1,
+        StopBits::Two => 2,
+    };
+    let parity = match coding.parity {
+        Parity::None => 0,
+        Parity::Odd => 1,
+        Parity::Even => 2,
+        Parity::Mark => 3,
+        Parity::Space => 4,
+    };
+    stop_bits | (parity << 4) | ((coding.data_bits as u16) << 8)
+}
+
+impl SerialChip for Cp210x {
+    fn matches(vendor_id: u16, product_id: u16) -> bool {
+        vendor_id == VENDOR_ID && PRODUCT_IDS.contains(&product_id)
+    }
+
+    fn open(interface: u8, requests: &mut RequestList) {
+        requests.push(VendorRequest::new(Recipient::Interface, REQUEST_IFC_ENABLE, UART_ENABLE, interface as u16));
+    }
+
+    fn set_line_coding(interface: u8, coding: &LineCoding, requests: &mut RequestList) -> Result<(), SerialError> {
+        if coding.baud_rate == 0 || coding.baud_rate > MAX_BAUD_RATE || !(5..=8).contains(&coding.data_bits) {
+            return Err(SerialError::Unsupported);
+        }
+        requests.push(VendorRequest::with_data(
+            Recipient::Interface,
+            REQUEST_SET_BAUDRATE,
+            0,
+            interface as u16,
+            &coding.baud_rate.to_le_bytes(),
+        ));
+        requests.push(VendorRequest::new(Recipient::Interface, REQUEST_SET_LINE_CTL, line_control(coding), interface as u16));
+        Ok(())
+    }
+
+    fn set_control_lines(interface: u8, dtr: bool, rts: bool, requests: &mut RequestList) {
+        let value = MHS_MASK_DTR | MHS_MASK_RTS | dtr as u16 | ((rts as u16) << 1);
+        requests.push(VendorRequest::new(Recipient::Interface, REQUEST_SET_MHS, value, interface as u16));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_coding_requests() {
+        let mut requests = RequestList::new();
+        assert!(Cp210x::set_line_coding(1, &LineCoding::default(), &mut requests).is_ok());
+        let mut requests = requests.iter();
+        let baud = requests.next().unwrap();
+        assert_eq!((baud.request, baud.index), (REQUEST_SET_BAUDRATE, 1));
+        assert_eq!(baud.data(), &[0x00, 0xC2, 0x01, 0x00]);
+        let line = requests.next().unwrap();
+        assert_eq!((line.request, line.value), (REQUEST_SET_LINE_CTL, 0x0800));
+        assert!(requests.next().is_none());
+
+        let coding = LineCoding { data_bits: 7, parity: Parity::Even, stop_bits: StopBits::Two, ..LineCoding::default() };
+        assert_eq!(line_control(&coding), 0x0722);
+    }
+}
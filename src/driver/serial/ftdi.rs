@@ -0,0 +1,117 @@
+//! FTDI USB to serial converters (FT232R, FT2232, FT232H, ...)
+//!
+//! The baud rate is derived from a 3 MHz base clock, with a 14 bit integer divisor and a fractional part in steps of
+//! 1/8. Every packet received from the chip starts with two bytes of modem and line status, which are stripped.
+
+use super::{LineCoding, Parity, RequestList, SerialChip, SerialDriver, SerialError, StopBits, VendorRequest};
+use usb_device::control::Recipient;
+
+const VENDOR_ID: u16 = 0x0403;
+/// FT232R, FT2232, FT4232H, FT232H and FT-X series
+const PRODUCT_IDS: &[u16] = &[0x6001, 0x6010, 0x6011, 0x6014, 0x6015];
+
+const REQUEST_RESET: u8 = 0x00;
+const REQUEST_SET_MODEM_CTRL: u8 = 0x01;
+const REQUEST_SET_FLOW_CTRL: u8 = 0x02;
+const REQUEST_SET_BAUD_RATE: u8 = 0x03;
+const REQUEST_SET_DATA: u8 = 0x04;
+
+const BASE_CLOCK: u32 = 3_000_000;
+/// Encoding of the fractional part of the divisor (in eighths)
+const FRACTION_CODES: [u32; 8] = [0, 3, 2, 4, 1, 5, 6, 7];
+/// Length of the status header of each received packet
+const STATUS_LENGTH: usize = 2;
+
+/// Driver for FTDI serial converters
+pub type FtdiDriver = SerialDriver<Ftdi>;
+
+/// [`SerialChip`] backend for FTDI chips
+#[derive(Debug)]
+pub struct Ftdi;
+
+/// Compute the `value` and `index` of the SET_BAUD_RATE request
+///
+/// Returns `None` if the baud rate cannot be generated.
+pub fn baud_rate_divisor(baud_rate: u32) -> Option<(u16, u16)> {
+    if baud_rate == 0 || baud_rate > BASE_CLOCK {
+        return None;
+    }
+    // divisor in eighths, rounded to the nearest value
+    let divisor = (BASE_CLOCK * 16 / baud_rate).div_ceil(2);
+    if divisor >> 3 > 0x3FFF {
+        return None;
+    }
+    let encoded = match (divisor >> 3) | (FRACTION_CODES[(divisor & 7) as usize] << 14) {
+        // 3 MBaud and 2 MBaud have special encodings
+        1 => 0,
+        0x4001 => 1,
+        encoded => encoded,
+    };
+    Some((encoded as u16, (encoded >> 16) as u16))
+}
+
+impl SerialChip for Ftdi {
+    fn matches(vendor_id: u16, product_id: u16) -> bool {
+        vendor_id == VENDOR_ID && PRODUCT_IDS.contains(&product_id)
+    }
+
+    fn open(_interface: u8, requests: &mut RequestList) {
+        requests.push(VendorRequest::new(Recipient::Device, REQUEST_RESET, 0, 0));
+        // no flow control
+        requests.push(VendorRequest::new(Recipient::Device, REQUEST_SET_FLOW_CTRL, 0, 0));
+    }
+
+    fn set_line_coding(_interface: u8, coding: &LineCoding, requests: &mut RequestList) -> Result<(), SerialError> {
+        let (value, index) = baud_rate_divisor(coding.baud_rate).ok_or(SerialError::Unsupported)?;
+        if !(7..=8).contains(&coding.data_bits) {
+            return Err(SerialError::Unsupported);
+        }
+        let parity = match coding.parity {
+            Parity::None => 0,
+            Parity::Odd => 1,
+            Parity::Even => 2,
+            Parity::Mark => 3,
+            Parity::Space => 4,
+        };
+        let stop_bits = match coding.stop_bits {
+            StopBits::One => 0,
+            StopBits::OnePointFive => 1,
+            StopBits::Two => 2,
+        };
+        requests.push(VendorRequest::new(Recipient::Device, REQUEST_SET_BAUD_RATE, value, index));
+        let data = coding.data_bits as u16 | (parity << 8) | (stop_bits << 11);
+        requests.push(VendorRequest::new(Recipient::Device, REQUEST_SET_DATA, data, 0));
+        Ok(())
+    }
+
+    fn set_control_lines(_interface: u8, dtr: bool, rts: bool, requests: &mut RequestList) {
+        // the high byte selects the lines to change
+        requests.push(VendorRequest::new(Recipient::Device, REQUEST_SET_MODEM_CTRL, 0x0100 | dtr as u16, 0));
+        requests.push(VendorRequest::new(Recipient::Device, REQUEST_SET_MODEM_CTRL, 0x0200 | ((rts as u16) << 1), 0));
+    }
+
+    fn payload(data: &[u8]) -> &[u8] {
+        data.get(STATUS_LENGTH..).unwrap_or(&[])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_baud_rate_divisor() {
+        assert_eq!(baud_rate_divisor(115200), Some((0x001A, 0)));
+        assert_eq!(baud_rate_divisor(9600), Some((0x4138, 0)));
+        assert_eq!(baud_rate_divisor(3_000_000), Some((0, 0)));
+        assert_eq!(baud_rate_divisor(2_000_000), Some((1, 0)));
+        assert_eq!(baud_rate_divisor(100), None);
+        assert_eq!(baud_rate_divisor(0), None);
+    }
+
+    #[test]
+    fn test_payload() {
+        assert_eq!(Ftdi::payload(&[0x01, 0x60, b'O', b'K']), b"OK");
+        assert!(Ftdi::payload(&[0x01, 0x60]).is_empty());
+    }
+}
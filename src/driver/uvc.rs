@@ -0,0 +1,1231 @@
+//! USB video class (UVC) devices, i.e. webcams
+//!
+//! The [`UvcDriver`] captures MJPEG frames from a webcam. When a device is attached, the driver looks for a
+//! VideoStreaming interface that supports the MJPEG format, and picks the largest frame size not exceeding the
+//! requested [`Resolution`] (e.g. [`Resolution::QVGA`]). Once the device is configured, the driver negotiates the
+//! stream parameters with the device (probe & commit), selects an alternate setting with enough bandwidth (for
+//! isochronous endpoints), and then receives the video payloads into a frame buffer provided by the application.
+//!
+//! Each complete frame is announced with [`UvcEvent::FrameReady`]. It can then be read via [`UvcDriver::frame`].
+//! The frame stays in the buffer until the application calls [`UvcDriver::release_frame`]; frames arriving
+//! in the meantime are dropped. This makes it easy to take still images, at whatever rate the application can handle.
+//!
+//! The class-specific descriptors of the VideoControl and VideoStreaming interfaces can also be parsed
+//! on their own, via [`parse_control_descriptor`] and [`parse_streaming_descriptor`].
+//!
+//! Since transfers cannot be initiated from within driver callbacks, the application must call [`UvcDriver::poll`]
+//! after every call to [`UsbHost::poll`], at least once per frame.
+//!
+//! Example:
+//! ```ignore
+//! static mut FRAME: [u8; 16384] = [0; 16384];
+//!
+//! let mut camera = UvcDriver::new(Resolution::QQVGA, unsafe { &mut FRAME });
+//!
+//! loop {
+//!     usb_host.poll(&mut [&mut camera]);
+//!     camera.poll(&mut usb_host).ok();
+//!
+//!     if let Some(UvcEvent::FrameReady(_)) = camera.take_event() {
+//!         save_jpeg(camera.frame().unwrap());
+//!         camera.release_frame();
+//!     }
+//! }
+//! ```
+//!
+//! Limitations:
+//! - Only a single device, and only the MJPEG format are supported
+//! - The frame interval is the default one of the chosen frame size
+//! - Still image capture methods 2 and 3 (dedicated still image transfers) are not used
+//! - Camera and processing unit controls (exposure, focus, ...) are not used
+//! - At full speed, isochronous endpoints deliver at most 1023 bytes per frame, which limits both the resolution and
+//!   the frame rate. Bulk endpoints are usually faster.
+
+use super::{detector::SingleDevice, Driver, EventQueue, HasEvents, EVENT_QUEUE_DEPTH};
+use crate::bus::HostBus;
+use crate::descriptor;
+use crate::types::{ConnectionSpeed, DeviceAddress, SetupPacket, TransferType};
+use crate::{ControlError, PipeId, TransferError, UsbHost};
+use usb_device::control::{Recipient, RequestType};
+use usb_device::UsbDirection;
+
+/// Interface class code for video devices
+const CLASS_VIDEO: u8 = 0x0e;
+const SUBCLASS_VIDEO_CONTROL: u8 = 0x01;
+const SUBCLASS_VIDEO_STREAMING: u8 = 0x02;
+
+/// Descriptor type of class-specific interface descriptors
+pub const TYPE_CS_INTERFACE: u8 = 0x24;
+
+const VC_HEADER: u8 = 0x01;
+
+const VS_INPUT_HEADER: u8 = 0x01;
+const VS_FORMAT_MJPEG: u8 = 0x06;
+const VS_FRAME_MJPEG: u8 = 0x07;
+
+const REQUEST_SET_CUR: u8 = 0x01;
+const REQUEST_GET_CUR: u8 = 0x81;
+const VS_PROBE_CONTROL: u8 = 0x01;
+const VS_COMMIT_CONTROL: u8 = 0x02;
+
+/// `bmHint` of the probe control: keep the frame interval fixed
+const HINT_FRAME_INTERVAL: u16 = 0x0001;
+
+/// Size of the probe & commit controls in UVC 1.5, the largest one
+const MAX_PROBE_LENGTH: usize = 48;
+
+/// Maximum number of VideoStreaming interfaces retained from a VideoControl header
+pub const MAX_STREAMING_INTERFACES: usize = 4;
+
+/// Maximum number of isochronous alternate settings considered by the [`UvcDriver`]
+pub const MAX_ALTERNATE_SETTINGS: usize = 8;
+
+/// Maximum packet size of the isochronous endpoints used by the [`UvcDriver`] (the limit for full speed endpoints)
+///
+/// Alternate settings with larger packets (high-bandwidth endpoints of high speed devices) are ignored.
+pub const MAX_PACKET_SIZE: u16 = 1023;
+
+/// Width and height of a video frame, in pixels
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Resolution {
+    pub width: u16,
+    pub height: u16,
+}
+
+impl Resolution {
+    /// 160 x 120 pixels
+    pub const QQVGA: Resolution = Resolution::new(160, 120);
+    /// 320 x 240 pixels
+    pub const QVGA: Resolution = Resolution::new(320, 240);
+    /// 640 x 480 pixels
+    pub const VGA: Resolution = Resolution::new(640, 480);
+
+    pub const fn new(width: u16, height: u16) -> Self {
+        Self { width, height }
+    }
+
+    /// Returns `true` if a frame of this size fits into a frame of the given size
+    pub fn fits(&self, limit: &Resolution) -> bool {
+        self.width <= limit.width && self.height <= limit.height
+    }
+
+    /// Number of pixels
+    pub fn area(&self) -> u32 {
+        self.width as u32 * self.height as u32
+    }
+}
+
+/// Header of the class-specific VideoControl interface descriptors
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct VideoControlHeader {
+    /// Video class release number, in BCD (e.g. `0x0110` for UVC 1.1)
+    pub video_class: u16,
+    /// Combined length of the class-specific VideoControl descriptors, including this one
+    pub total_length: u16,
+    /// Clock frequency in Hz (deprecated in UVC 1.5)
+    pub clock_frequency: u32,
+    interfaces: [u8; MAX_STREAMING_INTERFACES],
+    interface_count: u8,
+}
+
+impl VideoControlHeader {
+    /// Numbers of the VideoStreaming interfaces belonging to this function
+    ///
+    /// At most [`MAX_STREAMING_INTERFACES`] are retained.
+    pub fn streaming_interfaces(&self) -> &[u8] {
+        &self.interfaces[..self.interface_count as usize]
+    }
+
+    /// Length of the probe & commit controls, which depends on the class release
+    pub fn probe_length(&self) -> u16 {
+        match self.video_class {
+            0..=0x0100 => 26,
+            0x0101..=0x0110 => 34,
+            _ => MAX_PROBE_LENGTH as u16,
+        }
+    }
+}
+
+/// Class-specific VideoControl interface descriptor
+///
+/// Terminals and units are not decoded.
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ControlDescriptor {
+    Header(VideoControlHeader),
+}
+
+/// Input header of a VideoStreaming interface, i.e. one that streams from the device to the host
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct InputHeader {
+    /// Number of format descriptors following this one
+    pub format_count: u8,
+    /// Address of the endpoint used for video data
+    pub endpoint_address: u8,
+    /// ID of the output terminal the endpoint is connected to
+    pub terminal_link: u8,
+    /// Still image capture method supported by the interface (0 if none)
+    pub still_capture_method: u8,
+}
+
+/// MJPEG format descriptor
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MjpegFormat {
+    /// Index of this format, as used in the probe & commit controls
+    pub format_index: u8,
+    /// Number of frame descriptors following this one
+    pub frame_count: u8,
+    pub default_frame_index: u8,
+}
+
+/// MJPEG frame descriptor, describing one of the frame sizes of the preceding [`MjpegFormat`]
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MjpegFrame {
+    /// Index of this frame, as used in the probe & commit controls
+    pub frame_index: u8,
+    pub resolution: Resolution,
+    /// Maximum size of a compressed frame in bytes (deprecated in UVC 1.5)
+    pub max_frame_size: u32,
+    /// Default frame interval, in units of 100 ns
+    pub default_frame_interval: u32,
+}
+
+/// Class-specific VideoStreaming interface descriptor
+///
+/// Only the descriptors of the MJPEG format are decoded.
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum StreamingDescriptor {
+    InputHeader(InputHeader),
+    MjpegFormat(MjpegFormat),
+    MjpegFrame(MjpegFrame),
+}
+
+/// Parse a class-specific descriptor ([`TYPE_CS_INTERFACE`]) of a VideoControl interface
+///
+/// The `data` starts with the descriptor subtype, i.e. it excludes the length & type. Returns `None` for
+/// descriptors that are truncated or not supported.
+pub fn parse_control_descriptor(data: &[u8]) -> Option<ControlDescriptor> {
+    match data {
+        [VC_HEADER, bcd0, bcd1, len0, len1, clk0, clk1, clk2, clk3, count, interfaces @ ..] => {
+            let interfaces = &interfaces[..(*count as usize).min(interfaces.len()).min(MAX_STREAMING_INTERFACES)];
+            let mut header = VideoControlHeader {
+                video_class: u16::from_le_bytes([*bcd0, *bcd1]),
+                total_length: u16::from_le_bytes([*len0, *len1]),
+                clock_frequency: u32::from_le_bytes([*clk0, *clk1, *clk2, *clk3]),
+                interfaces: [0; MAX_STREAMING_INTERFACES],
+                interface_count: interfaces.len() as u8,
+            };
+            header.interfaces[..interfaces.len()].copy_from_slice(interfaces);
+            Some(ControlDescriptor::Header(header))
+        }
+        _ => None,
+    }
+}
+
+/// Parse a class-specific descriptor ([`TYPE_CS_INTERFACE`]) of a VideoStreaming interface
+///
+/// The `data` starts with the descriptor subtype, i.e. it excludes the length & type. Returns `None` for
+/// descriptors that are truncated or not supported.
+pub fn parse_streaming_descriptor(data: &[u8]) -> Option<StreamingDescriptor> {
+    match data {
+        [VS_INPUT_HEADER, count, _len0, _len1, endpoint, _info, link, still, ..] => {
+            Some(StreamingDescriptor::InputHeader(InputHeader {
+                format_count: *count,
+                endpoint_address: *endpoint,
+                terminal_link: *link,
+                still_capture_method: *still,
+            }))
+        }
+        [VS_FORMAT_MJPEG, index, count, _flags, default_frame, ..] => Some(StreamingDescriptor::MjpegFormat(MjpegFormat {
+            format_index: *index,
+            frame_count: *count,
+            default_frame_index: *default_frame,
+        })),
+        [VS_FRAME_MJPEG, index, _capabilities, w0, w1, h0, h1, ..] if data.len() >= 23 => {
+            let field = |offset: usize| u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]);
+            Some(StreamingDescriptor::MjpegFrame(MjpegFrame {
+                frame_index: *index,
+                resolution: Resolution::new(u16::from_le_bytes([*w0, *w1]), u16::from_le_bytes([*h0, *h1])),
+                max_frame_size: field(15),
+                default_frame_interval: field(19),
+            }))
+        }
+        _ => None,
+    }
+}
+
+/// Header of a video payload
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PayloadHeader {
+    /// Frame ID, toggles with each new frame
+    pub frame_id: bool,
+    /// Set on the last payload of a frame
+    pub end_of_frame: bool,
+    /// Set if the device could not deliver the data correctly. The current frame should be dropped.
+    pub error: bool,
+}
+
+impl PayloadHeader {
+    /// Split a payload into its header and the video data
+    ///
+    /// Returns `None` if the header is missing or malformed.
+    pub fn parse(data: &[u8]) -> Option<(PayloadHeader, &[u8])> {
+        match data {
+            [length, info, ..] if *length >= 2 && *length as usize <= data.len() => Some((
+                PayloadHeader { frame_id: info & 0x01 != 0, end_of_frame: info & 0x02 != 0, error: info & 0x40 != 0 },
+                &data[*length as usize..],
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// Assembles frames from video payloads, in memory provided by the application
+///
+/// A frame ends with a payload that has the end-of-frame bit set, or when the frame ID toggles. Frames with errors, or
+/// that do not fit into the buffer are dropped. So is the frame during which streaming started, since its start was missed.
+///
+/// Once a frame is complete it is kept, and further frames are dropped, until it is [released](FrameBuffer::release).
+#[derive(Debug)]
+pub struct FrameBuffer<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+    /// Frame ID of the current frame, `None` before the first payload
+    frame_id: Option<bool>,
+    /// Set if the rest of the current frame is dropped
+    skip: bool,
+    ready: bool,
+}
+
+impl<'a> FrameBuffer<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        Self { buffer, len: 0, frame_id: None, skip: false, ready: false }
+    }
+
+    /// The complete frame, if any
+    pub fn frame(&self) -> Option<&[u8]> {
+        self.ready.then(|| &self.buffer[..self.len])
+    }
+
+    /// Discard the complete frame, and start receiving the next one
+    pub fn release(&mut self) {
+        if self.ready {
+            self.ready = false;
+            self.len = 0;
+            // the current frame started while the buffer was occupied
+            self.skip = true;
+        }
+    }
+
+    /// Discard all data, e.g. when streaming is (re-)started
+    pub fn clear(&mut self) {
+        *self = Self::new(core::mem::take(&mut self.buffer));
+    }
+
+    /// Process a payload that consists of a single packet (as is the case for isochronous endpoints)
+    ///
+    /// Returns `true` if a frame was completed.
+    pub fn push_payload(&mut self, data: &[u8]) -> bool {
+        let Some((header, data)) = PayloadHeader::parse(data) else {
+            return false;
+        };
+        let mut complete = self.begin(&header);
+        self.append(data);
+        complete |= self.end(&header);
+        complete
+    }
+
+    /// Start a payload with the given header. Returns `true` if this completed the previous frame.
+    fn begin(&mut self, header: &PayloadHeader) -> bool {
+        let mut complete = false;
+        if self.frame_id != Some(header.frame_id) {
+            // a new frame starts, even if the previous one was not terminated with the end-of-frame bit
+            if self.frame_id.is_some() {
+                complete = self.complete();
+            }
+            // when streaming starts, the first frame is usually incomplete
+            self.skip = self.frame_id.is_none();
+            self.frame_id = Some(header.frame_id);
+        }
+        if header.error {
+            self.skip = true;
+        }
+        complete
+    }
+
+    /// Add video data to the current frame
+    fn append(&mut self, data: &[u8]) {
+        if self.ready || self.skip || data.is_empty() {
+            return;
+        }
+        match self.buffer.get_mut(self.len..self.len + data.len()) {
+            Some(target) => {
+                target.copy_from_slice(data);
+                self.len += data.len();
+            }
+            // too large for the buffer
+            None => self.skip = true,
+        }
+    }
+
+    /// End a payload with the given header. Returns `true` if this completed the current frame.
+    fn end(&mut self, header: &PayloadHeader) -> bool {
+        header.end_of_frame && self.complete()
+    }
+
+    fn complete(&mut self) -> bool {
+        if self.ready {
+            // the frame was not stored
+            return false;
+        }
+        let complete = !self.skip && self.len > 0;
+        self.skip = false;
+        if complete {
+            self.ready = true;
+        } else {
+            self.len = 0;
+        }
+        complete
+    }
+}
+
+/// Events generated by the [`UvcDriver`]
+#[derive(Copy, Clone, Debug)]
+pub enum UvcEvent {
+    /// A device with a matching MJPEG frame size was detected & configured
+    DeviceAdded(DeviceAddress),
+
+    /// The device was removed
+    DeviceRemoved(DeviceAddress),
+
+    /// The stream parameters were committed, streaming has started
+    StreamStarted(DeviceAddress),
+
+    /// Negotiating the stream parameters, or selecting the alternate setting failed. The device is not used.
+    SetupFailed(DeviceAddress),
+
+    /// A frame was received, and can be read via [`UvcDriver::frame`]
+    FrameReady(DeviceAddress),
+}
+
+/// Alternate setting of a VideoStreaming interface with an isochronous endpoint
+#[derive(Copy, Clone, Default, Debug)]
+struct AlternateSetting {
+    alternate: u8,
+    endpoint: u8,
+    max_packet_size: u16,
+}
+
+/// VideoStreaming interface, as found in the descriptors
+#[derive(Copy, Clone, Debug)]
+struct StreamingInterface {
+    interface: u8,
+    /// Alternate setting currently being inspected
+    current_alternate: u8,
+    /// Length of the probe & commit controls
+    probe_length: u16,
+    /// Index of the first MJPEG format
+    format_index: Option<u8>,
+    /// Set while inspecting the frame descriptors that belong to `format_index`
+    in_format: bool,
+    /// Largest frame that fits the requested resolution
+    frame: Option<MjpegFrame>,
+    /// Endpoint number & max packet size of a bulk endpoint in the default setting
+    bulk_endpoint: Option<(u8, u16)>,
+    alternates: [AlternateSetting; MAX_ALTERNATE_SETTINGS],
+    alternate_count: u8,
+}
+
+impl StreamingInterface {
+    fn usable(&self) -> bool {
+        self.frame.is_some() && (self.bulk_endpoint.is_some() || self.alternate_count > 0)
+    }
+
+    /// The alternate setting with the smallest packets that still fit the given payload size
+    fn alternate_for(&self, payload_size: u32) -> Option<AlternateSetting> {
+        self.alternates[..self.alternate_count as usize]
+            .iter()
+            .filter(|setting| setting.max_packet_size as u32 >= payload_size)
+            .min_by_key(|setting| setting.max_packet_size)
+            .copied()
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Request {
+    SetProbe,
+    GetProbe,
+    SetCommit,
+    SetInterface,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Stream {
+    /// The given request is sent on the next call to `poll`
+    Send(Request),
+    /// Waiting for the given request to complete
+    Waiting(Request),
+    Streaming,
+    Failed,
+}
+
+/// Descriptors of a newly attached device, as far as they were inspected
+#[derive(Copy, Clone, Debug)]
+struct Pending {
+    /// Value of the configuration currently being inspected
+    config: Option<u8>,
+    /// Length of the probe & commit controls, from the VideoControl header
+    probe_length: u16,
+    /// Set while inspecting a VideoControl interface
+    in_control: bool,
+    /// VideoStreaming interface currently being inspected
+    streaming: Option<StreamingInterface>,
+    /// Configuration value and the first usable interface
+    chosen: Option<(u8, StreamingInterface)>,
+}
+
+impl Default for Pending {
+    fn default() -> Self {
+        Self { config: None, probe_length: 26, in_control: false, streaming: None, chosen: None }
+    }
+}
+
+/// The device was configured
+#[derive(Copy, Clone, Debug)]
+struct Configured {
+    control_pipe: PipeId,
+    streaming: StreamingInterface,
+    /// Data pipe & max packet size. For isochronous endpoints, the pipe is created once the alternate setting was chosen.
+    pipe: Option<(PipeId, u16)>,
+    alternate: Option<AlternateSetting>,
+    stream: Stream,
+}
+
+/// Driver for USB video class webcams
+///
+/// See [module-level documentation](crate::driver::uvc) for details.
+#[derive(Debug)]
+pub struct UvcDriver {
+    resolution: Resolution,
+    state: SingleDevice<Pending, Configured>,
+    frames: FrameBuffer<'static>,
+    /// Probe & commit control, as returned by the device
+    probe: [u8; MAX_PROBE_LENGTH],
+    /// For bulk endpoints: header of the payload currently being received, and the number of bytes remaining
+    payload: Option<(PayloadHeader, u32)>,
+    in_flight: bool,
+    /// Frame count at which the last isochronous packet was requested
+    last_frame: Option<u32>,
+    events: EventQueue<UvcEvent, EVENT_QUEUE_DEPTH>,
+}
+
+impl UvcDriver {
+    /// Create a driver that captures MJPEG frames of at most the given `resolution`, into the given buffer
+    ///
+    /// The buffer must be large enough to hold a compressed frame. Frames that do not fit are dropped.
+    pub fn new(resolution: Resolution, buffer: &'static mut [u8]) -> Self {
+        Self {
+            resolution,
+            state: SingleDevice::Idle,
+            frames: FrameBuffer::new(buffer),
+            probe: [0; MAX_PROBE_LENGTH],
+            payload: None,
+            in_flight: false,
+            last_frame: None,
+            events: EventQueue::new(),
+        }
+    }
+
+    /// Returns the oldest pending event (if any), and removes it from the queue.
+    ///
+    /// This method should be called repeatedly after calling `usb_host.poll(...)` and `camera.poll(...)`, until it returns `None` (see
+    /// [`HasEvents`] for what happens otherwise).
+    pub fn take_event(&mut self) -> Option<UvcEvent> {
+        self.events.pop()
+    }
+
+    /// Address of the device, if it is currently configured
+    pub fn device_address(&self) -> Option<DeviceAddress> {
+        self.state.address()
+    }
+
+    /// Returns `true` while video is being streamed
+    pub fn streaming(&self) -> bool {
+        matches!(self.state, SingleDevice::Configured(_, Configured { stream: Stream::Streaming, .. }))
+    }
+
+    /// Size of the frames, if a device is configured
+    pub fn resolution(&self) -> Option<Resolution> {
+        match self.state {
+            SingleDevice::Configured(_, Configured { streaming, .. }) => streaming.frame.map(|frame| frame.resolution),
+            _ => None,
+        }
+    }
+
+    /// The most recently completed frame (a JPEG image), if it was not released yet
+    pub fn frame(&self) -> Option<&[u8]> {
+        self.frames.frame()
+    }
+
+    /// Release the current frame, so that the next one can be received
+    pub fn release_frame(&mut self) {
+        self.frames.release();
+    }
+
+    /// Send the next stream negotiation request, or receive the next packet of video data
+    ///
+    /// Must be called after every call to `usb_host.poll(...)`. For isochronous endpoints, at most one packet is
+    /// received per frame. If the bus is currently busy, nothing happens, and the transfer is initiated on a later call.
+    pub fn poll<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    >(
+        &mut self,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) -> Result<(), ControlError> {
+        let SingleDevice::Configured(dev_addr, Configured { control_pipe, streaming, pipe, alternate, stream }) = self.state else {
+            return Ok(());
+        };
+        let result = match stream {
+            Stream::Send(request) => {
+                let result = self.send(host, dev_addr, control_pipe, &streaming, alternate, request);
+                if result.is_ok() {
+                    self.set_stream(Stream::Waiting(request));
+                }
+                result
+            }
+            Stream::Streaming => return self.receive(host, pipe, alternate.is_some()),
+            Stream::Waiting(_) | Stream::Failed => return Ok(()),
+        };
+        match result {
+            Ok(()) | Err(ControlError::WouldBlock) => Ok(()),
+            Err(e) => {
+                self.setup_failed(dev_addr);
+                Err(e)
+            }
+        }
+    }
+
+    /// Request the next packet of video data, if one is due
+    fn receive<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    >(
+        &mut self,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+        pipe: Option<(PipeId, u16)>,
+        isochronous: bool,
+    ) -> Result<(), ControlError> {
+        let Some((pipe, max_packet_size)) = pipe else {
+            return Ok(());
+        };
+        let now = host.frame_count();
+        if self.in_flight || (isochronous && self.last_frame == Some(now)) {
+            return Ok(());
+        }
+        let result = match isochronous {
+            true => host.isochronous_in(pipe, max_packet_size),
+            false => host.bulk_in(pipe, max_packet_size),
+        };
+        match result {
+            Ok(()) => {
+                self.in_flight = true;
+                self.last_frame = Some(now);
+                Ok(())
+            }
+            Err(ControlError::WouldBlock) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn send<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    >(
+        &mut self,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+        dev_addr: DeviceAddress,
+        control_pipe: PipeId,
+        streaming: &StreamingInterface,
+        alternate: Option<AlternateSetting>,
+        request: Request,
+    ) -> Result<(), ControlError> {
+        let length = streaming.probe_length;
+        let setup = |direction, request, control: u8| {
+            SetupPacket::new(
+                direction,
+                RequestType::Class,
+                Recipient::Interface,
+                request,
+                (control as u16) << 8,
+                streaming.interface as u16,
+                length,
+            )
+        };
+        match request {
+            Request::SetProbe => {
+                let Some(frame) = streaming.frame else {
+                    return Err(ControlError::InvalidPipe);
+                };
+                self.probe.fill(0);
+                self.probe[0..2].copy_from_slice(&HINT_FRAME_INTERVAL.to_le_bytes());
+                self.probe[2] = streaming.format_index.unwrap_or(1);
+                self.probe[3] = frame.frame_index;
+                self.probe[4..8].copy_from_slice(&frame.default_frame_interval.to_le_bytes());
+                let setup = setup(UsbDirection::Out, REQUEST_SET_CUR, VS_PROBE_CONTROL);
+                host.control_out(Some(dev_addr), Some(control_pipe), setup, &self.probe[..length as usize])
+            }
+            Request::GetProbe => {
+                host.control_in(Some(dev_addr), Some(control_pipe), setup(UsbDirection::In, REQUEST_GET_CUR, VS_PROBE_CONTROL))
+            }
+            Request::SetCommit => {
+                let setup = setup(UsbDirection::Out, REQUEST_SET_CUR, VS_COMMIT_CONTROL);
+                host.control_out(Some(dev_addr), Some(control_pipe), setup, &self.probe[..length as usize])
+            }
+            Request::SetInterface => {
+                let Some(alternate) = alternate else {
+                    return Err(ControlError::InvalidPipe);
+                };
+                // the pipe is created before selecting the setting, so that running out of pipes does not occupy bandwidth
+                if let SingleDevice::Configured(_, Configured { pipe: pipe @ None, .. }) = &mut self.state {
+                    let id = host
+                        .create_isochronous_pipe(dev_addr, alternate.endpoint, UsbDirection::In, alternate.max_packet_size)
+                        .ok_or(ControlError::InvalidPipe)?;
+                    *pipe = Some((id, alternate.max_packet_size));
+                }
+                host.set_interface(dev_addr, Some(control_pipe), streaming.interface, alternate.alternate)
+            }
+        }
+    }
+
+    /// Maximum size of a payload (`dwMaxPayloadTransferSize`), as negotiated during probing
+    fn max_payload_size(&self) -> u32 {
+        u32::from_le_bytes([self.probe[22], self.probe[23], self.probe[24], self.probe[25]])
+    }
+
+    fn set_stream(&mut self, new_stream: Stream) {
+        if let SingleDevice::Configured(_, Configured { stream, .. }) = &mut self.state {
+            *stream = new_stream;
+        }
+    }
+
+    fn start_streaming(&mut self, dev_addr: DeviceAddress) {
+        self.set_stream(Stream::Streaming);
+        self.frames.clear();
+        self.payload = None;
+        self.events.push(UvcEvent::StreamStarted(dev_addr));
+    }
+
+    fn setup_failed(&mut self, dev_addr: DeviceAddress) {
+        if let SingleDevice::Configured(_, Configured { stream: stream @ (Stream::Send(_) | Stream::Waiting(_)), .. }) = &mut self.state {
+            *stream = Stream::Failed;
+            self.events.push(UvcEvent::SetupFailed(dev_addr));
+        }
+    }
+
+    /// Process a packet received on a bulk endpoint, where each payload may span multiple packets
+    ///
+    /// Returns `true` if a frame was completed.
+    fn bulk_packet(&mut self, data: &[u8], max_packet_size: u16) -> bool {
+        let mut complete = false;
+        let (header, remaining) = match self.payload.take() {
+            Some((header, remaining)) => {
+                self.frames.append(data);
+                (header, remaining)
+            }
+            None => {
+                let Some((header, video)) = PayloadHeader::parse(data) else {
+                    return false;
+                };
+                complete |= self.frames.begin(&header);
+                self.frames.append(video);
+                let max_payload_size = self.max_payload_size();
+                (header, if max_payload_size == 0 { u32::MAX } else { max_payload_size })
+            }
+        };
+        let remaining = remaining.saturating_sub(data.len() as u32);
+        // a short packet ends the payload early
+        if remaining == 0 || data.len() < max_packet_size as usize {
+            complete |= self.frames.end(&header);
+        } else {
+            self.payload = Some((header, remaining));
+        }
+        complete
+    }
+
+    /// Mark the transfer in progress as done, whether it succeeded or not
+    fn packet_done(&mut self) {
+        self.in_flight = false;
+    }
+
+    fn reset(&mut self) {
+        self.state = SingleDevice::Idle;
+        self.frames.clear();
+        self.payload = None;
+        self.in_flight = false;
+        self.last_frame = None;
+    }
+}
+
+/// Remember the given interface as the chosen one, if it is usable and none was chosen yet
+fn choose(chosen: &mut Option<(u8, StreamingInterface)>, config: Option<u8>, streaming: Option<StreamingInterface>) {
+    if let (None, Some(config), Some(streaming)) = (*chosen, config, streaming) {
+        if streaming.usable() {
+            *chosen = Some((config, streaming));
+        }
+    }
+}
+
+impl HasEvents for UvcDriver {
+    type Event = UvcEvent;
+
+    fn take_event(&mut self) -> Option<UvcEvent> {
+        self.events.pop()
+    }
+
+    fn events_pending(&self) -> bool {
+        !self.events.is_empty()
+    }
+}
+
+impl<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    > Driver<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE> for UvcDriver
+{
+    fn attached(&mut self, dev_addr: DeviceAddress, _connection_speed: ConnectionSpeed) {
+        self.state.attached(dev_addr, Pending::default());
+    }
+
+    fn detached(&mut self, dev_addr: DeviceAddress) {
+        if self.state.detached(dev_addr) {
+            self.reset();
+            self.events.push(UvcEvent::DeviceRemoved(dev_addr));
+        }
+    }
+
+    fn descriptor(&mut self, dev_addr: DeviceAddress, descriptor_type: u8, data: &[u8]) {
+        let limit = self.resolution;
+        let Some(Pending { config, probe_length, in_control, streaming, chosen }) = self.state.pending(dev_addr) else {
+            return;
+        };
+        if chosen.is_some() {
+            return;
+        }
+        match descriptor_type {
+            descriptor::TYPE_CONFIGURATION => {
+                choose(chosen, *config, streaming.take());
+                *in_control = false;
+                if let Ok((_, configuration)) = descriptor::parse::configuration_descriptor(data) {
+                    *config = Some(configuration.value);
+                }
+            }
+            descriptor::TYPE_INTERFACE => {
+                let Ok((_, descriptor)) = descriptor::parse::interface_descriptor(data) else {
+                    return;
+                };
+                let is_video = |subclass| descriptor.interface_class == CLASS_VIDEO && descriptor.interface_sub_class == subclass;
+                *in_control = is_video(SUBCLASS_VIDEO_CONTROL);
+                // alternate settings of the current streaming interface follow its default setting
+                if let Some(current) = streaming.as_mut().filter(|s| s.interface == descriptor.interface_number) {
+                    current.current_alternate = descriptor.alternate_setting;
+                    current.in_format = false;
+                    return;
+                }
+                choose(chosen, *config, streaming.take());
+                if is_video(SUBCLASS_VIDEO_STREAMING) && !descriptor.is_alternate_setting() {
+                    *streaming = Some(StreamingInterface {
+                        interface: descriptor.interface_number,
+                        current_alternate: 0,
+                        probe_length: *probe_length,
+                        format_index: None,
+                        in_format: false,
+                        frame: None,
+                        bulk_endpoint: None,
+                        alternates: [AlternateSetting::default(); MAX_ALTERNATE_SETTINGS],
+                        alternate_count: 0,
+                    });
+                }
+            }
+            TYPE_CS_INTERFACE if *in_control => {
+                if let Some(ControlDescriptor::Header(header)) = parse_control_descriptor(data) {
+                    *probe_length = header.probe_length();
+                }
+            }
+            TYPE_CS_INTERFACE => {
+                let Some(streaming) = streaming else {
+                    return;
+                };
+                match parse_streaming_descriptor(data) {
+                    Some(StreamingDescriptor::MjpegFormat(format)) => {
+                        streaming.in_format = streaming.format_index.is_none_or(|index| index == format.format_index);
+                        streaming.format_index.get_or_insert(format.format_index);
+                    }
+                    Some(StreamingDescriptor::MjpegFrame(frame)) if streaming.in_format => {
+                        let better = streaming.frame.is_none_or(|f| frame.resolution.area() > f.resolution.area());
+                        if frame.resolution.fits(&limit) && better {
+                            streaming.frame = Some(frame);
+                        }
+                    }
+                    // descriptors of other formats
+                    None => streaming.in_format = false,
+                    _ => {}
+                }
+            }
+            descriptor::TYPE_ENDPOINT => {
+                let Some(streaming) = streaming else {
+                    return;
+                };
+                let Ok((_, endpoint)) = descriptor::parse::endpoint_descriptor(data) else {
+                    return;
+                };
+                if endpoint.address.direction() != UsbDirection::In {
+                    return;
+                }
+                match endpoint.attributes.transfer_type() {
+                    TransferType::Bulk if streaming.bulk_endpoint.is_none() && endpoint.max_packet_size > 0 => {
+                        streaming.bulk_endpoint = Some((endpoint.address.number(), endpoint.max_packet_size));
+                    }
+                    TransferType::Isochronous => {
+                        let index = streaming.alternate_count as usize;
+                        // the upper bits select additional transactions per microframe, which are not supported
+                        if streaming.current_alternate != 0
+                            && (1..=MAX_PACKET_SIZE).contains(&endpoint.max_packet_size)
+                            && index < MAX_ALTERNATE_SETTINGS
+                        {
+                            streaming.alternates[index] = AlternateSetting {
+                                alternate: streaming.current_alternate,
+                                endpoint: endpoint.address.number(),
+                                max_packet_size: endpoint.max_packet_size,
+                            };
+                            streaming.alternate_count += 1;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn configure(&mut self, dev_addr: DeviceAddress) -> Option<u8> {
+        self.state.configure(dev_addr, |pending| {
+            choose(&mut pending.chosen, pending.config, pending.streaming.take());
+            pending.chosen.map(|(value, _)| value)
+        })
+    }
+
+    fn configured(
+        &mut self,
+        dev_addr: DeviceAddress,
+        value: u8,
+        host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    ) {
+        let Some(Pending { chosen, .. }) = self.state.take_pending(dev_addr) else {
+            return;
+        };
+        let Some((_, streaming)) = chosen.filter(|(chosen_value, _)| *chosen_value == value) else {
+            return;
+        };
+        let Some(control_pipe) = host.create_control_pipe(dev_addr) else {
+            // the host ran out of pipes
+            return;
+        };
+        // isochronous endpoints are preferred, since they guarantee bandwidth
+        let pipe = match streaming.bulk_endpoint {
+            Some((ep_number, max_packet_size)) if streaming.alternate_count == 0 => {
+                let Some(pipe) = host.create_bulk_pipe(dev_addr, ep_number, UsbDirection::In, max_packet_size) else {
+                    host.release_pipe(control_pipe);
+                    return;
+                };
+                Some((pipe, max_packet_size))
+            }
+            _ => None,
+        };
+        self.events.push(UvcEvent::DeviceAdded(dev_addr));
+        self.state = SingleDevice::Configured(
+            dev_addr,
+            Configured { control_pipe, streaming, pipe, alternate: None, stream: Stream::Send(Request::SetProbe) },
+        );
+    }
+
+    fn completed_control(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, data: Option<&[u8]>) {
+        let Some(&Configured { control_pipe, streaming, stream: Stream::Waiting(request), .. }) = self.state.device(dev_addr) else {
+            return;
+        };
+        if pipe_id != control_pipe {
+            return;
+        }
+        match request {
+            Request::SetProbe => self.set_stream(Stream::Send(Request::GetProbe)),
+            Request::GetProbe => {
+                let data = data.unwrap_or(&[]);
+                let length = data.len().min(streaming.probe_length as usize);
+                self.probe[..length].copy_from_slice(&data[..length]);
+                if length < 26 {
+                    self.setup_failed(dev_addr);
+                    return;
+                }
+                if streaming.alternate_count > 0 {
+                    let alternate = streaming.alternate_for(self.max_payload_size());
+                    if alternate.is_none() {
+                        // not enough bandwidth for the chosen frame size
+                        self.setup_failed(dev_addr);
+                        return;
+                    }
+                    if let Some(configured) = self.state.device_mut(dev_addr) {
+                        configured.alternate = alternate;
+                    }
+                }
+                self.set_stream(Stream::Send(Request::SetCommit));
+            }
+            // for bulk endpoints, committing the parameters starts the stream
+            Request::SetCommit if streaming.alternate_count == 0 => self.start_streaming(dev_addr),
+            Request::SetCommit => self.set_stream(Stream::Send(Request::SetInterface)),
+            Request::SetInterface => self.start_streaming(dev_addr),
+        }
+    }
+
+    fn completed_in(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, data: &[u8]) {
+        let Some(&Configured { pipe: Some((pipe, max_packet_size)), alternate, .. }) = self.state.device(dev_addr) else {
+            return;
+        };
+        if pipe_id != pipe {
+            return;
+        }
+        self.packet_done();
+        let complete = match alternate {
+            Some(_) => self.frames.push_payload(data),
+            None => self.bulk_packet(data, max_packet_size),
+        };
+        if complete {
+            self.events.push(UvcEvent::FrameReady(dev_addr));
+        }
+    }
+
+    fn completed_out(&mut self, _dev_addr: DeviceAddress, _pipe_id: PipeId, _data: &mut [u8]) {
+        // ignored, since there are no interrupt OUT pipes in use.
+    }
+
+    fn stall(&mut self, dev_addr: DeviceAddress) {
+        if self.device_address() == Some(dev_addr) {
+            self.setup_failed(dev_addr);
+        }
+    }
+
+    fn transfer_failed(&mut self, dev_addr: DeviceAddress, pipe_id: PipeId, _error: TransferError) {
+        let Some(&Configured { control_pipe, pipe, .. }) = self.state.device(dev_addr) else {
+            return;
+        };
+        if pipe_id == control_pipe {
+            self.setup_failed(dev_addr);
+        } else if pipe.is_some_and(|(pipe, _)| pipe == pipe_id) {
+            // the rest of the payload is lost, so is the current frame
+            self.packet_done();
+            self.payload = None;
+            self.frames.skip = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::mock::fixtures::DEVICE_DESCRIPTOR;
+    use crate::bus::mock::{ControlResponse, MockDevice, MockHostBus, Response};
+
+    #[test]
+    fn test_parse_control_descriptor() {
+        let header = [0x01, 0x10, 0x01, 0x28, 0x00, 0x80, 0x8d, 0x5b, 0x00, 0x01, 0x01];
+        let Some(ControlDescriptor::Header(header)) = parse_control_descriptor(&header) else {
+            panic!("expected header");
+        };
+        assert_eq!(header.video_class, 0x0110);
+        assert_eq!(header.total_length, 40);
+        assert_eq!(header.clock_frequency, 6_000_000);
+        assert_eq!(header.streaming_interfaces(), &[1]);
+        assert_eq!(header.probe_length(), 34);
+        // truncated
+        assert!(parse_control_descriptor(&[0x01, 0x00, 0x01, 0x28]).is_none());
+    }
+
+    #[test]
+    fn test_parse_streaming_descriptor() {
+        let input_header = [0x01, 0x01, 0x47, 0x00, 0x81, 0x00, 0x02, 0x01, 0x00, 0x00, 0x01, 0x00];
+        assert!(matches!(
+            parse_streaming_descriptor(&input_header),
+            Some(StreamingDescriptor::InputHeader(InputHeader { format_count: 1, endpoint_address: 0x81, terminal_link: 2, still_capture_method: 1 }))
+        ));
+        let format = [0x06, 0x01, 0x02, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00];
+        assert!(matches!(
+            parse_streaming_descriptor(&format),
+            Some(StreamingDescriptor::MjpegFormat(MjpegFormat { format_index: 1, frame_count: 2, default_frame_index: 1 }))
+        ));
+
+        // 160x120, 9600 bytes, 30 fps
+        let frame = [
+            0x07, 0x02, 0x00, 0xa0, 0x00, 0x78, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x25, 0x00, 0x00,
+            0x15, 0x16, 0x05, 0x00, 0x01, 0x15, 0x16, 0x05, 0x00,
+        ];
+        let Some(StreamingDescriptor::MjpegFrame(frame_descriptor)) = parse_streaming_descriptor(&frame) else {
+            panic!("expected MJPEG frame");
+        };
+        assert_eq!(frame_descriptor.frame_index, 2);
+        assert!(frame_descriptor.resolution == Resolution::QQVGA);
+        assert_eq!(frame_descriptor.max_frame_size, 9600);
+        assert_eq!(frame_descriptor.default_frame_interval, 333333);
+        assert!(parse_streaming_descriptor(&frame[..22]).is_none());
+
+        // uncompressed format
+        assert!(parse_streaming_descriptor(&[0x04, 0x01, 0x01]).is_none());
+    }
+
+    #[test]
+    fn test_payload_header() {
+        let (header, data) = PayloadHeader::parse(&[0x02, 0x83, 0xff, 0xd8]).unwrap();
+        assert!(header == PayloadHeader { frame_id: true, end_of_frame: true, error: false });
+        assert_eq!(data, &[0xff, 0xd8]);
+        // with presentation time stamp
+        let (_, data) = PayloadHeader::parse(&[0x06, 0x84, 0, 0, 0, 0, 0xff]).unwrap();
+        assert_eq!(data, &[0xff]);
+        assert!(PayloadHeader::parse(&[0x0c, 0x80, 0xff]).is_none());
+        assert!(PayloadHeader::parse(&[]).is_none());
+    }
+
+    #[test]
+    fn test_frame_buffer() {
+        let mut buffer = [0; 4];
+        let mut frames = FrameBuffer::new(&mut buffer);
+        // joined in the middle of a frame
+        assert!(!frames.push_payload(&[0x02, 0x80, 0x01]));
+        assert!(!frames.push_payload(&[0x02, 0x82, 0x02]));
+        assert!(!frames.push_payload(&[0x02, 0x80, 0x03, 0x04]));
+        assert!(frames.push_payload(&[0x02, 0x82, 0x05]));
+        assert_eq!(frames.frame(), Some(&[0x03, 0x04, 0x05][..]));
+
+        // dropped, since the previous frame was not released
+        assert!(!frames.push_payload(&[0x02, 0x83, 0x06]));
+        assert_eq!(frames.frame(), Some(&[0x03, 0x04, 0x05][..]));
+        frames.release();
+        assert!(frames.frame().is_none());
+
+        // ended by the toggled frame ID, instead of the end-of-frame bit
+        assert!(!frames.push_payload(&[0x02, 0x80, 0x07]));
+        assert!(frames.push_payload(&[0x02, 0x81, 0x08]));
+        assert_eq!(frames.frame(), Some(&[0x07][..]));
+        frames.release();
+
+        // errors and frames that are too large are dropped
+        assert!(!frames.push_payload(&[0x02, 0xc0, 0x09]));
+        assert!(!frames.push_payload(&[0x02, 0x82, 0x0a]));
+        assert!(!frames.push_payload(&[0x02, 0x81, 0x01, 0x02, 0x03]));
+        assert!(!frames.push_payload(&[0x02, 0x83, 0x04, 0x05]));
+        assert!(frames.push_payload(&[0x02, 0x82, 0x0b]));
+        assert_eq!(frames.frame(), Some(&[0x0b][..]));
+    }
+
+    #[test]
+    fn test_uvc_camera() {
+        const CAMERA_DESCRIPTOR: &[u8] = &[
+            0x09, 0x02, 0x9d, 0x00, 0x02, 0x01, 0x00, 0x80, 0xfa, // configuration 1
+            0x09, 0x04, 0x00, 0x00, 0x00, 0x0e, 0x01, 0x00, 0x00, // interface 0: video control
+            0x0d, 0x24, 0x01, 0x00, 0x01, 0x0d, 0x00, 0x80, 0x8d, 0x5b, 0x00, 0x01, 0x01, // header: UVC 1.0
+            0x09, 0x04, 0x01, 0x00, 0x00, 0x0e, 0x02, 0x00, 0x00, // interface 1: video streaming, no endpoints
+            0x0e, 0x24, 0x01, 0x01, 0x00, 0x00, 0x81, 0x00, 0x02, 0x00, 0x00, 0x00, 0x01, 0x00, // input header
+            0x0b, 0x24, 0x06, 0x01, 0x02, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, // format 1: MJPEG
+            0x1e, 0x24, 0x07, 0x01, 0x00, 0x40, 0x01, 0xf0, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x25,
+            0x00, 0x00, 0x15, 0x16, 0x05, 0x00, 0x01, 0x15, 0x16, 0x05, 0x00, // frame 1: 320x240
+            0x1e, 0x24, 0x07, 0x02, 0x00, 0xa0, 0x00, 0x78, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x25,
+            0x00, 0x00, 0x15, 0x16, 0x05, 0x00, 0x01, 0x15, 0x16, 0x05, 0x00, // frame 2: 160x120
+            0x09, 0x04, 0x01, 0x01, 0x01, 0x0e, 0x02, 0x00, 0x00, // interface 1, alternate setting 1
+            0x07, 0x05, 0x81, 0x05, 0x80, 0x00, 0x01, // endpoint 1 IN, isochronous, 128 bytes
+            0x09, 0x04, 0x01, 0x02, 0x01, 0x0e, 0x02, 0x00, 0x00, // interface 1, alternate setting 2
+            0x07, 0x05, 0x81, 0x05, 0x00, 0x02, 0x01, // endpoint 1 IN, isochronous, 512 bytes
+        ];
+        // format 1, frame 2, 30 fps, max. payload size 200 bytes
+        const PROBE: &[u8] = &[
+            0x01, 0x00, 0x01, 0x02, 0x15, 0x16, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x25, 0x00, 0x00, 0xc8, 0x00, 0x00, 0x00,
+        ];
+        const SET_PROBE: ControlResponse = ControlResponse {
+            request_type: 0x21,
+            request: 0x01,
+            value: 0x0100,
+            index: 1,
+            response: Response::Data(&[]),
+        };
+        const GET_PROBE: ControlResponse = ControlResponse {
+            request_type: 0xa1,
+            request: 0x81,
+            value: 0x0100,
+            index: 1,
+            response: Response::Data(PROBE),
+        };
+        const SET_COMMIT: ControlResponse = ControlResponse {
+            request_type: 0x21,
+            request: 0x01,
+            value: 0x0200,
+            index: 1,
+            response: Response::Data(&[]),
+        };
+        const SET_INTERFACE: ControlResponse = ControlResponse {
+            request_type: 0x01,
+            request: 0x0b,
+            value: 2,
+            index: 1,
+            response: Response::Data(&[]),
+        };
+        static mut FRAME: [u8; 64] = [0; 64];
+
+        let device = MockDevice {
+            control_responses: &[SET_PROBE, GET_PROBE, SET_COMMIT, SET_INTERFACE],
+            ..MockDevice::new(DEVICE_DESCRIPTOR, &[CAMERA_DESCRIPTOR])
+        };
+        let mut host = UsbHost::new(MockHostBus::new(device));
+        let frame = unsafe { &mut *core::ptr::addr_of_mut!(FRAME) };
+        let mut camera = UvcDriver::new(Resolution::QQVGA, frame);
+        host.bus().attach();
+        // every packet is a complete frame
+        host.bus().set_bulk_in(&[0x02, 0x02, 0xff, 0xd8]);
+
+        let mut events = [false; 2];
+        let mut frames = 0;
+        for _ in 0..1000 {
+            host.poll(&mut [&mut camera]);
+            assert!(camera.poll(&mut host).is_ok());
+            match camera.take_event() {
+                Some(UvcEvent::DeviceAdded(_)) => events[0] = true,
+                Some(UvcEvent::StreamStarted(_)) => {
+                    events[1] = true;
+                    assert_eq!(host.bus().last_setup().map(|setup| (setup.request, setup.value)), Some((0x0b, 2)));
+                }
+                Some(UvcEvent::SetupFailed(_)) => panic!("setup failed"),
+                Some(UvcEvent::FrameReady(_)) => {
+                    assert_eq!(camera.frame(), Some(&[0xff, 0xd8][..]));
+                    camera.release_frame();
+                    frames += 1;
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(events, [true; 2]);
+        assert!(camera.streaming());
+        assert!(camera.resolution() == Some(Resolution::QQVGA));
+        assert!(frames > 100);
+    }
+}
@@ -0,0 +1,95 @@
+//! Source of random numbers, for values that should not repeat
+//!
+//! Some protocols benefit from values that differ between runs (e.g. the tags of mass storage commands), and retrying
+//! failed operations works better with some random jitter in the delay, so that multiple devices don't retry in lockstep.
+//!
+//! Neither needs cryptographic quality. By default the host uses [`Xorshift32`] with a fixed seed, which makes the sequence
+//! deterministic (useful for tests). Applications with a hardware random number generator can provide their own
+//! [`EntropySource`] via [`UsbHost::set_entropy_source`](crate::UsbHost::set_entropy_source).
+
+/// Produces random numbers
+pub trait EntropySource {
+    fn next_u32(&mut self) -> u32;
+}
+
+/// Simple pseudo random number generator (xorshift, 32 bit)
+///
+/// Not suitable for anything security related.
+#[derive(Copy, Clone, Debug)]
+pub struct Xorshift32(u32);
+
+impl Xorshift32 {
+    /// Seed used by the host, unless another entropy source is provided
+    pub const DEFAULT_SEED: u32 = 0x2545_f491;
+
+    /// Create a generator with the given seed. A seed of 0 is replaced by [`DEFAULT_SEED`](Xorshift32::DEFAULT_SEED).
+    pub const fn new(seed: u32) -> Self {
+        Xorshift32(if seed == 0 { Self::DEFAULT_SEED } else { seed })
+    }
+}
+
+impl Default for Xorshift32 {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_SEED)
+    }
+}
+
+impl EntropySource for Xorshift32 {
+    fn next_u32(&mut self) -> u32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        x
+    }
+}
+
+/// The entropy source used by the host
+pub(crate) enum Entropy {
+    Default(Xorshift32),
+    Custom(&'static mut (dyn EntropySource + Send)),
+}
+
+impl EntropySource for Entropy {
+    fn next_u32(&mut self) -> u32 {
+        match self {
+            Entropy::Default(source) => source.next_u32(),
+            Entropy::Custom(source) => source.next_u32(),
+        }
+    }
+}
+
+/// Add a random amount of up to `max_jitter` to the given `delay`
+///
+/// Meant for delays before retrying an operation.
+pub fn jittered(delay: u32, max_jitter: u32, source: &mut dyn EntropySource) -> u32 {
+    match max_jitter {
+        0 => delay,
+        _ => delay.saturating_add(source.next_u32() % (max_jitter + 1)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deterministic() {
+        let mut a = Xorshift32::default();
+        let mut b = Xorshift32::new(0);
+        let first = a.next_u32();
+        assert_eq!(first, b.next_u32());
+        assert_ne!(first, a.next_u32());
+    }
+
+    #[test]
+    fn test_jittered() {
+        let mut source = Xorshift32::default();
+        for _ in 0..100 {
+            let delay = jittered(10, 5, &mut source);
+            assert!((10..=15).contains(&delay));
+        }
+        assert_eq!(jittered(10, 0, &mut source), 10);
+    }
+}
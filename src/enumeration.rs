@@ -1,149 +1,350 @@
-use crate::bus::HostBus;
-use crate::descriptor;
+//! Enumeration phase: resetting a newly attached device and assigning an address to it
+//!
+//! The state machine does not perform any I/O itself. Instead each step returns an [`EnumerationAction`],
+//! which is carried out by the [`UsbHost`](crate::UsbHost).
+
 use crate::types::{ConnectionSpeed, DeviceAddress};
-use crate::{Event, UsbHost};
-use defmt::{trace, Format};
-use usb_device::control::Recipient;
+use crate::Event;
 
-#[derive(Copy, Clone, Format)]
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum EnumerationState {
     /// No device is attached yet
     WaitForDevice,
     /// Device was attached, bus was reset, waiting for the device to appear again
     Reset0,
     /// Device has appeared, wait for a little while
-    Delay0(u8),
-    /// Have sent initial GET_DESCRIPTOR to addr (0, 0), waiting for a reply
-    WaitDescriptor,
+    Delay0(ConnectionSpeed, u16),
+    /// Have sent initial GET_DESCRIPTOR to addr (0, 0), waiting for a reply. Holds the number of retries left.
+    WaitDescriptor(ConnectionSpeed, u8),
     /// Bus was reset for the second time, waiting for the device to appear again
     Reset1,
     /// Device has appeared again, wait for a little while until setting address
-    Delay1(ConnectionSpeed, u8),
-    /// Device has reappeared, SET_ADDRESS was sent, waiting for a reply
-    WaitSetAddress(ConnectionSpeed, DeviceAddress),
+    Delay1(ConnectionSpeed, u16),
+    /// Device has reappeared, SET_ADDRESS was sent, waiting for a reply. Holds the number of retries left.
+    WaitSetAddress(ConnectionSpeed, DeviceAddress, u8),
     /// Device now has an address assigned, enumeration is done.
     Assigned(ConnectionSpeed, DeviceAddress),
+    /// All device addresses are in use, the device cannot be enumerated.
+    NoAddress,
+}
+
+/// Side effect of an enumeration step
+#[derive(Copy, Clone, PartialEq)]
+pub enum EnumerationAction {
+    /// Reset the bus
+    ResetBus,
+    /// Enable SOF generation and SOF interrupts, to count down a delay
+    StartDelay,
+    /// Enable SOF generation (SOF interrupts are still enabled)
+    EnableSof,
+    /// Send the initial GET_DESCRIPTOR request for the first 8 bytes of the device descriptor to address 0
+    GetDeviceDescriptor,
+    /// Send SET_ADDRESS with the given address to address 0
+    SetAddress(DeviceAddress),
+    /// Disable SOF interrupts again
+    StopDelay,
+}
+
+/// Timing and retry parameters of the enumeration process
+///
+/// The defaults work for most devices. Some devices (notably certain flash drives and hubs) need more time to settle
+/// after a reset. Passed to [`UsbHost::new_with_config`](crate::UsbHost::new_with_config).
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct EnumerationConfig {
+    /// Number of frames to wait after the first bus reset, before requesting the device descriptor
+    pub reset_0_delay: u16,
+    /// Number of frames to wait after the second bus reset (or after a hub port was reset), before assigning an address
+    pub reset_1_delay: u16,
+    /// Number of times the initial GET_DESCRIPTOR and the SET_ADDRESS request are repeated, if the device stalls them
+    pub request_retries: u8,
+    /// Assign the address right after the initial GET_DESCRIPTOR, without resetting the bus a second time
+    ///
+    /// The second reset is not required by the specification, but some devices expect it.
+    pub skip_second_reset: bool,
+    /// Number of frames after which enumeration is aborted, see [`PollResult::Timeout`](crate::PollResult::Timeout)
+    pub timeout: u32,
+    /// Number of times the device on the root port is reset and enumerated again, if its discovery fails
+    ///
+    /// Each attempt is reported via [`PollResult::Retrying`](crate::PollResult::Retrying). Once no attempts are left, the
+    /// device is left dormant (see [`PollResult::DiscoveryError`](crate::PollResult::DiscoveryError)). Devices on hub
+    /// ports are not retried by the host, since only the hub (driver) can reset their port.
+    pub discovery_retries: u8,
+    /// Maximum number of frames added at random to `reset_0_delay` when discovery is retried
+    ///
+    /// The jitter comes from the host's entropy source (see [`UsbHost::set_entropy_source`](crate::UsbHost::set_entropy_source)),
+    /// so that devices which fail the same way don't retry in lockstep. Defaults to 20.
+    pub retry_jitter: u16,
+}
+
+impl EnumerationConfig {
+    /// Default parameters: 10 frames of delay after each reset, no retries, and a timeout of one second
+    pub const fn new() -> Self {
+        EnumerationConfig {
+            reset_0_delay: 10,
+            reset_1_delay: 10,
+            request_retries: 0,
+            skip_second_reset: false,
+            timeout: 1000,
+            discovery_retries: 0,
+            retry_jitter: 20,
+        }
+    }
+}
+
+impl Default for EnumerationConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Begin enumeration of a device attached to a hub port
+///
+/// The hub has already reset the port, so the process starts out waiting to set the address.
+pub fn start_hub_port_enumeration(speed: ConnectionSpeed, config: &EnumerationConfig) -> EnumerationState {
+    trace!("-> Delay1 (hub port)");
+    EnumerationState::Delay1(speed, config.reset_1_delay)
 }
 
-const RESET_0_DELAY: u8 = 10;
-const RESET_1_DELAY: u8 = 10;
+/// Speed of the device being enumerated, once it is known
+///
+/// For the root port, the speed is known after the second reset. For hub ports it is known from the start.
+pub fn enumeration_speed(state: EnumerationState) -> Option<ConnectionSpeed> {
+    match state {
+        EnumerationState::Delay1(speed, _)
+        | EnumerationState::WaitSetAddress(speed, _, _)
+        | EnumerationState::Assigned(speed, _) => Some(speed),
+        _ => None,
+    }
+}
 
-pub fn process_enumeration<B: HostBus>(
+/// Advance enumeration by one event
+///
+/// `transfer_in_progress` indicates whether the bus is busy with a transfer. This can only be the case while
+/// enumerating a device on a hub port, since drivers for other devices keep running in the meantime.
+///
+/// `next_address` is the address to assign to the device, in case the returned action is [`EnumerationAction::SetAddress`].
+/// If it is `None`, enumeration ends in [`EnumerationState::NoAddress`] instead.
+pub fn process_enumeration(
     event: Event,
     state: EnumerationState,
-    host: &mut UsbHost<B>,
-) -> EnumerationState {
+    transfer_in_progress: bool,
+    next_address: Option<DeviceAddress>,
+    config: &EnumerationConfig,
+) -> (EnumerationState, Option<EnumerationAction>) {
     match state {
         EnumerationState::WaitForDevice => {
             match event {
                 Event::Attached(_) => {
                     trace!("-> Reset0");
-                    host.bus.reset_bus();
-                    EnumerationState::Reset0
+                    (EnumerationState::Reset0, Some(EnumerationAction::ResetBus))
                 }
-                // TODO: handle timeouts
-                _ => state,
+                // timeouts are handled by the host, see `UsbHost::check_timeouts`
+                _ => (state, None),
             }
         }
 
         EnumerationState::Reset0 => match event {
-            Event::Attached(_) => {
-                host.bus.enable_sof();
+            Event::Attached(speed) => {
                 trace!("-> Delay0");
-                host.bus.interrupt_on_sof(true);
-                EnumerationState::Delay0(RESET_0_DELAY)
+                (EnumerationState::Delay0(speed, config.reset_0_delay), Some(EnumerationAction::StartDelay))
             }
-            _ => state,
+            _ => (state, None),
         },
 
-        EnumerationState::Delay0(n) => {
-            match event {
-                Event::Sof => {
-                    if n > 0 {
-                        EnumerationState::Delay0(n - 1)
-                    } else {
-                        // Unwrap safety: no transfers are in progress during enumeration
-                        host.get_descriptor(
-                            None,
-                            None,
-                            Recipient::Device,
-                            descriptor::TYPE_DEVICE,
-                            0,
-                            8,
-                        )
-                        .ok()
-                        .unwrap();
-                        trace!("-> WaitDescriptor");
-                        EnumerationState::WaitDescriptor
-                    }
+        EnumerationState::Delay0(speed, n) => match event {
+            Event::Sof => {
+                if n > 0 {
+                    (EnumerationState::Delay0(speed, n - 1), None)
+                } else {
+                    trace!("-> WaitDescriptor");
+                    (
+                        EnumerationState::WaitDescriptor(speed, config.request_retries),
+                        Some(EnumerationAction::GetDeviceDescriptor),
+                    )
                 }
-                Event::Detached => EnumerationState::WaitForDevice,
-                _ => state,
             }
-        }
+            Event::Detached => detached(),
+            _ => (state, None),
+        },
 
-        EnumerationState::WaitDescriptor => match event {
-            Event::Detached => {
-                trace!("-> WaitForDevice");
-                host.bus.interrupt_on_sof(false);
-                EnumerationState::WaitForDevice
+        EnumerationState::WaitDescriptor(speed, retries) => match event {
+            Event::Detached => detached(),
+            Event::ControlInData(None, _) if config.skip_second_reset => {
+                // SOF generation and interrupts are still enabled from the first delay
+                trace!("-> Delay1 (no second reset)");
+                (EnumerationState::Delay1(speed, config.reset_1_delay), None)
             }
-            Event::ControlInData(_, _) => {
+            Event::ControlInData(None, _) => {
                 trace!("-> Reset1");
-                host.bus.reset_bus();
-                EnumerationState::Reset1
+                (EnumerationState::Reset1, Some(EnumerationAction::ResetBus))
+            }
+            Event::Stall(None) if retries > 0 => {
+                trace!("-> WaitDescriptor (retry)");
+                (EnumerationState::WaitDescriptor(speed, retries - 1), Some(EnumerationAction::GetDeviceDescriptor))
             }
-            _ => state,
+            _ => (state, None),
         },
 
         EnumerationState::Reset1 => {
             match event {
                 Event::Attached(speed) => {
-                    host.bus.enable_sof();
                     trace!("-> Delay1");
-                    EnumerationState::Delay1(speed, RESET_1_DELAY)
+                    (EnumerationState::Delay1(speed, config.reset_1_delay), Some(EnumerationAction::EnableSof))
                 }
-                // TODO: handle timeouts
-                _ => state,
+                // timeouts are handled by the host, see `UsbHost::check_timeouts`
+                _ => (state, None),
             }
         }
 
-        EnumerationState::Delay1(speed, n) => {
-            match event {
-                Event::Sof => {
-                    if n > 0 {
-                        EnumerationState::Delay1(speed, n - 1)
-                    } else {
-                        let address = host.next_address();
-                        // Unwrap safety: no transfers are in progress, since this is the first transfer after a reset.
-                        host.set_address(address).ok().unwrap();
-                        trace!("-> WaitSetAddress({}, {})", speed, address);
-                        EnumerationState::WaitSetAddress(speed, address)
-                    }
-                }
-                Event::Detached => {
-                    trace!("-> WaitForDevice");
-                    host.bus.interrupt_on_sof(false);
-                    EnumerationState::WaitForDevice
+        EnumerationState::Delay1(speed, n) => match event {
+            Event::Sof => {
+                if n > 0 {
+                    (EnumerationState::Delay1(speed, n - 1), None)
+                } else if transfer_in_progress {
+                    // When enumerating a device on a hub port, drivers may start transfers in the meantime.
+                    // Try again on the next frame.
+                    (state, None)
+                } else if let Some(next_address) = next_address {
+                    trace!("-> WaitSetAddress({}, {:?})", speed, next_address);
+                    (
+                        EnumerationState::WaitSetAddress(speed, next_address, config.request_retries),
+                        Some(EnumerationAction::SetAddress(next_address)),
+                    )
+                } else {
+                    trace!("-> NoAddress");
+                    (EnumerationState::NoAddress, Some(EnumerationAction::StopDelay))
                 }
-                _ => state,
             }
-        }
+            Event::Detached => detached(),
+            _ => (state, None),
+        },
 
-        EnumerationState::WaitSetAddress(speed, address) => match event {
-            Event::Detached => {
-                trace!("-> WaitForDevice");
-                host.bus.interrupt_on_sof(false);
-                EnumerationState::WaitForDevice
+        EnumerationState::WaitSetAddress(speed, address, retries) => match event {
+            Event::Detached => detached(),
+            Event::ControlOutComplete(None) => {
+                trace!("-> Assigned({}, {:?})", speed, address);
+                (EnumerationState::Assigned(speed, address), Some(EnumerationAction::StopDelay))
             }
-            Event::ControlOutComplete(_) => {
-                trace!("-> Assigned({}, {})", speed, address);
-                host.bus.interrupt_on_sof(false);
-                EnumerationState::Assigned(speed, address)
+            Event::Stall(None) if retries > 0 => {
+                trace!("-> WaitSetAddress (retry)");
+                (EnumerationState::WaitSetAddress(speed, address, retries - 1), Some(EnumerationAction::SetAddress(address)))
             }
-            _ => state,
+            _ => (state, None),
         },
 
-        EnumerationState::Assigned(_speed, _address) => unreachable!(),
+        // final states, which the host does not pass in (see `UsbHost::process_enumeration`)
+        EnumerationState::Assigned(_, _) | EnumerationState::NoAddress => (state, None),
+    }
+}
+
+fn detached() -> (EnumerationState, Option<EnumerationAction>) {
+    trace!("-> WaitForDevice");
+    (EnumerationState::WaitForDevice, Some(EnumerationAction::StopDelay))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::num::NonZeroU8;
+
+    const CONFIG: EnumerationConfig = EnumerationConfig::new();
+
+    fn address(n: u8) -> DeviceAddress {
+        DeviceAddress(NonZeroU8::new(n).unwrap())
+    }
+
+    /// Feed the given number of SOF events, expecting no action
+    fn sofs(mut state: EnumerationState, count: u16, transfer_in_progress: bool) -> EnumerationState {
+        for _ in 0..count {
+            let (next, action) = process_enumeration(Event::Sof, state, transfer_in_progress, Some(address(1)), &CONFIG);
+            assert!(action.is_none());
+            state = next;
+        }
+        state
+    }
+
+    #[test]
+    fn test_enumeration_sequence() {
+        let speed = ConnectionSpeed::Full;
+        let (state, action) = process_enumeration(Event::Attached(speed), EnumerationState::WaitForDevice, false, Some(address(1)), &CONFIG);
+        assert!((state, action) == (EnumerationState::Reset0, Some(EnumerationAction::ResetBus)));
+
+        let (state, action) = process_enumeration(Event::Attached(speed), state, false, Some(address(1)), &CONFIG);
+        assert!(action == Some(EnumerationAction::StartDelay));
+
+        let state = sofs(state, CONFIG.reset_0_delay, false);
+        let (state, action) = process_enumeration(Event::Sof, state, false, Some(address(1)), &CONFIG);
+        assert!((state, action) == (EnumerationState::WaitDescriptor(speed, 0), Some(EnumerationAction::GetDeviceDescriptor)));
+
+        // completion of a transfer on a pipe is not the initial GET_DESCRIPTOR
+        let (state, action) = process_enumeration(Event::ControlOutComplete(None), state, false, Some(address(1)), &CONFIG);
+        assert!((state, action) == (EnumerationState::WaitDescriptor(speed, 0), None));
+
+        let (state, action) = process_enumeration(Event::ControlInData(None, 8), state, false, Some(address(1)), &CONFIG);
+        assert!((state, action) == (EnumerationState::Reset1, Some(EnumerationAction::ResetBus)));
+
+        let (state, action) = process_enumeration(Event::Attached(speed), state, false, Some(address(1)), &CONFIG);
+        assert!(action == Some(EnumerationAction::EnableSof));
+
+        let state = sofs(state, CONFIG.reset_1_delay, false);
+        let (state, action) = process_enumeration(Event::Sof, state, false, Some(address(3)), &CONFIG);
+        assert!((state, action) == (EnumerationState::WaitSetAddress(speed, address(3), 0), Some(EnumerationAction::SetAddress(address(3)))));
+
+        let (state, action) = process_enumeration(Event::ControlOutComplete(None), state, false, Some(address(4)), &CONFIG);
+        assert!((state, action) == (EnumerationState::Assigned(speed, address(3)), Some(EnumerationAction::StopDelay)));
+    }
+
+    #[test]
+    fn test_hub_port_waits_for_idle_bus() {
+        let speed = ConnectionSpeed::Low;
+        let state = sofs(start_hub_port_enumeration(speed, &CONFIG), CONFIG.reset_1_delay, false);
+        let state = sofs(state, 3, true);
+        assert!(state == EnumerationState::Delay1(speed, 0));
+        let (_, action) = process_enumeration(Event::Sof, state, false, Some(address(2)), &CONFIG);
+        assert!(action == Some(EnumerationAction::SetAddress(address(2))));
+    }
+
+    #[test]
+    fn test_detach_during_enumeration() {
+        for state in [
+            EnumerationState::Delay0(ConnectionSpeed::Full, 3),
+            EnumerationState::WaitDescriptor(ConnectionSpeed::Full, 0),
+            EnumerationState::Delay1(ConnectionSpeed::Full, 3),
+            EnumerationState::WaitSetAddress(ConnectionSpeed::Full, address(1), 0),
+        ] {
+            let (state, action) = process_enumeration(Event::Detached, state, false, Some(address(1)), &CONFIG);
+            assert!((state, action) == (EnumerationState::WaitForDevice, Some(EnumerationAction::StopDelay)));
+        }
+    }
+
+    #[test]
+    fn test_no_address_left() {
+        let state = sofs(start_hub_port_enumeration(ConnectionSpeed::Full, &CONFIG), CONFIG.reset_1_delay, false);
+        let (state, action) = process_enumeration(Event::Sof, state, false, None, &CONFIG);
+        assert!((state, action) == (EnumerationState::NoAddress, Some(EnumerationAction::StopDelay)));
+
+        // the final state is kept, whatever happens next
+        assert!(process_enumeration(Event::Sof, state, false, None, &CONFIG) == (EnumerationState::NoAddress, None));
+    }
+
+    #[test]
+    fn test_custom_config() {
+        let config = EnumerationConfig { reset_0_delay: 50, request_retries: 1, skip_second_reset: true, ..EnumerationConfig::new() };
+        let speed = ConnectionSpeed::Full;
+        let (state, _) = process_enumeration(Event::Attached(speed), EnumerationState::Reset0, false, None, &config);
+        assert!(state == EnumerationState::Delay0(speed, 50));
+
+        // a stalled request is repeated, until no retries are left
+        let state = EnumerationState::WaitDescriptor(speed, 1);
+        let (state, action) = process_enumeration(Event::Stall(None), state, false, None, &config);
+        assert!((state, action) == (EnumerationState::WaitDescriptor(speed, 0), Some(EnumerationAction::GetDeviceDescriptor)));
+        let (state, action) = process_enumeration(Event::Stall(None), state, false, None, &config);
+        assert!((state, action) == (EnumerationState::WaitDescriptor(speed, 0), None));
+
+        // the address is assigned without resetting the bus again
+        let (state, action) = process_enumeration(Event::ControlInData(None, 8), state, false, None, &config);
+        assert!((state, action) == (EnumerationState::Delay1(speed, config.reset_1_delay), None));
     }
 }
@@ -0,0 +1,65 @@
+//! Logging macros, which forward to `defmt` or `log` (depending on the enabled feature)
+//!
+//! With neither feature enabled, the macros discard their arguments. If both are enabled (e.g. because another crate in
+//! the dependency graph enables `log`, while `defmt` is a default feature), `log` takes over. Format strings must be understood by both
+//! backends: use `{}` for numbers and strings only, and `{:?}` for anything else (which requires the argument to
+//! implement both `defmt::Format` and `core::fmt::Debug`).
+
+macro_rules! trace {
+    ($s:literal $(, $x:expr)* $(,)?) => {{
+        #[cfg(all(feature = "defmt", not(feature = "log")))]
+        ::defmt::trace!($s $(, $x)*);
+        #[cfg(feature = "log")]
+        ::log::trace!($s $(, $x)*);
+        #[cfg(not(any(feature = "defmt", feature = "log")))]
+        let _ = ($(&$x,)*);
+    }};
+}
+
+macro_rules! debug {
+    ($s:literal $(, $x:expr)* $(,)?) => {{
+        #[cfg(all(feature = "defmt", not(feature = "log")))]
+        ::defmt::debug!($s $(, $x)*);
+        #[cfg(feature = "log")]
+        ::log::debug!($s $(, $x)*);
+        #[cfg(not(any(feature = "defmt", feature = "log")))]
+        let _ = ($(&$x,)*);
+    }};
+}
+
+// only used by some of the optional drivers
+#[allow(unused_macros)]
+macro_rules! info {
+    ($s:literal $(, $x:expr)* $(,)?) => {{
+        #[cfg(all(feature = "defmt", not(feature = "log")))]
+        ::defmt::info!($s $(, $x)*);
+        #[cfg(feature = "log")]
+        ::log::info!($s $(, $x)*);
+        #[cfg(not(any(feature = "defmt", feature = "log")))]
+        let _ = ($(&$x,)*);
+    }};
+}
+
+macro_rules! warn {
+    ($s:literal $(, $x:expr)* $(,)?) => {{
+        #[cfg(all(feature = "defmt", not(feature = "log")))]
+        ::defmt::warn!($s $(, $x)*);
+        #[cfg(feature = "log")]
+        ::log::warn!($s $(, $x)*);
+        #[cfg(not(any(feature = "defmt", feature = "log")))]
+        let _ = ($(&$x,)*);
+    }};
+}
+
+// only used by some of the optional drivers
+#[allow(unused_macros)]
+macro_rules! error {
+    ($s:literal $(, $x:expr)* $(,)?) => {{
+        #[cfg(all(feature = "defmt", not(feature = "log")))]
+        ::defmt::error!($s $(, $x)*);
+        #[cfg(feature = "log")]
+        ::log::error!($s $(, $x)*);
+        #[cfg(not(any(feature = "defmt", feature = "log")))]
+        let _ = ($(&$x,)*);
+    }};
+}
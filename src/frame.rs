@@ -0,0 +1,123 @@
+//! Frame counting
+//!
+//! The host keeps a running count of USB frames (1 ms each at full and low speed), which is the time base for
+//! anything that needs to know how much time has passed (e.g. how long a device has been connected).
+//!
+//! There are three sources for the count, in order of preference:
+//! - the frame number reported by the host bus ([`HostBus::frame_number`](crate::bus::HostBus::frame_number)). It is
+//!   only 11 bits wide, so the count is extended each time the host is polled. This requires `poll` to be called at least
+//!   every 2 seconds.
+//! - ticks from the application, via [`UsbHost::tick_1ms`](crate::UsbHost::tick_1ms), if the bus does not report frame
+//!   numbers.
+//! - start-of-frame events, if there are neither frame numbers nor ticks. These are only generated while SOF interrupts
+//!   are enabled, so in that case frames which pass while SOF interrupts are disabled are not counted.
+
+/// Frame numbers sent in SOF packets are 11 bits wide
+pub(crate) const FRAME_NUMBER_MASK: u16 = 0x7FF;
+
+#[derive(Copy, Clone)]
+pub(crate) struct FrameTimer {
+    frames: u32,
+    last_frame_number: Option<u16>,
+    /// Set once the application started to provide ticks
+    ticking: bool,
+    /// Frames counted from frame numbers or ticks, since the last call to `take_elapsed`
+    elapsed: u16,
+}
+
+impl FrameTimer {
+    pub(crate) const fn new() -> Self {
+        FrameTimer {
+            frames: 0,
+            last_frame_number: None,
+            ticking: false,
+            elapsed: 0,
+        }
+    }
+
+    /// Number of frames counted so far
+    pub(crate) fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    /// Advance the count, based on the frame number currently reported by the host bus
+    pub(crate) fn update(&mut self, frame_number: Option<u16>) {
+        if let Some(current) = frame_number {
+            let current = current & FRAME_NUMBER_MASK;
+            if let Some(last) = self.last_frame_number {
+                let elapsed = current.wrapping_sub(last) & FRAME_NUMBER_MASK;
+                self.frames = self.frames.wrapping_add(elapsed as u32);
+                self.elapsed = self.elapsed.saturating_add(elapsed);
+            }
+            self.last_frame_number = Some(current);
+        }
+    }
+
+    /// Count a tick of 1 ms
+    ///
+    /// Ignored if the host bus reports frame numbers, since those are counted already.
+    pub(crate) fn tick(&mut self) {
+        if self.last_frame_number.is_none() {
+            self.ticking = true;
+            self.frames = self.frames.wrapping_add(1);
+            self.elapsed = self.elapsed.saturating_add(1);
+        }
+    }
+
+    /// Count a start-of-frame event
+    ///
+    /// Ignored if the host bus reports frame numbers, or the application provides ticks, since those are counted already.
+    pub(crate) fn sof(&mut self) {
+        if self.last_frame_number.is_none() && !self.ticking {
+            self.frames = self.frames.wrapping_add(1);
+        }
+    }
+
+    /// Number of frames that were counted from frame numbers or ticks, since the previous call
+    pub(crate) fn take_elapsed(&mut self) -> u16 {
+        core::mem::take(&mut self.elapsed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_number_wraparound() {
+        let mut timer = FrameTimer::new();
+        timer.update(Some(2040));
+        assert_eq!(timer.frames(), 0);
+        timer.update(Some(2047));
+        assert_eq!(timer.frames(), 7);
+        timer.update(Some(5));
+        assert_eq!(timer.frames(), 13);
+        assert_eq!(timer.take_elapsed(), 13);
+        // SOF events and ticks are not counted twice
+        timer.sof();
+        timer.tick();
+        assert_eq!(timer.frames(), 13);
+        assert_eq!(timer.take_elapsed(), 0);
+    }
+
+    #[test]
+    fn test_sof_counting() {
+        let mut timer = FrameTimer::new();
+        timer.update(None);
+        timer.sof();
+        timer.sof();
+        assert_eq!(timer.frames(), 2);
+    }
+
+    #[test]
+    fn test_ticks() {
+        let mut timer = FrameTimer::new();
+        timer.sof();
+        timer.tick();
+        timer.tick();
+        assert_eq!(timer.take_elapsed(), 2);
+        // once ticks are provided, SOF events are no longer counted
+        timer.sof();
+        assert_eq!(timer.frames(), 3);
+    }
+}
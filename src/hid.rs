@@ -0,0 +1,746 @@
+//! HID report descriptors
+//!
+//! HID devices describe the layout of the reports they send and receive in a *report descriptor*. Boot keyboards and
+//! mice can be used without looking at it (their boot protocol reports have a fixed layout), but for anything else
+//! (non-boot keyboards, gamepads, multimedia keys, ...) the report descriptor must be parsed to make sense of the reports.
+//!
+//! The report descriptor is not part of the configuration descriptor, so it is not seen by drivers during discovery.
+//! Instead it must be requested from the device, once it is configured, using [`get_report_descriptor`].
+//! Its length is found in the HID descriptor (type [`TYPE_HID`]), which follows the interface descriptor and *is* seen
+//! during discovery. Parse it with [`descriptor::parse::hid_descriptor`](crate::descriptor::parse::hid_descriptor) (or use
+//! [`report_descriptor_length`] to extract only the length).
+//!
+//! The received descriptor can then be parsed with [`ReportParser::parse`], which produces a list of [`ReportField`]s.
+//! Afterwards [`ReportParser::input_values`] maps raw interrupt IN reports to typed [`FieldValue`]s.
+//!
+//! Example:
+//! ```ignore
+//! // in `completed_control`, after calling `get_report_descriptor`:
+//! let parser: ReportParser = ReportParser::parse(data)?;
+//!
+//! // in `completed_in`:
+//! for value in parser.input_values(data) {
+//!     if value.usage_page == USAGE_PAGE_GENERIC_DESKTOP && value.usage == 0x30 {
+//!         // X axis
+//!     }
+//! }
+//! ```
+//!
+//! Note: only short items are supported. Long items (which are reserved, and not used by any known device) are skipped.
+
+use crate::bus::HostBus;
+use crate::types::{DeviceAddress, SetupPacket};
+use crate::{ControlError, PipeId, UsbHost};
+use usb_device::{
+    control::{Recipient, Request, RequestType},
+    UsbDirection,
+};
+
+/// Descriptor type of the HID descriptor
+pub const TYPE_HID: u8 = 0x21;
+/// Descriptor type of the HID report descriptor
+pub const TYPE_REPORT: u8 = 0x22;
+
+/// Usage page for generic desktop controls (pointers, joysticks, gamepads, ...)
+pub const USAGE_PAGE_GENERIC_DESKTOP: u16 = 0x01;
+/// Usage page for keyboard keys
+pub const USAGE_PAGE_KEYBOARD: u16 = 0x07;
+/// Usage page for LEDs
+pub const USAGE_PAGE_LED: u16 = 0x08;
+/// Usage page for buttons
+pub const USAGE_PAGE_BUTTON: u16 = 0x09;
+/// Usage page for consumer controls (multimedia keys)
+pub const USAGE_PAGE_CONSUMER: u16 = 0x0C;
+/// Usage page for point-of-sale scales
+pub const USAGE_PAGE_SCALE: u16 = 0x8D;
+
+/// Maximum number of usages that can be declared for a single main item
+const MAX_LOCAL_USAGES: usize = 16;
+/// Maximum number of distinct (report ID, report type) combinations
+const MAX_REPORTS: usize = 8;
+
+/// Extract the length of the report descriptor from a HID descriptor
+///
+/// The `data` is the descriptor data, excluding the length & type bytes (i.e. [`Descriptor::data`](crate::descriptor::Descriptor::data)).
+pub fn report_descriptor_length(data: &[u8]) -> Option<u16> {
+    // bcdHID (2), bCountryCode (1), bNumDescriptors (1), followed by (bDescriptorType, wDescriptorLength) pairs
+    let num_descriptors = *data.get(3)? as usize;
+    data[4..]
+        .chunks_exact(3)
+        .take(num_descriptors)
+        .find(|chunk| chunk[0] == TYPE_REPORT)
+        .map(|chunk| u16::from_le_bytes([chunk[1], chunk[2]]))
+}
+
+/// Usage page of the first top-level collection of a report descriptor
+///
+/// The page identifies the kind of device, e.g. [`USAGE_PAGE_GENERIC_DESKTOP`] for mice, keyboards and gamepads.
+/// Only the beginning of the descriptor (up to the first collection) is looked at, so it may be cut off after that.
+/// Returns `None` if the descriptor ends before declaring a collection.
+pub fn top_level_usage_page(descriptor: &[u8]) -> Option<u16> {
+    let mut usage_page = 0;
+    let mut usage = None;
+    let mut rest = descriptor;
+    while let Some((&prefix, tail)) = rest.split_first() {
+        if prefix == 0xFE {
+            // long item: bDataSize, bLongItemTag, data
+            let size = *tail.first()? as usize;
+            rest = tail.get(2 + size..)?;
+            continue;
+        }
+        let size = match prefix & 0x03 {
+            3 => 4,
+            n => n as usize,
+        };
+        let data = tail.get(..size)?;
+        rest = &tail[size..];
+        let unsigned = data.iter().rev().fold(0u32, |acc, b| (acc << 8) | *b as u32);
+        match ((prefix >> 2) & 0x03, prefix >> 4) {
+            // Collection: its usage may name the page explicitly
+            (0, 0xA) => {
+                return Some(match usage {
+                    Some(usage) if usage > 0xFFFF => (usage >> 16) as u16,
+                    _ => usage_page,
+                })
+            }
+            // other main items end the scope of local items
+            (0, _) => usage = None,
+            (1, 0x0) => usage_page = unsigned as u16,
+            (2, 0x0) if usage.is_none() => usage = Some(extended_usage(unsigned, size)),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Request the report descriptor of the given HID interface
+///
+/// This is a convenience wrapper around [`UsbHost::control_in`]. The descriptor is passed to the
+/// [`completed_control`](crate::driver::Driver::completed_control) callback of the driver owning `pipe_id`.
+///
+/// Note: the amount of data that can be received in a single control transfer may be limited by the host bus.
+pub fn get_report_descriptor<
+    B: HostBus,
+    const MAX_PIPES: usize,
+    const CONTROL_QUEUE_DEPTH: usize,
+    const CONTROL_BUFFER_SIZE: usize,
+>(
+    host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    dev_addr: DeviceAddress,
+    pipe_id: PipeId,
+    interface: u8,
+    length: u16,
+) -> Result<(), ControlError> {
+    host.control_in(
+        Some(dev_addr),
+        Some(pipe_id),
+        SetupPacket::new(
+            UsbDirection::In,
+            RequestType::Standard,
+            Recipient::Interface,
+            Request::GET_DESCRIPTOR,
+            (TYPE_REPORT as u16) << 8,
+            interface as u16,
+            length,
+        ),
+    )
+}
+
+/// HID class request GET_PROTOCOL
+const REQUEST_GET_PROTOCOL: u8 = 0x03;
+/// HID class request SET_PROTOCOL
+const REQUEST_SET_PROTOCOL: u8 = 0x0b;
+
+/// Protocol of a HID interface that supports the boot protocol (subclass `0x01`)
+///
+/// In *boot* protocol, keyboards and mice send reports with a fixed layout, which can be used without parsing the
+/// report descriptor. In *report* protocol, the report descriptor describes the layout. Devices are required to start
+/// out in report protocol, but many use a layout that is compatible with the boot protocol anyway.
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Protocol {
+    Boot = 0,
+    Report = 1,
+}
+
+impl Protocol {
+    /// Interpret the value returned by GET_PROTOCOL
+    pub fn from_value(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Protocol::Boot),
+            1 => Some(Protocol::Report),
+            _ => None,
+        }
+    }
+}
+
+/// Switch the given HID interface to the given protocol
+///
+/// This is a convenience wrapper around [`UsbHost::control_out`]. Completion is reported to the
+/// [`completed_control`](crate::driver::Driver::completed_control) callback of the driver owning `pipe_id`.
+/// Only interfaces which support the boot protocol (subclass `0x01`) are required to support this request.
+pub fn set_protocol<
+    B: HostBus,
+    const MAX_PIPES: usize,
+    const CONTROL_QUEUE_DEPTH: usize,
+    const CONTROL_BUFFER_SIZE: usize,
+>(
+    host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    dev_addr: DeviceAddress,
+    pipe_id: PipeId,
+    interface: u8,
+    protocol: Protocol,
+) -> Result<(), ControlError> {
+    host.control_out(
+        Some(dev_addr),
+        Some(pipe_id),
+        SetupPacket::new(
+            UsbDirection::Out,
+            RequestType::Class,
+            Recipient::Interface,
+            REQUEST_SET_PROTOCOL,
+            protocol as u16,
+            interface as u16,
+            0,
+        ),
+        &[],
+    )
+}
+
+/// Request the protocol that the given HID interface is currently using
+///
+/// This is a convenience wrapper around [`UsbHost::control_in`]. The response (a single byte, see [`Protocol::from_value`])
+/// is passed to the [`completed_control`](crate::driver::Driver::completed_control) callback of the driver owning `pipe_id`.
+pub fn get_protocol<
+    B: HostBus,
+    const MAX_PIPES: usize,
+    const CONTROL_QUEUE_DEPTH: usize,
+    const CONTROL_BUFFER_SIZE: usize,
+>(
+    host: &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    dev_addr: DeviceAddress,
+    pipe_id: PipeId,
+    interface: u8,
+) -> Result<(), ControlError> {
+    host.control_in(
+        Some(dev_addr),
+        Some(pipe_id),
+        SetupPacket::new(
+            UsbDirection::In,
+            RequestType::Class,
+            Recipient::Interface,
+            REQUEST_GET_PROTOCOL,
+            0,
+            interface as u16,
+            1,
+        ),
+    )
+}
+
+/// Type of a report
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ReportType {
+    Input,
+    Output,
+    Feature,
+}
+
+/// A field within a report, as declared by an `Input`, `Output` or `Feature` item
+///
+/// A field consists of `count` elements, of `size` bits each.
+///
+/// For *variable* fields, each element represents one usage (e.g. an axis, or a button): element `i` has usage `usage_min + i`.
+///
+/// For *array* fields, each element contains an index into the usage range (e.g. the key code of a pressed key):
+/// an element value of `logical_min` corresponds to `usage_min`. Values outside of the logical range indicate that the element is unused.
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ReportField {
+    pub report_type: ReportType,
+    /// Report ID, or 0 if the device does not use report IDs
+    pub report_id: u8,
+    pub usage_page: u16,
+    pub usage_min: u16,
+    pub usage_max: u16,
+    pub logical_min: i32,
+    pub logical_max: i32,
+    /// Offset of the first element in the report, in bits (not counting the report ID)
+    pub bit_offset: u16,
+    /// Size of each element in bits
+    pub size: u8,
+    /// Number of elements
+    pub count: u8,
+    /// Raw flags of the main item (bit 1: variable, bit 2: relative, ...)
+    pub flags: u16,
+}
+
+impl ReportField {
+    const EMPTY: ReportField = ReportField {
+        report_type: ReportType::Input,
+        report_id: 0,
+        usage_page: 0,
+        usage_min: 0,
+        usage_max: 0,
+        logical_min: 0,
+        logical_max: 0,
+        bit_offset: 0,
+        size: 0,
+        count: 0,
+        flags: 0,
+    };
+
+    /// Does each element represent one usage (as opposed to an array of usage indices)?
+    pub fn is_variable(&self) -> bool {
+        self.flags & 0x02 != 0
+    }
+
+    /// Are values relative to the previous report (e.g. mouse movement)?
+    pub fn is_relative(&self) -> bool {
+        self.flags & 0x04 != 0
+    }
+
+    /// Extract the raw value of the element with the given index from the report (excluding the report ID)
+    ///
+    /// If the logical minimum is negative, the value is sign extended.
+    /// Returns `None` if the element is out of range, or the report is too short.
+    pub fn element(&self, report: &[u8], index: u8) -> Option<i32> {
+        if index >= self.count || self.size == 0 || self.size > 32 {
+            return None;
+        }
+        let raw = extract_bits(report, self.bit_offset as usize + index as usize * self.size as usize, self.size)?;
+        Some(if self.logical_min < 0 && self.size < 32 {
+            let shift = 32 - self.size as u32;
+            ((raw << shift) as i32) >> shift
+        } else {
+            raw as i32
+        })
+    }
+
+    /// Map the element with the given index to a usage & value
+    fn value(&self, report: &[u8], index: u8) -> Option<FieldValue> {
+        let raw = self.element(report, index)?;
+        if self.is_variable() {
+            Some(FieldValue {
+                usage_page: self.usage_page,
+                usage: self.usage_min.saturating_add(index as u16).min(self.usage_max),
+                value: raw,
+            })
+        } else if raw >= self.logical_min && raw <= self.logical_max {
+            let usage = self.usage_min as i32 + (raw - self.logical_min);
+            if usage == 0 || usage > self.usage_max as i32 {
+                // usage 0 is reserved ("no event"), which is what unused array elements report
+                return None;
+            }
+            Some(FieldValue {
+                usage_page: self.usage_page,
+                usage: usage as u16,
+                value: 1,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+/// A value extracted from a report
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FieldValue {
+    pub usage_page: u16,
+    pub usage: u16,
+    /// The element value. For array fields this is always `1` (indicating that the usage is active, e.g. the key is pressed).
+    pub value: i32,
+}
+
+/// Error parsing a report descriptor
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum HidParseError {
+    /// The descriptor ended in the middle of an item
+    Truncated,
+    /// The descriptor declares more fields than the parser can hold
+    TooManyFields,
+    /// The descriptor uses more distinct report IDs than the parser can track
+    TooManyReports,
+}
+
+/// Parses HID report descriptors, and maps reports to values
+///
+/// `MAX_FIELDS` limits the number of fields that can be declared by the report descriptor. Constant (padding) fields don't count.
+///
+/// See [module-level documentation](crate::hid) for details.
+#[derive(Debug)]
+pub struct ReportParser<const MAX_FIELDS: usize = 32> {
+    fields: [ReportField; MAX_FIELDS],
+    len: usize,
+    uses_report_ids: bool,
+}
+
+#[derive(Copy, Clone)]
+struct GlobalState {
+    usage_page: u16,
+    logical_min: i32,
+    logical_max: i32,
+    report_size: u8,
+    report_count: u8,
+    report_id: u8,
+}
+
+struct LocalState {
+    /// Usages (with usage page in the upper 16 bits, if it was given explicitly)
+    usages: [u32; MAX_LOCAL_USAGES],
+    num_usages: usize,
+    usage_min: Option<u32>,
+    usage_max: Option<u32>,
+}
+
+impl LocalState {
+    const fn new() -> Self {
+        LocalState {
+            usages: [0; MAX_LOCAL_USAGES],
+            num_usages: 0,
+            usage_min: None,
+            usage_max: None,
+        }
+    }
+}
+
+impl<const MAX_FIELDS: usize> ReportParser<MAX_FIELDS> {
+    /// Parse the given report descriptor
+    pub fn parse(descriptor: &[u8]) -> Result<Self, HidParseError> {
+        let mut parser = ReportParser {
+            fields: [ReportField::EMPTY; MAX_FIELDS],
+            len: 0,
+            uses_report_ids: false,
+        };
+        let mut global = GlobalState {
+            usage_page: 0,
+            logical_min: 0,
+            logical_max: 0,
+            report_size: 0,
+            report_count: 0,
+            report_id: 0,
+        };
+        let mut global_stack = [global; 4];
+        let mut stack_depth = 0;
+        let mut local = LocalState::new();
+        // bit offsets for each (report ID, report type)
+        let mut offsets: [(u8, u8, u16); MAX_REPORTS] = [(0, 0, 0); MAX_REPORTS];
+        let mut num_offsets = 0;
+
+        let mut rest = descriptor;
+        while let Some((&prefix, tail)) = rest.split_first() {
+            if prefix == 0xFE {
+                // long item: bDataSize, bLongItemTag, data
+                let size = *tail.first().ok_or(HidParseError::Truncated)? as usize;
+                rest = tail.get(2 + size..).ok_or(HidParseError::Truncated)?;
+                continue;
+            }
+            let size = match prefix & 0x03 {
+                3 => 4,
+                n => n as usize,
+            };
+            let data = tail.get(..size).ok_or(HidParseError::Truncated)?;
+            rest = &tail[size..];
+            let unsigned = data.iter().rev().fold(0u32, |acc, b| (acc << 8) | *b as u32);
+            let signed = match size {
+                1 => data[0] as i8 as i32,
+                2 => i16::from_le_bytes([data[0], data[1]]) as i32,
+                4 => unsigned as i32,
+                _ => 0,
+            };
+            let item_type = (prefix >> 2) & 0x03;
+            let tag = prefix >> 4;
+            match (item_type, tag) {
+                // Main items: Input, Output, Feature
+                (0, 0x8 | 0x9 | 0xB) => {
+                    let report_type = match tag {
+                        0x8 => ReportType::Input,
+                        0x9 => ReportType::Output,
+                        _ => ReportType::Feature,
+                    };
+                    let key = (global.report_id, tag);
+                    let slot = match offsets[..num_offsets].iter().position(|(id, t, _)| (*id, *t) == key) {
+                        Some(index) => index,
+                        None if num_offsets < MAX_REPORTS => {
+                            offsets[num_offsets] = (key.0, key.1, 0);
+                            num_offsets += 1;
+                            num_offsets - 1
+                        }
+                        None => return Err(HidParseError::TooManyReports),
+                    };
+                    let bit_offset = offsets[slot].2;
+                    offsets[slot].2 += global.report_size as u16 * global.report_count as u16;
+
+                    let constant = unsigned & 0x01 != 0;
+                    if !constant {
+                        parser.add_fields(report_type, unsigned as u16, bit_offset, &global, &local)?;
+                    }
+                    local = LocalState::new();
+                }
+                // Main items: Collection, End Collection
+                (0, 0xA | 0xC) => local = LocalState::new(),
+                // Global items
+                (1, 0x0) => global.usage_page = unsigned as u16,
+                (1, 0x1) => global.logical_min = signed,
+                (1, 0x2) => {
+                    global.logical_max = if global.logical_min >= 0 { unsigned as i32 } else { signed };
+                }
+                (1, 0x7) => global.report_size = unsigned as u8,
+                (1, 0x8) => {
+                    global.report_id = unsigned as u8;
+                    parser.uses_report_ids = true;
+                }
+                (1, 0x9) => global.report_count = unsigned as u8,
+                // Push
+                (1, 0xA) if stack_depth < global_stack.len() => {
+                    global_stack[stack_depth] = global;
+                    stack_depth += 1;
+                }
+                // Pop
+                (1, 0xB) if stack_depth > 0 => {
+                    stack_depth -= 1;
+                    global = global_stack[stack_depth];
+                }
+                // Local items
+                (2, 0x0) if local.num_usages < MAX_LOCAL_USAGES => {
+                    local.usages[local.num_usages] = extended_usage(unsigned, size);
+                    local.num_usages += 1;
+                }
+                (2, 0x1) => local.usage_min = Some(extended_usage(unsigned, size)),
+                (2, 0x2) => local.usage_max = Some(extended_usage(unsigned, size)),
+                _ => {}
+            }
+        }
+        Ok(parser)
+    }
+
+    fn add_fields(
+        &mut self,
+        report_type: ReportType,
+        flags: u16,
+        bit_offset: u16,
+        global: &GlobalState,
+        local: &LocalState,
+    ) -> Result<(), HidParseError> {
+        let template = ReportField {
+            report_type,
+            report_id: global.report_id,
+            usage_page: global.usage_page,
+            usage_min: 0,
+            usage_max: 0,
+            logical_min: global.logical_min,
+            logical_max: global.logical_max,
+            bit_offset,
+            size: global.report_size,
+            count: global.report_count,
+            flags,
+        };
+        let variable = flags & 0x02 != 0;
+        if let (Some(min), Some(max)) = (local.usage_min, local.usage_max) {
+            self.push(with_usages(template, min, max))
+        } else if variable && local.num_usages > 1 {
+            // one field per element, so that each element can have its own usage. If there are more elements than usages,
+            // the last usage applies to the remaining ones.
+            for index in 0..global.report_count {
+                let usage = local.usages[(index as usize).min(local.num_usages - 1)];
+                let field = ReportField {
+                    bit_offset: bit_offset + index as u16 * global.report_size as u16,
+                    count: 1,
+                    ..with_usages(template, usage, usage)
+                };
+                self.push(field)?;
+            }
+            Ok(())
+        } else {
+            let usage = if local.num_usages > 0 { local.usages[0] } else { 0 };
+            self.push(with_usages(template, usage, usage))
+        }
+    }
+
+    fn push(&mut self, field: ReportField) -> Result<(), HidParseError> {
+        if self.len < MAX_FIELDS {
+            self.fields[self.len] = field;
+            self.len += 1;
+            Ok(())
+        } else {
+            Err(HidParseError::TooManyFields)
+        }
+    }
+
+    /// All (non-constant) fields declared by the report descriptor
+    pub fn fields(&self) -> &[ReportField] {
+        &self.fields[..self.len]
+    }
+
+    /// Whether the device prefixes its reports with a report ID
+    pub fn uses_report_ids(&self) -> bool {
+        self.uses_report_ids
+    }
+
+    /// Map an input report, as received on the interrupt IN endpoint, to values
+    ///
+    /// If the device uses report IDs, the first byte of the report must be the report ID.
+    pub fn input_values<'a>(&'a self, report: &'a [u8]) -> impl Iterator<Item = FieldValue> + 'a {
+        let (report_id, data) = match (self.uses_report_ids, report.split_first()) {
+            (true, Some((id, data))) => (*id, data),
+            _ => (0, report),
+        };
+        self.fields()
+            .iter()
+            .filter(move |field| field.report_type == ReportType::Input && field.report_id == report_id)
+            .flat_map(move |field| (0..field.count).filter_map(move |index| field.value(data, index)))
+    }
+}
+
+/// Fill in the usage range of a field, taking into account an explicit usage page given with the usage
+fn with_usages(field: ReportField, min: u32, max: u32) -> ReportField {
+    ReportField {
+        usage_page: if min > 0xFFFF { (min >> 16) as u16 } else { field.usage_page },
+        usage_min: min as u16,
+        usage_max: max as u16,
+        ..field
+    }
+}
+
+/// Usages given with 4 bytes of data include the usage page in the upper 16 bits
+fn extended_usage(value: u32, size: usize) -> u32 {
+    if size == 4 {
+        value
+    } else {
+        value & 0xFFFF
+    }
+}
+
+/// Extract `size` bits starting at bit `offset` (little endian)
+fn extract_bits(data: &[u8], offset: usize, size: u8) -> Option<u32> {
+    let last_byte = (offset + size as usize - 1) / 8;
+    if last_byte >= data.len() {
+        return None;
+    }
+    let mut value: u64 = 0;
+    for (i, byte) in data[offset / 8..=last_byte].iter().enumerate() {
+        value |= (*byte as u64) << (8 * i);
+    }
+    value >>= offset % 8;
+    Some((value & ((1u64 << size) - 1)) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Report descriptor of a standard boot mouse, with a wheel
+    const MOUSE: &[u8] = &[
+        0x05, 0x01, // Usage Page (Generic Desktop)
+        0x09, 0x02, // Usage (Mouse)
+        0xA1, 0x01, // Collection (Application)
+        0x09, 0x01, //   Usage (Pointer)
+        0xA1, 0x00, //   Collection (Physical)
+        0x05, 0x09, //     Usage Page (Button)
+        0x19, 0x01, //     Usage Minimum (1)
+        0x29, 0x03, //     Usage Maximum (3)
+        0x15, 0x00, //     Logical Minimum (0)
+        0x25, 0x01, //     Logical Maximum (1)
+        0x95, 0x03, //     Report Count (3)
+        0x75, 0x01, //     Report Size (1)
+        0x81, 0x02, //     Input (Data, Variable, Absolute)
+        0x95, 0x01, //     Report Count (1)
+        0x75, 0x05, //     Report Size (5)
+        0x81, 0x01, //     Input (Constant)
+        0x05, 0x01, //     Usage Page (Generic Desktop)
+        0x09, 0x30, //     Usage (X)
+        0x09, 0x31, //     Usage (Y)
+        0x09, 0x38, //     Usage (Wheel)
+        0x15, 0x81, //     Logical Minimum (-127)
+        0x25, 0x7F, //     Logical Maximum (127)
+        0x75, 0x08, //     Report Size (8)
+        0x95, 0x03, //     Report Count (3)
+        0x81, 0x06, //     Input (Data, Variable, Relative)
+        0xC0, //         End Collection
+        0xC0, //       End Collection
+    ];
+
+    #[test]
+    fn test_parse_mouse() {
+        let parser: ReportParser = ReportParser::parse(MOUSE).ok().unwrap();
+        assert!(!parser.uses_report_ids());
+        let fields = parser.fields();
+        assert_eq!(fields.len(), 4);
+        assert_eq!(fields[0].usage_page, USAGE_PAGE_BUTTON);
+        assert_eq!((fields[0].usage_min, fields[0].usage_max), (1, 3));
+        assert_eq!(fields[1].usage_min, 0x30);
+        assert_eq!(fields[1].bit_offset, 8);
+        assert_eq!(fields[3].usage_min, 0x38);
+        assert_eq!(fields[3].bit_offset, 24);
+        assert!(fields[3].is_relative());
+
+        let mut values = parser.input_values(&[0b011, 0x05, 0xFE, 0x00]);
+        assert!(values.next() == Some(FieldValue { usage_page: USAGE_PAGE_BUTTON, usage: 1, value: 1 }));
+        assert!(values.next() == Some(FieldValue { usage_page: USAGE_PAGE_BUTTON, usage: 2, value: 1 }));
+        assert!(values.next() == Some(FieldValue { usage_page: USAGE_PAGE_BUTTON, usage: 3, value: 0 }));
+        assert!(values.next() == Some(FieldValue { usage_page: USAGE_PAGE_GENERIC_DESKTOP, usage: 0x30, value: 5 }));
+        assert!(values.next() == Some(FieldValue { usage_page: USAGE_PAGE_GENERIC_DESKTOP, usage: 0x31, value: -2 }));
+        assert!(values.next() == Some(FieldValue { usage_page: USAGE_PAGE_GENERIC_DESKTOP, usage: 0x38, value: 0 }));
+        assert!(values.next().is_none());
+    }
+
+    #[test]
+    fn test_array_with_report_id() {
+        let descriptor = &[
+            0x05, 0x0C, // Usage Page (Consumer)
+            0x09, 0x01, // Usage (Consumer Control)
+            0xA1, 0x01, // Collection (Application)
+            0x85, 0x02, //   Report ID (2)
+            0x19, 0x00, //   Usage Minimum (0)
+            0x2A, 0x3C, 0x02, //   Usage Maximum (0x23C)
+            0x15, 0x00, //   Logical Minimum (0)
+            0x26, 0x3C, 0x02, //   Logical Maximum (0x23C)
+            0x95, 0x01, //   Report Count (1)
+            0x75, 0x10, //   Report Size (16)
+            0x81, 0x00, //   Input (Data, Array)
+            0xC0, //       End Collection
+        ];
+        let parser: ReportParser<4> = ReportParser::parse(descriptor).ok().unwrap();
+        assert!(parser.uses_report_ids());
+
+        // Volume Up (0xE9)
+        let mut values = parser.input_values(&[0x02, 0xE9, 0x00]);
+        assert!(values.next() == Some(FieldValue { usage_page: USAGE_PAGE_CONSUMER, usage: 0xE9, value: 1 }));
+        assert!(values.next().is_none());
+
+        // nothing pressed
+        assert!(parser.input_values(&[0x02, 0x00, 0x00]).next().is_none());
+        // different report ID
+        assert!(parser.input_values(&[0x01, 0xE9, 0x00]).next().is_none());
+    }
+
+    #[test]
+    fn test_report_descriptor_length() {
+        assert_eq!(report_descriptor_length(&[0x11, 0x01, 0x00, 0x01, 0x22, 0x3F, 0x00]), Some(63));
+        assert_eq!(report_descriptor_length(&[0x11, 0x01, 0x00]), None);
+    }
+
+    #[test]
+    fn test_top_level_usage_page() {
+        assert_eq!(top_level_usage_page(MOUSE), Some(USAGE_PAGE_GENERIC_DESKTOP));
+        // Usage (Scale Device), with the page given explicitly, cut off after the collection
+        assert_eq!(top_level_usage_page(&[0x0B, 0x20, 0x00, 0x8D, 0x00, 0xA1, 0x01, 0x85]), Some(USAGE_PAGE_SCALE));
+        assert_eq!(top_level_usage_page(&[0x05, 0x8D, 0x09, 0x20]), None);
+        assert_eq!(top_level_usage_page(&[0x05]), None);
+    }
+
+    #[test]
+    fn test_truncated() {
+        assert!(ReportParser::<4>::parse(&[0x05]).err() == Some(HidParseError::Truncated));
+    }
+
+    #[test]
+    fn test_protocol_value() {
+        assert!(Protocol::from_value(0) == Some(Protocol::Boot));
+        assert!(Protocol::from_value(1) == Some(Protocol::Report));
+        assert!(Protocol::from_value(2).is_none());
+    }
+}
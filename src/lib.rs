@@ -19,6 +19,12 @@
 //!
 //! If you are planning to implement support for a new hardware, please [open an issue](https://github.com/nilclass/usbh/issues/new?title=Hardware%20support%20for:%20...), so we can figure out together if any additions or changes to the HostBus interface are necessary to accomodate it.
 //!
+//! ## Logging
+//!
+//! By default, the crate logs via [`defmt`](https://docs.rs/defmt), and implements `defmt::Format` for its public types.
+//! To use the [`log`](https://docs.rs/log) crate instead, disable the default features and enable the `log` feature.
+//! With neither of the two features enabled, nothing is logged.
+//!
 //! ## Usage
 //!
 //! The code block below shows a brief example, leaving out any hardware and driver specifics.
@@ -41,7 +47,7 @@
 //!     let mut driver1 = create_first_driver();
 //!     let mut driver2 = create_second_driver();
 //!
-//!     // (leaving out details on how to share `usb_host` and `driver*` with the interrupt routine)
+//!     // (leaving out details on how to share `usb_host` and `driver*` with the interrupt routine, see the `shared` module)
 //! }
 //!
 //! #[...]
@@ -58,7 +64,7 @@
 //!         }
 //!         Event::Busy => {
 //!             // Host is currently busy with a transfer.
-//!             // Trying to start a transfer now will return `WouldBlock`.
+//!             // Control transfers started now are queued, other transfers return `WouldBlock`.
 //!         }
 //!         Event::Idle => {
 //!             // Host is not currently handling any transfer.
@@ -77,56 +83,375 @@
 //! ```
 
 #![no_std]
+#![warn(missing_debug_implementations)]
 
 use embed_doc_image::embed_doc_image;
 
+// must come first, so that the logging macros are available in all other modules
+#[macro_use]
+mod fmt;
+
 pub mod bus;
 pub mod driver;
 pub mod types;
+pub mod memory;
 
+mod address;
+mod blob;
 mod discovery;
 mod enumeration;
-mod enumerator; // alternative.
+mod frame;
+mod queue;
 mod transfer;
 
+pub mod asynch;
+pub mod charging;
 pub mod descriptor;
+pub mod diagnostics;
+pub mod entropy;
+pub mod hid;
+pub mod power;
+pub mod report;
+#[cfg(feature = "critical-section")]
+pub mod shared;
+#[cfg(feature = "trace")]
+pub mod trace;
 
 use bus::HostBus;
-use core::num::NonZeroU8;
-use defmt::Format;
 use discovery::DiscoveryState;
-use enumeration::EnumerationState;
-use types::{DeviceAddress, SetupPacket, TransferType};
+use enumeration::{EnumerationAction, EnumerationState};
+
+pub use enumeration::EnumerationConfig;
+use types::{DeviceAddress, InterfaceSet, PollingInterval, PowerPolicy, SetupPacket, TransferType, ZlpPolicy};
 use usb_device::{
     control::{Recipient, Request, RequestType},
     UsbDirection,
 };
 
-/// Maximum number of pipes that the host supports.
-const MAX_PIPES: usize = 32;
+/// Number of pipes that a [`UsbHost`] supports, unless another number is given as its `MAX_PIPES` parameter
+pub const DEFAULT_MAX_PIPES: usize = 32;
+
+/// Maximum number of additional bus channels used for bulk transfers (see [`bus::TransferChannels`])
+const MAX_CHANNELS: usize = 4;
+
+/// Maximum number of devices that can be attached at the same time (directly, or through hubs).
+const MAX_DEVICES: usize = 16;
+
+/// Number of frames after which a control transfer is aborted
+///
+/// The USB specification allows devices up to 5 seconds to complete a standard request.
+const CONTROL_TIMEOUT_FRAMES: u32 = 5000;
+
+/// Number of devices remembered for tamper detection (see [`UsbHost::set_tamper_detection`])
+const MAX_KNOWN_DEVICES: usize = 8;
+
+/// Maximum number of bus events processed within a single call to `poll`
+const MAX_POLL_EVENTS: usize = 8;
+
+/// Largest packet of interrupt pipes that the host runs itself, i.e. the largest packet allowed at full speed
+const MAX_HOST_INTERRUPT_SIZE: usize = 64;
+
+/// Number of control transfers that a [`UsbHost`] can queue while another transfer is in progress, unless another number
+/// is given as its `CONTROL_QUEUE_DEPTH` parameter
+pub const DEFAULT_CONTROL_QUEUE_DEPTH: usize = 4;
+
+/// Size of the buffer which holds the data of control IN transfers (see [`UsbHost::received_control_data`]), unless
+/// another size is given as the `CONTROL_BUFFER_SIZE` parameter of a [`UsbHost`]
+///
+/// See [Control buffer](UsbHost#control-buffer) for what happens to larger transfers.
+pub const DEFAULT_CONTROL_BUFFER_SIZE: usize = 512;
+
+/// A [`UsbHost`] with the default pipe table, control queue and control buffer
+///
+/// This is the same type as `UsbHost<B>`, spelled out for places where the parameters would otherwise have to be
+/// repeated, e.g. in drivers or applications which only support the defaults.
+pub type DefaultUsbHost<B> =
+    UsbHost<B, DEFAULT_MAX_PIPES, DEFAULT_CONTROL_QUEUE_DEPTH, DEFAULT_CONTROL_BUFFER_SIZE>;
+
+/// Maximum packet size of endpoint zero, until the device descriptor was read
+const DEFAULT_MAX_PACKET_SIZE_0: u8 = 8;
+
+/// Number of frames the host drives resume signalling for (TDRSMDN, at least 20 ms)
+const RESUME_SIGNALLING_FRAMES: u8 = 20;
+
+/// Number of frames to wait after resume signalling, before the device must respond to requests (TRSMRCY, 10 ms)
+const RESUME_RECOVERY_FRAMES: u8 = 10;
+
+/// Progress of the resume sequence, after a device signalled remote wakeup
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum ResumeState {
+    /// The host is driving resume signalling, for the given number of frames
+    Signalling(u8),
+    /// SOF generation was restarted, the device is given the given number of frames to recover
+    Recovery(u8),
+}
 
 /// State of the host stack
 ///
-/// Currently the host can only handle a single port, with a single device.
-/// When that changes, this state will need to be split, to be per-host / per-port / per-device, as needed.
+/// Devices are set up one at a time. This state describes which phase the device that is currently
+/// being set up is in. Once it is either configured or dormant, the host returns to the `Idle` state.
 #[derive(Copy, Clone)]
 enum State {
-    /// Enumeration phase: starts in WaitForDevice state, ends with an address being assigned
+    /// Enumeration phase (root port): starts in WaitForDevice state, ends with an address being assigned
     Enumeration(EnumerationState),
+    /// Enumeration phase for a device attached to a hub port: starts after the port was reset, ends with an address being assigned
+    HubEnumeration(HubPort, EnumerationState),
     /// Discovery phase: starts with an assigned address, ends with a configuration being chosen
     Discovery(DeviceAddress, DiscoveryState),
+    /// Verification phase (optional): the application checks the device, before it is configured
+    ///
+    /// Holds the chosen configuration (if any), and the length of the last completed control transfer (`Some` for IN transfers).
+    Verifying(DeviceAddress, Option<u8>, Option<u16>),
     /// Configuration phase: put the device into the chosen configuration
-    Configuring(DeviceAddress, u8),
-    /// The device is configured. Communication is forwarded to drivers.
-    Configured(DeviceAddress, u8),
-    /// No driver is interested, or the device misbehaved during one of the previous phases
-    Dormant(DeviceAddress),
+    ///
+    /// The flag is set once SET_CONFIGURATION completed, while the configuration is read back with GET_CONFIGURATION.
+    Configuring(DeviceAddress, u8, bool),
+    /// No device is currently being set up. Communication with configured devices is forwarded to drivers.
+    Idle,
+}
+
+/// Identifies a downstream port of a hub
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct HubPort {
+    /// Address of the hub
+    pub hub_addr: DeviceAddress,
+    /// Port number (starting at 1)
+    pub port: u8,
+}
+
+/// A device known to the host
+#[derive(Copy, Clone)]
+struct Device {
+    address: DeviceAddress,
+    /// Hub port the device is attached to, or `None` if it is attached to the root port
+    hub_port: Option<HubPort>,
+    speed: types::ConnectionSpeed,
+    /// Set when the device was removed, until drivers have been notified
+    detached: Option<DetachReason>,
+    /// Information from the device descriptor, known once it was read
+    info: Option<DeviceInfo>,
+    /// Hash over the descriptors seen during discovery
+    hasher: descriptor::DescriptorHasher,
+    /// Set once discovery finished, i.e. when `hasher` covers the full descriptor set
+    discovered: bool,
+    /// Frame count at the time the device was assigned an address
+    connected_at: u32,
+    /// Maximum packet size of endpoint zero, once it was learned from the device descriptor (8 until then)
+    max_packet_size_0: u8,
+    /// Hash of the serial number string descriptor, if it was read during discovery
+    serial_hash: Option<u32>,
+    /// Set if the descriptors differ from those seen when the device was last connected
+    descriptors_changed: bool,
+    /// Number of transfers that failed in a row (not counting STALLs)
+    errors: u8,
+    /// Interfaces claimed by drivers, once the device is configured
+    claimed: InterfaceSet,
+    /// Configuration selected by the host, once the device confirmed it (or stalled GET_CONFIGURATION)
+    configuration: Option<u8>,
+    /// Power requirements of the configurations, seen during discovery
+    power: power::DevicePower,
+}
+
+/// A device that was connected before, remembered for tamper detection
+#[derive(Copy, Clone)]
+struct KnownDevice {
+    vendor_id: u16,
+    product_id: u16,
+    serial_hash: Option<u32>,
+    descriptor_hash: u32,
+}
+
+impl Device {
+    /// Find the (attached) device with given address
+    ///
+    /// This takes the device table, rather than the host, so that it can be used while other parts of the host are borrowed.
+    fn find_mut(devices: &mut [Option<Device>], dev_addr: DeviceAddress) -> Option<&mut Device> {
+        devices.iter_mut().flatten().find(|d| d.address == dev_addr && d.detached.is_none())
+    }
+}
+
+/// Summary of a device known to the host
+///
+/// Returned from [`UsbHost::device_summary`].
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DeviceSummary {
+    pub address: DeviceAddress,
+    /// Hub port the device is attached to, or `None` if it is attached to the root port
+    pub hub_port: Option<HubPort>,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// Hash over the device descriptor and all configuration descriptors (including interface, endpoint and class
+    /// specific descriptors), as read during discovery
+    ///
+    /// The hash is stable across reconnects, so applications can use it to detect whether the firmware (or at least
+    /// the descriptors) of a device changed since it was last seen. See [`descriptor::DescriptorHasher`].
+    pub descriptor_hash: u32,
+    /// Number of frames (i.e. milliseconds) since the device was assigned an address
+    ///
+    /// See [`UsbHost::frame_count`] for limitations.
+    pub connected_frames: u32,
+    /// Set if the `descriptor_hash` differs from the one seen when the same device (by vendor ID, product ID and serial number)
+    /// was connected before. Only detected if enabled, see [`UsbHost::set_tamper_detection`].
+    pub descriptors_changed: bool,
+}
+
+/// Phase of the setup process a device is in, see [`DeviceEntry`]
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DevicePhase {
+    /// The descriptors of the device are being read
+    Discovery,
+    /// Discovery has finished, waiting for the application to verify the device (see [`PollResult::VerifyDevice`])
+    Verification,
+    /// The chosen configuration is being set
+    Configuring,
+    /// The device is configured, and in use by drivers
+    Configured,
+    /// The device is not used, until it is removed. Either no driver chose a configuration, or setting it up failed.
+    Dormant,
+}
+
+/// Entry of the host's device table
+///
+/// Returned from [`UsbHost::device_list`].
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DeviceEntry {
+    pub address: DeviceAddress,
+    /// Hub port the device is attached to, or `None` if it is attached to the root port
+    pub hub_port: Option<HubPort>,
+    pub speed: types::ConnectionSpeed,
+    pub phase: DevicePhase,
+    /// Configuration selected by the host, if the device is configured
+    pub configuration: Option<u8>,
+}
+
+/// Information from the device descriptor of a device
+///
+/// Returned from [`UsbHost::device_info`]. See [`descriptor::DeviceDescriptor`] for a description of the fields.
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DeviceInfo {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub usb_release: types::Bcd16,
+    pub device_class: u8,
+    pub device_sub_class: u8,
+    pub device_protocol: u8,
+    /// Maximum packet size for endpoint zero
+    pub max_packet_size_0: u8,
+    pub device_release: types::Bcd16,
+    pub num_configurations: u8,
+}
+
+impl From<&descriptor::DeviceDescriptor> for DeviceInfo {
+    fn from(descriptor: &descriptor::DeviceDescriptor) -> Self {
+        DeviceInfo {
+            vendor_id: descriptor.id_vendor,
+            product_id: descriptor.id_product,
+            usb_release: descriptor.usb_release,
+            device_class: descriptor.device_class,
+            device_sub_class: descriptor.device_sub_class,
+            device_protocol: descriptor.device_protocol,
+            max_packet_size_0: descriptor.max_packet_size,
+            device_release: descriptor.device_release,
+            num_configurations: descriptor.num_configurations,
+        }
+    }
+}
+
+/// Reason why a device was removed
+///
+/// Passed to [`driver::Driver::detached_with_reason`].
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DetachReason {
+    /// The device was unplugged from the root port, or from the port of a hub
+    Unplugged,
+    /// The hub the device was attached to was removed
+    ParentRemoved,
+    /// The hub disabled the port of the device, due to an overcurrent condition
+    ///
+    /// Reported by the hub driver (or application code), see [`UsbHost::hub_port_removed`].
+    Overcurrent,
+    /// The device is removed on purpose, in order to enumerate it again (e.g. after it stopped responding)
+    ///
+    /// Reported by the hub driver (or application code), see [`UsbHost::hub_port_removed`].
+    Recovery,
+    /// Too many transfers to the device failed in a row, so the host gave up on it (see [`UsbHost::set_error_threshold`])
+    Errors,
+}
+
+/// Reason why a transfer on a pipe failed
+///
+/// Passed to [`driver::Driver::transfer_failed`].
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TransferError {
+    /// The endpoint responded with a STALL handshake
+    ///
+    /// `cleared` indicates whether the host has cleared the halt condition already (see [`UsbHost::set_auto_clear_halt`]).
+    /// Control endpoints recover from a STALL with the next SETUP packet, so for control pipes the transfer can be retried either way.
+    Stall { cleared: bool },
+
+    /// The transfer was aborted due to an error reported by the host bus
+    BusError(bus::Error),
+
+    /// The transfer did not complete in time (see [`PollResult::Timeout`])
+    Timeout,
+
+    /// The transfer was aborted by the application (see [`UsbHost::cancel_transfer`])
+    Cancelled,
+}
+
+impl core::fmt::Display for TransferError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            TransferError::Stall { cleared: true } => f.write_str("endpoint stalled (halt cleared)"),
+            TransferError::Stall { cleared: false } => f.write_str("endpoint stalled"),
+            TransferError::BusError(error) => write!(f, "{error}"),
+            TransferError::Timeout => f.write_str("transfer timed out"),
+            TransferError::Cancelled => f.write_str("transfer cancelled"),
+        }
+    }
+}
+
+/// Periodic control request, used by the host to check that a device is still responding
+///
+/// Registered for a control pipe with [`UsbHost::set_liveness_ping`].
+#[derive(Copy, Clone, Debug)]
+pub struct LivenessPing {
+    /// The request to send
+    ///
+    /// It should not have any side effects. Suitable requests are a standard `GET_STATUS`, or `GET_STATUS` for a port of a hub.
+    /// For OUT requests, no data stage is sent, so the `length` must be 0.
+    pub setup: SetupPacket,
+    /// Number of frames (i.e. milliseconds) between two pings
+    pub interval: u16,
+    /// Number of consecutive failed pings, after which the device is considered unresponsive
+    pub max_failures: u8,
+}
+
+/// A registered liveness ping, with its schedule
+#[derive(Copy, Clone)]
+struct Ping {
+    /// Pipe the ping is sent on (including its context)
+    pipe_id: PipeId,
+    ping: LivenessPing,
+    /// Frame count at which the ping is sent next
+    due: u32,
+    /// Number of consecutive failures so far
+    failures: u8,
 }
 
 /// Error initiating a control transfer
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum ControlError {
-    /// Indicates that the bus is currently busy with another transfer.
+    /// Indicates that the bus is currently busy with another transfer, and the transfer could not be queued.
     ///
     /// The transfer can be tried again once the host's `poll` method returned [`PollResult::Idle`].
     WouldBlock,
@@ -138,28 +463,131 @@ pub enum ControlError {
     InvalidPipe,
 }
 
+impl core::fmt::Display for ControlError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            ControlError::WouldBlock => "bus is busy",
+            ControlError::InvalidPipe => "invalid pipe",
+        })
+    }
+}
+
+/// Internal error of the host stack, reported via [`PollResult::HostError`]
+///
+/// These indicate a misbehaving host bus implementation (or a bug in the host stack). The host recovers from them on
+/// its own: the event that caused the error is ignored, and a device that was being set up is left dormant.
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum HostError {
+    /// The host bus reported a completed transaction, while no transfer was in progress.
+    UnexpectedCompletion,
+
+    /// A bulk transfer completed, which was not associated with a pipe.
+    MissingPipe,
+
+    /// A transfer that is part of enumeration, discovery or configuration could not be started.
+    ///
+    /// The device address is given if one was assigned already. The device is left dormant, until it is removed.
+    TransferNotStarted(Option<DeviceAddress>),
+
+    /// A device was assigned the given address, but the host has no room left to keep track of it.
+    ///
+    /// The device is ignored until it is removed.
+    TooManyDevices(DeviceAddress),
+
+    /// Enumeration or discovery received an event after it had already finished. The event was ignored.
+    InvalidState,
+}
+
+impl core::fmt::Display for HostError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            HostError::UnexpectedCompletion => f.write_str("unexpected transfer completion"),
+            HostError::MissingPipe => f.write_str("bulk transfer without pipe"),
+            HostError::TransferNotStarted(Some(dev_addr)) => write!(f, "transfer for device {dev_addr} could not be started"),
+            HostError::TransferNotStarted(None) => f.write_str("transfer could not be started"),
+            HostError::TooManyDevices(dev_addr) => write!(f, "no room for device {dev_addr}"),
+            HostError::InvalidState => f.write_str("event after enumeration or discovery finished"),
+        }
+    }
+}
+
+/// Error returned from [`UsbHost::enumerate_hub_port`]
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum EnumerateError {
+    /// Another device is currently being set up, or a transfer is in progress.
+    ///
+    /// Enumeration can be tried again after the next call to `poll`.
+    WouldBlock,
+
+    /// The given hub address does not belong to a device that is currently attached.
+    UnknownHub,
+
+    /// The maximum number of devices that the host can handle has been reached.
+    TooManyDevices,
+}
+
+impl core::fmt::Display for EnumerateError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            EnumerateError::WouldBlock => "another device is being set up",
+            EnumerateError::UnknownHub => "unknown hub",
+            EnumerateError::TooManyDevices => "too many devices",
+        })
+    }
+}
+
+/// Error returned from [`UsbHost::reset_device`]
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ResetError {
+    /// Another device is currently being set up, or the bus is busy (or suspended).
+    ///
+    /// The reset can be tried again once `poll` returned [`PollResult::Idle`].
+    WouldBlock,
+
+    /// The given address does not belong to a device that is currently attached.
+    UnknownDevice,
+}
+
+impl core::fmt::Display for ResetError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            ResetError::WouldBlock => "bus is busy",
+            ResetError::UnknownDevice => "unknown device",
+        })
+    }
+}
+
 /// Internal event type, used by `poll` and the enumeration process
-#[derive(Copy, Clone, Format)]
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Event {
     None,
     Attached(types::ConnectionSpeed),
     Detached,
     ControlInData(Option<PipeId>, u16),
     ControlOutComplete(Option<PipeId>),
-    Stall,
-    Resume,
+    /// Data of a bulk IN transfer, received on the given channel (or the regular transfer path)
+    BulkInData(PipeId, Option<u8>, u16),
+    BulkOutComplete(PipeId),
+    Stall(Option<PipeId>),
+    RemoteWakeup,
     InterruptPipe(u8),
     BusError(bus::Error),
     Sof,
 }
 
 /// Result returned from `UsbHost::poll`.
+#[derive(Debug)]
 #[non_exhaustive]
 pub enum PollResult {
     /// There is no device attached. It does not make sense to do anything else with the UsbHost instance, until a device was attached.
     NoDevice,
 
-    /// Bus is currently busy talking to a device. Calling any transfer methods on the host will result in [`ControlError::WouldBlock`].
+    /// Bus is currently busy talking to a device. Control transfers on pipes are queued (see [`UsbHost::control_in`]), other
+    /// transfer methods on the host will result in [`ControlError::WouldBlock`].
+    ///
+    /// Bulk transfers running on additional channels of the bus do not make the host busy, see [`UsbHost::pipe_busy`].
     Busy,
 
     /// A device is attached and the bus is available. The caller can use the UsbHost instance to start a transfer.
@@ -170,8 +598,99 @@ pub enum PollResult {
 
     /// An error happened during discovery.
     ///
-    /// After this result the host is put in "dormant" state until the device is removed.
+    /// After this result the device is put in "dormant" state until it is removed.
     DiscoveryError(DeviceAddress),
+
+    /// Discovery of the device on the root port failed, so it is reset and enumerated again
+    ///
+    /// The second value is the number of the attempt (starting at 1). Only returned if retries are enabled, see
+    /// [`EnumerationConfig::discovery_retries`]. Drivers were informed that the device was removed, with
+    /// [`DetachReason::Recovery`]. Once enumerated again, the device is likely to get a different address.
+    Retrying(DeviceAddress, u8),
+
+    /// Discovery of the device has finished, but none of the drivers chose a configuration for it.
+    ///
+    /// The device is left dormant until it is removed. The application can use [`UsbHost::device_info`] to tell the user
+    /// which device it is (e.g. by vendor and product ID). Not returned if device verification is enabled, since the
+    /// application decides about the device anyway (see [`PollResult::VerifyDevice`]).
+    UnsupportedDevice(DeviceAddress),
+
+    /// Discovery of the device has finished, and the application needs to verify it before it can be configured.
+    ///
+    /// Only returned if verification is enabled, see [`UsbHost::set_device_verification`].
+    VerifyDevice(DeviceAddress),
+
+    /// A control transfer initiated by the application during verification has completed.
+    ///
+    /// For IN transfers, the received data can be accessed via [`UsbHost::verification_data`].
+    VerificationTransferComplete(DeviceAddress),
+
+    /// The descriptors of the device differ from those seen when it was last connected, which can indicate that its
+    /// firmware was replaced in the meantime.
+    ///
+    /// Only returned if tamper detection is enabled, see [`UsbHost::set_tamper_detection`]. The device is set up as usual.
+    /// If device verification is enabled as well, [`PollResult::VerifyDevice`] is returned instead, and the change is
+    /// reported via [`DeviceSummary::descriptors_changed`].
+    DeviceChanged(DeviceAddress),
+
+    /// The device stopped responding, and the current operation was aborted
+    ///
+    /// This is reported if:
+    /// - enumeration does not finish within one second (or the [configured](EnumerationConfig::timeout) time). The device address is `None` in that case. A device attached to
+    ///   the root port needs to be reconnected (or the host [reset](UsbHost::reset)) before it is enumerated again.
+    /// - a control transfer does not complete within 5 seconds. If the transfer was part of discovery or configuration,
+    ///   the device is put in "dormant" state until it is removed. If it was initiated on a pipe, the driver is
+    ///   informed via [`transfer_failed`](driver::Driver::transfer_failed).
+    ///
+    /// Time is measured in frames (see [`UsbHost::frame_count`]). If the host bus reports neither frame numbers, nor
+    /// SOF events, timeouts are only detected if the application provides ticks (see [`UsbHost::tick_1ms`]).
+    Timeout(Option<DeviceAddress>),
+
+    /// A device was attached, but all device addresses are already in use.
+    ///
+    /// Addresses are only re-used once the device that had them was removed. The new device is left without an address
+    /// until it is removed again.
+    AddressesExhausted,
+
+    /// A device signalled remote wakeup while the bus was suspended.
+    ///
+    /// The host drives resume signalling and restarts SOF generation on its own. Until the device had time to recover
+    /// (about 30 ms in total), `poll` returns [`PollResult::Busy`], and starting a transfer results in [`ControlError::WouldBlock`].
+    RemoteWakeup,
+
+    /// The device failed to answer its liveness ping too many times in a row (see [`UsbHost::set_liveness_ping`])
+    ///
+    /// The driver owning the ping's pipe was informed via [`transfer_failed`](driver::Driver::transfer_failed). The device
+    /// is still known to the host. To recover, the application (or hub driver) can reset the port the device is attached
+    /// to, or [reset](UsbHost::reset) the host, to have it enumerated again.
+    DeviceUnresponsive(DeviceAddress),
+
+    /// The bus is suspended (see [`UsbHost::suspend`]). No transfers can be started until it is resumed.
+    Suspended,
+
+    /// The host gave up on a device, after too many transfers failed in a row (see [`UsbHost::set_error_threshold`])
+    ///
+    /// The device was removed, and drivers were informed with [`DetachReason::Errors`]. It is still physically connected
+    /// though, and will not be enumerated again on its own. If it is attached to a hub (the [`HubPort`] is given), the
+    /// hub driver (or application) can reset the port to enumerate it again. A device on the root port is enumerated
+    /// again after a [reset](UsbHost::reset) of the host.
+    DeviceFailed(DeviceAddress, Option<HubPort>),
+
+    /// The configuration chosen for a device draws more current than available at its port (see [`UsbHost::set_power_budget`])
+    ///
+    /// With [`BudgetAction::Refuse`](power::BudgetAction::Refuse), the device was left dormant. Otherwise it is configured
+    /// as usual, or verified first if verification is enabled (see [`UsbHost::verifying_device`]).
+    PowerBudgetExceeded(DeviceAddress),
+
+    /// The device did not apply the configuration chosen for it
+    ///
+    /// After SET_CONFIGURATION, the host reads the configuration back with GET_CONFIGURATION. This is returned if the
+    /// device reported a different value. Drivers were not informed about the configuration, and the device is left dormant
+    /// until it is removed.
+    ConfigurationNotApplied(DeviceAddress),
+
+    /// The host stack encountered an internal error, see [`HostError`]
+    HostError(HostError),
 }
 
 /// Entrypoint for the USB host stack
@@ -199,52 +718,400 @@ pub enum PollResult {
 /// (there is one exception to this: within the enumeration phase, two resets are performed, during which the device will
 /// "disconnect" and "connect" again - these disconnects do not return to the initial enumeration state).
 ///
+/// ## Devices attached to hubs
+///
+/// Devices attached to the ports of a hub go through the same phases, one device at a time. Unlike the root port, the
+/// host cannot detect on its own when such a device is connected or disconnected. Instead the hub driver (or application
+/// code controlling it) must hand the device over to the host, using [`enumerate_hub_port`](UsbHost::enumerate_hub_port)
+/// and [`hub_port_detached`](UsbHost::hub_port_detached).
+///
 /// For a more detailed description of these phases, check out the [documentation for the Driver interface](crate::driver).
 ///
+/// ## Number of pipes
+///
+/// The host keeps a table of `MAX_PIPES` pipes, which limits how many pipes the drivers can have open at the same time
+/// (including one control pipe per configured device that a driver talks to). Every entry costs RAM, so small projects can
+/// lower the number, while projects with many devices behind hubs may need to raise it:
+///
+/// ```ignore
+/// let mut usb_host: UsbHost<_, 4> = UsbHost::new_sized(bus, EnumerationConfig::default());
+/// ```
+///
+/// The drivers passed to [`poll`](UsbHost::poll) are then [`Driver<B, 4>`](driver::Driver) objects. `MAX_PIPES` may be
+/// at most 256.
+///
+/// ## Control queue
+///
+/// Control transfers that drivers submit while the bus is busy are queued, and started in order once it becomes idle.
+/// The queue holds `CONTROL_QUEUE_DEPTH` transfers; once it is full, further transfers fail with
+/// [`ControlError::WouldBlock`]. Drivers which send bursts of requests (e.g. to set up several devices behind a hub at
+/// once) may need a deeper queue:
+///
+/// ```ignore
+/// let mut usb_host: UsbHost<_, DEFAULT_MAX_PIPES, 8> = UsbHost::new_sized(bus, EnumerationConfig::default());
+/// ```
+///
+/// ## Control buffer
+///
+/// The data of control IN transfers is kept in a buffer of `CONTROL_BUFFER_SIZE` bytes (see
+/// [`received_control_data`](UsbHost::received_control_data)). Data beyond this size is not requested from the device:
+/// the `length` of the setup packet is reduced to the size of the buffer, so the transfer completes with (at most) that
+/// many bytes, just like when the device sent less data than requested. Drivers which read larger descriptors or reports
+/// need a host with a larger buffer:
+///
+/// ```ignore
+/// let mut usb_host: UsbHost<_, DEFAULT_MAX_PIPES, DEFAULT_CONTROL_QUEUE_DEPTH, 1024> = UsbHost::new_sized(bus, EnumerationConfig::default());
+/// ```
+///
+/// Configuration descriptors read during discovery are the exception: those are read in multiple windows of the
+/// buffer's size, so that their `wTotalLength` can exceed it. The buffer must hold at least a device descriptor
+/// (18 bytes).
+///
 #[embed_doc_image("usb-host-phases", "doc/usb-host-phases.png")]
-pub struct UsbHost<B> {
+pub struct UsbHost<
+    B,
+    const MAX_PIPES: usize = DEFAULT_MAX_PIPES,
+    const CONTROL_QUEUE_DEPTH: usize = DEFAULT_CONTROL_QUEUE_DEPTH,
+    const CONTROL_BUFFER_SIZE: usize = DEFAULT_CONTROL_BUFFER_SIZE,
+> {
     bus: B,
     state: State,
     active_transfer: Option<(Option<PipeId>, transfer::Transfer)>,
-    last_address: u8,
+    /// Bulk transfers in progress on the additional channels of the bus, if it has any
+    channel_transfers: [Option<(PipeId, transfer::Transfer)>; MAX_CHANNELS],
+    /// Configuration and interface of the most recent descriptor passed to the drivers during discovery
+    descriptor_context: descriptor::DescriptorContext,
+    /// Control transfers submitted by drivers while the bus was busy
+    control_queue: queue::ControlQueue<CONTROL_QUEUE_DEPTH>,
+    addresses: address::AddressTable,
     pipes: [Option<Pipe>; MAX_PIPES],
+    devices: [Option<Device>; MAX_DEVICES],
+    frame_timer: frame::FrameTimer,
+    control_buffer: ControlBuffer<CONTROL_BUFFER_SIZE>,
+    /// Pipe and length of the most recent control IN transfer, while its data is still available
+    last_control_in: Option<(PipeId, u16)>,
+    /// Clear the halt condition of endpoints automatically when they STALL (see [`UsbHost::set_auto_clear_halt`])
+    auto_clear_halt: bool,
+    /// Pipe whose endpoint halt is currently being cleared automatically
+    clearing_halt: Option<PipeId>,
+    /// Device and endpoint address being recovered by [`UsbHost::recover_endpoint`]
+    recovering_endpoint: Option<(DeviceAddress, u8)>,
+    /// Device and interface of the `Set_Interface` request in progress
+    selecting_interface: Option<(DeviceAddress, u8)>,
+    /// Pause after discovery, until the application verified the device (see [`UsbHost::set_device_verification`])
+    verify_devices: bool,
+    /// Compare descriptors of reconnecting devices (see [`UsbHost::set_tamper_detection`])
+    tamper_detection: bool,
+    known_devices: [Option<KnownDevice>; MAX_KNOWN_DEVICES],
+    /// Slot in `known_devices` that is replaced next
+    next_known_device: usize,
+    /// Frame count at which the current enumeration process started
+    enumeration_started: Option<u32>,
+    /// Maximum packet size of endpoint zero of the device on the root port, learned from the initial GET_DESCRIPTOR request
+    enumeration_max_packet_size_0: Option<u8>,
+    /// Timing and retry parameters of the enumeration process
+    enumeration_config: EnumerationConfig,
+    /// Number of times enumeration of the device on the root port was retried, since it was attached
+    discovery_attempts: u8,
+    /// Delay after the bus reset of a discovery retry, in place of `reset_0_delay` (see `retry_discovery`)
+    retry_delay: Option<u16>,
+    /// Frame count at which the current control transfer was started
+    control_started: Option<u32>,
+    /// Frame count at which the active transfer (of any type) was started
+    transfer_started: u32,
+    /// Set while the bus is suspended (and not resuming yet)
+    suspended: bool,
+    /// Whether the bus is suspended automatically (see [`UsbHost::set_power_policy`])
+    power_policy: PowerPolicy,
+    /// Set if the bus was suspended because of the power policy, rather than by the application
+    auto_suspended: bool,
+    /// Frame count at which the bus was last busy, or a device was being set up
+    last_activity: u32,
+    /// Set while the bus is resuming from suspend
+    resume: Option<ResumeState>,
+    /// Liveness pings, indexed by the control pipe they are sent on
+    pings: [Option<Ping>; MAX_PIPES],
+    /// Index (within the slice passed to `poll`) of the driver that created each pipe, if known
+    pipe_owners: [Option<u8>; MAX_PIPES],
+    /// NAK policies set for individual pipes, overriding `nak_policy` (see [`UsbHost::set_pipe_nak_policy`])
+    pipe_nak_policies: [Option<bus::NakPolicy>; MAX_PIPES],
+    /// NAK policy for control and bulk transfers (see [`UsbHost::set_nak_policy`])
+    nak_policy: bus::NakPolicy,
+    /// NAK policy last applied to the transfer path of the bus, or `None` if it is unknown (e.g. after a reset)
+    bus_nak_policy: Option<bus::NakPolicy>,
+    /// Index of the driver whose `configured` callback is currently running
+    configuring_driver: Option<u8>,
+    /// Pipe of the liveness ping currently in progress
+    pinging: Option<PipeId>,
+    /// Device that failed too many liveness pings, to be reported by `poll`
+    unresponsive: Option<DeviceAddress>,
+    /// Number of failed transfers in a row, after which a device is removed
+    error_threshold: Option<u8>,
+    /// Current available to devices (see [`UsbHost::set_power_budget`])
+    power_budget: Option<power::PowerBudget>,
+    /// Device that was removed after too many errors, to be reported by `poll`
+    failed: Option<(DeviceAddress, Option<HubPort>)>,
+    /// Pipe of the transfer aborted by [`UsbHost::cancel_transfer`], until the drivers were informed by `poll`
+    cancelled: Option<PipeId>,
+    /// Endpoint recovery aborted by [`UsbHost::cancel_transfer`], until the drivers were informed by `poll`
+    cancelled_recovery: Option<(DeviceAddress, u8)>,
+    /// Set while the `SET_FEATURE(PORT_RESET)` request sent by [`UsbHost::reset_device`] is in progress
+    resetting_port: bool,
+    /// Internal error, to be reported by `poll`
+    host_error: Option<HostError>,
+    /// Storage for the configuration descriptors of configured devices, if provided by the application
+    configuration_storage: Option<blob::BlobStorage<'static>>,
+    /// Source of random numbers, for drivers and the delay of discovery retries
+    entropy: entropy::Entropy,
+    /// Keep SOF interrupts enabled, to forward every start-of-frame to the drivers
+    sof_events: bool,
+    /// Whether SOF interrupts are currently enabled, or would be if the bus had a [`SofTimer`](bus::SofTimer)
+    sof_interrupt: bool,
+    /// Storage for interrupt IN data, until it is dispatched by the application (see [`UsbHost::set_report_buffer`])
+    report_buffer: Option<&'static mut (dyn report::ReportBuffer + Send)>,
+    /// Receives a record of each transaction stage, bus event and phase change (see [`UsbHost::set_trace_hook`])
+    #[cfg(feature = "trace")]
+    trace_hook: Option<&'static mut (dyn trace::TraceHook + Send)>,
+    /// Recipient set for the current transfer, for trace records
+    #[cfg(feature = "trace")]
+    trace_recipient: (Option<DeviceAddress>, u8, TransferType),
+    /// Phase most recently reported to the trace hook
+    #[cfg(feature = "trace")]
+    traced_phase: diagnostics::HostPhase,
+}
+
+impl<
+        B: core::fmt::Debug,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    > core::fmt::Debug for UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("UsbHost")
+            .field("bus", &self.bus)
+            .field("devices", &self.devices.iter().flatten().count())
+            .field("pipes", &self.pipes.iter().flatten().count())
+            .field("busy", &self.active_transfer.is_some())
+            .field("suspended", &self.suspended)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Host-owned copy of the data of the most recent control IN transfer
+///
+/// Received packets are copied out of the bus' buffer as soon as they arrive, so the data that drivers get to see is
+/// not affected by later transactions (which may reuse the bus' buffer), and chunked transfers can be reassembled.
+struct ControlBuffer<const SIZE: usize> {
+    data: [u8; SIZE],
+    len: usize,
+    /// Number of bytes at the start of the data stage which are discarded, instead of being stored
+    skip: usize,
+    /// Number of bytes received in the data stage so far, including skipped ones
+    position: usize,
+}
+
+impl<const SIZE: usize> ControlBuffer<SIZE> {
+    const fn new() -> Self {
+        ControlBuffer {
+            data: [0; SIZE],
+            len: 0,
+            skip: 0,
+            position: 0,
+        }
+    }
+
+    fn start(&mut self, skip: usize) {
+        self.len = 0;
+        self.skip = skip;
+        self.position = 0;
+    }
+
+    /// The stored data, up to the given length
+    fn data(&self, length: u16) -> &[u8] {
+        &self.data[..self.len.min(length as usize)]
+    }
+
+    /// Append a received packet, returning the number of bytes received
+    fn append(&mut self, packet: &[u8]) -> usize {
+        let skipped = self.skip.saturating_sub(self.position).min(packet.len());
+        self.position += packet.len();
+        let packet = &packet[skipped..];
+        let end = (self.len + packet.len()).min(SIZE);
+        self.data[self.len..end].copy_from_slice(&packet[..end - self.len]);
+        self.len = end;
+        skipped + packet.len()
+    }
 }
 
 #[derive(Copy, Clone)]
 enum Pipe {
+    /// Control pipes need no toggle state: every transfer starts with a DATA0 setup packet, and the data and status
+    /// stages start with DATA1.
     Control {
         dev_addr: DeviceAddress,
     },
     Interrupt {
         dev_addr: DeviceAddress,
-        bus_ref: u8,
+        /// Reference of the pipe on the host bus, or `None` if the host runs its transactions itself (because the bus
+        /// has no [`InterruptPipeHw`](bus::InterruptPipeHw))
+        bus_ref: Option<u8>,
+        endpoint: u8,
         direction: UsbDirection,
         size: u16,
-        ptr: *mut u8,
+        interval: PollingInterval,
+        /// Context value, passed back to drivers as part of the `PipeId`
+        context: u16,
+        /// Interface the endpoint belongs to, if known (see [`UsbHost::set_pipe_interface`])
+        interface: Option<u8>,
+        /// Data PID for the next packet (`true` for DATA1)
+        data_toggle: bool,
+        /// Frame count at which the pipe is polled next, if the host schedules (or runs) its transactions
+        next_poll: u32,
+        /// Whether the pipe's data is left in the bus' buffer, because the report buffer was full
+        deferred: bool,
+    },
+    Bulk {
+        dev_addr: DeviceAddress,
+        endpoint: u8,
+        direction: UsbDirection,
+        max_packet_size: u16,
+        /// Interface the endpoint belongs to, if known (see [`UsbHost::set_pipe_interface`])
+        interface: Option<u8>,
+        /// Data PID for the next packet (`true` for DATA1)
+        data_toggle: bool,
+    },
+    /// Isochronous pipes need no toggle state either: full-speed isochronous packets always use DATA0.
+    Isochronous {
+        dev_addr: DeviceAddress,
+        endpoint: u8,
+        direction: UsbDirection,
+        max_packet_size: u16,
     },
 }
 
-unsafe impl Send for Pipe {}
-
 /// Handle for a pipe
 ///
 /// A pipe connects a specific endpoint of a specific device to a driver.
-#[derive(Copy, Clone, PartialEq, Format)]
-pub struct PipeId(u8);
+///
+/// Besides identifying the pipe, the handle carries a small context value, chosen by the driver when creating
+/// the pipe (via one of the `create_*_pipe_with_context` methods). Since the host passes the handle to the
+/// `completed_*` callbacks, drivers can use the [`context`](PipeId::context) to look up the state associated with
+/// the pipe directly (e.g. by using it as an index into their device table), instead of searching for it.
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PipeId(u8, u16);
+
+impl PipeId {
+    /// Context value given when the pipe was created
+    ///
+    /// For pipes created without a context, this is zero.
+    pub fn context(&self) -> u16 {
+        self.1
+    }
+}
 
 impl<B: HostBus> UsbHost<B> {
     /// Initialize the USB host stack
     ///
     /// Resets the `HostBus` controller using [`reset_controller`](bus::HostBus::reset_controller).
     ///
-    pub fn new(mut bus: B) -> Self {
+    pub fn new(bus: B) -> Self {
+        Self::new_with_config(bus, EnumerationConfig::default())
+    }
+
+    /// Initialize the USB host stack, with custom parameters for the enumeration process
+    ///
+    /// See [`EnumerationConfig`] for details. Otherwise this is the same as [`new`](UsbHost::new).
+    pub fn new_with_config(bus: B, config: EnumerationConfig) -> Self {
+        Self::new_sized(bus, config)
+    }
+}
+
+impl<
+        B: HostBus,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    > UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>
+{
+    /// Initialize the USB host stack, with a table of `MAX_PIPES` pipes, a queue of `CONTROL_QUEUE_DEPTH` control transfers
+    /// and a control buffer of `CONTROL_BUFFER_SIZE` bytes
+    ///
+    /// Same as [`new_with_config`](UsbHost::new_with_config), for hosts with another number of pipes than
+    /// [`DEFAULT_MAX_PIPES`] (see [Number of pipes](UsbHost#number-of-pipes)), another depth of the control queue
+    /// than [`DEFAULT_CONTROL_QUEUE_DEPTH`] (see [Control queue](UsbHost#control-queue)), or another size of the control
+    /// buffer than [`DEFAULT_CONTROL_BUFFER_SIZE`] (see [Control buffer](UsbHost#control-buffer)).
+    pub fn new_sized(mut bus: B, config: EnumerationConfig) -> Self {
+        // pipe IDs store the index into the pipe table in a `u8`
+        const { assert!(MAX_PIPES <= 256, "UsbHost supports at most 256 pipes") };
+        // discovery reads the device descriptor in one go, and lengths of control transfers are `u16`
+        const {
+            assert!(
+                CONTROL_BUFFER_SIZE >= 18 && CONTROL_BUFFER_SIZE <= u16::MAX as usize,
+                "UsbHost needs a control buffer of 18 to 65535 bytes"
+            )
+        };
         bus.reset_controller();
         Self {
             bus,
             state: State::Enumeration(EnumerationState::WaitForDevice),
             active_transfer: None,
-            last_address: 0,
+            channel_transfers: [const { None }; MAX_CHANNELS],
+            descriptor_context: descriptor::DescriptorContext::default(),
+            control_queue: queue::ControlQueue::new(),
+            addresses: address::AddressTable::new(),
             pipes: [None; MAX_PIPES],
+            devices: [None; MAX_DEVICES],
+            frame_timer: frame::FrameTimer::new(),
+            control_buffer: ControlBuffer::new(),
+            last_control_in: None,
+            auto_clear_halt: false,
+            clearing_halt: None,
+            recovering_endpoint: None,
+            selecting_interface: None,
+            verify_devices: false,
+            tamper_detection: false,
+            known_devices: [None; MAX_KNOWN_DEVICES],
+            next_known_device: 0,
+            enumeration_started: None,
+            enumeration_max_packet_size_0: None,
+            enumeration_config: config,
+            discovery_attempts: 0,
+            retry_delay: None,
+            control_started: None,
+            transfer_started: 0,
+            suspended: false,
+            power_policy: PowerPolicy::KeepAlive,
+            auto_suspended: false,
+            last_activity: 0,
+            resume: None,
+            pings: [None; MAX_PIPES],
+            pipe_owners: [None; MAX_PIPES],
+            pipe_nak_policies: [None; MAX_PIPES],
+            nak_policy: bus::NakPolicy::UNLIMITED,
+            bus_nak_policy: None,
+            configuring_driver: None,
+            pinging: None,
+            unresponsive: None,
+            host_error: None,
+            error_threshold: None,
+            power_budget: None,
+            failed: None,
+            cancelled: None,
+            cancelled_recovery: None,
+            resetting_port: false,
+            configuration_storage: None,
+            entropy: entropy::Entropy::Default(entropy::Xorshift32::default()),
+            sof_events: false,
+            sof_interrupt: false,
+            report_buffer: None,
+            #[cfg(feature = "trace")]
+            trace_hook: None,
+            #[cfg(feature = "trace")]
+            trace_recipient: (None, 0, TransferType::Control),
+            #[cfg(feature = "trace")]
+            traced_phase: diagnostics::HostPhase::Enumeration,
         }
     }
 
@@ -254,6 +1121,8 @@ impl<B: HostBus> UsbHost<B> {
     /// Otherwise make sure to call it at least once per millisecond.
     ///
     /// The given list of drivers must be the same on every call to `poll`, otherwise drivers will likely not function as intended.
+    /// A [`DriverRegistry`](driver::registry::DriverRegistry) keeps the list fixed, see [`poll_registry`](UsbHost::poll_registry).
+    /// Pipes are associated with the driver that created them by their position in this list (see [`UsbHost::set_pipe_owner`]).
     ///
     /// ```ignore
     /// #[...]
@@ -263,61 +1132,466 @@ impl<B: HostBus> UsbHost<B> {
     ///     }
     /// }
     /// ```
-    pub fn poll(&mut self, drivers: &mut [&mut dyn driver::Driver<B>]) -> PollResult {
-        let event = if let Some(event) = self.bus.poll() {
+    ///
+    /// All events that the host bus has pending are processed (in order), see [`HostBus::poll_many`].
+    /// If processing more than one event produces an error result, the first one is returned.
+    pub fn poll(
+        &mut self,
+        drivers: &mut [&mut dyn driver::Driver<
+            B,
+            MAX_PIPES,
+            CONTROL_QUEUE_DEPTH,
+            CONTROL_BUFFER_SIZE,
+        >],
+    ) -> PollResult {
+        self.frame_timer.update(self.bus.frame_number());
+        let elapsed = self.frame_timer.take_elapsed();
+        self.poll_interrupt_pipes(drivers);
+        self.start_queued_transfer();
+        self.send_due_ping();
+
+        let mut events = [None; MAX_POLL_EVENTS];
+        let mut count = 0;
+        if self.sof_interrupt && self.bus.sof_timer().is_none() {
+            // every frame that passed counts as a start-of-frame, leaving room for at least one event from the bus
+            count = (elapsed as usize).min(MAX_POLL_EVENTS - 1);
+            events[..count].fill(Some(bus::Event::Sof));
+        }
+        self.bus.poll_many(&mut |event| {
+            if count < MAX_POLL_EVENTS {
+                events[count] = Some(event);
+                count += 1;
+                true
+            } else {
+                false
+            }
+        });
+
+        if count == 0 {
+            let result = self.process_event(None, drivers);
+            self.trace_phase();
+            return self.finish_poll(result, drivers);
+        }
+        let mut result = None;
+        for event in events[..count].iter().copied() {
+            let event_result = self.process_event(event, drivers);
+            self.trace_phase();
+            result = match result {
+                Some(
+                    error @ (PollResult::BusError(_)
+                    | PollResult::DiscoveryError(_)
+                    | PollResult::Retrying(_, _)
+                    | PollResult::UnsupportedDevice(_)
+                    | PollResult::VerifyDevice(_)
+                    | PollResult::VerificationTransferComplete(_)
+                    | PollResult::DeviceChanged(_)
+                    | PollResult::Timeout(_)
+                    | PollResult::AddressesExhausted
+                    | PollResult::RemoteWakeup
+                    | PollResult::DeviceUnresponsive(_)
+                    | PollResult::DeviceFailed(_, _)
+                    | PollResult::PowerBudgetExceeded(_)
+                    | PollResult::ConfigurationNotApplied(_)
+                    | PollResult::HostError(_)),
+                ) => Some(error),
+                _ => Some(event_result),
+            };
+        }
+        // Unwrap safety: at least one event was processed
+        self.finish_poll(result.unwrap(), drivers)
+    }
+
+    /// Poll the USB host, with the drivers that were registered in the given registry
+    ///
+    /// Same as [`poll`](UsbHost::poll), with the registered drivers in the order they were registered in. Drivers that
+    /// were disabled in the registry are not offered new devices
+    /// (see [`DriverRegistry::set_enabled`](driver::registry::DriverRegistry::set_enabled)).
+    pub fn poll_registry<const N: usize>(
+        &mut self,
+        registry: &mut driver::registry::DriverRegistry<
+            '_,
+            B,
+            N,
+            MAX_PIPES,
+            CONTROL_QUEUE_DEPTH,
+            CONTROL_BUFFER_SIZE,
+        >,
+    ) -> PollResult {
+        registry.with_gated(|drivers| self.poll(drivers))
+    }
+
+    /// Check for timeouts, internal errors, unresponsive and failed devices, which take precedence over the `result` of
+    /// processing events
+    fn finish_poll(
+        &mut self,
+        result: PollResult,
+        drivers: &mut [&mut dyn driver::Driver<
+            B,
+            MAX_PIPES,
+            CONTROL_QUEUE_DEPTH,
+            CONTROL_BUFFER_SIZE,
+        >],
+    ) -> PollResult {
+        if let Some(pipe_id) = self.cancelled.take() {
+            self.notify_transfer_failed(pipe_id, TransferError::Cancelled, drivers);
+        }
+        if let Some((dev_addr, endpoint)) = self.cancelled_recovery.take() {
+            self.notify_endpoint_recovered(dev_addr, endpoint, Err(TransferError::Cancelled), drivers);
+        }
+        if let Some(HostError::TransferNotStarted(dev_addr)) = self.host_error {
+            self.abandon_setup(dev_addr);
+        }
+        self.expire_interrupt_transaction();
+        self.start_queued_transfer();
+        self.apply_power_policy(drivers);
+        let result = match result {
+            PollResult::Idle if self.bus_busy() => PollResult::Busy,
+            result => result,
+        };
+        self.check_timeouts(drivers)
+            .or_else(|| self.host_error.take().map(PollResult::HostError))
+            .or_else(|| self.unresponsive.take().map(PollResult::DeviceUnresponsive))
+            .or_else(|| self.failed.take().map(|(dev_addr, hub_port)| PollResult::DeviceFailed(dev_addr, hub_port)))
+            .unwrap_or(result)
+    }
+
+    /// Leave the device that is being set up dormant, after the next step of its setup could not be started
+    fn abandon_setup(&mut self, dev_addr: Option<DeviceAddress>) {
+        self.enumeration_started = None;
+        let needed = self.sof_needed();
+        self.interrupt_on_sof(needed);
+        self.state = match self.state {
+            // the device stays unusable until it is reconnected
+            State::Enumeration(_) => State::Enumeration(EnumerationState::WaitForDevice),
+            _ => State::Idle,
+        };
+        if let (Some(dev_addr), Some(storage)) = (dev_addr, &mut self.configuration_storage) {
+            storage.remove(dev_addr);
+        }
+    }
+
+    /// Start the oldest queued control transfer, if the bus is idle
+    ///
+    /// Transfers whose pipe became invalid while they were waiting are dropped.
+    fn start_queued_transfer(&mut self) {
+        while matches!(self.state, State::Idle) && !self.bus_busy() {
+            let Some(queued) = self.control_queue.pop() else {
+                return;
+            };
+            let result = match queued.out_data() {
+                Some(data) => self.start_control_out(Some(queued.dev_addr), Some(queued.pipe_id), queued.setup, data),
+                None => self.control_in_with_skip(Some(queued.dev_addr), Some(queued.pipe_id), queued.setup, 0),
+            };
+            if result.is_err() {
+                warn!("Dropping queued control transfer for invalid pipe");
+            }
+        }
+    }
+
+    /// Start the liveness ping that is due next, if the bus is idle
+    fn send_due_ping(&mut self) {
+        // queued transfers go first
+        if !matches!(self.state, State::Idle) || self.bus_busy() || !self.control_queue.is_empty() {
+            return;
+        }
+        let now = self.frame_timer.frames();
+        let Some((index, ping)) = self
+            .pings
+            .iter()
+            .enumerate()
+            .find_map(|(index, ping)| ping.filter(|ping| now.wrapping_sub(ping.due) as i32 >= 0).map(|ping| (index, ping)))
+        else {
+            return;
+        };
+        let Some(Pipe::Control { dev_addr }) = self.pipes[index] else {
+            return;
+        };
+        let pipe_id = ping.pipe_id;
+        let setup = ping.ping.setup;
+        let started = if setup.request_type & 0x80 != 0 {
+            self.control_in(Some(dev_addr), Some(pipe_id), setup)
+        } else {
+            self.control_out(Some(dev_addr), Some(pipe_id), setup, &[])
+        };
+        if started.is_ok() {
+            self.pinging = Some(pipe_id);
+        }
+        if let Some(ping) = &mut self.pings[index] {
+            ping.due = now.wrapping_add(ping.ping.interval as u32);
+        }
+    }
+
+    /// Returns `true` if the given pipe belongs to the liveness ping in progress, which got a response from the device
+    fn ping_answered(&mut self, pipe_id: Option<PipeId>) -> bool {
+        match (pipe_id, self.pinging) {
+            (Some(pipe_id), Some(pinging)) if pipe_id == pinging => {
+                self.pinging = None;
+                if let Some(ping) = &mut self.pings[pipe_id.0 as usize] {
+                    ping.failures = 0;
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Abort the enumeration process or the current control transfer, if they take too long
+    fn check_timeouts(
+        &mut self,
+        drivers: &mut [&mut dyn driver::Driver<
+            B,
+            MAX_PIPES,
+            CONTROL_QUEUE_DEPTH,
+            CONTROL_BUFFER_SIZE,
+        >],
+    ) -> Option<PollResult> {
+        let now = self.frame_timer.frames();
+
+        let enumerating = match self.state {
+            State::Enumeration(EnumerationState::WaitForDevice) => false,
+            State::Enumeration(_) | State::HubEnumeration(..) => true,
+            _ => false,
+        };
+        if !enumerating {
+            self.enumeration_started = None;
+        } else if now.wrapping_sub(*self.enumeration_started.get_or_insert(now)) >= self.enumeration_config.timeout {
+            debug!("Enumeration timed out");
+            self.enumeration_started = None;
+            let needed = self.sof_needed();
+            self.interrupt_on_sof(needed);
+            if let Some((None, _)) = self.active_transfer {
+                self.bus.stop_transaction();
+                self.active_transfer = None;
+            }
+            self.state = match self.state {
+                // the device stays unusable until it is reconnected
+                State::Enumeration(_) => State::Enumeration(EnumerationState::WaitForDevice),
+                _ => State::Idle,
+            };
+            return Some(PollResult::Timeout(None));
+        }
+
+        let Some(started) = self.control_started.filter(|_| self.active_transfer.is_some()) else {
+            self.control_started = None;
+            return None;
+        };
+        if now.wrapping_sub(started) < CONTROL_TIMEOUT_FRAMES {
+            return None;
+        }
+        debug!("Control transfer timed out");
+        self.control_started = None;
+        self.bus.stop_transaction();
+        let (pipe_id, _) = self.active_transfer.take()?;
+        if let Some(pipe_id) = self.clearing_halt.take().or(pipe_id) {
+            let dev_addr = self.pipe_device(pipe_id);
+            self.notify_transfer_failed(pipe_id, TransferError::Timeout, drivers);
+            return Some(PollResult::Timeout(dev_addr));
+        }
+        if let Some((dev_addr, _)) = self.recovering_endpoint {
+            self.endpoint_recovery_finished(Err(TransferError::Timeout), drivers);
+            return Some(PollResult::Timeout(Some(dev_addr)));
+        }
+        // a transfer initiated by the host itself (or by the application, without a pipe)
+        match self.state {
+            State::Discovery(dev_addr, _) | State::Configuring(dev_addr, _, _) => {
+                // device stays dormant, until it is removed
+                self.state = State::Idle;
+                if let Some(storage) = &mut self.configuration_storage {
+                    storage.remove(dev_addr);
+                }
+                Some(PollResult::Timeout(Some(dev_addr)))
+            }
+            State::Verifying(dev_addr, _, _) => Some(PollResult::Timeout(Some(dev_addr))),
+            _ => Some(PollResult::Timeout(None)),
+        }
+    }
+
+    /// Process a single event (or lack thereof) from the host bus
+    fn process_event(
+        &mut self,
+        bus_event: Option<bus::Event>,
+        drivers: &mut [&mut dyn driver::Driver<
+            B,
+            MAX_PIPES,
+            CONTROL_QUEUE_DEPTH,
+            CONTROL_BUFFER_SIZE,
+        >],
+    ) -> PollResult {
+        #[cfg(feature = "trace")]
+        if let Some(event) = bus_event {
+            self.trace(trace::TraceRecord::Event(event));
+        }
+        let event = if let Some(event) = bus_event {
             match event {
                 bus::Event::Attached(speed) => Event::Attached(speed),
                 bus::Event::Detached => Event::Detached,
                 bus::Event::TransComplete => {
                     if let Some((pipe_id, transfer)) = self.active_transfer.take() {
-                        match transfer.stage_complete(self) {
-                            transfer::PollResult::ControlInComplete(length) => {
+                        let transfer_length = transfer.length();
+                        // the device may end an IN data stage early, with a short packet
+                        let received = transfer.expected_in().map(|expected| {
+                            let data = self.bus.received_data(expected as usize);
+                            if transfer.transfer_type() == TransferType::Control {
+                                // copied right away, since the next transaction may overwrite the bus' buffer
+                                self.control_buffer.append(data) as u16
+                            } else {
+                                data.len() as u16
+                            }
+                        });
+                        let (result, action) = transfer.stage_complete(received);
+                        if let Some(action) = action {
+                            self.execute_bus_action(action);
+                        }
+                        match result {
+                            transfer::PollResult::ControlInComplete(length) => {
+                                self.last_control_in = pipe_id.map(|pipe_id| (pipe_id, length));
                                 Event::ControlInData(pipe_id, length)
                             }
                             transfer::PollResult::ControlOutComplete => {
-                                Event::ControlOutComplete(pipe_id)
+                                if let Some(halted) = self.clearing_halt.take() {
+                                    self.notify_transfer_failed(halted, TransferError::Stall { cleared: true }, drivers);
+                                    Event::None
+                                } else if self.recovering_endpoint.is_some() {
+                                    self.endpoint_recovery_finished(Ok(()), drivers);
+                                    Event::None
+                                } else {
+                                    if let Some((dev_addr, interface)) = self.selecting_interface.take() {
+                                        self.reset_interface_data_toggle(dev_addr, interface);
+                                    }
+                                    Event::ControlOutComplete(pipe_id)
+                                }
                             }
+                            transfer::PollResult::BulkInComplete(length) => match pipe_id {
+                                Some(pipe_id) => {
+                                    self.bulk_transfer_complete(pipe_id, length, false);
+                                    Event::BulkInData(pipe_id, None, length)
+                                }
+                                None => self.missing_pipe(),
+                            },
+                            transfer::PollResult::BulkOutComplete => match pipe_id {
+                                Some(pipe_id) => {
+                                    self.bulk_transfer_complete(pipe_id, transfer_length, transfer.zlp_sent());
+                                    Event::BulkOutComplete(pipe_id)
+                                }
+                                None => self.missing_pipe(),
+                            },
                             transfer::PollResult::Continue(transfer) => {
                                 self.active_transfer = Some((pipe_id, transfer));
                                 Event::None
                             }
                         }
                     } else {
-                        panic!("BUG: received WriteComplete while no transfer was in progress")
+                        warn!("Received TransComplete while no transfer was in progress");
+                        self.host_error = Some(HostError::UnexpectedCompletion);
+                        Event::None
                     }
                 }
                 bus::Event::Resume => {
-                    // TODO: figure out if drivers need to see this event
-                    Event::Resume
+                    debug!("Remote wakeup, resuming bus");
+                    self.start_resume_sequence();
+                    Event::RemoteWakeup
                 }
                 bus::Event::Stall => {
                     // abort current transfer
-                    self.active_transfer.take();
-                    Event::Stall
+                    let pipe_id = self.active_transfer.take().and_then(|(pipe_id, _)| pipe_id);
+                    if self.ping_answered(pipe_id) {
+                        // the device does not support the request, but it is alive
+                        Event::None
+                    } else {
+                        if let Some(halted) = self.clearing_halt.take() {
+                            // the device refused to clear the halt condition
+                            self.notify_transfer_failed(halted, TransferError::Stall { cleared: false }, drivers);
+                        } else if self.recovering_endpoint.is_some() {
+                            self.endpoint_recovery_finished(Err(TransferError::Stall { cleared: false }), drivers);
+                        } else if let Some(pipe_id) = pipe_id {
+                            if !(self.auto_clear_halt && self.start_clear_halt(pipe_id)) {
+                                self.notify_transfer_failed(pipe_id, TransferError::Stall { cleared: false }, drivers);
+                            }
+                        }
+                        Event::Stall(pipe_id)
+                    }
                 }
                 bus::Event::Error(error) => {
-                    if error == bus::Error::RxTimeout {
+                    // the bus gave up on the transaction
+                    if matches!(error, bus::Error::RxTimeout | bus::Error::NakLimit) {
                         self.bus.stop_transaction();
-                        self.active_transfer = None;
+                        let pipe_id = self.active_transfer.take().and_then(|(pipe_id, _)| pipe_id);
+                        if let Some(pipe_id) = self.clearing_halt.take().or(pipe_id) {
+                            self.notify_transfer_failed(pipe_id, TransferError::BusError(error), drivers);
+                        } else if self.recovering_endpoint.is_some() {
+                            self.endpoint_recovery_finished(Err(TransferError::BusError(error)), drivers);
+                        }
                     }
                     Event::BusError(error)
                 },
                 bus::Event::InterruptPipe(buf_ref) => Event::InterruptPipe(buf_ref),
-                bus::Event::Sof => Event::Sof,
+                bus::Event::ChannelComplete(channel) => match self.take_channel_transfer(channel) {
+                    Some((pipe_id, transfer)) if transfer.direction() == UsbDirection::In => {
+                        let length = Self::bulk_data(&mut self.bus, Some(channel), transfer.length()).len() as u16;
+                        self.bulk_transfer_complete(pipe_id, length, false);
+                        Event::BulkInData(pipe_id, Some(channel), length)
+                    }
+                    Some((pipe_id, transfer)) => {
+                        self.bulk_transfer_complete(pipe_id, transfer.length(), false);
+                        Event::BulkOutComplete(pipe_id)
+                    }
+                    None => {
+                        warn!("Received ChannelComplete while no transfer was in progress on the channel");
+                        self.host_error = Some(HostError::UnexpectedCompletion);
+                        Event::None
+                    }
+                },
+                bus::Event::ChannelStall(channel) => match self.take_channel_transfer(channel) {
+                    Some((pipe_id, _)) => {
+                        if !(self.auto_clear_halt && self.start_clear_halt(pipe_id)) {
+                            self.notify_transfer_failed(pipe_id, TransferError::Stall { cleared: false }, drivers);
+                        }
+                        Event::Stall(Some(pipe_id))
+                    }
+                    None => Event::None,
+                },
+                bus::Event::ChannelError(channel, error) => {
+                    if let Some((pipe_id, _)) = self.take_channel_transfer(channel) {
+                        self.notify_transfer_failed(pipe_id, TransferError::BusError(error), drivers);
+                    }
+                    Event::BusError(error)
+                }
+                bus::Event::Sof => {
+                    self.frame_timer.sof();
+                    self.advance_resume(drivers);
+                    let frame_number = self.bus.frame_number().unwrap_or(self.frame_timer.frames() as u16 & frame::FRAME_NUMBER_MASK);
+                    for driver in drivers.iter_mut() {
+                        driver.sof(frame_number);
+                    }
+                    Event::Sof
+                }
             }
         } else {
             Event::None
         };
 
-        match &self.state {
+        self.process_hub_port_detach(drivers);
+
+        match self.state {
             State::Enumeration(enumeration_state) => {
-                match enumeration::process_enumeration(event, *enumeration_state, self) {
+                match (enumeration_state, event) {
+                    (EnumerationState::WaitForDevice, Event::Attached(_)) => {
+                        // a newly attached device gets a fresh set of retries
+                        self.discovery_attempts = 0;
+                    }
+                    (EnumerationState::WaitDescriptor(..), Event::ControlInData(None, length)) => {
+                        let data = self.control_buffer.data(length);
+                        self.enumeration_max_packet_size_0 = discovery::max_packet_size_0(data);
+                    }
+                    _ => {}
+                }
+                match self.process_enumeration(event, enumeration_state) {
                     EnumerationState::Assigned(speed, dev_addr) => {
-                        for driver in drivers {
-                            driver.attached(dev_addr, speed);
-                        }
-                        let discovery_state = discovery::start_discovery(dev_addr, self);
-                        self.state = State::Discovery(dev_addr, discovery_state);
+                        self.device_assigned(dev_addr, speed, None, drivers);
+                    }
+                    EnumerationState::NoAddress => {
+                        self.state = State::Idle;
+                        return PollResult::AddressesExhausted;
                     }
                     other => {
                         self.state = State::Enumeration(other);
@@ -325,471 +1599,4017 @@ impl<B: HostBus> UsbHost<B> {
                 };
             }
 
-            State::Discovery(dev_addr, discovery_state) => {
-                let dev_addr = *dev_addr;
-                match discovery::process_discovery(event, dev_addr, *discovery_state, drivers, self)
-                {
-                    DiscoveryState::Done => {
-                        let mut chosen_config = None;
-                        // Ask all the drivers to choose a configuration
-                        for driver in drivers {
-                            if let Some(config) = driver.configure(dev_addr) {
-                                // first driver to choose one wins...
-                                chosen_config = Some(config);
-                                // ...drivers later in the list don't get a say.
-                                break;
-                            }
+            State::HubEnumeration(hub_port, enumeration_state) => {
+                if let Event::Detached = event {
+                    self.detach_all(drivers);
+                } else {
+                    match self.process_enumeration(event, enumeration_state) {
+                        EnumerationState::Assigned(speed, dev_addr) => {
+                            self.device_assigned(dev_addr, speed, Some(hub_port), drivers);
                         }
-                        if let Some(config) = chosen_config {
-                            // Unwrap safety: when reaching `Done` state, the discovery phase leaves the bus idle.
-                            self.set_configuration(dev_addr, None, config).ok().unwrap();
-                            self.state = State::Configuring(dev_addr, config);
-                        } else {
-                            self.state = State::Dormant(dev_addr);
+                        EnumerationState::NoAddress => {
+                            self.state = State::Idle;
+                            return PollResult::AddressesExhausted;
+                        }
+                        other => {
+                            self.state = State::HubEnumeration(hub_port, other);
                         }
                     }
-                    DiscoveryState::ParseError => {
-                        self.state = State::Dormant(dev_addr);
-                        return PollResult::DiscoveryError(dev_addr);
-                    }
-                    other => {
-                        self.state = State::Discovery(dev_addr, other);
+                    if let Some(result) = self.dispatch(event, drivers) {
+                        return result;
                     }
                 }
             }
 
-            State::Configuring(dev_addr, config) => {
-                let dev_addr = *dev_addr;
-                let config = *config;
-                match event {
-                    Event::ControlOutComplete(_) => {
-                        for driver in drivers {
-                            driver.configured(dev_addr, config, self);
+            State::Discovery(dev_addr, discovery_state) => {
+                if let Event::Detached = event {
+                    self.detach_all(drivers);
+                } else {
+                    match self.process_discovery(event, dev_addr, discovery_state, drivers) {
+                        DiscoveryState::Done => {
+                            if let Some(device) = self.find_device_mut(dev_addr) {
+                                device.discovered = true;
+                            }
+                            let changed = self.tamper_detection && self.check_known_device(dev_addr);
+                            let mut chosen_config = None;
+                            // Ask all the drivers to choose a configuration
+                            for driver in drivers.iter_mut() {
+                                if let Some(config) = driver.configure(dev_addr) {
+                                    // first driver to choose one wins...
+                                    chosen_config = Some(config);
+                                    // ...drivers later in the list don't get a say.
+                                    break;
+                                }
+                            }
+                            let over_budget = chosen_config.is_some_and(|config| self.exceeds_power_budget(dev_addr, config));
+                            let refused = over_budget && self.power_budget.is_some_and(|budget| budget.action == power::BudgetAction::Refuse);
+                            if over_budget {
+                                warn!("Configuration of device {:?} exceeds the power budget", dev_addr);
+                            }
+                            if refused {
+                                chosen_config = None;
+                            } else if self.verify_devices {
+                                self.state = State::Verifying(dev_addr, chosen_config, None);
+                                return if over_budget { PollResult::PowerBudgetExceeded(dev_addr) } else { PollResult::VerifyDevice(dev_addr) };
+                            }
+                            self.configure_device(dev_addr, chosen_config);
+                            if over_budget {
+                                return PollResult::PowerBudgetExceeded(dev_addr);
+                            }
+                            if changed {
+                                return PollResult::DeviceChanged(dev_addr);
+                            }
+                            if chosen_config.is_none() {
+                                return PollResult::UnsupportedDevice(dev_addr);
+                            }
                         }
-                        self.state = State::Configured(dev_addr, config);
-                    }
-                    Event::Detached => {
-                        for driver in drivers {
-                            driver.detached(dev_addr);
+                        DiscoveryState::ParseError => {
+                            self.state = State::Idle;
+                            if let Some(storage) = &mut self.configuration_storage {
+                                storage.remove(dev_addr);
+                            }
+                            if let Some(attempt) = self.retry_discovery(dev_addr, drivers) {
+                                return PollResult::Retrying(dev_addr, attempt);
+                            }
+                            return PollResult::DiscoveryError(dev_addr);
+                        }
+                        other => {
+                            self.state = State::Discovery(dev_addr, other);
                         }
-                        self.reset();
                     }
-                    _ => {}
+                    if let Some(result) = self.dispatch(event, drivers) {
+                        return result;
+                    }
                 }
             }
 
-            State::Configured(dev_addr, _config) => match event {
-                Event::Detached => {
-                    for driver in drivers {
-                        driver.detached(*dev_addr);
-                    }
-                    self.cleanup(*dev_addr);
+            State::Verifying(dev_addr, config, _) => match event {
+                Event::ControlInData(None, length) => {
+                    self.state = State::Verifying(dev_addr, config, Some(length));
+                    return PollResult::VerificationTransferComplete(dev_addr);
                 }
-
-                Event::ControlInData(pipe_id, len) => {
-                    let data = self.bus.received_data(len as usize);
-                    if let Some(pipe_id) = pipe_id {
-                        for driver in drivers {
-                            driver.completed_control(*dev_addr, pipe_id, Some(data));
-                        }
-                    } else {
-                        defmt::warn!("Control in data w/o pipe: {}", data);
+                Event::ControlOutComplete(None) => {
+                    self.state = State::Verifying(dev_addr, config, None);
+                    return PollResult::VerificationTransferComplete(dev_addr);
+                }
+                Event::Detached => self.detach_all(drivers),
+                _ => {
+                    if let Some(result) = self.dispatch(event, drivers) {
+                        return result;
                     }
                 }
+            },
 
-                Event::ControlOutComplete(pipe_id) => {
-                    if let Some(pipe_id) = pipe_id {
-                        for driver in drivers {
-                            driver.completed_control(*dev_addr, pipe_id, None);
-                        }
-                    } else {
-                        defmt::warn!("Control out complete w/o pipe");
+            State::Configuring(dev_addr, config, false) => match event {
+                Event::ControlOutComplete(None) => {
+                    // read the configuration back, to catch devices which silently ignore SET_CONFIGURATION
+                    let started = self.get_configuration(dev_addr, None);
+                    self.check_started(started, Some(dev_addr));
+                    self.state = if started.is_ok() { State::Configuring(dev_addr, config, true) } else { State::Idle };
+                }
+                Event::Detached => self.detach_all(drivers),
+                _ => {
+                    if let Some(result) = self.dispatch(event, drivers) {
+                        return result;
                     }
                 }
+            },
 
-                Event::InterruptPipe(pipe_ref) => {
-                    let matching_pipe = self
-                        .pipes
-                        .iter()
-                        .enumerate()
-                        .find(|(_, pipe)| {
-                            if let Some(Pipe::Interrupt { bus_ref, .. }) = pipe {
-                                *bus_ref == pipe_ref
-                            } else {
-                                false
-                            }
-                        })
-                        .map(|(id, pipe)| (PipeId(id as u8), pipe.unwrap()));
-
-                    if let Some((
-                        pipe_id,
-                        Pipe::Interrupt {
-                            dev_addr,
-                            size,
-                            ptr,
-                            direction,
-                            ..
-                        },
-                    )) = matching_pipe
-                    {
-                        match direction {
-                            UsbDirection::In => {
-                                let buf =
-                                    unsafe { core::slice::from_raw_parts(ptr, size as usize) };
-                                for driver in drivers {
-                                    driver.completed_in(dev_addr, pipe_id, buf);
-                                }
-                            }
-                            UsbDirection::Out => {
-                                let buf =
-                                    unsafe { core::slice::from_raw_parts_mut(ptr, size as usize) };
-                                for driver in drivers {
-                                    driver.completed_out(dev_addr, pipe_id, buf);
-                                }
-                            }
+            State::Configuring(dev_addr, config, true) => match event {
+                Event::ControlInData(None, length) => {
+                    let data = self.control_buffer.data(length);
+                    if data.first().is_some_and(|value| *value == config) {
+                        self.configured(dev_addr, config, drivers);
+                    } else {
+                        warn!("Device {:?} did not apply configuration {}", dev_addr, config);
+                        // device stays dormant, until it is removed
+                        self.state = State::Idle;
+                        if let Some(storage) = &mut self.configuration_storage {
+                            storage.remove(dev_addr);
                         }
+                        return PollResult::ConfigurationNotApplied(dev_addr);
                     }
-                    self.bus.pipe_continue(pipe_ref);
                 }
-
-                Event::BusError(error) => return PollResult::BusError(error),
-
-                Event::Stall => {
-                    for driver in drivers {
-                        driver.stall(*dev_addr);
+                // GET_CONFIGURATION is mandatory, but not every device implements it. Trust SET_CONFIGURATION in that case.
+                Event::Stall(None) => self.configured(dev_addr, config, drivers),
+                Event::Detached => self.detach_all(drivers),
+                _ => {
+                    if let Some(result) = self.dispatch(event, drivers) {
+                        return result;
                     }
                 }
-
-                _ => {}
             },
 
-            State::Dormant(dev_addr) => match event {
-                Event::Detached => {
-                    for driver in drivers {
-                        driver.detached(*dev_addr);
+            State::Idle => match event {
+                Event::Detached => self.detach_all(drivers),
+                _ => {
+                    if let Some(result) = self.dispatch(event, drivers) {
+                        return result;
                     }
-                    self.reset();
                 }
-                _ => {}
             },
         }
 
         if let State::Enumeration(EnumerationState::WaitForDevice) = self.state {
             PollResult::NoDevice
-        } else if self.active_transfer.is_some() {
+        } else if let Event::RemoteWakeup = event {
+            PollResult::RemoteWakeup
+        } else if self.suspended {
+            PollResult::Suspended
+        } else if self.bus_busy() {
             PollResult::Busy
         } else {
             PollResult::Idle
         }
     }
 
-    /// Reset the entire host stack
-    ///
-    /// This resets the host controller (via [`bus::HostBus::reset_controller`]) and resets
-    /// all internal state of the UsbHost to their defaults.
-    ///
-    /// Any current transfer will never complete, and any pipes created will no longer be valid.
-    /// At the end of the reset, no device will be connected.
-    ///
-    /// **Drivers must never call this method.**
+    /// Count down the frames of the resume sequence
     ///
-    /// NOTE: since the host does not keep track of any drivers, it cannot reset the drivers' internal state.
-    ///   It is up to application code to reset / re-initialize the drivers after resetting the host stack.
-    ///   Any `PipeId` or `DeviceAddress` held by the application or driver(s) must be considered invalid after a reset.
-    ///   Continuing to use them can lead to strange behavior, since after a reset, pipe and device addresses *will* be re-used.
-    pub fn reset(&mut self) {
-        self.bus.reset_controller();
-        self.state = State::Enumeration(EnumerationState::WaitForDevice);
-        self.active_transfer = None;
-        self.last_address = 0;
-        self.pipes = [None; MAX_PIPES];
+    /// Once resume signalling has been driven for long enough, it is ended and SOF generation is restarted. After the
+    /// recovery time has passed as well, transfers can be started again.
+    fn advance_resume(
+        &mut self,
+        drivers: &mut [&mut dyn driver::Driver<
+            B,
+            MAX_PIPES,
+            CONTROL_QUEUE_DEPTH,
+            CONTROL_BUFFER_SIZE,
+        >],
+    ) {
+        self.resume = match self.resume {
+            Some(ResumeState::Signalling(1)) => {
+                self.bus.end_resume();
+                self.bus.enable_sof();
+                Some(ResumeState::Recovery(RESUME_RECOVERY_FRAMES))
+            }
+            Some(ResumeState::Signalling(n)) => Some(ResumeState::Signalling(n - 1)),
+            Some(ResumeState::Recovery(1)) => {
+                debug!("Resume complete");
+                // enumeration relies on SOF interrupts for its delays
+                if !self.enumeration_delays() {
+                    let needed = self.sof_needed();
+                    self.interrupt_on_sof(needed);
+                }
+                for driver in drivers.iter_mut() {
+                    driver.resumed();
+                }
+                None
+            }
+            Some(ResumeState::Recovery(n)) => Some(ResumeState::Recovery(n - 1)),
+            None => None,
+        };
     }
 
-    fn alloc_pipe(&mut self) -> Option<(PipeId, &mut Option<Pipe>)> {
-        self.pipes
-            .iter_mut()
-            .enumerate()
-            .find(|(_, slot)| slot.is_none())
-            .map(|(i, slot)| (PipeId(i as u8), slot))
+    /// Returns `true` if no transfer can be started right now
+    ///
+    /// This is the case while a transfer is in progress, or while the bus is suspended or resuming from suspend.
+    fn bus_busy(&self) -> bool {
+        self.active_transfer.is_some() || self.resume.is_some() || self.suspended
     }
 
-    /// Create a pipe for control transfers
+    /// Suspend the bus
     ///
-    /// This method is meant to be called by drivers.
+    /// SOF generation is stopped (see [`HostBus::suspend`]), which causes all devices to enter suspend mode after 3 ms. The
+    /// drivers are informed via [`suspended`](driver::Driver::suspended). Until the bus is resumed, `poll` returns
+    /// [`PollResult::Suspended`], and starting a transfer results in [`ControlError::WouldBlock`] (control transfers on pipes are
+    /// queued instead).
     ///
-    /// The returned `PipeId` can be used to initiate transfers by calling [`control_out`](UsbHost::control_out),
-    /// [`control_in`](UsbHost::control_in) or one of their wrappers.
+    /// The bus can be resumed by calling [`resume`](UsbHost::resume), or by a device signalling remote wakeup (see
+    /// [`set_remote_wakeup`](UsbHost::set_remote_wakeup)).
     ///
-    /// Returns `None` if the maximum number of supported pipes has been reached.
-    pub fn create_control_pipe(&mut self, dev_addr: DeviceAddress) -> Option<PipeId> {
-        self.alloc_pipe().map(|(id, slot)| {
-            slot.replace(Pipe::Control { dev_addr });
-            id
-        })
+    /// Returns [`ControlError::WouldBlock`] if a transfer is in progress, or a device is currently being set up.
+    pub fn suspend(
+        &mut self,
+        drivers: &mut [&mut dyn driver::Driver<
+            B,
+            MAX_PIPES,
+            CONTROL_QUEUE_DEPTH,
+            CONTROL_BUFFER_SIZE,
+        >],
+    ) -> Result<(), ControlError> {
+        if self.suspended {
+            return Ok(());
+        }
+        if self.bus_busy() || self.channel_transfers.iter().any(Option::is_some) || !matches!(self.state, State::Idle) {
+            return Err(ControlError::WouldBlock);
+        }
+        debug!("Suspending bus");
+        self.bus.suspend();
+        self.suspended = true;
+        for driver in drivers {
+            driver.suspended();
+        }
+        Ok(())
     }
 
-    /// Returns the next unassigned address, and increments the counter
-    ///
-    /// The address is allowed to overflow, at which point it starts out at 1 again (0 is skipped).
+    /// Resume the bus, after it was suspended with [`suspend`](UsbHost::suspend)
     ///
-    /// FIXME: prevent re-use of addresses. The overflowing address counter is not just theoretical,
-    ///   it can be triggered by a device resetting itself over and over directly after receiving an address.
-    fn next_address(&mut self) -> DeviceAddress {
-        self.last_address = self.last_address.wrapping_add(1);
-        if self.last_address == 0 {
-            self.last_address += 1;
+    /// The host drives resume signalling and restarts SOF generation, the same way as after a remote wakeup. Once the devices
+    /// had time to recover (about 30 ms), the drivers are informed via [`resumed`](driver::Driver::resumed), and transfers
+    /// can be started again. Until then, `poll` returns [`PollResult::Busy`].
+    pub fn resume(&mut self) {
+        if self.suspended {
+            self.start_resume_sequence();
         }
-        DeviceAddress(NonZeroU8::new(self.last_address).unwrap())
     }
 
-    pub fn ls_preamble(&mut self, enable: bool) {
-        self.bus.ls_preamble(enable);
+    /// Choose whether the bus is suspended automatically, while none of the devices is in use
+    ///
+    /// The default is [`PowerPolicy::KeepAlive`]. When the bus is suspended because of the policy, the drivers are
+    /// informed via [`suspended`](driver::Driver::suspended) and `poll` returns [`PollResult::Suspended`], as if
+    /// [`suspend`](UsbHost::suspend) was called. It is resumed automatically when the policy no longer applies (e.g. after
+    /// it was changed).
+    pub fn set_power_policy(&mut self, policy: PowerPolicy) {
+        self.power_policy = policy;
+        self.last_activity = self.frame_timer.frames();
     }
 
-    /// Initiate an IN transfer on the control endpoint of the given device
-    ///
-    /// If a `pipe_id` is given, the driver that set up the pipe will be able to associate the subsequent
-    /// [`completed_control`](driver::Driver::completed_control) callback with this transfer.
-    /// Otherwise the transfer will not be reported to any drivers.
-    ///
-    /// The number of bytes transferred is determined by the `length` from the setup packet.
-    ///
-    /// If there is currently a transfer in progress, [`ControlError::WouldBlock`] is returned, and no attempt is made to initiate the transfer.
-    ///
-    /// This method is usually called by drivers, not by application code.
-    pub fn control_in(
+    /// Whether the power policy calls for the bus to be suspended right now
+    fn policy_suspends(&self) -> bool {
+        let idle_frames = match self.power_policy {
+            PowerPolicy::KeepAlive => return false,
+            PowerPolicy::SuspendWhenDormant => None,
+            PowerPolicy::SuspendWhenIdle(frames) => Some(frames as u32),
+        };
+        let idle_for = self.frame_timer.frames().wrapping_sub(self.last_activity);
+        let in_use = |device: &Device| {
+            let has_pipes = || {
+                self.pipes.iter().flatten().any(|pipe| match *pipe {
+                    Pipe::Interrupt { dev_addr, .. } | Pipe::Bulk { dev_addr, .. } | Pipe::Isochronous { dev_addr, .. } => {
+                        dev_addr == device.address
+                    }
+                    Pipe::Control { .. } => false,
+                })
+            };
+            device.configuration.is_some() && idle_frames.is_none_or(|frames| idle_for < frames || has_pipes())
+        };
+        matches!(self.state, State::Idle)
+            && self.control_queue.is_empty()
+            && self.pings.iter().all(Option::is_none)
+            && self.devices.iter().flatten().next().is_some()
+            && !self.devices.iter().flatten().any(in_use)
+    }
+
+    /// Suspend or resume the bus, as called for by the power policy
+    fn apply_power_policy(
         &mut self,
-        dev_addr: Option<DeviceAddress>,
-        pipe_id: Option<PipeId>,
-        setup: SetupPacket,
-    ) -> Result<(), ControlError> {
-        self.validate_control_pipe(dev_addr, pipe_id)?;
-        if self.active_transfer.is_some() {
-            return Err(ControlError::WouldBlock);
+        drivers: &mut [&mut dyn driver::Driver<
+            B,
+            MAX_PIPES,
+            CONTROL_QUEUE_DEPTH,
+            CONTROL_BUFFER_SIZE,
+        >],
+    ) {
+        let channels_busy = self.channel_transfers.iter().any(Option::is_some);
+        if self.active_transfer.is_some() || channels_busy || self.resume.is_some() || !matches!(self.state, State::Idle) {
+            self.last_activity = self.frame_timer.frames();
+        }
+        if self.suspended {
+            if self.auto_suspended && !self.policy_suspends() {
+                debug!("Resuming bus for power policy");
+                self.start_resume_sequence();
+            }
+        } else if self.policy_suspends() && self.suspend(drivers).is_ok() {
+            self.auto_suspended = true;
         }
+    }
 
-        self.active_transfer = Some((pipe_id, transfer::Transfer::new_control_in(setup.length)));
-        self.bus.set_recipient(dev_addr, 0, TransferType::Control);
-        self.bus.write_setup(setup);
+    /// Whether enumeration is in progress, and relies on SOF interrupts for its delays
+    fn enumeration_delays(&self) -> bool {
+        matches!(self.state, State::Enumeration(state) | State::HubEnumeration(_, state) if state != EnumerationState::WaitForDevice)
+    }
 
-        Ok(())
+    fn start_resume_sequence(&mut self) {
+        if self.resume.is_none() {
+            self.suspended = false;
+            self.auto_suspended = false;
+            self.bus.start_resume();
+            self.interrupt_on_sof(true);
+            self.resume = Some(ResumeState::Signalling(RESUME_SIGNALLING_FRAMES));
+        }
     }
 
-    /// Initiate an OUT transfer on the control endpoint of the given device
-    ///
-    /// If a `pipe_id` is given, the driver that set up the pipe will be able to associate the [`driver::Driver::completed_control`]
-    /// call with this transfer.
-    /// Otherwise the transfer will not be reported to any drivers.
-    ///
-    /// The `length` of the `setup` packet MUST be equal to the size of the `data` slice.
+    /// Forward events related to pipes to the drivers
     ///
-    /// If there is currently a transfer in progress, [`ControlError::WouldBlock`] is returned, and no attempt is made to initiate the transfer.
-    ///
-    /// This method is usually called by drivers, not by application code.
-    pub fn control_out(
+    /// Events that are not related to a pipe (i.e. those belonging to transfers initiated by the host itself) are ignored.
+    fn dispatch(
         &mut self,
-        dev_addr: Option<DeviceAddress>,
-        pipe_id: Option<PipeId>,
-        setup: SetupPacket,
-        data: &[u8],
-    ) -> Result<(), ControlError> {
-        self.validate_control_pipe(dev_addr, pipe_id)?;
-
-        if self.active_transfer.is_some() {
-            return Err(ControlError::WouldBlock);
+        event: Event,
+        drivers: &mut [&mut dyn driver::Driver<
+            B,
+            MAX_PIPES,
+            CONTROL_QUEUE_DEPTH,
+            CONTROL_BUFFER_SIZE,
+        >],
+    ) -> Option<PollResult> {
+        if let Event::ControlInData(Some(pipe_id), _)
+        | Event::ControlOutComplete(Some(pipe_id))
+        | Event::BulkInData(pipe_id, _, _)
+        | Event::BulkOutComplete(pipe_id) = event
+        {
+            self.transfer_succeeded(pipe_id);
         }
+        if let Event::ControlInData(pipe_id, _) | Event::ControlOutComplete(pipe_id) = event {
+            if self.ping_answered(pipe_id) {
+                // liveness pings are not reported to drivers
+                return None;
+            }
+        }
+        match event {
+            Event::ControlInData(pipe_id, len) => {
+                if let Some((pipe_id, dev_addr)) = pipe_id.and_then(|id| self.pipe_device(id).map(|addr| (id, addr))) {
+                    let data = self.control_buffer.data(len);
+                    for driver in self.pipe_drivers(pipe_id, drivers) {
+                        driver.completed_control(dev_addr, pipe_id, Some(data));
+                    }
+                } else if let State::Idle = self.state {
+                    warn!("Control in data w/o pipe: {:?}", self.control_buffer.data(len));
+                }
+            }
 
-        self.active_transfer = Some((
-            pipe_id,
-            transfer::Transfer::new_control_out(data.len() as u16),
-        ));
-        self.bus.set_recipient(dev_addr, 0, TransferType::Control);
-        self.bus.prepare_data_out(data);
-        self.bus.write_setup(setup);
+            Event::ControlOutComplete(pipe_id) => {
+                if let Some((pipe_id, dev_addr)) = pipe_id.and_then(|id| self.pipe_device(id).map(|addr| (id, addr))) {
+                    for driver in self.pipe_drivers(pipe_id, drivers) {
+                        driver.completed_control(dev_addr, pipe_id, None);
+                    }
+                } else if core::mem::take(&mut self.resetting_port) {
+                    debug!("Port reset requested");
+                } else if let State::Idle = self.state {
+                    warn!("Control out complete w/o pipe");
+                }
+            }
 
-        Ok(())
-    }
+            Event::InterruptPipe(pipe_ref) => {
+                let matching_pipe = self
+                    .pipes
+                    .iter()
+                    .enumerate()
+                    .find(|(_, pipe)| {
+                        if let Some(Pipe::Interrupt { bus_ref, .. }) = pipe {
+                            *bus_ref == Some(pipe_ref)
+                        } else {
+                            false
+                        }
+                    })
+                    .map(|(id, pipe)| {
+                        let pipe = pipe.unwrap();
+                        let context = if let Pipe::Interrupt { context, .. } = pipe { context } else { 0 };
+                        (PipeId(id as u8, context), pipe)
+                    });
 
-    fn validate_control_pipe(
-        &self,
-        dev_addr: Option<DeviceAddress>,
-        pipe_id: Option<PipeId>,
-    ) -> Result<(), ControlError> {
-        let is_valid = match (dev_addr, pipe_id) {
-            (None, None) | (Some(_), None) => true,
-            (None, Some(_)) => false,
-            (Some(given_dev_addr), Some(pipe_id)) => {
-                // Index safety: a PipeId that is not in the 0..MAX_PIPES range (valid indices for self.pipes)
-                //   should not be produced and indicates a bug within UsbHost.
-                if let Some(Pipe::Control { dev_addr }) = self.pipes[pipe_id.0 as usize] {
-                    dev_addr == given_dev_addr
-                } else {
-                    false
+                if let Some((
+                    pipe_id,
+                    Pipe::Interrupt {
+                        dev_addr,
+                        size,
+                        direction,
+                        ..
+                    },
+                )) = matching_pipe
+                {
+                    if let Some(buf) = self.bus.interrupt_pipe_hw().and_then(|hw| hw.interrupt_buffer(pipe_ref)) {
+                        let len = (size as usize).min(buf.len());
+                        let buf = &mut buf[..len];
+                        match (direction, &mut self.report_buffer) {
+                            (UsbDirection::In, Some(reports)) => {
+                                if !reports.push(pipe_id, buf) {
+                                    // the data stays in the bus' buffer, until there is room for it
+                                    if let Some(Pipe::Interrupt { deferred, .. }) = &mut self.pipes[pipe_id.0 as usize] {
+                                        *deferred = true;
+                                    }
+                                    return None;
+                                }
+                            }
+                            (UsbDirection::In, None) => {
+                                for driver in Self::owned_by(self.pipe_owners[pipe_id.0 as usize], drivers) {
+                                    driver.completed_in(dev_addr, pipe_id, buf);
+                                }
+                            }
+                            (UsbDirection::Out, _) => {
+                                for driver in Self::owned_by(self.pipe_owners[pipe_id.0 as usize], drivers) {
+                                    driver.completed_out(dev_addr, pipe_id, buf);
+                                }
+                            }
+                        }
+                    }
+                }
+                self.continue_interrupt_pipe(pipe_ref);
+            }
+
+            Event::BulkInData(pipe_id, channel, len) => {
+                if let Some(dev_addr) = self.pipe_device(pipe_id) {
+                    let data = Self::bulk_data(&mut self.bus, channel, len);
+                    let interrupt = matches!(self.pipes[pipe_id.0 as usize], Some(Pipe::Interrupt { .. }));
+                    match &mut self.report_buffer {
+                        // there was room, otherwise the transaction would not have been started
+                        Some(reports) if interrupt => {
+                            reports.push(pipe_id, data);
+                        }
+                        _ => {
+                            for driver in Self::owned_by(self.pipe_owners[pipe_id.0 as usize], drivers) {
+                                driver.completed_in(dev_addr, pipe_id, data);
+                            }
+                        }
+                    }
+                }
+            }
+
+            Event::BulkOutComplete(pipe_id) => {
+                let interrupt = matches!(self.pipes[pipe_id.0 as usize], Some(Pipe::Interrupt { .. }));
+                if let Some(dev_addr) = self.pipe_device(pipe_id).filter(|_| !interrupt) {
+                    for driver in self.pipe_drivers(pipe_id, drivers) {
+                        driver.completed_bulk_out(dev_addr, pipe_id);
+                    }
+                }
+            }
+
+            Event::BusError(error) => return Some(PollResult::BusError(error)),
+
+            Event::Stall(Some(pipe_id)) => {
+                if let Some(dev_addr) = self.pipe_device(pipe_id) {
+                    for driver in self.pipe_drivers(pipe_id, drivers) {
+                        driver.stall(dev_addr);
+                    }
                 }
             }
+
+            _ => {}
+        }
+        None
+    }
+
+    /// Called when a device has been assigned an address: informs drivers, and starts discovery
+    fn device_assigned(
+        &mut self,
+        dev_addr: DeviceAddress,
+        speed: types::ConnectionSpeed,
+        hub_port: Option<HubPort>,
+        drivers: &mut [&mut dyn driver::Driver<
+            B,
+            MAX_PIPES,
+            CONTROL_QUEUE_DEPTH,
+            CONTROL_BUFFER_SIZE,
+        >],
+    ) {
+        // devices on hub ports are assigned an address right away, without the initial GET_DESCRIPTOR request
+        let max_packet_size_0 = match hub_port {
+            None => self.enumeration_max_packet_size_0.take(),
+            Some(_) => None,
         };
-        if is_valid {
-            Ok(())
+        if let Some(slot) = self.devices.iter_mut().find(|slot| slot.is_none()) {
+            slot.replace(Device {
+                address: dev_addr,
+                hub_port,
+                speed,
+                detached: None,
+                info: None,
+                hasher: descriptor::DescriptorHasher::new(),
+                discovered: false,
+                connected_at: self.frame_timer.frames(),
+                max_packet_size_0: max_packet_size_0.unwrap_or(DEFAULT_MAX_PACKET_SIZE_0),
+                serial_hash: None,
+                descriptors_changed: false,
+                errors: 0,
+                claimed: InterfaceSet::EMPTY,
+                configuration: None,
+                power: power::DevicePower::default(),
+            });
         } else {
-            Err(ControlError::InvalidPipe)
+            // `enumerate_hub_port` checks that there is room before starting, but a root device may be enumerated (e.g.
+            // after `reset_device`) while the devices behind a hub are still known.
+            warn!("No room for device {:?}", dev_addr);
+            self.host_error = Some(HostError::TooManyDevices(dev_addr));
+            self.state = State::Idle;
+            return;
+        }
+        self.addresses.allocate(dev_addr);
+        for driver in drivers {
+            driver.attached(dev_addr, speed);
         }
+        let (discovery_state, request) = discovery::start_discovery(self.tamper_detection, max_packet_size_0.is_none());
+        self.request_discovery_descriptor(dev_addr, request);
+        self.state = State::Discovery(dev_addr, discovery_state);
     }
 
-    /// Initiate a `Get_Descriptor` (0x06) control IN transfer
-    ///
-    /// This is a convenience wrapper around [`UsbHost::control_in`], for the `Get_Descriptor` standard request.
-    ///
-    /// The `descriptor_type` can be one of the `TYPE_*` constants defined in the [`descriptor`] module, but usally these
-    /// are already requested during the discovery phase.
-    ///
-    /// Thus usually this method will be used to request class- or vendor-specific descriptors.
-    pub fn get_descriptor(
-        &mut self,
-        dev_addr: Option<DeviceAddress>,
-        pipe_id: Option<PipeId>,
-        recipient: Recipient,
-        descriptor_type: u8,
-        descriptor_index: u8,
-        length: u16,
-    ) -> Result<(), ControlError> {
-        self.control_in(
-            dev_addr,
-            pipe_id,
-            SetupPacket::new(
-                UsbDirection::In,
-                RequestType::Standard,
-                recipient,
-                Request::GET_DESCRIPTOR,
-                ((descriptor_type as u16) << 8) | (descriptor_index as u16),
-                0,
-                length,
-            ),
-        )
+    /// Run one step of the enumeration process, and carry out the resulting action
+    fn process_enumeration(&mut self, event: Event, state: EnumerationState) -> EnumerationState {
+        if let EnumerationState::Assigned(..) | EnumerationState::NoAddress = state {
+            self.host_error = Some(HostError::InvalidState);
+            return state;
+        }
+        let (mut next, action) =
+            enumeration::process_enumeration(event, state, self.bus_busy(), self.addresses.next(), &self.enumeration_config);
+        if let (EnumerationState::Delay0(speed, _), Some(delay)) = (next, self.retry_delay) {
+            next = EnumerationState::Delay0(speed, delay);
+        }
+        if !matches!(next, EnumerationState::Reset0) {
+            self.retry_delay = None;
+        }
+        if let Some(action) = action {
+            self.execute_enumeration_action(action);
+        }
+        next
     }
 
-    pub fn get_status(
+    fn execute_enumeration_action(&mut self, action: EnumerationAction) {
+        match action {
+            EnumerationAction::ResetBus => self.bus.reset_bus(),
+            EnumerationAction::StartDelay => {
+                self.bus.enable_sof();
+                self.interrupt_on_sof(true);
+            }
+            EnumerationAction::EnableSof => self.bus.enable_sof(),
+            EnumerationAction::GetDeviceDescriptor => {
+                let started = self.get_descriptor(None, None, Recipient::Device, descriptor::TYPE_DEVICE, 0, 8);
+                self.check_started(started, None);
+            }
+            EnumerationAction::SetAddress(address) => {
+                let started = self.set_address(address);
+                self.check_started(started, None);
+            }
+            EnumerationAction::StopDelay => {
+                let needed = self.sof_needed();
+                self.interrupt_on_sof(needed);
+            }
+        }
+    }
+
+    /// Run one step of the discovery process: forwards descriptors to drivers, and requests the next one
+    fn process_discovery(
         &mut self,
+        event: Event,
         dev_addr: DeviceAddress,
-        pipe_id: PipeId,
-        recipient: Recipient,
-    ) -> Result<(), ControlError> {
-        self.control_in(Some(dev_addr), Some(pipe_id), SetupPacket::new(UsbDirection::In, RequestType::Standard, recipient, Request::GET_STATUS, 0, 0, 2))
+        state: DiscoveryState,
+        drivers: &mut [&mut dyn driver::Driver<
+            B,
+            MAX_PIPES,
+            CONTROL_QUEUE_DEPTH,
+            CONTROL_BUFFER_SIZE,
+        >],
+    ) -> DiscoveryState {
+        if let DiscoveryState::Done | DiscoveryState::ParseError = state {
+            self.host_error = Some(HostError::InvalidState);
+            return state;
+        }
+        let data = match event {
+            Event::ControlInData(None, length) => Some(self.control_buffer.data(length)),
+            _ => None,
+        };
+        let previous_state = state;
+        let (state, discovery::DiscoveryStep { descriptors, max_packet_size_0, device, serial_number, report_descriptor, request }) = match event {
+            Event::Stall(None) => discovery::discovery_stalled(state),
+            _ => discovery::process_discovery(data, state, CONTROL_BUFFER_SIZE as u16),
+        };
+        if let Some(max_packet_size_0) = max_packet_size_0 {
+            if let Some(device) = Device::find_mut(&mut self.devices, dev_addr) {
+                device.max_packet_size_0 = max_packet_size_0;
+            }
+        }
+        if let Some(serial_number) = serial_number {
+            if let Some(device) = Device::find_mut(&mut self.devices, dev_addr) {
+                let mut hasher = descriptor::DescriptorHasher::new();
+                hasher.update(serial_number);
+                device.serial_hash = Some(hasher.finish());
+            }
+        }
+        if let Some(mut data) = descriptors {
+            if let Some(device) = Device::find_mut(&mut self.devices, dev_addr) {
+                device.hasher.update(data);
+            }
+            if let (DiscoveryState::ConfigDesc(..), Some(storage)) = (previous_state, &mut self.configuration_storage) {
+                storage.append(dev_addr, data);
+            }
+            // Descriptors were validated by the discovery process already
+            while let Ok((rest, descriptor)) = descriptor::parse::any_descriptor(data) {
+                if descriptor.descriptor_type == descriptor::TYPE_CONFIGURATION {
+                    if let (Ok((_, configuration)), Some(device)) =
+                        (descriptor::parse::configuration_descriptor(descriptor.data), Device::find_mut(&mut self.devices, dev_addr))
+                    {
+                        device.power.record(power::ConfigurationPower::from(&configuration));
+                    }
+                }
+                self.descriptor_context.update(descriptor.descriptor_type, descriptor.data);
+                for driver in drivers.iter_mut() {
+                    driver.descriptor_with_context(dev_addr, descriptor.descriptor_type, descriptor.data, self.descriptor_context);
+                }
+                if rest.is_empty() {
+                    break;
+                }
+                data = rest;
+            }
+        }
+        if let Some(report_descriptor) = report_descriptor {
+            for driver in drivers.iter_mut() {
+                driver.descriptor(dev_addr, hid::TYPE_REPORT, report_descriptor);
+            }
+        }
+        if let Some(info) = device {
+            self.discovered_device(dev_addr, info);
+        }
+        // Read the report descriptors which drivers need to decide, before going on
+        if let DiscoveryState::ConfigDescLen(..) | DiscoveryState::Done = state {
+            let wanted = drivers.iter_mut().find_map(|driver| match driver.discovery_interest(dev_addr) {
+                driver::DiscoveryInterest::ReportDescriptor { interface, length } => Some((interface, length)),
+                _ => None,
+            });
+            if let Some((interface, length)) = wanted {
+                let (state, request) = discovery::request_report_descriptor(state, interface, length);
+                self.request_discovery_descriptor(dev_addr, request);
+                return state;
+            }
+        }
+        // Skip the remaining configuration descriptors, once every driver has made up its mind
+        if let DiscoveryState::ConfigDescLen(n, m) = state {
+            if !drivers.is_empty() && drivers.iter_mut().all(|driver| driver.discovery_interest(dev_addr) != driver::DiscoveryInterest::Undecided) {
+                debug!("All drivers decided on device {:?}, skipping configurations {}..{}", dev_addr, n, m);
+                return DiscoveryState::Done;
+            }
+        }
+        if let Some(request) = request {
+            self.request_discovery_descriptor(dev_addr, request);
+        }
+        state
     }
 
-    /// Initiate a `Set_Address` (0x05) control OUT transfer
+    fn request_discovery_descriptor(&mut self, dev_addr: DeviceAddress, request: discovery::DiscoveryRequest) {
+        let setup = SetupPacket::new(
+            UsbDirection::In,
+            RequestType::Standard,
+            request.recipient(),
+            Request::GET_DESCRIPTOR,
+            ((request.descriptor_type() as u16) << 8) | request.index() as u16,
+            request.w_index(),
+            request.length(),
+        );
+        let started = self.control_in_with_skip(Some(dev_addr), None, setup, request.offset());
+        self.check_started(started, Some(dev_addr));
+    }
+
+    /// Record an error for a bulk transfer that completed without a pipe
+    fn missing_pipe(&mut self) -> Event {
+        warn!("Bulk transfer completed without a pipe");
+        self.host_error = Some(HostError::MissingPipe);
+        Event::None
+    }
+
+    /// Record an error if a transfer that is part of setting up a device could not be started
     ///
-    /// Private, since this is only used by the enumeration process.
+    /// The device is left dormant by `finish_poll`.
+    fn check_started(&mut self, started: Result<(), ControlError>, dev_addr: Option<DeviceAddress>) {
+        if started.is_err() {
+            warn!("Failed to start transfer while setting up device");
+            self.host_error = Some(HostError::TransferNotStarted(dev_addr));
+        }
+    }
+
+    /// Start the next stage of the active transfer
+    fn execute_bus_action(&mut self, action: transfer::BusAction) {
+        match action {
+            transfer::BusAction::WriteDataIn(length, pid) => self.write_data_in(length, pid),
+            transfer::BusAction::WriteDataOutPrepared(pid) => self.write_data_out_prepared(pid),
+            transfer::BusAction::WriteStatusOut => self.write_data_out(&[], true),
+            transfer::BusAction::WriteStatusIn => self.write_data_in(0, true),
+            transfer::BusAction::WriteZeroLengthOut(pid) => self.write_data_out(&[], pid),
+        }
+    }
+
+    /// Hand a SETUP packet to the bus, for the recipient set with `set_recipient`
+    fn write_setup(&mut self, setup: SetupPacket) {
+        #[cfg(feature = "trace")]
+        self.trace(trace::TraceRecord::Setup { dev_addr: self.trace_recipient.0, packet: setup.to_bytes() });
+        self.bus.write_setup(setup);
+    }
+
+    /// Start an IN data stage (or transaction) on the bus
+    fn write_data_in(&mut self, length: u16, pid: bool) {
+        #[cfg(feature = "trace")]
+        {
+            let (dev_addr, endpoint, transfer_type) = self.trace_recipient;
+            self.trace(trace::TraceRecord::DataIn { dev_addr, endpoint, transfer_type, length, pid });
+        }
+        self.bus.write_data_in(length, pid);
+    }
+
+    /// Start an OUT data stage (or transaction) on the bus
+    fn write_data_out(&mut self, data: &[u8], pid: bool) {
+        #[cfg(feature = "trace")]
+        {
+            let (dev_addr, endpoint, transfer_type) = self.trace_recipient;
+            self.trace(trace::TraceRecord::DataOut { dev_addr, endpoint, transfer_type, length: data.len() as u16, pid });
+        }
+        self.bus.write_data_out(data, pid);
+    }
+
+    /// Start the OUT data stage of the active control transfer, whose data was prepared along with the SETUP packet
+    fn write_data_out_prepared(&mut self, pid: bool) {
+        #[cfg(feature = "trace")]
+        {
+            let (dev_addr, endpoint, transfer_type) = self.trace_recipient;
+            let length = self.active_transfer.as_ref().map_or(0, |(_, transfer)| transfer.length());
+            self.trace(trace::TraceRecord::DataOut { dev_addr, endpoint, transfer_type, length, pid });
+        }
+        self.bus.write_data_out_prepared(pid);
+    }
+
+    /// Pass a record to the trace hook, if there is one
+    #[cfg(feature = "trace")]
+    fn trace(&mut self, record: trace::TraceRecord) {
+        let frame = self.frame_timer.frames();
+        if let Some(hook) = &mut self.trace_hook {
+            hook.trace(frame, record);
+        }
+    }
+
+    /// Report a change of the host's phase to the trace hook, if there was one since the last call
+    fn trace_phase(&mut self) {
+        #[cfg(feature = "trace")]
+        {
+            let phase = self.host_phase();
+            if phase != self.traced_phase {
+                let from = core::mem::replace(&mut self.traced_phase, phase);
+                self.trace(trace::TraceRecord::Phase { from, to: phase });
+            }
+        }
+    }
+
+    /// Remove the device on the root port after its discovery failed, and enumerate it again after a jittered delay
     ///
-    /// If drivers want to mess with the device address, they can do so manually.
-    fn set_address(&mut self, address: DeviceAddress) -> Result<(), ControlError> {
-        self.control_out(
-            None,
-            None,
-            SetupPacket::new(
-                UsbDirection::Out,
-                RequestType::Standard,
-                Recipient::Device,
-                Request::SET_ADDRESS,
-                address.into(),
-                0,
-                0,
-            ),
-            &[],
-        )
+    /// Returns the number of the attempt, or `None` if the device is left dormant instead.
+    fn retry_discovery(
+        &mut self,
+        dev_addr: DeviceAddress,
+        drivers: &mut [&mut dyn driver::Driver<
+            B,
+            MAX_PIPES,
+            CONTROL_QUEUE_DEPTH,
+            CONTROL_BUFFER_SIZE,
+        >],
+    ) -> Option<u8> {
+        if self.discovery_attempts >= self.enumeration_config.discovery_retries {
+            return None;
+        }
+        let device = self.find_device_mut(dev_addr).filter(|device| device.hub_port.is_none())?;
+        device.detached = Some(DetachReason::Recovery);
+        self.discovery_attempts += 1;
+        debug!("Discovery failed, retrying enumeration (attempt {})", self.discovery_attempts);
+        self.process_hub_port_detach(drivers);
+        let EnumerationConfig { reset_0_delay, retry_jitter, .. } = self.enumeration_config;
+        let delay = entropy::jittered(reset_0_delay as u32, retry_jitter as u32, &mut self.entropy);
+        self.retry_delay = Some(delay.min(u16::MAX as u32) as u16);
+        self.execute_enumeration_action(EnumerationAction::ResetBus);
+        self.state = State::Enumeration(EnumerationState::Reset0);
+        Some(self.discovery_attempts)
     }
 
-    /// Initiate a `Set_Configuration` (0x09) control OUT transfer
+    /// The root device was detached, so all the devices are gone.
+    fn detach_all(
+        &mut self,
+        drivers: &mut [&mut dyn driver::Driver<
+            B,
+            MAX_PIPES,
+            CONTROL_QUEUE_DEPTH,
+            CONTROL_BUFFER_SIZE,
+        >],
+    ) {
+        for device in self.devices.iter().flatten() {
+            // devices behind hubs are gone because the root device (a hub) was removed
+            let reason = if device.hub_port.is_some() { DetachReason::ParentRemoved } else { DetachReason::Unplugged };
+            for driver in drivers.iter_mut() {
+                driver.detached_with_reason(device.address, reason);
+            }
+        }
+        self.reset();
+    }
+
+    /// Notify drivers about devices which were marked as detached (e.g. removed from a hub port), and clean up after them
+    fn process_hub_port_detach(
+        &mut self,
+        drivers: &mut [&mut dyn driver::Driver<
+            B,
+            MAX_PIPES,
+            CONTROL_QUEUE_DEPTH,
+            CONTROL_BUFFER_SIZE,
+        >],
+    ) {
+        for i in 0..MAX_DEVICES {
+            if let Some(Device { address, detached: Some(reason), .. }) = self.devices[i] {
+                self.devices[i] = None;
+                match self.state {
+                    State::Discovery(dev_addr, _) | State::Verifying(dev_addr, _, _) | State::Configuring(dev_addr, _, _) if dev_addr == address => {
+                        // the device was still being set up
+                        self.state = State::Idle;
+                        if let Some((None, _)) = self.active_transfer {
+                            self.bus.stop_transaction();
+                            self.active_transfer = None;
+                        }
+                    }
+                    _ => {}
+                }
+                for driver in drivers.iter_mut() {
+                    driver.detached_with_reason(address, reason);
+                }
+                self.cleanup(address);
+            }
+        }
+    }
+
+    /// Reset the entire host stack
     ///
-    /// This is a convenience wrapper around [`UsbHost::control_out`] for the `Set_Configuration` standard request.
+    /// This resets the host controller (via [`bus::HostBus::reset_controller`]) and resets
+    /// all internal state of the UsbHost to their defaults.
     ///
-    /// Normally this does not need to be called manually. Instead the configuration is selected by the usb host during the discovery phase,
-    /// depending on the drivers.
+    /// Any current transfer will never complete, and any pipes created will no longer be valid.
+    /// At the end of the reset, no device will be connected.
     ///
-    /// Changing the configuration after the discovery phase is not supported yet by the driver interface. While it will probably work, make sure
-    /// your drivers are aware of it and can handle this situation.
-    pub fn set_configuration(
-        &mut self,
-        dev_addr: DeviceAddress,
-        pipe_id: Option<PipeId>,
-        configuration: u8,
-    ) -> Result<(), ControlError> {
-        self.control_out(
-            Some(dev_addr),
-            pipe_id,
-            SetupPacket::new(
-                UsbDirection::Out,
-                RequestType::Standard,
-                Recipient::Device,
-                Request::SET_CONFIGURATION,
-                configuration as u16,
-                0,
-                0,
-            ),
-            &[],
-        )
+    /// **Drivers must never call this method.**
+    ///
+    /// NOTE: since the host does not keep track of any drivers, it cannot reset the drivers' internal state.
+    ///   It is up to application code to reset / re-initialize the drivers after resetting the host stack.
+    ///   Any `PipeId` or `DeviceAddress` held by the application or driver(s) must be considered invalid after a reset.
+    ///   Continuing to use them can lead to strange behavior, since after a reset, pipe and device addresses *will* be re-used.
+    pub fn reset(&mut self) {
+        self.bus.reset_controller();
+        self.bus_nak_policy = None;
+        self.state = State::Enumeration(EnumerationState::WaitForDevice);
+        self.active_transfer = None;
+        self.channel_transfers = [const { None }; MAX_CHANNELS];
+        self.last_control_in = None;
+        self.control_queue.clear();
+        self.clearing_halt = None;
+        self.recovering_endpoint = None;
+        self.selecting_interface = None;
+        self.suspended = false;
+        self.auto_suspended = false;
+        self.resume = None;
+        self.pings = [None; MAX_PIPES];
+        self.pinging = None;
+        self.unresponsive = None;
+        self.failed = None;
+        self.cancelled = None;
+        self.cancelled_recovery = None;
+        self.resetting_port = false;
+        self.host_error = None;
+        self.discovery_attempts = 0;
+        self.retry_delay = None;
+        self.addresses = address::AddressTable::new();
+        if let Some(reports) = &mut self.report_buffer {
+            reports.clear();
+        }
+        self.sof_interrupt = false;
+        if let Some(storage) = &mut self.configuration_storage {
+            storage.clear();
+        }
+        self.pipes = [None; MAX_PIPES];
+        self.devices = [None; MAX_DEVICES];
+        self.trace_phase();
     }
 
-    /// Create a pipe for interrupt transfers
+    fn alloc_pipe(&mut self, context: u16) -> Option<(PipeId, &mut Option<Pipe>)> {
+        let owner = self.configuring_driver;
+        self.pipes
+            .iter_mut()
+            .zip(self.pipe_owners.iter_mut().zip(self.pipe_nak_policies.iter_mut()))
+            .enumerate()
+            .find(|(_, (slot, _))| slot.is_none())
+            .map(|(i, (slot, (slot_owner, nak_policy)))| {
+                *slot_owner = owner;
+                *nak_policy = None;
+                (PipeId(i as u8, context), slot)
+            })
+    }
+
+    /// Change which driver is informed about the transfers on the given pipe
     ///
-    /// This method is meant to be called by drivers.
+    /// Pipes created from within [`configured`](driver::Driver::configured) belong to the driver being configured: their
+    /// `completed_*`, [`stall`](driver::Driver::stall) and [`transfer_failed`](driver::Driver::transfer_failed) callbacks are only
+    /// called on that driver. Pipes created at any other time (e.g. from a driver's own `poll` method) do not belong to any
+    /// driver, and their callbacks are called on all drivers. Passing `None` turns a pipe into such a shared pipe.
     ///
-    /// Transfers on the interrupt pipe are always initiated by the host controller at the appropriate times.
+    /// `driver` is the index of the driver in the slice passed to [`poll`](UsbHost::poll).
+    pub fn set_pipe_owner(&mut self, pipe_id: PipeId, driver: Option<usize>) {
+        self.pipe_owners[pipe_id.0 as usize] = driver.and_then(|index| u8::try_from(index).ok());
+    }
+
+    /// Record which interface the endpoint of the given bulk or interrupt pipe belongs to
     ///
-    /// Drivers must implement the [`completed_in`](driver::Driver::completed_in) / [`completed_out`](driver::Driver::completed_out) callbacks to
-    /// consume / produce data for the pipe as needed. The returned `PipeId` will be passed to those callbacks for the
-    /// driver to be able to associate the calls with an individual pipe they created.
+    /// The host does not know which interface an endpoint was taken from, since pipes are created from the endpoint
+    /// number alone. When the interface is known, selecting one of its alternate settings with
+    /// [`set_interface`](UsbHost::set_interface) resets the data toggle of the pipe, like the device does for the endpoint.
+    /// Passing `None` (the default for new pipes) leaves the pipe's toggle alone.
+    pub fn set_pipe_interface(&mut self, pipe_id: PipeId, interface: Option<u8>) {
+        if let Some(Pipe::Bulk { interface: pipe_interface, .. } | Pipe::Interrupt { interface: pipe_interface, .. }) =
+            &mut self.pipes[pipe_id.0 as usize]
+        {
+            *pipe_interface = interface;
+        }
+    }
+
+    /// Drivers to pass the callbacks of the given pipe to (see [`UsbHost::set_pipe_owner`])
+    fn pipe_drivers<'a, 'd>(
+        &self,
+        pipe_id: PipeId,
+        drivers: &'a mut [&'d mut dyn driver::Driver<
+            B,
+            MAX_PIPES,
+            CONTROL_QUEUE_DEPTH,
+            CONTROL_BUFFER_SIZE,
+        >],
+    ) -> &'a mut [&'d mut dyn driver::Driver<
+        B,
+        MAX_PIPES,
+        CONTROL_QUEUE_DEPTH,
+        CONTROL_BUFFER_SIZE,
+    >] {
+        Self::owned_by(self.pipe_owners[pipe_id.0 as usize], drivers)
+    }
+
+    fn owned_by<'a, 'd>(
+        owner: Option<u8>,
+        drivers: &'a mut [&'d mut dyn driver::Driver<
+            B,
+            MAX_PIPES,
+            CONTROL_QUEUE_DEPTH,
+            CONTROL_BUFFER_SIZE,
+        >],
+    ) -> &'a mut [&'d mut dyn driver::Driver<
+        B,
+        MAX_PIPES,
+        CONTROL_QUEUE_DEPTH,
+        CONTROL_BUFFER_SIZE,
+    >] {
+        match owner.map(usize::from) {
+            // falls back to all drivers, if the application passes a shorter list than before
+            Some(index) if index < drivers.len() => &mut drivers[index..=index],
+            _ => drivers,
+        }
+    }
+
+    /// Create a pipe for control transfers
+    ///
+    /// This method is meant to be called by drivers.
+    ///
+    /// The returned `PipeId` can be used to initiate transfers by calling [`control_out`](UsbHost::control_out),
+    /// [`control_in`](UsbHost::control_in) or one of their wrappers.
     ///
     /// Returns `None` if the maximum number of supported pipes has been reached.
-    pub fn create_interrupt_pipe(
-        &mut self,
-        dev_addr: DeviceAddress,
-        ep_number: u8,
-        direction: UsbDirection,
-        size: u16,
-        interval: u8,
-    ) -> Option<PipeId> {
-        if let Some(bus::InterruptPipe { bus_ref, ptr }) = self.bus().create_interrupt_pipe(dev_addr, ep_number, direction, size, interval) {
-            if let Some((id, slot)) = self.alloc_pipe() {
-                slot.replace(Pipe::Interrupt {
-                    dev_addr,
-                    bus_ref,
-                    direction,
-                    size,
-                    ptr,
-                });
-                Some(id)
-            } else {
-                self.bus().release_interrupt_pipe(bus_ref);
-                // the host has no more free pipe slots
-                None
-            }
-        } else {
-            // the bus has no free interrupt pipes
-            None
-        }
+    pub fn create_control_pipe(&mut self, dev_addr: DeviceAddress) -> Option<PipeId> {
+        self.create_control_pipe_with_context(dev_addr, 0)
     }
 
-    pub fn bus(&mut self) -> &mut B {
-        &mut self.bus
+    /// Create a pipe for control transfers, with the given context value
+    ///
+    /// Same as [`create_control_pipe`](UsbHost::create_control_pipe), but the returned `PipeId` carries
+    /// the given `context` (see [`PipeId::context`]).
+    pub fn create_control_pipe_with_context(&mut self, dev_addr: DeviceAddress, context: u16) -> Option<PipeId> {
+        self.alloc_pipe(context).map(|(id, slot)| {
+            slot.replace(Pipe::Control { dev_addr });
+            id
+        })
     }
 
-    pub fn release_pipe(&mut self, pipe_id: PipeId) {}
+    /// Enable/disable PRE packets for upcoming transfers
+    ///
+    /// The host enables them automatically for low speed devices attached to a hub (see [`HostBus::set_low_speed_hub`]),
+    /// and overrides this setting whenever a transfer is initiated.
+    pub fn ls_preamble(&mut self, enable: bool) {
+        self.bus.ls_preamble(enable);
+    }
 
-    /// Clean up after device was removed
-    fn cleanup(&mut self, addr: DeviceAddress) {
-        for pipe in self.pipes.iter_mut() {
-            match pipe {
-                Some(Pipe::Control { dev_addr } | Pipe::Interrupt { dev_addr, .. })
-                    if *dev_addr == addr =>
-                {
-                    *pipe = None;
-                }
-                _ => {}
+    /// Hub port through which the given device is attached, if it is a low speed device behind a hub
+    ///
+    /// A `dev_addr` of `None` refers to the device that is currently being enumerated.
+    fn low_speed_hub(&self, dev_addr: Option<DeviceAddress>) -> Option<HubPort> {
+        match (dev_addr, self.state) {
+            (Some(dev_addr), _) => self
+                .devices
+                .iter()
+                .flatten()
+                .find(|d| d.address == dev_addr && d.speed == types::ConnectionSpeed::Low)
+                .and_then(|d| d.hub_port),
+            (None, State::HubEnumeration(hub_port, state)) => {
+                (enumeration::enumeration_speed(state) == Some(types::ConnectionSpeed::Low)).then_some(hub_port)
             }
+            (None, _) => None,
+        }
+    }
+
+    /// Set the recipient of the next transfer on the bus, including the hub port for low speed devices behind a hub
+    fn set_recipient(&mut self, dev_addr: Option<DeviceAddress>, endpoint: u8, transfer_type: TransferType) {
+        #[cfg(feature = "trace")]
+        {
+            self.trace_recipient = (dev_addr, endpoint, transfer_type);
+        }
+        let hub = self.low_speed_hub(dev_addr);
+        self.bus.set_recipient(dev_addr, endpoint, transfer_type);
+        self.bus.set_low_speed_hub(hub);
+        if transfer_type == TransferType::Control {
+            self.bus.set_max_packet_size_0(self.max_packet_size_0(dev_addr));
+        }
+    }
+
+    /// Initiate an IN transfer on the control endpoint of the given device
+    ///
+    /// If a `pipe_id` is given, the driver that set up the pipe will be able to associate the subsequent
+    /// [`completed_control`](driver::Driver::completed_control) callback with this transfer.
+    /// Otherwise the transfer will not be reported to any drivers.
+    ///
+    /// The number of bytes transferred is determined by the `length` from the setup packet. A `length` larger than the
+    /// host's control buffer is reduced to the size of the buffer, so at most `CONTROL_BUFFER_SIZE` bytes are received
+    /// (see [Control buffer](UsbHost#control-buffer)).
+    ///
+    /// If there is currently a transfer in progress, a transfer on a pipe is queued, and started once the bus becomes
+    /// idle. Queued transfers are started in the order in which they were submitted, and reported via `completed_control`
+    /// as usual. If the queue is full, or no `pipe_id` is given, [`ControlError::WouldBlock`] is returned instead, and no
+    /// attempt is made to initiate the transfer.
+    ///
+    /// This method is usually called by drivers, not by application code.
+    pub fn control_in(
+        &mut self,
+        dev_addr: Option<DeviceAddress>,
+        pipe_id: Option<PipeId>,
+        setup: SetupPacket,
+    ) -> Result<(), ControlError> {
+        if let Some((dev_addr, pipe_id)) = self.queue_target(dev_addr, pipe_id)? {
+            return self.enqueue_control(Some(queue::QueuedControl::new_in(dev_addr, pipe_id, setup)));
         }
+        self.control_in_with_skip(dev_addr, pipe_id, setup, 0)
+    }
 
-        if self.active_transfer.is_some() {
-            self.active_transfer.take();
+    /// Returns the device and pipe to queue a control transfer for, or `None` if it should be started right away
+    ///
+    /// Only transfers on pipes are queued. To keep them in order, new transfers are queued as long as there are
+    /// others waiting.
+    fn queue_target(
+        &self,
+        dev_addr: Option<DeviceAddress>,
+        pipe_id: Option<PipeId>,
+    ) -> Result<Option<(DeviceAddress, PipeId)>, ControlError> {
+        self.validate_control_pipe(dev_addr, pipe_id)?;
+        if !self.bus_busy() && self.control_queue.is_empty() {
+            return Ok(None);
         }
+        Ok(dev_addr.zip(pipe_id))
+    }
+
+    fn enqueue_control(&mut self, transfer: Option<queue::QueuedControl>) -> Result<(), ControlError> {
+        match transfer {
+            Some(transfer) if self.control_queue.push(transfer) => Ok(()),
+            _ => Err(ControlError::WouldBlock),
+        }
+    }
+
+    /// Initiate a control IN transfer, discarding the first `skip` bytes of the data stage
+    ///
+    /// Used to read descriptors which are larger than the control buffer, one window at a time.
+    fn control_in_with_skip(
+        &mut self,
+        dev_addr: Option<DeviceAddress>,
+        pipe_id: Option<PipeId>,
+        setup: SetupPacket,
+        skip: u16,
+    ) -> Result<(), ControlError> {
+        self.validate_control_pipe(dev_addr, pipe_id)?;
+        if self.bus_busy() {
+            return Err(ControlError::WouldBlock);
+        }
+
+        let mut setup = setup;
+        if setup.length > self.bus.max_control_data_size() || skip > 0 {
+            // the data stage is too large for the bus to handle in one go: split it into individual packets.
+            let max_packet_size = self.max_packet_size_0(dev_addr);
+            setup.length = setup.length.min(skip.saturating_add(CONTROL_BUFFER_SIZE as u16));
+            self.control_buffer.start(skip as usize);
+            self.active_transfer = Some((pipe_id, transfer::Transfer::new_control_in_chunked(setup.length, max_packet_size as u16)));
+        } else {
+            setup.length = setup.length.min(CONTROL_BUFFER_SIZE as u16);
+            self.control_buffer.start(0);
+            self.active_transfer = Some((pipe_id, transfer::Transfer::new_control_in(setup.length)));
+        }
+        self.control_started = Some(self.frame_timer.frames());
+        self.transfer_started = self.frame_timer.frames();
+        self.last_control_in = None;
+        self.selecting_interface = None;
+        self.set_recipient(dev_addr, 0, TransferType::Control);
+        self.apply_nak_policy(pipe_id);
+        self.write_setup(setup);
+
+        Ok(())
+    }
+
+    /// Maximum packet size of the control endpoint of the given device (the default one, if the device is not known)
+    fn max_packet_size_0(&self, dev_addr: Option<DeviceAddress>) -> u8 {
+        dev_addr
+            .and_then(|addr| self.devices.iter().flatten().find(|d| d.address == addr))
+            .map_or(DEFAULT_MAX_PACKET_SIZE_0, |d| d.max_packet_size_0)
+    }
+
+    /// Data received by the most recent control IN transfer on the given pipe
+    ///
+    /// This is the same data that was passed to [`completed_control`](driver::Driver::completed_control). It stays available
+    /// until the next control transfer is started, so that drivers can parse it outside of the callback (e.g. from their own
+    /// `poll` method, which has access to the host), without copying it. This is useful for class or vendor protocols which
+    /// decide on the next request based on the response to the previous one. The data is kept in a buffer owned by the host,
+    /// so bulk, interrupt and isochronous transfers in the meantime do not affect it.
+    ///
+    /// Returns `None` if the most recent control IN transfer was not made on this pipe, or another control transfer was
+    /// started since. Note that control transfers which were queued while the bus was busy are started at the end of `poll`.
+    pub fn received_control_data(&self, pipe_id: PipeId) -> Option<&[u8]> {
+        match self.last_control_in {
+            Some((last_pipe_id, length)) if last_pipe_id == pipe_id => Some(self.control_buffer.data(length)),
+            _ => None,
+        }
+    }
+
+    /// Initiate an OUT transfer on the control endpoint of the given device
+    ///
+    /// If a `pipe_id` is given, the driver that set up the pipe will be able to associate the [`driver::Driver::completed_control`]
+    /// call with this transfer.
+    /// Otherwise the transfer will not be reported to any drivers.
+    ///
+    /// The `length` of the `setup` packet is usually equal to the size of the `data` slice. It MUST NOT be smaller. If it
+    /// is larger, the data stage ends early: if the data fills a whole number of packets, it is terminated with a
+    /// zero-length packet, so that the device can tell that the data stage is complete.
+    ///
+    /// If there is currently a transfer in progress, a transfer on a pipe is queued like for [`control_in`](UsbHost::control_in).
+    /// The data is copied into the queue, which is only possible for up to 64 bytes. Otherwise [`ControlError::WouldBlock`]
+    /// is returned, and no attempt is made to initiate the transfer.
+    ///
+    /// This method is usually called by drivers, not by application code.
+    pub fn control_out(
+        &mut self,
+        dev_addr: Option<DeviceAddress>,
+        pipe_id: Option<PipeId>,
+        setup: SetupPacket,
+        data: &[u8],
+    ) -> Result<(), ControlError> {
+        if let Some((dev_addr, pipe_id)) = self.queue_target(dev_addr, pipe_id)? {
+            return self.enqueue_control(queue::QueuedControl::new_out(dev_addr, pipe_id, setup, data));
+        }
+        self.start_control_out(dev_addr, pipe_id, setup, data)
+    }
+
+    fn start_control_out(
+        &mut self,
+        dev_addr: Option<DeviceAddress>,
+        pipe_id: Option<PipeId>,
+        setup: SetupPacket,
+        data: &[u8],
+    ) -> Result<(), ControlError> {
+        self.validate_control_pipe(dev_addr, pipe_id)?;
+
+        if self.bus_busy() {
+            return Err(ControlError::WouldBlock);
+        }
+
+        let mut transfer = transfer::Transfer::new_control_out(data.len() as u16);
+        let max_packet_size = self.max_packet_size_0(dev_addr) as usize;
+        if setup.length as usize > data.len() && data.len().checked_rem(max_packet_size) == Some(0) {
+            // the data stage starts with DATA1, and the PID alternates with every packet
+            transfer = transfer.with_zlp((data.len() / max_packet_size).is_multiple_of(2));
+        }
+        self.active_transfer = Some((pipe_id, transfer));
+        self.control_started = Some(self.frame_timer.frames());
+        self.transfer_started = self.frame_timer.frames();
+        self.last_control_in = None;
+        // the toggles of the interface's pipes are reset once the device acknowledged the new setting
+        self.selecting_interface = dev_addr
+            .filter(|_| setup.request_type == 0x01 && setup.request == Request::SET_INTERFACE)
+            .map(|dev_addr| (dev_addr, setup.index as u8));
+        self.set_recipient(dev_addr, 0, TransferType::Control);
+        self.apply_nak_policy(pipe_id);
+        self.bus.prepare_data_out(data);
+        self.write_setup(setup);
+
+        Ok(())
+    }
+
+    fn validate_control_pipe(
+        &self,
+        dev_addr: Option<DeviceAddress>,
+        pipe_id: Option<PipeId>,
+    ) -> Result<(), ControlError> {
+        let is_valid = match (dev_addr, pipe_id) {
+            (None, None) | (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (Some(given_dev_addr), Some(pipe_id)) => {
+                // Index safety: a PipeId that is not in the 0..MAX_PIPES range (valid indices for self.pipes)
+                //   should not be produced and indicates a bug within UsbHost.
+                if let Some(Pipe::Control { dev_addr }) = self.pipes[pipe_id.0 as usize] {
+                    dev_addr == given_dev_addr
+                } else {
+                    false
+                }
+            }
+        };
+        if is_valid {
+            Ok(())
+        } else {
+            Err(ControlError::InvalidPipe)
+        }
+    }
+
+    /// Initiate a `Get_Descriptor` (0x06) control IN transfer
+    ///
+    /// This is a convenience wrapper around [`UsbHost::control_in`], for the `Get_Descriptor` standard request.
+    ///
+    /// The `descriptor_type` can be one of the `TYPE_*` constants defined in the [`descriptor`] module, but usally these
+    /// are already requested during the discovery phase.
+    ///
+    /// Thus usually this method will be used to request class- or vendor-specific descriptors.
+    pub fn get_descriptor(
+        &mut self,
+        dev_addr: Option<DeviceAddress>,
+        pipe_id: Option<PipeId>,
+        recipient: Recipient,
+        descriptor_type: u8,
+        descriptor_index: u8,
+        length: u16,
+    ) -> Result<(), ControlError> {
+        self.control_in(
+            dev_addr,
+            pipe_id,
+            SetupPacket::new(
+                UsbDirection::In,
+                RequestType::Standard,
+                recipient,
+                Request::GET_DESCRIPTOR,
+                ((descriptor_type as u16) << 8) | (descriptor_index as u16),
+                0,
+                length,
+            ),
+        )
+    }
+
+    /// Initiate a `Get_Descriptor` control IN transfer for a string descriptor
+    ///
+    /// This method is meant to be called by drivers, or application code holding a control pipe for the device.
+    ///
+    /// The `index` is one of the string indices found in other descriptors (e.g. [`DeviceDescriptor::product_index`](descriptor::DeviceDescriptor::product_index)).
+    /// Index 0 is special: it returns the list of supported language IDs (see [`descriptor::language_ids`]) and requires a `lang_id` of 0.
+    /// For all other indices, `lang_id` should be one of the supported language IDs (usually [`descriptor::LANG_ID_EN_US`]).
+    ///
+    /// The descriptor is passed to [`completed_control`](driver::Driver::completed_control). Use [`descriptor::parse::any_descriptor`] to strip the
+    /// framing, and [`descriptor::decode_string`] to decode the string.
+    pub fn get_string_descriptor(
+        &mut self,
+        dev_addr: DeviceAddress,
+        pipe_id: Option<PipeId>,
+        index: u8,
+        lang_id: u16,
+        length: u16,
+    ) -> Result<(), ControlError> {
+        self.control_in(
+            Some(dev_addr),
+            pipe_id,
+            SetupPacket::new(
+                UsbDirection::In,
+                RequestType::Standard,
+                Recipient::Device,
+                Request::GET_DESCRIPTOR,
+                ((descriptor::TYPE_STRING as u16) << 8) | (index as u16),
+                lang_id,
+                length,
+            ),
+        )
+    }
+
+    /// Initiate a `Get_Descriptor` control IN transfer for the BOS descriptor
+    ///
+    /// This method is meant to be called by drivers, or application code holding a control pipe for the device.
+    ///
+    /// Only devices with a [`usb_release`](descriptor::DeviceDescriptor::usb_release) of 2.1 or higher have a BOS descriptor.
+    /// Its length is not known in advance: request the 5 bytes of the header first, and then the full
+    /// [`total_length`](descriptor::BosDescriptor::total_length) (see [`descriptor::parse::bos_descriptor`]). The device
+    /// capabilities contained in the full descriptor can be read with [`descriptor::device_capabilities`].
+    pub fn get_bos_descriptor(&mut self, dev_addr: DeviceAddress, pipe_id: Option<PipeId>, length: u16) -> Result<(), ControlError> {
+        self.get_descriptor(Some(dev_addr), pipe_id, Recipient::Device, descriptor::TYPE_BOS, 0, length)
+    }
+
+    /// Initiate a `Get_Descriptor` control IN transfer for the device qualifier descriptor
+    ///
+    /// This method is meant to be called by drivers, or application code holding a control pipe for the device.
+    ///
+    /// The descriptor is 10 bytes long, and can be parsed with [`descriptor::parse::device_qualifier_descriptor`]. Devices that
+    /// are not high-speed capable respond with a STALL.
+    pub fn get_device_qualifier_descriptor(&mut self, dev_addr: DeviceAddress, pipe_id: Option<PipeId>) -> Result<(), ControlError> {
+        self.get_descriptor(Some(dev_addr), pipe_id, Recipient::Device, descriptor::TYPE_DEVICE_QUALIFIER, 0, 10)
+    }
+
+    /// Initiate a `Get_Status` (0x00) control IN transfer
+    ///
+    /// This is a convenience wrapper around [`UsbHost::control_in`] for the `Get_Status` standard request.
+    ///
+    /// The `index` is the interface or endpoint number (for endpoints including the direction bit), depending on the
+    /// `recipient`. For the `Device` recipient it must be 0. The device responds with two bytes of status information.
+    pub fn get_status(
+        &mut self,
+        dev_addr: DeviceAddress,
+        pipe_id: Option<PipeId>,
+        recipient: Recipient,
+        index: u16,
+    ) -> Result<(), ControlError> {
+        self.control_in(
+            Some(dev_addr),
+            pipe_id,
+            SetupPacket::new(UsbDirection::In, RequestType::Standard, recipient, Request::GET_STATUS, 0, index, 2),
+        )
+    }
+
+    /// Initiate a `Set_Feature` (0x03) control OUT transfer
+    ///
+    /// This is a convenience wrapper around [`UsbHost::control_out`] for the `Set_Feature` standard request.
+    ///
+    /// The `feature` selector must match the `recipient`, e.g. [`Request::FEATURE_DEVICE_REMOTE_WAKEUP`] for the device,
+    /// or [`Request::FEATURE_ENDPOINT_HALT`] for an endpoint. The `index` is interpreted as for [`get_status`](UsbHost::get_status).
+    pub fn set_feature(
+        &mut self,
+        dev_addr: DeviceAddress,
+        pipe_id: Option<PipeId>,
+        recipient: Recipient,
+        index: u16,
+        feature: u16,
+    ) -> Result<(), ControlError> {
+        self.control_out(
+            Some(dev_addr),
+            pipe_id,
+            SetupPacket::new(UsbDirection::Out, RequestType::Standard, recipient, Request::SET_FEATURE, feature, index, 0),
+            &[],
+        )
+    }
+
+    /// Initiate a `Clear_Feature` (0x01) control OUT transfer
+    ///
+    /// This is a convenience wrapper around [`UsbHost::control_out`] for the `Clear_Feature` standard request.
+    /// The parameters are the same as for [`set_feature`](UsbHost::set_feature).
+    ///
+    /// To clear the halt condition of an endpoint, use [`clear_halt`](UsbHost::clear_halt) instead, which also resets the
+    /// data toggle of affected bulk pipes.
+    pub fn clear_feature(
+        &mut self,
+        dev_addr: DeviceAddress,
+        pipe_id: Option<PipeId>,
+        recipient: Recipient,
+        index: u16,
+        feature: u16,
+    ) -> Result<(), ControlError> {
+        self.control_out(
+            Some(dev_addr),
+            pipe_id,
+            SetupPacket::new(UsbDirection::Out, RequestType::Standard, recipient, Request::CLEAR_FEATURE, feature, index, 0),
+            &[],
+        )
+    }
+
+    /// Allow or forbid the device to signal remote wakeup while the bus is suspended
+    ///
+    /// This sets or clears the `DEVICE_REMOTE_WAKEUP` feature, see [`set_feature`](UsbHost::set_feature). Devices which support
+    /// remote wakeup indicate so in the attributes of their configuration descriptor. It is disabled after a reset.
+    pub fn set_remote_wakeup(&mut self, dev_addr: DeviceAddress, pipe_id: Option<PipeId>, enable: bool) -> Result<(), ControlError> {
+        if enable {
+            self.set_feature(dev_addr, pipe_id, Recipient::Device, 0, Request::FEATURE_DEVICE_REMOTE_WAKEUP)
+        } else {
+            self.clear_feature(dev_addr, pipe_id, Recipient::Device, 0, Request::FEATURE_DEVICE_REMOTE_WAKEUP)
+        }
+    }
+
+    /// Initiate a `Set_Address` (0x05) control OUT transfer
+    ///
+    /// Private, since this is only used by the enumeration process.
+    ///
+    /// If drivers want to mess with the device address, they can do so manually.
+    fn set_address(&mut self, address: DeviceAddress) -> Result<(), ControlError> {
+        self.control_out(
+            None,
+            None,
+            SetupPacket::new(
+                UsbDirection::Out,
+                RequestType::Standard,
+                Recipient::Device,
+                Request::SET_ADDRESS,
+                address.into(),
+                0,
+                0,
+            ),
+            &[],
+        )
+    }
+
+    /// Initiate a `Get_Configuration` (0x08) control IN transfer
+    ///
+    /// This is a convenience wrapper around [`UsbHost::control_in`] for the `Get_Configuration` standard request.
+    ///
+    /// The device responds with a single byte: the value of its current configuration, or 0 if it is not configured.
+    /// The configuration selected by the host can be queried without a transfer, see [`UsbHost::active_configuration`].
+    pub fn get_configuration(&mut self, dev_addr: DeviceAddress, pipe_id: Option<PipeId>) -> Result<(), ControlError> {
+        self.control_in(
+            Some(dev_addr),
+            pipe_id,
+            SetupPacket::new(UsbDirection::In, RequestType::Standard, Recipient::Device, Request::GET_CONFIGURATION, 0, 0, 1),
+        )
+    }
+
+    /// Initiate a `Set_Configuration` (0x09) control OUT transfer
+    ///
+    /// This is a convenience wrapper around [`UsbHost::control_out`] for the `Set_Configuration` standard request.
+    ///
+    /// Normally this does not need to be called manually. Instead the configuration is selected by the usb host during the discovery phase,
+    /// depending on the drivers.
+    ///
+    /// Changing the configuration after the discovery phase is not supported yet by the driver interface. While it will probably work, make sure
+    /// your drivers are aware of it and can handle this situation.
+    ///
+    /// Selecting a configuration resets the data toggle of all endpoints, so the toggles of the device's pipes are reset to DATA0.
+    pub fn set_configuration(
+        &mut self,
+        dev_addr: DeviceAddress,
+        pipe_id: Option<PipeId>,
+        configuration: u8,
+    ) -> Result<(), ControlError> {
+        self.control_out(
+            Some(dev_addr),
+            pipe_id,
+            SetupPacket::new(
+                UsbDirection::Out,
+                RequestType::Standard,
+                Recipient::Device,
+                Request::SET_CONFIGURATION,
+                configuration as u16,
+                0,
+                0,
+            ),
+            &[],
+        )?;
+        self.reset_data_toggle(dev_addr, None);
+        Ok(())
+    }
+
+    /// Initiate a `Set_Interface` (0x0B) control OUT transfer, selecting an alternate setting for the given interface
+    ///
+    /// This is a convenience wrapper around [`UsbHost::control_out`] for the `Set_Interface` standard request.
+    ///
+    /// After configuration, all interfaces are in their default setting (0). Some interfaces only use bandwidth in an alternate
+    /// setting (e.g. streaming interfaces of audio devices). The endpoints of each alternate setting are reported to the drivers
+    /// during discovery, following the interface descriptor of the setting (see [`descriptor::InterfaceDescriptor::is_alternate_setting`]).
+    ///
+    /// Selecting a setting resets the data toggles of the interface's endpoints on the device side. Once the device acknowledged
+    /// the request, the host resets the data toggles of the bulk and interrupt pipes on that interface as well (see
+    /// [`UsbHost::set_pipe_interface`]). Pipes for the endpoints of the previous setting should no longer be used.
+    pub fn set_interface(
+        &mut self,
+        dev_addr: DeviceAddress,
+        pipe_id: Option<PipeId>,
+        interface: u8,
+        alt_setting: u8,
+    ) -> Result<(), ControlError> {
+        self.control_out(
+            Some(dev_addr),
+            pipe_id,
+            SetupPacket::new(
+                UsbDirection::Out,
+                RequestType::Standard,
+                Recipient::Interface,
+                Request::SET_INTERFACE,
+                alt_setting as u16,
+                interface as u16,
+                0,
+            ),
+            &[],
+        )
+    }
+
+    /// Create a pipe for interrupt transfers
+    ///
+    /// This method is meant to be called by drivers.
+    ///
+    /// Transfers on the interrupt pipe are always initiated by the host controller at the appropriate times.
+    ///
+    /// Drivers must implement the [`completed_in`](driver::Driver::completed_in) / [`completed_out`](driver::Driver::completed_out) callbacks to
+    /// consume / produce data for the pipe as needed. The returned `PipeId` will be passed to those callbacks for the
+    /// driver to be able to associate the calls with an individual pipe they created.
+    ///
+    /// Returns `None` if the maximum number of supported pipes has been reached, or the `size` is zero.
+    pub fn create_interrupt_pipe(
+        &mut self,
+        dev_addr: DeviceAddress,
+        ep_number: u8,
+        direction: UsbDirection,
+        size: u16,
+        interval: u8,
+    ) -> Option<PipeId> {
+        self.create_interrupt_pipe_with_context(dev_addr, ep_number, direction, size, interval, 0)
+    }
+
+    /// Create an interrupt pipe, with the given context value
+    ///
+    /// Same as [`create_interrupt_pipe`](UsbHost::create_interrupt_pipe), but the `PipeId` passed to the
+    /// `completed_in` / `completed_out` callbacks carries the given `context` (see [`PipeId::context`]).
+    ///
+    /// The `interval` is the raw `bInterval` value from the endpoint descriptor. It is interpreted according to the speed
+    /// of the device (see [`PollingInterval`]), and passed on to the host bus in frames.
+    ///
+    /// Pipes for low speed devices attached to a hub are created with [`bus::InterruptPipeHw::create_low_speed_interrupt_pipe`],
+    /// which is not supported by all host buses.
+    ///
+    /// If the host bus does not manage interrupt pipes (see [`bus::InterruptPipeHw`]), the host runs the transactions
+    /// itself, in between other transfers. The `size` is then limited to a single packet of 64 bytes.
+    pub fn create_interrupt_pipe_with_context(
+        &mut self,
+        dev_addr: DeviceAddress,
+        ep_number: u8,
+        direction: UsbDirection,
+        size: u16,
+        interval: u8,
+        context: u16,
+    ) -> Option<PipeId> {
+        if size == 0 {
+            return None;
+        }
+        let speed = self.device_speed(dev_addr).unwrap_or(types::ConnectionSpeed::Full);
+        let interval = PollingInterval::from_descriptor(interval, speed, TransferType::Interrupt);
+        let frames = interval.frames().min(u8::MAX as u16) as u8;
+        let hub = self.low_speed_hub(Some(dev_addr));
+        let bus_ref = match self.bus.interrupt_pipe_hw() {
+            Some(hw) => {
+                let bus_pipe = match hub {
+                    Some(hub) => hw.create_low_speed_interrupt_pipe(dev_addr, ep_number, direction, size, frames, hub),
+                    None => hw.create_interrupt_pipe(dev_addr, ep_number, direction, size, frames),
+                };
+                // the bus has no free interrupt pipes
+                Some(bus_pipe?.bus_ref)
+            }
+            // the host runs the transactions itself, one packet each
+            None if size as usize > MAX_HOST_INTERRUPT_SIZE => return None,
+            None => None,
+        };
+        let now = self.frame_timer.frames();
+        if let Some((id, slot)) = self.alloc_pipe(context) {
+            slot.replace(Pipe::Interrupt {
+                dev_addr,
+                bus_ref,
+                endpoint: ep_number,
+                direction,
+                size,
+                interval,
+                context,
+                interface: None,
+                data_toggle: false,
+                next_poll: now,
+                deferred: false,
+            });
+            self.update_sof_interrupt();
+            Some(id)
+        } else {
+            Self::release_bus_pipe(&mut self.bus, bus_ref);
+            // the host has no more free pipe slots
+            None
+        }
+    }
+
+    /// Release the host bus' part of an interrupt pipe, if it has one
+    fn release_bus_pipe(bus: &mut B, bus_ref: Option<u8>) {
+        if let (Some(bus_ref), Some(hw)) = (bus_ref, bus.interrupt_pipe_hw()) {
+            hw.release_interrupt_pipe(bus_ref);
+        }
+    }
+
+    /// Create a pipe for bulk transfers
+    ///
+    /// This method is meant to be called by drivers.
+    ///
+    /// Unlike interrupt pipes, transfers on bulk pipes are initiated by the driver, by calling [`bulk_in`](UsbHost::bulk_in)
+    /// or [`bulk_out`](UsbHost::bulk_out) (depending on the `direction`). Completion is reported via the
+    /// [`completed_in`](driver::Driver::completed_in) and [`completed_bulk_out`](driver::Driver::completed_bulk_out) callbacks respectively.
+    ///
+    /// The `max_packet_size` must be taken from the endpoint descriptor. It is used to keep track of the data toggle.
+    ///
+    /// Returns `None` if the maximum number of supported pipes has been reached, or the `max_packet_size` is zero (which
+    /// some broken devices claim in their endpoint descriptors).
+    pub fn create_bulk_pipe(
+        &mut self,
+        dev_addr: DeviceAddress,
+        ep_number: u8,
+        direction: UsbDirection,
+        max_packet_size: u16,
+    ) -> Option<PipeId> {
+        self.create_bulk_pipe_with_context(dev_addr, ep_number, direction, max_packet_size, 0)
+    }
+
+    /// Create a pipe for bulk transfers, with the given context value
+    ///
+    /// Same as [`create_bulk_pipe`](UsbHost::create_bulk_pipe), but the returned `PipeId` carries
+    /// the given `context` (see [`PipeId::context`]).
+    pub fn create_bulk_pipe_with_context(
+        &mut self,
+        dev_addr: DeviceAddress,
+        ep_number: u8,
+        direction: UsbDirection,
+        max_packet_size: u16,
+        context: u16,
+    ) -> Option<PipeId> {
+        if max_packet_size == 0 {
+            return None;
+        }
+        self.alloc_pipe(context).map(|(id, slot)| {
+            slot.replace(Pipe::Bulk {
+                dev_addr,
+                endpoint: ep_number,
+                direction,
+                max_packet_size,
+                interface: None,
+                data_toggle: false,
+            });
+            id
+        })
+    }
+
+    /// Initiate a bulk IN transfer on the given pipe, receiving up to `length` bytes
+    ///
+    /// Once the transfer is complete, the received data is passed to [`completed_in`](driver::Driver::completed_in).
+    ///
+    /// Note that the host bus may not be able to receive more than a single packet at once. To be safe, drivers should
+    /// not request more than the endpoint's maximum packet size in a single transfer.
+    ///
+    /// If there is currently a transfer in progress, [`ControlError::WouldBlock`] is returned, and no attempt is made to initiate the transfer.
+    /// If the bus has [additional channels](bus::TransferChannels), the transfer is started on a free one instead, and only
+    /// a transfer in progress on the same pipe (see [`pipe_busy`](UsbHost::pipe_busy)) blocks it.
+    pub fn bulk_in(&mut self, pipe_id: PipeId, length: u16) -> Result<(), ControlError> {
+        let (dev_addr, endpoint, max_packet_size, data_toggle) = self.validate_bulk_pipe(pipe_id, UsbDirection::In)?;
+        if self.pipe_busy(pipe_id) {
+            return Err(ControlError::WouldBlock);
+        }
+        if let Some((channel, channels)) = self.free_channel() {
+            channels.start_bulk_in(channel, dev_addr, endpoint, max_packet_size, length, data_toggle);
+            self.channel_transfers[channel as usize] = Some((pipe_id, transfer::Transfer::new_bulk_in(length)));
+            return Ok(());
+        }
+        if self.bus_busy() {
+            return Err(ControlError::WouldBlock);
+        }
+
+        self.active_transfer = Some((Some(pipe_id), transfer::Transfer::new_bulk_in(length)));
+        self.transfer_started = self.frame_timer.frames();
+        self.set_recipient(Some(dev_addr), endpoint, TransferType::Bulk);
+        self.apply_nak_policy(Some(pipe_id));
+        self.write_data_in(length, data_toggle);
+
+        Ok(())
+    }
+
+    /// Initiate a bulk OUT transfer on the given pipe, sending the given `data`
+    ///
+    /// Once the transfer is complete, [`completed_bulk_out`](driver::Driver::completed_bulk_out) is called.
+    ///
+    /// The transfer is never terminated with a zero-length packet. Use [`bulk_out_with_zlp`](UsbHost::bulk_out_with_zlp)
+    /// for protocols that require one.
+    ///
+    /// If there is currently a transfer in progress, [`ControlError::WouldBlock`] is returned, and no attempt is made to initiate the transfer.
+    /// Like [`bulk_in`](UsbHost::bulk_in), the transfer is started on an additional channel of the bus, if there is a free one.
+    pub fn bulk_out(&mut self, pipe_id: PipeId, data: &[u8]) -> Result<(), ControlError> {
+        self.bulk_out_with_zlp(pipe_id, data, ZlpPolicy::Never)
+    }
+
+    /// Initiate a bulk OUT transfer on the given pipe, terminating it with a zero-length packet according to the given `zlp` policy
+    ///
+    /// Same as [`bulk_out`](UsbHost::bulk_out) otherwise. The zero-length packet is sent as a separate packet after the data,
+    /// and [`completed_bulk_out`](driver::Driver::completed_bulk_out) is only called once it was sent.
+    pub fn bulk_out_with_zlp(&mut self, pipe_id: PipeId, data: &[u8], zlp: ZlpPolicy) -> Result<(), ControlError> {
+        let (dev_addr, endpoint, max_packet_size, data_toggle) = self.validate_bulk_pipe(pipe_id, UsbDirection::Out)?;
+        if self.pipe_busy(pipe_id) {
+            return Err(ControlError::WouldBlock);
+        }
+        let needs_zlp = zlp.needs_zlp(data.len() as u16, max_packet_size);
+        // the zero-length packet is sent by the host, so such transfers use the regular transfer path
+        if let Some((channel, channels)) = self.free_channel().filter(|_| !needs_zlp) {
+            channels.start_bulk_out(channel, dev_addr, endpoint, max_packet_size, data, data_toggle);
+            self.channel_transfers[channel as usize] = Some((pipe_id, transfer::Transfer::new_bulk_out(data.len() as u16)));
+            return Ok(());
+        }
+        if self.bus_busy() {
+            return Err(ControlError::WouldBlock);
+        }
+
+        let mut transfer = transfer::Transfer::new_bulk_out(data.len() as u16);
+        if needs_zlp {
+            // the PID toggles with each packet of the data
+            let packets = (data.len() as u16).div_ceil(max_packet_size);
+            transfer = transfer.with_zlp(data_toggle ^ (packets % 2 == 1));
+        }
+        self.active_transfer = Some((Some(pipe_id), transfer));
+        self.transfer_started = self.frame_timer.frames();
+        self.set_recipient(Some(dev_addr), endpoint, TransferType::Bulk);
+        self.apply_nak_policy(Some(pipe_id));
+        self.write_data_out(data, data_toggle);
+
+        Ok(())
+    }
+
+    fn validate_bulk_pipe(
+        &self,
+        pipe_id: PipeId,
+        expected_direction: UsbDirection,
+    ) -> Result<(DeviceAddress, u8, u16, bool), ControlError> {
+        match self.pipes[pipe_id.0 as usize] {
+            Some(Pipe::Bulk { dev_addr, endpoint, direction, max_packet_size, data_toggle, .. }) if direction == expected_direction => {
+                Ok((dev_addr, endpoint, max_packet_size, data_toggle))
+            }
+            _ => Err(ControlError::InvalidPipe),
+        }
+    }
+
+    /// Returns `true` if a transfer is in progress on the given pipe
+    ///
+    /// [`PollResult::Busy`] only covers the regular transfer path of the bus. If the bus has additional channels (see
+    /// [`bus::TransferChannels`]), bulk transfers run on those without keeping the host busy, so drivers can use this
+    /// method to check on their own pipes instead.
+    pub fn pipe_busy(&self, pipe_id: PipeId) -> bool {
+        matches!(self.active_transfer, Some((Some(active), _)) if active.0 == pipe_id.0)
+            || self.channel_transfers.iter().flatten().any(|(active, _)| active.0 == pipe_id.0)
+    }
+
+    /// Find a channel of the bus that is available for a bulk transfer
+    fn free_channel(&mut self) -> Option<(u8, &mut dyn bus::TransferChannels)> {
+        if self.resume.is_some() || self.suspended {
+            return None;
+        }
+        let channels = self.bus.transfer_channels()?;
+        let count = channels.channel_count().min(MAX_CHANNELS as u8);
+        let channel = (0..count).find(|channel| self.channel_transfers[*channel as usize].is_none())?;
+        Some((channel, channels))
+    }
+
+    fn take_channel_transfer(&mut self, channel: u8) -> Option<(PipeId, transfer::Transfer)> {
+        self.channel_transfers.get_mut(channel as usize).and_then(Option::take)
+    }
+
+    /// Abort the transfers on additional channels of the bus, for which `matches` returns `true`
+    fn stop_channel_transfers(&mut self, matches: impl Fn(&Self, PipeId) -> bool) {
+        for channel in 0..MAX_CHANNELS {
+            if let Some((pipe_id, _)) = self.channel_transfers[channel] {
+                if matches(self, pipe_id) {
+                    self.channel_transfers[channel] = None;
+                    if let Some(channels) = self.bus.transfer_channels() {
+                        channels.stop_channel(channel as u8);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Access the data received by a bulk IN transfer, on the given channel or the regular transfer path
+    ///
+    /// Takes the bus, rather than the host, so that it can be used while other parts of the host are borrowed.
+    fn bulk_data(bus: &mut B, channel: Option<u8>, length: u16) -> &[u8] {
+        match channel {
+            Some(channel) => bus.transfer_channels().map_or(&[], |channels| channels.channel_data(channel, length as usize)),
+            None => bus.received_data(length as usize),
+        }
+    }
+
+    /// Create a pipe for isochronous transfers
+    ///
+    /// Isochronous transfers are started explicitly, one packet at a time, via [`isochronous_in`](UsbHost::isochronous_in)
+    /// and [`isochronous_out`](UsbHost::isochronous_out). The host does not schedule them: to stream data, drivers need
+    /// to start one transfer per frame (see [`frame_count`](UsbHost::frame_count)).
+    ///
+    /// Returns `None` if the maximum number of supported pipes has been reached, or the `max_packet_size` is zero.
+    pub fn create_isochronous_pipe(
+        &mut self,
+        dev_addr: DeviceAddress,
+        ep_number: u8,
+        direction: UsbDirection,
+        max_packet_size: u16,
+    ) -> Option<PipeId> {
+        if max_packet_size == 0 {
+            return None;
+        }
+        let (id, slot) = self.alloc_pipe(0)?;
+        slot.replace(Pipe::Isochronous { dev_addr, endpoint: ep_number, direction, max_packet_size });
+        self.update_sof_interrupt();
+        Some(id)
+    }
+
+    /// Initiate an isochronous IN transaction on the given pipe, receiving a single packet of up to `length` bytes
+    ///
+    /// Once the packet was received, the data is passed to [`completed_in`](driver::Driver::completed_in).
+    /// The `length` is limited to the endpoint's maximum packet size.
+    ///
+    /// If there is currently a transfer in progress, [`ControlError::WouldBlock`] is returned, and no attempt is made to initiate the transfer.
+    pub fn isochronous_in(&mut self, pipe_id: PipeId, length: u16) -> Result<(), ControlError> {
+        let (dev_addr, endpoint, max_packet_size) = self.validate_isochronous_pipe(pipe_id, UsbDirection::In)?;
+        if self.bus_busy() {
+            return Err(ControlError::WouldBlock);
+        }
+
+        let length = length.min(max_packet_size);
+        self.active_transfer = Some((Some(pipe_id), transfer::Transfer::new_bulk_in(length)));
+        self.transfer_started = self.frame_timer.frames();
+        self.set_recipient(Some(dev_addr), endpoint, TransferType::Isochronous);
+        self.write_data_in(length, false);
+
+        Ok(())
+    }
+
+    /// Initiate an isochronous OUT transaction on the given pipe, sending the given `data` as a single packet
+    ///
+    /// Once the packet was sent, [`completed_bulk_out`](driver::Driver::completed_bulk_out) is called. Data exceeding
+    /// the endpoint's maximum packet size is not sent.
+    ///
+    /// If there is currently a transfer in progress, [`ControlError::WouldBlock`] is returned, and no attempt is made to initiate the transfer.
+    pub fn isochronous_out(&mut self, pipe_id: PipeId, data: &[u8]) -> Result<(), ControlError> {
+        let (dev_addr, endpoint, max_packet_size) = self.validate_isochronous_pipe(pipe_id, UsbDirection::Out)?;
+        if self.bus_busy() {
+            return Err(ControlError::WouldBlock);
+        }
+
+        let data = &data[..data.len().min(max_packet_size as usize)];
+        self.active_transfer = Some((Some(pipe_id), transfer::Transfer::new_bulk_out(data.len() as u16)));
+        self.transfer_started = self.frame_timer.frames();
+        self.set_recipient(Some(dev_addr), endpoint, TransferType::Isochronous);
+        self.write_data_out(data, false);
+
+        Ok(())
+    }
+
+    fn validate_isochronous_pipe(
+        &self,
+        pipe_id: PipeId,
+        expected_direction: UsbDirection,
+    ) -> Result<(DeviceAddress, u8, u16), ControlError> {
+        match self.pipes[pipe_id.0 as usize] {
+            Some(Pipe::Isochronous { dev_addr, endpoint, direction, max_packet_size }) if direction == expected_direction => {
+                Ok((dev_addr, endpoint, max_packet_size))
+            }
+            _ => Err(ControlError::InvalidPipe),
+        }
+    }
+
+    /// Update the data toggle of a bulk pipe, after `length` bytes (followed by a zero-length packet, if `zlp` is set) were transferred
+    fn bulk_transfer_complete(&mut self, pipe_id: PipeId, length: u16, zlp: bool) {
+        match &mut self.pipes[pipe_id.0 as usize] {
+            Some(Pipe::Bulk { max_packet_size, data_toggle, .. }) => {
+                // a zero-length transfer still consists of one packet
+                let packets = (length.div_ceil(*max_packet_size)).max(1) + zlp as u16;
+                if packets % 2 == 1 {
+                    *data_toggle = !*data_toggle;
+                }
+            }
+            // a transaction on an interrupt pipe run by the host, which is always a single packet
+            Some(Pipe::Interrupt { data_toggle, .. }) => *data_toggle = !*data_toggle,
+            _ => {}
+        }
+    }
+
+    pub fn bus(&mut self) -> &mut B {
+        &mut self.bus
+    }
+
+    /// Release a pipe that is no longer needed
+    ///
+    /// This method is meant to be called by drivers, e.g. when a device turns out to be unusable after some of its pipes
+    /// were already created. The pipe's slot is freed, and interrupt pipes are released on the host bus as well.
+    /// A transfer in progress (or queued) on the pipe is aborted, without being reported to the drivers.
+    ///
+    /// The pipes of a device are released automatically when it is detached, so there is no need to call this from
+    /// [`detached`](driver::Driver::detached).
+    ///
+    /// Afterwards the `pipe_id` must no longer be used, since the slot will be re-used for other pipes.
+    pub fn release_pipe(&mut self, pipe_id: PipeId) {
+        let index = pipe_id.0 as usize;
+        let Some(pipe) = self.pipes[index].take() else {
+            return;
+        };
+        match pipe {
+            Pipe::Interrupt { bus_ref, .. } => {
+                Self::release_bus_pipe(&mut self.bus, bus_ref);
+                self.update_sof_interrupt();
+                if let Some(reports) = &mut self.report_buffer {
+                    reports.discard(pipe_id);
+                }
+            }
+            Pipe::Isochronous { .. } => self.update_sof_interrupt(),
+            _ => {}
+        }
+        self.pings[index] = None;
+        self.control_queue.retain(|queued| queued.pipe_id.0 != pipe_id.0);
+        if self.last_control_in.is_some_and(|(last, _)| last.0 == pipe_id.0) {
+            self.last_control_in = None;
+        }
+        if self.pinging.is_some_and(|pinging| pinging.0 == pipe_id.0) {
+            self.pinging = None;
+        }
+        let active = matches!(self.active_transfer, Some((Some(active), _)) if active.0 == pipe_id.0);
+        let clearing_halt = self.clearing_halt.is_some_and(|halted| halted.0 == pipe_id.0);
+        if active || clearing_halt {
+            self.bus.stop_transaction();
+            self.active_transfer = None;
+            self.clearing_halt = None;
+        }
+        self.stop_channel_transfers(|_, active| active.0 == pipe_id.0);
+    }
+
+    /// Periodically send the given request on a control pipe, to check that the device is still responding
+    ///
+    /// This method is meant to be called by drivers, after creating the control pipe.
+    ///
+    /// Devices can stop responding without being detached, e.g. after a firmware crash. The host sends the ping whenever
+    /// it is due and the bus is idle, and no other device is being set up. Responses (including a STALL) are not
+    /// reported to the driver. Once `max_failures` pings in a row failed, the driver is informed via
+    /// [`transfer_failed`](driver::Driver::transfer_failed), and `poll` returns [`PollResult::DeviceUnresponsive`].
+    ///
+    /// Passing `None` removes the ping again. Pings are removed automatically when the device is detached.
+    pub fn set_liveness_ping(&mut self, pipe_id: PipeId, ping: Option<LivenessPing>) -> Result<(), ControlError> {
+        let Some(Pipe::Control { .. }) = self.pipes[pipe_id.0 as usize] else {
+            return Err(ControlError::InvalidPipe);
+        };
+        let due = self.frame_timer.frames().wrapping_add(ping.map_or(0, |ping| ping.interval as u32));
+        self.pings[pipe_id.0 as usize] = ping.map(|ping| Ping { pipe_id, ping, due, failures: 0 });
+        Ok(())
+    }
+
+    /// Initiate a `Clear_Feature(ENDPOINT_HALT)` control OUT transfer, to recover an endpoint after it sent a STALL
+    ///
+    /// The `endpoint` is the endpoint address, including the direction bit (e.g. `0x81` for endpoint 1 IN).
+    /// The data toggle of bulk and interrupt pipes on that endpoint is reset to DATA0, as required after clearing the halt condition.
+    ///
+    /// If a `pipe_id` is given, the driver that set up the pipe will be able to associate the [`driver::Driver::completed_control`]
+    /// call with this transfer. See also [`UsbHost::set_auto_clear_halt`].
+    pub fn clear_halt(&mut self, dev_addr: DeviceAddress, pipe_id: Option<PipeId>, endpoint: u8) -> Result<(), ControlError> {
+        self.clear_feature(dev_addr, pipe_id, Recipient::Endpoint, endpoint as u16, Request::FEATURE_ENDPOINT_HALT)?;
+        self.reset_data_toggle(dev_addr, Some(endpoint));
+        Ok(())
+    }
+
+    /// Clear the halt condition of endpoints automatically, when a transfer on a pipe ends with a STALL
+    ///
+    /// Disabled by default. When enabled, the driver that initiated the failed transfer is informed via
+    /// [`transfer_failed`](driver::Driver::transfer_failed) once the halt condition was cleared. Otherwise
+    /// `transfer_failed` is called right away, and the driver can call [`UsbHost::clear_halt`] itself.
+    pub fn set_auto_clear_halt(&mut self, enable: bool) {
+        self.auto_clear_halt = enable;
+    }
+
+    /// Clear the halt condition of an endpoint, and let the drivers resynchronize with it
+    ///
+    /// Sends a `Clear_Feature(ENDPOINT_HALT)` request for the given endpoint address (including the direction bit, e.g.
+    /// `0x81` for endpoint 1 IN). Unlike [`clear_halt`](UsbHost::clear_halt), the data toggle of the pipes on that endpoint
+    /// is only reset once the device acknowledged the request. Afterwards [`endpoint_recovered`](driver::Driver::endpoint_recovered)
+    /// is called with the outcome, on the driver owning the pipe for the endpoint (or on all drivers, if there is none),
+    /// so that it can bring its own state in line with the device before starting new transfers.
+    ///
+    /// Returns [`ControlError::WouldBlock`] if the bus is busy with another transfer.
+    pub fn recover_endpoint(&mut self, dev_addr: DeviceAddress, endpoint: u8) -> Result<(), ControlError> {
+        self.clear_feature(dev_addr, None, Recipient::Endpoint, endpoint as u16, Request::FEATURE_ENDPOINT_HALT)?;
+        self.recovering_endpoint = Some((dev_addr, endpoint));
+        Ok(())
+    }
+
+    /// Complete the endpoint recovery in progress (if any) with the given result
+    fn endpoint_recovery_finished(
+        &mut self,
+        result: Result<(), TransferError>,
+        drivers: &mut [&mut dyn driver::Driver<
+            B,
+            MAX_PIPES,
+            CONTROL_QUEUE_DEPTH,
+            CONTROL_BUFFER_SIZE,
+        >],
+    ) {
+        if let Some((dev_addr, endpoint)) = self.recovering_endpoint.take() {
+            self.notify_endpoint_recovered(dev_addr, endpoint, result, drivers);
+        }
+    }
+
+    fn notify_endpoint_recovered(
+        &mut self,
+        dev_addr: DeviceAddress,
+        endpoint: u8,
+        result: Result<(), TransferError>,
+        drivers: &mut [&mut dyn driver::Driver<
+            B,
+            MAX_PIPES,
+            CONTROL_QUEUE_DEPTH,
+            CONTROL_BUFFER_SIZE,
+        >],
+    ) {
+        if result.is_ok() {
+            self.reset_data_toggle(dev_addr, Some(endpoint));
+        }
+        let owner = self.pipes.iter().zip(self.pipe_owners.iter()).find_map(|(pipe, owner)| match pipe {
+            Some(
+                Pipe::Bulk { dev_addr: pipe_dev_addr, endpoint: number, direction, .. }
+                | Pipe::Interrupt { dev_addr: pipe_dev_addr, endpoint: number, direction, .. }
+                | Pipe::Isochronous { dev_addr: pipe_dev_addr, endpoint: number, direction, .. },
+            ) if *pipe_dev_addr == dev_addr && (*number | *direction as u8) == endpoint => Some(*owner),
+            _ => None,
+        });
+        for driver in Self::owned_by(owner.flatten(), drivers) {
+            driver.endpoint_recovered(dev_addr, endpoint, result);
+        }
+    }
+
+    /// Start clearing the halt condition for the endpoint of the given pipe. Returns `false` if that is not possible.
+    fn start_clear_halt(&mut self, pipe_id: PipeId) -> bool {
+        let (dev_addr, endpoint) = match self.pipes[pipe_id.0 as usize] {
+            Some(Pipe::Control { dev_addr }) => (dev_addr, 0),
+            Some(
+                Pipe::Bulk { dev_addr, endpoint, direction, .. } | Pipe::Interrupt { dev_addr, endpoint, direction, .. },
+            ) => (dev_addr, endpoint | direction as u8),
+            _ => return false,
+        };
+        if self.clear_halt(dev_addr, None, endpoint).is_ok() {
+            self.clearing_halt = Some(pipe_id);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Reset the data toggle of the pipes of the given device to DATA0
+    ///
+    /// With an `endpoint_address`, only pipes on that endpoint are reset, otherwise all of the device's pipes.
+    fn reset_data_toggle(&mut self, dev_addr: DeviceAddress, endpoint_address: Option<u8>) {
+        self.reset_data_toggle_where(dev_addr, |address, _| endpoint_address.is_none_or(|expected| address == expected));
+    }
+
+    /// Reset the data toggle of the given device's pipes that were assigned to the given interface to DATA0
+    fn reset_interface_data_toggle(&mut self, dev_addr: DeviceAddress, interface: u8) {
+        self.reset_data_toggle_where(dev_addr, |_, pipe_interface| pipe_interface == Some(interface));
+    }
+
+    /// Reset the data toggle of the pipes of the given device, for which `matches` returns true given the endpoint address
+    /// and interface of the pipe
+    fn reset_data_toggle_where(&mut self, dev_addr: DeviceAddress, matches: impl Fn(u8, Option<u8>) -> bool) {
+        for pipe in self.pipes.iter_mut().flatten() {
+            match pipe {
+                Pipe::Bulk { dev_addr: pipe_dev_addr, endpoint, direction, interface, data_toggle, .. } => {
+                    if *pipe_dev_addr == dev_addr && matches(*endpoint | *direction as u8, *interface) {
+                        *data_toggle = false;
+                    }
+                }
+                Pipe::Interrupt { dev_addr: pipe_dev_addr, bus_ref, endpoint, direction, interface, data_toggle, .. } => {
+                    if *pipe_dev_addr == dev_addr && matches(*endpoint | *direction as u8, *interface) {
+                        *data_toggle = false;
+                        if let (Some(bus_ref), Some(hw)) = (bus_ref, self.bus.interrupt_pipe_hw()) {
+                            hw.set_pipe_data_toggle(*bus_ref, false);
+                        }
+                    }
+                }
+                Pipe::Control { .. } | Pipe::Isochronous { .. } => {}
+            }
+        }
+    }
+
+    fn notify_transfer_failed(
+        &mut self,
+        pipe_id: PipeId,
+        error: TransferError,
+        drivers: &mut [&mut dyn driver::Driver<
+            B,
+            MAX_PIPES,
+            CONTROL_QUEUE_DEPTH,
+            CONTROL_BUFFER_SIZE,
+        >],
+    ) {
+        if self.pinging == Some(pipe_id) {
+            self.pinging = None;
+            let Some(ping) = &mut self.pings[pipe_id.0 as usize] else {
+                return;
+            };
+            ping.failures = ping.failures.saturating_add(1);
+            if ping.failures < ping.ping.max_failures {
+                // failed pings are only reported once the device is considered unresponsive
+                return;
+            }
+            ping.failures = 0;
+            self.unresponsive = self.pipe_device(pipe_id);
+        }
+        if let Some(dev_addr) = self.pipe_device(pipe_id) {
+            for driver in self.pipe_drivers(pipe_id, drivers) {
+                driver.transfer_failed(dev_addr, pipe_id, error);
+            }
+            if !matches!(error, TransferError::Stall { .. } | TransferError::Cancelled | TransferError::BusError(bus::Error::NakLimit)) {
+                self.count_error(dev_addr, drivers);
+            }
+        }
+    }
+
+    /// Count a failed transfer of the given device, and remove the device if the error threshold is reached
+    fn count_error(
+        &mut self,
+        dev_addr: DeviceAddress,
+        drivers: &mut [&mut dyn driver::Driver<
+            B,
+            MAX_PIPES,
+            CONTROL_QUEUE_DEPTH,
+            CONTROL_BUFFER_SIZE,
+        >],
+    ) {
+        let threshold = self.error_threshold;
+        let Some(device) = self.find_device_mut(dev_addr) else {
+            return;
+        };
+        device.errors = device.errors.saturating_add(1);
+        if threshold.is_none_or(|threshold| device.errors < threshold) {
+            return;
+        }
+        warn!("Removing device {} after {} failed transfers", u8::from(dev_addr), device.errors);
+        device.detached = Some(DetachReason::Errors);
+        self.failed = Some((dev_addr, device.hub_port));
+        self.mark_orphans();
+        self.process_hub_port_detach(drivers);
+    }
+
+    /// Abort the transfer that is currently in progress
+    ///
+    /// Meant to be called by application code, e.g. when a device stopped responding in the middle of a transfer, to make
+    /// the bus available again before the transfer [times out](PollResult::Timeout). The transaction on the bus is stopped
+    /// (see [`HostBus::stop_transaction`]), and the driver that initiated the transfer is informed during the next call to
+    /// `poll`, via [`transfer_failed`](driver::Driver::transfer_failed) with [`TransferError::Cancelled`]. Control
+    /// transfers that were queued in the meantime are started afterwards, as usual.
+    ///
+    /// Returns `false` if no transfer was in progress, or if the transfer is one that the host started itself in order to
+    /// set up a device (those time out on their own).
+    ///
+    /// Bulk transfers running on additional channels of the bus (see [`bus::TransferChannels`]) are not affected. To abort
+    /// those, release their pipe.
+    pub fn cancel_transfer(&mut self) -> bool {
+        let Some((pipe_id, _)) = self.active_transfer else {
+            return false;
+        };
+        if pipe_id.is_none() && !matches!(self.state, State::Idle | State::Verifying(..)) {
+            return false;
+        }
+        debug!("Cancelling transfer");
+        self.bus.stop_transaction();
+        self.active_transfer = None;
+        self.control_started = None;
+        self.last_control_in = None;
+        self.cancelled_recovery = self.recovering_endpoint.take();
+        let pipe_id = self.clearing_halt.take().or(pipe_id);
+        if pipe_id.is_some() && pipe_id == self.pinging {
+            // liveness pings are not reported to drivers
+            self.pinging = None;
+        } else if let Some(pipe_id) = pipe_id.filter(|id| !matches!(self.pipes[id.0 as usize], Some(Pipe::Interrupt { .. }))) {
+            self.cancelled = Some(pipe_id);
+        }
+        true
+    }
+
+    /// Reset the error count of the device owning the given pipe
+    fn transfer_succeeded(&mut self, pipe_id: PipeId) {
+        if let Some(dev_addr) = self.pipe_device(pipe_id) {
+            if let Some(device) = self.find_device_mut(dev_addr) {
+                device.errors = 0;
+            }
+        }
+    }
+
+    /// Remove devices after too many failed transfers
+    ///
+    /// Disabled by default. When set, a device is considered broken once `threshold` transfers to it failed in a row, due to a
+    /// bus error or timeout (STALLs are not counted, since they are a regular response). Any successful control or bulk
+    /// transfer resets the count. A broken device is treated as if it was detached: its pipes are removed, drivers are informed
+    /// via [`detached_with_reason`](driver::Driver::detached_with_reason) with [`DetachReason::Errors`], and `poll` returns
+    /// [`PollResult::DeviceFailed`], so that the port can be recovered.
+    ///
+    /// This prevents a dying device from occupying the bus (and the host's pipes) indefinitely, while appearing to be connected.
+    pub fn set_error_threshold(&mut self, threshold: Option<u8>) {
+        self.error_threshold = threshold;
+    }
+
+    /// Set how NAKed transactions of control and bulk transfers are retried
+    ///
+    /// By default, NAKed transactions are retried indefinitely, at the pace of the bus ([`NakPolicy::UNLIMITED`](bus::NakPolicy::UNLIMITED)).
+    /// With a retry limit, a transfer to a device that keeps answering with NAK fails with
+    /// [`TransferError::BusError`]`(`[`Error::NakLimit`](bus::Error::NakLimit)`)`, instead of occupying the bus. Such failures
+    /// do not count towards the [error threshold](UsbHost::set_error_threshold).
+    ///
+    /// The policy is applied via [`HostBus::set_nak_policy`] when a transfer is started. Buses which do not implement it
+    /// ignore the policy. Bulk transfers running on [`TransferChannels`](bus::TransferChannels) are not affected.
+    /// Individual pipes can use a different policy, see [`set_pipe_nak_policy`](UsbHost::set_pipe_nak_policy).
+    pub fn set_nak_policy(&mut self, policy: bus::NakPolicy) {
+        self.nak_policy = policy;
+    }
+
+    /// The NAK policy for control and bulk transfers, set with [`set_nak_policy`](UsbHost::set_nak_policy)
+    pub fn nak_policy(&self) -> bus::NakPolicy {
+        self.nak_policy
+    }
+
+    /// Set how NAKed transactions on the given pipe are retried, overriding the host's [NAK policy](UsbHost::set_nak_policy)
+    ///
+    /// Passing `None` returns to the host's policy. For interrupt pipes, which are retried at their polling interval anyway,
+    /// the default is [`NakPolicy::UNLIMITED`](bus::NakPolicy::UNLIMITED) instead. The policy of an interrupt pipe managed by
+    /// the bus (see [`InterruptPipeHw`](bus::InterruptPipeHw)) is applied right away.
+    ///
+    /// Returns [`ControlError::InvalidPipe`] for isochronous pipes (which are never NAKed), and pipes that do not exist.
+    pub fn set_pipe_nak_policy(&mut self, pipe_id: PipeId, policy: Option<bus::NakPolicy>) -> Result<(), ControlError> {
+        match self.pipes[pipe_id.0 as usize] {
+            Some(Pipe::Control { .. } | Pipe::Bulk { .. }) => {}
+            Some(Pipe::Interrupt { bus_ref, .. }) => {
+                if let Some(bus_ref) = bus_ref {
+                    self.bus.set_nak_policy(Some(bus_ref), policy.unwrap_or(bus::NakPolicy::UNLIMITED));
+                }
+            }
+            _ => return Err(ControlError::InvalidPipe),
+        }
+        self.pipe_nak_policies[pipe_id.0 as usize] = policy;
+        Ok(())
+    }
+
+    /// Apply the NAK policy of the given pipe to the transfer path of the bus, unless it is in effect already
+    fn apply_nak_policy(&mut self, pipe_id: Option<PipeId>) {
+        let default = match pipe_id.and_then(|pipe_id| self.pipes[pipe_id.0 as usize]) {
+            Some(Pipe::Interrupt { .. }) => bus::NakPolicy::UNLIMITED,
+            _ => self.nak_policy,
+        };
+        let policy = pipe_id.and_then(|pipe_id| self.pipe_nak_policies[pipe_id.0 as usize]).unwrap_or(default);
+        if self.bus_nak_policy != Some(policy) {
+            self.bus.set_nak_policy(None, policy);
+            self.bus_nak_policy = Some(policy);
+        }
+    }
+
+    /// Check the configurations chosen by drivers against the current available at the device's port
+    ///
+    /// Disabled by default. See the [`power`] module for details.
+    pub fn set_power_budget(&mut self, budget: Option<power::PowerBudget>) {
+        self.power_budget = budget;
+    }
+
+    /// Current available to a device attached to the root port (`None`) or the given hub port, in mA
+    ///
+    /// Returns `None` if no power budget is set. Hubs are considered bus-powered until they are configured.
+    pub fn available_current(&self, hub_port: Option<HubPort>) -> Option<u16> {
+        let budget = self.power_budget?;
+        let Some(HubPort { hub_addr, .. }) = hub_port else {
+            return Some(budget.root_port);
+        };
+        let self_powered = self
+            .devices
+            .iter()
+            .flatten()
+            .find(|hub| hub.address == hub_addr)
+            .and_then(|hub| hub.configuration.and_then(|config| hub.power.get(config)))
+            .is_some_and(|config| config.self_powered);
+        Some(if self_powered { power::SELF_POWERED_HUB_PORT } else { power::BUS_POWERED_HUB_PORT })
+    }
+
+    /// Whether the given configuration of the device draws more current than available at its port
+    fn exceeds_power_budget(&self, dev_addr: DeviceAddress, config: u8) -> bool {
+        let Some(device) = self.devices.iter().flatten().find(|d| d.address == dev_addr) else {
+            return false;
+        };
+        match (self.available_current(device.hub_port), device.power.get(config)) {
+            (Some(available), Some(power)) => power.max_current > available,
+            _ => false,
+        }
+    }
+
+    /// Generate a start-of-frame event for every frame
+    ///
+    /// Disabled by default. SOF interrupts are normally only enabled while the host needs them for its own delays (during
+    /// enumeration and resume). When enabled, they stay on, and every start-of-frame is forwarded to the drivers via
+    /// [`Driver::sof`](driver::Driver::sof), so that drivers can implement their own timing. Note that this means an
+    /// interrupt every millisecond.
+    pub fn set_sof_events(&mut self, enable: bool) {
+        self.sof_events = enable;
+        self.update_sof_interrupt();
+    }
+
+    /// Whether SOF interrupts are needed, apart from the delays during enumeration and resume
+    ///
+    /// This is the case if they were requested with [`set_sof_events`](UsbHost::set_sof_events), or if the host schedules
+    /// interrupt pipes (see [`bus::InterruptPipeHw::schedules_interrupt_pipes`]), or runs them itself (without
+    /// [`InterruptPipeHw`](bus::InterruptPipeHw)). Isochronous pipes need them as well, since drivers pace their transfers
+    /// by the frame count.
+    fn sof_needed(&mut self) -> bool {
+        let host_scheduling = !self.bus.interrupt_pipe_hw().is_some_and(|hw| hw.schedules_interrupt_pipes());
+        self.sof_events
+            || self.pipes.iter().flatten().any(|pipe| match pipe {
+                Pipe::Interrupt { .. } => host_scheduling,
+                Pipe::Isochronous { .. } => true,
+                _ => false,
+            })
+    }
+
+    /// Enable or disable SOF interrupts as needed, unless enumeration or resume currently rely on them
+    fn update_sof_interrupt(&mut self) {
+        let needed = self.sof_needed();
+        if needed || (self.resume.is_none() && !self.enumeration_delays()) {
+            self.interrupt_on_sof(needed);
+        }
+    }
+
+    /// Enable or disable SOF interrupts
+    ///
+    /// Without a [`SofTimer`](bus::SofTimer), `poll` generates the `Sof` events itself, from the frame number.
+    fn interrupt_on_sof(&mut self, enable: bool) {
+        self.sof_interrupt = enable;
+        if let Some(timer) = self.bus.sof_timer() {
+            timer.interrupt_on_sof(enable);
+        }
+    }
+
+    /// Let the bus continue with the next transaction on an interrupt pipe, after the data of the previous one was consumed
+    fn continue_interrupt_pipe(&mut self, pipe_ref: u8) {
+        // the bus only reports packets that were acknowledged, so NAKed retries keep the toggle
+        let data_toggle = self.pipes.iter_mut().flatten().find_map(|pipe| match pipe {
+            Pipe::Interrupt { bus_ref, data_toggle, .. } if *bus_ref == Some(pipe_ref) => {
+                *data_toggle = !*data_toggle;
+                Some(*data_toggle)
+            }
+            _ => None,
+        });
+        if let Some(hw) = self.bus.interrupt_pipe_hw() {
+            if let Some(data_toggle) = data_toggle {
+                hw.set_pipe_data_toggle(pipe_ref, data_toggle);
+            }
+            hw.pipe_continue(pipe_ref);
+        }
+    }
+
+    /// Store data received on interrupt IN pipes in the given buffer, instead of passing it to the drivers right away
+    ///
+    /// The application must then call [`dispatch_report`](UsbHost::dispatch_report) regularly, to pass the reports on
+    /// to the drivers. See the [`report`] module for details.
+    ///
+    /// The buffer must be `Send`, so that the host can be moved to (or shared with) an interrupt handler.
+    pub fn set_report_buffer(&mut self, buffer: &'static mut (dyn report::ReportBuffer + Send)) {
+        self.report_buffer = Some(buffer);
+    }
+
+    /// Report every transaction stage, bus event and phase change to the given hook
+    ///
+    /// Requires the `trace` feature. See the [`trace`] module for details.
+    #[cfg(feature = "trace")]
+    pub fn set_trace_hook(&mut self, hook: &'static mut (dyn trace::TraceHook + Send)) {
+        self.trace_hook = Some(hook);
+    }
+
+    /// Pass the oldest report from the report buffer to the drivers, via [`completed_in`](driver::Driver::completed_in)
+    ///
+    /// Returns `false` if there was no report to dispatch (or no report buffer was set, see [`UsbHost::set_report_buffer`]).
+    /// Since each report may produce an event in the drivers, events should be taken after each call.
+    pub fn dispatch_report(
+        &mut self,
+        drivers: &mut [&mut dyn driver::Driver<
+            B,
+            MAX_PIPES,
+            CONTROL_QUEUE_DEPTH,
+            CONTROL_BUFFER_SIZE,
+        >],
+    ) -> bool {
+        let Some((pipe_id, _)) = self.report_buffer.as_ref().and_then(|reports| reports.front()) else {
+            return false;
+        };
+        if let (Some(dev_addr), Some(reports)) = (self.pipe_device(pipe_id), &mut self.report_buffer) {
+            if let Some((_, data)) = reports.front() {
+                for driver in Self::owned_by(self.pipe_owners[pipe_id.0 as usize], drivers) {
+                    driver.completed_in(dev_addr, pipe_id, data);
+                }
+            }
+        }
+        if let Some(reports) = &mut self.report_buffer {
+            reports.pop();
+        }
+        self.take_deferred_reports();
+        true
+    }
+
+    /// Move data that was left in the bus' buffers into the report buffer, as long as there is room
+    fn take_deferred_reports(&mut self) {
+        for index in 0..MAX_PIPES {
+            let Some(Pipe::Interrupt { bus_ref: Some(bus_ref), size, context, deferred: true, .. }) = self.pipes[index] else {
+                continue;
+            };
+            let buf = self.bus.interrupt_pipe_hw().and_then(|hw| hw.interrupt_buffer(bus_ref));
+            let (Some(reports), Some(buf)) = (&mut self.report_buffer, buf) else {
+                return;
+            };
+            let len = (size as usize).min(buf.len());
+            if !reports.push(PipeId(index as u8, context), &buf[..len]) {
+                return;
+            }
+            if let Some(Pipe::Interrupt { deferred, .. }) = &mut self.pipes[index] {
+                *deferred = false;
+            }
+            self.continue_interrupt_pipe(bus_ref);
+        }
+    }
+
+    /// Start transactions on interrupt pipes whose interval elapsed, if the bus leaves scheduling them to the host
+    ///
+    /// Without [`InterruptPipeHw`](bus::InterruptPipeHw), the host runs the transactions itself.
+    fn poll_interrupt_pipes(
+        &mut self,
+        drivers: &mut [&mut dyn driver::Driver<
+            B,
+            MAX_PIPES,
+            CONTROL_QUEUE_DEPTH,
+            CONTROL_BUFFER_SIZE,
+        >],
+    ) {
+        if self.suspended || self.resume.is_some() {
+            return;
+        }
+        let now = self.frame_timer.frames();
+        match self.bus.interrupt_pipe_hw() {
+            Some(hw) if hw.schedules_interrupt_pipes() => {}
+            Some(hw) => {
+                for pipe in self.pipes.iter_mut().flatten() {
+                    if let Pipe::Interrupt { bus_ref: Some(bus_ref), interval, next_poll, .. } = pipe {
+                        if now.wrapping_sub(*next_poll) as i32 >= 0 {
+                            hw.poll_interrupt_pipe(*bus_ref);
+                            *next_poll = now.wrapping_add(interval.frames() as u32);
+                        }
+                    }
+                }
+            }
+            None => self.start_interrupt_transaction(now, drivers),
+        }
+    }
+
+    /// Start a transaction on the first interrupt pipe run by the host whose interval elapsed, if the bus is idle
+    ///
+    /// The data of IN transactions is passed on like that of bulk IN transfers (via `Event::BulkInData`). The data of
+    /// OUT transactions is requested from the drivers via [`completed_out`](driver::Driver::completed_out) right before
+    /// it is sent.
+    fn start_interrupt_transaction(
+        &mut self,
+        now: u32,
+        drivers: &mut [&mut dyn driver::Driver<
+            B,
+            MAX_PIPES,
+            CONTROL_QUEUE_DEPTH,
+            CONTROL_BUFFER_SIZE,
+        >],
+    ) {
+        if !matches!(self.state, State::Idle) || self.bus_busy() {
+            return;
+        }
+        // IN pipes wait while their data could not be stored
+        let reports_full = self.report_buffer.as_ref().is_some_and(|reports| reports.is_full());
+        let Some(index) = self.pipes.iter().position(|pipe| match pipe {
+            Some(Pipe::Interrupt { bus_ref: None, direction, next_poll, .. }) => {
+                now.wrapping_sub(*next_poll) as i32 >= 0 && !(*direction == UsbDirection::In && reports_full)
+            }
+            _ => false,
+        }) else {
+            return;
+        };
+        let Some(Pipe::Interrupt { dev_addr, endpoint, direction, size, interval, context, data_toggle, next_poll, .. }) =
+            &mut self.pipes[index]
+        else {
+            return;
+        };
+        *next_poll = now.wrapping_add(interval.frames() as u32);
+        let (dev_addr, endpoint, direction, size, data_toggle) = (*dev_addr, *endpoint, *direction, *size, *data_toggle);
+        let pipe_id = PipeId(index as u8, *context);
+
+        self.set_recipient(Some(dev_addr), endpoint, TransferType::Interrupt);
+        self.apply_nak_policy(Some(pipe_id));
+        match direction {
+            UsbDirection::In => {
+                self.active_transfer = Some((Some(pipe_id), transfer::Transfer::new_bulk_in(size)));
+                self.write_data_in(size, data_toggle);
+            }
+            UsbDirection::Out => {
+                let mut buf = [0; MAX_HOST_INTERRUPT_SIZE];
+                let buf = &mut buf[..size as usize];
+                for driver in Self::owned_by(self.pipe_owners[index], drivers) {
+                    driver.completed_out(dev_addr, pipe_id, buf);
+                }
+                self.active_transfer = Some((Some(pipe_id), transfer::Transfer::new_bulk_out(size)));
+                self.write_data_out(buf, data_toggle);
+            }
+        }
+        self.transfer_started = now;
+    }
+
+    /// Stop the transaction on an interrupt pipe run by the host, if the device did not answer it within its frame
+    ///
+    /// The device NAKs interrupt transactions while it has no data to send (or cannot accept any). The transaction is
+    /// tried again once the interval of the pipe elapsed, with the same data toggle.
+    fn expire_interrupt_transaction(&mut self) {
+        let Some((Some(pipe_id), _)) = self.active_transfer else {
+            return;
+        };
+        let host_run = matches!(self.pipes[pipe_id.0 as usize], Some(Pipe::Interrupt { bus_ref: None, .. }));
+        if host_run && self.frame_timer.frames() != self.transfer_started {
+            self.bus.stop_transaction();
+            self.active_transfer = None;
+        }
+    }
+
+    /// Require the application to verify each device, before it is configured
+    ///
+    /// Disabled by default. When enabled, the host pauses after discovery, and `poll` returns [`PollResult::VerifyDevice`].
+    /// The application can then talk to the device via control transfers without a pipe (e.g. [`UsbHost::control_in`] with a
+    /// `pipe_id` of `None`), for example to run a vendor specific challenge/response exchange that proves the device is genuine.
+    /// Completion of each of these transfers is reported via [`PollResult::VerificationTransferComplete`]. The exchange can
+    /// span as many calls to `poll` as needed.
+    ///
+    /// Once done, the application calls [`UsbHost::complete_verification`] to either let the host configure the device,
+    /// or to leave it dormant. No other device is set up in the meantime.
+    pub fn set_device_verification(&mut self, enable: bool) {
+        self.verify_devices = enable;
+    }
+
+    /// Returns the device that is currently awaiting verification, if any
+    pub fn verifying_device(&self) -> Option<DeviceAddress> {
+        match self.state {
+            State::Verifying(dev_addr, _, _) => Some(dev_addr),
+            _ => None,
+        }
+    }
+
+    /// Data received by the last control IN transfer during verification
+    ///
+    /// Returns `None` if no device is being verified, or if the last transfer was not an IN transfer.
+    pub fn verification_data(&self) -> Option<&[u8]> {
+        match self.state {
+            State::Verifying(_, _, Some(length)) => Some(self.control_buffer.data(length)),
+            _ => None,
+        }
+    }
+
+    /// Finish verification of the given device
+    ///
+    /// If the device is `accepted`, it is configured as usual. Otherwise it stays dormant, until it is removed.
+    ///
+    /// Returns [`ControlError::InvalidPipe`] if the given device is not being verified, and [`ControlError::WouldBlock`]
+    /// if a transfer is still in progress.
+    pub fn complete_verification(&mut self, dev_addr: DeviceAddress, accepted: bool) -> Result<(), ControlError> {
+        match self.state {
+            State::Verifying(verifying, config, _) if verifying == dev_addr => {
+                if self.active_transfer.is_some() {
+                    return Err(ControlError::WouldBlock);
+                }
+                self.configure_device(dev_addr, if accepted { config } else { None });
+                Ok(())
+            }
+            _ => Err(ControlError::InvalidPipe),
+        }
+    }
+
+    /// Detect devices whose descriptors changed between two connections
+    ///
+    /// Disabled by default. When enabled, the host additionally reads the serial number of each device during discovery,
+    /// and remembers the [descriptor hash](DeviceSummary::descriptor_hash) of the last few devices it has seen. If a device with
+    /// the same vendor ID, product ID and serial number reconnects with different descriptors, [`PollResult::DeviceChanged`]
+    /// is returned. This can indicate that the device was reflashed with a different (possibly malicious) firmware.
+    ///
+    /// Devices without a serial number are identified by vendor and product ID alone.
+    /// Note that firmware which reports exactly the same descriptors cannot be detected this way.
+    pub fn set_tamper_detection(&mut self, enable: bool) {
+        self.tamper_detection = enable;
+    }
+
+    /// Compare the descriptor hash of a newly discovered device to the one remembered for the same device
+    ///
+    /// Returns `true` if it changed. The remembered hash is updated in any case.
+    fn check_known_device(&mut self, dev_addr: DeviceAddress) -> bool {
+        let Some(device) = Device::find_mut(&mut self.devices, dev_addr) else {
+            return false;
+        };
+        let (vendor_id, product_id) = device.info.map(|info| (info.vendor_id, info.product_id)).unwrap_or_default();
+        let current = KnownDevice {
+            vendor_id,
+            product_id,
+            serial_hash: device.serial_hash,
+            descriptor_hash: device.hasher.finish(),
+        };
+        let known = self.known_devices.iter_mut().find(|known| {
+            matches!(known, Some(known) if (known.vendor_id, known.product_id, known.serial_hash) == (vendor_id, product_id, current.serial_hash))
+        });
+        let changed = if let Some(known) = known {
+            let changed = known.is_some_and(|known| known.descriptor_hash != current.descriptor_hash);
+            known.replace(current);
+            changed
+        } else {
+            self.known_devices[self.next_known_device] = Some(current);
+            self.next_known_device = (self.next_known_device + 1) % MAX_KNOWN_DEVICES;
+            false
+        };
+        device.descriptors_changed = changed;
+        changed
+    }
+
+    /// Put the device into the given configuration, or leave it dormant if there is none
+    fn configure_device(&mut self, dev_addr: DeviceAddress, config: Option<u8>) {
+        if let Some(storage) = &mut self.configuration_storage {
+            storage.select(dev_addr, config);
+        }
+        if let Some(config) = config {
+            let started = self.set_configuration(dev_addr, None, config);
+            self.check_started(started, Some(dev_addr));
+            self.state = State::Configuring(dev_addr, config, false);
+        } else {
+            // device stays dormant, until it is removed
+            self.state = State::Idle;
+        }
+    }
+
+    /// Called once the device is in the chosen configuration: hands its interfaces to the drivers
+    fn configured(
+        &mut self,
+        dev_addr: DeviceAddress,
+        config: u8,
+        drivers: &mut [&mut dyn driver::Driver<
+            B,
+            MAX_PIPES,
+            CONTROL_QUEUE_DEPTH,
+            CONTROL_BUFFER_SIZE,
+        >],
+    ) {
+        if let Some(device) = self.find_device_mut(dev_addr) {
+            device.configuration = Some(config);
+        }
+        // each interface goes to the first driver claiming it
+        let mut claimed = InterfaceSet::EMPTY;
+        for (index, driver) in drivers.iter_mut().enumerate() {
+            let granted = driver.claim_interfaces(dev_addr, config).difference(claimed);
+            claimed = claimed.union(granted);
+            self.configuring_driver = u8::try_from(index).ok();
+            driver.configured_interfaces(dev_addr, config, granted, self);
+        }
+        self.configuring_driver = None;
+        if let Some(device) = self.find_device_mut(dev_addr) {
+            device.claimed = claimed;
+        }
+        self.state = State::Idle;
+    }
+
+    /// Clean up after device was removed
+    fn cleanup(&mut self, addr: DeviceAddress) {
+        self.addresses.free(addr);
+        self.control_queue.retain(|queued| queued.dev_addr != addr);
+        if let Some(storage) = &mut self.configuration_storage {
+            storage.remove(addr);
+        }
+        if let Some((Some(pipe_id), _)) = self.active_transfer {
+            if self.pipe_device(pipe_id) == Some(addr) {
+                self.bus.stop_transaction();
+                self.active_transfer = None;
+            }
+        }
+        self.stop_channel_transfers(|host, pipe_id| host.pipe_device(pipe_id) == Some(addr));
+        if let Some(halted) = self.clearing_halt {
+            if self.pipe_device(halted) == Some(addr) {
+                self.bus.stop_transaction();
+                self.active_transfer = None;
+                self.clearing_halt = None;
+            }
+        }
+        if self.recovering_endpoint.is_some_and(|(dev_addr, _)| dev_addr == addr) {
+            self.bus.stop_transaction();
+            self.active_transfer = None;
+            self.recovering_endpoint = None;
+        }
+        if self.cancelled_recovery.is_some_and(|(dev_addr, _)| dev_addr == addr) {
+            self.cancelled_recovery = None;
+        }
+
+        if let Some(pinging) = self.pinging {
+            if self.pipe_device(pinging) == Some(addr) {
+                self.pinging = None;
+            }
+        }
+        if let Some((last, _)) = self.last_control_in {
+            if self.pipe_device(last) == Some(addr) {
+                self.last_control_in = None;
+            }
+        }
+
+        for (index, (pipe, ping)) in self.pipes.iter_mut().zip(self.pings.iter_mut()).enumerate() {
+            match pipe {
+                Some(Pipe::Control { dev_addr }) if *dev_addr == addr => {
+                    *pipe = None;
+                    *ping = None;
+                }
+                Some(Pipe::Bulk { dev_addr, .. } | Pipe::Isochronous { dev_addr, .. }) if *dev_addr == addr => {
+                    *pipe = None;
+                }
+                Some(Pipe::Interrupt { dev_addr, bus_ref, context, .. }) if *dev_addr == addr => {
+                    Self::release_bus_pipe(&mut self.bus, *bus_ref);
+                    if let Some(reports) = &mut self.report_buffer {
+                        reports.discard(PipeId(index as u8, *context));
+                    }
+                    *pipe = None;
+                }
+                _ => {}
+            }
+        }
+        self.update_sof_interrupt();
+    }
+
+    fn find_device_mut(&mut self, dev_addr: DeviceAddress) -> Option<&mut Device> {
+        Device::find_mut(&mut self.devices, dev_addr)
+    }
+
+    /// Record the information from the device descriptor read during discovery
+    fn discovered_device(&mut self, dev_addr: DeviceAddress, info: DeviceInfo) {
+        if let Some(device) = self.find_device_mut(dev_addr) {
+            // the maximum packet size of endpoint zero was learned (and validated) before the device descriptor was read
+            device.info = Some(info);
+        }
+    }
+
+    /// Number of frames (i.e. milliseconds) that passed since the host was created
+    ///
+    /// The count is based on the frame number reported by [`HostBus::frame_number`], which requires `poll`
+    /// to be called at least every 2 seconds. If the host bus does not report frame numbers, the ticks passed to
+    /// [`tick_1ms`](UsbHost::tick_1ms) are counted, or if there are none, start-of-frame events.
+    /// These are only generated while a device is being enumerated, so the count is not accurate in that case.
+    ///
+    /// The count wraps around after about 49 days.
+    pub fn frame_count(&self) -> u32 {
+        self.frame_timer.frames()
+    }
+
+    /// Let one millisecond pass, for host buses which report neither frame numbers nor start-of-frame events
+    ///
+    /// Such host buses cannot time the delays during enumeration (or any timeouts) on their own. The application can
+    /// call this method from a 1 ms timer instead: each tick is counted as a frame, and while the host would have enabled
+    /// SOF interrupts, the next call to [`poll`](UsbHost::poll) processes it as a start-of-frame. Like `poll`, it must not
+    /// be called concurrently with other methods of the host (e.g. by calling both from the same interrupt priority).
+    ///
+    /// Ticks are ignored if the host bus reports frame numbers ([`HostBus::frame_number`]).
+    pub fn tick_1ms(&mut self) {
+        self.frame_timer.tick();
+    }
+
+    /// Describes the transfer that is currently in progress, if any
+    ///
+    /// Meant for diagnostics, e.g. to find out which device a hang was waiting for. See also [`UsbHost::crash_dump`].
+    pub fn active_transfer_info(&self) -> Option<diagnostics::TransferInfo> {
+        let (pipe_id, transfer) = self.active_transfer?;
+        let dev_addr = match (pipe_id, self.state) {
+            (Some(pipe_id), _) => self.pipe_device(pipe_id),
+            (None, State::Discovery(dev_addr, _) | State::Verifying(dev_addr, _, _) | State::Configuring(dev_addr, _, _)) => Some(dev_addr),
+            (None, _) => None,
+        };
+        Some(diagnostics::TransferInfo {
+            dev_addr,
+            pipe_id,
+            transfer_type: transfer.transfer_type(),
+            direction: transfer.direction(),
+            stage: transfer.stage(),
+            length: transfer.length(),
+            frames_in_flight: self.frame_timer.frames().wrapping_sub(self.transfer_started),
+        })
+    }
+
+    /// Capture the current state of the host in a compact form, that can be stored in memory which survives a reset
+    ///
+    /// See the [`diagnostics`] module for an example.
+    pub fn crash_dump(&self) -> diagnostics::CrashDump {
+        diagnostics::CrashDump::new(self.frame_timer.frames(), self.host_phase() as u8, self.active_transfer_info())
+    }
+
+    /// The phase the host is currently in
+    pub fn host_phase(&self) -> diagnostics::HostPhase {
+        match self.state {
+            State::Enumeration(_) => diagnostics::HostPhase::Enumeration,
+            State::HubEnumeration(_, _) => diagnostics::HostPhase::HubEnumeration,
+            State::Discovery(_, _) => diagnostics::HostPhase::Discovery,
+            State::Verifying(_, _, _) => diagnostics::HostPhase::Verification,
+            State::Configuring(_, _, _) => diagnostics::HostPhase::Configuration,
+            State::Idle => diagnostics::HostPhase::Idle,
+        }
+    }
+
+    /// Returns the devices that are currently attached, and the phase they are in
+    ///
+    /// This includes all devices that were assigned an address. A device that is still being enumerated (i.e. before its
+    /// address is assigned) is not listed.
+    pub fn device_list(&self) -> impl Iterator<Item = DeviceEntry> + '_ {
+        self.devices.iter().flatten().filter(|d| d.detached.is_none()).map(|d| {
+            let phase = match self.state {
+                State::Discovery(dev_addr, _) if dev_addr == d.address => DevicePhase::Discovery,
+                State::Verifying(dev_addr, _, _) if dev_addr == d.address => DevicePhase::Verification,
+                State::Configuring(dev_addr, _, _) if dev_addr == d.address => DevicePhase::Configuring,
+                _ if d.configuration.is_some() => DevicePhase::Configured,
+                _ => DevicePhase::Dormant,
+            };
+            DeviceEntry { address: d.address, hub_port: d.hub_port, speed: d.speed, phase, configuration: d.configuration }
+        })
+    }
+
+    /// Returns the value of the configuration the device is in
+    ///
+    /// This is the configuration selected by the host during setup, once the device confirmed it (see
+    /// [`PollResult::ConfigurationNotApplied`]). Returns `None` if there is no such device, or it is not configured (yet).
+    /// Configurations selected later via [`set_configuration`](UsbHost::set_configuration) are not tracked.
+    pub fn active_configuration(&self, dev_addr: DeviceAddress) -> Option<u8> {
+        self.devices
+            .iter()
+            .flatten()
+            .find(|d| d.address == dev_addr && d.detached.is_none())
+            .and_then(|d| d.configuration)
+    }
+
+    /// Returns a summary of the device with the given address
+    ///
+    /// Returns `None` if there is no such device, or if discovery of the device has not finished yet.
+    /// Devices which were not claimed by any driver (i.e. dormant devices) are included.
+    pub fn device_summary(&self, dev_addr: DeviceAddress) -> Option<DeviceSummary> {
+        self.devices
+            .iter()
+            .flatten()
+            .find(|d| d.address == dev_addr && d.detached.is_none() && d.discovered)
+            .map(|d| {
+                let (vendor_id, product_id) = d.info.map(|info| (info.vendor_id, info.product_id)).unwrap_or_default();
+                DeviceSummary {
+                    address: d.address,
+                    hub_port: d.hub_port,
+                    vendor_id,
+                    product_id,
+                    descriptor_hash: d.hasher.finish(),
+                    connected_frames: self.frame_timer.frames().wrapping_sub(d.connected_at),
+                    descriptors_changed: d.descriptors_changed,
+                }
+            })
+    }
+
+    /// Retain the raw configuration descriptor of the active configuration of each device, in the given `storage`
+    ///
+    /// The configuration descriptor (followed by all the interface, endpoint and other descriptors belonging to it) is the same
+    /// data that was passed to the drivers' [`descriptor`](driver::Driver::descriptor) method during discovery. Keeping it
+    /// allows drivers to parse it again later, e.g. to find an endpoint they only need in a special mode, via
+    /// [`configuration_blob`](UsbHost::configuration_blob).
+    ///
+    /// The storage is shared by all devices. During discovery it must be large enough to hold all the configurations of the
+    /// device, otherwise the descriptors of that device are not retained. Only devices discovered after this call are covered.
+    pub fn set_configuration_storage(&mut self, storage: &'static mut [u8]) {
+        self.configuration_storage = Some(blob::BlobStorage::new(storage));
+    }
+
+    /// Use the given source of random numbers, instead of the default (deterministic) pseudo random number generator
+    ///
+    /// See the [`entropy`] module. Typically this is backed by the hardware random number generator of the microcontroller.
+    /// Like the report buffer, the source must be `Send`.
+    pub fn set_entropy_source(&mut self, source: &'static mut (dyn entropy::EntropySource + Send)) {
+        self.entropy = entropy::Entropy::Custom(source);
+    }
+
+    /// Returns a random number from the host's entropy source
+    ///
+    /// Drivers can use this for values which should not repeat, e.g. between reconnects of a device.
+    pub fn random_u32(&mut self) -> u32 {
+        entropy::EntropySource::next_u32(&mut self.entropy)
+    }
+
+    /// Returns the raw descriptors of the active configuration of the given device
+    ///
+    /// Only available if storage was provided with [`set_configuration_storage`](UsbHost::set_configuration_storage), once
+    /// the host has chosen a configuration. Returns `None` for dormant devices, and if the descriptors did not fit into the storage.
+    pub fn configuration_blob(&self, dev_addr: DeviceAddress) -> Option<&[u8]> {
+        self.configuration_storage.as_ref().and_then(|storage| storage.get(dev_addr))
+    }
+
+    /// Returns information from the device descriptor of the given device
+    ///
+    /// The information is available as soon as the device descriptor was read during discovery, so drivers can use it
+    /// from within [`configured`](driver::Driver::configured), without requesting the descriptor again.
+    /// Returns `None` if there is no such device, or if its device descriptor was not read yet.
+    pub fn device_info(&self, dev_addr: DeviceAddress) -> Option<DeviceInfo> {
+        self.devices
+            .iter()
+            .flatten()
+            .find(|d| d.address == dev_addr && d.detached.is_none())
+            .and_then(|d| d.info)
+    }
+
+    /// Returns the interfaces of the given device that were claimed by drivers
+    ///
+    /// See [`Driver::claim_interfaces`](driver::Driver::claim_interfaces). The set is empty until the device is configured,
+    /// and stays empty if none of the drivers claim individual interfaces. Returns `None` if there is no such device.
+    pub fn claimed_interfaces(&self, dev_addr: DeviceAddress) -> Option<InterfaceSet> {
+        self.devices
+            .iter()
+            .flatten()
+            .find(|d| d.address == dev_addr && d.detached.is_none())
+            .map(|d| d.claimed)
+    }
+
+    /// Speed at which the given device is connected
+    pub fn device_speed(&self, dev_addr: DeviceAddress) -> Option<types::ConnectionSpeed> {
+        self.devices
+            .iter()
+            .flatten()
+            .find(|d| d.address == dev_addr && d.detached.is_none())
+            .map(|d| d.speed)
+    }
+
+    /// Polling interval of the given interrupt pipe
+    ///
+    /// Returns `None` if the pipe is not an interrupt pipe.
+    pub fn pipe_interval(&self, pipe_id: PipeId) -> Option<PollingInterval> {
+        match self.pipes[pipe_id.0 as usize] {
+            Some(Pipe::Interrupt { interval, .. }) => Some(interval),
+            _ => None,
+        }
+    }
+
+    /// Returns the address of the device that the given pipe belongs to
+    fn pipe_device(&self, pipe_id: PipeId) -> Option<DeviceAddress> {
+        match self.pipes[pipe_id.0 as usize] {
+            Some(
+                Pipe::Control { dev_addr }
+                | Pipe::Interrupt { dev_addr, .. }
+                | Pipe::Bulk { dev_addr, .. }
+                | Pipe::Isochronous { dev_addr, .. },
+            ) => Some(dev_addr),
+            None => None,
+        }
+    }
+
+    /// Start enumeration of a device attached to the port of a hub
+    ///
+    /// This method is meant to be called by the hub driver (or application code controlling it), after
+    /// a device was connected to the given `port` of the hub with address `hub_addr`, and the port was
+    /// reset and enabled (i.e. after `C_PORT_RESET` was reported for the port).
+    /// The `speed` of the device is determined from the port status (`PORT_LOW_SPEED` bit).
+    ///
+    /// The device then goes through the same phases as a device attached to the root port: once it has an address,
+    /// drivers are informed via [`attached`](driver::Driver::attached), followed by discovery and configuration.
+    ///
+    /// Only one device can be set up at a time. If another device is currently being set up, or a transfer is in
+    /// progress, [`EnumerateError::WouldBlock`] is returned, and the call can be retried after the next `poll`.
+    /// Note that the device on the port will keep responding to address 0 until the host was able to assign an address
+    /// to it. The port must not be reset, or another port enabled in the meantime.
+    pub fn enumerate_hub_port(
+        &mut self,
+        hub_addr: DeviceAddress,
+        port: u8,
+        speed: types::ConnectionSpeed,
+    ) -> Result<(), EnumerateError> {
+        if !self.devices.iter().flatten().any(|d| d.address == hub_addr && d.detached.is_none()) {
+            return Err(EnumerateError::UnknownHub);
+        }
+        if !matches!(self.state, State::Idle) || self.active_transfer.is_some() {
+            return Err(EnumerateError::WouldBlock);
+        }
+        if self.devices.iter().all(|d| d.is_some()) {
+            return Err(EnumerateError::TooManyDevices);
+        }
+        self.interrupt_on_sof(true);
+        self.state = State::HubEnumeration(
+            HubPort { hub_addr, port },
+            enumeration::start_hub_port_enumeration(speed, &self.enumeration_config),
+        );
+        Ok(())
+    }
+
+    /// Inform the host that the device attached to the port of a hub was disconnected
+    ///
+    /// This method is meant to be called by the hub driver (or application code controlling it), when the hub reports
+    /// that the device on the given `port` is no longer connected.
+    ///
+    /// The device (and, if it is a hub itself, all devices attached to it) is removed during the next call to `poll`, informing
+    /// the drivers via [`detached_with_reason`](driver::Driver::detached_with_reason), with [`DetachReason::Unplugged`].
+    pub fn hub_port_detached(&mut self, hub_addr: DeviceAddress, port: u8) {
+        self.hub_port_removed(hub_addr, port, DetachReason::Unplugged);
+    }
+
+    /// Inform the host that the device attached to the port of a hub is gone, for the given reason
+    ///
+    /// Same as [`hub_port_detached`](UsbHost::hub_port_detached), but lets the hub driver (or application code) tell drivers
+    /// why the device is gone, e.g. if the port was disabled due to an overcurrent condition, or was reset to recover an
+    /// unresponsive device. Devices attached to the device (if it is a hub) are removed with [`DetachReason::ParentRemoved`].
+    pub fn hub_port_removed(&mut self, hub_addr: DeviceAddress, port: u8, reason: DetachReason) {
+        let hub_port = HubPort { hub_addr, port };
+        if let State::HubEnumeration(enumerating, _) = self.state {
+            if enumerating == hub_port {
+                // device was not assigned an address yet, so no driver knows about it
+                if self.active_transfer.is_some() {
+                    self.bus.stop_transaction();
+                    self.active_transfer = None;
+                }
+                let needed = self.sof_needed();
+                self.interrupt_on_sof(needed);
+                self.state = State::Idle;
+            }
+        }
+        for device in self.devices.iter_mut().flatten() {
+            if device.hub_port == Some(hub_port) {
+                device.detached = Some(reason);
+            }
+        }
+        self.mark_orphans();
+    }
+
+    /// Reset a single device, and enumerate it again
+    ///
+    /// Meant to be called by application code, to recover a device that misbehaves (e.g. one that stopped responding),
+    /// without disturbing the other devices on the bus (unlike [`reset`](UsbHost::reset)).
+    ///
+    /// The device is removed during the next call to `poll`, informing the drivers via
+    /// [`detached_with_reason`](driver::Driver::detached_with_reason), with [`DetachReason::Recovery`]. If it is a hub,
+    /// the devices attached to it are removed as well, with [`DetachReason::ParentRemoved`]. Then it is enumerated again,
+    /// as if it was newly attached, so it is likely to get a different address.
+    ///
+    /// - For the device on the root port, the host resets the bus, and enumerates the device on its own.
+    /// - For a device attached to a hub, the host asks the hub to reset the port (`SET_FEATURE(PORT_RESET)`). The hub
+    ///   then reports the completed reset (`C_PORT_RESET`) to the hub driver, and the device is handed over to the host
+    ///   by calling [`enumerate_hub_port`](UsbHost::enumerate_hub_port), as usual.
+    pub fn reset_device(&mut self, dev_addr: DeviceAddress) -> Result<(), ResetError> {
+        let Some(device) = self.devices.iter().flatten().find(|d| d.address == dev_addr && d.detached.is_none()) else {
+            return Err(ResetError::UnknownDevice);
+        };
+        if !matches!(self.state, State::Idle) || self.bus_busy() {
+            return Err(ResetError::WouldBlock);
+        }
+        match device.hub_port {
+            None => {
+                // Unwrap safety: the device was found above
+                self.find_device_mut(dev_addr).unwrap().detached = Some(DetachReason::Recovery);
+                self.mark_orphans();
+                self.execute_enumeration_action(EnumerationAction::ResetBus);
+                self.state = State::Enumeration(EnumerationState::Reset0);
+            }
+            Some(HubPort { hub_addr, port }) => {
+                /// Hub class feature selector for resetting a port
+                const PORT_RESET: u16 = 4;
+                let setup =
+                    SetupPacket::new(UsbDirection::Out, RequestType::Class, Recipient::Other, Request::SET_FEATURE, PORT_RESET, port as u16, 0);
+                self.control_out(Some(hub_addr), None, setup, &[]).map_err(|_| ResetError::WouldBlock)?;
+                self.resetting_port = true;
+                self.hub_port_removed(hub_addr, port, DetachReason::Recovery);
+            }
+        }
+        Ok(())
+    }
+
+    /// Mark devices attached to removed hubs as detached as well
+    fn mark_orphans(&mut self) {
+        loop {
+            let mut changed = false;
+            for i in 0..MAX_DEVICES {
+                if let Some(Device { hub_port: Some(HubPort { hub_addr, .. }), detached: None, .. }) = self.devices[i] {
+                    if self.devices.iter().flatten().any(|d| d.address == hub_addr && d.detached.is_some()) {
+                        // Unwrap safety: checked by the `if let` above
+                        self.devices[i].as_mut().unwrap().detached = Some(DetachReason::ParentRemoved);
+                        changed = true;
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::mock::fixtures::{CONFIGURATION_DESCRIPTOR, DEVICE_DESCRIPTOR};
+    #[cfg(feature = "driver-kbd")]
+    use crate::bus::mock::fixtures::{enumerate, enumerated_keyboard, keyboard, Observer, SET_PROTOCOL};
+    #[cfg(feature = "driver-kbd")]
+    use crate::bus::mock::{ControlResponse, Response};
+    use crate::bus::mock::{MockDevice, MockHostBus};
+    #[cfg(feature = "driver-kbd")]
+    use crate::bus::{Error, Event, NakPolicy};
+    #[cfg(feature = "driver-kbd")]
+    use crate::driver::kbd::{KbdDriver, KbdEvent};
+    use crate::entropy::Xorshift32;
+
+    #[test]
+    #[cfg(feature = "driver-kbd")]
+    fn test_discovery_interest() {
+        const DEVICE_DESCRIPTOR_2: &[u8] = &[
+            0x12, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x40, 0x34, 0x12, 0x78, 0x56, 0x00, 0x01, 0x00, 0x00, 0x00, 0x02,
+        ];
+        const CONFIGURATION_DESCRIPTOR_2: &[u8] = &[
+            0x09, 0x02, 0x09, 0x00, 0x00, 0x02, 0x00, 0xa0, 0x32, // configuration 2, without interfaces
+        ];
+        fn enumerate_with(observer: Option<&mut Observer>) -> usize {
+            let device = MockDevice {
+                configuration_descriptors: &[CONFIGURATION_DESCRIPTOR, CONFIGURATION_DESCRIPTOR_2],
+                device_descriptor: DEVICE_DESCRIPTOR_2,
+                ..keyboard()
+            };
+            let mut host = UsbHost::new(MockHostBus::new(device));
+            let mut kbd: KbdDriver = KbdDriver::new();
+            enumerate(&mut host, &mut kbd, observer);
+            assert_eq!(host.bus().configuration(), Some(1));
+            host.bus().setup_count()
+        }
+        // the keyboard is decided after the first configuration, the observer never is
+        let decided = enumerate_with(None);
+        let undecided = enumerate_with(Some(&mut Observer::default()));
+        assert_eq!(decided + 2, undecided);
+    }
+
+    #[test]
+    #[cfg(feature = "driver-kbd")]
+    fn test_interrupt_data_toggle() {
+        let (mut host, mut kbd, dev_addr) = enumerated_keyboard();
+        assert_eq!(host.bus().interrupt_data_toggle(1), Some(false));
+
+        // every acknowledged packet flips the toggle
+        for toggle in [true, false, true] {
+            assert!(host.bus().send_interrupt(1, &[0; 8]));
+            host.poll(&mut [&mut kbd]);
+            assert_eq!(host.bus().interrupt_data_toggle(1), Some(toggle));
+        }
+
+        // clearing the halt condition resets it to DATA0
+        assert!(host.clear_halt(dev_addr, None, 0x81).is_ok());
+        assert_eq!(host.bus().interrupt_data_toggle(1), Some(false));
+    }
+
+    #[test]
+    #[cfg(feature = "driver-kbd")]
+    fn test_recover_endpoint() {
+        const CLEAR_HALT: ControlResponse = ControlResponse {
+            request_type: 0x02,
+            request: 0x01,
+            value: 0,
+            index: 0x81,
+            response: Response::Data(&[]),
+        };
+        let device = MockDevice { control_responses: &[SET_PROTOCOL, CLEAR_HALT], ..keyboard() };
+        let mut host = UsbHost::new(MockHostBus::new(device));
+        let mut kbd: KbdDriver = KbdDriver::new();
+        let mut observer = Observer::default();
+        let dev_addr = enumerate(&mut host, &mut kbd, None);
+        assert!(host.bus().send_interrupt(1, &[0; 8]));
+        host.poll(&mut [&mut kbd, &mut observer]);
+        assert_eq!(host.bus().interrupt_data_toggle(1), Some(true));
+
+        // the toggle is only reset once the device acknowledged the request
+        assert!(host.recover_endpoint(dev_addr, 0x81).is_ok());
+        assert_eq!(host.bus().interrupt_data_toggle(1), Some(true));
+        for _ in 0..10 {
+            host.poll(&mut [&mut kbd, &mut observer]);
+        }
+        assert_eq!(host.bus().interrupt_data_toggle(1), Some(false));
+        // only the keyboard driver owns a pipe on that endpoint
+        assert_eq!(observer.recovered, None);
+
+        // the device refuses to clear the halt condition of another endpoint, which no driver has a pipe on
+        assert!(host.recover_endpoint(dev_addr, 0x82).is_ok());
+        for _ in 0..10 {
+            host.poll(&mut [&mut kbd, &mut observer]);
+        }
+        assert_eq!(observer.recovered, Some((0x82, Err(TransferError::Stall { cleared: false }))));
+    }
+
+    #[test]
+    #[cfg(feature = "driver-kbd")]
+    fn test_set_interface_data_toggle() {
+        const SET_INTERFACE: ControlResponse = ControlResponse {
+            request_type: 0x01,
+            request: 0x0b,
+            value: 1,
+            index: 0,
+            response: Response::Data(&[]),
+        };
+        let device = MockDevice { control_responses: &[SET_PROTOCOL, SET_INTERFACE], ..keyboard() };
+        let mut host = UsbHost::new(MockHostBus::new(device));
+        let mut kbd: KbdDriver = KbdDriver::new();
+        let dev_addr = enumerate(&mut host, &mut kbd, None);
+        let index = host.pipes.iter().position(|pipe| matches!(pipe, Some(Pipe::Interrupt { .. }))).unwrap();
+        let pipe_id = PipeId(index as u8, 0);
+        let mut select_alternate_setting = |host: &mut UsbHost<MockHostBus>| {
+            // bring the pipe to DATA1 first
+            if host.bus().interrupt_data_toggle(1) == Some(false) {
+                assert!(host.bus().send_interrupt(1, &[0; 8]));
+                host.poll(&mut [&mut kbd]);
+            }
+            assert_eq!(host.bus().interrupt_data_toggle(1), Some(true));
+            assert!(host.set_interface(dev_addr, None, 0, 1).is_ok());
+            for _ in 0..10 {
+                host.poll(&mut [&mut kbd]);
+            }
+            host.bus().interrupt_data_toggle(1)
+        };
+
+        // the host cannot tell which interface the pipe belongs to
+        assert_eq!(select_alternate_setting(&mut host), Some(true));
+
+        // once it can, the toggle is reset along with the device's
+        host.set_pipe_interface(pipe_id, Some(0));
+        assert_eq!(select_alternate_setting(&mut host), Some(false));
+
+        // pipes on other interfaces are not affected
+        host.set_pipe_interface(pipe_id, Some(1));
+        assert_eq!(select_alternate_setting(&mut host), Some(true));
+    }
+
+    #[test]
+    #[cfg(feature = "driver-kbd")]
+    fn test_release_pipe() {
+        let (mut host, _kbd, dev_addr) = enumerated_keyboard();
+        let pipe_id = host.create_interrupt_pipe(dev_addr, 2, UsbDirection::In, 8, 10).unwrap();
+        assert!(host.bus().interrupt_pipe_device(2) == Some(dev_addr));
+        host.release_pipe(pipe_id);
+        assert!(host.bus().interrupt_pipe_device(2).is_none());
+        // the pipe belonging to the keyboard driver is unaffected
+        assert!(host.bus().interrupt_pipe_device(1) == Some(dev_addr));
+    }
+
+    #[test]
+    #[cfg(feature = "driver-kbd")]
+    fn test_zero_max_packet_size() {
+        let (mut host, _kbd, dev_addr) = enumerated_keyboard();
+        // a transfer on an endpoint without packets could never complete
+        assert!(host.create_bulk_pipe(dev_addr, 2, UsbDirection::In, 0).is_none());
+        assert!(host.create_interrupt_pipe(dev_addr, 2, UsbDirection::In, 0, 10).is_none());
+        assert!(host.create_isochronous_pipe(dev_addr, 2, UsbDirection::In, 0).is_none());
+        assert!(host.bus().interrupt_pipe_device(2).is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "driver-kbd")]
+    fn test_max_pipes() {
+        // just enough for the keyboard's control and interrupt pipes
+        let mut host: UsbHost<_, 2> = UsbHost::new_sized(MockHostBus::new(keyboard()), Default::default());
+        let mut kbd: KbdDriver = KbdDriver::new();
+        let dev_addr = enumerate(&mut host, &mut kbd, None);
+        assert!(host.bus().interrupt_pipe_device(1) == Some(dev_addr));
+        assert!(host.create_control_pipe(dev_addr).is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "driver-kbd")]
+    fn test_control_queue_depth() {
+        let mut host: UsbHost<_, DEFAULT_MAX_PIPES, 1> = UsbHost::new_sized(MockHostBus::new(keyboard()), Default::default());
+        let mut kbd: KbdDriver = KbdDriver::new();
+        let dev_addr = enumerate(&mut host, &mut kbd, None);
+        let pipe_id = host.create_control_pipe(dev_addr).unwrap();
+        let get_device_descriptor = |host: &mut UsbHost<_, DEFAULT_MAX_PIPES, 1>| {
+            host.get_descriptor(Some(dev_addr), Some(pipe_id), Recipient::Device, descriptor::TYPE_DEVICE, 0, 18)
+        };
+        // the first transfer is started right away, the second one is queued, and the queue is then full
+        assert!(get_device_descriptor(&mut host).is_ok());
+        assert!(get_device_descriptor(&mut host).is_ok());
+        assert!(matches!(get_device_descriptor(&mut host), Err(ControlError::WouldBlock)));
+        for _ in 0..20 {
+            host.poll(&mut [&mut kbd]);
+        }
+        assert_eq!(host.received_control_data(pipe_id), Some(DEVICE_DESCRIPTOR));
+    }
+
+    #[test]
+    #[cfg(feature = "driver-kbd")]
+    fn test_control_buffer_size() {
+        type SmallHost =
+            UsbHost<MockHostBus<'static>, DEFAULT_MAX_PIPES, DEFAULT_CONTROL_QUEUE_DEPTH, 24>;
+        // the configuration descriptor is larger than the buffer, so discovery reads it in two windows
+        let mut host: SmallHost = UsbHost::new_sized(MockHostBus::new(keyboard()), Default::default());
+        let mut kbd: KbdDriver = KbdDriver::new();
+        let dev_addr = enumerate(&mut host, &mut kbd, None);
+
+        // other transfers are cut off at the size of the buffer
+        let pipe_id = host.create_control_pipe(dev_addr).unwrap();
+        assert!(host.get_descriptor(Some(dev_addr), Some(pipe_id), Recipient::Device, descriptor::TYPE_CONFIGURATION, 0, 255).is_ok());
+        for _ in 0..10 {
+            host.poll(&mut [&mut kbd]);
+        }
+        assert_eq!(host.received_control_data(pipe_id), Some(&CONFIGURATION_DESCRIPTOR[..24]));
+    }
+
+    #[test]
+    #[cfg(feature = "driver-kbd")]
+    fn test_host_scheduled_interrupt_pipe() {
+        let mut bus = MockHostBus::new(keyboard());
+        bus.set_host_scheduling(true);
+        let mut host = UsbHost::new(bus);
+        let mut kbd: KbdDriver = KbdDriver::new();
+        enumerate(&mut host, &mut kbd, None);
+        // SOF interrupts stay enabled, so that the host can count frames
+        assert!(host.bus().sof_interrupt_enabled());
+
+        let polls = host.bus().interrupt_polls(1).unwrap();
+        let start = host.frame_count();
+        while host.frame_count().wrapping_sub(start) < 100 {
+            host.poll(&mut [&mut kbd]);
+        }
+        // bInterval is 10 frames
+        let polled = host.bus().interrupt_polls(1).unwrap() - polls;
+        assert!((9..=11).contains(&polled));
+    }
+
+    #[test]
+    fn test_tick_1ms() {
+        let mut bus = MockHostBus::new(MockDevice::new(DEVICE_DESCRIPTOR, &[CONFIGURATION_DESCRIPTOR]));
+        bus.set_sof_timer(false);
+        bus.set_frame_numbers(false);
+        let mut host = UsbHost::new(bus);
+        host.bus().attach();
+
+        // without a time base, enumeration waits for the device to settle forever
+        for _ in 0..100 {
+            host.poll(&mut []);
+        }
+        assert!(host.bus().address().is_none());
+        assert_eq!(host.frame_count(), 0);
+
+        for _ in 0..100 {
+            host.tick_1ms();
+            host.poll(&mut []);
+        }
+        assert!(host.bus().address().is_some());
+        assert_eq!(host.frame_count(), 100);
+    }
+
+    #[test]
+    #[cfg(feature = "driver-kbd")]
+    fn test_without_bus_capabilities() {
+        let mut bus = MockHostBus::new(keyboard());
+        bus.set_interrupt_pipe_hw(false);
+        bus.set_sof_timer(false);
+        let mut host = UsbHost::new(bus);
+        let mut kbd: KbdDriver = KbdDriver::new();
+        // the enumeration delays are timed by the frame number
+        let dev_addr = enumerate(&mut host, &mut kbd, None);
+        assert!(matches!(kbd.take_event(), Some(KbdEvent::DeviceAdded(added)) if added == dev_addr));
+        assert!(host.bus().interrupt_pipe_device(1).is_none());
+
+        // the host polls the endpoint itself, and the device NAKs until it has data
+        for keys in [[0x04, 0], [0x04, 0x05]] {
+            assert!(host.bus().send_interrupt(1, &[0, 0, keys[0], keys[1], 0, 0, 0, 0]));
+            let mut report = None;
+            for _ in 0..100 {
+                assert!(!matches!(host.poll(&mut [&mut kbd]), PollResult::HostError(_)));
+                if let Some(KbdEvent::InputChanged(_, input)) = kbd.take_event() {
+                    report = Some(input);
+                }
+            }
+            assert!(report.unwrap().pressed_keys().eq(keys.into_iter().filter(|key| *key != 0)));
+        }
+
+        let pipe_id = host.create_interrupt_pipe(dev_addr, 2, UsbDirection::Out, 1, 10).unwrap();
+        for _ in 0..100 {
+            host.poll(&mut [&mut kbd]);
+        }
+        assert_eq!(host.bus().interrupt_out_data(2), Some(&[0][..]));
+        host.release_pipe(pipe_id);
+        // larger interrupt packets would need hardware support
+        assert!(host.create_interrupt_pipe(dev_addr, 2, UsbDirection::In, 65, 10).is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "driver-kbd")]
+    fn test_received_control_data() {
+        let (mut host, mut kbd, dev_addr) = enumerated_keyboard();
+        let pipe_id = host.create_control_pipe(dev_addr).unwrap();
+        assert!(host.get_descriptor(Some(dev_addr), Some(pipe_id), Recipient::Device, descriptor::TYPE_DEVICE, 0, 18).is_ok());
+        for _ in 0..10 {
+            host.poll(&mut [&mut kbd]);
+        }
+        assert_eq!(host.received_control_data(pipe_id), Some(DEVICE_DESCRIPTOR));
+
+        // a bulk transfer reuses the bus' buffer, but not the host's copy of the data
+        let bulk_in = host.create_bulk_pipe(dev_addr, 2, UsbDirection::In, 64).unwrap();
+        host.bus().set_bulk_in(&[0xaa; 18]);
+        assert!(host.bulk_in(bulk_in, 64).is_ok());
+        for _ in 0..10 {
+            host.poll(&mut [&mut kbd]);
+        }
+        assert!(!host.pipe_busy(bulk_in));
+        assert_eq!(host.received_control_data(pipe_id), Some(DEVICE_DESCRIPTOR));
+
+        // starting another control transfer invalidates the data
+        assert!(host.set_configuration(dev_addr, Some(pipe_id), 1).is_ok());
+        assert!(host.received_control_data(pipe_id).is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "driver-kbd")]
+    fn test_pipe_owner() {
+        let mut host = UsbHost::new(MockHostBus::new(keyboard()));
+        let mut kbd: KbdDriver = KbdDriver::new();
+        let mut observer = Observer::default();
+        let dev_addr = enumerate(&mut host, &mut kbd, Some(&mut observer));
+        // descriptors are passed on with the configuration and interface they belong to
+        let context = descriptor::DescriptorContext { configuration: Some(1), interface: Some((0, 0)) };
+        assert_eq!(observer.endpoint_context, Some(context));
+        // the keyboard driver's SET_PROTOCOL request was only reported to the keyboard driver
+        assert!(matches!(kbd.take_event(), Some(KbdEvent::DeviceAdded(_))));
+        assert_eq!(observer.completed, 0);
+
+        // pipes created outside of `configured` are shared by all drivers
+        let pipe_id = host.create_control_pipe(dev_addr).unwrap();
+        assert!(host.get_descriptor(Some(dev_addr), Some(pipe_id), Recipient::Device, descriptor::TYPE_DEVICE, 0, 18).is_ok());
+        for _ in 0..10 {
+            host.poll(&mut [&mut kbd, &mut observer]);
+        }
+        assert_eq!(observer.completed, 1);
+
+        host.set_pipe_owner(pipe_id, Some(0));
+        assert!(host.get_descriptor(Some(dev_addr), Some(pipe_id), Recipient::Device, descriptor::TYPE_DEVICE, 0, 18).is_ok());
+        for _ in 0..10 {
+            host.poll(&mut [&mut kbd, &mut observer]);
+        }
+        assert_eq!(observer.completed, 1);
+    }
+
+    #[test]
+    #[cfg(feature = "driver-kbd")]
+    fn test_transfer_channels() {
+        let (mut host, mut kbd, dev_addr) = enumerated_keyboard();
+        let mut observer = Observer::default();
+        host.bus().set_transfer_channels(2);
+        host.bus().set_bulk_in(b"hello");
+        let control = host.create_control_pipe(dev_addr).unwrap();
+        let bulk_in = host.create_bulk_pipe(dev_addr, 2, UsbDirection::In, 64).unwrap();
+        let bulk_out = host.create_bulk_pipe(dev_addr, 3, UsbDirection::Out, 64).unwrap();
+
+        // bulk transfers run on the additional channels, next to the control transfer
+        assert!(host.get_descriptor(Some(dev_addr), Some(control), Recipient::Device, descriptor::TYPE_DEVICE, 0, 18).is_ok());
+        assert!(host.bulk_in(bulk_in, 64).is_ok());
+        assert!(host.bulk_out(bulk_out, b"x").is_ok());
+        assert!(host.pipe_busy(bulk_in) && host.pipe_busy(bulk_out));
+        assert!(matches!(host.bulk_in(bulk_in, 64), Err(ControlError::WouldBlock)));
+        for _ in 0..10 {
+            host.poll(&mut [&mut kbd, &mut observer]);
+        }
+        assert_eq!((observer.completed, observer.received), (1, 5));
+        assert!(!host.pipe_busy(bulk_in) && !host.pipe_busy(bulk_out));
+
+        // without channels, the control transfer blocks the bulk transfer
+        host.bus().set_transfer_channels(0);
+        assert!(host.get_descriptor(Some(dev_addr), Some(control), Recipient::Device, descriptor::TYPE_DEVICE, 0, 18).is_ok());
+        assert!(matches!(host.bulk_in(bulk_in, 64), Err(ControlError::WouldBlock)));
+    }
+
+    #[test]
+    #[cfg(feature = "driver-kbd")]
+    fn test_nak_policy() {
+        let (mut host, mut kbd, dev_addr) = enumerated_keyboard();
+        let mut observer = Observer::default();
+        let bulk_in = host.create_bulk_pipe(dev_addr, 2, UsbDirection::In, 64).unwrap();
+        host.bus().set_bulk_nak(true);
+
+        // by default, the device NAKs indefinitely
+        assert!(host.bulk_in(bulk_in, 64).is_ok());
+        for _ in 0..10 {
+            host.poll(&mut [&mut kbd, &mut observer]);
+        }
+        assert!(host.pipe_busy(bulk_in) && observer.failed.is_none());
+        assert!(host.cancel_transfer());
+        host.poll(&mut [&mut kbd, &mut observer]);
+
+        // with a retry limit, the transfer fails
+        let limited = NakPolicy::UNLIMITED.with_retry_limit(3);
+        host.set_nak_policy(limited);
+        assert!(host.bulk_in(bulk_in, 64).is_ok());
+        assert_eq!(host.bus().nak_policy(), limited);
+        for _ in 0..10 {
+            host.poll(&mut [&mut kbd, &mut observer]);
+        }
+        assert!(!host.pipe_busy(bulk_in));
+        assert_eq!(observer.failed, Some(TransferError::BusError(Error::NakLimit)));
+
+        // unless the pipe has a policy of its own
+        assert!(host.set_pipe_nak_policy(bulk_in, Some(NakPolicy::UNLIMITED)).is_ok());
+        assert!(host.bulk_in(bulk_in, 64).is_ok());
+        assert_eq!(host.bus().nak_policy(), NakPolicy::UNLIMITED);
+        assert!(host.pipe_busy(bulk_in));
+
+        // policies of interrupt pipes managed by the bus are applied right away
+        let interrupt = host.create_interrupt_pipe(dev_addr, 4, UsbDirection::In, 8, 10).unwrap();
+        assert!(host.set_pipe_nak_policy(interrupt, Some(limited)).is_ok());
+        assert_eq!(host.bus().interrupt_pipe_nak_policy(4), Some(limited));
+        host.release_pipe(interrupt);
+        assert!(matches!(host.set_pipe_nak_policy(interrupt, None), Err(ControlError::InvalidPipe)));
+    }
+
+    #[test]
+    #[cfg(feature = "driver-kbd")]
+    fn test_reset_device() {
+        let (mut host, mut kbd, dev_addr) = enumerated_keyboard();
+        assert!(matches!(kbd.take_event(), Some(KbdEvent::DeviceAdded(_))));
+        let unknown = DeviceAddress(core::num::NonZeroU8::new(100).unwrap());
+        assert_eq!(host.reset_device(unknown), Err(ResetError::UnknownDevice));
+
+        assert_eq!(host.reset_device(dev_addr), Ok(()));
+        host.poll(&mut [&mut kbd]);
+        assert!(matches!(kbd.take_event(), Some(KbdEvent::DeviceRemoved(removed)) if removed == dev_addr));
+        assert!(host.device_summary(dev_addr).is_none());
+
+        // the device is enumerated and configured again
+        for _ in 0..1000 {
+            host.poll(&mut [&mut kbd]);
+            kbd.poll(&mut host);
+        }
+        assert!(matches!(kbd.take_event(), Some(KbdEvent::DeviceAdded(_))));
+        assert!(host.bus().address().is_some());
+    }
+
+    #[test]
+    #[cfg(feature = "driver-kbd")]
+    fn test_too_many_devices() {
+        let (mut host, mut kbd, dev_addr) = enumerated_keyboard();
+        let device = host.devices.iter().flatten().find(|device| device.address == dev_addr).copied().unwrap();
+        assert_eq!(host.reset_device(dev_addr), Ok(()));
+        host.poll(&mut [&mut kbd]);
+        // devices behind a hub take up all the room
+        for (n, slot) in host.devices.iter_mut().enumerate() {
+            let address = DeviceAddress(core::num::NonZeroU8::new(100 + n as u8).unwrap());
+            slot.replace(Device { address, hub_port: Some(HubPort { hub_addr: dev_addr, port: n as u8 + 1 }), ..device });
+        }
+
+        let mut error = None;
+        for _ in 0..1000 {
+            if let PollResult::HostError(host_error) = host.poll(&mut [&mut kbd]) {
+                error = Some(host_error);
+            }
+        }
+        let assigned = host.bus().address().unwrap();
+        assert_eq!(error, Some(HostError::TooManyDevices(assigned)));
+        assert!(host.device_summary(assigned).is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "driver-kbd")]
+    fn test_cancel_transfer() {
+        let (mut host, mut kbd, dev_addr) = enumerated_keyboard();
+        let mut observer = Observer::default();
+        assert!(!host.cancel_transfer());
+
+        let pipe_id = host.create_control_pipe(dev_addr).unwrap();
+        assert!(host.get_descriptor(Some(dev_addr), Some(pipe_id), Recipient::Device, descriptor::TYPE_DEVICE, 0, 18).is_ok());
+        assert!(host.cancel_transfer());
+        assert!(matches!(host.poll(&mut [&mut kbd, &mut observer]), PollResult::Idle));
+        assert_eq!(observer.failed, Some(TransferError::Cancelled));
+        assert_eq!(observer.completed, 0);
+
+        // the bus can be used again right away
+        assert!(host.get_descriptor(Some(dev_addr), Some(pipe_id), Recipient::Device, descriptor::TYPE_DEVICE, 0, 18).is_ok());
+        for _ in 0..10 {
+            host.poll(&mut [&mut kbd, &mut observer]);
+        }
+        assert_eq!(observer.completed, 1);
+    }
+
+    #[test]
+    #[cfg(feature = "driver-kbd")]
+    fn test_unexpected_completion() {
+        let (mut host, mut kbd, _) = enumerated_keyboard();
+        // a misbehaving bus reports a completed transaction out of the blue
+        host.bus().push_event(Event::TransComplete);
+        assert!(matches!(host.poll(&mut [&mut kbd]), PollResult::HostError(HostError::UnexpectedCompletion)));
+        // the host carries on as usual
+        assert!(matches!(host.poll(&mut [&mut kbd]), PollResult::Idle));
+    }
+
+    const BROKEN_DESCRIPTOR: &[u8] = &[
+        0x09, 0x02, 0x0c, 0x00, 0x01, 0x01, 0x00, 0xa0, 0x32, // configuration 1
+        0x09, 0x04, 0x00, // truncated interface descriptor
+    ];
+
+    #[test]
+    fn test_discovery_retries() {
+        let device = MockDevice::new(DEVICE_DESCRIPTOR, &[BROKEN_DESCRIPTOR]);
+        let config = EnumerationConfig { discovery_retries: 2, ..EnumerationConfig::new() };
+        let mut host = UsbHost::new_with_config(MockHostBus::new(device), config);
+        host.bus().attach();
+
+        let mut attempts = [0; 2];
+        let mut retries = 0;
+        let mut failed = false;
+        for _ in 0..1000 {
+            match host.poll(&mut []) {
+                PollResult::Retrying(_, attempt) => {
+                    attempts[retries] = attempt;
+                    retries += 1;
+                }
+                PollResult::DiscoveryError(_) => failed = true,
+                _ => {}
+            }
+        }
+        assert_eq!(attempts, [1, 2]);
+        assert!(failed);
+    }
+
+    #[test]
+    fn test_discovery_retry_jitter() {
+        static mut SOURCES: [Xorshift32; 2] = [Xorshift32::new(1), Xorshift32::new(2)];
+        let mut delays = [0; 2];
+        for (i, delay) in delays.iter_mut().enumerate() {
+            let device = MockDevice::new(DEVICE_DESCRIPTOR, &[BROKEN_DESCRIPTOR]);
+            let config = EnumerationConfig { discovery_retries: 1, ..EnumerationConfig::new() };
+            let mut host = UsbHost::new_with_config(MockHostBus::new(device), config);
+            host.set_entropy_source(unsafe { &mut (*core::ptr::addr_of_mut!(SOURCES))[i] });
+            host.bus().attach();
+            let mut retried = None;
+            for _ in 0..1000 {
+                if let PollResult::Retrying(..) = host.poll(&mut []) {
+                    retried = Some(host.frame_count());
+                    break;
+                }
+            }
+            // frames between the retry and the initial GET_DESCRIPTOR request
+            let retried = retried.unwrap();
+            let setups = host.bus().setup_count();
+            while host.bus().setup_count() == setups && host.frame_count() - retried < 1000 {
+                host.poll(&mut []);
+            }
+            *delay = host.frame_count() - retried;
+        }
+        let jitter = |seed| entropy::jittered(10, 20, &mut Xorshift32::new(seed));
+        assert_ne!(jitter(1), jitter(2));
+        assert_eq!(delays[1].wrapping_sub(delays[0]), jitter(2).wrapping_sub(jitter(1)));
+    }
+
+    #[test]
+    fn test_unsupported_device() {
+        let mut host = UsbHost::new(MockHostBus::new(MockDevice::new(DEVICE_DESCRIPTOR, &[CONFIGURATION_DESCRIPTOR])));
+        host.bus().attach();
+        let mut unsupported = None;
+        for _ in 0..1000 {
+            if let PollResult::UnsupportedDevice(dev_addr) = host.poll(&mut []) {
+                unsupported = Some(dev_addr);
+            }
+        }
+        let info = host.device_info(unsupported.unwrap()).unwrap();
+        assert_eq!((info.vendor_id, info.product_id), (0x1234, 0x5678));
+        // the device was left unconfigured
+        assert_eq!(host.bus().configuration(), None);
+        assert!(host.device_list().all(|entry| entry.phase == DevicePhase::Dormant && entry.configuration.is_none()));
+    }
+
+    #[test]
+    #[cfg(feature = "driver-kbd")]
+    fn test_power_budget() {
+        use crate::power::{BudgetAction, PowerBudget};
+        for (action, configuration) in [(BudgetAction::Refuse, None), (BudgetAction::Warn, Some(1))] {
+            let mut host = UsbHost::new(MockHostBus::new(keyboard()));
+            let mut kbd: KbdDriver = KbdDriver::new();
+            // the keyboard draws 100 mA
+            host.set_power_budget(Some(PowerBudget { root_port: 50, action }));
+            assert_eq!(host.available_current(None), Some(50));
+            host.bus().attach();
+            let mut exceeded = None;
+            for _ in 0..1000 {
+                if let PollResult::PowerBudgetExceeded(dev_addr) = host.poll(&mut [&mut kbd]) {
+                    exceeded = Some(dev_addr);
+                }
+            }
+            assert!(exceeded.is_some() && host.bus().address() == exceeded);
+            assert_eq!(host.bus().configuration(), configuration);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "driver-kbd")]
+    fn test_configuration_readback() {
+        let get_configuration = |response| ControlResponse { request_type: 0x80, request: 0x08, value: 0, index: 0, response };
+        // a device that ignores SET_CONFIGURATION is left dormant, one that stalls GET_CONFIGURATION is trusted
+        for (response, configuration) in [(Response::Data(&[0]), None), (Response::Stall, Some(1))] {
+            let responses = [SET_PROTOCOL, get_configuration(response)];
+            let device = MockDevice { control_responses: &responses, ..keyboard() };
+            let mut host = UsbHost::new(MockHostBus::new(device));
+            let mut kbd: KbdDriver = KbdDriver::new();
+            host.bus().attach();
+            let mut not_applied = None;
+            let mut added = None;
+            for _ in 0..1000 {
+                if let PollResult::ConfigurationNotApplied(dev_addr) = host.poll(&mut [&mut kbd]) {
+                    not_applied = Some(dev_addr);
+                }
+                kbd.poll(&mut host);
+                if let Some(KbdEvent::DeviceAdded(dev_addr)) = kbd.take_event() {
+                    added = Some(dev_addr);
+                }
+            }
+            let dev_addr = host.bus().address().unwrap();
+            assert_eq!(not_applied.is_some(), configuration.is_none());
+            assert_eq!(added.is_some(), configuration.is_some());
+            assert_eq!(host.active_configuration(dev_addr), configuration);
+            let entry = host.device_list().next().unwrap();
+            let phase = if configuration.is_some() { DevicePhase::Configured } else { DevicePhase::Dormant };
+            assert!(entry.phase == phase);
+        }
+    }
+
+    #[test]
+    fn test_power_policy() {
+        let mut host = UsbHost::new(MockHostBus::new(MockDevice::new(DEVICE_DESCRIPTOR, &[CONFIGURATION_DESCRIPTOR])));
+        host.set_power_policy(PowerPolicy::SuspendWhenDormant);
+        host.bus().attach();
+        let mut result = PollResult::NoDevice;
+        for _ in 0..1000 {
+            result = host.poll(&mut []);
+        }
+        // no driver chose a configuration, so the device is left dormant
+        assert!(matches!(result, PollResult::Suspended));
+        assert!(!host.bus().sof_enabled());
+
+        host.set_power_policy(PowerPolicy::KeepAlive);
+        for _ in 0..100 {
+            result = host.poll(&mut []);
+        }
+        assert!(matches!(result, PollResult::Idle));
+        assert!(host.bus().sof_enabled());
+    }
+
+    #[test]
+    fn test_max_packet_size_0() {
+        const DEVICE_DESCRIPTOR_16: &[u8] = &[
+            0x12, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x10, 0x34, 0x12, 0x78, 0x56, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01,
+        ];
+        let mut host = UsbHost::new(MockHostBus::new(MockDevice::new(DEVICE_DESCRIPTOR_16, &[CONFIGURATION_DESCRIPTOR])));
+        host.bus().attach();
+        let mut unsupported = None;
+        for _ in 0..1000 {
+            if let PollResult::UnsupportedDevice(dev_addr) = host.poll(&mut []) {
+                unsupported = Some(dev_addr);
+            }
+        }
+        assert_eq!(host.device_info(unsupported.unwrap()).unwrap().max_packet_size_0, 16);
+        // the size learned from the initial GET_DESCRIPTOR request was used for the descriptor requests of discovery
+        assert_eq!(host.bus().max_packet_size_0(), Some(16));
+    }
+
+    #[test]
+    fn test_enumeration_timeout() {
+        let mut bus = MockHostBus::new(MockDevice::new(DEVICE_DESCRIPTOR, &[CONFIGURATION_DESCRIPTOR]));
+        bus.set_sof_timer(false);
+        // the device acknowledges the SETUP packet of the initial GET_DESCRIPTOR request, but never sends the descriptor
+        bus.set_control_nak(true);
+        let mut host = UsbHost::new(bus);
+        host.bus().attach();
+        let mut timeout = None;
+        for _ in 0..2000 {
+            if let PollResult::Timeout(dev_addr) = host.poll(&mut []) {
+                timeout = Some((dev_addr, host.frame_count()));
+                break;
+            }
+        }
+        let (dev_addr, frames) = timeout.expect("enumeration timed out");
+        assert!(dev_addr.is_none());
+        assert!(frames >= EnumerationConfig::new().timeout);
+        assert!(host.bus().address().is_none());
+
+        // once reconnected, the device is enumerated as usual
+        host.bus().set_control_nak(false);
+        host.bus().detach();
+        host.bus().attach();
+        for _ in 0..1000 {
+            host.poll(&mut []);
+        }
+        assert!(host.bus().address().is_some());
+    }
+
+    #[test]
+    #[cfg(feature = "driver-kbd")]
+    fn test_control_timeout() {
+        let (mut host, mut kbd, dev_addr) = enumerated_keyboard();
+        let mut observer = Observer::default();
+        // let time pass on every poll
+        host.set_sof_events(true);
+        let pipe_id = host.create_control_pipe(dev_addr).unwrap();
+        host.bus().set_control_nak(true);
+        assert!(host.get_descriptor(Some(dev_addr), Some(pipe_id), Recipient::Device, descriptor::TYPE_DEVICE, 0, 18).is_ok());
+        let started = host.frame_count();
+        let mut timeout = None;
+        for _ in 0..2 * CONTROL_TIMEOUT_FRAMES {
+            if let PollResult::Timeout(dev_addr) = host.poll(&mut [&mut kbd, &mut observer]) {
+                timeout = Some(dev_addr);
+                break;
+            }
+        }
+        assert_eq!(timeout, Some(Some(dev_addr)));
+        assert!(host.frame_count() - started >= CONTROL_TIMEOUT_FRAMES);
+        assert_eq!(observer.failed, Some(TransferError::Timeout));
+
+        // the bus is available again
+        host.bus().set_control_nak(false);
+        assert!(host.get_descriptor(Some(dev_addr), Some(pipe_id), Recipient::Device, descriptor::TYPE_DEVICE, 0, 18).is_ok());
+        for _ in 0..10 {
+            host.poll(&mut [&mut kbd, &mut observer]);
+        }
+        assert_eq!(observer.completed, 1);
+    }
+
+    #[test]
+    #[cfg(feature = "driver-kbd")]
+    fn test_auto_clear_halt() {
+        const CLEAR_HALT: ControlResponse = ControlResponse {
+            request_type: 0x02,
+            request: 0x01,
+            value: 0,
+            index: 0x82,
+            response: Response::Data(&[]),
+        };
+        let device = MockDevice { control_responses: &[SET_PROTOCOL, CLEAR_HALT], ..keyboard() };
+        let mut host = UsbHost::new(MockHostBus::new(device));
+        let mut kbd: KbdDriver = KbdDriver::new();
+        let mut observer = Observer::default();
+        let dev_addr = enumerate(&mut host, &mut kbd, None);
+        let pipe_id = host.create_bulk_pipe(dev_addr, 2, UsbDirection::In, 64).unwrap();
+        host.bus().set_bulk_in(b"data");
+        host.bus().set_bulk_stall(true);
+
+        // by default, the driver is informed right away
+        assert!(host.bulk_in(pipe_id, 64).is_ok());
+        for _ in 0..10 {
+            host.poll(&mut [&mut kbd, &mut observer]);
+        }
+        assert_eq!(observer.failed, Some(TransferError::Stall { cleared: false }));
+
+        // with automatic recovery, only once the halt condition was cleared
+        host.set_auto_clear_halt(true);
+        assert!(host.bulk_in(pipe_id, 64).is_ok());
+        host.poll(&mut [&mut kbd, &mut observer]);
+        assert_eq!(observer.failed, Some(TransferError::Stall { cleared: false }));
+        for _ in 0..10 {
+            host.poll(&mut [&mut kbd, &mut observer]);
+        }
+        assert_eq!(observer.failed, Some(TransferError::Stall { cleared: true }));
+        assert!(host.bulk_in(pipe_id, 64).is_ok());
+        for _ in 0..10 {
+            host.poll(&mut [&mut kbd, &mut observer]);
+        }
+        assert_eq!(observer.received, 4);
+
+        // the device refuses to clear the halt condition of another endpoint
+        let other = host.create_bulk_pipe(dev_addr, 3, UsbDirection::In, 64).unwrap();
+        host.bus().set_bulk_stall(true);
+        assert!(host.bulk_in(other, 64).is_ok());
+        for _ in 0..10 {
+            host.poll(&mut [&mut kbd, &mut observer]);
+        }
+        assert_eq!(observer.failed, Some(TransferError::Stall { cleared: false }));
+    }
+
+    /// Poll until the resume sequence is over, and return the number of frames of resume signalling and recovery
+    #[cfg(feature = "driver-kbd")]
+    fn resume_frames(host: &mut UsbHost<MockHostBus<'static>>, kbd: &mut KbdDriver, observer: &mut Observer) -> (usize, usize) {
+        let mut signalling = 0;
+        while host.bus().resume_signalling() && signalling < 100 {
+            assert!(!host.bus().sof_enabled());
+            host.poll(&mut [&mut *kbd, &mut *observer]);
+            signalling += 1;
+        }
+        assert!(host.bus().sof_enabled());
+        let mut recovery = 0;
+        while observer.suspended && recovery < 100 {
+            // transfers have to wait until the devices recovered
+            assert!(matches!(host.poll(&mut [&mut *kbd, &mut *observer]), PollResult::Busy | PollResult::Idle));
+            recovery += 1;
+        }
+        (signalling, recovery)
+    }
+
+    #[test]
+    #[cfg(feature = "driver-kbd")]
+    fn test_suspend_resume() {
+        let (mut host, mut kbd, dev_addr) = enumerated_keyboard();
+        let mut observer = Observer::default();
+        let pipe_id = host.create_control_pipe(dev_addr).unwrap();
+        assert!(host.suspend(&mut [&mut kbd, &mut observer]).is_ok());
+        assert!(observer.suspended);
+        assert!(!host.bus().sof_enabled());
+        assert!(matches!(host.poll(&mut [&mut kbd, &mut observer]), PollResult::Suspended));
+        let setups = host.bus().setup_count();
+        assert!(matches!(
+            host.get_descriptor(Some(dev_addr), None, Recipient::Device, descriptor::TYPE_DEVICE, 0, 18),
+            Err(ControlError::WouldBlock)
+        ));
+        // transfers on pipes are queued until the bus is resumed
+        assert!(host.get_descriptor(Some(dev_addr), Some(pipe_id), Recipient::Device, descriptor::TYPE_DEVICE, 0, 18).is_ok());
+
+        host.resume();
+        assert!(host.bus().resume_signalling());
+        assert_eq!(resume_frames(&mut host, &mut kbd, &mut observer), (20, 10));
+        for _ in 0..10 {
+            host.poll(&mut [&mut kbd, &mut observer]);
+        }
+        assert_eq!(host.bus().setup_count(), setups + 1);
+        assert_eq!(observer.completed, 1);
+    }
+
+    #[test]
+    #[cfg(feature = "driver-kbd")]
+    fn test_remote_wakeup() {
+        let (mut host, mut kbd, _) = enumerated_keyboard();
+        let mut observer = Observer::default();
+        assert!(host.suspend(&mut [&mut kbd, &mut observer]).is_ok());
+        assert!(matches!(host.poll(&mut [&mut kbd, &mut observer]), PollResult::Suspended));
+
+        // the device signals resume, the host takes over the signalling
+        host.bus().push_event(Event::Resume);
+        assert!(matches!(host.poll(&mut [&mut kbd, &mut observer]), PollResult::RemoteWakeup));
+        assert!(host.bus().resume_signalling());
+        assert_eq!(resume_frames(&mut host, &mut kbd, &mut observer), (20, 10));
+        assert!(matches!(host.poll(&mut [&mut kbd, &mut observer]), PollResult::Idle));
+    }
+
+    #[test]
+    #[cfg(feature = "driver-kbd")]
+    fn test_liveness_ping() {
+        let (mut host, mut kbd, dev_addr) = enumerated_keyboard();
+        let mut observer = Observer::default();
+        host.set_sof_events(true);
+        host.set_nak_policy(NakPolicy::UNLIMITED.with_retry_limit(3));
+        let pipe_id = host.create_control_pipe(dev_addr).unwrap();
+        let setup = SetupPacket::new(UsbDirection::In, RequestType::Standard, Recipient::Device, Request::GET_STATUS, 0, 0, 2);
+        assert!(host.set_liveness_ping(pipe_id, Some(LivenessPing { setup, interval: 10, max_failures: 2 })).is_ok());
+        let (setups, started) = (host.bus().setup_count(), host.frame_count());
+        while host.frame_count() - started < 100 {
+            assert!(!matches!(host.poll(&mut [&mut kbd, &mut observer]), PollResult::DeviceUnresponsive(_)));
+        }
+        // the keyboard stalls the request, which still shows that it is alive. Answers are not reported to drivers.
+        assert!((9..=11).contains(&(host.bus().setup_count() - setups)));
+        assert_eq!((observer.completed, observer.failed), (0, None));
+
+        host.bus().set_control_nak(true);
+        let mut unresponsive = None;
+        for _ in 0..100 {
+            if let PollResult::DeviceUnresponsive(dev_addr) = host.poll(&mut [&mut kbd, &mut observer]) {
+                unresponsive = Some(dev_addr);
+                break;
+            }
+        }
+        assert_eq!(unresponsive, Some(dev_addr));
+        assert_eq!(observer.failed, Some(TransferError::BusError(Error::NakLimit)));
+        // the device is still known to the host
+        assert!(host.device_summary(dev_addr).is_some());
+    }
+
+    #[test]
+    #[cfg(feature = "driver-kbd")]
+    fn test_error_threshold() {
+        let (mut host, mut kbd, dev_addr) = enumerated_keyboard();
+        assert!(matches!(kbd.take_event(), Some(KbdEvent::DeviceAdded(_))));
+        host.set_error_threshold(Some(2));
+        let pipe_id = host.create_bulk_pipe(dev_addr, 2, UsbDirection::In, 64).unwrap();
+        host.bus().set_bulk_in(b"data");
+        let transfer = |host: &mut UsbHost<MockHostBus<'static>>, kbd: &mut KbdDriver, fail: bool| {
+            host.bus().set_bulk_nak(fail);
+            assert!(host.bulk_in(pipe_id, 64).is_ok());
+            if fail {
+                // the device does not answer at all
+                host.bus().push_event(Event::Error(Error::RxTimeout));
+            }
+            let mut failed = None;
+            for _ in 0..10 {
+                if let PollResult::DeviceFailed(dev_addr, hub_port) = host.poll(&mut [&mut *kbd]) {
+                    failed = Some((dev_addr, hub_port));
+                }
+            }
+            failed
+        };
+
+        // a successful transfer resets the count
+        assert_eq!(transfer(&mut host, &mut kbd, true), None);
+        assert_eq!(transfer(&mut host, &mut kbd, false), None);
+        assert_eq!(transfer(&mut host, &mut kbd, true), None);
+        assert_eq!(transfer(&mut host, &mut kbd, true), Some((dev_addr, None)));
+
+        // the device is gone, as if it was detached
+        assert!(matches!(kbd.take_event(), Some(KbdEvent::DeviceRemoved(removed)) if removed == dev_addr));
+        assert!(host.device_summary(dev_addr).is_none());
+        assert!(host.bus().interrupt_pipe_device(1).is_none());
     }
 }
@@ -0,0 +1,154 @@
+//! Static memory footprint of the host stack
+//!
+//! All of the state of the host stack and its drivers is statically sized. The [`MemoryUsage`] type breaks down
+//! how much RAM the individual parts occupy, so that the footprint can be checked before flashing.
+//!
+//! The [`report_memory_usage!`](crate::report_memory_usage) macro computes the usage for a given bus type and set of drivers.
+//! Since it evaluates to a constant, it can be used to fail the build when a budget is exceeded:
+//! ```ignore
+//! const USAGE: usbh::memory::MemoryUsage = usbh::report_memory_usage!(UsbHostBus, KbdDriver, MscDriver);
+//! const _: () = assert!(USAGE.total() <= 4096);
+//!
+//! defmt::info!("usbh memory usage: {}", USAGE);
+//! ```
+//!
+//! For a host with another number of pipes than the default (see [`UsbHost`](crate::UsbHost#number-of-pipes)), the
+//! number is passed after the bus type. The same goes for the depth of the control queue and the size of the control
+//! buffer, in this order:
+//! ```ignore
+//! const USAGE: usbh::memory::MemoryUsage = usbh::report_memory_usage!(UsbHostBus, max_pipes = 4, KbdDriver);
+//! const LARGE: usbh::memory::MemoryUsage =
+//!     usbh::report_memory_usage!(UsbHostBus, control_queue_depth = 8, control_buffer_size = 1024, MscDriver);
+//! ```
+
+use crate::{
+    queue, transfer, ControlBuffer, Device, Pipe, PipeId, UsbHost, DEFAULT_CONTROL_BUFFER_SIZE,
+    DEFAULT_CONTROL_QUEUE_DEPTH, DEFAULT_MAX_PIPES, MAX_DEVICES,
+};
+use core::mem::size_of;
+
+/// RAM used by the host stack, in bytes
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MemoryUsage {
+    /// Size of the [`UsbHost`], including the tables below
+    pub host: usize,
+    /// Table of pipes (part of `host`)
+    pub pipe_table: usize,
+    /// Table of attached devices (part of `host`)
+    pub device_table: usize,
+    /// State of the transfer currently in progress (part of `host`)
+    pub active_transfer: usize,
+    /// Buffer for reassembling large control transfers (part of `host`)
+    pub control_buffer: usize,
+    /// Control transfers waiting for the bus to become idle (part of `host`)
+    pub control_queue: usize,
+    /// Combined size of the drivers
+    pub drivers: usize,
+}
+
+impl MemoryUsage {
+    /// Memory used by a [`UsbHost`] with the given bus type and default parameters, without any drivers
+    pub const fn of<B>() -> Self {
+        Self::of_sized::<B, DEFAULT_MAX_PIPES, DEFAULT_CONTROL_QUEUE_DEPTH, DEFAULT_CONTROL_BUFFER_SIZE>()
+    }
+
+    /// Memory used by a [`UsbHost`] with the given bus type, number of pipes, control queue depth and control buffer size,
+    /// without any drivers
+    pub const fn of_sized<
+        B,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    >() -> Self {
+        MemoryUsage {
+            host: size_of::<UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>>(),
+            pipe_table: size_of::<[Option<Pipe>; MAX_PIPES]>(),
+            device_table: size_of::<[Option<Device>; MAX_DEVICES]>(),
+            active_transfer: size_of::<Option<(Option<PipeId>, transfer::Transfer)>>(),
+            control_buffer: size_of::<ControlBuffer<CONTROL_BUFFER_SIZE>>(),
+            control_queue: size_of::<queue::ControlQueue<CONTROL_QUEUE_DEPTH>>(),
+            drivers: 0,
+        }
+    }
+
+    /// Add the given number of bytes used by drivers
+    pub const fn with_drivers(self, drivers: usize) -> Self {
+        MemoryUsage {
+            drivers: self.drivers + drivers,
+            ..self
+        }
+    }
+
+    /// Total memory used by the host and drivers
+    pub const fn total(&self) -> usize {
+        self.host + self.drivers
+    }
+}
+
+/// Computes the [`MemoryUsage`](crate::memory::MemoryUsage) of the host stack at compile time
+///
+/// The first argument is the [`HostBus`](crate::bus::HostBus) implementation, optionally followed by the number of pipes
+/// (`max_pipes = N`), the depth of the control queue (`control_queue_depth = N`) and the size of the control buffer
+/// (`control_buffer_size = N`), and then the types of all drivers that are passed to [`UsbHost::poll`](crate::UsbHost::poll).
+/// Parameters which are not given take their default value.
+///
+/// See [module-level documentation](crate::memory) for an example.
+#[macro_export]
+macro_rules! report_memory_usage {
+    (@sized $bus:ty, [$max_pipes:expr, $depth:expr, $size:expr], max_pipes = $value:expr $(, $($rest:tt)*)?) => {
+        $crate::report_memory_usage!(@sized $bus, [$value, $depth, $size], $($($rest)*)?)
+    };
+    (@sized $bus:ty, [$max_pipes:expr, $depth:expr, $size:expr], control_queue_depth = $value:expr $(, $($rest:tt)*)?) => {
+        $crate::report_memory_usage!(@sized $bus, [$max_pipes, $value, $size], $($($rest)*)?)
+    };
+    (@sized $bus:ty, [$max_pipes:expr, $depth:expr, $size:expr], control_buffer_size = $value:expr $(, $($rest:tt)*)?) => {
+        $crate::report_memory_usage!(@sized $bus, [$max_pipes, $depth, $value], $($($rest)*)?)
+    };
+    (@sized $bus:ty, [$max_pipes:expr, $depth:expr, $size:expr], $($driver:ty),* $(,)?) => {
+        $crate::memory::MemoryUsage::of_sized::<$bus, { $max_pipes }, { $depth }, { $size }>()
+            .with_drivers(0 $(+ ::core::mem::size_of::<$driver>())*)
+    };
+    ($bus:ty $(, $($rest:tt)*)?) => {
+        $crate::report_memory_usage!(
+            @sized $bus,
+            [$crate::DEFAULT_MAX_PIPES, $crate::DEFAULT_CONTROL_QUEUE_DEPTH, $crate::DEFAULT_CONTROL_BUFFER_SIZE],
+            $($($rest)*)?
+        )
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_memory_usage() {
+        const USAGE: MemoryUsage = crate::report_memory_usage!((), [u8; 10], [u8; 20]);
+        assert_eq!(USAGE.drivers, 30);
+        const { assert!(USAGE.control_buffer >= DEFAULT_CONTROL_BUFFER_SIZE) };
+        const { assert!(USAGE.host >= USAGE.pipe_table + USAGE.device_table + USAGE.active_transfer + USAGE.control_buffer + USAGE.control_queue) };
+        assert_eq!(USAGE.total(), USAGE.host + 30);
+    }
+
+    #[test]
+    fn test_report_memory_usage_max_pipes() {
+        const SMALL: MemoryUsage = crate::report_memory_usage!((), max_pipes = 4, [u8; 10]);
+        const DEFAULT: MemoryUsage = crate::report_memory_usage!((), [u8; 10]);
+        assert_eq!(SMALL.drivers, 10);
+        assert_eq!(SMALL.pipe_table * 8, DEFAULT.pipe_table);
+        const { assert!(SMALL.host < DEFAULT.host) };
+
+        const LARGE: MemoryUsage = crate::report_memory_usage!((), control_queue_depth = 8, control_buffer_size = 1024, [u8; 10]);
+        assert_eq!(LARGE.drivers, 10);
+        assert_eq!(LARGE.pipe_table, DEFAULT.pipe_table);
+        const { assert!(LARGE.control_buffer >= 1024) };
+        const { assert!(LARGE.control_queue > DEFAULT.control_queue) };
+        const { assert!(LARGE.host >= DEFAULT.host + 1024 - DEFAULT_CONTROL_BUFFER_SIZE) };
+
+        // parameters can be combined, the others keep their default
+        const BUFFER: MemoryUsage = crate::report_memory_usage!((), max_pipes = 4, control_buffer_size = 1024);
+        assert_eq!((BUFFER.pipe_table, BUFFER.control_queue, BUFFER.drivers), (SMALL.pipe_table, DEFAULT.control_queue, 0));
+        assert_eq!(BUFFER.control_buffer, LARGE.control_buffer);
+    }
+}
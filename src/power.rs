@@ -0,0 +1,103 @@
+//! Budget for the current drawn by devices from the bus
+//!
+//! Each configuration of a device declares how much current it draws from the bus once selected (`bMaxPower` in the
+//! configuration descriptor). Before a device is configured, it may only draw 100 mA.
+//!
+//! With a [`PowerBudget`] installed via [`UsbHost::set_power_budget`](crate::UsbHost::set_power_budget), the host checks
+//! the configuration chosen by a driver against the current available at the port the device is attached to:
+//! - on the root port, the current is given by the application (e.g. 500 mA for a regular port, or less for a port
+//!   supplied from a battery)
+//! - on the ports of a self-powered hub, 500 mA are available, on those of a bus-powered hub 100 mA
+//!
+//! A configuration that exceeds the budget is reported via [`PollResult::PowerBudgetExceeded`](crate::PollResult::PowerBudgetExceeded).
+//! Depending on the [`BudgetAction`], the device is configured anyway, or left dormant. A device on a hub port that was
+//! left dormant still draws up to 100 mA. The application can switch off the port with
+//! [`HubDriver::set_port_power`](crate::driver::hub::HubDriver::set_port_power), if the hub supports per-port power switching.
+
+use crate::descriptor::ConfigurationDescriptor;
+
+/// Current available at the ports of a self-powered hub, in mA
+pub const SELF_POWERED_HUB_PORT: u16 = 500;
+/// Current available at the ports of a bus-powered hub, in mA
+pub const BUS_POWERED_HUB_PORT: u16 = 100;
+
+/// Maximum number of configurations per device, whose power requirements are recorded
+///
+/// Devices with more configurations are rare. Their other configurations are treated as if they drew no current.
+const MAX_CONFIGURATIONS: usize = 4;
+
+/// What the host does with a configuration that draws more current than available
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BudgetAction {
+    /// Configure the device anyway (the violation is still reported)
+    Warn,
+    /// Leave the device dormant
+    Refuse,
+}
+
+/// Current available to devices, see the [module documentation](self)
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PowerBudget {
+    /// Current available at the root port, in mA
+    pub root_port: u16,
+    pub action: BudgetAction,
+}
+
+/// Power requirements of a configuration
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ConfigurationPower {
+    /// Configuration value (as passed to SET_CONFIGURATION)
+    pub value: u8,
+    /// Maximum current drawn from the bus, in mA
+    pub max_current: u16,
+    pub self_powered: bool,
+}
+
+impl From<&ConfigurationDescriptor> for ConfigurationPower {
+    fn from(descriptor: &ConfigurationDescriptor) -> Self {
+        ConfigurationPower {
+            value: descriptor.value,
+            max_current: descriptor.max_power as u16 * 2,
+            self_powered: descriptor.attributes.self_powered(),
+        }
+    }
+}
+
+/// Power requirements of the configurations of a device, recorded during discovery
+#[derive(Copy, Clone, Default)]
+pub(crate) struct DevicePower {
+    configurations: [Option<ConfigurationPower>; MAX_CONFIGURATIONS],
+}
+
+impl DevicePower {
+    pub(crate) fn record(&mut self, power: ConfigurationPower) {
+        if let Some(slot) = self.configurations.iter_mut().find(|slot| slot.is_none_or(|c| c.value == power.value)) {
+            *slot = Some(power);
+        }
+    }
+
+    pub(crate) fn get(&self, value: u8) -> Option<ConfigurationPower> {
+        self.configurations.iter().flatten().find(|c| c.value == value).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_power() {
+        let mut power = DevicePower::default();
+        for value in 1..=5 {
+            power.record(ConfigurationPower { value, max_current: 100 * value as u16, self_powered: false });
+        }
+        // recorded again during a later discovery
+        power.record(ConfigurationPower { value: 2, max_current: 50, self_powered: true });
+        assert!(power.get(2) == Some(ConfigurationPower { value: 2, max_current: 50, self_powered: true }));
+        assert!(power.get(4).is_some_and(|c| c.max_current == 400));
+        assert!(power.get(5).is_none());
+    }
+}
@@ -0,0 +1,137 @@
+//! Queue for control transfers which are submitted while the bus is busy
+
+use crate::types::{DeviceAddress, SetupPacket};
+use crate::PipeId;
+
+/// Largest data stage of a control OUT transfer that can be queued
+pub(crate) const MAX_QUEUED_DATA_SIZE: usize = 64;
+
+/// A control transfer waiting to be started
+#[derive(Copy, Clone)]
+pub(crate) struct QueuedControl {
+    pub(crate) dev_addr: DeviceAddress,
+    pub(crate) pipe_id: PipeId,
+    pub(crate) setup: SetupPacket,
+    /// Data stage of an OUT transfer, `None` for IN transfers
+    out: Option<([u8; MAX_QUEUED_DATA_SIZE], usize)>,
+}
+
+impl QueuedControl {
+    pub(crate) fn new_in(dev_addr: DeviceAddress, pipe_id: PipeId, setup: SetupPacket) -> Self {
+        QueuedControl { dev_addr, pipe_id, setup, out: None }
+    }
+
+    /// Returns `None` if the `data` is too large to be queued
+    pub(crate) fn new_out(dev_addr: DeviceAddress, pipe_id: PipeId, setup: SetupPacket, data: &[u8]) -> Option<Self> {
+        let mut buf = [0; MAX_QUEUED_DATA_SIZE];
+        buf.get_mut(..data.len())?.copy_from_slice(data);
+        Some(QueuedControl { dev_addr, pipe_id, setup, out: Some((buf, data.len())) })
+    }
+
+    /// Data stage of an OUT transfer, `None` for IN transfers
+    pub(crate) fn out_data(&self) -> Option<&[u8]> {
+        self.out.as_ref().map(|(buf, len)| &buf[..*len])
+    }
+}
+
+/// Fixed-size FIFO of control transfers
+///
+/// Transfers are started in the order in which they were submitted, once the bus becomes idle.
+pub(crate) struct ControlQueue<const DEPTH: usize> {
+    entries: [Option<QueuedControl>; DEPTH],
+    /// Index of the oldest entry
+    head: usize,
+    len: usize,
+}
+
+impl<const DEPTH: usize> ControlQueue<DEPTH> {
+    pub(crate) const fn new() -> Self {
+        ControlQueue { entries: [None; DEPTH], head: 0, len: 0 }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Append a transfer to the queue. Returns `false` if the queue is full.
+    pub(crate) fn push(&mut self, transfer: QueuedControl) -> bool {
+        if self.len == DEPTH {
+            return false;
+        }
+        self.entries[(self.head + self.len) % DEPTH] = Some(transfer);
+        self.len += 1;
+        true
+    }
+
+    /// Remove the oldest transfer from the queue
+    pub(crate) fn pop(&mut self) -> Option<QueuedControl> {
+        if self.len == 0 {
+            return None;
+        }
+        let transfer = self.entries[self.head].take();
+        self.head = (self.head + 1) % DEPTH;
+        self.len -= 1;
+        transfer
+    }
+
+    /// Drop all transfers for which `keep` returns `false`, preserving the order of the others
+    pub(crate) fn retain(&mut self, mut keep: impl FnMut(&QueuedControl) -> bool) {
+        for _ in 0..self.len {
+            // Unwrap safety: there are `len` entries in the queue
+            let transfer = self.pop().unwrap();
+            if keep(&transfer) {
+                self.push(transfer);
+            }
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        *self = Self::new();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::SetupPacket;
+    use core::num::NonZeroU8;
+
+    fn transfer(addr: u8, value: u16) -> QueuedControl {
+        let setup = SetupPacket { request_type: 0x80, request: 0x06, value, index: 0, length: 0 };
+        QueuedControl::new_in(DeviceAddress(NonZeroU8::new(addr).unwrap()), PipeId(0, 0), setup)
+    }
+
+    #[test]
+    fn test_fifo_order() {
+        let mut queue: ControlQueue<2> = ControlQueue::new();
+        assert!(queue.push(transfer(1, 1)));
+        assert!(queue.push(transfer(1, 2)));
+        assert!(!queue.push(transfer(1, 3)));
+        assert_eq!(queue.pop().map(|t| t.setup.value), Some(1));
+        assert!(queue.push(transfer(1, 3)));
+        assert_eq!(queue.pop().map(|t| t.setup.value), Some(2));
+        assert_eq!(queue.pop().map(|t| t.setup.value), Some(3));
+        assert!(queue.pop().is_none());
+    }
+
+    #[test]
+    fn test_retain() {
+        let mut queue: ControlQueue<4> = ControlQueue::new();
+        queue.push(transfer(1, 1));
+        queue.push(transfer(2, 2));
+        queue.push(transfer(1, 3));
+        queue.retain(|t| u8::from(t.dev_addr) != 2);
+        assert_eq!(queue.pop().map(|t| t.setup.value), Some(1));
+        assert_eq!(queue.pop().map(|t| t.setup.value), Some(3));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_out_data_too_large() {
+        let setup = SetupPacket { request_type: 0x00, request: 0x09, value: 0, index: 0, length: 0 };
+        let addr = DeviceAddress(NonZeroU8::new(1).unwrap());
+        assert!(QueuedControl::new_out(addr, PipeId(0, 0), setup, &[0; MAX_QUEUED_DATA_SIZE + 1]).is_none());
+        let queued = QueuedControl::new_out(addr, PipeId(0, 0), setup, &[1, 2, 3]).unwrap();
+        assert_eq!(queued.out_data(), Some(&[1, 2, 3][..]));
+    }
+}
@@ -0,0 +1,205 @@
+//! Buffering of data received on interrupt IN pipes
+//!
+//! By default the host passes the data of interrupt IN pipes to the drivers right away, from within
+//! [`UsbHost::poll`](crate::UsbHost::poll). Drivers usually turn each report into an event, but only hold on to the most
+//! recent one, so reports that arrive between two calls to the driver's `take_event` are lost if the application polls slowly.
+//!
+//! With a report buffer installed via [`UsbHost::set_report_buffer`](crate::UsbHost::set_report_buffer), the host stores
+//! the reports instead, and the application hands them to the drivers one at a time, via
+//! [`UsbHost::dispatch_report`](crate::UsbHost::dispatch_report):
+//!
+//! ```ignore
+//! static mut REPORTS: ReportRing<8, 8> = ReportRing::new();
+//!
+//! usb_host.set_report_buffer(unsafe { &mut *core::ptr::addr_of_mut!(REPORTS) });
+//!
+//! loop {
+//!     usb_host.poll(&mut [&mut kbd]);
+//!     // handle events from control transfers etc.
+//!     handle(kbd.take_event());
+//!
+//!     while usb_host.dispatch_report(&mut [&mut kbd]) {
+//!         handle(kbd.take_event());
+//!     }
+//! }
+//! ```
+//!
+//! When the buffer is full, the host leaves further data in the host bus' buffer of the pipe, and does not poll the
+//! endpoint again until there is room. The device keeps NAKing in the meantime, so no report is dropped. Interrupt pipes
+//! that the host runs itself (see [`InterruptPipeHw`](crate::bus::InterruptPipeHw)) are not polled while the buffer is full.
+//!
+//! Interrupt OUT pipes are not affected.
+
+use crate::PipeId;
+
+/// Storage for reports received on interrupt IN pipes
+///
+/// Implemented by [`ReportRing`]. Reports are dispatched in the order in which they were pushed.
+pub trait ReportBuffer {
+    /// Append a report. Returns `false` if the buffer is full.
+    fn push(&mut self, pipe_id: PipeId, data: &[u8]) -> bool;
+
+    /// Whether another report can be pushed
+    fn is_full(&self) -> bool;
+
+    /// The oldest report, if any
+    fn front(&self) -> Option<(PipeId, &[u8])>;
+
+    /// Remove the oldest report
+    fn pop(&mut self);
+
+    /// Remove all reports of the given pipe
+    fn discard(&mut self, pipe_id: PipeId);
+
+    /// Remove all reports
+    fn clear(&mut self);
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Report<const SIZE: usize> {
+    pipe_id: PipeId,
+    len: usize,
+    data: [u8; SIZE],
+}
+
+/// Fixed-size FIFO of up to `DEPTH` reports, each up to `SIZE` bytes
+///
+/// Longer reports are truncated. The size should match the largest interrupt IN endpoint in use
+/// (8 bytes for boot protocol keyboards).
+#[derive(Debug)]
+pub struct ReportRing<const DEPTH: usize, const SIZE: usize> {
+    reports: [Report<SIZE>; DEPTH],
+    /// Index of the oldest report
+    head: usize,
+    len: usize,
+}
+
+impl<const DEPTH: usize, const SIZE: usize> ReportRing<DEPTH, SIZE> {
+    pub const fn new() -> Self {
+        ReportRing { reports: [Report { pipe_id: PipeId(0, 0), len: 0, data: [0; SIZE] }; DEPTH], head: 0, len: 0 }
+    }
+
+    /// Number of reports in the buffer
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<const DEPTH: usize, const SIZE: usize> Default for ReportRing<DEPTH, SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const DEPTH: usize, const SIZE: usize> ReportBuffer for ReportRing<DEPTH, SIZE> {
+    fn push(&mut self, pipe_id: PipeId, data: &[u8]) -> bool {
+        if self.len == DEPTH {
+            return false;
+        }
+        let report = &mut self.reports[(self.head + self.len) % DEPTH];
+        let len = data.len().min(SIZE);
+        report.pipe_id = pipe_id;
+        report.len = len;
+        report.data[..len].copy_from_slice(&data[..len]);
+        self.len += 1;
+        true
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == DEPTH
+    }
+
+    fn front(&self) -> Option<(PipeId, &[u8])> {
+        if self.len == 0 {
+            return None;
+        }
+        let report = &self.reports[self.head];
+        Some((report.pipe_id, &report.data[..report.len]))
+    }
+
+    fn pop(&mut self) {
+        if self.len > 0 {
+            self.head = (self.head + 1) % DEPTH;
+            self.len -= 1;
+        }
+    }
+
+    fn discard(&mut self, pipe_id: PipeId) {
+        let mut kept = 0;
+        for i in 0..self.len {
+            let report = self.reports[(self.head + i) % DEPTH];
+            if report.pipe_id != pipe_id {
+                self.reports[(self.head + kept) % DEPTH] = report;
+                kept += 1;
+            }
+        }
+        self.len = kept;
+    }
+
+    fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "driver-kbd")]
+    use crate::bus::mock::fixtures::enumerated_keyboard;
+
+    #[test]
+    fn test_report_ring() {
+        let mut ring: ReportRing<3, 4> = ReportRing::new();
+        assert!(ring.push(PipeId(1, 0), &[1, 2]));
+        assert!(ring.push(PipeId(2, 0), &[3, 4, 5, 6, 7]));
+        assert!(ring.push(PipeId(1, 0), &[8]));
+        assert!(ring.is_full() && !ring.push(PipeId(1, 0), &[9]));
+        assert!(ring.front() == Some((PipeId(1, 0), &[1, 2][..])));
+        ring.pop();
+        // truncated to the report size
+        assert!(ring.front() == Some((PipeId(2, 0), &[3, 4, 5, 6][..])));
+
+        // wraps around
+        assert!(ring.push(PipeId(2, 0), &[10]));
+        ring.discard(PipeId(2, 0));
+        assert_eq!(ring.len(), 1);
+        assert!(ring.front() == Some((PipeId(1, 0), &[8][..])));
+        ring.pop();
+        assert!(ring.is_empty() && ring.front().is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "driver-kbd")]
+    fn test_report_buffer() {
+        use crate::driver::kbd::KbdEvent;
+        static mut REPORTS: ReportRing<2, 8> = ReportRing::new();
+
+        let (mut host, mut kbd, _) = enumerated_keyboard();
+        host.set_report_buffer(unsafe { &mut *core::ptr::addr_of_mut!(REPORTS) });
+        kbd.take_event();
+
+        // three reports arrive before the application gets to handle them
+        for key in [0x04, 0x05, 0x06] {
+            assert!(host.bus().send_interrupt(1, &[0, 0, key, 0, 0, 0, 0, 0]));
+            host.poll(&mut [&mut kbd]);
+        }
+        assert!(kbd.take_event().is_none());
+        // the buffer is full, so the third report is still held by the bus
+        assert!(!host.bus().send_interrupt(1, &[0; 8]));
+
+        for key in [0x04, 0x05, 0x06] {
+            assert!(host.dispatch_report(&mut [&mut kbd]));
+            match kbd.take_event() {
+                Some(KbdEvent::InputChanged(_, report)) => assert!(report.pressed_keys().eq([key])),
+                _ => panic!("expected input report"),
+            }
+        }
+        assert!(!host.dispatch_report(&mut [&mut kbd]));
+        assert!(host.bus().send_interrupt(1, &[0; 8]));
+    }
+}
@@ -0,0 +1,190 @@
+//! Sharing the host between an interrupt handler and thread mode
+//!
+//! Usually the host is polled from the USB interrupt handler, while the application uses the drivers (e.g. to take
+//! events, or start transfers) from its main loop. Both need mutable access to the host and the drivers, so they must
+//! be kept in a `static`, guarded by a critical section. [`SharedUsbHost`] does that.
+//!
+//! Requires the `critical-section` feature, and an implementation of the [`critical-section`](https://docs.rs/critical-section)
+//! crate for the target (e.g. from `cortex-m`, with its `critical-section-single-core` feature).
+//!
+//! ```ignore
+//! struct Drivers {
+//!     kbd: KbdDriver,
+//!     hub: HubDriver,
+//! }
+//!
+//! static USB: SharedUsbHost<HostBus, Drivers> = SharedUsbHost::new();
+//!
+//! fn main() {
+//!     USB.init(UsbHost::new(host_bus), Drivers { kbd: KbdDriver::new(), hub: HubDriver::new() });
+//!     loop {
+//!         USB.with(|host, drivers| {
+//!             drivers.kbd.poll(host);
+//!             while let Some(event) = drivers.kbd.take_event() {
+//!                 // ...
+//!             }
+//!         });
+//!     }
+//! }
+//!
+//! #[interrupt]
+//! fn USBCTRL_IRQ() {
+//!     USB.with(|host, drivers| host.poll(&mut [&mut drivers.kbd, &mut drivers.hub]));
+//! }
+//! ```
+//!
+//! Interrupts are disabled while the closure passed to [`with`](SharedUsbHost::with) runs, so it should return quickly.
+
+use crate::{UsbHost, DEFAULT_CONTROL_BUFFER_SIZE, DEFAULT_CONTROL_QUEUE_DEPTH, DEFAULT_MAX_PIPES};
+use core::cell::RefCell;
+use critical_section::Mutex;
+
+/// Host and drivers, which can be accessed from interrupt handlers and thread mode alike
+///
+/// See [module-level documentation](self) for details.
+///
+/// `MAX_PIPES`, `CONTROL_QUEUE_DEPTH` and `CONTROL_BUFFER_SIZE` are the sizes of the host's pipe table, control queue and
+/// control buffer (see [`UsbHost`](UsbHost#number-of-pipes)).
+pub struct SharedUsbHost<
+    B,
+    D,
+    const MAX_PIPES: usize = DEFAULT_MAX_PIPES,
+    const CONTROL_QUEUE_DEPTH: usize = DEFAULT_CONTROL_QUEUE_DEPTH,
+    const CONTROL_BUFFER_SIZE: usize = DEFAULT_CONTROL_BUFFER_SIZE,
+> {
+    inner: Mutex<RefCell<Option<Shared<B, D, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>>>>,
+}
+
+type Shared<
+    B,
+    D,
+    const MAX_PIPES: usize,
+    const CONTROL_QUEUE_DEPTH: usize,
+    const CONTROL_BUFFER_SIZE: usize,
+> = (
+    UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+    D,
+);
+
+impl<
+        B,
+        D,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    > SharedUsbHost<B, D, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>
+{
+    /// Create an empty instance, to be initialized with [`init`](SharedUsbHost::init)
+    ///
+    /// Being `const`, this can be used to initialize a `static`.
+    pub const fn new() -> Self {
+        Self {
+            inner: Mutex::new(RefCell::new(None)),
+        }
+    }
+
+    /// Store the given host and drivers, replacing any that were stored before
+    pub fn init(
+        &self,
+        host: UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+        drivers: D,
+    ) {
+        critical_section::with(|cs| {
+            self.inner.borrow(cs).replace(Some((host, drivers)));
+        });
+    }
+
+    /// Remove the host and drivers again, e.g. to shut down the host controller
+    ///
+    /// Returns `None` if they were not initialized, or are currently in use (i.e. this is called from within [`with`](SharedUsbHost::with)).
+    pub fn take(
+        &self,
+    ) -> Option<(
+        UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+        D,
+    )> {
+        critical_section::with(|cs| self.inner.borrow(cs).try_borrow_mut().ok()?.take())
+    }
+
+    /// Whether the host was initialized (and not taken since)
+    pub fn is_initialized(&self) -> bool {
+        critical_section::with(|cs| self.inner.borrow(cs).try_borrow().map_or(true, |inner| inner.is_some()))
+    }
+
+    /// Call `f` with the host and drivers, within a critical section
+    ///
+    /// Returns the result of `f`, or `None` if the host was not initialized yet. Also returns `None` without calling `f`,
+    /// if the host is in use already, i.e. when called from within another call to `with`.
+    pub fn with<R>(
+        &self,
+        f: impl FnOnce(
+            &mut UsbHost<B, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>,
+            &mut D,
+        ) -> R,
+    ) -> Option<R> {
+        critical_section::with(|cs| {
+            let mut inner = self.inner.borrow(cs).try_borrow_mut().ok()?;
+            let (host, drivers) = inner.as_mut()?;
+            Some(f(host, drivers))
+        })
+    }
+}
+
+impl<
+        B,
+        D,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    > Default for SharedUsbHost<B, D, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<
+        B,
+        D,
+        const MAX_PIPES: usize,
+        const CONTROL_QUEUE_DEPTH: usize,
+        const CONTROL_BUFFER_SIZE: usize,
+    > core::fmt::Debug
+    for SharedUsbHost<B, D, MAX_PIPES, CONTROL_QUEUE_DEPTH, CONTROL_BUFFER_SIZE>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SharedUsbHost")
+            .field("initialized", &self.is_initialized())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::mock::{MockDevice, MockHostBus};
+    use crate::PollResult;
+
+    const DEVICE_DESCRIPTOR: &[u8] = &[
+        0x12, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x40, 0x34, 0x12, 0x78, 0x56, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01,
+    ];
+
+    static SHARED: SharedUsbHost<MockHostBus<'static>, ()> = SharedUsbHost::new();
+
+    #[test]
+    fn test_shared_host() {
+        assert!(!SHARED.is_initialized());
+        assert!(SHARED.with(|_, _| ()).is_none());
+
+        SHARED.init(UsbHost::new(MockHostBus::new(MockDevice::new(DEVICE_DESCRIPTOR, &[]))), ());
+        assert!(SHARED.is_initialized());
+        assert!(matches!(SHARED.with(|host, _| host.poll(&mut [])), Some(PollResult::NoDevice)));
+
+        // nested access is refused, rather than panicking
+        assert_eq!(SHARED.with(|_, _| SHARED.with(|_, _| ()).is_none()), Some(true));
+        assert_eq!(SHARED.with(|_, _| SHARED.take().is_none()), Some(true));
+
+        assert!(SHARED.take().is_some());
+        assert!(!SHARED.is_initialized());
+    }
+}
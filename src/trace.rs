@@ -0,0 +1,124 @@
+//! Transaction level tracing, for protocol debugging
+//!
+//! With the `trace` feature, an application can register a [`TraceHook`] with
+//! [`UsbHost::set_trace_hook`](crate::UsbHost::set_trace_hook). The host then reports every SETUP packet and data stage it
+//! hands to the bus, every event it receives from the bus (which includes the handshake that ended a transaction), and
+//! every change of its phase. Together these are enough to reconstruct what happened on the wire, e.g. to produce a dump
+//! in the style of a logic analyzer.
+//!
+//! ```ignore
+//! struct Dump;
+//!
+//! impl TraceHook for Dump {
+//!     fn trace(&mut self, frame: u32, record: TraceRecord) {
+//!         defmt::println!("{=u32} {}", frame, defmt::Debug2Format(&record));
+//!     }
+//! }
+//!
+//! static mut DUMP: Dump = Dump;
+//! usb_host.set_trace_hook(unsafe { &mut *core::ptr::addr_of_mut!(DUMP) });
+//! ```
+//!
+//! The hook is called from within [`UsbHost::poll`](crate::UsbHost::poll) and the transfer methods, so it should return
+//! quickly (e.g. by copying the record into a ring buffer). Bulk transfers running on
+//! [`TransferChannels`](crate::bus::TransferChannels) only show up through their events.
+//!
+//! To analyse traces on a PC, [`capture::UsbmonCapture`] encodes the records as a capture file for Wireshark.
+
+pub mod capture;
+
+use crate::bus;
+use crate::diagnostics::HostPhase;
+use crate::types::{DeviceAddress, TransferType};
+
+/// Something the host did or observed, see the [module documentation](self)
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum TraceRecord {
+    /// A SETUP packet was handed to the bus
+    Setup {
+        /// Recipient of the packet, `None` for the default address during enumeration
+        dev_addr: Option<DeviceAddress>,
+        /// The eight bytes of the packet, as sent on the wire
+        packet: [u8; 8],
+    },
+    /// An IN data stage (or a transaction of an interrupt pipe run by the host) was started, for up to `length` bytes
+    DataIn {
+        dev_addr: Option<DeviceAddress>,
+        endpoint: u8,
+        transfer_type: TransferType,
+        length: u16,
+        /// Data PID of the first packet (`true` for DATA1)
+        pid: bool,
+    },
+    /// An OUT data stage was started, sending `length` bytes
+    DataOut {
+        dev_addr: Option<DeviceAddress>,
+        endpoint: u8,
+        transfer_type: TransferType,
+        length: u16,
+        /// Data PID of the first packet (`true` for DATA1)
+        pid: bool,
+    },
+    /// The bus reported an event, e.g. [`TransComplete`](bus::Event::TransComplete) or [`Stall`](bus::Event::Stall)
+    /// for the transaction in flight
+    Event(bus::Event),
+    /// The host moved on to another phase
+    Phase { from: HostPhase, to: HostPhase },
+}
+
+/// Receives the [`TraceRecord`]s of a host
+pub trait TraceHook {
+    /// Called for every record, with the [frame count](crate::UsbHost::frame_count) at the time it was made
+    fn trace(&mut self, frame: u32, record: TraceRecord);
+}
+
+#[cfg(all(test, feature = "driver-kbd"))]
+mod tests {
+    use super::*;
+    use crate::bus::mock::fixtures::{enumerate, keyboard};
+
+    #[test]
+    fn test_trace_hook() {
+        use crate::bus::mock::MockHostBus;
+        use crate::driver::kbd::KbdDriver;
+        use crate::UsbHost;
+
+        struct Recorder {
+            first_setup: Option<[u8; 8]>,
+            data_stages: usize,
+            completions: usize,
+            /// Number of phase changes, and the most recent one
+            phases: (usize, Option<(HostPhase, HostPhase)>),
+        }
+
+        impl TraceHook for Recorder {
+            fn trace(&mut self, _frame: u32, record: TraceRecord) {
+                match record {
+                    TraceRecord::Setup { packet, .. } => {
+                        self.first_setup.get_or_insert(packet);
+                    }
+                    TraceRecord::DataIn { .. } | TraceRecord::DataOut { .. } => self.data_stages += 1,
+                    TraceRecord::Event(bus::Event::TransComplete) => self.completions += 1,
+                    TraceRecord::Event(_) => {}
+                    TraceRecord::Phase { from, to } => self.phases = (self.phases.0 + 1, Some((from, to))),
+                }
+            }
+        }
+
+        static mut RECORDER: Recorder =
+            Recorder { first_setup: None, data_stages: 0, completions: 0, phases: (0, None) };
+        let mut host = UsbHost::new(MockHostBus::new(keyboard()));
+        let mut kbd: KbdDriver = KbdDriver::new();
+        host.set_trace_hook(unsafe { &mut *core::ptr::addr_of_mut!(RECORDER) });
+        enumerate(&mut host, &mut kbd, None);
+        assert_eq!(host.bus().configuration(), Some(1));
+
+        let recorder = unsafe { &*core::ptr::addr_of!(RECORDER) };
+        // enumeration starts with GET_DESCRIPTOR(DEVICE)
+        let setup = recorder.first_setup.unwrap();
+        assert_eq!((setup[0], setup[1], setup[3]), (0x80, 0x06, 0x01));
+        assert!(recorder.data_stages > 0 && recorder.completions > recorder.data_stages);
+        assert!(recorder.phases.0 >= 3);
+        assert_eq!(recorder.phases.1.map(|(_, to)| to), Some(HostPhase::Idle));
+    }
+}
@@ -0,0 +1,308 @@
+//! Capture files, for analysing traces in Wireshark
+//!
+//! [`UsbmonCapture`] is a [`TraceHook`] which encodes the records it receives as a
+//! [pcap](https://wiki.wireshark.org/Development/LibpcapFileFormat) stream with the `LINKTYPE_USB_LINUX` link type, the
+//! format in which Linux `usbmon` captures are stored. The bytes are handed to an application-provided
+//! [`CaptureWriter`] (e.g. a UART, RTT channel or file), and can be opened in Wireshark once they are saved to a file.
+//!
+//! Each SETUP packet and data stage becomes a submission (`S`) record, and the event that ended it becomes the matching
+//! completion (`C`) record, carrying the status Linux would report (e.g. `-EPIPE` for a STALL). Since trace records do
+//! not carry payload, the records only contain the setup packet and the requested length, not the data itself.
+//! Events of interrupt pipes run by the bus hardware, bulk transfers on
+//! [`TransferChannels`](crate::bus::TransferChannels) and phase changes are not part of the capture.
+//!
+//! ```ignore
+//! struct Uart(/* ... */);
+//!
+//! impl CaptureWriter for Uart {
+//!     fn write(&mut self, bytes: &[u8]) {
+//!         self.0.write_all(bytes);
+//!     }
+//! }
+//!
+//! static mut CAPTURE: UsbmonCapture<Uart> = UsbmonCapture::new(Uart(/* ... */));
+//! usb_host.set_trace_hook(unsafe { &mut *core::ptr::addr_of_mut!(CAPTURE) });
+//! ```
+
+use super::{TraceHook, TraceRecord};
+use crate::bus::{Error, Event};
+use crate::types::{DeviceAddress, TransferType};
+
+/// Destination for the bytes of a capture
+pub trait CaptureWriter {
+    /// Write all of the given bytes
+    ///
+    /// Called from within the trace hook, so the same constraints apply (see the [module documentation](super)).
+    fn write(&mut self, bytes: &[u8]);
+}
+
+/// Link type of captures in the usbmon format, with the 48 byte header
+const LINKTYPE_USB_LINUX: u32 = 189;
+/// Size of the usbmon header of each packet
+const USBMON_HEADER_LEN: usize = 48;
+
+const EINPROGRESS: i32 = 115;
+const EPIPE: i32 = 32;
+const EPROTO: i32 = 71;
+const EILSEQ: i32 = 84;
+const EOVERFLOW: i32 = 75;
+const ETIMEDOUT: i32 = 110;
+const ESHUTDOWN: i32 = 108;
+
+/// The stage that was submitted most recently, which the next event completes
+#[derive(Copy, Clone, Debug)]
+struct Submission {
+    id: u64,
+    transfer_type: u8,
+    endpoint: u8,
+    dev_addr: u8,
+    setup: Option<[u8; 8]>,
+}
+
+/// Encodes trace records in the usbmon pcap format, see the [module documentation](self)
+#[derive(Debug)]
+pub struct UsbmonCapture<W: CaptureWriter> {
+    writer: W,
+    bus_number: u16,
+    header_written: bool,
+    next_id: u64,
+    pending: Option<Submission>,
+}
+
+impl<W: CaptureWriter> UsbmonCapture<W> {
+    /// Create a capture that writes to the given writer
+    ///
+    /// The pcap file header is written along with the first record.
+    pub const fn new(writer: W) -> Self {
+        Self {
+            writer,
+            bus_number: 1,
+            header_written: false,
+            next_id: 1,
+            pending: None,
+        }
+    }
+
+    /// Bus number recorded for all packets (1 by default)
+    ///
+    /// Useful to tell apart the captures of multiple hosts, when they are merged into one file.
+    pub const fn with_bus_number(mut self, bus_number: u16) -> Self {
+        self.bus_number = bus_number;
+        self
+    }
+
+    /// Access the writer
+    pub fn writer(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    /// Stop capturing, and return the writer
+    pub fn into_writer(self) -> W {
+        self.writer
+    }
+
+    fn submit(&mut self, frame: u32, submission: Submission, length: u16) {
+        self.pending = Some(submission);
+        self.next_id = self.next_id.wrapping_add(1);
+        self.write_packet(frame, &submission, b'S', -EINPROGRESS, length as u32);
+    }
+
+    fn complete(&mut self, frame: u32, status: i32) {
+        if let Some(submission) = self.pending.take() {
+            self.write_packet(frame, &submission, b'C', status, 0);
+        }
+    }
+
+    fn write_packet(&mut self, frame: u32, submission: &Submission, kind: u8, status: i32, length: u32) {
+        if !self.header_written {
+            self.header_written = true;
+            let mut header = [0; 24];
+            header[0..4].copy_from_slice(&0xa1b2c3d4u32.to_le_bytes());
+            header[4..6].copy_from_slice(&2u16.to_le_bytes());
+            header[6..8].copy_from_slice(&4u16.to_le_bytes());
+            // thiszone and sigfigs stay 0
+            header[16..20].copy_from_slice(&(u16::MAX as u32).to_le_bytes());
+            header[20..24].copy_from_slice(&LINKTYPE_USB_LINUX.to_le_bytes());
+            self.writer.write(&header);
+        }
+
+        // Timestamps are derived from the frame count, so they have millisecond resolution
+        let (seconds, micros) = (frame / 1000, (frame % 1000) * 1000);
+
+        let mut record = [0; 16];
+        record[0..4].copy_from_slice(&seconds.to_le_bytes());
+        record[4..8].copy_from_slice(&micros.to_le_bytes());
+        record[8..12].copy_from_slice(&(USBMON_HEADER_LEN as u32).to_le_bytes());
+        record[12..16].copy_from_slice(&(USBMON_HEADER_LEN as u32).to_le_bytes());
+        self.writer.write(&record);
+
+        let mut header = [0; USBMON_HEADER_LEN];
+        header[0..8].copy_from_slice(&submission.id.to_le_bytes());
+        header[8] = kind;
+        header[9] = submission.transfer_type;
+        header[10] = submission.endpoint;
+        header[11] = submission.dev_addr;
+        header[12..14].copy_from_slice(&self.bus_number.to_le_bytes());
+        let setup = submission.setup.filter(|_| kind == b'S');
+        header[14] = if setup.is_some() { 0 } else { b'-' };
+        // No payload follows in either direction
+        header[15] = if submission.endpoint & 0x80 != 0 { b'<' } else { b'>' };
+        header[16..24].copy_from_slice(&(seconds as i64).to_le_bytes());
+        header[24..28].copy_from_slice(&(micros as i32).to_le_bytes());
+        header[28..32].copy_from_slice(&status.to_le_bytes());
+        header[32..36].copy_from_slice(&length.to_le_bytes());
+        // len_cap stays 0
+        if let Some(setup) = setup {
+            header[40..48].copy_from_slice(&setup);
+        }
+        self.writer.write(&header);
+    }
+}
+
+impl<W: CaptureWriter> TraceHook for UsbmonCapture<W> {
+    fn trace(&mut self, frame: u32, record: TraceRecord) {
+        match record {
+            TraceRecord::Setup { dev_addr, packet } => {
+                let submission = Submission {
+                    id: self.next_id,
+                    transfer_type: usbmon_transfer_type(TransferType::Control),
+                    endpoint: packet[0] & 0x80,
+                    dev_addr: usbmon_dev_addr(dev_addr),
+                    setup: Some(packet),
+                };
+                self.submit(frame, submission, u16::from_le_bytes([packet[6], packet[7]]));
+            }
+            TraceRecord::DataIn { dev_addr, endpoint, transfer_type, length, .. }
+            | TraceRecord::DataOut { dev_addr, endpoint, transfer_type, length, .. } => {
+                let direction = if matches!(record, TraceRecord::DataIn { .. }) { 0x80 } else { 0 };
+                let submission = Submission {
+                    id: self.next_id,
+                    transfer_type: usbmon_transfer_type(transfer_type),
+                    endpoint: endpoint | direction,
+                    dev_addr: usbmon_dev_addr(dev_addr),
+                    setup: None,
+                };
+                self.submit(frame, submission, length);
+            }
+            TraceRecord::Event(Event::TransComplete) => self.complete(frame, 0),
+            TraceRecord::Event(Event::Stall) => self.complete(frame, -EPIPE),
+            TraceRecord::Event(Event::Error(error)) => self.complete(frame, -usbmon_errno(error)),
+            TraceRecord::Event(Event::Detached) => self.complete(frame, -ESHUTDOWN),
+            TraceRecord::Event(_) | TraceRecord::Phase { .. } => {}
+        }
+    }
+}
+
+fn usbmon_transfer_type(transfer_type: TransferType) -> u8 {
+    match transfer_type {
+        TransferType::Isochronous => 0,
+        TransferType::Interrupt => 1,
+        TransferType::Control => 2,
+        TransferType::Bulk => 3,
+    }
+}
+
+fn usbmon_dev_addr(dev_addr: Option<DeviceAddress>) -> u8 {
+    dev_addr.map(u8::from).unwrap_or(0)
+}
+
+/// Error number that Linux host controller drivers report for the given error
+fn usbmon_errno(error: Error) -> i32 {
+    match error {
+        Error::Crc | Error::DataSequence => EILSEQ,
+        Error::RxOverflow => EOVERFLOW,
+        Error::NakLimit => ETIMEDOUT,
+        Error::BitStuffing | Error::RxTimeout | Error::Other => EPROTO,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Buffer {
+        data: [u8; 512],
+        len: usize,
+    }
+
+    impl CaptureWriter for Buffer {
+        fn write(&mut self, bytes: &[u8]) {
+            self.data[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+            self.len += bytes.len();
+        }
+    }
+
+    fn capture() -> UsbmonCapture<Buffer> {
+        UsbmonCapture::new(Buffer { data: [0; 512], len: 0 })
+    }
+
+    /// The usbmon header of the n-th packet
+    fn packet(buffer: &Buffer, n: usize) -> &[u8] {
+        let start = 24 + n * (16 + USBMON_HEADER_LEN) + 16;
+        &buffer.data[start..start + USBMON_HEADER_LEN]
+    }
+
+    #[test]
+    fn test_file_header() {
+        let mut capture = capture();
+        capture.trace(0, TraceRecord::Event(Event::Sof));
+        assert_eq!(capture.writer().len, 0);
+
+        capture.trace(0, TraceRecord::Setup { dev_addr: None, packet: [0x80, 0x06, 0x00, 0x01, 0, 0, 0x12, 0] });
+        let buffer = capture.into_writer();
+        assert_eq!(buffer.len, 24 + 16 + USBMON_HEADER_LEN);
+        assert_eq!(&buffer.data[0..4], &[0xd4, 0xc3, 0xb2, 0xa1]);
+        assert_eq!(&buffer.data[20..24], &[189, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_control_stages() {
+        let mut capture = capture().with_bus_number(3);
+        let dev_addr = Some(DeviceAddress(core::num::NonZeroU8::new(5).unwrap()));
+        let setup = [0x80, 0x06, 0x00, 0x01, 0, 0, 0x12, 0];
+        capture.trace(1500, TraceRecord::Setup { dev_addr, packet: setup });
+        capture.trace(1501, TraceRecord::Event(Event::TransComplete));
+        capture.trace(
+            1501,
+            TraceRecord::DataIn { dev_addr, endpoint: 0, transfer_type: TransferType::Control, length: 18, pid: true },
+        );
+        capture.trace(1502, TraceRecord::Event(Event::Stall));
+        // nothing is pending, so this does not produce a packet
+        capture.trace(1503, TraceRecord::Event(Event::TransComplete));
+        let buffer = capture.into_writer();
+        assert_eq!(buffer.len, 24 + 4 * (16 + USBMON_HEADER_LEN));
+
+        let submit = packet(&buffer, 0);
+        assert_eq!(&submit[8..16], &[b'S', 2, 0x80, 5, 3, 0, 0, b'<']);
+        assert_eq!(&submit[16..24], &1i64.to_le_bytes());
+        assert_eq!(&submit[24..28], &500_000i32.to_le_bytes());
+        assert_eq!(&submit[28..32], &(-EINPROGRESS).to_le_bytes());
+        assert_eq!(&submit[32..36], &18u32.to_le_bytes());
+        assert_eq!(&submit[40..48], &setup);
+
+        let complete = packet(&buffer, 1);
+        assert_eq!(complete[0..8], submit[0..8]);
+        assert_eq!(&complete[8..16], &[b'C', 2, 0x80, 5, 3, 0, b'-', b'<']);
+        assert_eq!(&complete[28..32], &0i32.to_le_bytes());
+
+        let data = packet(&buffer, 2);
+        assert_ne!(data[0..8], submit[0..8]);
+        assert_eq!(&data[8..16], &[b'S', 2, 0x80, 5, 3, 0, b'-', b'<']);
+        assert_eq!(&packet(&buffer, 3)[28..32], &(-EPIPE).to_le_bytes());
+    }
+
+    #[test]
+    fn test_bulk_error() {
+        let mut capture = capture();
+        capture.trace(
+            0,
+            TraceRecord::DataOut { dev_addr: None, endpoint: 2, transfer_type: TransferType::Bulk, length: 64, pid: false },
+        );
+        capture.trace(0, TraceRecord::Event(Event::Error(Error::NakLimit)));
+        let buffer = capture.into_writer();
+        assert_eq!(&packet(&buffer, 0)[8..16], &[b'S', 3, 0x02, 0, 1, 0, b'-', b'>']);
+        let complete = packet(&buffer, 1);
+        assert_eq!(complete[8], b'C');
+        assert_eq!(&complete[28..32], &(-ETIMEDOUT).to_le_bytes());
+    }
+}
@@ -1,25 +1,71 @@
-use crate::bus::HostBus;
-use crate::UsbHost;
+//! Transfer state machine
+//!
+//! Tracks the stages of a transfer that is in progress. The state machine does not access the bus itself: when a stage
+//! completes, [`Transfer::stage_complete`] returns a [`BusAction`] that the host uses to start the next stage.
+
+use crate::diagnostics::TransferStage;
+use crate::types::TransferType;
 use usb_device::UsbDirection;
 
+#[derive(Copy, Clone)]
 pub struct Transfer {
     length: u16,
     state: TransferState,
+    /// Set for control IN transfers whose data stage is split into individual packets of the given size
+    ///
+    /// The packets are reassembled in the host's control buffer.
+    chunk_size: Option<u16>,
+    /// Number of bytes received so far, or in total once the data stage of an IN transfer is complete
+    received: u16,
+    /// Zero-length packet to send after the data of an OUT transfer
+    zlp: Zlp,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Zlp {
+    None,
+    /// Send a zero-length packet with given PID (`true` for DATA1), once the data was sent
+    Pending(bool),
+    Sent,
 }
 
+#[derive(Copy, Clone)]
 enum TransferState {
     Control(UsbDirection, ControlState),
+    Bulk(UsbDirection),
 }
 
+#[derive(Copy, Clone)]
+#[allow(clippy::enum_variant_names)]
 enum ControlState {
     WaitSetup,
     WaitData,
     WaitConfirm,
 }
 
+/// Operation on the host bus, to start the next stage of a transfer
+///
+/// Variants are named after the `HostBus` methods they translate to.
+#[derive(Copy, Clone, PartialEq)]
+#[allow(clippy::enum_variant_names)]
+pub enum BusAction {
+    /// Request an IN packet with given length and PID (`true` for DATA1)
+    WriteDataIn(u16, bool),
+    /// Send the data prepared via `prepare_data_out`, with given PID (`true` for DATA1)
+    WriteDataOutPrepared(bool),
+    /// Status stage of a control IN transfer: zero-length OUT packet with DATA1
+    WriteStatusOut,
+    /// Status stage of a control OUT transfer: zero-length IN packet with DATA1
+    WriteStatusIn,
+    /// Terminate the data of an OUT transfer with a zero-length packet, with given PID (`true` for DATA1)
+    WriteZeroLengthOut(bool),
+}
+
 pub enum PollResult {
     ControlInComplete(u16),
     ControlOutComplete,
+    BulkInComplete(u16),
+    BulkOutComplete,
     Continue(Transfer),
 }
 
@@ -28,6 +74,17 @@ impl Transfer {
         Self {
             length,
             state: TransferState::Control(UsbDirection::In, ControlState::WaitSetup),
+            chunk_size: None,
+            received: 0,
+            zlp: Zlp::None,
+        }
+    }
+
+    /// Control IN transfer, with the data stage split into packets of `chunk_size` bytes
+    pub(crate) fn new_control_in_chunked(length: u16, chunk_size: u16) -> Self {
+        Self {
+            chunk_size: Some(chunk_size),
+            ..Self::new_control_in(length)
         }
     }
 
@@ -35,65 +92,336 @@ impl Transfer {
         Self {
             length,
             state: TransferState::Control(UsbDirection::Out, ControlState::WaitSetup),
+            chunk_size: None,
+            received: 0,
+            zlp: Zlp::None,
+        }
+    }
+
+    pub(crate) fn length(&self) -> u16 {
+        self.length
+    }
+
+    pub(crate) fn transfer_type(&self) -> TransferType {
+        match self.state {
+            TransferState::Control(..) => TransferType::Control,
+            TransferState::Bulk(_) => TransferType::Bulk,
+        }
+    }
+
+    pub(crate) fn direction(&self) -> UsbDirection {
+        match self.state {
+            TransferState::Control(direction, _) | TransferState::Bulk(direction) => direction,
+        }
+    }
+
+    /// Stage the transfer is waiting for
+    pub(crate) fn stage(&self) -> TransferStage {
+        match self.state {
+            TransferState::Control(_, ControlState::WaitSetup) => TransferStage::Setup,
+            TransferState::Control(_, ControlState::WaitData) | TransferState::Bulk(_) => TransferStage::Data,
+            TransferState::Control(_, ControlState::WaitConfirm) => TransferStage::Status,
+        }
+    }
+
+    /// Terminate the data with a zero-length packet, using the given PID (`true` for DATA1)
+    ///
+    /// Only has an effect on OUT transfers. For control OUT transfers without data, the data stage consists of the
+    /// zero-length packet alone.
+    pub(crate) fn with_zlp(self, pid: bool) -> Self {
+        Self {
+            zlp: Zlp::Pending(pid),
+            ..self
+        }
+    }
+
+    /// Returns `true` if a zero-length packet was sent after the data
+    pub(crate) fn zlp_sent(&self) -> bool {
+        self.zlp == Zlp::Sent
+    }
+
+    pub(crate) fn new_bulk_in(length: u16) -> Self {
+        Self {
+            length,
+            state: TransferState::Bulk(UsbDirection::In),
+            chunk_size: None,
+            received: 0,
+            zlp: Zlp::None,
+        }
+    }
+
+    pub(crate) fn new_bulk_out(length: u16) -> Self {
+        Self {
+            length,
+            state: TransferState::Bulk(UsbDirection::Out),
+            chunk_size: None,
+            received: 0,
+            zlp: Zlp::None,
         }
     }
 
-    pub(crate) fn stage_complete<B: HostBus>(self, host: &mut UsbHost<B>) -> PollResult {
+    /// Size of the next packet to request in a chunked data stage
+    fn next_chunk(&self, chunk_size: u16) -> u16 {
+        chunk_size.min(self.length - self.received)
+    }
+
+    /// Number of bytes requested from the device in the current stage, if it is the data stage of an IN transfer
+    ///
+    /// The device may end the data stage early, with a short (or zero-length) packet. Once the stage is complete, the host
+    /// passes the number of bytes actually received to [`Transfer::stage_complete`].
+    pub(crate) fn expected_in(&self) -> Option<u16> {
+        match self.state {
+            TransferState::Control(UsbDirection::In, ControlState::WaitData) => {
+                Some(self.chunk_size.map_or(self.length, |chunk_size| self.next_chunk(chunk_size)))
+            }
+            TransferState::Bulk(UsbDirection::In) => Some(self.length),
+            _ => None,
+        }
+    }
+
+    /// Advance the transfer, after the current stage completed
+    ///
+    /// `received` is the number of bytes received in the completed stage, for stages where
+    /// [`expected_in`](Transfer::expected_in) returned a value. If it is `None`, the requested length is assumed.
+    ///
+    /// The returned action (if any) starts the next stage, and must be carried out by the host.
+    pub(crate) fn stage_complete(self, received: Option<u16>) -> (PollResult, Option<BusAction>) {
         match self {
             Transfer {
                 state: TransferState::Control(UsbDirection::In, control_state),
-                length,
+                chunk_size: Some(chunk_size),
+                ..
             } => match control_state {
-                ControlState::WaitSetup => {
-                    host.bus.write_data_in(length, true);
+                ControlState::WaitSetup => (
                     PollResult::Continue(Transfer {
                         state: TransferState::Control(UsbDirection::In, ControlState::WaitData),
-                        length,
-                    })
-                }
+                        ..self
+                    }),
+                    Some(BusAction::WriteDataIn(self.next_chunk(chunk_size), true)),
+                ),
                 ControlState::WaitData => {
-                    host.bus.write_data_out(&[]);
+                    let requested = self.next_chunk(chunk_size);
+                    let received = received.unwrap_or(0);
+                    let total = self.received + received;
+                    if received < requested || total >= self.length {
+                        // short packet, or all data received: continue with status stage
+                        (
+                            PollResult::Continue(Transfer {
+                                state: TransferState::Control(UsbDirection::In, ControlState::WaitConfirm),
+                                received: total,
+                                ..self
+                            }),
+                            Some(BusAction::WriteStatusOut),
+                        )
+                    } else {
+                        let next = Transfer { received: total, ..self };
+                        // the data stage starts with DATA1, and alternates from there
+                        let pid = (total / chunk_size).is_multiple_of(2);
+                        (
+                            PollResult::Continue(next),
+                            Some(BusAction::WriteDataIn(next.next_chunk(chunk_size), pid)),
+                        )
+                    }
+                }
+                ControlState::WaitConfirm => (PollResult::ControlInComplete(self.received), None),
+            },
+            Transfer {
+                state: TransferState::Control(UsbDirection::In, control_state),
+                length,
+                ..
+            } => match control_state {
+                ControlState::WaitSetup => (
+                    PollResult::Continue(Transfer {
+                        state: TransferState::Control(UsbDirection::In, ControlState::WaitData),
+                        ..self
+                    }),
+                    Some(BusAction::WriteDataIn(length, true)),
+                ),
+                ControlState::WaitData => (
                     PollResult::Continue(Transfer {
                         state: TransferState::Control(UsbDirection::In, ControlState::WaitConfirm),
-                        length,
-                    })
-                }
-                ControlState::WaitConfirm => PollResult::ControlInComplete(length),
+                        received: received.unwrap_or(length).min(length),
+                        ..self
+                    }),
+                    Some(BusAction::WriteStatusOut),
+                ),
+                ControlState::WaitConfirm => (PollResult::ControlInComplete(self.received), None),
             },
             Transfer {
                 state: TransferState::Control(UsbDirection::Out, control_state),
                 length,
+                ..
             } => match control_state {
-                ControlState::WaitSetup => {
-                    if length == 0 {
-                        host.bus.write_data_in(0, true);
+                ControlState::WaitSetup => match (length, self.zlp) {
+                    (0, Zlp::Pending(pid)) => (
                         PollResult::Continue(Transfer {
-                            state: TransferState::Control(
-                                UsbDirection::Out,
-                                ControlState::WaitConfirm,
-                            ),
-                            length,
-                        })
-                    } else {
-                        host.bus.write_data_out_prepared();
+                            state: TransferState::Control(UsbDirection::Out, ControlState::WaitData),
+                            zlp: Zlp::Sent,
+                            ..self
+                        }),
+                        Some(BusAction::WriteZeroLengthOut(pid)),
+                    ),
+                    (0, _) => (
                         PollResult::Continue(Transfer {
-                            state: TransferState::Control(
-                                UsbDirection::Out,
-                                ControlState::WaitData,
-                            ),
-                            length,
-                        })
-                    }
+                            state: TransferState::Control(UsbDirection::Out, ControlState::WaitConfirm),
+                            ..self
+                        }),
+                        Some(BusAction::WriteStatusIn),
+                    ),
+                    _ => (
+                        PollResult::Continue(Transfer {
+                            state: TransferState::Control(UsbDirection::Out, ControlState::WaitData),
+                            ..self
+                        }),
+                        Some(BusAction::WriteDataOutPrepared(true)),
+                    ),
+                },
+                ControlState::WaitData => match self.zlp {
+                    Zlp::Pending(pid) => (
+                        PollResult::Continue(Transfer { zlp: Zlp::Sent, ..self }),
+                        Some(BusAction::WriteZeroLengthOut(pid)),
+                    ),
+                    _ => (
+                        PollResult::Continue(Transfer {
+                            state: TransferState::Control(UsbDirection::Out, ControlState::WaitConfirm),
+                            ..self
+                        }),
+                        Some(BusAction::WriteStatusIn),
+                    ),
+                },
+                ControlState::WaitConfirm => (PollResult::ControlOutComplete, None),
+            },
+            Transfer {
+                state: TransferState::Bulk(UsbDirection::In),
+                length,
+                ..
+            } => (PollResult::BulkInComplete(received.unwrap_or(length).min(length)), None),
+            Transfer {
+                state: TransferState::Bulk(UsbDirection::Out),
+                zlp: Zlp::Pending(pid),
+                ..
+            } => (
+                PollResult::Continue(Transfer { zlp: Zlp::Sent, ..self }),
+                Some(BusAction::WriteZeroLengthOut(pid)),
+            ),
+            Transfer {
+                state: TransferState::Bulk(UsbDirection::Out),
+                ..
+            } => (PollResult::BulkOutComplete, None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run the transfer until it completes, feeding the given packet sizes for IN data stages
+    fn run(mut transfer: Transfer, mut packets: &[u16]) -> (PollResult, [Option<BusAction>; 8]) {
+        let mut actions = [None; 8];
+        for action in actions.iter_mut() {
+            let received = transfer.expected_in().map(|expected| {
+                let (first, rest) = packets.split_first().unwrap();
+                assert!(*first <= expected);
+                packets = rest;
+                *first
+            });
+            match transfer.stage_complete(received) {
+                (PollResult::Continue(next), next_action) => {
+                    *action = next_action;
+                    transfer = next;
                 }
-                ControlState::WaitData => {
-                    host.bus.write_data_in(0, true);
-                    PollResult::Continue(Transfer {
-                        state: TransferState::Control(UsbDirection::Out, ControlState::WaitConfirm),
-                        length,
-                    })
+                (result, next_action) => {
+                    assert!(next_action.is_none());
+                    return (result, actions);
                 }
-                ControlState::WaitConfirm => PollResult::ControlOutComplete,
-            },
+            }
         }
+        panic!("transfer did not complete");
+    }
+
+    #[test]
+    fn test_control_in_stages() {
+        let (result, actions) = run(Transfer::new_control_in(18), &[18]);
+        assert!(matches!(result, PollResult::ControlInComplete(18)));
+        assert!(actions[..3] == [Some(BusAction::WriteDataIn(18, true)), Some(BusAction::WriteStatusOut), None]);
+
+        // the device sent less data than requested
+        let (result, actions) = run(Transfer::new_control_in(255), &[9]);
+        assert!(matches!(result, PollResult::ControlInComplete(9)));
+        assert!(actions[1] == Some(BusAction::WriteStatusOut));
+    }
+
+    #[test]
+    fn test_short_bulk_in() {
+        let (result, _) = run(Transfer::new_bulk_in(64), &[0]);
+        assert!(matches!(result, PollResult::BulkInComplete(0)));
+    }
+
+    #[test]
+    fn test_control_out_stages() {
+        let (result, actions) = run(Transfer::new_control_out(0), &[]);
+        assert!(matches!(result, PollResult::ControlOutComplete));
+        assert!(actions[..2] == [Some(BusAction::WriteStatusIn), None]);
+
+        let (result, actions) = run(Transfer::new_control_out(4), &[]);
+        assert!(matches!(result, PollResult::ControlOutComplete));
+        assert!(actions[..3] == [Some(BusAction::WriteDataOutPrepared(true)), Some(BusAction::WriteStatusIn), None]);
+    }
+
+    #[test]
+    fn test_control_out_zlp() {
+        let (result, actions) = run(Transfer::new_control_out(64).with_zlp(false), &[]);
+        assert!(matches!(result, PollResult::ControlOutComplete));
+        assert!(
+            actions[..4]
+                == [
+                    Some(BusAction::WriteDataOutPrepared(true)),
+                    Some(BusAction::WriteZeroLengthOut(false)),
+                    Some(BusAction::WriteStatusIn),
+                    None,
+                ]
+        );
+
+        // without data, the zero-length packet is the whole data stage
+        let (_, actions) = run(Transfer::new_control_out(0).with_zlp(true), &[]);
+        assert!(actions[..3] == [Some(BusAction::WriteZeroLengthOut(true)), Some(BusAction::WriteStatusIn), None]);
+    }
+
+    #[test]
+    fn test_chunked_control_in() {
+        let (result, actions) = run(Transfer::new_control_in_chunked(150, 64), &[64, 64, 22]);
+        assert!(matches!(result, PollResult::ControlInComplete(150)));
+        assert!(
+            actions[..5]
+                == [
+                    Some(BusAction::WriteDataIn(64, true)),
+                    Some(BusAction::WriteDataIn(64, false)),
+                    Some(BusAction::WriteDataIn(22, true)),
+                    Some(BusAction::WriteStatusOut),
+                    None,
+                ]
+        );
+
+        // a short packet ends the data stage early
+        let (result, actions) = run(Transfer::new_control_in_chunked(255, 64), &[64, 10]);
+        assert!(matches!(result, PollResult::ControlInComplete(74)));
+        assert!(actions[2] == Some(BusAction::WriteStatusOut));
+    }
+
+    #[test]
+    fn test_bulk_out_zlp() {
+        let (result, actions) = run(Transfer::new_bulk_out(128), &[]);
+        assert!(matches!(result, PollResult::BulkOutComplete));
+        assert!(actions[0].is_none());
+
+        let transfer = Transfer::new_bulk_out(128).with_zlp(false);
+        assert!(!transfer.zlp_sent());
+        let (PollResult::Continue(transfer), action) = transfer.stage_complete(None) else {
+            panic!("expected the transfer to continue");
+        };
+        assert!(action == Some(BusAction::WriteZeroLengthOut(false)));
+        assert!(transfer.zlp_sent());
+        assert!(matches!(transfer.stage_complete(None), (PollResult::BulkOutComplete, None)));
     }
 }
@@ -2,7 +2,6 @@
 //!
 
 use core::num::NonZeroU8;
-use defmt::Format;
 use usb_device::{
     control::{Recipient, RequestType},
     UsbDirection,
@@ -15,7 +14,9 @@ use usb_device::{
 ///
 /// This type only represents assigned addresses, and thus cannot represent the special address 0.
 /// Address 0 is only used to assign an address to the device during enumeration, and should not be used by any drivers.
-#[derive(Clone, Copy, PartialEq, Format)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DeviceAddress(pub(crate) NonZeroU8);
 
 impl From<DeviceAddress> for u16 {
@@ -30,10 +31,17 @@ impl From<DeviceAddress> for u8 {
     }
 }
 
+impl core::fmt::Display for DeviceAddress {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 /// Represents a 16-bit binary-coded-decimal value
 ///
 /// A 16-bit BCD represents 4 decimal digits (0-9).
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Bcd16(pub(crate) u16);
 
 impl Bcd16 {
@@ -57,7 +65,8 @@ impl Bcd16 {
     }
 }
 
-impl Format for Bcd16 {
+#[cfg(feature = "defmt")]
+impl defmt::Format for Bcd16 {
     fn format(&self, fmt: defmt::Formatter) {
         defmt::write!(
             fmt,
@@ -70,16 +79,33 @@ impl Format for Bcd16 {
     }
 }
 
+/// Formats the value as a version number, e.g. `2.10` for USB 2.1
+impl core::fmt::Display for Bcd16 {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let [d0, d1, d2, d3] = self.to_digits();
+        if d0 > 0 {
+            write!(f, "{d0}")?;
+        }
+        write!(f, "{d1}.{d2}{d3}")
+    }
+}
+
 /// Refers to the speed at which a device operates
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ConnectionSpeed {
     /// USB 1.0 low speed
     Low,
     /// USB 1.0 full speed
     Full,
+    /// USB 2.0 high speed
+    ///
+    /// Not reported by any host bus yet. Used to interpret descriptors correctly, see [`PollingInterval`].
+    High,
 }
 
-impl Format for ConnectionSpeed {
+#[cfg(feature = "defmt")]
+impl defmt::Format for ConnectionSpeed {
     fn format(&self, fmt: defmt::Formatter) {
         defmt::write!(
             fmt,
@@ -87,13 +113,25 @@ impl Format for ConnectionSpeed {
             match self {
                 ConnectionSpeed::Low => "low",
                 ConnectionSpeed::Full => "full",
+                ConnectionSpeed::High => "high",
             }
         )
     }
 }
 
+impl core::fmt::Display for ConnectionSpeed {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            ConnectionSpeed::Low => "low",
+            ConnectionSpeed::Full => "full",
+            ConnectionSpeed::High => "high",
+        })
+    }
+}
+
 /// Represents one of the four transfer types that USB supports
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum TransferType {
     Control = 0,
@@ -102,12 +140,168 @@ pub enum TransferType {
     Interrupt = 3,
 }
 
+/// A set of interface numbers, within one configuration
+///
+/// Used by drivers to claim the interfaces of a composite device that they handle, see
+/// [`Driver::claim_interfaces`](crate::driver::Driver::claim_interfaces). Only interface numbers below 32 can be represented;
+/// inserting a higher number has no effect.
+#[derive(Copy, Clone, PartialEq, Eq, Default, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct InterfaceSet(u32);
+
+impl InterfaceSet {
+    /// The empty set
+    pub const EMPTY: InterfaceSet = InterfaceSet(0);
+
+    /// Set containing only the given interface
+    pub fn single(interface: u8) -> Self {
+        Self::EMPTY.with(interface)
+    }
+
+    /// Returns this set, with the given interface added
+    pub fn with(self, interface: u8) -> Self {
+        InterfaceSet(self.0 | 1u32.checked_shl(interface as u32).unwrap_or(0))
+    }
+
+    pub fn contains(&self, interface: u8) -> bool {
+        self.0 & 1u32.checked_shl(interface as u32).unwrap_or(0) != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Interfaces contained in both sets
+    pub fn intersection(self, other: InterfaceSet) -> Self {
+        InterfaceSet(self.0 & other.0)
+    }
+
+    /// Interfaces contained in this set, but not in `other`
+    pub fn difference(self, other: InterfaceSet) -> Self {
+        InterfaceSet(self.0 & !other.0)
+    }
+
+    /// Interfaces contained in either set
+    pub fn union(self, other: InterfaceSet) -> Self {
+        InterfaceSet(self.0 | other.0)
+    }
+
+    /// Iterate over the interface numbers in this set, in ascending order
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..32).filter(|interface| self.contains(*interface))
+    }
+}
+
+/// Whether to terminate a bulk OUT transfer with a zero-length packet (ZLP)
+///
+/// A transfer ends with a packet shorter than the endpoint's maximum packet size. If the data is an exact multiple of
+/// the maximum packet size, some protocols (e.g. CDC) require a ZLP to mark the end of the transfer, while others
+/// (e.g. mass storage, where the length is known in advance) forbid it.
+///
+/// See [`UsbHost::bulk_out_with_zlp`](crate::UsbHost::bulk_out_with_zlp).
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ZlpPolicy {
+    /// Send a ZLP if the data is not empty, and an exact multiple of the maximum packet size
+    Auto,
+    /// Never send a ZLP
+    Never,
+    /// Always send a ZLP after the data, unless the data is empty (in which case the transfer consists of a ZLP anyway)
+    Force,
+}
+
+impl ZlpPolicy {
+    /// Returns `true` if `length` bytes of data, sent in packets of `max_packet_size`, must be followed by a ZLP
+    pub fn needs_zlp(&self, length: u16, max_packet_size: u16) -> bool {
+        match self {
+            ZlpPolicy::Auto => length > 0 && length.is_multiple_of(max_packet_size),
+            ZlpPolicy::Never => false,
+            ZlpPolicy::Force => length > 0,
+        }
+    }
+}
+
+/// Whether the host keeps generating SOF packets while none of the devices is in use
+///
+/// Devices enter suspend mode (and draw less current) when they see no SOF or keep-alive packets for 3 ms. By default
+/// the host keeps the bus running as long as a device is attached. The other policies suspend the bus automatically,
+/// the same way as [`UsbHost::suspend`](crate::UsbHost::suspend).
+///
+/// See [`UsbHost::set_power_policy`](crate::UsbHost::set_power_policy).
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PowerPolicy {
+    /// Keep generating SOF packets while a device is attached
+    KeepAlive,
+    /// Suspend the bus while all attached devices are dormant, i.e. no driver chose a configuration for any of them
+    ///
+    /// The bus stays suspended until the device is removed (or [`UsbHost::resume`](crate::UsbHost::resume) is called).
+    SuspendWhenDormant,
+    /// Suspend the bus once it was idle for the given number of frames, and none of the devices needs to be polled
+    ///
+    /// Devices with open interrupt, bulk or isochronous pipes (or liveness pings) are considered in use, so this mainly
+    /// applies to devices which only use control transfers, besides dormant ones. A control transfer submitted by a
+    /// driver while the bus is suspended is queued, and the bus is resumed to carry it out.
+    SuspendWhenIdle(u16),
+}
+
+/// Interval at which the host polls an interrupt (or isochronous) endpoint
+///
+/// The `bInterval` field of an endpoint descriptor is interpreted differently depending on the speed of the device
+/// and the type of the endpoint:
+/// - low / full speed interrupt endpoints: `bInterval` frames (1 ms each)
+/// - full speed isochronous endpoints: 2^(`bInterval`-1) frames
+/// - high speed endpoints: 2^(`bInterval`-1) microframes (125 µs each)
+///
+/// A `PollingInterval` is the result of that interpretation, so that drivers and host bus implementations don't need to know about it.
+/// Use [`PollingInterval::from_descriptor`] or [`EndpointDescriptor::polling_interval`](crate::descriptor::EndpointDescriptor::polling_interval) to obtain one.
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PollingInterval {
+    microframes: u32,
+}
+
+impl PollingInterval {
+    /// Interpret the `bInterval` value from an endpoint descriptor
+    ///
+    /// Out of range values are clamped to the nearest valid one.
+    pub fn from_descriptor(interval: u8, speed: ConnectionSpeed, transfer_type: TransferType) -> Self {
+        let exponential = |interval: u8| 1u32 << (interval.clamp(1, 16) - 1);
+        let microframes = match (speed, transfer_type) {
+            (ConnectionSpeed::High, _) => exponential(interval),
+            (_, TransferType::Isochronous) => exponential(interval) * 8,
+            _ => interval.max(1) as u32 * 8,
+        };
+        PollingInterval { microframes }
+    }
+
+    /// Interval given in frames (i.e. milliseconds)
+    pub const fn from_frames(frames: u16) -> Self {
+        PollingInterval {
+            microframes: frames as u32 * 8,
+        }
+    }
+
+    /// Interval in microframes (125 µs)
+    pub fn microframes(&self) -> u32 {
+        self.microframes
+    }
+
+    /// Interval in frames (i.e. milliseconds)
+    ///
+    /// Intervals shorter than a frame are rounded up to one frame.
+    pub fn frames(&self) -> u16 {
+        self.microframes.div_ceil(8).min(u16::MAX as u32) as u16
+    }
+}
+
 /// Represents a setup packet
 ///
 /// See [`SetupPacket::new`] for usage info.
 ///
 /// NOTE: the fields are all public, because they must be read by the [`crate::bus::HostBus`] implementation.
 ///   The fields are not meant to be written to though. Use the [`SetupPacket::new`] construct instead.
+#[derive(Copy, Clone, Debug)]
 pub struct SetupPacket {
     pub request_type: u8,
     pub request: u8,
@@ -152,6 +346,17 @@ impl SetupPacket {
             length,
         }
     }
+
+    /// The eight bytes of the packet, as sent on the wire (multi-byte fields are little endian)
+    pub fn to_bytes(&self) -> [u8; 8] {
+        let mut packet = [0; 8];
+        packet[0] = self.request_type;
+        packet[1] = self.request;
+        packet[2..4].copy_from_slice(&self.value.to_le_bytes());
+        packet[4..6].copy_from_slice(&self.index.to_le_bytes());
+        packet[6..8].copy_from_slice(&self.length.to_le_bytes());
+        packet
+    }
 }
 
 #[cfg(test)]
@@ -159,6 +364,26 @@ mod tests {
     use super::*;
     use usb_device::control::Request;
 
+    #[test]
+    fn test_interface_set() {
+        let set = InterfaceSet::single(1).with(3).with(40);
+        assert!(set.contains(1) && set.contains(3));
+        assert!(!set.contains(0) && !set.contains(40));
+        let claimed = set.difference(InterfaceSet::single(1));
+        assert!(claimed.iter().eq([3]));
+        assert!(claimed.intersection(InterfaceSet::single(1)).is_empty());
+    }
+
+    #[test]
+    fn test_zlp_policy() {
+        assert!(ZlpPolicy::Auto.needs_zlp(128, 64));
+        assert!(!ZlpPolicy::Auto.needs_zlp(100, 64));
+        assert!(!ZlpPolicy::Auto.needs_zlp(0, 64));
+        assert!(!ZlpPolicy::Never.needs_zlp(128, 64));
+        assert!(ZlpPolicy::Force.needs_zlp(100, 64));
+        assert!(!ZlpPolicy::Force.needs_zlp(0, 64));
+    }
+
     #[test]
     fn test_setup_new() {
         let packet = SetupPacket::new(
@@ -177,12 +402,56 @@ mod tests {
         assert_eq!(packet.length, 27);
     }
 
+    #[test]
+    fn test_polling_interval() {
+        let interval = PollingInterval::from_descriptor(10, ConnectionSpeed::Full, TransferType::Interrupt);
+        assert_eq!(interval.frames(), 10);
+        assert_eq!(interval.microframes(), 80);
+
+        let interval = PollingInterval::from_descriptor(0, ConnectionSpeed::Low, TransferType::Interrupt);
+        assert_eq!(interval.frames(), 1);
+
+        let interval = PollingInterval::from_descriptor(4, ConnectionSpeed::Full, TransferType::Isochronous);
+        assert_eq!(interval.frames(), 8);
+
+        let interval = PollingInterval::from_descriptor(4, ConnectionSpeed::High, TransferType::Interrupt);
+        assert_eq!(interval.microframes(), 8);
+        assert_eq!(interval.frames(), 1);
+
+        let interval = PollingInterval::from_descriptor(1, ConnectionSpeed::High, TransferType::Interrupt);
+        assert_eq!(interval.microframes(), 1);
+        assert_eq!(interval.frames(), 1);
+    }
+
     #[test]
     fn test_bcd_digits() {
         let bcd = Bcd16(0x1234);
         assert_eq!(bcd.to_digits(), [1, 2, 3, 4]);
     }
 
+    #[test]
+    fn test_display() {
+        extern crate std;
+        use std::format;
+        assert_eq!(format!("{}", Bcd16(0x0210)), "2.10");
+        assert_eq!(format!("{}", Bcd16(0x1234)), "12.34");
+        assert_eq!(format!("{}", DeviceAddress(NonZeroU8::new(5).unwrap())), "5");
+        assert_eq!(format!("{:?}", TransferType::Bulk), "Bulk");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let mut buf = [0; 8];
+        let address = DeviceAddress(NonZeroU8::new(7).unwrap());
+        let encoded = postcard::to_slice(&(address, ConnectionSpeed::Full), &mut buf).unwrap();
+        assert_eq!(encoded, [7, 1]);
+        let decoded: (DeviceAddress, ConnectionSpeed) = postcard::from_bytes(encoded).unwrap();
+        assert_eq!(decoded, (address, ConnectionSpeed::Full));
+        // address 0 is never assigned to a device
+        assert!(postcard::from_bytes::<DeviceAddress>(&[0]).is_err());
+    }
+
     #[test]
     fn test_bcd_is_valid() {
         assert!(Bcd16::is_valid(0x1234));